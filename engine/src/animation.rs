@@ -0,0 +1,244 @@
+use anyhow::*;
+use cgmath::{InnerSpace, Rad, Vector3};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    light::{LightId, PositionalLight},
+    particles::Lerp,
+    renderer::Renderer,
+};
+
+/// Frame layout and timing of an animated texture atlas. Frames are laid out
+/// left to right, top to bottom in a grid of `columns` x `rows` cells.
+#[derive(Debug, Copy, Clone)]
+pub struct Flipbook {
+    pub columns: u32,
+    pub rows: u32,
+    pub frame_count: u32,
+    pub fps: f32,
+    pub looping: bool,
+}
+
+impl Flipbook {
+    pub fn new(columns: u32, rows: u32, fps: f32) -> Self {
+        return Self {
+            columns,
+            rows,
+            frame_count: columns * rows,
+            fps,
+            looping: true,
+        };
+    }
+
+    pub fn with_frame_count(mut self, frame_count: u32) -> Self {
+        self.frame_count = frame_count.min(self.columns * self.rows);
+        return self;
+    }
+
+    pub fn with_looping(mut self, looping: bool) -> Self {
+        self.looping = looping;
+        return self;
+    }
+
+    pub fn frame_at(&self, time: f32) -> u32 {
+        if self.frame_count == 0 {
+            return 0;
+        }
+        let frame = (time.max(0.0) * self.fps) as u32;
+        return if self.looping {
+            frame % self.frame_count
+        } else {
+            frame.min(self.frame_count - 1)
+        };
+    }
+
+    /// Texture coordinate transform for `frame` as (scale.x, scale.y, offset.x, offset.y).
+    pub fn uv_transform(&self, frame: u32) -> [f32; 4] {
+        let scale_x = 1.0 / self.columns as f32;
+        let scale_y = 1.0 / self.rows as f32;
+        let column = frame % self.columns;
+        let row = frame / self.columns;
+        return [scale_x, scale_y, column as f32 * scale_x, row as f32 * scale_y];
+    }
+}
+
+/// A flipbook attached to a material, remembering when playback started.
+#[derive(Debug, Copy, Clone)]
+pub struct FlipbookState {
+    pub flipbook: Flipbook,
+    pub start_time: f32,
+    pub(crate) current_frame: Option<u32>,
+}
+
+impl FlipbookState {
+    pub fn new(flipbook: Flipbook, start_time: f32) -> Self {
+        return Self {
+            flipbook,
+            start_time,
+            current_frame: None,
+        };
+    }
+
+    /// Returns the new UV transform when the visible frame changed since the last call.
+    pub fn advance(&mut self, time: f32) -> Option<[f32; 4]> {
+        let frame = self.flipbook.frame_at(time - self.start_time);
+        if self.current_frame == Some(frame) {
+            return None;
+        }
+        self.current_frame = Some(frame);
+        return Some(self.flipbook.uv_transform(frame));
+    }
+}
+
+/// What a `Track` does past its last key.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Looping {
+    /// Hold the last key.
+    Once,
+    /// Start over from the first key.
+    Repeat,
+    /// Play back to the first key, then forwards again.
+    PingPong,
+}
+
+/// Value over time in seconds, linear between keys.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(from = "TrackKeys<T>")]
+pub struct Track<T> {
+    keys: Vec<(f32, T)>,
+    pub looping: Looping,
+}
+
+// Keys as written in a description, sorted when turned into a `Track`
+#[derive(Deserialize)]
+struct TrackKeys<T> {
+    keys: Vec<(f32, T)>,
+    looping: Looping,
+}
+
+impl<T> From<TrackKeys<T>> for Track<T> {
+    fn from(track: TrackKeys<T>) -> Self {
+        return Track::new(track.keys, track.looping);
+    }
+}
+
+impl<T> Track<T> {
+    /// Keys are sorted by time.
+    pub fn new(mut keys: Vec<(f32, T)>, looping: Looping) -> Self {
+        keys.sort_by(|a, b| a.0.total_cmp(&b.0));
+        return Self { keys, looping };
+    }
+
+    /// Time from the first key to the last.
+    pub fn duration(&self) -> f32 {
+        return match (self.keys.first(), self.keys.last()) {
+            (Some(first), Some(last)) => last.0 - first.0,
+            _ => 0.0,
+        };
+    }
+}
+
+impl<T: Lerp> Track<T> {
+    /// Value at `time`, `None` without keys.
+    pub fn sample(&self, time: f32) -> Option<T> {
+        let start = self.keys.first()?.0;
+        let duration = self.duration();
+        let time = if duration <= 0.0 {
+            start
+        } else {
+            match self.looping {
+                Looping::Once => time.clamp(start, start + duration),
+                Looping::Repeat => start + (time - start).rem_euclid(duration),
+                Looping::PingPong => {
+                    let t = (time - start).rem_euclid(2.0 * duration);
+                    start + if t > duration { 2.0 * duration - t } else { t }
+                }
+            }
+        };
+        let next = self.keys.partition_point(|(key_time, _)| *key_time <= time);
+        if next == 0 {
+            return Some(self.keys[0].1);
+        }
+        if next == self.keys.len() {
+            return Some(self.keys[next - 1].1);
+        }
+        let (a, b) = (self.keys[next - 1], self.keys[next]);
+        return Some(a.1.lerp(b.1, (time - a.0) / (b.0 - a.0)));
+    }
+}
+
+/// Keyframed properties of a positional light, sampled over the state the
+/// light was in when the animation was attached. Properties without a track
+/// keep that state. In RON, a pulsing alarm:
+/// `(color: Some((keys: [(0.0, (1.0, 0.0, 0.0))], looping: Once)),
+///   intensity: Some((keys: [(0.0, 0.0), (0.5, 4.0)], looping: PingPong)))`.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct LightAnimation {
+    /// Replaces the light's color.
+    pub color: Option<Track<[f32; 3]>>,
+    /// Multiplies the color of point and spot lights and the intensity of
+    /// area lights.
+    pub intensity: Option<Track<f32>>,
+    /// Angle from a spot light's axis to the edge of its cone, in radians.
+    pub cone_angle: Option<Track<f32>>,
+    pub position: Option<Track<[f32; 3]>>,
+    /// Where spot and area lights point, normalized after blending.
+    pub direction: Option<Track<[f32; 3]>>,
+}
+
+impl LightAnimation {
+    pub fn parse(source: &str) -> Result<Self> {
+        return ron::from_str(source).context("Invalid light animation");
+    }
+
+    /// `rest` with the tracks sampled at `time` seconds.
+    pub fn sample(&self, rest: &PositionalLight, time: f32) -> PositionalLight {
+        let mut light = rest.clone();
+        let color = self.color.as_ref().and_then(|t| t.sample(time)).unwrap_or_else(|| rest.color());
+        let intensity = self.intensity.as_ref().and_then(|t| t.sample(time)).unwrap_or(1.0);
+        match &mut light {
+            PositionalLight::Point(l) => l.color = color.map(|c| c * intensity),
+            PositionalLight::Spot(l) => {
+                l.base.color = color.map(|c| c * intensity);
+                if let Some(angle) = self.cone_angle.as_ref().and_then(|t| t.sample(time)) {
+                    l.cutoff = Rad(angle);
+                }
+            }
+            PositionalLight::Area(l) => {
+                l.color = color;
+                l.intensity *= intensity;
+            }
+        }
+        if let Some(position) = self.position.as_ref().and_then(|t| t.sample(time)) {
+            light.set_position(position.into());
+        }
+        let direction = self.direction.as_ref().and_then(|t| t.sample(time)).map(Vector3::from);
+        if let Some(direction) = direction.filter(|d| d.magnitude2() > 1e-8) {
+            light.set_direction(direction);
+        }
+        return light;
+    }
+
+    /// Play the animation on light `id` from the next simulation step, in a
+    /// simulation system, so it needs the simulation attached like any other.
+    pub fn attach(self, renderer: &mut Renderer, id: LightId) -> Result<()> {
+        let rest = renderer
+            .light_manager
+            .light(id)
+            .cloned()
+            .with_context(|| format!("No light {:?} to animate", id))?;
+        let simulation = renderer
+            .simulator
+            .simulation_mut()
+            .context("Light animations need the simulation attached")?;
+        let mut start = None;
+        simulation.add_system(move |state, _| {
+            let now = state.elapsed.as_secs_f32();
+            let time = now - *start.get_or_insert(now);
+            state.lights.retain(|(light, _)| *light != id);
+            state.lights.push((id, self.sample(&rest, time)));
+        });
+        return Ok(());
+    }
+}
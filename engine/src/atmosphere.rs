@@ -0,0 +1,460 @@
+use crate::{
+    memory::{MemoryCategory, MemoryTracker, TrackedBuffer, TrackedTexture},
+    shader::ShaderPreprocessor,
+};
+
+/// Physical description of a planet's atmosphere, Earth's by default. Lengths
+/// are in km, scattering and absorption coefficients per km.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct AtmosphereModel {
+    pub bottom_radius: f32,
+    pub top_radius: f32,
+    /// Rayleigh scattering at the ground, which makes the sky blue.
+    pub rayleigh_scattering: [f32; 3],
+    pub rayleigh_scale_height: f32,
+    /// Mie scattering and extinction at the ground, aerosols and haze.
+    pub mie_scattering: f32,
+    pub mie_extinction: f32,
+    pub mie_scale_height: f32,
+    /// Asymmetry of Mie scattering, towards 1 for a brighter halo around the sun.
+    pub mie_g: f32,
+    /// Ozone absorption at the peak of the layer.
+    pub ozone_absorption: [f32; 3],
+    pub ozone_center: f32,
+    /// Half the thickness of the ozone layer.
+    pub ozone_width: f32,
+    pub ground_albedo: [f32; 3],
+    /// Sun illuminance for a directional light strength of 1.
+    pub sun_illuminance: [f32; 3],
+    /// Size of a world unit, 0.001 for scenes in meters. Larger values thicken
+    /// the aerial perspective of small scenes.
+    pub km_per_unit: f32,
+    /// Altitude of the world origin above the ground.
+    pub origin_altitude: f32,
+}
+
+impl Default for AtmosphereModel {
+    fn default() -> Self {
+        Self {
+            bottom_radius: 6360.0,
+            top_radius: 6460.0,
+            rayleigh_scattering: [5.802e-3, 13.558e-3, 33.1e-3],
+            rayleigh_scale_height: 8.0,
+            mie_scattering: 3.996e-3,
+            mie_extinction: 4.44e-3,
+            mie_scale_height: 1.2,
+            mie_g: 0.8,
+            ozone_absorption: [0.65e-3, 1.881e-3, 0.085e-3],
+            ozone_center: 25.0,
+            ozone_width: 15.0,
+            ground_albedo: [0.3; 3],
+            sun_illuminance: [1.0; 3],
+            km_per_unit: 0.001,
+            origin_altitude: 0.0,
+        }
+    }
+}
+
+// Matches `Atmosphere` in atmosphere.wgsl
+#[repr(C)]
+#[derive(Debug, Copy, Clone, bytemuck::Pod, bytemuck::Zeroable)]
+struct AtmosphereUniform {
+    rayleigh: [f32; 4],
+    mie: [f32; 4],
+    ozone: [f32; 4],
+    radii: [f32; 4],
+    ground: [f32; 4],
+    sun: [f32; 4],
+}
+
+impl AtmosphereUniform {
+    fn new(model: &AtmosphereModel, aerial_perspective: bool) -> Self {
+        let [r, g, b] = model.rayleigh_scattering;
+        let [oz_r, oz_g, oz_b] = model.ozone_absorption;
+        let [al_r, al_g, al_b] = model.ground_albedo;
+        let [sun_r, sun_g, sun_b] = model.sun_illuminance;
+        return Self {
+            rayleigh: [r, g, b, model.rayleigh_scale_height],
+            mie: [
+                model.mie_scattering,
+                model.mie_extinction,
+                model.mie_scale_height,
+                model.mie_g.clamp(-0.999, 0.999),
+            ],
+            ozone: [oz_r, oz_g, oz_b, model.ozone_center],
+            radii: [
+                model.bottom_radius,
+                model.top_radius.max(model.bottom_radius + 1.0),
+                model.ozone_width.max(0.001),
+                model.km_per_unit,
+            ],
+            ground: [al_r, al_g, al_b, model.origin_altitude.max(0.0)],
+            sun: [sun_r, sun_g, sun_b, if aerial_perspective { 1.0 } else { 0.0 }],
+        };
+    }
+}
+
+/// Lookup tables of a physically based atmosphere, computed on the GPU when
+/// created and whenever the model changes: the transmittance from any
+/// altitude and direction to space, and the light scattered more than once.
+/// With them the sky and the aerial perspective of lit surfaces only march a
+/// few steps of single scattering. Bound in the light bind group.
+pub struct Atmosphere {
+    model: AtmosphereModel,
+    aerial_perspective: bool,
+    uniform_buffer: TrackedBuffer,
+    transmittance: TrackedTexture,
+    multiscattering: TrackedTexture,
+    // Written by the compute passes and copied into the tables
+    transmittance_rows: TrackedBuffer,
+    multiscattering_rows: TrackedBuffer,
+    transmittance_view: wgpu::TextureView,
+    multiscattering_view: wgpu::TextureView,
+    sampler: wgpu::Sampler,
+    transmittance_pipeline: wgpu::ComputePipeline,
+    transmittance_bind_group: wgpu::BindGroup,
+    multiscattering_pipeline: wgpu::ComputePipeline,
+    multiscattering_bind_group: wgpu::BindGroup,
+}
+
+impl Atmosphere {
+    const TRANSMITTANCE_SIZE: [u32; 2] = [256, 64];
+    const MULTISCATTERING_SIZE: u32 = 32;
+    const FORMAT: wgpu::TextureFormat = wgpu::TextureFormat::Rgba16Float;
+    const TEXEL_SIZE: u32 = 8;
+    const WORKGROUP_SIZE: u32 = 8;
+
+    pub fn new(device: &wgpu::Device, memory: &MemoryTracker, queue: &wgpu::Queue, model: AtmosphereModel) -> Self {
+        let uniform_buffer = memory.create_buffer_init(
+            device,
+            &wgpu::util::BufferInitDescriptor {
+                label: Some("Atmosphere Buffer"),
+                contents: bytemuck::cast_slice(&[AtmosphereUniform::new(&model, false)]),
+                usage: wgpu::BufferUsages::UNIFORM | wgpu::BufferUsages::COPY_DST,
+            },
+            MemoryCategory::Uniform,
+        );
+        let create_table = |label, width, height| {
+            memory.create_texture(
+                device,
+                &wgpu::TextureDescriptor {
+                    label: Some(label),
+                    size: wgpu::Extent3d {
+                        width,
+                        height,
+                        depth_or_array_layers: 1,
+                    },
+                    mip_level_count: 1,
+                    sample_count: 1,
+                    dimension: wgpu::TextureDimension::D2,
+                    format: Self::FORMAT,
+                    usage: wgpu::TextureUsages::COPY_DST | wgpu::TextureUsages::TEXTURE_BINDING,
+                },
+                MemoryCategory::Texture,
+            )
+        };
+        let create_rows = |label, width: u32, height: u32| {
+            memory.create_buffer(
+                device,
+                &wgpu::BufferDescriptor {
+                    label: Some(label),
+                    size: (width * height * Self::TEXEL_SIZE) as wgpu::BufferAddress,
+                    usage: wgpu::BufferUsages::STORAGE | wgpu::BufferUsages::COPY_SRC,
+                    mapped_at_creation: false,
+                },
+                MemoryCategory::Other,
+            )
+        };
+        let [width, height] = Self::TRANSMITTANCE_SIZE;
+        let transmittance = create_table("Atmosphere Transmittance", width, height);
+        let size = Self::MULTISCATTERING_SIZE;
+        let multiscattering = create_table("Atmosphere Multiple Scattering", size, size);
+        let transmittance_rows = create_rows("Atmosphere Transmittance Rows", width, height);
+        let multiscattering_rows = create_rows("Atmosphere Multiple Scattering Rows", size, size);
+        let transmittance_view = transmittance.create_view(&wgpu::TextureViewDescriptor::default());
+        let multiscattering_view = multiscattering.create_view(&wgpu::TextureViewDescriptor::default());
+        let sampler = device.create_sampler(&wgpu::SamplerDescriptor {
+            label: Some("Atmosphere Sampler"),
+            address_mode_u: wgpu::AddressMode::ClampToEdge,
+            address_mode_v: wgpu::AddressMode::ClampToEdge,
+            mag_filter: wgpu::FilterMode::Linear,
+            min_filter: wgpu::FilterMode::Linear,
+            ..Default::default()
+        });
+
+        let uniform_entry = wgpu::BindGroupLayoutEntry {
+            binding: 0,
+            visibility: wgpu::ShaderStages::COMPUTE,
+            ty: wgpu::BindingType::Buffer {
+                ty: wgpu::BufferBindingType::Uniform,
+                has_dynamic_offset: false,
+                min_binding_size: None,
+            },
+            count: None,
+        };
+        let storage_entry = |binding| wgpu::BindGroupLayoutEntry {
+            binding,
+            visibility: wgpu::ShaderStages::COMPUTE,
+            ty: wgpu::BindingType::Buffer {
+                ty: wgpu::BufferBindingType::Storage { read_only: false },
+                has_dynamic_offset: false,
+                min_binding_size: None,
+            },
+            count: None,
+        };
+        let transmittance_layout = device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
+            entries: &[uniform_entry, storage_entry(1)],
+            label: Some("atmosphere_transmittance_bind_group_layout"),
+        });
+        let multiscattering_layout = device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
+            entries: &[
+                uniform_entry,
+                wgpu::BindGroupLayoutEntry {
+                    binding: 2,
+                    visibility: wgpu::ShaderStages::COMPUTE,
+                    ty: wgpu::BindingType::Texture {
+                        multisampled: false,
+                        view_dimension: wgpu::TextureViewDimension::D2,
+                        sample_type: wgpu::TextureSampleType::Float { filterable: true },
+                    },
+                    count: None,
+                },
+                wgpu::BindGroupLayoutEntry {
+                    binding: 3,
+                    visibility: wgpu::ShaderStages::COMPUTE,
+                    ty: wgpu::BindingType::Sampler(wgpu::SamplerBindingType::Filtering),
+                    count: None,
+                },
+                storage_entry(4),
+            ],
+            label: Some("atmosphere_multiscattering_bind_group_layout"),
+        });
+        let transmittance_bind_group = device.create_bind_group(&wgpu::BindGroupDescriptor {
+            layout: &transmittance_layout,
+            entries: &[
+                wgpu::BindGroupEntry {
+                    binding: 0,
+                    resource: uniform_buffer.as_entire_binding(),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 1,
+                    resource: transmittance_rows.as_entire_binding(),
+                },
+            ],
+            label: Some("atmosphere_transmittance_bind_group"),
+        });
+        let multiscattering_bind_group = device.create_bind_group(&wgpu::BindGroupDescriptor {
+            layout: &multiscattering_layout,
+            entries: &[
+                wgpu::BindGroupEntry {
+                    binding: 0,
+                    resource: uniform_buffer.as_entire_binding(),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 2,
+                    resource: wgpu::BindingResource::TextureView(&transmittance_view),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 3,
+                    resource: wgpu::BindingResource::Sampler(&sampler),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 4,
+                    resource: multiscattering_rows.as_entire_binding(),
+                },
+            ],
+            label: Some("atmosphere_multiscattering_bind_group"),
+        });
+
+        let mut shaders = ShaderPreprocessor::new();
+        shaders.set_constant("TRANSMITTANCE_WIDTH", width);
+        shaders.set_constant("TRANSMITTANCE_HEIGHT", height);
+        shaders.set_constant("MULTISCATTERING_SIZE", size);
+        let shader = shaders
+            .descriptor("Atmosphere LUT Shader", "atmosphere_lut.wgsl")
+            .expect("Failed to preprocess atmosphere_lut.wgsl");
+        let shader = device.create_shader_module(shader);
+        let pipeline = |label, layout: &wgpu::BindGroupLayout, entry_point| {
+            let pipeline_layout = device.create_pipeline_layout(&wgpu::PipelineLayoutDescriptor {
+                label: Some(label),
+                bind_group_layouts: &[layout],
+                push_constant_ranges: &[],
+            });
+            device.create_compute_pipeline(&wgpu::ComputePipelineDescriptor {
+                label: Some(label),
+                layout: Some(&pipeline_layout),
+                module: &shader,
+                entry_point,
+            })
+        };
+        let transmittance_pipeline = pipeline("Atmosphere Transmittance Pipeline", &transmittance_layout, "transmittance_main");
+        let multiscattering_pipeline = pipeline(
+            "Atmosphere Multiple Scattering Pipeline",
+            &multiscattering_layout,
+            "multiscattering_main",
+        );
+
+        let atmosphere = Self {
+            model,
+            aerial_perspective: false,
+            uniform_buffer,
+            transmittance,
+            multiscattering,
+            transmittance_rows,
+            multiscattering_rows,
+            transmittance_view,
+            multiscattering_view,
+            sampler,
+            transmittance_pipeline,
+            transmittance_bind_group,
+            multiscattering_pipeline,
+            multiscattering_bind_group,
+        };
+        atmosphere.precompute(device, queue);
+        return atmosphere;
+    }
+
+    pub fn model(&self) -> &AtmosphereModel {
+        return &self.model;
+    }
+
+    /// Recompute the tables for `model`, if it changed.
+    pub fn set_model(&mut self, device: &wgpu::Device, queue: &wgpu::Queue, model: AtmosphereModel) {
+        if self.model != model {
+            self.model = model;
+            self.write_uniform(queue);
+            self.precompute(device, queue);
+        }
+    }
+
+    /// Haze lit surfaces with the atmosphere between them and the eye.
+    pub fn set_aerial_perspective(&mut self, queue: &wgpu::Queue, enabled: bool) {
+        if self.aerial_perspective != enabled {
+            self.aerial_perspective = enabled;
+            self.write_uniform(queue);
+        }
+    }
+
+    fn write_uniform(&self, queue: &wgpu::Queue) {
+        let uniform = AtmosphereUniform::new(&self.model, self.aerial_perspective);
+        queue.write_buffer(&self.uniform_buffer, 0, bytemuck::cast_slice(&[uniform]));
+    }
+
+    // Transmittance first, multiple scattering reads it, so each table is
+    // copied out before the next pass
+    fn precompute(&self, device: &wgpu::Device, queue: &wgpu::Queue) {
+        let mut encoder = device.create_command_encoder(&wgpu::CommandEncoderDescriptor {
+            label: Some("Atmosphere Encoder"),
+        });
+        let [width, height] = Self::TRANSMITTANCE_SIZE;
+        let size = Self::MULTISCATTERING_SIZE;
+        let passes = [
+            (
+                &self.transmittance_pipeline,
+                &self.transmittance_bind_group,
+                &self.transmittance_rows,
+                &self.transmittance,
+                width,
+                height,
+            ),
+            (
+                &self.multiscattering_pipeline,
+                &self.multiscattering_bind_group,
+                &self.multiscattering_rows,
+                &self.multiscattering,
+                size,
+                size,
+            ),
+        ];
+        for (pipeline, bind_group, rows, table, width, height) in passes {
+            {
+                let mut pass = encoder.begin_compute_pass(&wgpu::ComputePassDescriptor {
+                    label: Some("Atmosphere LUT Pass"),
+                });
+                pass.set_pipeline(pipeline);
+                pass.set_bind_group(0, bind_group, &[]);
+                pass.dispatch_workgroups(
+                    width.div_ceil(Self::WORKGROUP_SIZE),
+                    height.div_ceil(Self::WORKGROUP_SIZE),
+                    1,
+                );
+            }
+            // Row sizes are multiples of the 256 byte copy alignment
+            encoder.copy_buffer_to_texture(
+                wgpu::ImageCopyBuffer {
+                    buffer: rows,
+                    layout: wgpu::ImageDataLayout {
+                        offset: 0,
+                        bytes_per_row: std::num::NonZeroU32::new(width * Self::TEXEL_SIZE),
+                        rows_per_image: None,
+                    },
+                },
+                table.as_image_copy(),
+                wgpu::Extent3d {
+                    width,
+                    height,
+                    depth_or_array_layers: 1,
+                },
+            );
+        }
+        queue.submit(std::iter::once(encoder.finish()));
+    }
+
+    /// Layout entries of the atmosphere, starting at `first_binding`, for
+    /// bind groups that also carry other lighting data. Bindings are fixed in
+    /// atmosphere.wgsl.
+    pub fn layout_entries(first_binding: u32) -> [wgpu::BindGroupLayoutEntry; 4] {
+        let table = |binding| wgpu::BindGroupLayoutEntry {
+            binding,
+            visibility: wgpu::ShaderStages::FRAGMENT,
+            ty: wgpu::BindingType::Texture {
+                multisampled: false,
+                view_dimension: wgpu::TextureViewDimension::D2,
+                sample_type: wgpu::TextureSampleType::Float { filterable: true },
+            },
+            count: None,
+        };
+        return [
+            wgpu::BindGroupLayoutEntry {
+                binding: first_binding,
+                visibility: wgpu::ShaderStages::FRAGMENT,
+                ty: wgpu::BindingType::Buffer {
+                    ty: wgpu::BufferBindingType::Uniform,
+                    has_dynamic_offset: false,
+                    min_binding_size: None,
+                },
+                count: None,
+            },
+            table(first_binding + 1),
+            table(first_binding + 2),
+            wgpu::BindGroupLayoutEntry {
+                binding: first_binding + 3,
+                visibility: wgpu::ShaderStages::FRAGMENT,
+                ty: wgpu::BindingType::Sampler(wgpu::SamplerBindingType::Filtering),
+                count: None,
+            },
+        ];
+    }
+
+    /// Bind group entries matching `layout_entries`.
+    pub fn bind_entries(&self, first_binding: u32) -> [wgpu::BindGroupEntry<'_>; 4] {
+        return [
+            wgpu::BindGroupEntry {
+                binding: first_binding,
+                resource: self.uniform_buffer.as_entire_binding(),
+            },
+            wgpu::BindGroupEntry {
+                binding: first_binding + 1,
+                resource: wgpu::BindingResource::TextureView(&self.transmittance_view),
+            },
+            wgpu::BindGroupEntry {
+                binding: first_binding + 2,
+                resource: wgpu::BindingResource::TextureView(&self.multiscattering_view),
+            },
+            wgpu::BindGroupEntry {
+                binding: first_binding + 3,
+                resource: wgpu::BindingResource::Sampler(&self.sampler),
+            },
+        ];
+    }
+}
@@ -0,0 +1,214 @@
+// Physically based atmosphere with precomputed transmittance and multiple
+// scattering, after Hillaire, "A Scalable and Production Ready Sky and
+// Atmosphere Rendering Technique" (2020). See atmosphere.rs.
+//
+// Lengths are in km, positions relative to the planet's center. The tables
+// are bound in the light group, or at the start of group 0 with
+// ATMOSPHERE_PRECOMPUTE defined, which leaves out everything needing the
+// multiple scattering table.
+
+struct Atmosphere {
+    // Rayleigh scattering per km at the ground in rgb, its scale height in w
+    rayleigh: vec4<f32>,
+    // x: Mie scattering per km at the ground, y: Mie extinction, z: scale
+    // height, w: asymmetry
+    mie: vec4<f32>,
+    // Ozone absorption per km at its peak in rgb, altitude of the peak in w
+    ozone: vec4<f32>,
+    // x: planet radius, y: radius of the top of the atmosphere, z: half width
+    // of the ozone layer, w: km per world unit
+    radii: vec4<f32>,
+    // Ground albedo in rgb, altitude of the world origin in w
+    ground: vec4<f32>,
+    // Sun illuminance for a light strength of 1 in rgb, w: 1 to apply aerial
+    // perspective to lit surfaces
+    sun: vec4<f32>,
+};
+
+#ifdef ATMOSPHERE_PRECOMPUTE
+@group(0) @binding(0)
+var<uniform> atmosphere: Atmosphere;
+@group(0) @binding(2)
+var t_transmittance: texture_2d<f32>;
+@group(0) @binding(3)
+var s_atmosphere: sampler;
+#else
+@group(2) @binding(8)
+var<uniform> atmosphere: Atmosphere;
+@group(2) @binding(9)
+var t_transmittance: texture_2d<f32>;
+@group(2) @binding(10)
+var t_multiscattering: texture_2d<f32>;
+@group(2) @binding(11)
+var s_atmosphere: sampler;
+#endif
+
+struct Medium {
+    rayleigh: vec3<f32>,
+    mie: f32,
+    scattering: vec3<f32>,
+    extinction: vec3<f32>,
+};
+
+fn atmosphere_medium(altitude: f32) -> Medium {
+    let rayleigh_density = exp(-altitude / atmosphere.rayleigh.w);
+    let mie_density = exp(-altitude / atmosphere.mie.z);
+    let ozone_density = max(1.0 - abs(altitude - atmosphere.ozone.w) / atmosphere.radii.z, 0.0);
+    var medium: Medium;
+    medium.rayleigh = atmosphere.rayleigh.rgb * rayleigh_density;
+    medium.mie = atmosphere.mie.x * mie_density;
+    medium.scattering = medium.rayleigh + medium.mie;
+    medium.extinction = medium.rayleigh + atmosphere.mie.y * mie_density + atmosphere.ozone.rgb * ozone_density;
+    return medium;
+}
+
+// Distance along the ray to a sphere around the planet's center, the nearest
+// hit in front of `origin`, negative without one
+fn ray_sphere(origin: vec3<f32>, direction: vec3<f32>, radius: f32) -> f32 {
+    let b = dot(origin, direction);
+    let c = dot(origin, origin) - radius * radius;
+    let discriminant = b * b - c;
+    if (discriminant < 0.0) {
+        return -1.0;
+    }
+    let s = sqrt(discriminant);
+    if (-b - s >= 0.0) {
+        return -b - s;
+    }
+    return -b + s;
+}
+
+// Distance to where the ray leaves the atmosphere or hits the ground
+fn atmosphere_ray_length(origin: vec3<f32>, direction: vec3<f32>) -> f32 {
+    let ground = ray_sphere(origin, direction, atmosphere.radii.x);
+    if (ground > 0.0) {
+        return ground;
+    }
+    return max(ray_sphere(origin, direction, atmosphere.radii.y), 0.0);
+}
+
+fn rayleigh_phase(cos_theta: f32) -> f32 {
+    return 3.0 / (16.0 * 3.14159265) * (1.0 + cos_theta * cos_theta);
+}
+
+// Cornette-Shanks
+fn mie_phase(cos_theta: f32, g: f32) -> f32 {
+    let g2 = g * g;
+    let denominator = (2.0 + g2) * pow(max(1.0 + g2 - 2.0 * g * cos_theta, 0.0001), 1.5);
+    return 3.0 / (8.0 * 3.14159265) * (1.0 - g2) * (1.0 + cos_theta * cos_theta) / denominator;
+}
+
+// Transmittance table coordinates of a ray starting at radius `r` with
+// cosine `mu` to the zenith, mapped so the horizon gets more resolution
+fn transmittance_uv(r: f32, mu: f32) -> vec2<f32> {
+    let bottom = atmosphere.radii.x;
+    let top = atmosphere.radii.y;
+    let h = sqrt(top * top - bottom * bottom);
+    let rho = sqrt(max(r * r - bottom * bottom, 0.0));
+    let discriminant = r * r * (mu * mu - 1.0) + top * top;
+    let d = max(-r * mu + sqrt(max(discriminant, 0.0)), 0.0);
+    let d_min = top - r;
+    let d_max = rho + h;
+    return vec2<f32>((d - d_min) / (d_max - d_min), rho / h);
+}
+
+// Inverse of `transmittance_uv`, radius and cosine
+fn transmittance_ray(uv: vec2<f32>) -> vec2<f32> {
+    let bottom = atmosphere.radii.x;
+    let top = atmosphere.radii.y;
+    let h = sqrt(top * top - bottom * bottom);
+    let rho = h * uv.y;
+    let r = sqrt(rho * rho + bottom * bottom);
+    let d_min = top - r;
+    let d_max = rho + h;
+    let d = d_min + uv.x * (d_max - d_min);
+    var mu = 1.0;
+    if (d > 0.0) {
+        mu = clamp((h * h - rho * rho - d * d) / (2.0 * r * d), -1.0, 1.0);
+    }
+    return vec2<f32>(r, mu);
+}
+
+// Transmittance from a point at `position` towards the sun, zero where the
+// planet is in the way
+fn sun_transmittance(position: vec3<f32>, to_sun: vec3<f32>) -> vec3<f32> {
+    if (ray_sphere(position, to_sun, atmosphere.radii.x) > 0.0) {
+        return vec3<f32>(0.0);
+    }
+    let r = length(position);
+    let uv = transmittance_uv(r, dot(position, to_sun) / r);
+    return textureSampleLevel(t_transmittance, s_atmosphere, uv, 0.0).rgb;
+}
+
+#ifndef ATMOSPHERE_PRECOMPUTE
+// Light scattered more than once, per unit of scattering coefficient
+fn multiscattering(position: vec3<f32>, to_sun: vec3<f32>) -> vec3<f32> {
+    let r = length(position);
+    let altitude = (r - atmosphere.radii.x) / (atmosphere.radii.y - atmosphere.radii.x);
+    let uv = vec2<f32>(dot(position, to_sun) / r * 0.5 + 0.5, altitude);
+    return textureSampleLevel(t_multiscattering, s_atmosphere, uv, 0.0).rgb;
+}
+
+// Position of a world space point relative to the planet's center
+fn atmosphere_position(world_position: vec3<f32>) -> vec3<f32> {
+    let position = world_position * atmosphere.radii.w + vec3<f32>(0.0, atmosphere.radii.x + atmosphere.ground.w, 0.0);
+    // Keep just above the ground, below it every ray would end right away
+    let r = length(position);
+    return position * (max(r, atmosphere.radii.x + 0.001) / max(r, 0.0001));
+}
+
+// Light scattered towards the eye per unit of path, sun light scattered once
+// plus the precomputed higher orders
+fn atmosphere_in_scattering(medium: Medium, position: vec3<f32>, to_sun: vec3<f32>, cos_theta: f32) -> vec3<f32> {
+    let phase = medium.rayleigh * rayleigh_phase(cos_theta) + medium.mie * mie_phase(cos_theta, atmosphere.mie.w);
+    return phase * sun_transmittance(position, to_sun) + medium.scattering * multiscattering(position, to_sun);
+}
+
+// Sky radiance seen from `eye` along `direction` for a sun of unit
+// illuminance, marched until the ray leaves the atmosphere or hits the ground
+fn sky_radiance(eye: vec3<f32>, direction: vec3<f32>, to_sun: vec3<f32>) -> vec3<f32> {
+    let steps = 32;
+    let ray_length = atmosphere_ray_length(eye, direction);
+    let cos_theta = dot(direction, to_sun);
+    var radiance = vec3<f32>(0.0);
+    var throughput = vec3<f32>(1.0);
+    var t = 0.0;
+    for (var i = 0; i < steps; i++) {
+        // Denser steps close to the eye, where the air is thickest
+        let next = ray_length * pow((f32(i) + 1.0) / f32(steps), 2.0);
+        let dt = next - t;
+        let position = eye + direction * (t + dt * 0.5);
+        let medium = atmosphere_medium(length(position) - atmosphere.radii.x);
+        let transmittance = exp(-medium.extinction * dt);
+        let scattering = atmosphere_in_scattering(medium, position, to_sun, cos_theta);
+        // Analytic integral over the step, see the paper
+        let integrated = (scattering - scattering * transmittance) / max(medium.extinction, vec3<f32>(0.000001));
+        radiance += throughput * integrated;
+        throughput *= transmittance;
+        t = next;
+    }
+    return radiance;
+}
+
+struct AerialPerspective {
+    // Light scattered into the path, for a sun of unit illuminance
+    in_scattering: vec3<f32>,
+    transmittance: vec3<f32>,
+};
+
+// Haze between the eye and a surface, both world space. Scenes are small
+// next to the scale heights, so the medium at the middle of the path stands
+// in for all of it.
+fn aerial_perspective(eye: vec3<f32>, surface: vec3<f32>, to_sun: vec3<f32>) -> AerialPerspective {
+    let distance = length(surface - eye) * atmosphere.radii.w;
+    let direction = normalize(surface - eye);
+    let middle = atmosphere_position((eye + surface) * 0.5);
+    let medium = atmosphere_medium(length(middle) - atmosphere.radii.x);
+    let scattering = atmosphere_in_scattering(medium, middle, to_sun, dot(direction, to_sun));
+
+    var out: AerialPerspective;
+    out.transmittance = exp(-medium.extinction * distance);
+    out.in_scattering = (scattering - scattering * out.transmittance) / max(medium.extinction, vec3<f32>(0.000001));
+    return out;
+}
+#endif
@@ -0,0 +1,104 @@
+// Precomputes the atmosphere's lookup tables, see atmosphere.rs. The
+// transmittance table has to be done before the multiple scattering one,
+// which reads it. Tables are written as rows of half floats, copied into
+// textures afterwards: the GL backend has no storage textures.
+#define ATMOSPHERE_PRECOMPUTE
+#include "atmosphere.wgsl"
+// Table sizes in texels, specialized from atmosphere.rs
+override TRANSMITTANCE_WIDTH: i32 = 256;
+override TRANSMITTANCE_HEIGHT: i32 = 64;
+override MULTISCATTERING_SIZE: i32 = 32;
+// The atmosphere at binding 0, the transmittance table to read at 2 and 3
+@group(0) @binding(1)
+var<storage, read_write> transmittance_out: array<vec2<u32>>;
+@group(0) @binding(4)
+var<storage, read_write> multiscattering_out: array<vec2<u32>>;
+
+fn pack_half4(value: vec3<f32>) -> vec2<u32> {
+    return vec2<u32>(pack2x16float(value.rg), pack2x16float(vec2<f32>(value.b, 1.0)));
+}
+
+// Transmittance from a radius and zenith angle to the top of the atmosphere
+@compute @workgroup_size(8, 8)
+fn transmittance_main(@builtin(global_invocation_id) id: vec3<u32>) {
+    let size = vec2<i32>(TRANSMITTANCE_WIDTH, TRANSMITTANCE_HEIGHT);
+    if (i32(id.x) >= size.x || i32(id.y) >= size.y) {
+        return;
+    }
+    let uv = (vec2<f32>(id.xy) + 0.5) / vec2<f32>(size);
+    let ray = transmittance_ray(uv);
+    let origin = vec3<f32>(0.0, ray.x, 0.0);
+    let direction = vec3<f32>(sqrt(max(1.0 - ray.y * ray.y, 0.0)), ray.y, 0.0);
+    let ray_length = max(ray_sphere(origin, direction, atmosphere.radii.y), 0.0);
+
+    let steps = 40;
+    let dt = ray_length / f32(steps);
+    var optical_depth = vec3<f32>(0.0);
+    for (var i = 0; i < steps; i++) {
+        let position = origin + direction * (f32(i) + 0.5) * dt;
+        optical_depth += atmosphere_medium(length(position) - atmosphere.radii.x).extinction * dt;
+    }
+    transmittance_out[id.y * u32(size.x) + id.x] = pack_half4(exp(-optical_depth));
+}
+
+// Light scattered twice or more per unit of scattering coefficient, for an
+// altitude and sun zenith angle: second order scattering from all directions
+// around the point, scaled by the geometric series of the fraction that is
+// scattered again
+@compute @workgroup_size(8, 8)
+fn multiscattering_main(@builtin(global_invocation_id) id: vec3<u32>) {
+    let size = vec2<i32>(MULTISCATTERING_SIZE, MULTISCATTERING_SIZE);
+    if (i32(id.x) >= size.x || i32(id.y) >= size.y) {
+        return;
+    }
+    let uv = (vec2<f32>(id.xy) + 0.5) / vec2<f32>(size);
+    let cos_sun = uv.x * 2.0 - 1.0;
+    let r = mix(atmosphere.radii.x + 0.001, atmosphere.radii.y - 0.001, uv.y);
+    let origin = vec3<f32>(0.0, r, 0.0);
+    let to_sun = vec3<f32>(sqrt(max(1.0 - cos_sun * cos_sun, 0.0)), cos_sun, 0.0);
+    // Isotropic phase function
+    let phase = 1.0 / (4.0 * 3.14159265);
+
+    let directions = 8;
+    let steps = 20;
+    var second_order = vec3<f32>(0.0);
+    var transfer = vec3<f32>(0.0);
+    for (var i = 0; i < directions; i++) {
+        for (var j = 0; j < directions; j++) {
+            // Evenly spread over the sphere
+            let cos_theta = 1.0 - 2.0 * (f32(i) + 0.5) / f32(directions);
+            let phi = 2.0 * 3.14159265 * (f32(j) + 0.5) / f32(directions);
+            let sin_theta = sqrt(max(1.0 - cos_theta * cos_theta, 0.0));
+            let direction = vec3<f32>(sin_theta * cos(phi), cos_theta, sin_theta * sin(phi));
+
+            let ray_length = atmosphere_ray_length(origin, direction);
+            let dt = ray_length / f32(steps);
+            var throughput = vec3<f32>(1.0);
+            for (var k = 0; k < steps; k++) {
+                let position = origin + direction * (f32(k) + 0.5) * dt;
+                let medium = atmosphere_medium(length(position) - atmosphere.radii.x);
+                let transmittance = exp(-medium.extinction * dt);
+                let extinction = max(medium.extinction, vec3<f32>(0.000001));
+                let scattered = medium.scattering * phase * sun_transmittance(position, to_sun);
+                second_order += throughput * (scattered - scattered * transmittance) / extinction;
+                transfer += throughput * (medium.scattering - medium.scattering * transmittance) / extinction;
+                throughput *= transmittance;
+            }
+
+            // Sun light bouncing off the ground
+            let ground = ray_sphere(origin, direction, atmosphere.radii.x);
+            if (ground > 0.0) {
+                let position = origin + direction * ground;
+                let normal = normalize(position);
+                let lit = max(dot(normal, to_sun), 0.0) * sun_transmittance(position + normal * 0.001, to_sun);
+                second_order += throughput * lit * atmosphere.ground.rgb / 3.14159265;
+            }
+        }
+    }
+    let count = f32(directions * directions);
+    second_order = second_order / count;
+    // Isotropic scattering of what's scattered on, over all directions
+    transfer = transfer * phase * 4.0 * 3.14159265 / count;
+    let multiscattered = second_order / (1.0 - min(transfer, vec3<f32>(0.99)));
+    multiscattering_out[id.y * u32(size.x) + id.x] = pack_half4(multiscattered);
+}
@@ -0,0 +1,262 @@
+use std::sync::Arc;
+
+use crate::{
+    memory::{MemoryCategory, MemoryTracker, TrackedBuffer},
+    pipelines::{PipelineCache, PipelineDescriptor},
+    post::PostProcess,
+    settings::{BackgroundMode, BackgroundSettings, ImageFit},
+    shader::ShaderPreprocessor,
+    texture::Texture,
+};
+
+#[repr(C)]
+#[derive(Debug, Copy, Clone, bytemuck::Pod, bytemuck::Zeroable)]
+struct BackgroundUniform {
+    mode: u32,
+    fit: u32,
+    image_aspect: f32,
+    _padding: f32,
+    color: [f32; 4],
+    zenith: [f32; 4],
+    horizon: [f32; 4],
+}
+
+impl BackgroundUniform {
+    // `image_aspect` is `None` without an image
+    fn new(settings: &BackgroundSettings, image_aspect: Option<f32>) -> Self {
+        let mode = match settings.mode {
+            BackgroundMode::Color => 0,
+            BackgroundMode::Gradient => 1,
+            BackgroundMode::Image if image_aspect.is_some() => 2,
+            BackgroundMode::Image => 0,
+            BackgroundMode::Atmosphere => 3,
+        };
+        let fit = match settings.image_fit {
+            ImageFit::Stretch => 0,
+            ImageFit::Contain => 1,
+            ImageFit::Cover => 2,
+        };
+        let rgba = |c: [f32; 3]| [c[0], c[1], c[2], 1.0];
+        return Self {
+            mode,
+            fit,
+            image_aspect: image_aspect.unwrap_or(1.0),
+            _padding: 0.0,
+            color: rgba(settings.color),
+            zenith: rgba(settings.zenith),
+            horizon: rgba(settings.horizon),
+        };
+    }
+}
+
+/// Gradient sky, atmosphere or screen-fixed backdrop image drawn behind the
+/// scene, a fullscreen triangle at the far plane. The plain color mode only
+/// clears.
+pub struct Background {
+    settings: BackgroundSettings,
+    image: Option<Texture>,
+    // Width over height of `image`
+    image_aspect: f32,
+    // Bound while there is no image
+    placeholder: Texture,
+    uniform_buffer: TrackedBuffer,
+    bind_group_layout: wgpu::BindGroupLayout,
+    bind_group: wgpu::BindGroup,
+    pipeline: Arc<wgpu::RenderPipeline>,
+}
+
+impl Background {
+    #[allow(clippy::too_many_arguments)]
+    pub fn new(
+        device: &wgpu::Device,
+        memory: &MemoryTracker,
+        queue: &wgpu::Queue,
+        pipelines: &mut PipelineCache,
+        shaders: &ShaderPreprocessor,
+        camera_bind_group_layout: &wgpu::BindGroupLayout,
+        light_bind_group_layout: &wgpu::BindGroupLayout,
+        depth_format: wgpu::TextureFormat,
+        multisample: wgpu::MultisampleState,
+        settings: BackgroundSettings,
+    ) -> Self {
+        let placeholder = Texture::from_image(
+            device,
+            memory,
+            queue,
+            &image::DynamicImage::new_rgba8(1, 1),
+            Some("Background Placeholder"),
+            false,
+        )
+        .expect("Failed to create the background placeholder");
+        let uniform_buffer = memory.create_buffer_init(
+            device,
+            &wgpu::util::BufferInitDescriptor {
+                label: Some("Background Buffer"),
+                contents: bytemuck::cast_slice(&[BackgroundUniform::new(&settings, None)]),
+                usage: wgpu::BufferUsages::UNIFORM | wgpu::BufferUsages::COPY_DST,
+            },
+            MemoryCategory::Uniform,
+        );
+
+        let bind_group_layout = device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
+            entries: &[
+                wgpu::BindGroupLayoutEntry {
+                    binding: 0,
+                    visibility: wgpu::ShaderStages::FRAGMENT,
+                    ty: wgpu::BindingType::Buffer {
+                        ty: wgpu::BufferBindingType::Uniform,
+                        has_dynamic_offset: false,
+                        min_binding_size: None,
+                    },
+                    count: None,
+                },
+                wgpu::BindGroupLayoutEntry {
+                    binding: 1,
+                    visibility: wgpu::ShaderStages::FRAGMENT,
+                    ty: wgpu::BindingType::Texture {
+                        multisampled: false,
+                        view_dimension: wgpu::TextureViewDimension::D2,
+                        sample_type: wgpu::TextureSampleType::Float { filterable: true },
+                    },
+                    count: None,
+                },
+                wgpu::BindGroupLayoutEntry {
+                    binding: 2,
+                    visibility: wgpu::ShaderStages::FRAGMENT,
+                    ty: wgpu::BindingType::Sampler(wgpu::SamplerBindingType::Filtering),
+                    count: None,
+                },
+            ],
+            label: Some("background_bind_group_layout"),
+        });
+        let bind_group = Self::create_bind_group(device, &bind_group_layout, &uniform_buffer, &placeholder);
+
+        let shader = shaders
+            .process("background.wgsl")
+            .expect("Failed to preprocess background.wgsl");
+        let pipeline = pipelines.pipeline(&PipelineDescriptor {
+            label: "Background Pipeline",
+            layout: "Background Pipeline Layout",
+            bind_group_layouts: &[camera_bind_group_layout, &bind_group_layout, light_bind_group_layout],
+            shader: &shader,
+            vertex_layouts: &[],
+            color_format: Some(PostProcess::SCENE_FORMAT),
+            depth_format: Some(depth_format),
+            blend: wgpu::BlendState::REPLACE,
+            cull_mode: Some(wgpu::Face::Back),
+            multisample,
+            order_independent: false,
+        });
+
+        return Self {
+            settings,
+            image: None,
+            image_aspect: 1.0,
+            placeholder,
+            uniform_buffer,
+            bind_group_layout,
+            bind_group,
+            pipeline,
+        };
+    }
+
+    fn create_bind_group(
+        device: &wgpu::Device,
+        layout: &wgpu::BindGroupLayout,
+        uniform_buffer: &TrackedBuffer,
+        image: &Texture,
+    ) -> wgpu::BindGroup {
+        return device.create_bind_group(&wgpu::BindGroupDescriptor {
+            layout,
+            entries: &[
+                wgpu::BindGroupEntry {
+                    binding: 0,
+                    resource: uniform_buffer.as_entire_binding(),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 1,
+                    resource: wgpu::BindingResource::TextureView(&image.view),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 2,
+                    resource: wgpu::BindingResource::Sampler(&image.sampler),
+                },
+            ],
+            label: Some("background_bind_group"),
+        });
+    }
+
+    pub fn image(&self) -> Option<&Texture> {
+        return self.image.as_ref();
+    }
+
+    /// Upload the image shown in `BackgroundMode::Image`, `None` removes it.
+    pub fn set_image(
+        &mut self,
+        device: &wgpu::Device,
+        memory: &MemoryTracker,
+        queue: &wgpu::Queue,
+        image: Option<&image::DynamicImage>,
+    ) -> anyhow::Result<()> {
+        self.image = match image {
+            Some(image) => {
+                self.image_aspect = image.width() as f32 / image.height().max(1) as f32;
+                Some(Texture::from_image(device, memory, queue, image, Some("Background Image"), false)?)
+            }
+            None => None,
+        };
+        let texture = self.image.as_ref().unwrap_or(&self.placeholder);
+        self.bind_group = Self::create_bind_group(device, &self.bind_group_layout, &self.uniform_buffer, texture);
+        self.write_uniform(queue);
+        return Ok(());
+    }
+
+    pub fn update(&mut self, queue: &wgpu::Queue, settings: BackgroundSettings) {
+        if self.settings != settings {
+            self.settings = settings;
+            self.write_uniform(queue);
+        }
+    }
+
+    fn write_uniform(&self, queue: &wgpu::Queue) {
+        let image_aspect = self.image.as_ref().map(|_| self.image_aspect);
+        let uniform = BackgroundUniform::new(&self.settings, image_aspect);
+        queue.write_buffer(&self.uniform_buffer, 0, bytemuck::cast_slice(&[uniform]));
+    }
+
+    /// Color to clear the scene to, all the background there is in color mode.
+    pub fn clear_color(&self) -> wgpu::Color {
+        let [r, g, b] = self.settings.color;
+        return wgpu::Color {
+            r: r as f64,
+            g: g as f64,
+            b: b as f64,
+            a: 1.0,
+        };
+    }
+
+    pub fn is_drawn(&self) -> bool {
+        return match self.settings.mode {
+            BackgroundMode::Color => false,
+            BackgroundMode::Gradient | BackgroundMode::Atmosphere => true,
+            BackgroundMode::Image => self.image.is_some(),
+        };
+    }
+
+    /// The light bind group carries the atmosphere.
+    pub fn render<'a>(
+        &'a self,
+        render_pass: &mut wgpu::RenderPass<'a>,
+        camera_bind_group: &'a wgpu::BindGroup,
+        light_bind_group: &'a wgpu::BindGroup,
+    ) {
+        if !self.is_drawn() {
+            return;
+        }
+        render_pass.set_pipeline(&self.pipeline);
+        render_pass.set_bind_group(0, camera_bind_group, &[]);
+        render_pass.set_bind_group(1, &self.bind_group, &[]);
+        render_pass.set_bind_group(2, light_bind_group, &[]);
+        render_pass.draw(0..3, 0..1);
+    }
+}
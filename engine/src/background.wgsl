@@ -0,0 +1,119 @@
+// Background drawn behind the scene, see background.rs
+#include "camera.wgsl"
+#include "scene.wgsl"
+@group(0) @binding(0)
+var<uniform> camera: Camera;
+@group(0) @binding(1)
+var<uniform> scene: Scene;
+// Bound with the light group
+#include "atmosphere.wgsl"
+
+struct Background {
+    // 1 gradient, 2 image, 3 atmosphere, anything else the plain color
+    mode: u32,
+    // 0 stretch, 1 contain, 2 cover
+    fit: u32,
+    // Width over height of the image
+    image_aspect: f32,
+    _padding: f32,
+    color: vec4<f32>,
+    zenith: vec4<f32>,
+    horizon: vec4<f32>,
+};
+@group(1) @binding(0)
+var<uniform> background: Background;
+@group(1) @binding(1)
+var t_image: texture_2d<f32>;
+@group(1) @binding(2)
+var s_image: sampler;
+
+// Just in front of the far plane, so everything drawn covers it
+let FAR_DEPTH: f32 = 0.99999;
+// Radiance of the sun disc per unit of illuminance. Far dimmer than the real
+// sun, which would swamp bloom and exposure.
+let SUN_DISC_RADIANCE: f32 = 20.0;
+// Cosine of the sun's angular radius
+let SUN_DISC_COS: f32 = 0.99996;
+
+struct VertexOutput {
+    @builtin(position) clip_position: vec4<f32>,
+    @location(0) ndc: vec2<f32>,
+};
+
+// Fullscreen triangle, no vertex buffer needed
+@vertex
+fn vs_main(@builtin(vertex_index) index: u32) -> VertexOutput {
+    let x = f32(index & 1u) * 4.0 - 1.0;
+    let y = f32(index >> 1u) * 4.0 - 1.0;
+
+    var out: VertexOutput;
+    out.clip_position = vec4<f32>(x, y, FAR_DEPTH, 1.0);
+    out.ndc = vec2<f32>(x, y);
+    return out;
+}
+
+fn view_direction(ndc: vec2<f32>) -> vec3<f32> {
+    let far = camera.inv_view_proj * vec4<f32>(ndc, 1.0, 1.0);
+    return normalize(far.xyz * (1.0 / far.w) - camera.view_pos.xyz);
+}
+
+fn gradient(ndc: vec2<f32>) -> vec3<f32> {
+    let direction = view_direction(ndc);
+    // Square root keeps the horizon band narrow, like a real sky
+    let t = sqrt(clamp(direction.y, 0.0, 1.0));
+    return mix(background.horizon.rgb, background.zenith.rgb, t);
+}
+
+// Scattered sun light, black without a sun
+fn sky(ndc: vec2<f32>) -> vec3<f32> {
+    let sun = scene.sun_direction;
+    if (dot(sun.xyz, sun.xyz) == 0.0) {
+        return vec3<f32>(0.0);
+    }
+    let direction = view_direction(ndc);
+    let to_sun = -normalize(sun.xyz);
+    let eye = atmosphere_position(camera.view_pos.xyz);
+    let illuminance = atmosphere.sun.rgb * sun.w;
+    var color = sky_radiance(eye, direction, to_sun) * illuminance;
+    if (dot(direction, to_sun) > SUN_DISC_COS && ray_sphere(eye, direction, atmosphere.radii.x) < 0.0) {
+        color += sun_transmittance(eye, to_sun) * illuminance * SUN_DISC_RADIANCE;
+    }
+    return color;
+}
+
+fn image_uv(ndc: vec2<f32>) -> vec2<f32> {
+    let uv = vec2<f32>(ndc.x * 0.5 + 0.5, 0.5 - ndc.y * 0.5);
+    let screen_aspect = scene.screen_size.x / max(scene.screen_size.y, 1.0);
+    // Above 1 the screen is wider than the image
+    let ratio = screen_aspect / max(background.image_aspect, 0.0001);
+    var scale = vec2<f32>(1.0, 1.0);
+    if (background.fit == 1u) {
+        if (ratio > 1.0) {
+            scale = vec2<f32>(ratio, 1.0);
+        } else {
+            scale = vec2<f32>(1.0, 1.0 / ratio);
+        }
+    } else if (background.fit == 2u) {
+        if (ratio > 1.0) {
+            scale = vec2<f32>(1.0, 1.0 / ratio);
+        } else {
+            scale = vec2<f32>(ratio, 1.0);
+        }
+    }
+    return (uv - 0.5) * scale + 0.5;
+}
+
+@fragment
+fn fs_main(in: VertexOutput) -> @location(0) vec4<f32> {
+    let uv = image_uv(in.ndc);
+    let image = textureSample(t_image, s_image, uv).rgb;
+    var color = background.color.rgb;
+    if (background.mode == 1u) {
+        color = gradient(in.ndc);
+    } else if (background.mode == 3u) {
+        color = sky(in.ndc);
+    } else if (background.mode == 2u && all(uv >= vec2<f32>(0.0)) && all(uv <= vec2<f32>(1.0))) {
+        color = image;
+    }
+    return vec4<f32>(color, 1.0);
+}
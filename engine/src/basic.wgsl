@@ -1,34 +1,39 @@
-struct Camera {
-    view_pos: vec4<f32>,
-    view_proj: mat4x4<f32>,
-};
+#include "camera.wgsl"
 @group(1) @binding(0)
 var<uniform> camera: Camera;
+#include "scene.wgsl"
+@group(1) @binding(1)
+var<uniform> scene: Scene;
 
-// Lights
-struct DirectionalLight {
-    color_strength: vec4<f32>,
-    direction: vec3<f32>,
-};
-struct PointLight {
-    color: vec3<f32>,
-    attenuation: vec3<f32>,
-    position: vec3<f32>,
-};
-struct SpotLight {
-    base: PointLight,
-    direction_ccos: vec4<f32>,
-};
-struct LightBuffer {
-    ambients: array<vec4<f32>, 1>,
-    dirs: array<DirectionalLight, 10>,
-    points: array<PointLight, 256>,
-    spots: array<SpotLight, 256>,
-    lens: vec4<u32>,
-}
+#include "lights.wgsl"
 @group(2) @binding(0)
 var<uniform> lights: LightBuffer;
 
+// Image based lighting, see environment.rs
+struct EnvironmentParams {
+    intensity: f32,
+    max_lod: f32,
+};
+@group(2) @binding(1)
+var t_irradiance: texture_cube<f32>;
+@group(2) @binding(2)
+var t_prefiltered: texture_cube<f32>;
+@group(2) @binding(3)
+var t_brdf_lut: texture_2d<f32>;
+@group(2) @binding(4)
+var s_environment: sampler;
+@group(2) @binding(5)
+var<uniform> environment: EnvironmentParams;
+@group(2) @binding(6)
+var t_shadow: texture_depth_2d;
+@group(2) @binding(7)
+var s_shadow: sampler_comparison;
+// Bindings 8 to 11
+#include "atmosphere.wgsl"
+#ifdef ORDER_INDEPENDENT
+#include "oit.wgsl"
+#endif
+
 struct VertexInput {
     @location(0) position: vec3<f32>,
     @location(1) tex_coord: vec2<f32>,
@@ -37,16 +42,62 @@ struct VertexInput {
     @location(4) bitangent: vec3<f32>
 };
 
-struct InstanceInput {
-    @location(5) model_matrix_0: vec4<f32>,
-    @location(6) model_matrix_1: vec4<f32>,
-    @location(7) model_matrix_2: vec4<f32>,
-    @location(8) model_matrix_3: vec4<f32>,
-    @location(9) normal_matrix_0: vec3<f32>,
-    @location(10) normal_matrix_1: vec3<f32>,
-    @location(11) normal_matrix_2: vec3<f32>
+#include "instance.wgsl"
+
+// Baked animation frames of skinned and vertex animated instances
+struct AnimationInput {
+    // Offsets of the two baked frames to blend between
+    @location(14) frames: vec2<u32>,
+    @location(15) blend: f32,
+};
+
+#ifdef SKINNING
+struct SkinInput {
+    @location(12) joints: vec4<u32>,
+    @location(13) weights: vec4<f32>,
 };
 
+@group(3) @binding(0)
+var<storage, read> joint_matrices: array<mat4x4<f32>>;
+
+fn calculate_skin_matrix(skin: SkinInput, animation: AnimationInput) -> mat4x4<f32> {
+    var result = mat4x4<f32>(
+        vec4<f32>(0.0, 0.0, 0.0, 0.0),
+        vec4<f32>(0.0, 0.0, 0.0, 0.0),
+        vec4<f32>(0.0, 0.0, 0.0, 0.0),
+        vec4<f32>(0.0, 0.0, 0.0, 0.0),
+    );
+    for (var i = 0; i < 4; i++) {
+        let joint = skin.joints[i];
+        let current = joint_matrices[animation.frames.x + joint];
+        let next = joint_matrices[animation.frames.y + joint];
+        result += (current * (1.0 - animation.blend) + next * animation.blend) * skin.weights[i];
+    }
+    return result;
+}
+#endif
+
+#ifdef VERTEX_ANIMATION
+// Position and normal of each vertex in each frame, see vat.rs
+@group(3) @binding(0)
+var t_vat_positions: texture_2d<f32>;
+@group(3) @binding(1)
+var t_vat_normals: texture_2d<f32>;
+
+fn vat_texel(index: u32) -> vec2<i32> {
+    let width = u32(textureDimensions(t_vat_positions).x);
+    return vec2<i32>(i32(index % width), i32(index / width));
+}
+#endif
+
+#ifdef LIGHTMAP
+// Baked light of static geometry, see lightmap.rs
+@group(3) @binding(0)
+var t_lightmap: texture_2d<f32>;
+@group(3) @binding(1)
+var s_lightmap: sampler;
+#endif
+
 struct VertexOutput {
     @builtin(position) clip_position: vec4<f32>,
     @location(0) tex_coord: vec2<f32>,
@@ -56,25 +107,61 @@ struct VertexOutput {
     @location(4) world_tangent: vec3<f32>,
     @location(5) world_bitangent: vec3<f32>,
     @location(6) world_normal: vec3<f32>,
+    @location(7) @interpolate(flat) fade: f32,
+#ifdef LIGHTMAP
+    @location(8) lightmap_uv: vec2<f32>,
+#endif
 };
 
+#ifndef OUTLINE
 @vertex
-fn vs_main(model: VertexInput, instance: InstanceInput) -> VertexOutput {
-    let model_matrix = mat4x4<f32>(
-        instance.model_matrix_0,
-        instance.model_matrix_1,
-        instance.model_matrix_2,
-        instance.model_matrix_3,
-    );
-    let normal_matrix = mat3x3<f32>(
-        instance.normal_matrix_0,
-        instance.normal_matrix_1,
-        instance.normal_matrix_2
-    );
-    let world_normal = (model_matrix * vec4<f32>(model.normal, 0.0)).xyz;
-    let world_tangent = normalize(normal_matrix * model.tangent);
-    let world_bitangent = normalize(normal_matrix * model.bitangent);
-    let world_position = model_matrix * vec4<f32>(model.position, 1.0);
+fn vs_main(
+    model: VertexInput,
+    instance: InstanceInput,
+#ifdef SKINNING
+    skin: SkinInput,
+    animation: AnimationInput,
+#endif
+#ifdef VERTEX_ANIMATION
+    animation: AnimationInput,
+    @builtin(vertex_index) vertex_index: u32,
+#endif
+#ifdef LIGHTMAP
+    @location(12) lightmap_uv: vec2<f32>,
+#endif
+) -> VertexOutput {
+    let transform = instance_transform(instance);
+    var model_matrix = transform.model;
+    var normal_matrix = transform.normal;
+    var position = model.position;
+    var normal = model.normal;
+    var tangent = model.tangent;
+    var bitangent = model.bitangent;
+#ifdef VERTEX_ANIMATION
+    let texel0 = vat_texel(animation.frames.x + vertex_index);
+    let texel1 = vat_texel(animation.frames.y + vertex_index);
+    position = mix(textureLoad(t_vat_positions, texel0, 0).xyz, textureLoad(t_vat_positions, texel1, 0).xyz, animation.blend);
+    normal = normalize(mix(textureLoad(t_vat_normals, texel0, 0).xyz, textureLoad(t_vat_normals, texel1, 0).xyz, animation.blend));
+    // Keep the rest pose tangent frame perpendicular to the baked normal
+    tangent = normalize(tangent - normal * dot(normal, tangent));
+    bitangent = normalize(bitangent - normal * dot(normal, bitangent));
+#endif
+#ifdef SKINNING
+    let skin_matrix = calculate_skin_matrix(skin, animation);
+    model_matrix = model_matrix * skin_matrix;
+    // The cofactor matrix is the inverse transpose scaled by the determinant,
+    // which normalizing removes
+    let skin_x = skin_matrix[0].xyz;
+    let skin_y = skin_matrix[1].xyz;
+    let skin_z = skin_matrix[2].xyz;
+    normal_matrix = normal_matrix * mat3x3<f32>(cross(skin_y, skin_z), cross(skin_z, skin_x), cross(skin_x, skin_y));
+#endif
+    // Tangents lie in the surface and follow the model matrix, normals stay
+    // perpendicular to it through the inverse transpose
+    let world_normal = normalize(normal_matrix * normal);
+    let world_tangent = normalize((model_matrix * vec4<f32>(tangent, 0.0)).xyz);
+    let world_bitangent = normalize((model_matrix * vec4<f32>(bitangent, 0.0)).xyz);
+    let world_position = model_matrix * vec4<f32>(position, 1.0);
     let tangent_matrix = transpose(mat3x3<f32>(
         world_tangent,
         world_bitangent,
@@ -90,8 +177,13 @@ fn vs_main(model: VertexInput, instance: InstanceInput) -> VertexOutput {
     out.world_tangent = world_tangent;
     out.world_bitangent = world_bitangent;
     out.world_normal = world_normal;
+    out.fade = transform.fade;
+#ifdef LIGHTMAP
+    out.lightmap_uv = lightmap_uv;
+#endif
     return out;
 }
+#endif
 
 @group(0) @binding(0)
 var t_diffuse: texture_2d<f32>;
@@ -102,6 +194,23 @@ var t_normal: texture_2d<f32>;
 @group(0)@binding(3)
 var s_normal: sampler;
 
+struct MaterialParams {
+    tint: vec4<f32>,
+    roughness: f32,
+    specular: f32,
+    // Alpha below this is cut out, 0 renders the material opaque
+    alpha_cutoff: f32,
+    // Diffuse texture coordinate scale (xy) and offset (zw), used for flipbooks
+    uv_transform: vec4<f32>,
+    // Toon shading with this many diffuse bands and a hard highlight, 0 for smooth shading
+    toon_bands: f32,
+    // Silhouette of outlined instances in pixels, 0 for none
+    outline_width: f32,
+    outline_color: vec4<f32>,
+};
+@group(0) @binding(4)
+var<uniform> material: MaterialParams;
+
 
 fn calculate_directional_light_color(light: DirectionalLight, object_normal: vec4<f32>, input: VertexOutput, tangent_light_position: vec3<f32>) -> vec3<f32> {
     let tangent_normal = object_normal.xyz * 2.0 - 1.0;
@@ -109,10 +218,18 @@ fn calculate_directional_light_color(light: DirectionalLight, object_normal: vec
     let view_dir = normalize(input.tangent_view_position - input.tangent_position);
     let half_dir = normalize(view_dir + light_dir);
 
-    let diffuse_strength = max(dot(tangent_normal, light_dir), 0.0) * light.color_strength.w;
+    var diffuse = max(dot(tangent_normal, light_dir), 0.0);
+    let shininess = exp2(10.0 * (1.0 - material.roughness));
+    var highlight = pow(max(dot(tangent_normal, half_dir), 0.0), shininess);
+    if (material.toon_bands > 0.0) {
+        diffuse = ceil(diffuse * material.toon_bands) / material.toon_bands;
+        highlight = smoothstep(0.45, 0.55, highlight);
+    }
+
+    let diffuse_strength = diffuse * light.color_strength.w;
     let diffuse_color = light.color_strength.xyz * diffuse_strength;
 
-    let specular_strength = pow(max(dot(tangent_normal, half_dir), 0.0), 32.0) * light.color_strength.w;
+    let specular_strength = highlight * material.specular * light.color_strength.w;
     let specular_color = light.color_strength.xyz * specular_strength;
 
     return diffuse_color + specular_color;
@@ -127,8 +244,10 @@ fn calculate_point_light_color(light: PointLight, object_normal: vec4<f32>, inpu
 
     let distance = length(tangent_light_position - input.tangent_position);
     let atteniuation = light.attenuation.x + light.attenuation.y * distance + light.attenuation.z * distance * distance;
+    // Fade to zero at the range so the early-out does not cut the light off visibly
+    let window = clamp(1.0 - pow(distance / light.range, 4.0), 0.0, 1.0);
 
-    return result / atteniuation;
+    return result / atteniuation * window * window;
 }
 
 fn calculate_spot_light_color(light: SpotLight, object_normal: vec4<f32>, input: VertexOutput, tangent_light_position: vec3<f32>, tangent_light_direction: vec3<f32>) -> vec3<f32> {
@@ -145,30 +264,261 @@ fn calculate_spot_light_color(light: SpotLight, object_normal: vec4<f32>, input:
     }
 }
 
+// Bound of the PCF loops, see `shadow::MAX_PCF_KERNEL`
+override MAX_PCF_KERNEL: i32 = 7;
+
+// Fraction of the light reaching `world_position` past the casters in shadow
+// map `index`. `scale` turns the normal bias into world units at this distance
+fn calculate_shadow(index: i32, world_position: vec3<f32>, normal: vec3<f32>, scale: f32) -> f32 {
+    if (index < 0) {
+        return 1.0;
+    }
+    let shadow = lights.shadows[index];
+    let clip = shadow.view_proj * vec4<f32>(world_position + normal * shadow.params.y * scale, 1.0);
+    if (clip.w <= 0.0) {
+        return 1.0;
+    }
+    let ndc = clip.xyz * (1.0 / clip.w);
+    // Outside of the shadow map counts as lit
+    if (any(abs(ndc.xy) > vec2<f32>(1.0)) || ndc.z > 1.0) {
+        return 1.0;
+    }
+    let uv = shadow.rect.xy + (ndc.xy * vec2<f32>(0.5, -0.5) + vec2<f32>(0.5)) * shadow.rect.zw;
+    let depth = ndc.z - shadow.params.x;
+    let texel = shadow.params.w;
+    // Taps stay inside the tile so neighbouring shadow maps don't bleed in
+    let low = shadow.rect.xy + vec2<f32>(texel * 0.5);
+    let high = shadow.rect.xy + shadow.rect.zw - vec2<f32>(texel * 0.5);
+    let taps = min(i32(shadow.params.z), MAX_PCF_KERNEL);
+    let center = vec2<f32>(f32(taps - 1) * 0.5);
+    var lit = 0.0;
+    for (var y = 0; y < taps; y++) {
+        for (var x = 0; x < taps; x++) {
+            let tap = uv + (vec2<f32>(f32(x), f32(y)) - center) * texel;
+            lit += textureSampleCompareLevel(t_shadow, s_shadow, clamp(tap, low, high), depth);
+        }
+    }
+    return lit / f32(taps * taps);
+}
+
+fn closest_point_on_rect(light: AreaLight, p: vec3<f32>) -> vec3<f32> {
+    let center = light.position_two_sided.xyz;
+    let right = light.half_right.xyz;
+    let up = light.half_up.xyz;
+    let d = p - center;
+    let x = clamp(dot(d, right) / dot(right, right), -1.0, 1.0);
+    let y = clamp(dot(d, up) / dot(up, up), -1.0, 1.0);
+    return center + right * x + up * y;
+}
+
+// Representative point approximation: diffuse uses the point of the rectangle
+// closest to the surface, specular the point closest to the reflection ray.
+fn calculate_area_light_color(light: AreaLight, object_normal: vec4<f32>, input: VertexOutput, tangent_matrix: mat3x3<f32>) -> vec3<f32> {
+    let world_position = input.world_position.xyz;
+    let tangent_normal = object_normal.xyz * 2.0 - 1.0;
+    let world_normal = normalize(transpose(tangent_matrix) * tangent_normal);
+    let light_normal = normalize(cross(light.half_right.xyz, light.half_up.xyz));
+
+    let diffuse_point = closest_point_on_rect(light, world_position);
+    var specular_point = diffuse_point;
+    let view_dir = normalize(camera.view_pos.xyz - world_position);
+    let reflected = reflect(-view_dir, world_normal);
+    let denom = dot(reflected, light_normal);
+    if (abs(denom) > 0.0001) {
+        let t = dot(light.position_two_sided.xyz - world_position, light_normal) / denom;
+        if (t > 0.0) {
+            specular_point = closest_point_on_rect(light, world_position + reflected * t);
+        }
+    }
+
+    // One-sided lights only emit along their normal
+    let to_surface = normalize(world_position - diffuse_point);
+    var facing = dot(light_normal, to_surface);
+    if (light.position_two_sided.w > 0.5) {
+        facing = abs(facing);
+    }
+    if (facing <= 0.0) {
+        return vec3<f32>(0.0, 0.0, 0.0);
+    }
+
+    let tangent_view_dir = normalize(input.tangent_view_position - input.tangent_position);
+    let diffuse_dir = normalize(tangent_matrix * diffuse_point - input.tangent_position);
+    let specular_dir = normalize(tangent_matrix * specular_point - input.tangent_position);
+    let half_dir = normalize(tangent_view_dir + specular_dir);
+
+    let shininess = exp2(10.0 * (1.0 - material.roughness));
+    let diffuse_strength = max(dot(tangent_normal, diffuse_dir), 0.0);
+    let specular_strength = pow(max(dot(tangent_normal, half_dir), 0.0), shininess) * material.specular;
+
+    // Behaves like a point light far away and stays finite close to the surface
+    let area = 4.0 * length(light.half_right.xyz) * length(light.half_up.xyz);
+    let distance = length(diffuse_point - world_position);
+    let falloff = area / (area + distance * distance);
+
+    return light.color_intensity.xyz * light.color_intensity.w * falloff * (diffuse_strength * facing + specular_strength);
+}
+
+// Split-sum approximation of the environment's diffuse and specular light.
+// Explicit levels keep the lookups valid after a discard.
+fn calculate_environment_color(object_normal: vec4<f32>, input: VertexOutput, tangent_matrix: mat3x3<f32>, albedo: vec3<f32>) -> vec3<f32> {
+    let normal = normalize(transpose(tangent_matrix) * (object_normal.xyz * 2.0 - 1.0));
+    let view = normalize(camera.view_pos.xyz - input.world_position.xyz);
+    let n_dot_v = max(dot(normal, view), 0.0);
+
+    let irradiance = textureSampleLevel(t_irradiance, s_environment, normal, 0.0).rgb;
+    let prefiltered = textureSampleLevel(t_prefiltered, s_environment, reflect(-view, normal), material.roughness * environment.max_lod).rgb;
+    let brdf = textureSampleLevel(t_brdf_lut, s_environment, vec2<f32>(n_dot_v, material.roughness), 0.0).rg;
+    // Dielectric reflectance, scaled like the other lights' specular
+    let specular = prefiltered * (0.04 * brdf.x + brdf.y) * material.specular;
+
+    return (irradiance * albedo + specular) * environment.intensity;
+}
+
+// 4x4 ordered dither threshold for screen-door transparency
+fn dither_threshold(position: vec2<f32>) -> f32 {
+    var bayer = array<f32, 16>(
+        0.0, 8.0, 2.0, 10.0,
+        12.0, 4.0, 14.0, 6.0,
+        3.0, 11.0, 1.0, 9.0,
+        15.0, 7.0, 13.0, 5.0
+    );
+    let p = vec2<u32>(position) % 4u;
+    return (bayer[p.y * 4u + p.x] + 0.5) / 16.0;
+}
+
+#ifndef OUTLINE
 @fragment
-fn fs_main(input: VertexOutput) -> @location(0) vec4<f32> {
-    let object_color: vec4<f32> = textureSample(t_diffuse, s_diffuse, input.tex_coord);
-    let object_normal: vec4<f32> = textureSample(t_normal, s_normal, input.tex_coord);
+fn fs_main(
+    input: VertexOutput,
+#ifdef DOUBLE_SIDED
+    @builtin(front_facing) front_facing: bool,
+#endif
+#ifdef ORDER_INDEPENDENT
+) -> OitOutput {
+#else
+) -> @location(0) vec4<f32> {
+#endif
+    let object_color: vec4<f32> = textureSample(t_diffuse, s_diffuse, input.tex_coord * material.uv_transform.xy + material.uv_transform.zw) * material.tint;
+
+#ifdef NORMAL_MAPPING
+    var object_normal: vec4<f32> = textureSample(t_normal, s_normal, input.tex_coord);
+#else
+    var object_normal = vec4<f32>(0.5, 0.5, 1.0, 1.0);
+#endif
+    // Baked light in rgb, occlusion of ambient light in alpha
+#ifdef LIGHTMAP
+    let baked = textureSample(t_lightmap, s_lightmap, input.lightmap_uv);
+#else
+    let baked = vec4<f32>(0.0, 0.0, 0.0, 1.0);
+#endif
+
+    var alpha = 1.0;
+    if (material.alpha_cutoff > 0.0) {
+        // Sharpen the cutout edge to about one pixel
+        alpha = clamp((object_color.a - material.alpha_cutoff) / max(fwidth(object_color.a), 0.0001) + 0.5, 0.0, 1.0);
+#ifndef ALPHA_TO_COVERAGE
+        if (alpha < dither_threshold(input.clip_position.xy)) {
+            discard;
+        }
+        alpha = 1.0;
+#endif
+    }
+#ifdef ORDER_INDEPENDENT
+    // Transparent materials blend by their texture and tint alpha
+    alpha = object_color.a;
+#endif
+
+    // User clip plane, after the derivatives above
+    if (dot(camera.clip_plane.xyz, input.world_position.xyz) + camera.clip_plane.w < 0.0) {
+        discard;
+    }
+
+    // LOD cross-fade, the two levels of an instance cover complementary pixels
+    let fade_threshold = dither_threshold(input.clip_position.xy);
+    if ((input.fade >= 0.0 && fade_threshold >= input.fade) || (input.fade < 0.0 && fade_threshold < -input.fade)) {
+        discard;
+    }
+
     let tangent_matrix = transpose(mat3x3<f32>(
         input.world_tangent,
         input.world_bitangent,
         input.world_normal
     ));
 
-    var result = vec3<f32>(0.0, 0.0, 0.0);
-    for(var i = 0u; i < lights.lens[0]; i++) {
+    var world_normal = normalize(input.world_normal);
+#ifdef DOUBLE_SIDED
+    // Back faces are lit as seen, the encoded tangent space normal mirrored
+    if (!front_facing) {
+        object_normal = vec4<f32>(object_normal.xy, 1.0 - object_normal.z, object_normal.w);
+        world_normal = -world_normal;
+    }
+#endif
+    var result = scene.ambient_color;
+    for(var i = 0u; i < lights.lens[0][0]; i++) {
         result += lights.ambients[i].xyz * lights.ambients[i].w;
     }
-    for(var i = 0u; i < lights.lens[1]; i++) {
-        result += calculate_directional_light_color(lights.dirs[i], object_normal, input, tangent_matrix * (input.world_position.xyz - normalize(lights.dirs[i].direction)));
+    result = result * baked.a + baked.rgb;
+    for(var i = 0u; i < lights.lens[0][1]; i++) {
+        let shadow = calculate_shadow(lights.dirs[i].shadow, input.world_position.xyz, world_normal, 1.0);
+        result += calculate_directional_light_color(lights.dirs[i], object_normal, input, tangent_matrix * (input.world_position.xyz - normalize(lights.dirs[i].direction))) * shadow;
     }
-    for(var i = 0u; i < lights.lens[2]; i++) {
+    for(var i = 0u; i < lights.lens[0][2]; i++) {
+        if (distance(input.world_position.xyz, lights.points[i].position) > lights.points[i].range) {
+            continue;
+        }
         result += calculate_point_light_color(lights.points[i], object_normal, input, tangent_matrix * lights.points[i].position);
     }
-    for(var i = 0u; i < lights.lens[3]; i++) {
-        result += calculate_spot_light_color(lights.spots[i], object_normal, input, tangent_matrix * lights.spots[i].base.position, normalize(tangent_matrix * lights.spots[i].direction_ccos.xyz));
+    for(var i = 0u; i < lights.lens[0][3]; i++) {
+        if (distance(input.world_position.xyz, lights.spots[i].base.position) > lights.spots[i].base.range) {
+            continue;
+        }
+        let spot = lights.spots[i];
+        let shadow = calculate_shadow(spot.base.shadow, input.world_position.xyz, world_normal, distance(input.world_position.xyz, spot.base.position));
+        result += calculate_spot_light_color(spot, object_normal, input, tangent_matrix * spot.base.position, normalize(tangent_matrix * spot.direction_ccos.xyz)) * shadow;
+    }
+    for(var i = 0u; i < lights.lens[1][0]; i++) {
+        result += calculate_area_light_color(lights.areas[i], object_normal, input, tangent_matrix);
     }
     result *= object_color.xyz;
+    result += calculate_environment_color(object_normal, input, tangent_matrix, object_color.xyz) * baked.a;
+    // Aerial perspective, lit by the sun
+    if (atmosphere.sun.w > 0.0 && dot(scene.sun_direction.xyz, scene.sun_direction.xyz) > 0.0) {
+        let haze = aerial_perspective(camera.view_pos.xyz, input.world_position.xyz, -normalize(scene.sun_direction.xyz));
+        result = result * haze.transmittance + haze.in_scattering * atmosphere.sun.rgb * scene.sun_direction.w;
+    }
+    let fog = fog_amount(scene, distance(input.world_position.xyz, camera.view_pos.xyz));
+    result = mix(result, scene.fog_color, fog);
+
+#ifdef ORDER_INDEPENDENT
+    return oit_output(result, alpha, distance(input.world_position.xyz, camera.view_pos.xyz));
+#else
+    return vec4<f32>(result, alpha);
+#endif
+}
+#endif
 
-    return vec4<f32>(result, object_color.a);
-}
\ No newline at end of file
+#ifdef OUTLINE
+// Inverted hull silhouette: the mesh pushed out along its normals by the
+// material's outline width in pixels, drawn with front faces culled so only
+// the rim around the object shows
+@vertex
+fn vs_main(model: VertexInput, instance: InstanceInput) -> @builtin(position) vec4<f32> {
+    let transform = instance_transform(instance);
+    let world_position = transform.model * vec4<f32>(model.position, 1.0);
+    let world_normal = normalize(transform.normal * model.normal);
+    let clip = camera.view_proj * world_position;
+    let clip_normal = (camera.view_proj * vec4<f32>(world_normal, 0.0)).xy;
+    if (material.outline_width <= 0.0 || dot(clip_normal, clip_normal) <= 0.0) {
+        // Collapsed, nothing is rasterized
+        return vec4<f32>(0.0, 0.0, 0.0, 1.0);
+    }
+    let pixel = vec2<f32>(2.0 / scene.screen_size.x, 2.0 / scene.screen_size.y);
+    let offset = normalize(clip_normal) * pixel * material.outline_width * clip.w;
+    return vec4<f32>(clip.xy + offset, clip.zw);
+}
+
+@fragment
+fn fs_main() -> @location(0) vec4<f32> {
+    return vec4<f32>(material.outline_color.rgb, 1.0);
+}
+#endif
\ No newline at end of file
@@ -0,0 +1,126 @@
+use std::collections::BTreeMap;
+
+use cgmath::{Matrix4, SquareMatrix};
+
+use crate::{
+    geometry::Geometry,
+    lightmap::{LightmapUvs, LightmapVertex},
+    memory::{MemoryCategory, MemoryTracker},
+    model::{Material, Mesh, Model, Submesh},
+    resources::Instance,
+};
+
+/// Merges geometry that never moves into one world space mesh at load, with
+/// one submesh per material, so level geometry takes a draw per material
+/// instead of one per object.
+#[derive(Debug, Clone, Default)]
+pub struct StaticBatcher {
+    // Geometry of each material in world space
+    groups: BTreeMap<usize, Geometry>,
+}
+
+impl StaticBatcher {
+    pub fn new() -> Self {
+        return Self::default();
+    }
+
+    /// Add `geometry` placed with `transform`, drawn with `material` of the
+    /// batch's model.
+    pub fn add(&mut self, geometry: &Geometry, transform: Matrix4<f32>, material: usize) {
+        let mut placed = geometry.clone();
+        placed.transform(transform);
+        // Mirroring turns triangles inside out
+        if transform.determinant() < 0.0 {
+            for triangle in placed.indices.chunks_exact_mut(3) {
+                triangle.swap(1, 2);
+            }
+            placed.calculate_tangents_bitangents();
+        }
+
+        let group = self.groups.entry(material).or_default();
+        let offset = group.vertices.len() as u32;
+        group.vertices.extend(placed.vertices);
+        group.indices.extend(placed.indices.iter().map(|i| i + offset));
+    }
+
+    /// Add a copy of `geometry` for each of `instances`.
+    pub fn add_instances(&mut self, geometry: &Geometry, instances: &[Instance], material: usize) {
+        for instance in instances {
+            self.add(geometry, instance.model_matrix(), material);
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        return self.groups.is_empty();
+    }
+
+    pub fn triangle_count(&self) -> usize {
+        return self.groups.values().map(Geometry::triangle_count).sum();
+    }
+
+    /// Upload everything added so far as a model with a single mesh, using
+    /// `materials` for the indices given to `add`.
+    pub fn build(&self, device: &wgpu::Device, memory: &MemoryTracker, name: &str, materials: Vec<Material>) -> Model {
+        let (combined, submeshes) = self.combine();
+        return Self::model(combined.to_mesh(device, memory, name, 0), submeshes, materials);
+    }
+
+    /// Like `build`, with lightmap coordinates packed into a `resolution`
+    /// square lightmap for `Renderer::bake_lightmap`. Secondary texture
+    /// coordinates are used as they are where the geometry has them.
+    pub fn build_lightmapped(
+        &self,
+        device: &wgpu::Device,
+        memory: &MemoryTracker,
+        name: &str,
+        materials: Vec<Material>,
+        resolution: u32,
+    ) -> Model {
+        let (mut combined, submeshes) = self.combine();
+        let uvs = if combined.has_secondary_uvs() {
+            combined.vertices.iter().map(|v| v.secondary_uv()).collect()
+        } else {
+            // Two texels apart so bilinear filtering stays inside each chart
+            combined.lightmap_uvs(resolution, 2)
+        };
+        let uvs = uvs.into_iter().map(|uv| LightmapVertex { uv }).collect::<Vec<_>>();
+        let buffer = memory.create_buffer_init(
+            device,
+            &wgpu::util::BufferInitDescriptor {
+                label: Some(&format!("{:?} Lightmap UV Buffer", name)),
+                contents: bytemuck::cast_slice(&uvs),
+                usage: wgpu::BufferUsages::VERTEX,
+            },
+            MemoryCategory::Mesh,
+        );
+        let mesh = Mesh {
+            lightmap_uvs: Some(LightmapUvs { buffer, resolution }),
+            ..combined.to_mesh(device, memory, name, 0)
+        };
+        return Self::model(mesh, submeshes, materials);
+    }
+
+    // Geometry of every material one after the other, with a submesh each
+    fn combine(&self) -> (Geometry, Vec<Submesh>) {
+        let mut combined = Geometry::default();
+        let mut submeshes = Vec::new();
+        for (&material, geometry) in &self.groups {
+            let offset = combined.vertices.len() as u32;
+            let start = combined.indices.len() as u32;
+            combined.vertices.extend_from_slice(&geometry.vertices);
+            combined.indices.extend(geometry.indices.iter().map(|i| i + offset));
+            submeshes.push(Submesh {
+                indices: start..combined.indices.len() as u32,
+                material,
+            });
+        }
+        return (combined, submeshes);
+    }
+
+    fn model(mesh: Mesh, submeshes: Vec<Submesh>, materials: Vec<Material>) -> Model {
+        return Model {
+            meshes: vec![Mesh { submeshes, ..mesh }],
+            materials,
+        };
+    }
+}
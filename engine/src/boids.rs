@@ -0,0 +1,233 @@
+use std::{collections::HashMap, time::Duration};
+
+use cgmath::{prelude::*, Quaternion, Vector3};
+
+use crate::{layers::RenderLayers, particles::Rng, renderer::Renderer, resources::Instance};
+
+/// Steering of a `Flock`. Radii are in world units, weights scale the
+/// three classic rules before the result is limited to `max_force`.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct BoidsSettings {
+    pub count: usize,
+    pub seed: u32,
+    /// Boids start inside and are steered back into this sphere.
+    pub center: Vector3<f32>,
+    pub radius: f32,
+    /// Boids within this distance are neighbours for alignment and cohesion.
+    pub neighbor_radius: f32,
+    /// Boids closer than this push each other apart.
+    pub separation_radius: f32,
+    pub separation: f32,
+    pub alignment: f32,
+    pub cohesion: f32,
+    /// Pull back towards `center` once outside `radius`.
+    pub containment: f32,
+    pub min_speed: f32,
+    pub max_speed: f32,
+    /// Largest change of velocity per second.
+    pub max_force: f32,
+    pub scale: f32,
+}
+
+impl Default for BoidsSettings {
+    fn default() -> Self {
+        Self {
+            count: 500,
+            seed: 1,
+            center: Vector3::new(0.0, 10.0, 0.0),
+            radius: 20.0,
+            neighbor_radius: 3.0,
+            separation_radius: 1.0,
+            separation: 1.5,
+            alignment: 1.0,
+            cohesion: 1.0,
+            containment: 2.0,
+            min_speed: 2.0,
+            max_speed: 6.0,
+            max_force: 8.0,
+            scale: 0.2,
+        }
+    }
+}
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct Boid {
+    pub position: Vector3<f32>,
+    pub velocity: Vector3<f32>,
+}
+
+/// Flocking demo: a group of the renderer's instances moved by separation,
+/// alignment and cohesion on the CPU and written back every update, which
+/// exercises the dynamic instance upload path with many moving instances.
+/// Each boid's instance turns its +Z axis along its velocity.
+#[derive(Debug, Clone)]
+pub struct Flock {
+    pub settings: BoidsSettings,
+    boids: Vec<Boid>,
+    // Index of the first of the flock's instances, the rest follow it
+    first: usize,
+}
+
+impl Flock {
+    /// Boids at random positions in the settings' sphere, heading in random
+    /// directions at a speed between the limits.
+    pub fn new(settings: BoidsSettings) -> Self {
+        let mut rng = Rng(settings.seed.max(1));
+        let boids = (0..settings.count)
+            .map(|_| {
+                let direction = rng.unit_vector();
+                let distance = rng.next().cbrt() * settings.radius;
+                let speed = rng.range((settings.min_speed, settings.max_speed));
+                Boid {
+                    position: settings.center + rng.unit_vector() * distance,
+                    velocity: direction * speed,
+                }
+            })
+            .collect();
+        return Self {
+            settings,
+            boids,
+            first: 0,
+        };
+    }
+
+    /// Generate a flock and append an instance per boid to the renderer's.
+    pub fn spawn(renderer: &mut Renderer, settings: BoidsSettings) -> Self {
+        let mut flock = Self::new(settings);
+        flock.first = renderer.instances().len();
+        for boid in &flock.boids {
+            renderer.spawn_instance(flock.instance(boid));
+        }
+        return flock;
+    }
+
+    /// Step and write the flock back to its instances at the start of every
+    /// update, from a scene update handler.
+    pub fn attach(mut self, renderer: &mut Renderer) {
+        renderer.scene.on_update(move |renderer, dt| {
+            self.step(dt);
+            self.write(renderer.instances_mut());
+        });
+    }
+
+    pub fn boids(&self) -> &[Boid] {
+        return &self.boids;
+    }
+
+    /// Indices of the flock's instances.
+    pub fn instances(&self) -> std::ops::Range<usize> {
+        return self.first..self.first + self.boids.len();
+    }
+
+    fn instance(&self, boid: &Boid) -> Instance {
+        let heading = boid.velocity.normalize();
+        return Instance {
+            position: boid.position,
+            rotation: Quaternion::from_arc(Vector3::unit_z(), heading, Some(Vector3::unit_y())),
+            scale: Vector3::from_value(self.settings.scale),
+            layers: RenderLayers::DEFAULT,
+            outline: false,
+        };
+    }
+
+    /// Advance the flock by `dt`. Neighbours are found through a grid of
+    /// `neighbor_radius` cells, so a step costs about the boid count times
+    /// the neighbours each has.
+    pub fn step(&mut self, dt: Duration) {
+        let dt = dt.as_secs_f32();
+        if dt <= 0.0 || self.boids.is_empty() {
+            return;
+        }
+        let s = self.settings;
+        let cell_size = s.neighbor_radius.max(0.01);
+        let cell = |p: Vector3<f32>| (p / cell_size).map(|c| c.floor() as i32);
+        let mut grid: HashMap<[i32; 3], Vec<usize>> = HashMap::new();
+        for (i, boid) in self.boids.iter().enumerate() {
+            let c = cell(boid.position);
+            grid.entry([c.x, c.y, c.z]).or_default().push(i);
+        }
+
+        let steered = self
+            .boids
+            .iter()
+            .enumerate()
+            .map(|(i, boid)| {
+                let (mut separation, mut velocities, mut positions, mut neighbors) =
+                    (Vector3::zero(), Vector3::zero(), Vector3::zero(), 0);
+                let c = cell(boid.position);
+                for offset in NEIGHBOR_CELLS {
+                    let key = [c.x + offset[0], c.y + offset[1], c.z + offset[2]];
+                    for &j in grid.get(&key).map_or(&[][..], |cell| cell.as_slice()) {
+                        let other = &self.boids[j];
+                        let away = boid.position - other.position;
+                        let distance2 = away.magnitude2();
+                        if j == i || distance2 > s.neighbor_radius * s.neighbor_radius {
+                            continue;
+                        }
+                        if distance2 < s.separation_radius * s.separation_radius && distance2 > 1e-8 {
+                            // Stronger the closer they are
+                            separation += away / distance2;
+                        }
+                        velocities += other.velocity;
+                        positions += other.position;
+                        neighbors += 1;
+                    }
+                }
+
+                let mut force = separation * s.separation;
+                if neighbors > 0 {
+                    let count = neighbors as f32;
+                    force += (velocities / count - boid.velocity) * s.alignment;
+                    force += (positions / count - boid.position) * s.cohesion;
+                }
+                let outside = (boid.position - s.center).magnitude() - s.radius;
+                if outside > 0.0 {
+                    force += (s.center - boid.position).normalize() * outside * s.containment;
+                }
+                if force.magnitude() > s.max_force {
+                    force = force.normalize() * s.max_force;
+                }
+
+                let mut velocity = boid.velocity + force * dt;
+                let speed = velocity.magnitude();
+                if speed > 1e-6 {
+                    velocity *= speed.clamp(s.min_speed, s.max_speed) / speed;
+                } else {
+                    velocity = boid.velocity;
+                }
+                Boid {
+                    position: boid.position + velocity * dt,
+                    velocity,
+                }
+            })
+            .collect();
+        self.boids = steered;
+    }
+
+    /// Write the boids' positions and headings to their instances, which
+    /// must have been spawned by `spawn`.
+    pub fn write(&self, instances: &mut [Instance]) {
+        let range = self.instances();
+        if range.end > instances.len() {
+            return;
+        }
+        for (instance, boid) in instances[range].iter_mut().zip(&self.boids) {
+            let layers = instance.layers;
+            *instance = Instance {
+                layers,
+                ..self.instance(boid)
+            };
+        }
+    }
+}
+
+// The cell itself and all of its neighbours
+const NEIGHBOR_CELLS: [[i32; 3]; 27] = {
+    let mut cells = [[0; 3]; 27];
+    let mut i = 0;
+    while i < 27 {
+        cells[i] = [(i % 3) as i32 - 1, (i / 3 % 3) as i32 - 1, (i / 9) as i32 - 1];
+        i += 1;
+    }
+    cells
+};
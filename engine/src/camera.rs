@@ -1,37 +1,133 @@
-use cgmath::{perspective, InnerSpace, Matrix4, Rad};
+use std::sync::Arc;
+
+use cgmath::{
+    EuclideanSpace, InnerSpace, Matrix4, Point3, Quaternion, Rad, Rotation, Rotation3, SquareMatrix, Vector3, Vector4,
+};
 use winit::event::{ElementState, VirtualKeyCode};
 
-use crate::controller::{Controller, ControllerEvent};
+use crate::{
+    collision::MeshCollider,
+    controller::{Controller, ControllerEvent, ControllerSettings, LookMode, ScrollMode},
+    layers::RenderLayers,
+    math::Plane,
+};
+
+// Coordinate conventions: world and view space are right-handed with +Y up,
+// cameras look down -Z. The projections below map straight into wgpu's clip
+// space, x and y from -1 to 1 with +Y up and depth from 0 at the near plane
+// to 1 at the far plane. Assets authored otherwise are converted on import,
+// see `geometry::CoordinateSystem`.
+
+/// Perspective projection of a view looking down -Z into wgpu's clip space.
+pub fn perspective_rh<F: Into<Rad<f32>>>(fovy: F, aspect: f32, znear: f32, zfar: f32) -> Matrix4<f32> {
+    let f = 1.0 / (fovy.into().0 * 0.5).tan();
+    let depth = znear - zfar;
+    return Matrix4::from_cols(
+        Vector4::new(f / aspect, 0.0, 0.0, 0.0),
+        Vector4::new(0.0, f, 0.0, 0.0),
+        Vector4::new(0.0, 0.0, zfar / depth, -1.0),
+        Vector4::new(0.0, 0.0, znear * zfar / depth, 0.0),
+    );
+}
 
-#[rustfmt::skip]
-pub const OPENGL_TO_WGPU_MATRIX: cgmath::Matrix4<f32> = cgmath::Matrix4::new(
-    1.0, 0.0, 0.0, 0.0,
-    0.0, 1.0, 0.0, 0.0,
-    0.0, 0.0, 0.5, 0.0,
-    0.0, 0.0, 0.5, 1.0,
-);
+/// Asymmetric perspective projection with the sides of the near plane given
+/// in view space, e.g. for XR eyes or portals.
+pub fn frustum_rh(left: f32, right: f32, bottom: f32, top: f32, znear: f32, zfar: f32) -> Matrix4<f32> {
+    let (width, height, depth) = (right - left, top - bottom, znear - zfar);
+    return Matrix4::from_cols(
+        Vector4::new(2.0 * znear / width, 0.0, 0.0, 0.0),
+        Vector4::new(0.0, 2.0 * znear / height, 0.0, 0.0),
+        Vector4::new((right + left) / width, (top + bottom) / height, zfar / depth, -1.0),
+        Vector4::new(0.0, 0.0, znear * zfar / depth, 0.0),
+    );
+}
+
+/// Orthographic projection of the view space box between the planes into
+/// wgpu's clip space, `znear` and `zfar` as distances down -Z.
+pub fn ortho_rh(left: f32, right: f32, bottom: f32, top: f32, znear: f32, zfar: f32) -> Matrix4<f32> {
+    let (width, height, depth) = (right - left, top - bottom, znear - zfar);
+    return Matrix4::from_cols(
+        Vector4::new(2.0 / width, 0.0, 0.0, 0.0),
+        Vector4::new(0.0, 2.0 / height, 0.0, 0.0),
+        Vector4::new(0.0, 0.0, 1.0 / depth, 0.0),
+        Vector4::new(-(right + left) / width, -(top + bottom) / height, znear / depth, 1.0),
+    );
+}
 
 #[repr(C)]
 #[derive(Copy, Clone, bytemuck::Pod, bytemuck::Zeroable)]
 pub struct CameraUniform {
     view_position: [f32; 4],
     view_proj: [[f32; 4]; 4],
+    inv_view_proj: [[f32; 4]; 4],
+    // Matrices of the previous frame, for reprojection
+    prev_view_proj: [[f32; 4]; 4],
+    prev_inv_view_proj: [[f32; 4]; 4],
+    // Plane equation, fragments behind it are discarded. All zero disables clipping
+    clip_plane: [f32; 4],
 }
 
+impl CameraUniform {
+    pub fn new(view_position: cgmath::Point3<f32>, view_proj: Matrix4<f32>) -> Self {
+        let inv_view_proj = view_proj.invert().unwrap_or_else(Matrix4::identity);
+        return Self {
+            view_position: view_position.to_homogeneous().into(),
+            view_proj: view_proj.into(),
+            inv_view_proj: inv_view_proj.into(),
+            prev_view_proj: view_proj.into(),
+            prev_inv_view_proj: inv_view_proj.into(),
+            clip_plane: [0.0; 4],
+        };
+    }
+
+    pub fn with_clip_plane(mut self, plane: Option<Plane>) -> Self {
+        self.clip_plane = plane.map_or([0.0; 4], |p| p.equation());
+        return self;
+    }
+
+    /// Take the previous-frame matrices from the uniform uploaded last frame.
+    pub fn with_previous(mut self, previous: &CameraUniform) -> Self {
+        self.prev_view_proj = previous.view_proj;
+        self.prev_inv_view_proj = previous.inv_view_proj;
+        return self;
+    }
+
+    pub fn view_proj(&self) -> Matrix4<f32> {
+        return self.view_proj.into();
+    }
+
+    pub fn inv_view_proj(&self) -> Matrix4<f32> {
+        return self.inv_view_proj.into();
+    }
+
+    pub fn prev_view_proj(&self) -> Matrix4<f32> {
+        return self.prev_view_proj.into();
+    }
+}
+
+#[derive(Clone)]
 pub struct Projection {
     aspect: f32,
     fovy: Rad<f32>,
+    // Field of view `fovy` is interpolated towards in `update`
+    target_fovy: Rad<f32>,
     znear: f32,
     zfar: f32,
+    /// Seconds for a field of view change to get about two thirds of the way
+    /// to its target, 0 applies changes immediately.
+    pub zoom_time: f32,
 }
 
 impl Projection {
     pub fn new<F: Into<Rad<f32>>>(width: u32, height: u32, fovy: F, znear: f32, zfar: f32) -> Self {
+        let fovy = fovy.into();
         return Self {
             aspect: width as f32 / height as f32,
-            fovy: fovy.into(),
+            fovy,
+            target_fovy: fovy,
             znear,
             zfar,
+            zoom_time: 0.08,
         };
     }
 
@@ -39,8 +135,64 @@ impl Projection {
         self.aspect = width as f32 / height as f32;
     }
 
+    pub fn aspect(&self) -> f32 {
+        return self.aspect;
+    }
+
+    /// Current vertical field of view, which may still be moving towards
+    /// the one last set.
+    pub fn fovy(&self) -> Rad<f32> {
+        return self.fovy;
+    }
+
+    pub fn target_fovy(&self) -> Rad<f32> {
+        return self.target_fovy;
+    }
+
+    pub fn znear(&self) -> f32 {
+        return self.znear;
+    }
+
+    pub fn zfar(&self) -> f32 {
+        return self.zfar;
+    }
+
+    /// Zoom smoothly to `fovy` over the following updates, e.g. for aiming
+    /// down sights. The renderer's camera takes its field of view from the
+    /// simulation's, zoom it with `Renderer::set_camera_fovy` instead.
+    pub fn set_fovy<F: Into<Rad<f32>>>(&mut self, fovy: F) {
+        self.target_fovy = fovy.into();
+    }
+
+    /// Jump to `fovy` without interpolating.
+    pub fn snap_fovy<F: Into<Rad<f32>>>(&mut self, fovy: F) {
+        self.fovy = fovy.into();
+        self.target_fovy = self.fovy;
+    }
+
+    pub fn is_zooming(&self) -> bool {
+        return self.fovy != self.target_fovy;
+    }
+
+    // Move the field of view towards its target, `dt` in seconds
+    pub fn update(&mut self, dt: f32) {
+        if !self.is_zooming() {
+            return;
+        }
+        let t = if self.zoom_time > 0.0 {
+            1.0 - (-dt / self.zoom_time).exp()
+        } else {
+            1.0
+        };
+        self.fovy += (self.target_fovy - self.fovy) * t;
+        // Exponential approach never arrives, stop once the change is invisible
+        if (self.target_fovy - self.fovy).0.abs() < 1e-4 {
+            self.fovy = self.target_fovy;
+        }
+    }
+
     pub fn calc_matrix(&self) -> Matrix4<f32> {
-        return OPENGL_TO_WGPU_MATRIX * perspective(self.fovy, self.aspect, self.znear, self.zfar);
+        return perspective_rh(self.fovy, self.aspect, self.znear, self.zfar);
     }
 }
 
@@ -92,10 +244,7 @@ impl Camera for PerspectiveCamera {
         let view = cgmath::Matrix4::look_at_rh(self.eye, self.target, self.up);
         let proj = self.projection.calc_matrix();
 
-        return CameraUniform {
-            view_position: self.eye.to_homogeneous().into(),
-            view_proj: (proj * view).into(),
-        };
+        return CameraUniform::new(self.eye, proj * view);
     }
 
     fn projection(&self) -> &Projection {
@@ -180,15 +329,44 @@ impl Controller for PerspectiveCamera {
 
 const SAFE_FRAC_PI_2: f32 = core::f32::consts::FRAC_PI_2 - 0.0001;
 
+/// Orientation looking along `yaw` around +Y, 0 towards +X and a quarter
+/// turn towards +Z, and `pitch` up from the horizon, without roll.
+pub fn yaw_pitch_orientation<Y: Into<Rad<f32>>, P: Into<Rad<f32>>>(yaw: Y, pitch: P) -> Quaternion<f32> {
+    // Cameras look down -Z, a quarter turn clockwise looks down +X
+    let yaw = Quaternion::from_angle_y(-yaw.into() - Rad(core::f32::consts::FRAC_PI_2));
+    return yaw * Quaternion::from_angle_x(pitch.into());
+}
+
+/// Yaw and pitch of `orientation`'s view direction, see `yaw_pitch_orientation`.
+pub fn orientation_yaw_pitch(orientation: Quaternion<f32>) -> (Rad<f32>, Rad<f32>) {
+    let forward = orientation.rotate_vector(-Vector3::unit_z()).normalize();
+    return (Rad(forward.z.atan2(forward.x)), Rad(forward.y.clamp(-1.0, 1.0).asin()));
+}
+
+/// Where an `FPSCamera` is and where it looks.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct CameraPose {
+    pub position: cgmath::Point3<f32>,
+    /// Rotation from view space, looking down -Z with +Y up, into the world.
+    pub orientation: Quaternion<f32>,
+    // Current vertical field of view, zooms happen on the simulation side
+    pub fovy: Rad<f32>,
+}
+
+/// Camera moved with the movement keys and turned with the mouse, either
+/// upright like in a first person game or freely with roll for flight and
+/// space scenes, see `ControllerSettings::look_mode`.
+#[derive(Clone)]
 pub struct FPSCamera {
-    yaw: Rad<f32>,
-    pitch: Rad<f32>,
+    orientation: Quaternion<f32>,
     amount_left: f32,
     amount_right: f32,
     amount_forward: f32,
     amount_backward: f32,
     amount_up: f32,
     amount_down: f32,
+    amount_roll_left: f32,
+    amount_roll_right: f32,
     rotate_horizontal: f32,
     rotate_vertical: f32,
     scroll: f32,
@@ -197,6 +375,11 @@ pub struct FPSCamera {
     pub projection: Projection,
     pub speed: f32,
     pub sensitivity: f32,
+    pub controls: ControllerSettings,
+    // Only instances on one of these layers are drawn
+    pub render_layers: RenderLayers,
+    // Moves slide along this mesh instead of going through it
+    collider: Option<Arc<MeshCollider>>,
 }
 
 impl FPSCamera {
@@ -210,37 +393,85 @@ impl FPSCamera {
     ) -> Self {
         Self {
             position: position.into(),
-            yaw: yaw.into(),
-            pitch: pitch.into(),
+            orientation: yaw_pitch_orientation(yaw, pitch),
             amount_left: 0.0,
             amount_right: 0.0,
             amount_forward: 0.0,
             amount_backward: 0.0,
             amount_up: 0.0,
             amount_down: 0.0,
+            amount_roll_left: 0.0,
+            amount_roll_right: 0.0,
             rotate_horizontal: 0.0,
             rotate_vertical: 0.0,
             scroll: 0.0,
             projection,
             speed,
-            sensitivity
+            sensitivity,
+            controls: ControllerSettings::default(),
+            render_layers: RenderLayers::DEFAULT,
+            collider: None,
         }
     }
 }
 
+impl FPSCamera {
+    pub fn pose(&self) -> CameraPose {
+        return CameraPose {
+            position: self.position,
+            orientation: self.orientation,
+            fovy: self.projection.fovy(),
+        };
+    }
+
+    // The field of view is applied without interpolation. The rest of the
+    // projection, speed and render layers are left untouched
+    pub fn set_pose(&mut self, pose: CameraPose) {
+        self.position = pose.position;
+        self.orientation = pose.orientation.normalize();
+        self.projection.snap_fovy(pose.fovy);
+    }
+
+    pub fn orientation(&self) -> Quaternion<f32> {
+        return self.orientation;
+    }
+
+    /// Turn the camera to `orientation`. `LookMode::Fps` drops its roll and
+    /// clamps its pitch on the next update.
+    pub fn set_orientation(&mut self, orientation: Quaternion<f32>) {
+        self.orientation = orientation.normalize();
+    }
+
+    pub fn forward(&self) -> Vector3<f32> {
+        return self.orientation.rotate_vector(-Vector3::unit_z());
+    }
+
+    // True while input is still moving or rotating the camera
+    pub fn is_moving(&self) -> bool {
+        let amounts = [
+            self.amount_left,
+            self.amount_right,
+            self.amount_forward,
+            self.amount_backward,
+            self.amount_up,
+            self.amount_down,
+            self.amount_roll_left,
+            self.amount_roll_right,
+            self.rotate_horizontal,
+            self.rotate_vertical,
+            self.scroll,
+        ];
+        return amounts.iter().any(|a| *a != 0.0) || self.projection.is_zooming();
+    }
+}
+
 impl Camera for FPSCamera {
     fn uniform(&self) -> CameraUniform {
-        let view = Matrix4::look_to_rh(
-            self.position,
-            cgmath::Vector3::new(self.yaw.0.cos(), self.pitch.0.sin(), self.yaw.0.sin()).normalize(),
-            cgmath::Vector3::unit_y(),
-        );
+        let up = self.orientation.rotate_vector(Vector3::unit_y());
+        let view = Matrix4::look_to_rh(self.position, self.forward(), up);
         let proj = self.projection.calc_matrix();
 
-        return CameraUniform {
-            view_position: self.position.to_homogeneous().into(),
-            view_proj: (proj * view).into(),
-        };
+        return CameraUniform::new(self.position, proj * view);
     }
 
     fn projection(&self) -> &Projection {
@@ -257,27 +488,40 @@ impl Controller for FPSCamera {
         match event {
             ControllerEvent::KeyboardInput(state, key) => {
                 let amount = if state == ElementState::Pressed {1.0} else {0.0};
-                match key {
-                    VirtualKeyCode::W | VirtualKeyCode::Up => {
-                        self.amount_forward = amount;
-                    }
-                    VirtualKeyCode::S | VirtualKeyCode::Down => {
-                        self.amount_backward = amount;
-                    }
-                    VirtualKeyCode::A | VirtualKeyCode::Left => {
-                        self.amount_left = amount;
-                    }
-                    VirtualKeyCode::D | VirtualKeyCode::Right => {
-                        self.amount_right = amount;
-                    }
-                    VirtualKeyCode::Space => {
-                        self.amount_up = amount;
-                    }
-                    VirtualKeyCode::LShift => {
-                        self.amount_down = amount;
-                    },
-                    _ => {}
+                let keys = self.controls.keys;
+                if keys.forward.matches(key) {
+                    self.amount_forward = amount;
+                }
+                if keys.backward.matches(key) {
+                    self.amount_backward = amount;
+                }
+                if keys.left.matches(key) {
+                    self.amount_left = amount;
+                }
+                if keys.right.matches(key) {
+                    self.amount_right = amount;
+                }
+                if keys.up.matches(key) {
+                    self.amount_up = amount;
                 }
+                if keys.down.matches(key) {
+                    self.amount_down = amount;
+                }
+                if keys.roll_left.matches(key) {
+                    self.amount_roll_left = amount;
+                }
+                if keys.roll_right.matches(key) {
+                    self.amount_roll_right = amount;
+                }
+            },
+            ControllerEvent::Configure(controls) => {
+                self.controls = controls;
+            },
+            ControllerEvent::Collide(collider) => {
+                self.collider = collider;
+            },
+            ControllerEvent::Zoom(fovy) => {
+                self.projection.set_fovy(fovy);
             },
             ControllerEvent::MouseMove((dx, dy)) => {
                 self.rotate_horizontal = dx as f32;
@@ -292,42 +536,78 @@ impl Controller for FPSCamera {
 
     fn update(&mut self, dt: std::time::Duration) {
         let dt = dt.as_secs_f32();
-
-        // Move forward/backward and left/right
-        let (yaw_sin, yaw_cos) = self.yaw.0.sin_cos();
-        let forward = cgmath::Vector3::new(yaw_cos, 0.0, yaw_sin).normalize();
-        let right = cgmath::Vector3::new(-yaw_sin, 0.0, yaw_cos).normalize();
+        let start = self.position;
+
+        // Move forward/backward and left/right, upright cameras along the
+        // ground and free ones along their own axes
+        let look_mode = self.controls.look_mode;
+        let (forward, right, up) = match look_mode {
+            LookMode::Fps => {
+                let (yaw, _) = orientation_yaw_pitch(self.orientation);
+                let (yaw_sin, yaw_cos) = yaw.0.sin_cos();
+                (Vector3::new(yaw_cos, 0.0, yaw_sin), Vector3::new(-yaw_sin, 0.0, yaw_cos), Vector3::unit_y())
+            }
+            LookMode::Free => (
+                self.forward(),
+                self.orientation.rotate_vector(Vector3::unit_x()),
+                self.orientation.rotate_vector(Vector3::unit_y()),
+            ),
+        };
         self.position += forward * (self.amount_forward - self.amount_backward) * self.speed * dt;
         self.position += right * (self.amount_right - self.amount_left) * self.speed * dt;
 
-        // Move in/out (aka. "zoom")
-        // Note: this isn't an actual zoom. The camera's position
-        // changes when zooming. I've added this to make it easier
-        // to get closer to an object you want to focus on.
-        let (pitch_sin, pitch_cos) = self.pitch.0.sin_cos();
-        let scrollward = cgmath::Vector3::new(pitch_cos * yaw_cos, pitch_sin, pitch_cos * yaw_sin).normalize();
-        self.position += scrollward * self.scroll * self.speed * self.sensitivity * dt;
+        match self.controls.scroll_mode {
+            ScrollMode::Dolly => {
+                // Move in/out (aka. "zoom")
+                // Note: this isn't an actual zoom. The camera's position
+                // changes when zooming. I've added this to make it easier
+                // to get closer to an object you want to focus on.
+                self.position += self.forward() * self.scroll * self.speed * self.sensitivity * dt;
+            }
+            ScrollMode::Fov if self.scroll != 0.0 => {
+                // Scrolling up narrows the view
+                let controls = &self.controls;
+                let fovy = self.projection.target_fovy() + controls.fov_per_scroll * self.scroll;
+                let fovy = Rad(fovy.0.clamp(controls.min_fovy.0, controls.max_fovy.0));
+                self.projection.set_fovy(fovy);
+            }
+            ScrollMode::Fov => {}
+        }
         self.scroll = 0.0;
+        self.projection.update(dt);
 
-        // Move up/down. Since we don't use roll, we can just
-        // modify the y coordinate directly.
-        self.position.y += (self.amount_up - self.amount_down) * self.speed * dt;
+        // Move up/down, along the world's up axis when upright
+        self.position += up * (self.amount_up - self.amount_down) * self.speed * dt;
+        if let Some(collider) = &self.collider {
+            let radius = self.controls.collision_radius;
+            self.position = Point3::from_vec(collider.slide(start.to_vec(), self.position.to_vec(), radius));
+        }
 
         // Rotate
-        self.yaw += Rad(self.rotate_horizontal) * self.sensitivity * dt;
-        self.pitch += Rad(-self.rotate_vertical) * self.sensitivity * dt;
+        let sensitivity = self.sensitivity * self.controls.look_sensitivity;
+        let turn = Rad(self.rotate_horizontal) * sensitivity * dt;
+        let tilt = Rad(-self.rotate_vertical) * sensitivity * dt;
+        match look_mode {
+            LookMode::Fps => {
+                // Roll is dropped and the camera's angle kept from going
+                // too high/low, both by going through yaw and pitch
+                let (yaw, pitch) = orientation_yaw_pitch(self.orientation);
+                let pitch = Rad((pitch + tilt).0.clamp(-SAFE_FRAC_PI_2, SAFE_FRAC_PI_2));
+                self.orientation = yaw_pitch_orientation(yaw + turn, pitch);
+            }
+            LookMode::Free => {
+                // Around the camera's own axes, rolling left turns its up
+                // axis to the left
+                let roll = self.controls.roll_speed * (self.amount_roll_left - self.amount_roll_right) * dt;
+                let rotation = Quaternion::from_angle_y(-turn) * Quaternion::from_angle_x(tilt) * Quaternion::from_angle_z(roll);
+                self.orientation = (self.orientation * rotation).normalize();
+            }
+        }
 
         // If process_mouse isn't called every frame, these values
         // will not get set to zero, and the camera will rotate
         // when moving in a non cardinal direction.
         self.rotate_horizontal = 0.0;
         self.rotate_vertical = 0.0;
-
-        // Keep the camera's angle from going too high/low.
-        if self.pitch < -Rad(SAFE_FRAC_PI_2) {
-            self.pitch = -Rad(SAFE_FRAC_PI_2);
-        } else if self.pitch > Rad(SAFE_FRAC_PI_2) {
-            self.pitch = Rad(SAFE_FRAC_PI_2);
-        }
     }
 }
@@ -0,0 +1,9 @@
+struct Camera {
+    view_pos: vec4<f32>,
+    view_proj: mat4x4<f32>,
+    inv_view_proj: mat4x4<f32>,
+    prev_view_proj: mat4x4<f32>,
+    prev_inv_view_proj: mat4x4<f32>,
+    // Fragments with dot(xyz, position) + w < 0 are clipped, all zero disables it
+    clip_plane: vec4<f32>,
+};
@@ -0,0 +1,261 @@
+use std::{
+    io::Write,
+    path::PathBuf,
+    process::{Child, Command, Stdio},
+};
+
+use anyhow::*;
+
+use crate::memory::{MemoryCategory, MemoryTracker, TrackedBuffer, TrackedTexture};
+
+/// Where captured frames are written.
+#[derive(Debug, Clone, PartialEq)]
+pub enum CaptureOutput {
+    // `frame_00000.png`, `frame_00001.png`, ... inside the directory
+    PngSequence { directory: PathBuf },
+    // Raw RGBA frames piped to an `ffmpeg` process encoding into `path`
+    Ffmpeg { path: PathBuf, args: Vec<String> },
+    // Nothing is written, frames are only read back with `FrameCapture::read_frame`
+    Memory,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct CaptureSettings {
+    pub width: u32,
+    pub height: u32,
+    /// Simulation steps per second, every captured frame advances time by `1 / fps`.
+    pub fps: f32,
+    /// Stop after this many frames; `None` records until stopped.
+    pub frame_count: Option<u32>,
+    pub output: CaptureOutput,
+}
+
+impl Default for CaptureSettings {
+    fn default() -> Self {
+        Self {
+            width: 1920,
+            height: 1080,
+            fps: 60.0,
+            frame_count: None,
+            output: CaptureOutput::PngSequence {
+                directory: PathBuf::from("capture"),
+            },
+        }
+    }
+}
+
+impl CaptureSettings {
+    pub fn frame_time(&self) -> std::time::Duration {
+        return std::time::Duration::from_secs_f32(1.0 / self.fps.max(1.0));
+    }
+}
+
+/// Offscreen target and readback for rendering a fixed-timestep frame sequence.
+pub struct FrameCapture {
+    settings: CaptureSettings,
+    format: wgpu::TextureFormat,
+    texture: TrackedTexture,
+    view: wgpu::TextureView,
+    readback: TrackedBuffer,
+    padded_bytes_per_row: u32,
+    frame: u32,
+    ffmpeg: Option<Child>,
+}
+
+impl FrameCapture {
+    /// `format` has to match the format the post pass was built for.
+    pub fn new(
+        device: &wgpu::Device,
+        memory: &MemoryTracker,
+        format: wgpu::TextureFormat,
+        settings: CaptureSettings,
+    ) -> Result<Self> {
+        if !matches!(
+            format,
+            wgpu::TextureFormat::Rgba8Unorm
+                | wgpu::TextureFormat::Rgba8UnormSrgb
+                | wgpu::TextureFormat::Bgra8Unorm
+                | wgpu::TextureFormat::Bgra8UnormSrgb
+        ) {
+            bail!("Cannot capture frames in format {:?}", format);
+        }
+        if settings.width == 0 || settings.height == 0 {
+            bail!("Capture resolution must not be empty");
+        }
+
+        let texture = memory.create_texture(
+            device,
+            &wgpu::TextureDescriptor {
+                label: Some("Capture Texture"),
+                size: wgpu::Extent3d {
+                    width: settings.width,
+                    height: settings.height,
+                    depth_or_array_layers: 1,
+                },
+                mip_level_count: 1,
+                sample_count: 1,
+                dimension: wgpu::TextureDimension::D2,
+                format,
+                usage: wgpu::TextureUsages::RENDER_ATTACHMENT | wgpu::TextureUsages::COPY_SRC,
+            },
+            MemoryCategory::Texture,
+        );
+        let view = texture.create_view(&wgpu::TextureViewDescriptor::default());
+
+        // Rows of a texture copy have to be aligned
+        let align = wgpu::COPY_BYTES_PER_ROW_ALIGNMENT;
+        let padded_bytes_per_row = (settings.width * 4).div_ceil(align) * align;
+        let readback = memory.create_buffer(
+            device,
+            &wgpu::BufferDescriptor {
+                label: Some("Capture Readback Buffer"),
+                size: (padded_bytes_per_row * settings.height) as wgpu::BufferAddress,
+                usage: wgpu::BufferUsages::COPY_DST | wgpu::BufferUsages::MAP_READ,
+                mapped_at_creation: false,
+            },
+            MemoryCategory::Other,
+        );
+
+        let ffmpeg = match &settings.output {
+            CaptureOutput::PngSequence { directory } => {
+                std::fs::create_dir_all(directory).with_context(|| {
+                    format!("Failed to create capture directory {:?}", directory)
+                })?;
+                None
+            }
+            CaptureOutput::Ffmpeg { path, args } => {
+                let child = Command::new("ffmpeg")
+                    .args(["-y", "-loglevel", "error", "-f", "rawvideo"])
+                    .args(["-pixel_format", "rgba"])
+                    .arg("-video_size")
+                    .arg(format!("{}x{}", settings.width, settings.height))
+                    .arg("-framerate")
+                    .arg(settings.fps.to_string())
+                    .args(["-i", "-"])
+                    .args(args)
+                    .arg(path)
+                    .stdin(Stdio::piped())
+                    .spawn()
+                    .context("Failed to start ffmpeg")?;
+                Some(child)
+            }
+            CaptureOutput::Memory => None,
+        };
+
+        return Ok(Self {
+            settings,
+            format,
+            texture,
+            view,
+            readback,
+            padded_bytes_per_row,
+            frame: 0,
+            ffmpeg,
+        });
+    }
+
+    pub fn settings(&self) -> &CaptureSettings {
+        return &self.settings;
+    }
+
+    /// Target the post pass renders the captured frame into.
+    pub fn view(&self) -> &wgpu::TextureView {
+        return &self.view;
+    }
+
+    /// Number of frames written so far.
+    pub fn frame(&self) -> u32 {
+        return self.frame;
+    }
+
+    pub fn is_finished(&self) -> bool {
+        return self.settings.frame_count.is_some_and(|n| self.frame >= n);
+    }
+
+    /// Record the copy of the rendered frame into the readback buffer.
+    pub fn copy_to_buffer(&self, encoder: &mut wgpu::CommandEncoder) {
+        encoder.copy_texture_to_buffer(
+            self.texture.as_image_copy(),
+            wgpu::ImageCopyBuffer {
+                buffer: &self.readback,
+                layout: wgpu::ImageDataLayout {
+                    offset: 0,
+                    bytes_per_row: std::num::NonZeroU32::new(self.padded_bytes_per_row),
+                    rows_per_image: None,
+                },
+            },
+            wgpu::Extent3d {
+                width: self.settings.width,
+                height: self.settings.height,
+                depth_or_array_layers: 1,
+            },
+        );
+    }
+
+    /// Wait for the submitted copy and read the frame back as RGBA.
+    pub fn read_frame(&self, device: &wgpu::Device) -> Result<image::RgbaImage> {
+        let slice = self.readback.slice(..);
+        let (sender, receiver) = std::sync::mpsc::channel();
+        slice.map_async(wgpu::MapMode::Read, move |result| {
+            let _ = sender.send(result);
+        });
+        device.poll(wgpu::Maintain::Wait);
+        receiver
+            .recv()
+            .context("Capture readback was dropped")?
+            .context("Failed to map capture readback")?;
+
+        let (width, height) = (self.settings.width, self.settings.height);
+        let mut pixels = Vec::with_capacity((width * height * 4) as usize);
+        {
+            let data = slice.get_mapped_range();
+            for row in data.chunks_exact(self.padded_bytes_per_row as usize) {
+                pixels.extend_from_slice(&row[..(width * 4) as usize]);
+            }
+        }
+        self.readback.unmap();
+
+        if matches!(
+            self.format,
+            wgpu::TextureFormat::Bgra8Unorm | wgpu::TextureFormat::Bgra8UnormSrgb
+        ) {
+            for pixel in pixels.chunks_exact_mut(4) {
+                pixel.swap(0, 2);
+            }
+        }
+
+        return image::RgbaImage::from_raw(width, height, pixels).context("Capture readback has the wrong size");
+    }
+
+    /// Wait for the submitted copy and write the frame out. Blocks until the
+    /// GPU has finished, which is what keeps the sequence independent of speed.
+    pub fn write_frame(&mut self, device: &wgpu::Device) -> Result<()> {
+        let frame = self.read_frame(device)?;
+        if let Some(ffmpeg) = &mut self.ffmpeg {
+            let stdin = ffmpeg.stdin.as_mut().context("ffmpeg stdin is closed")?;
+            stdin
+                .write_all(frame.as_raw())
+                .context("Failed to pipe frame to ffmpeg")?;
+        } else if let CaptureOutput::PngSequence { directory } = &self.settings.output {
+            let path = directory.join(format!("frame_{:05}.png", self.frame));
+            frame
+                .save(&path)
+                .with_context(|| format!("Failed to write {:?}", path))?;
+        }
+
+        self.frame += 1;
+        return Ok(());
+    }
+
+    /// Close the output, waiting for ffmpeg to finish encoding.
+    pub fn finish(mut self) -> Result<u32> {
+        if let Some(mut ffmpeg) = self.ffmpeg.take() {
+            drop(ffmpeg.stdin.take());
+            let status = ffmpeg.wait().context("Failed to wait for ffmpeg")?;
+            if !status.success() {
+                bail!("ffmpeg exited with {}", status);
+            }
+        }
+        return Ok(self.frame);
+    }
+}
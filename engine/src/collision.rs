@@ -0,0 +1,515 @@
+use std::collections::{BTreeMap, HashSet};
+
+use cgmath::{prelude::*, Vector3};
+
+use crate::{
+    geometry::{Bvh, Geometry},
+    math::Aabb,
+    resources::Instance,
+};
+
+/// Convex shape in the local space of the instance it's attached to, scaled,
+/// rotated and moved along with it.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Shape {
+    Aabb(Aabb),
+    Sphere { center: Vector3<f32>, radius: f32 },
+    // Segment from `a` to `b` grown by `radius`
+    Capsule { a: Vector3<f32>, b: Vector3<f32>, radius: f32 },
+    // Only the points are kept, queries don't need the hull's faces
+    ConvexHull(Vec<Vector3<f32>>),
+}
+
+impl Shape {
+    pub fn sphere(radius: f32) -> Self {
+        return Shape::Sphere {
+            center: Vector3::zero(),
+            radius,
+        };
+    }
+
+    /// Upright capsule centered on the origin, `height` including the caps.
+    pub fn capsule(height: f32, radius: f32) -> Self {
+        let half = (height * 0.5 - radius).max(0.0);
+        return Shape::Capsule {
+            a: Vector3::new(0.0, -half, 0.0),
+            b: Vector3::new(0.0, half, 0.0),
+            radius,
+        };
+    }
+
+    /// Convex hull of the vertex positions of `geometry`. Concave meshes are
+    /// filled in.
+    pub fn convex_hull(geometry: &Geometry) -> Self {
+        let mut seen = HashSet::new();
+        let points = geometry
+            .vertices
+            .iter()
+            .filter(|v| seen.insert(v.position.map(f32::to_bits)))
+            .map(|v| Vector3::from(v.position))
+            .collect();
+        return Shape::ConvexHull(points);
+    }
+
+    // Farthest local point along `direction`
+    fn support(&self, direction: Vector3<f32>) -> Vector3<f32> {
+        let outward = |radius: f32| {
+            if direction.magnitude2() > 0.0 {
+                direction.normalize() * radius
+            } else {
+                Vector3::zero()
+            }
+        };
+        return match self {
+            Shape::Aabb(aabb) => {
+                let pick = |d: f32, min: f32, max: f32| if d >= 0.0 { max } else { min };
+                Vector3::new(
+                    pick(direction.x, aabb.min.x, aabb.max.x),
+                    pick(direction.y, aabb.min.y, aabb.max.y),
+                    pick(direction.z, aabb.min.z, aabb.max.z),
+                )
+            }
+            Shape::Sphere { center, radius } => center + outward(*radius),
+            Shape::Capsule { a, b, radius } => {
+                let end = if direction.dot(b - a) >= 0.0 { b } else { a };
+                end + outward(*radius)
+            }
+            Shape::ConvexHull(points) => points
+                .iter()
+                .copied()
+                .max_by(|p, q| p.dot(direction).total_cmp(&q.dot(direction)))
+                .unwrap_or_else(Vector3::zero),
+        };
+    }
+}
+
+/// Shape placed with an instance's position, rotation and scale.
+#[derive(Debug, Copy, Clone)]
+pub struct Placed<'a> {
+    pub shape: &'a Shape,
+    pub transform: &'a Instance,
+}
+
+impl<'a> Placed<'a> {
+    pub fn new(shape: &'a Shape, transform: &'a Instance) -> Self {
+        return Self { shape, transform };
+    }
+
+    // Farthest world space point along `direction`. Scale is symmetric, so
+    // the local direction is scaled the same way as the support point
+    fn support(&self, direction: Vector3<f32>) -> Vector3<f32> {
+        let t = self.transform;
+        let local = t.rotation.invert().rotate_vector(direction).mul_element_wise(t.scale);
+        return t.position + t.rotation.rotate_vector(self.shape.support(local).mul_element_wise(t.scale));
+    }
+
+    pub fn bounds(&self) -> Aabb {
+        let axis = |i: usize| {
+            let mut d = Vector3::zero();
+            d[i] = 1.0;
+            (self.support(-d)[i], self.support(d)[i])
+        };
+        let (x, y, z) = (axis(0), axis(1), axis(2));
+        return Aabb::new(Vector3::new(x.0, y.0, z.0), Vector3::new(x.1, y.1, z.1));
+    }
+
+    /// True if the shapes touch or overlap.
+    pub fn overlaps(&self, other: &Placed) -> bool {
+        return gjk(|d| self.support(d) - other.support(-d));
+    }
+
+    /// Fraction of `translation` this shape can move before touching `other`,
+    /// `None` if it never does.
+    pub fn sweep(&self, translation: Vector3<f32>, other: &Placed) -> Option<f32> {
+        // The shape swept from `t0` to `t1` is convex, so the time of impact
+        // can be bisected without tunnelling through thin shapes
+        let swept = |t0: f32, t1: f32| {
+            move |d: Vector3<f32>| {
+                let t = if d.dot(translation) > 0.0 { t1 } else { t0 };
+                self.support(d) + translation * t - other.support(-d)
+            }
+        };
+        if !gjk(swept(0.0, 1.0)) {
+            return None;
+        }
+        let (mut lo, mut hi) = (0.0, 1.0);
+        if gjk(swept(0.0, 0.0)) {
+            return Some(0.0);
+        }
+        for _ in 0..SWEEP_ITERATIONS {
+            let mid = (lo + hi) * 0.5;
+            if gjk(swept(lo, mid)) {
+                hi = mid;
+            } else {
+                lo = mid;
+            }
+        }
+        return Some(lo);
+    }
+}
+
+const GJK_ITERATIONS: usize = 64;
+// Precision of sweeps is 2^-20 of the translation
+const SWEEP_ITERATIONS: usize = 20;
+
+fn same_direction(a: Vector3<f32>, b: Vector3<f32>) -> bool {
+    return a.dot(b) > 0.0;
+}
+
+// Gilbert-Johnson-Keerthi: whether the Minkowski difference described by
+// `support` contains the origin. Newest simplex point first
+fn gjk<F: Fn(Vector3<f32>) -> Vector3<f32>>(support: F) -> bool {
+    let first = support(Vector3::unit_x());
+    let mut simplex = vec![first];
+    let mut direction = -first;
+    for _ in 0..GJK_ITERATIONS {
+        // The origin lies on the simplex, the shapes touch
+        if direction.magnitude2() < 1e-12 {
+            return true;
+        }
+        let point = support(direction);
+        if !same_direction(point, direction) {
+            return false;
+        }
+        simplex.insert(0, point);
+        if next_simplex(&mut simplex, &mut direction) {
+            return true;
+        }
+    }
+    // Cycling on the boundary, count it as touching
+    return true;
+}
+
+fn next_simplex(simplex: &mut Vec<Vector3<f32>>, direction: &mut Vector3<f32>) -> bool {
+    return match simplex.len() {
+        2 => line(simplex, direction),
+        3 => triangle(simplex, direction),
+        _ => tetrahedron(simplex, direction),
+    };
+}
+
+fn line(simplex: &mut Vec<Vector3<f32>>, direction: &mut Vector3<f32>) -> bool {
+    let (a, b) = (simplex[0], simplex[1]);
+    let ab = b - a;
+    let ao = -a;
+    if same_direction(ab, ao) {
+        *direction = ab.cross(ao).cross(ab);
+    } else {
+        *simplex = vec![a];
+        *direction = ao;
+    }
+    return false;
+}
+
+fn triangle(simplex: &mut Vec<Vector3<f32>>, direction: &mut Vector3<f32>) -> bool {
+    let (a, b, c) = (simplex[0], simplex[1], simplex[2]);
+    let ab = b - a;
+    let ac = c - a;
+    let ao = -a;
+    let abc = ab.cross(ac);
+
+    if same_direction(abc.cross(ac), ao) {
+        if same_direction(ac, ao) {
+            *simplex = vec![a, c];
+            *direction = ac.cross(ao).cross(ac);
+            return false;
+        }
+        *simplex = vec![a, b];
+        return line(simplex, direction);
+    }
+    if same_direction(ab.cross(abc), ao) {
+        *simplex = vec![a, b];
+        return line(simplex, direction);
+    }
+    if same_direction(abc, ao) {
+        *direction = abc;
+    } else {
+        *simplex = vec![a, c, b];
+        *direction = -abc;
+    }
+    return false;
+}
+
+fn tetrahedron(simplex: &mut Vec<Vector3<f32>>, direction: &mut Vector3<f32>) -> bool {
+    let (a, b, c, d) = (simplex[0], simplex[1], simplex[2], simplex[3]);
+    let ab = b - a;
+    let ac = c - a;
+    let ad = d - a;
+    let ao = -a;
+
+    if same_direction(ab.cross(ac), ao) {
+        *simplex = vec![a, b, c];
+        return triangle(simplex, direction);
+    }
+    if same_direction(ac.cross(ad), ao) {
+        *simplex = vec![a, c, d];
+        return triangle(simplex, direction);
+    }
+    if same_direction(ad.cross(ab), ao) {
+        *simplex = vec![a, d, b];
+        return triangle(simplex, direction);
+    }
+    return true;
+}
+
+/// First collider hit by a sweep.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct SweepHit {
+    pub index: usize,
+    // Fraction of the translation that is free to move
+    pub fraction: f32,
+    pub distance: f32,
+}
+
+/// Shapes attached to instances by index, queried with the instances'
+/// current transforms. Indices follow `Renderer::instances`, so removing
+/// instances from the middle needs the colliders moved too.
+#[derive(Debug, Default)]
+pub struct Colliders {
+    shapes: BTreeMap<usize, Shape>,
+}
+
+impl Colliders {
+    pub fn attach(&mut self, index: usize, shape: Shape) {
+        self.shapes.insert(index, shape);
+    }
+
+    pub fn detach(&mut self, index: usize) -> Option<Shape> {
+        return self.shapes.remove(&index);
+    }
+
+    pub fn shape(&self, index: usize) -> Option<&Shape> {
+        return self.shapes.get(&index);
+    }
+
+    fn placed<'a>(&'a self, instances: &'a [Instance]) -> impl Iterator<Item = (usize, Placed<'a>)> {
+        return self
+            .shapes
+            .iter()
+            .filter_map(|(&i, shape)| Some((i, Placed::new(shape, instances.get(i)?))));
+    }
+
+    /// Instances whose colliders overlap `query`, e.g. for trigger volumes.
+    pub fn overlaps(&self, instances: &[Instance], query: &Placed) -> Vec<usize> {
+        let bounds = query.bounds();
+        return self
+            .placed(instances)
+            .filter(|(_, placed)| placed.bounds().intersects(&bounds) && query.overlaps(placed))
+            .map(|(i, _)| i)
+            .collect();
+    }
+
+    /// Other instances overlapping the collider of instance `index`.
+    pub fn overlapping(&self, instances: &[Instance], index: usize) -> Vec<usize> {
+        let (shape, transform) = match (self.shapes.get(&index), instances.get(index)) {
+            (Some(shape), Some(transform)) => (shape, transform),
+            _ => return Vec::new(),
+        };
+        let mut hits = self.overlaps(instances, &Placed::new(shape, transform));
+        hits.retain(|&i| i != index);
+        return hits;
+    }
+
+    /// First collider `query` touches when moved by `translation`, ignoring
+    /// instance `ignore`. Colliders it already overlaps are hit at distance 0.
+    pub fn sweep(
+        &self,
+        instances: &[Instance],
+        query: &Placed,
+        translation: Vector3<f32>,
+        ignore: Option<usize>,
+    ) -> Option<SweepHit> {
+        let start = query.bounds();
+        let moved = Aabb::new(start.min + translation, start.max + translation);
+        let bounds = start.union(&moved);
+        let length = translation.magnitude();
+        return self
+            .placed(instances)
+            .filter(|(i, placed)| Some(*i) != ignore && placed.bounds().intersects(&bounds))
+            .filter_map(|(i, placed)| {
+                let fraction = query.sweep(translation, &placed)?;
+                Some(SweepHit {
+                    index: i,
+                    fraction,
+                    distance: fraction * length,
+                })
+            })
+            .min_by(|a, b| a.fraction.total_cmp(&b.fraction));
+    }
+
+    /// Sweep the collider of instance `index` by `translation`, e.g. a short
+    /// distance down for ground checks.
+    pub fn sweep_collider(
+        &self,
+        instances: &[Instance],
+        index: usize,
+        translation: Vector3<f32>,
+    ) -> Option<SweepHit> {
+        let shape = self.shapes.get(&index)?;
+        let transform = instances.get(index)?;
+        return self.sweep(instances, &Placed::new(shape, transform), translation, Some(index));
+    }
+}
+
+// Steps per sphere radius moved, small enough that no triangle is passed
+// between two of them
+const STEPS_PER_RADIUS: f32 = 2.0;
+// Bound on the steps of a single move, anything longer is a teleport
+const MAX_STEPS: usize = 256;
+// Pushes out per step, enough for the sphere to settle into a corner
+const PUSH_ITERATIONS: usize = 4;
+
+/// Deepest overlap of a sphere with a `MeshCollider`.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct SphereContact {
+    /// Away from the surface, towards the sphere's center.
+    pub normal: Vector3<f32>,
+    pub depth: f32,
+}
+
+/// Where a sphere cast stopped.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct SphereHit {
+    /// Fraction of the translation that is free to move.
+    pub fraction: f32,
+    pub center: Vector3<f32>,
+    pub normal: Vector3<f32>,
+}
+
+/// Triangle mesh in world space that spheres are cast against, e.g. to keep
+/// the camera out of the scene, see `Renderer::set_camera_collider`.
+pub struct MeshCollider {
+    bvh: Bvh,
+    // Infinite plane everything rests on, at this height
+    ground: Option<f32>,
+}
+
+impl MeshCollider {
+    /// Collide with `geometry`, already in world space.
+    pub fn new(geometry: &Geometry) -> Self {
+        return Self {
+            bvh: Bvh::new(geometry),
+            ground: None,
+        };
+    }
+
+    /// Collide with `geometry` placed at each of `instances`. The triangles
+    /// are copied, so moving the instances later needs a new collider.
+    pub fn from_instances(geometry: &Geometry, instances: &[Instance]) -> Self {
+        let mut combined = Geometry::default();
+        for instance in instances {
+            let mut placed = geometry.clone();
+            placed.transform(instance.model_matrix());
+            let offset = combined.vertices.len() as u32;
+            combined.vertices.extend(placed.vertices);
+            combined.indices.extend(placed.indices.iter().map(|i| i + offset));
+        }
+        return Self::new(&combined);
+    }
+
+    /// Also collide with the ground plane at `height`, which spheres are
+    /// pushed up out of from either side.
+    pub fn with_ground(mut self, height: f32) -> Self {
+        self.ground = Some(height);
+        return self;
+    }
+
+    /// Deepest overlap of the sphere at `center`, `None` if it's free.
+    pub fn contact(&self, center: Vector3<f32>, radius: f32) -> Option<SphereContact> {
+        let mut deepest: Option<SphereContact> = self
+            .ground
+            .filter(|&height| center.y < height + radius)
+            .map(|height| SphereContact {
+                normal: Vector3::unit_y(),
+                depth: height + radius - center.y,
+            });
+        let bounds = Aabb::new(center - Vector3::from_value(radius), center + Vector3::from_value(radius));
+        self.bvh.for_each_overlapping(&bounds, |triangle| {
+            let offset = center - triangle.closest_point(center);
+            let distance = offset.magnitude();
+            if distance >= radius || deepest.is_some_and(|c| c.depth >= radius - distance) {
+                return;
+            }
+            // Centers right on the surface are pushed out the front
+            let normal = if distance > f32::EPSILON {
+                offset / distance
+            } else {
+                triangle.face_normal()
+            };
+            deepest = Some(SphereContact {
+                normal,
+                depth: radius - distance,
+            });
+        });
+        return deepest;
+    }
+
+    // Move the sphere out of everything it overlaps, along the contact normals
+    fn push_out(&self, mut center: Vector3<f32>, radius: f32) -> Vector3<f32> {
+        for _ in 0..PUSH_ITERATIONS {
+            match self.contact(center, radius) {
+                Some(contact) => center += contact.normal * contact.depth,
+                None => break,
+            }
+        }
+        return center;
+    }
+
+    fn steps(translation: Vector3<f32>, radius: f32) -> usize {
+        let steps = translation.magnitude() * STEPS_PER_RADIUS / radius.max(f32::EPSILON);
+        return (steps.ceil() as usize).clamp(1, MAX_STEPS);
+    }
+
+    /// First point a sphere of `radius` moved from `from` to `to` touches the
+    /// mesh, found in steps of half the radius and refined by bisection.
+    pub fn sphere_cast(&self, from: Vector3<f32>, to: Vector3<f32>, radius: f32) -> Option<SphereHit> {
+        let translation = to - from;
+        let steps = Self::steps(translation, radius);
+        let mut free = 0.0;
+        for step in 1..=steps {
+            let fraction = step as f32 / steps as f32;
+            let contact = match self.contact(from + translation * fraction, radius) {
+                Some(contact) => contact,
+                None => {
+                    free = fraction;
+                    continue;
+                }
+            };
+            let (mut low, mut high, mut normal) = (free, fraction, contact.normal);
+            for _ in 0..8 {
+                let middle = (low + high) * 0.5;
+                match self.contact(from + translation * middle, radius) {
+                    Some(contact) => (high, normal) = (middle, contact.normal),
+                    None => low = middle,
+                }
+            }
+            return Some(SphereHit {
+                fraction: low,
+                center: from + translation * low,
+                normal,
+            });
+        }
+        return None;
+    }
+
+    /// Move a sphere of `radius` from `from` towards `to`, sliding along
+    /// whatever it touches instead of stopping: each step it's pushed back
+    /// out along the contact normal, which only takes away the part of the
+    /// move going into the surface.
+    pub fn slide(&self, from: Vector3<f32>, to: Vector3<f32>, radius: f32) -> Vector3<f32> {
+        let translation = to - from;
+        let steps = Self::steps(translation, radius);
+        let step = translation / steps as f32;
+        let mut center = self.push_out(from, radius);
+        for _ in 0..steps {
+            center = self.push_out(center + step, radius);
+        }
+        return center;
+    }
+}
+
+impl std::fmt::Debug for MeshCollider {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        return f.debug_struct("MeshCollider").field("ground", &self.ground).finish();
+    }
+}
@@ -0,0 +1,342 @@
+use std::path::{Path, PathBuf};
+
+use anyhow::*;
+use cgmath::{InnerSpace, Vector3};
+
+use crate::{
+    decode::{DecodedImage, PixelEncoding},
+    resources::resource_path,
+};
+
+// Start of every cache file, bumped whenever the encoder or layout changes
+const CACHE_MAGIC: &[u8; 4] = b"BCN1";
+// Appended to the source's file name
+const CACHE_EXTENSION: &str = "bcn";
+
+/// Decode a PNG or JPEG and compress it to BC1, or BC3 if it has any
+/// transparency, caching the result next to the file with a hash of its
+/// contents so later loads skip decoding and compressing altogether. Images
+/// whose sides aren't multiples of 4 stay RGBA8, as block compressed textures
+/// can't have them.
+pub fn decode_cached(source: &str, bytes: &[u8], generate_mips: bool) -> Result<DecodedImage> {
+    let hash = content_hash(bytes);
+    let path = cache_path(source);
+    if let Some(image) = read_cache(&path, hash, generate_mips) {
+        return Ok(image);
+    }
+    let image = DecodedImage::decode(bytes, generate_mips)?;
+    let compressed = match compress(&image) {
+        Some(compressed) => compressed,
+        None => return Ok(image),
+    };
+    if let Err(e) = write_cache(&path, hash, generate_mips, &compressed) {
+        log::warn!("Failed to cache compressed texture `{}`: {:?}", source, e);
+    }
+    return Ok(compressed);
+}
+
+/// Block compress every mip of an RGBA8 image, `None` if it already is or
+/// its size isn't a multiple of 4.
+pub fn compress(image: &DecodedImage) -> Option<DecodedImage> {
+    if image.encoding != PixelEncoding::Rgba8 || !image.width.is_multiple_of(4) || !image.height.is_multiple_of(4) {
+        return None;
+    }
+    let opaque = image.mips.first()?.chunks_exact(4).all(|p| p[3] == 255);
+    let encoding = if opaque { PixelEncoding::Bc1 } else { PixelEncoding::Bc3 };
+    let mips = image
+        .mips
+        .iter()
+        .enumerate()
+        .map(|(level, pixels)| {
+            let width = (image.width >> level).max(1);
+            let height = (image.height >> level).max(1);
+            compress_level(pixels, width, height, encoding)
+        })
+        .collect();
+    return Some(DecodedImage {
+        width: image.width,
+        height: image.height,
+        encoding,
+        mips,
+    });
+}
+
+// FNV-1a, stable across runs and toolchains unlike the std hasher
+fn content_hash(bytes: &[u8]) -> u64 {
+    let mut hash = 0xcbf29ce484222325u64;
+    for &byte in bytes {
+        hash = (hash ^ byte as u64).wrapping_mul(0x100000001b3);
+    }
+    return hash;
+}
+
+fn cache_path(source: &str) -> PathBuf {
+    let path = resource_path(source);
+    let mut name = path.file_name().unwrap_or_default().to_os_string();
+    name.push(".");
+    name.push(CACHE_EXTENSION);
+    return path.with_file_name(name);
+}
+
+// The cached image if there is one for these contents and mip setting, and
+// its size, mip count and mip sizes are what compressing it would give
+fn read_cache(path: &Path, hash: u64, generate_mips: bool) -> Option<DecodedImage> {
+    let data = std::fs::read(path).ok()?;
+    let mut reader = CacheReader { data: &data, offset: 0 };
+    if reader.bytes(4)? != CACHE_MAGIC || reader.u64()? != hash || reader.u8()? != generate_mips as u8 {
+        return None;
+    }
+    let encoding = match reader.u8()? {
+        1 => PixelEncoding::Bc1,
+        2 => PixelEncoding::Bc3,
+        _ => return None,
+    };
+    let (width, height, levels) = (reader.u32()?, reader.u32()?, reader.u32()?);
+    if width == 0 || height == 0 || !width.is_multiple_of(4) || !height.is_multiple_of(4) {
+        return None;
+    }
+    let expected_levels = if generate_mips { width.max(height).ilog2() + 1 } else { 1 };
+    if levels != expected_levels {
+        return None;
+    }
+    let mut mips = Vec::with_capacity(levels as usize);
+    for level in 0..levels {
+        let len = reader.u32()? as usize;
+        if len != compressed_size(width >> level, height >> level, encoding) {
+            return None;
+        }
+        mips.push(reader.bytes(len)?.to_vec());
+    }
+    if reader.offset != reader.data.len() {
+        return None;
+    }
+    return Some(DecodedImage {
+        width,
+        height,
+        encoding,
+        mips,
+    });
+}
+
+fn write_cache(path: &Path, hash: u64, generate_mips: bool, image: &DecodedImage) -> Result<()> {
+    let encoding: u8 = match image.encoding {
+        PixelEncoding::Bc1 => 1,
+        PixelEncoding::Bc3 => 2,
+        PixelEncoding::Rgba8 => bail!("Only compressed images are cached"),
+    };
+    let mut data = Vec::with_capacity(32 + image.mips.iter().map(|m| m.len() + 4).sum::<usize>());
+    data.extend_from_slice(CACHE_MAGIC);
+    data.extend_from_slice(&hash.to_le_bytes());
+    data.push(generate_mips as u8);
+    data.push(encoding);
+    for value in [image.width, image.height, image.mips.len() as u32] {
+        data.extend_from_slice(&value.to_le_bytes());
+    }
+    for mip in &image.mips {
+        data.extend_from_slice(&(mip.len() as u32).to_le_bytes());
+        data.extend_from_slice(mip);
+    }
+    // Written aside and renamed, so a load never sees half a file
+    let partial = path.with_extension("partial");
+    std::fs::write(&partial, data)?;
+    std::fs::rename(&partial, path)?;
+    return Ok(());
+}
+
+// Bytes of a level of `width` by `height` pixels, at least one block
+fn compressed_size(width: u32, height: u32, encoding: PixelEncoding) -> usize {
+    let block_size = if encoding == PixelEncoding::Bc1 { 8 } else { 16 };
+    let blocks = width.max(1).div_ceil(4) as usize * height.max(1).div_ceil(4) as usize;
+    return blocks * block_size;
+}
+
+struct CacheReader<'a> {
+    data: &'a [u8],
+    offset: usize,
+}
+
+impl<'a> CacheReader<'a> {
+    fn bytes(&mut self, len: usize) -> Option<&'a [u8]> {
+        let bytes = self.data.get(self.offset..self.offset.checked_add(len)?)?;
+        self.offset += len;
+        return Some(bytes);
+    }
+
+    fn u8(&mut self) -> Option<u8> {
+        return Some(self.bytes(1)?[0]);
+    }
+
+    fn u32(&mut self) -> Option<u32> {
+        return Some(u32::from_le_bytes(self.bytes(4)?.try_into().ok()?));
+    }
+
+    fn u64(&mut self) -> Option<u64> {
+        return Some(u64::from_le_bytes(self.bytes(8)?.try_into().ok()?));
+    }
+}
+
+// Blocks of one mip level, row by row. Levels smaller than a block repeat
+// their edge pixels
+fn compress_level(pixels: &[u8], width: u32, height: u32, encoding: PixelEncoding) -> Vec<u8> {
+    let (blocks_x, blocks_y) = (width.div_ceil(4), height.div_ceil(4));
+    let mut out = Vec::with_capacity((blocks_x * blocks_y) as usize * 16);
+    for by in 0..blocks_y {
+        for bx in 0..blocks_x {
+            let mut block = [[0u8; 4]; 16];
+            for (i, texel) in block.iter_mut().enumerate() {
+                let x = (bx * 4 + i as u32 % 4).min(width - 1);
+                let y = (by * 4 + i as u32 / 4).min(height - 1);
+                let offset = ((y * width + x) * 4) as usize;
+                texel.copy_from_slice(&pixels[offset..offset + 4]);
+            }
+            if encoding == PixelEncoding::Bc3 {
+                out.extend_from_slice(&encode_alpha_block(&block));
+            }
+            out.extend_from_slice(&encode_color_block(&block));
+        }
+    }
+    return out;
+}
+
+fn to_565(color: Vector3<f32>) -> u16 {
+    let r = (color.x.clamp(0.0, 255.0) * 31.0 / 255.0).round() as u16;
+    let g = (color.y.clamp(0.0, 255.0) * 63.0 / 255.0).round() as u16;
+    let b = (color.z.clamp(0.0, 255.0) * 31.0 / 255.0).round() as u16;
+    return (r << 11) | (g << 5) | b;
+}
+
+fn from_565(color: u16) -> Vector3<f32> {
+    let (r, g, b) = ((color >> 11) & 31, (color >> 5) & 63, color & 31);
+    return Vector3::new(((r << 3) | (r >> 2)) as f32, ((g << 2) | (g >> 4)) as f32, ((b << 3) | (b >> 2)) as f32);
+}
+
+// Closest of the four palette colors for each texel and the squared error,
+// for endpoints with `c0 > c1`. Equal ones would select the three color
+// mode, all texels get c0
+fn color_indices(colors: &[Vector3<f32>; 16], c0: u16, c1: u16) -> (u32, f32) {
+    let (e0, e1) = (from_565(c0), from_565(c1));
+    if c0 == c1 {
+        return (0, colors.iter().map(|c| (e0 - c).magnitude2()).sum());
+    }
+    let palette = [e0, e1, (e0 * 2.0 + e1) / 3.0, (e0 + e1 * 2.0) / 3.0];
+    let (mut indices, mut error) = (0u32, 0.0);
+    for (i, c) in colors.iter().enumerate() {
+        let (index, distance) = palette
+            .iter()
+            .map(|p| (p - c).magnitude2())
+            .enumerate()
+            .min_by(|a, b| a.1.total_cmp(&b.1))
+            .unwrap_or((0, 0.0));
+        indices |= (index as u32) << (i * 2);
+        error += distance;
+    }
+    return (indices, error);
+}
+
+// Endpoints that fit the texels best in the least squares sense for the
+// palette entries they were given
+fn refine_endpoints(colors: &[Vector3<f32>; 16], indices: u32) -> Option<(Vector3<f32>, Vector3<f32>)> {
+    // Weight of the first endpoint in each palette entry
+    const WEIGHTS: [f32; 4] = [1.0, 0.0, 2.0 / 3.0, 1.0 / 3.0];
+    let zero = Vector3::new(0.0, 0.0, 0.0);
+    let (mut aa, mut ab, mut bb, mut ax, mut bx) = (0.0, 0.0, 0.0, zero, zero);
+    for (i, c) in colors.iter().enumerate() {
+        let a = WEIGHTS[(indices >> (i * 2)) as usize & 3];
+        let b = 1.0 - a;
+        aa += a * a;
+        ab += a * b;
+        bb += b * b;
+        ax += c * a;
+        bx += c * b;
+    }
+    let determinant = aa * bb - ab * ab;
+    if determinant.abs() < 1e-6 {
+        return None;
+    }
+    return Some(((ax * bb - bx * ab) / determinant, (bx * aa - ax * ab) / determinant));
+}
+
+// Endpoint pair in the order that selects the four color mode
+fn ordered(a: u16, b: u16) -> (u16, u16) {
+    return if a < b { (b, a) } else { (a, b) };
+}
+
+// BC1 color block: endpoints at the extremes of the texels along their
+// principal axis, refined once by least squares, each texel taking the
+// closest of the four palette colors
+fn encode_color_block(block: &[[u8; 4]; 16]) -> [u8; 8] {
+    let colors = block.map(|t| Vector3::new(t[0] as f32, t[1] as f32, t[2] as f32));
+    let mean = colors.iter().fold(Vector3::new(0.0, 0.0, 0.0), |sum, c| sum + c) / 16.0;
+    let mut covariance = [[0.0f32; 3]; 3];
+    for c in &colors {
+        let d = c - mean;
+        let d = [d.x, d.y, d.z];
+        for (i, row) in covariance.iter_mut().enumerate() {
+            for (j, value) in row.iter_mut().enumerate() {
+                *value += d[i] * d[j];
+            }
+        }
+    }
+    // Power iteration from the spread of the block's bounding box
+    let (min, max) = colors.iter().fold((colors[0], colors[0]), |(min, max), c| {
+        (
+            Vector3::new(min.x.min(c.x), min.y.min(c.y), min.z.min(c.z)),
+            Vector3::new(max.x.max(c.x), max.y.max(c.y), max.z.max(c.z)),
+        )
+    });
+    let mut axis = max - min;
+    for _ in 0..4 {
+        let next = Vector3::new(
+            covariance[0][0] * axis.x + covariance[0][1] * axis.y + covariance[0][2] * axis.z,
+            covariance[1][0] * axis.x + covariance[1][1] * axis.y + covariance[1][2] * axis.z,
+            covariance[2][0] * axis.x + covariance[2][1] * axis.y + covariance[2][2] * axis.z,
+        );
+        if next.magnitude2() < 1e-6 {
+            break;
+        }
+        axis = next.normalize();
+    }
+    let project = |c: &Vector3<f32>| (c - mean).dot(axis);
+    let (low, high) = colors.iter().fold((colors[0], colors[0]), |(low, high), c| {
+        let low = if project(c) < project(&low) { *c } else { low };
+        let high = if project(c) > project(&high) { *c } else { high };
+        (low, high)
+    });
+
+    let (mut c0, mut c1) = ordered(to_565(high), to_565(low));
+    let (mut indices, error) = color_indices(&colors, c0, c1);
+    if let Some((high, low)) = refine_endpoints(&colors, indices) {
+        let (r0, r1) = ordered(to_565(high), to_565(low));
+        let (refined, refined_error) = color_indices(&colors, r0, r1);
+        if refined_error < error {
+            (c0, c1, indices) = (r0, r1, refined);
+        }
+    }
+    let mut out = [0u8; 8];
+    out[0..2].copy_from_slice(&c0.to_le_bytes());
+    out[2..4].copy_from_slice(&c1.to_le_bytes());
+    out[4..8].copy_from_slice(&indices.to_le_bytes());
+    return out;
+}
+
+// BC3 alpha block: the block's alpha range split into eight steps
+fn encode_alpha_block(block: &[[u8; 4]; 16]) -> [u8; 8] {
+    let (a0, a1) = block.iter().fold((0u8, 255u8), |(max, min), t| (max.max(t[3]), min.min(t[3])));
+    let mut indices = 0u64;
+    if a0 > a1 {
+        let mut palette = [a0 as u32, a1 as u32, 0, 0, 0, 0, 0, 0];
+        for (i, value) in palette.iter_mut().enumerate().skip(2) {
+            let step = i as u32 - 1;
+            *value = ((7 - step) * a0 as u32 + step * a1 as u32) / 7;
+        }
+        for (i, t) in block.iter().enumerate() {
+            let index = (0..8).min_by_key(|&k| (palette[k] as i32 - t[3] as i32).abs()).unwrap_or(0);
+            indices |= (index as u64) << (i * 3);
+        }
+    }
+    let mut out = [0u8; 8];
+    out[0] = a0;
+    out[1] = a1;
+    out[2..8].copy_from_slice(&indices.to_le_bytes()[0..6]);
+    return out;
+}
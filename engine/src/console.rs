@@ -0,0 +1,257 @@
+use std::collections::VecDeque;
+
+use winit::event::VirtualKeyCode;
+
+use crate::{overlay::Overlay, renderer::Renderer};
+
+const ROW_HEIGHT: f32 = 12.0;
+const PADDING: f32 = 8.0;
+const INPUT_COLOR: [f32; 4] = [1.0, 0.9, 0.2, 1.0];
+const OUTPUT_COLOR: [f32; 4] = [1.0, 1.0, 1.0, 1.0];
+const ERROR_COLOR: [f32; 4] = [1.0, 0.3, 0.2, 1.0];
+// Oldest lines past these are dropped
+const MAX_OUTPUT: usize = 200;
+const MAX_HISTORY: usize = 50;
+
+/// Runs a console command with the words typed after its name, returning
+/// what to print.
+pub type CommandHandler = Box<dyn FnMut(&mut Renderer, &[&str]) -> anyhow::Result<String>>;
+
+struct Command {
+    name: String,
+    handler: CommandHandler,
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+enum LineKind {
+    Input,
+    Output,
+    Error,
+}
+
+/// Drop-down developer console. While open it takes the typed characters
+/// and keys: Enter runs the line, Up and Down walk the history, Tab
+/// completes command names and Escape closes it. `help` lists the commands
+/// and `clear` empties the output.
+#[derive(Default)]
+pub struct Console {
+    pub open: bool,
+    /// Output lines shown above the input line.
+    pub rows: usize,
+    input: String,
+    history: Vec<String>,
+    // Entry of `history` shown in the input line, `None` for what's typed
+    history_index: Option<usize>,
+    output: VecDeque<(LineKind, String)>,
+    commands: Vec<Command>,
+    // Set by `toggle` to drop the character typed by the toggle's chord,
+    // whichever key it is bound to. Cleared by the next key
+    skip_char: bool,
+}
+
+impl Console {
+    pub fn new() -> Self {
+        return Self {
+            rows: 12,
+            ..Self::default()
+        };
+    }
+
+    /// Add a command, replacing any registered under `name` before.
+    pub fn register<F: FnMut(&mut Renderer, &[&str]) -> anyhow::Result<String> + 'static>(
+        &mut self,
+        name: &str,
+        handler: F,
+    ) {
+        self.commands.retain(|c| c.name != name);
+        self.commands.push(Command {
+            name: name.to_string(),
+            handler: Box::new(handler),
+        });
+    }
+
+    pub fn command_names(&self) -> impl Iterator<Item = &str> {
+        return self.commands.iter().map(|c| c.name.as_str());
+    }
+
+    pub fn input(&self) -> &str {
+        return &self.input;
+    }
+
+    /// Lines run, oldest first.
+    pub fn history(&self) -> &[String] {
+        return &self.history;
+    }
+
+    pub fn print(&mut self, line: &str) {
+        self.push(LineKind::Output, line);
+    }
+
+    pub fn print_error(&mut self, line: &str) {
+        self.push(LineKind::Error, line);
+    }
+
+    pub fn clear(&mut self) {
+        self.output.clear();
+    }
+
+    fn push(&mut self, kind: LineKind, text: &str) {
+        for line in text.lines() {
+            self.output.push_back((kind, line.to_string()));
+        }
+        while self.output.len() > MAX_OUTPUT {
+            self.output.pop_front();
+        }
+    }
+
+    /// Open or close the console, for the toggle action.
+    pub fn toggle(&mut self) {
+        self.open = !self.open;
+        self.skip_char = true;
+    }
+
+    /// Type `ch` into the input line. The toggle's character and control
+    /// characters are left out, they come with keys handled by `toggle` and
+    /// `key_pressed`.
+    pub fn receive_char(&mut self, ch: char) {
+        if std::mem::take(&mut self.skip_char) || ch.is_control() {
+            return;
+        }
+        self.input.push(ch);
+        self.history_index = None;
+    }
+
+    /// Edit the input line with `key`. Returns the line to run when Enter is
+    /// pressed, see `execute`.
+    pub fn key_pressed(&mut self, key: VirtualKeyCode) -> Option<String> {
+        self.skip_char = false;
+        match key {
+            VirtualKeyCode::Return | VirtualKeyCode::NumpadEnter => {
+                let line = std::mem::take(&mut self.input);
+                self.history_index = None;
+                if line.trim().is_empty() {
+                    return None;
+                }
+                if self.history.last() != Some(&line) {
+                    self.history.push(line.clone());
+                }
+                if self.history.len() > MAX_HISTORY {
+                    self.history.remove(0);
+                }
+                return Some(line);
+            }
+            VirtualKeyCode::Back => {
+                self.input.pop();
+            }
+            VirtualKeyCode::Up if !self.history.is_empty() => {
+                let index = match self.history_index {
+                    Some(index) => index.saturating_sub(1),
+                    None => self.history.len() - 1,
+                };
+                self.history_index = Some(index);
+                self.input = self.history[index].clone();
+            }
+            VirtualKeyCode::Down => {
+                if let Some(index) = self.history_index {
+                    self.history_index = (index + 1 < self.history.len()).then_some(index + 1);
+                    self.input = self.history_index.map(|i| self.history[i].clone()).unwrap_or_default();
+                }
+            }
+            VirtualKeyCode::Tab => self.complete(),
+            VirtualKeyCode::Escape => self.open = false,
+            _ => {}
+        }
+        return None;
+    }
+
+    // Complete the command name being typed if only one command starts with it
+    fn complete(&mut self) {
+        if self.input.contains(' ') {
+            return;
+        }
+        let matches = self
+            .command_names()
+            .filter(|name| name.starts_with(self.input.as_str()))
+            .collect::<Vec<_>>();
+        if let [name] = matches[..] {
+            self.input = format!("{} ", name);
+        }
+    }
+
+    /// Echo `line` and run the command it names on `renderer`, printing what
+    /// it returns.
+    pub fn execute(renderer: &mut Renderer, line: &str) {
+        let console = &mut renderer.console;
+        console.push(LineKind::Input, &format!("> {}", line));
+        let words = line.split_whitespace().collect::<Vec<_>>();
+        let (name, args) = match words.split_first() {
+            Some((name, args)) => (*name, args),
+            None => return,
+        };
+        match name {
+            "help" => {
+                let names = console.command_names().collect::<Vec<_>>().join(" ");
+                console.print(&format!("help clear {}", names));
+                return;
+            }
+            "clear" => {
+                console.clear();
+                return;
+            }
+            _ => {}
+        }
+        let index = match console.commands.iter().position(|c| c.name == name) {
+            Some(index) => index,
+            None => {
+                console.print_error(&format!("Unknown command {}", name));
+                return;
+            }
+        };
+
+        // Handlers get the whole renderer, so theirs is taken out of the
+        // console while it runs, like scene handlers
+        let mut command = console.commands.remove(index);
+        let result = (command.handler)(renderer, args);
+        let console = &mut renderer.console;
+        if !console.commands.iter().any(|c| c.name == command.name) {
+            console.commands.insert(index.min(console.commands.len()), command);
+        }
+        match result {
+            Ok(output) => console.print(&output),
+            Err(e) => console.print_error(&e.to_string()),
+        }
+    }
+
+    /// Height of the console drawn by `draw`.
+    pub fn height(&self) -> f32 {
+        return (self.rows as f32 + 1.0) * ROW_HEIGHT * 1.5 + PADDING * 2.0;
+    }
+
+    /// Queue the console into `overlay` across the top of a `width` wide
+    /// window, the newest output just above the input line.
+    pub fn draw(&self, overlay: &mut Overlay, width: f32) {
+        if !self.open {
+            return;
+        }
+        let height = self.height();
+        overlay.fill_rect([0.0, 0.0], [width, height], [0.0, 0.0, 0.0, 0.75]);
+
+        let mut y = height - PADDING - ROW_HEIGHT;
+        let prompt = format!("> {}", self.input);
+        overlay.text([PADDING, y], &prompt, ROW_HEIGHT, INPUT_COLOR);
+        // Cursor after the last character, advancing roughly like `Overlay::text`
+        let cursor_x = PADDING + prompt.chars().count() as f32 * ROW_HEIGHT * 0.75;
+        overlay.fill_rect([cursor_x, y], [cursor_x + ROW_HEIGHT * 0.5, y + ROW_HEIGHT], INPUT_COLOR);
+        overlay.line([0.0, y - ROW_HEIGHT * 0.25], [width, y - ROW_HEIGHT * 0.25], [1.0, 1.0, 1.0, 0.3], 1.0);
+
+        for (kind, line) in self.output.iter().rev().take(self.rows) {
+            y -= ROW_HEIGHT * 1.5;
+            let color = match kind {
+                LineKind::Input => INPUT_COLOR,
+                LineKind::Output => OUTPUT_COLOR,
+                LineKind::Error => ERROR_COLOR,
+            };
+            overlay.text([PADDING, y], line, ROW_HEIGHT, color);
+        }
+    }
+}
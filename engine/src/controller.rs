@@ -1,10 +1,176 @@
-use winit::event::{ElementState, MouseButton, VirtualKeyCode};
+use std::sync::Arc;
+
+use cgmath::{Deg, Rad};
+use serde::{Deserialize, Serialize};
+use winit::{
+    dpi::PhysicalPosition,
+    event::{DeviceEvent, ElementState, Event, KeyboardInput, MouseButton, MouseScrollDelta, VirtualKeyCode, WindowEvent},
+};
+
+use crate::collision::MeshCollider;
 
 pub enum ControllerEvent {
     MouseMove((f64, f64)),
     MouseScroll(f32),
     MouseInput(ElementState, MouseButton),
     KeyboardInput(ElementState, VirtualKeyCode),
+    // Replace the controller's settings, e.g. after the user changed them
+    Configure(ControllerSettings),
+    // Keep the camera out of this mesh from now on, `None` to fly through
+    Collide(Option<Arc<MeshCollider>>),
+    // Zoom smoothly to this vertical field of view
+    Zoom(Rad<f32>),
+}
+
+impl ControllerEvent {
+    // The controller's part of a winit event, mouse motion only turns the
+    // camera in `InputMode::GameLook`
+    pub fn from_event(event: &Event<()>, mode: InputMode) -> Option<Self> {
+        return match event {
+            Event::DeviceEvent {
+                event: DeviceEvent::MouseMotion { delta },
+                ..
+            } if mode == InputMode::GameLook => Some(ControllerEvent::MouseMove(*delta)),
+            Event::WindowEvent { event, .. } => match event {
+                WindowEvent::KeyboardInput {
+                    input:
+                        KeyboardInput {
+                            state,
+                            virtual_keycode: Some(key),
+                            ..
+                        },
+                    ..
+                } => Some(ControllerEvent::KeyboardInput(*state, *key)),
+                WindowEvent::MouseInput { state, button, .. } => Some(ControllerEvent::MouseInput(*state, *button)),
+                WindowEvent::MouseWheel { delta, .. } => Some(ControllerEvent::MouseScroll(match delta {
+                    MouseScrollDelta::LineDelta(_, scroll) => scroll * 100.0,
+                    MouseScrollDelta::PixelDelta(PhysicalPosition { y: scroll, .. }) => *scroll as f32,
+                })),
+                _ => None,
+            },
+            _ => None,
+        };
+    }
+}
+
+/// Where mouse input goes.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum InputMode {
+    // Mouse motion turns the camera, the cursor is hidden and kept in the window
+    GameLook,
+    // The cursor is free for the overlay and tools, the camera only moves with keys
+    UIInteract,
+}
+
+impl InputMode {
+    pub fn toggled(self) -> Self {
+        return match self {
+            InputMode::GameLook => InputMode::UIInteract,
+            InputMode::UIInteract => InputMode::GameLook,
+        };
+    }
+}
+
+/// What the mouse wheel does on the FPS camera.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum ScrollMode {
+    // Move along the view direction
+    Dolly,
+    // Narrow or widen the field of view
+    Fov,
+}
+
+/// How the FPS camera turns.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum LookMode {
+    // Upright, yawing around the world's up axis and pitching short of
+    // straight up or down, without roll
+    Fps,
+    // Around the camera's own axes with roll from the roll keys, without
+    // gimbal limits, for flight and space scenes
+    Free,
+}
+
+/// A key and an optional alternative doing the same.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct KeyBinding {
+    pub key: VirtualKeyCode,
+    pub alternate: Option<VirtualKeyCode>,
+}
+
+impl KeyBinding {
+    pub const fn new(key: VirtualKeyCode, alternate: Option<VirtualKeyCode>) -> Self {
+        return Self { key, alternate };
+    }
+
+    pub fn matches(&self, key: VirtualKeyCode) -> bool {
+        return self.key == key || self.alternate == Some(key);
+    }
+}
+
+/// Keys moving the FPS camera.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct KeyBindings {
+    pub forward: KeyBinding,
+    pub backward: KeyBinding,
+    pub left: KeyBinding,
+    pub right: KeyBinding,
+    pub up: KeyBinding,
+    pub down: KeyBinding,
+    /// Only in `LookMode::Free`.
+    pub roll_left: KeyBinding,
+    pub roll_right: KeyBinding,
+}
+
+impl Default for KeyBindings {
+    fn default() -> Self {
+        Self {
+            forward: KeyBinding::new(VirtualKeyCode::W, Some(VirtualKeyCode::Up)),
+            backward: KeyBinding::new(VirtualKeyCode::S, Some(VirtualKeyCode::Down)),
+            left: KeyBinding::new(VirtualKeyCode::A, Some(VirtualKeyCode::Left)),
+            right: KeyBinding::new(VirtualKeyCode::D, Some(VirtualKeyCode::Right)),
+            up: KeyBinding::new(VirtualKeyCode::Space, None),
+            down: KeyBinding::new(VirtualKeyCode::LShift, None),
+            roll_left: KeyBinding::new(VirtualKeyCode::Q, None),
+            roll_right: KeyBinding::new(VirtualKeyCode::E, None),
+        }
+    }
+}
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct ControllerSettings {
+    pub scroll_mode: ScrollMode,
+    pub look_mode: LookMode,
+    /// Roll per second while a roll key is held.
+    pub roll_speed: Rad<f32>,
+    /// Multiplies the camera's own mouse look sensitivity.
+    pub look_sensitivity: f32,
+    pub keys: KeyBindings,
+    /// Field of view change per unit of scroll in `ScrollMode::Fov`, one
+    /// wheel line is 100 units.
+    pub fov_per_scroll: Rad<f32>,
+    pub min_fovy: Rad<f32>,
+    pub max_fovy: Rad<f32>,
+    /// Radius of the sphere kept out of the collider given to
+    /// `Renderer::set_camera_collider`.
+    pub collision_radius: f32,
+}
+
+impl Default for ControllerSettings {
+    fn default() -> Self {
+        Self {
+            scroll_mode: ScrollMode::Dolly,
+            look_mode: LookMode::Fps,
+            roll_speed: Deg(90.0).into(),
+            look_sensitivity: 1.0,
+            keys: KeyBindings::default(),
+            fov_per_scroll: Deg(0.05).into(),
+            min_fovy: Deg(10.0).into(),
+            max_fovy: Deg(90.0).into(),
+            collision_radius: 0.3,
+        }
+    }
 }
 
 pub trait Controller {
@@ -0,0 +1,252 @@
+use anyhow::*;
+use cgmath::Vector3;
+use image::{DynamicImage, GenericImageView, RgbaImage};
+
+/// Face of a cube map, in layer order.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum CubeFace {
+    PositiveX,
+    NegativeX,
+    PositiveY,
+    NegativeY,
+    PositiveZ,
+    NegativeZ,
+}
+
+impl CubeFace {
+    pub const ALL: [CubeFace; 6] = [
+        CubeFace::PositiveX,
+        CubeFace::NegativeX,
+        CubeFace::PositiveY,
+        CubeFace::NegativeY,
+        CubeFace::PositiveZ,
+        CubeFace::NegativeZ,
+    ];
+
+    pub fn index(self) -> usize {
+        return self as usize;
+    }
+
+    /// Direction through a point of the face, `s` and `t` going from -1 to 1
+    /// right and down the face image. This is how every backend samples cube
+    /// maps: seen from inside, +X is right of +Z and +Y above it.
+    pub fn direction(self, s: f32, t: f32) -> Vector3<f32> {
+        return match self {
+            CubeFace::PositiveX => Vector3::new(1.0, -t, -s),
+            CubeFace::NegativeX => Vector3::new(-1.0, -t, s),
+            CubeFace::PositiveY => Vector3::new(s, 1.0, t),
+            CubeFace::NegativeY => Vector3::new(s, -1.0, -t),
+            CubeFace::PositiveZ => Vector3::new(s, -t, 1.0),
+            CubeFace::NegativeZ => Vector3::new(-s, -t, -1.0),
+        };
+    }
+
+    /// Face `direction` points through and the point on it, the inverse of
+    /// `direction`.
+    pub fn from_direction(direction: Vector3<f32>) -> (CubeFace, f32, f32) {
+        let Vector3 { x, y, z } = direction;
+        let (ax, ay, az) = (x.abs(), y.abs(), z.abs());
+        return if ax >= ay && ax >= az {
+            if x > 0.0 {
+                (CubeFace::PositiveX, -z / ax, -y / ax)
+            } else {
+                (CubeFace::NegativeX, z / ax, -y / ax)
+            }
+        } else if ay >= az {
+            if y > 0.0 {
+                (CubeFace::PositiveY, x / ay, z / ay)
+            } else {
+                (CubeFace::NegativeY, x / ay, -z / ay)
+            }
+        } else if z > 0.0 {
+            (CubeFace::PositiveZ, x / az, -y / az)
+        } else {
+            (CubeFace::NegativeZ, -x / az, -y / az)
+        };
+    }
+}
+
+/// Orientation fix of a face image: an optional horizontal flip followed by
+/// clockwise quarter turns.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Default)]
+pub struct FaceTransform {
+    pub flip: bool,
+    pub quarter_turns: u8,
+}
+
+impl FaceTransform {
+    pub const IDENTITY: FaceTransform = FaceTransform {
+        flip: false,
+        quarter_turns: 0,
+    };
+
+    /// Every distinct orientation, the identity first.
+    pub fn all() -> impl Iterator<Item = FaceTransform> {
+        return [false, true].into_iter().flat_map(|flip| {
+            (0..4).map(move |quarter_turns| FaceTransform { flip, quarter_turns })
+        });
+    }
+
+    pub fn apply(&self, image: &DynamicImage) -> DynamicImage {
+        let mut image = if self.flip { image.fliph() } else { image.clone() };
+        for _ in 0..self.quarter_turns % 4 {
+            image = image.rotate90();
+        }
+        return image;
+    }
+
+    // Texel of the untransformed `size` square image shown at `x`, `y`
+    fn source(&self, mut x: u32, mut y: u32, size: u32) -> (u32, u32) {
+        for _ in 0..self.quarter_turns % 4 {
+            (x, y) = (y, size - 1 - x);
+        }
+        if self.flip {
+            x = size - 1 - x;
+        }
+        return (x, y);
+    }
+}
+
+/// Fixes for cube face images exported with other conventions, applied
+/// before `Environment::from_faces`.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Default)]
+pub struct CubemapImport {
+    /// Mirror the cube along x, for faces of the other handedness: swaps the
+    /// +X and -X faces and flips every face horizontally. Seams look the same
+    /// either way, so check the unwrap view for mirrored text or landmarks.
+    pub mirror: bool,
+    /// Applied after mirroring, in layer order.
+    pub transforms: [FaceTransform; 6],
+}
+
+impl CubemapImport {
+    /// Orientations that join the seams of `faces` best, see `validate_faces`.
+    pub fn suggested(faces: &[DynamicImage; 6]) -> Result<Self> {
+        let reports = validate_faces(faces)?;
+        return Ok(Self {
+            mirror: false,
+            transforms: reports.map(|r| r.suggested),
+        });
+    }
+
+    pub fn apply(&self, faces: &[DynamicImage; 6]) -> [DynamicImage; 6] {
+        let source = |face: CubeFace| {
+            let index = match (self.mirror, face) {
+                (true, CubeFace::PositiveX) => CubeFace::NegativeX.index(),
+                (true, CubeFace::NegativeX) => CubeFace::PositiveX.index(),
+                _ => face.index(),
+            };
+            if self.mirror {
+                faces[index].fliph()
+            } else {
+                faces[index].clone()
+            }
+        };
+        return CubeFace::ALL.map(|face| self.transforms[face.index()].apply(&source(face)));
+    }
+}
+
+/// Seam check of one cube face.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct FaceReport {
+    pub face: CubeFace,
+    /// Mean color difference between the face's edge texels and the texels
+    /// across the edge, from 0 to 1.
+    pub seam_error: f32,
+    /// Orientation joining the seams best, the identity unless clearly better.
+    pub suggested: FaceTransform,
+    /// Seam error with every face in its suggested orientation.
+    pub suggested_error: f32,
+}
+
+impl FaceReport {
+    pub fn looks_wrong(&self) -> bool {
+        return self.suggested != FaceTransform::IDENTITY;
+    }
+}
+
+/// Compare the edges of every face with its neighbors to find rotated or
+/// flipped faces. Faces must be square and of equal size.
+pub fn validate_faces(faces: &[DynamicImage; 6]) -> Result<[FaceReport; 6]> {
+    let size = faces[0].width();
+    ensure!(
+        size > 0 && faces.iter().all(|f| f.dimensions() == (size, size)),
+        "Cube faces must be square and of equal size"
+    );
+    let faces = Seams {
+        images: faces.iter().map(|f| f.to_rgba8()).collect(),
+        size,
+    };
+
+    let original = [FaceTransform::IDENTITY; 6];
+    let mut transforms = original;
+    // A face next to a wrong one looks worse than it is, a second round
+    // revisits faces once their neighbors are fixed
+    for _ in 0..2 {
+        for face in CubeFace::ALL {
+            let current = faces.error(&transforms, face);
+            let (best, best_error) = FaceTransform::all()
+                .map(|t| {
+                    let mut candidate = transforms;
+                    candidate[face.index()] = t;
+                    (t, faces.error(&candidate, face))
+                })
+                .fold((transforms[face.index()], current), |best, c| if c.1 < best.1 { c } else { best });
+            if best_error < current * Seams::CLEARLY_BETTER && current - best_error > Seams::MIN_IMPROVEMENT {
+                transforms[face.index()] = best;
+            }
+        }
+    }
+
+    return Ok(CubeFace::ALL.map(|face| FaceReport {
+        face,
+        seam_error: faces.error(&original, face),
+        suggested: transforms[face.index()],
+        suggested_error: faces.error(&transforms, face),
+    }));
+}
+
+// Face images for comparing texels across the cube's edges
+struct Seams {
+    images: Vec<RgbaImage>,
+    size: u32,
+}
+
+impl Seams {
+    // Orientations replace the current one when they at least halve the error
+    // by a noticeable amount, faces of a plain color fit any orientation
+    const CLEARLY_BETTER: f32 = 0.5;
+    const MIN_IMPROVEMENT: f32 = 0.01;
+
+    fn texel(&self, transforms: &[FaceTransform; 6], face: CubeFace, x: u32, y: u32) -> [f32; 3] {
+        let (x, y) = transforms[face.index()].source(x, y, self.size);
+        let p = self.images[face.index()].get_pixel(x, y);
+        return [p[0] as f32, p[1] as f32, p[2] as f32].map(|c| c / 255.0);
+    }
+
+    // Mean difference between the edge texels of `face` and their neighbors
+    fn error(&self, transforms: &[FaceTransform; 6], face: CubeFace) -> f32 {
+        let n = self.size;
+        let to_st = |i: u32| (i as f32 + 0.5) / n as f32 * 2.0 - 1.0;
+        let to_texel = |c: f32| (((c + 1.0) * 0.5 * n as f32) as u32).min(n - 1);
+        // Texel centers one texel past the edge land on the neighbor's edge
+        let outside = 1.0 + 1.0 / n as f32;
+
+        let mut sum = 0.0;
+        for i in 0..n {
+            let edges = [
+                ((i, 0), (to_st(i), -outside)),
+                ((i, n - 1), (to_st(i), outside)),
+                ((0, i), (-outside, to_st(i))),
+                ((n - 1, i), (outside, to_st(i))),
+            ];
+            for ((x, y), (s, t)) in edges {
+                let (neighbor, ns, nt) = CubeFace::from_direction(face.direction(s, t));
+                let a = self.texel(transforms, face, x, y);
+                let b = self.texel(transforms, neighbor, to_texel(ns), to_texel(nt));
+                sum += (a[0] - b[0]).abs() + (a[1] - b[1]).abs() + (a[2] - b[2]).abs();
+            }
+        }
+        return sum / (n * 4 * 3) as f32;
+    }
+}
\ No newline at end of file
@@ -0,0 +1,68 @@
+// Cube map unwrapped into a horizontal cross for the overlay: +Y above
+// -X, +Z, +X, -Z and -Y below, as seen from inside the cube. Face directions
+// match `CubeFace::direction`
+struct Panel {
+    // xy: target size in pixels, zw: top left corner of the cross
+    screen: vec4<f32>,
+    // x: face size in pixels, y: exposure
+    params: vec4<f32>,
+};
+@group(0) @binding(0)
+var<uniform> panel: Panel;
+@group(0) @binding(1)
+var cube_texture: texture_cube<f32>;
+@group(0) @binding(2)
+var cube_sampler: sampler;
+
+struct VertexOutput {
+    @builtin(position) clip_position: vec4<f32>,
+    // Position in faces from the top left corner of the cross
+    @location(0) local: vec2<f32>,
+};
+
+@vertex
+fn vs_main(@builtin(vertex_index) index: u32) -> VertexOutput {
+    // Two triangles covering the 4 by 3 faces around the cross
+    var corners = array<vec2<f32>, 6>(
+        vec2<f32>(0.0, 0.0),
+        vec2<f32>(4.0, 0.0),
+        vec2<f32>(4.0, 3.0),
+        vec2<f32>(0.0, 0.0),
+        vec2<f32>(4.0, 3.0),
+        vec2<f32>(0.0, 3.0),
+    );
+    let local = corners[index];
+    let pixel = panel.screen.zw + local * panel.params.x;
+    let ndc = pixel / panel.screen.xy * 2.0 - 1.0;
+
+    var out: VertexOutput;
+    out.clip_position = vec4<f32>(ndc.x, -ndc.y, 0.0, 1.0);
+    out.local = local;
+    return out;
+}
+
+@fragment
+fn fs_main(in: VertexOutput) -> @location(0) vec4<f32> {
+    let cell = floor(in.local);
+    let st = (in.local - cell) * 2.0 - 1.0;
+    let s = st.x;
+    let t = st.y;
+    var direction = vec3<f32>(0.0);
+    if (cell.x == 1.0 && cell.y == 0.0) {
+        direction = vec3<f32>(s, 1.0, t);
+    } else if (cell.x == 1.0 && cell.y == 2.0) {
+        direction = vec3<f32>(s, -1.0, -t);
+    } else if (cell.y == 1.0 && cell.x == 0.0) {
+        direction = vec3<f32>(-1.0, -t, s);
+    } else if (cell.y == 1.0 && cell.x == 1.0) {
+        direction = vec3<f32>(s, -t, 1.0);
+    } else if (cell.y == 1.0 && cell.x == 2.0) {
+        direction = vec3<f32>(1.0, -t, -s);
+    } else if (cell.y == 1.0 && cell.x == 3.0) {
+        direction = vec3<f32>(-s, -t, -1.0);
+    } else {
+        discard;
+    }
+    let color = textureSampleLevel(cube_texture, cube_sampler, direction, 0.0).rgb * panel.params.y;
+    return vec4<f32>(clamp(color, vec3<f32>(0.0), vec3<f32>(1.0)), 1.0);
+}
\ No newline at end of file
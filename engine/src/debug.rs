@@ -0,0 +1,59 @@
+/// Wraps GPU work in a named debug group so captures and validation messages
+/// can be traced back to engine objects. `label` is `None` when debug labels
+/// are disabled, in which case no markers are recorded.
+pub trait DebugGroup {
+    fn debug_group<L, F, R>(&mut self, label: Option<L>, f: F) -> R
+    where
+        L: AsRef<str>,
+        F: FnOnce(&mut Self) -> R;
+
+    fn debug_marker<L: AsRef<str>>(&mut self, label: Option<L>);
+}
+
+impl<'a> DebugGroup for wgpu::RenderPass<'a> {
+    fn debug_group<L, F, R>(&mut self, label: Option<L>, f: F) -> R
+    where
+        L: AsRef<str>,
+        F: FnOnce(&mut Self) -> R,
+    {
+        let enabled = label.is_some();
+        if let Some(label) = label {
+            self.push_debug_group(label.as_ref());
+        }
+        let result = f(self);
+        if enabled {
+            self.pop_debug_group();
+        }
+        return result;
+    }
+
+    fn debug_marker<L: AsRef<str>>(&mut self, label: Option<L>) {
+        if let Some(label) = label {
+            self.insert_debug_marker(label.as_ref());
+        }
+    }
+}
+
+impl DebugGroup for wgpu::CommandEncoder {
+    fn debug_group<L, F, R>(&mut self, label: Option<L>, f: F) -> R
+    where
+        L: AsRef<str>,
+        F: FnOnce(&mut Self) -> R,
+    {
+        let enabled = label.is_some();
+        if let Some(label) = label {
+            self.push_debug_group(label.as_ref());
+        }
+        let result = f(self);
+        if enabled {
+            self.pop_debug_group();
+        }
+        return result;
+    }
+
+    fn debug_marker<L: AsRef<str>>(&mut self, label: Option<L>) {
+        if let Some(label) = label {
+            self.insert_debug_marker(label.as_ref());
+        }
+    }
+}
@@ -0,0 +1,312 @@
+use std::{
+    collections::{HashMap, HashSet, VecDeque},
+    sync::{Arc, Condvar, Mutex},
+    thread::JoinHandle,
+};
+
+use anyhow::*;
+use image::imageops::FilterType;
+
+use crate::{compress, resources::load_binary};
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct DecodeSettings {
+    /// Worker threads decoding images, at least one.
+    pub threads: usize,
+    /// Requests waiting for a worker past which `DecodeQueue::request`
+    /// blocks until one is taken.
+    pub queue_capacity: usize,
+    /// Generate the full mip chain on the workers, only the first level otherwise.
+    pub generate_mips: bool,
+    /// Block compress images other than normal maps on the workers and cache
+    /// them next to their files, see `compress::decode_cached`. Turned off by
+    /// the renderer when the GPU can't sample BC textures.
+    pub compress: bool,
+}
+
+impl Default for DecodeSettings {
+    fn default() -> Self {
+        // Leave a core to the render thread
+        let cores = std::thread::available_parallelism().map_or(2, |n| n.get());
+        Self {
+            threads: (cores - 1).clamp(1, 4),
+            queue_capacity: 64,
+            generate_mips: true,
+            compress: true,
+        }
+    }
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum DecodePriority {
+    Background,
+    /// Drawn last frame, taken before any background request.
+    Visible,
+}
+
+/// How the pixels of a `DecodedImage` are stored.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum PixelEncoding {
+    Rgba8,
+    /// 4x4 blocks of 8 bytes, opaque color only.
+    Bc1,
+    /// 4x4 blocks of 16 bytes, color and alpha.
+    Bc3,
+}
+
+impl PixelEncoding {
+    pub fn texture_format(self, srgb: bool) -> wgpu::TextureFormat {
+        return match (self, srgb) {
+            (PixelEncoding::Rgba8, true) => wgpu::TextureFormat::Rgba8UnormSrgb,
+            (PixelEncoding::Rgba8, false) => wgpu::TextureFormat::Rgba8Unorm,
+            (PixelEncoding::Bc1, true) => wgpu::TextureFormat::Bc1RgbaUnormSrgb,
+            (PixelEncoding::Bc1, false) => wgpu::TextureFormat::Bc1RgbaUnorm,
+            (PixelEncoding::Bc3, true) => wgpu::TextureFormat::Bc3RgbaUnormSrgb,
+            (PixelEncoding::Bc3, false) => wgpu::TextureFormat::Bc3RgbaUnorm,
+        };
+    }
+}
+
+/// Pixels of a decoded image, ready for `Texture::from_decoded`.
+#[derive(Debug, Clone)]
+pub struct DecodedImage {
+    pub width: u32,
+    pub height: u32,
+    pub encoding: PixelEncoding,
+    /// Pixels of each mip level, halving down to 1x1 if mips were generated.
+    pub mips: Vec<Vec<u8>>,
+}
+
+impl DecodedImage {
+    /// Decode a PNG or JPEG, optionally with its mip chain.
+    pub fn decode(bytes: &[u8], generate_mips: bool) -> Result<Self> {
+        let rgba = image::load_from_memory(bytes)?.to_rgba8();
+        let (width, height) = rgba.dimensions();
+        let mut mips = Vec::new();
+        if generate_mips {
+            let levels = width.max(height).max(1).ilog2() + 1;
+            let mut level = rgba.clone();
+            for i in 1..levels {
+                // Filtered from the previous level, in the stored color space
+                let next = image::imageops::resize(&level, (width >> i).max(1), (height >> i).max(1), FilterType::Triangle);
+                mips.push(std::mem::replace(&mut level, next).into_raw());
+            }
+            mips.push(level.into_raw());
+        } else {
+            mips.push(rgba.into_raw());
+        }
+        return Ok(Self {
+            width,
+            height,
+            encoding: PixelEncoding::Rgba8,
+            mips,
+        });
+    }
+}
+
+struct DecodeJob {
+    id: u64,
+    source: String,
+    is_normal_map: bool,
+    priority: DecodePriority,
+}
+
+#[derive(Default)]
+struct DecodeState {
+    waiting: VecDeque<DecodeJob>,
+    // Requests waiting or being decoded, a dropped `PendingTexture` removes
+    // its own so the result is thrown away
+    requested: HashSet<u64>,
+    finished: HashMap<u64, Result<DecodedImage>>,
+    next_id: u64,
+    shutdown: bool,
+}
+
+struct Shared {
+    state: Mutex<DecodeState>,
+    // Signalled when a job is queued or the pool shuts down
+    queued: Condvar,
+    // Signalled when a worker takes a job out of a full queue
+    taken: Condvar,
+    capacity: usize,
+    generate_mips: bool,
+    compress: bool,
+}
+
+/// Submits images to an `ImageDecoder`'s workers, cheap to clone into
+/// background loads.
+#[derive(Clone)]
+pub struct DecodeQueue {
+    shared: Arc<Shared>,
+}
+
+impl DecodeQueue {
+    /// Read and decode the resource `source` on a worker. Blocks while the
+    /// queue is full.
+    pub fn request(&self, source: &str, is_normal_map: bool, priority: DecodePriority) -> PendingTexture {
+        let mut state = self.shared.state.lock().unwrap();
+        while state.waiting.len() >= self.shared.capacity.max(1) && !state.shutdown {
+            state = self.shared.taken.wait(state).unwrap();
+        }
+        let id = state.next_id;
+        state.next_id += 1;
+        state.requested.insert(id);
+        state.waiting.push_back(DecodeJob {
+            id,
+            source: source.to_string(),
+            is_normal_map,
+            priority,
+        });
+        self.shared.queued.notify_one();
+        return PendingTexture {
+            source: source.to_string(),
+            is_normal_map,
+            id,
+            queue: self.clone(),
+        };
+    }
+
+    /// Requests waiting for a worker or being decoded.
+    pub fn pending(&self) -> usize {
+        return self.shared.state.lock().unwrap().requested.len();
+    }
+}
+
+/// Material texture slot showing a placeholder until a worker has decoded
+/// its file, see `resources::poll_pending_textures`.
+pub struct PendingTexture {
+    pub source: String,
+    /// Normal map slot if set, diffuse otherwise.
+    pub is_normal_map: bool,
+    id: u64,
+    queue: DecodeQueue,
+}
+
+impl PendingTexture {
+    /// The decoded image once a worker is done with it, handed out once.
+    pub fn poll(&self) -> Option<Result<DecodedImage>> {
+        return self.queue.shared.state.lock().unwrap().finished.remove(&self.id);
+    }
+
+    /// Move the request ahead of background ones if it's still waiting.
+    pub fn prioritize(&self) {
+        let mut state = self.queue.shared.state.lock().unwrap();
+        if let Some(job) = state.waiting.iter_mut().find(|j| j.id == self.id) {
+            job.priority = DecodePriority::Visible;
+        }
+    }
+}
+
+impl std::fmt::Debug for PendingTexture {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        return f
+            .debug_struct("PendingTexture")
+            .field("source", &self.source)
+            .field("is_normal_map", &self.is_normal_map)
+            .finish();
+    }
+}
+
+impl Drop for PendingTexture {
+    fn drop(&mut self) {
+        let mut state = self.queue.shared.state.lock().unwrap();
+        state.requested.remove(&self.id);
+        state.waiting.retain(|j| j.id != self.id);
+        state.finished.remove(&self.id);
+        self.queue.shared.taken.notify_all();
+    }
+}
+
+/// Worker threads reading and decoding texture files and generating their
+/// mips, so neither stalls the loads or the render thread. Requests drawn
+/// last frame go first, the rest in the order they came.
+pub struct ImageDecoder {
+    queue: DecodeQueue,
+    workers: Vec<JoinHandle<()>>,
+}
+
+impl ImageDecoder {
+    pub fn new(settings: DecodeSettings) -> Self {
+        let shared = Arc::new(Shared {
+            state: Mutex::new(DecodeState::default()),
+            queued: Condvar::new(),
+            taken: Condvar::new(),
+            capacity: settings.queue_capacity,
+            generate_mips: settings.generate_mips,
+            compress: settings.compress,
+        });
+        let workers = (0..settings.threads.max(1))
+            .filter_map(|i| {
+                let shared = shared.clone();
+                let spawned = std::thread::Builder::new()
+                    .name(format!("decode {}", i))
+                    .spawn(move || work(&shared));
+                if let Err(e) = &spawned {
+                    log::error!("Failed to start image decode worker: {}", e);
+                }
+                spawned.ok()
+            })
+            .collect::<Vec<_>>();
+        return Self {
+            queue: DecodeQueue { shared },
+            workers,
+        };
+    }
+
+    pub fn queue(&self) -> &DecodeQueue {
+        return &self.queue;
+    }
+
+    pub fn thread_count(&self) -> usize {
+        return self.workers.len();
+    }
+}
+
+impl Drop for ImageDecoder {
+    fn drop(&mut self) {
+        // Workers finish the image at hand, waiting requests are dropped
+        self.queue.shared.state.lock().unwrap().shutdown = true;
+        self.queue.shared.queued.notify_all();
+        self.queue.shared.taken.notify_all();
+        for worker in self.workers.drain(..) {
+            let _ = worker.join();
+        }
+    }
+}
+
+fn work(shared: &Shared) {
+    loop {
+        let job = {
+            let mut state = shared.state.lock().unwrap();
+            while state.waiting.is_empty() && !state.shutdown {
+                state = shared.queued.wait(state).unwrap();
+            }
+            if state.shutdown {
+                return;
+            }
+            let index = state
+                .waiting
+                .iter()
+                .position(|j| j.priority == DecodePriority::Visible)
+                .unwrap_or(0);
+            shared.taken.notify_one();
+            state.waiting.remove(index).unwrap()
+        };
+
+        let result = pollster::block_on(load_binary(&job.source))
+            .with_context(|| format!("Failed to read texture `{}`", job.source))
+            .and_then(|bytes| {
+                // BC1 and BC3 color endpoints visibly band tangent space normals
+                let decoded = if shared.compress && !job.is_normal_map {
+                    compress::decode_cached(&job.source, &bytes, shared.generate_mips)
+                } else {
+                    DecodedImage::decode(&bytes, shared.generate_mips)
+                };
+                decoded.with_context(|| format!("Failed to decode texture `{}`", job.source))
+            });
+        let mut state = shared.state.lock().unwrap();
+        if state.requested.remove(&job.id) {
+            state.finished.insert(job.id, result);
+        }
+    }
+}
@@ -0,0 +1,309 @@
+use std::sync::{Arc, Mutex};
+
+use anyhow::*;
+use cgmath::Point3;
+
+use crate::{
+    memory::{MemoryCategory, MemoryTracker, TrackedBuffer},
+    shader::ShaderPreprocessor,
+    texture::Texture,
+};
+
+#[repr(C)]
+#[derive(Debug, Copy, Clone, bytemuck::Pod, bytemuck::Zeroable)]
+struct ProbeUniform {
+    center: [i32; 2],
+    _padding: [i32; 2],
+}
+
+type MapResult = Arc<Mutex<Option<Result<(), wgpu::BufferAsyncError>>>>;
+
+/// Depth under a point of the window asked for with `Renderer::request_depth`.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct DepthSample {
+    /// Where it was requested, in physical window pixels.
+    pub position: [f32; 2],
+    /// Depth buffer value, `None` where nothing was drawn.
+    pub depth: Option<f32>,
+    /// `depth` unprojected with the camera the frame was drawn from.
+    pub point: Option<Point3<f32>>,
+}
+
+/// Reads scene depth around a pixel back to the CPU, e.g. to place objects
+/// on or focus at what's under the cursor. Depth is copied out of the depth
+/// buffer in a compute pass since multisampled and stencil formats can't be
+/// copied to a buffer directly. `request` and `poll` read it without
+/// stalling, `read_blocking` waits for the GPU.
+pub struct DepthProbe {
+    bind_group_layout: wgpu::BindGroupLayout,
+    bind_group: wgpu::BindGroup,
+    pipeline: wgpu::ComputePipeline,
+    uniform_buffer: TrackedBuffer,
+    depths_buffer: TrackedBuffer,
+    readback_buffer: TrackedBuffer,
+    // Mapping of the readback buffer started by `request`
+    pending: Option<MapResult>,
+}
+
+impl DepthProbe {
+    /// Pixels around the probed one that are read, matches `RADIUS` in
+    /// depth_probe.wgsl.
+    pub const RADIUS: i32 = 1;
+    const SIZE: usize = (2 * Self::RADIUS + 1) as usize;
+
+    pub fn new(
+        device: &wgpu::Device,
+        memory: &MemoryTracker,
+        shaders: &ShaderPreprocessor,
+        depth_texture: &Texture,
+        sample_count: u32,
+    ) -> Self {
+        let bind_group_layout = device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
+            entries: &[
+                wgpu::BindGroupLayoutEntry {
+                    binding: 0,
+                    visibility: wgpu::ShaderStages::COMPUTE,
+                    ty: wgpu::BindingType::Buffer {
+                        ty: wgpu::BufferBindingType::Uniform,
+                        has_dynamic_offset: false,
+                        min_binding_size: None,
+                    },
+                    count: None,
+                },
+                wgpu::BindGroupLayoutEntry {
+                    binding: 1,
+                    visibility: wgpu::ShaderStages::COMPUTE,
+                    ty: wgpu::BindingType::Texture {
+                        sample_type: wgpu::TextureSampleType::Depth,
+                        view_dimension: wgpu::TextureViewDimension::D2,
+                        multisampled: sample_count > 1,
+                    },
+                    count: None,
+                },
+                wgpu::BindGroupLayoutEntry {
+                    binding: 2,
+                    visibility: wgpu::ShaderStages::COMPUTE,
+                    ty: wgpu::BindingType::Buffer {
+                        ty: wgpu::BufferBindingType::Storage { read_only: false },
+                        has_dynamic_offset: false,
+                        min_binding_size: None,
+                    },
+                    count: None,
+                },
+            ],
+            label: Some("depth_probe_bind_group_layout"),
+        });
+
+        let mut shaders = shaders.clone();
+        if sample_count > 1 {
+            shaders.enable("MULTISAMPLED_DEPTH");
+        }
+        let shader = shaders
+            .descriptor("Depth Probe Shader", "depth_probe.wgsl")
+            .expect("Failed to preprocess depth_probe.wgsl");
+        let shader = device.create_shader_module(shader);
+        let layout = device.create_pipeline_layout(&wgpu::PipelineLayoutDescriptor {
+            label: Some("Depth Probe Pipeline Layout"),
+            bind_group_layouts: &[&bind_group_layout],
+            push_constant_ranges: &[],
+        });
+        let pipeline = device.create_compute_pipeline(&wgpu::ComputePipelineDescriptor {
+            label: Some("Depth Probe Pipeline"),
+            layout: Some(&layout),
+            module: &shader,
+            entry_point: "probe_main",
+        });
+
+        let uniform_buffer = memory.create_buffer(
+            device,
+            &wgpu::BufferDescriptor {
+                label: Some("Depth Probe Uniform Buffer"),
+                size: std::mem::size_of::<ProbeUniform>() as u64,
+                usage: wgpu::BufferUsages::UNIFORM | wgpu::BufferUsages::COPY_DST,
+                mapped_at_creation: false,
+            },
+            MemoryCategory::Uniform,
+        );
+        let depths_size = (Self::SIZE * Self::SIZE * std::mem::size_of::<f32>()) as u64;
+        let depths_buffer = memory.create_buffer(
+            device,
+            &wgpu::BufferDescriptor {
+                label: Some("Depth Probe Buffer"),
+                size: depths_size,
+                usage: wgpu::BufferUsages::STORAGE | wgpu::BufferUsages::COPY_SRC,
+                mapped_at_creation: false,
+            },
+            MemoryCategory::Other,
+        );
+        let readback_buffer = memory.create_buffer(
+            device,
+            &wgpu::BufferDescriptor {
+                label: Some("Depth Probe Readback Buffer"),
+                size: depths_size,
+                usage: wgpu::BufferUsages::COPY_DST | wgpu::BufferUsages::MAP_READ,
+                mapped_at_creation: false,
+            },
+            MemoryCategory::Other,
+        );
+        let bind_group =
+            Self::create_bind_group(device, &bind_group_layout, &uniform_buffer, &depths_buffer, depth_texture);
+
+        return Self {
+            bind_group_layout,
+            bind_group,
+            pipeline,
+            uniform_buffer,
+            depths_buffer,
+            readback_buffer,
+            pending: None,
+        };
+    }
+
+    fn create_bind_group(
+        device: &wgpu::Device,
+        layout: &wgpu::BindGroupLayout,
+        uniform_buffer: &TrackedBuffer,
+        depths_buffer: &TrackedBuffer,
+        depth_texture: &Texture,
+    ) -> wgpu::BindGroup {
+        // Stencil formats can only be sampled one aspect at a time
+        let depth_view = depth_texture.texture.create_view(&wgpu::TextureViewDescriptor {
+            aspect: wgpu::TextureAspect::DepthOnly,
+            ..Default::default()
+        });
+        return device.create_bind_group(&wgpu::BindGroupDescriptor {
+            layout,
+            entries: &[
+                wgpu::BindGroupEntry {
+                    binding: 0,
+                    resource: uniform_buffer.as_entire_binding(),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 1,
+                    resource: wgpu::BindingResource::TextureView(&depth_view),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 2,
+                    resource: depths_buffer.as_entire_binding(),
+                },
+            ],
+            label: Some("depth_probe_bind_group"),
+        });
+    }
+
+    /// Must be called whenever the depth texture is recreated.
+    pub fn set_depth_texture(&mut self, device: &wgpu::Device, depth_texture: &Texture) {
+        self.bind_group = Self::create_bind_group(
+            device,
+            &self.bind_group_layout,
+            &self.uniform_buffer,
+            &self.depths_buffer,
+            depth_texture,
+        );
+    }
+
+    // Copy the depths around `(x, y)` into the readback buffer
+    fn submit(&self, device: &wgpu::Device, queue: &wgpu::Queue, x: i32, y: i32) {
+        let uniform = ProbeUniform {
+            center: [x, y],
+            _padding: [0; 2],
+        };
+        queue.write_buffer(&self.uniform_buffer, 0, bytemuck::cast_slice(&[uniform]));
+        let mut encoder = device.create_command_encoder(&wgpu::CommandEncoderDescriptor {
+            label: Some("Depth Probe Encoder"),
+        });
+        {
+            let mut pass = encoder.begin_compute_pass(&wgpu::ComputePassDescriptor {
+                label: Some("Depth Probe Pass"),
+            });
+            pass.set_pipeline(&self.pipeline);
+            pass.set_bind_group(0, &self.bind_group, &[]);
+            pass.dispatch_workgroups(1, 1, 1);
+        }
+        encoder.copy_buffer_to_buffer(&self.depths_buffer, 0, &self.readback_buffer, 0, self.depths_buffer.size());
+        queue.submit(Some(encoder.finish()));
+    }
+
+    /// Start reading the depths around `(x, y)` of what was drawn so far,
+    /// picked up by `poll` once the GPU has copied them. False while an
+    /// earlier request is still in flight.
+    pub fn request(&mut self, device: &wgpu::Device, queue: &wgpu::Queue, x: i32, y: i32) -> bool {
+        if self.pending.is_some() {
+            return false;
+        }
+        self.submit(device, queue, x, y);
+        let result = Arc::new(Mutex::new(None));
+        let sender = result.clone();
+        self.readback_buffer
+            .slice(..)
+            .map_async(wgpu::MapMode::Read, move |r| {
+                *sender.lock().unwrap() = Some(r);
+            });
+        self.pending = Some(result);
+        return true;
+    }
+
+    pub fn is_idle(&self) -> bool {
+        return self.pending.is_none();
+    }
+
+    /// Depths of a finished `request`, like `read_blocking`. Needs the device
+    /// to have been polled.
+    pub fn poll(&mut self) -> Option<Vec<f32>> {
+        let mapped = self.pending.as_ref()?.lock().unwrap().take()?;
+        self.pending = None;
+        if let Err(e) = mapped {
+            log::warn!("Failed to read back depth: {:?}", e);
+            return None;
+        }
+        let depths = bytemuck::cast_slice::<u8, f32>(&self.readback_buffer.slice(..).get_mapped_range()).to_vec();
+        self.readback_buffer.unmap();
+        return Some(depths);
+    }
+
+    /// Depths of the square of pixels `RADIUS` around `(x, y)` in rows, 1
+    /// where nothing was drawn or outside of the buffer. Blocks until the
+    /// GPU has finished all submitted work, prefer `request` every frame.
+    pub fn read_blocking(&self, device: &wgpu::Device, queue: &wgpu::Queue, x: i32, y: i32) -> Result<Vec<f32>> {
+        ensure!(self.pending.is_none(), "A depth request is still in flight");
+        self.submit(device, queue, x, y);
+        let slice = self.readback_buffer.slice(..);
+        let (sender, receiver) = std::sync::mpsc::channel();
+        slice.map_async(wgpu::MapMode::Read, move |result| {
+            let _ = sender.send(result);
+        });
+        device.poll(wgpu::Maintain::Wait);
+        receiver
+            .recv()
+            .context("Depth readback was dropped")?
+            .context("Failed to map depth readback")?;
+        let depths = bytemuck::cast_slice::<u8, f32>(&slice.get_mapped_range()).to_vec();
+        self.readback_buffer.unmap();
+        return Ok(depths);
+    }
+
+    /// Depth of the pixel at `(x, y)`, `None` where nothing was drawn,
+    /// blocking like `read_blocking`. See `center_depth`.
+    pub fn depth_at_blocking(
+        &self,
+        device: &wgpu::Device,
+        queue: &wgpu::Queue,
+        x: i32,
+        y: i32,
+    ) -> Result<Option<f32>> {
+        return Ok(Self::center_depth(&self.read_blocking(device, queue, x, y)?));
+    }
+
+    /// Depth of the probed pixel out of the square read around it, `None`
+    /// where nothing was drawn. Where the pixel itself is background, e.g.
+    /// just off a thin edge, the nearest depth around it is taken.
+    pub fn center_depth(depths: &[f32]) -> Option<f32> {
+        let center = *depths.get(depths.len() / 2)?;
+        let depth = if center < 1.0 {
+            center
+        } else {
+            depths.iter().cloned().fold(1.0, f32::min)
+        };
+        return (depth < 1.0).then_some(depth);
+    }
+}
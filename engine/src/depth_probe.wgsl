@@ -0,0 +1,38 @@
+// Copies the depths of a small square of pixels around a point of the scene
+// depth buffer, in rows. Taps outside of the buffer read as far.
+
+struct Probe {
+    center: vec2<i32>,
+    _padding: vec2<i32>,
+};
+@group(0) @binding(0)
+var<uniform> probe: Probe;
+
+#ifdef MULTISAMPLED_DEPTH
+@group(0) @binding(1)
+var t_depth: texture_depth_multisampled_2d;
+#else
+@group(0) @binding(1)
+var t_depth: texture_depth_2d;
+#endif
+
+@group(0) @binding(2)
+var<storage, read_write> depths: array<f32>;
+
+let RADIUS: i32 = 1;
+
+@compute @workgroup_size(1)
+fn probe_main() {
+    let dimensions = textureDimensions(t_depth);
+    let size = 2 * RADIUS + 1;
+    for (var y = 0; y < size; y++) {
+        for (var x = 0; x < size; x++) {
+            let p = probe.center + vec2<i32>(x - RADIUS, y - RADIUS);
+            var depth = 1.0;
+            if (all(p >= vec2<i32>(0)) && all(p < dimensions)) {
+                depth = textureLoad(t_depth, p, 0);
+            }
+            depths[y * size + x] = depth;
+        }
+    }
+}
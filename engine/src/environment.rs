@@ -0,0 +1,640 @@
+use std::num::NonZeroU32;
+
+use anyhow::*;
+use cgmath::{InnerSpace, Vector3};
+use image::GenericImageView;
+use wgpu::util::DeviceExt;
+
+use crate::{
+    cubemap::validate_faces,
+    memory::{MemoryCategory, MemoryTracker, TrackedBuffer, TrackedTexture},
+    shader::ShaderPreprocessor,
+};
+
+#[repr(C)]
+#[derive(Debug, Copy, Clone, Default, bytemuck::Pod, bytemuck::Zeroable)]
+struct ConvolutionUniform {
+    face: u32,
+    roughness: f32,
+    source_size: f32,
+    _padding: f32,
+    zenith: [f32; 4],
+    horizon: [f32; 4],
+    ground: [f32; 4],
+    sun_direction: [f32; 4],
+    sun_color: [f32; 4],
+}
+
+#[repr(C)]
+#[derive(Debug, Copy, Clone, bytemuck::Pod, bytemuck::Zeroable)]
+struct EnvironmentUniform {
+    intensity: f32,
+    // Mip of the prefiltered map used for roughness 1
+    max_lod: f32,
+    _padding: [f32; 2],
+}
+
+/// Procedural gradient sky used when no environment images are available.
+#[derive(Debug, Clone)]
+pub struct SkySettings {
+    pub zenith: [f32; 3],
+    pub horizon: [f32; 3],
+    pub ground: [f32; 3],
+    /// Direction towards the sun.
+    pub sun_direction: Vector3<f32>,
+    pub sun_color: [f32; 3],
+    pub sun_radius: cgmath::Rad<f32>,
+}
+
+impl Default for SkySettings {
+    fn default() -> Self {
+        Self {
+            zenith: [0.15, 0.3, 0.6],
+            horizon: [0.6, 0.7, 0.8],
+            ground: [0.2, 0.18, 0.15],
+            sun_direction: Vector3::new(0.3, 0.8, 0.5),
+            sun_color: [20.0, 18.0, 15.0],
+            sun_radius: cgmath::Deg(2.0).into(),
+        }
+    }
+}
+
+/// Cube maps of an environment, e.g. to inspect them with `Overlay::cubemap`.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum EnvironmentMap {
+    Source,
+    Irradiance,
+    /// Prefiltered specular map at a mip, 0 for the sharpest.
+    Prefiltered(u32),
+}
+
+/// Image based lighting derived from an environment cube map: a diffuse
+/// irradiance map, a specular map prefiltered per roughness into its mips and
+/// the split-sum BRDF lookup table. Bound next to the lights of the scene.
+pub struct Environment {
+    source: TrackedTexture,
+    irradiance: TrackedTexture,
+    prefiltered: TrackedTexture,
+    brdf_lut: TrackedTexture,
+    irradiance_view: wgpu::TextureView,
+    prefiltered_view: wgpu::TextureView,
+    brdf_lut_view: wgpu::TextureView,
+    sampler: wgpu::Sampler,
+    uniform_buffer: TrackedBuffer,
+    intensity: f32,
+}
+
+// Shared state of the passes that fill an environment
+struct Convolver<'a> {
+    device: &'a wgpu::Device,
+    queue: &'a wgpu::Queue,
+    shader: wgpu::ShaderModule,
+    uniform_layout: wgpu::BindGroupLayout,
+    source_layout: wgpu::BindGroupLayout,
+    sampler: &'a wgpu::Sampler,
+}
+
+impl<'a> Convolver<'a> {
+    fn pipeline(&self, entry_point: &str, format: wgpu::TextureFormat, sampled: bool) -> wgpu::RenderPipeline {
+        let layout = if sampled {
+            &self.source_layout
+        } else {
+            &self.uniform_layout
+        };
+        let layout = self
+            .device
+            .create_pipeline_layout(&wgpu::PipelineLayoutDescriptor {
+                label: Some("Environment Pipeline Layout"),
+                bind_group_layouts: &[layout],
+                push_constant_ranges: &[],
+            });
+        return self
+            .device
+            .create_render_pipeline(&wgpu::RenderPipelineDescriptor {
+                label: Some(entry_point),
+                layout: Some(&layout),
+                vertex: wgpu::VertexState {
+                    module: &self.shader,
+                    entry_point: "vs_main",
+                    buffers: &[],
+                },
+                fragment: Some(wgpu::FragmentState {
+                    module: &self.shader,
+                    entry_point,
+                    targets: &[Some(format.into())],
+                }),
+                primitive: wgpu::PrimitiveState::default(),
+                depth_stencil: None,
+                multisample: wgpu::MultisampleState::default(),
+                multiview: None,
+            });
+    }
+
+    /// Draw `pipeline` into one face and mip of `target`, reading `source`
+    /// if given.
+    #[allow(clippy::too_many_arguments)]
+    fn draw(
+        &self,
+        encoder: &mut wgpu::CommandEncoder,
+        pipeline: &wgpu::RenderPipeline,
+        params: ConvolutionUniform,
+        source: Option<&wgpu::TextureView>,
+        target: &wgpu::Texture,
+        layer: u32,
+        mip: u32,
+    ) {
+        // Each draw needs its own parameters since all of them are submitted at once
+        let buffer = self.device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
+            label: Some("Environment Params"),
+            contents: bytemuck::cast_slice(&[params]),
+            usage: wgpu::BufferUsages::UNIFORM,
+        });
+        let mut entries = vec![wgpu::BindGroupEntry {
+            binding: 0,
+            resource: buffer.as_entire_binding(),
+        }];
+        if let Some(source) = source {
+            entries.push(wgpu::BindGroupEntry {
+                binding: 1,
+                resource: wgpu::BindingResource::TextureView(source),
+            });
+            entries.push(wgpu::BindGroupEntry {
+                binding: 2,
+                resource: wgpu::BindingResource::Sampler(self.sampler),
+            });
+        }
+        let bind_group = self.device.create_bind_group(&wgpu::BindGroupDescriptor {
+            layout: if source.is_some() {
+                &self.source_layout
+            } else {
+                &self.uniform_layout
+            },
+            entries: &entries,
+            label: Some("Environment Bind Group"),
+        });
+
+        let view = target.create_view(&wgpu::TextureViewDescriptor {
+            label: Some("Environment Face"),
+            dimension: Some(wgpu::TextureViewDimension::D2),
+            base_mip_level: mip,
+            mip_level_count: NonZeroU32::new(1),
+            base_array_layer: layer,
+            array_layer_count: NonZeroU32::new(1),
+            ..Default::default()
+        });
+        let mut render_pass = encoder.begin_render_pass(&wgpu::RenderPassDescriptor {
+            label: Some("Environment Pass"),
+            color_attachments: &[Some(wgpu::RenderPassColorAttachment {
+                view: &view,
+                resolve_target: None,
+                ops: wgpu::Operations {
+                    load: wgpu::LoadOp::Clear(wgpu::Color::BLACK),
+                    store: true,
+                },
+            })],
+            depth_stencil_attachment: None,
+        });
+        render_pass.set_pipeline(pipeline);
+        render_pass.set_bind_group(0, &bind_group, &[]);
+        render_pass.draw(0..3, 0..1);
+    }
+
+    fn submit(&self, encoder: wgpu::CommandEncoder) {
+        self.queue.submit(std::iter::once(encoder.finish()));
+    }
+}
+
+fn cube_view(texture: &wgpu::Texture) -> wgpu::TextureView {
+    return texture.create_view(&wgpu::TextureViewDescriptor {
+        dimension: Some(wgpu::TextureViewDimension::Cube),
+        ..Default::default()
+    });
+}
+
+impl Environment {
+    pub const FORMAT: wgpu::TextureFormat = wgpu::TextureFormat::Rgba16Float;
+    pub const SOURCE_SIZE: u32 = 256;
+    pub const IRRADIANCE_SIZE: u32 = 32;
+    pub const PREFILTER_SIZE: u32 = 128;
+    /// Roughness levels of the prefiltered map, spread evenly from 0 to 1.
+    pub const PREFILTER_MIPS: u32 = 5;
+    pub const BRDF_LUT_SIZE: u32 = 128;
+
+    fn create_cube(
+        device: &wgpu::Device,
+        memory: &MemoryTracker,
+        size: u32,
+        mips: u32,
+        format: wgpu::TextureFormat,
+        usage: wgpu::TextureUsages,
+        label: &str,
+    ) -> TrackedTexture {
+        return memory.create_texture(
+            device,
+            &wgpu::TextureDescriptor {
+                label: Some(label),
+                size: wgpu::Extent3d {
+                    width: size,
+                    height: size,
+                    depth_or_array_layers: 6,
+                },
+                mip_level_count: mips,
+                sample_count: 1,
+                dimension: wgpu::TextureDimension::D2,
+                format,
+                usage: usage | wgpu::TextureUsages::TEXTURE_BINDING,
+            },
+            MemoryCategory::Texture,
+        );
+    }
+
+    /// Environment of a procedural sky.
+    pub fn from_sky(
+        device: &wgpu::Device,
+        memory: &MemoryTracker,
+        queue: &wgpu::Queue,
+        shaders: &ShaderPreprocessor,
+        sky: &SkySettings,
+    ) -> Self {
+        let mips = Self::SOURCE_SIZE.ilog2() + 1;
+        let source = Self::create_cube(
+            device,
+            memory,
+            Self::SOURCE_SIZE,
+            mips,
+            Self::FORMAT,
+            wgpu::TextureUsages::RENDER_ATTACHMENT,
+            "Sky Cube",
+        );
+
+        let sampler = Self::create_sampler(device);
+        let convolver = Self::convolver(device, queue, shaders, &sampler);
+        let pipeline = convolver.pipeline("fs_sky", Self::FORMAT, false);
+        let rgba = |c: [f32; 3]| [c[0], c[1], c[2], 1.0];
+        let params = ConvolutionUniform {
+            zenith: rgba(sky.zenith),
+            horizon: rgba(sky.horizon),
+            ground: rgba(sky.ground),
+            sun_direction: sky.sun_direction.normalize().extend(sky.sun_radius.0).into(),
+            sun_color: rgba(sky.sun_color),
+            ..Default::default()
+        };
+
+        // Every mip is drawn directly, the sky is cheap to evaluate
+        let mut encoder = device.create_command_encoder(&wgpu::CommandEncoderDescriptor {
+            label: Some("Sky Encoder"),
+        });
+        for mip in 0..mips {
+            for face in 0..6 {
+                let params = ConvolutionUniform { face, ..params };
+                convolver.draw(&mut encoder, &pipeline, params, None, &source, face, mip);
+            }
+        }
+        convolver.submit(encoder);
+
+        return Self::from_source(device, memory, queue, shaders, source, Self::SOURCE_SIZE, sampler);
+    }
+
+    /// Environment of six cube faces in +X, -X, +Y, -Y, +Z, -Z order.
+    pub fn from_faces(
+        device: &wgpu::Device,
+        memory: &MemoryTracker,
+        queue: &wgpu::Queue,
+        shaders: &ShaderPreprocessor,
+        faces: &[image::DynamicImage; 6],
+    ) -> Result<Self> {
+        let (size, height) = faces[0].dimensions();
+        if size != height || faces.iter().any(|f| f.dimensions() != (size, size)) {
+            bail!("Environment faces must be square and of equal size");
+        }
+        for report in validate_faces(faces)?.iter().filter(|r| r.looks_wrong()) {
+            log::warn!(
+                "Environment face {:?} looks misoriented, seam error {:.3} or {:.3} with {:?}, see CubemapImport",
+                report.face,
+                report.seam_error,
+                report.suggested_error,
+                report.suggested
+            );
+        }
+
+        let source = Self::create_cube(
+            device,
+            memory,
+            size,
+            1,
+            wgpu::TextureFormat::Rgba8UnormSrgb,
+            wgpu::TextureUsages::COPY_DST,
+            "Environment Cube",
+        );
+        for (layer, face) in faces.iter().enumerate() {
+            queue.write_texture(
+                wgpu::ImageCopyTexture {
+                    texture: &source,
+                    mip_level: 0,
+                    origin: wgpu::Origin3d {
+                        x: 0,
+                        y: 0,
+                        z: layer as u32,
+                    },
+                    aspect: wgpu::TextureAspect::All,
+                },
+                &face.to_rgba8(),
+                wgpu::ImageDataLayout {
+                    offset: 0,
+                    bytes_per_row: NonZeroU32::new(4 * size),
+                    rows_per_image: NonZeroU32::new(size),
+                },
+                wgpu::Extent3d {
+                    width: size,
+                    height: size,
+                    depth_or_array_layers: 1,
+                },
+            );
+        }
+
+        let sampler = Self::create_sampler(device);
+        return Ok(Self::from_source(device, memory, queue, shaders, source, size, sampler));
+    }
+
+    fn create_sampler(device: &wgpu::Device) -> wgpu::Sampler {
+        return device.create_sampler(&wgpu::SamplerDescriptor {
+            label: Some("Environment Sampler"),
+            address_mode_u: wgpu::AddressMode::ClampToEdge,
+            address_mode_v: wgpu::AddressMode::ClampToEdge,
+            address_mode_w: wgpu::AddressMode::ClampToEdge,
+            mag_filter: wgpu::FilterMode::Linear,
+            min_filter: wgpu::FilterMode::Linear,
+            mipmap_filter: wgpu::FilterMode::Linear,
+            ..Default::default()
+        });
+    }
+
+    fn convolver<'a>(
+        device: &'a wgpu::Device,
+        queue: &'a wgpu::Queue,
+        shaders: &ShaderPreprocessor,
+        sampler: &'a wgpu::Sampler,
+    ) -> Convolver<'a> {
+        let shader = device.create_shader_module(
+            shaders
+                .descriptor("IBL Shader", "ibl.wgsl")
+                .expect("Failed to preprocess ibl.wgsl"),
+        );
+        let uniform_entry = wgpu::BindGroupLayoutEntry {
+            binding: 0,
+            visibility: wgpu::ShaderStages::FRAGMENT,
+            ty: wgpu::BindingType::Buffer {
+                ty: wgpu::BufferBindingType::Uniform,
+                has_dynamic_offset: false,
+                min_binding_size: None,
+            },
+            count: None,
+        };
+        let uniform_layout = device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
+            entries: &[uniform_entry],
+            label: Some("environment_uniform_layout"),
+        });
+        let source_layout = device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
+            entries: &[
+                uniform_entry,
+                wgpu::BindGroupLayoutEntry {
+                    binding: 1,
+                    visibility: wgpu::ShaderStages::FRAGMENT,
+                    ty: wgpu::BindingType::Texture {
+                        multisampled: false,
+                        view_dimension: wgpu::TextureViewDimension::Cube,
+                        sample_type: wgpu::TextureSampleType::Float { filterable: true },
+                    },
+                    count: None,
+                },
+                wgpu::BindGroupLayoutEntry {
+                    binding: 2,
+                    visibility: wgpu::ShaderStages::FRAGMENT,
+                    ty: wgpu::BindingType::Sampler(wgpu::SamplerBindingType::Filtering),
+                    count: None,
+                },
+            ],
+            label: Some("environment_source_layout"),
+        });
+
+        return Convolver {
+            device,
+            queue,
+            shader,
+            uniform_layout,
+            source_layout,
+            sampler,
+        };
+    }
+
+    // Run the convolution passes over `source`
+    fn from_source(
+        device: &wgpu::Device,
+        memory: &MemoryTracker,
+        queue: &wgpu::Queue,
+        shaders: &ShaderPreprocessor,
+        source: TrackedTexture,
+        source_size: u32,
+        sampler: wgpu::Sampler,
+    ) -> Self {
+        let target_usage = wgpu::TextureUsages::RENDER_ATTACHMENT;
+        let irradiance = Self::create_cube(
+            device,
+            memory,
+            Self::IRRADIANCE_SIZE,
+            1,
+            Self::FORMAT,
+            target_usage,
+            "Irradiance Cube",
+        );
+        let prefiltered = Self::create_cube(
+            device,
+            memory,
+            Self::PREFILTER_SIZE,
+            Self::PREFILTER_MIPS,
+            Self::FORMAT,
+            target_usage,
+            "Prefiltered Cube",
+        );
+        let brdf_lut = memory.create_texture(
+            device,
+            &wgpu::TextureDescriptor {
+                label: Some("BRDF LUT"),
+                size: wgpu::Extent3d {
+                    width: Self::BRDF_LUT_SIZE,
+                    height: Self::BRDF_LUT_SIZE,
+                    depth_or_array_layers: 1,
+                },
+                mip_level_count: 1,
+                sample_count: 1,
+                dimension: wgpu::TextureDimension::D2,
+                format: wgpu::TextureFormat::Rg16Float,
+                usage: target_usage | wgpu::TextureUsages::TEXTURE_BINDING,
+            },
+            MemoryCategory::Texture,
+        );
+
+        let convolver = Self::convolver(device, queue, shaders, &sampler);
+        let source_view = cube_view(&source);
+        let source_size = source_size as f32;
+        let mut encoder = device.create_command_encoder(&wgpu::CommandEncoderDescriptor {
+            label: Some("Environment Encoder"),
+        });
+
+        let pipeline = convolver.pipeline("fs_irradiance", Self::FORMAT, true);
+        for face in 0..6 {
+            let params = ConvolutionUniform {
+                face,
+                source_size,
+                ..Default::default()
+            };
+            convolver.draw(&mut encoder, &pipeline, params, Some(&source_view), &irradiance, face, 0);
+        }
+
+        let pipeline = convolver.pipeline("fs_prefilter", Self::FORMAT, true);
+        for mip in 0..Self::PREFILTER_MIPS {
+            let roughness = mip as f32 / (Self::PREFILTER_MIPS - 1) as f32;
+            for face in 0..6 {
+                let params = ConvolutionUniform {
+                    face,
+                    roughness,
+                    source_size,
+                    ..Default::default()
+                };
+                convolver.draw(&mut encoder, &pipeline, params, Some(&source_view), &prefiltered, face, mip);
+            }
+        }
+
+        let pipeline = convolver.pipeline("fs_brdf", wgpu::TextureFormat::Rg16Float, false);
+        convolver.draw(&mut encoder, &pipeline, ConvolutionUniform::default(), None, &brdf_lut, 0, 0);
+        convolver.submit(encoder);
+
+        let intensity = 1.0;
+        let uniform_buffer = memory.create_buffer_init(
+            device,
+            &wgpu::util::BufferInitDescriptor {
+                label: Some("Environment Buffer"),
+                contents: bytemuck::cast_slice(&[EnvironmentUniform {
+                    intensity,
+                    max_lod: (Self::PREFILTER_MIPS - 1) as f32,
+                    _padding: [0.0; 2],
+                }]),
+                usage: wgpu::BufferUsages::UNIFORM | wgpu::BufferUsages::COPY_DST,
+            },
+            MemoryCategory::Uniform,
+        );
+
+        return Self {
+            irradiance_view: cube_view(&irradiance),
+            prefiltered_view: cube_view(&prefiltered),
+            brdf_lut_view: brdf_lut.create_view(&wgpu::TextureViewDescriptor::default()),
+            source,
+            irradiance,
+            prefiltered,
+            brdf_lut,
+            sampler,
+            uniform_buffer,
+            intensity,
+        };
+    }
+
+    pub fn intensity(&self) -> f32 {
+        return self.intensity;
+    }
+
+    pub fn set_intensity(&mut self, queue: &wgpu::Queue, intensity: f32) {
+        self.intensity = intensity;
+        queue.write_buffer(&self.uniform_buffer, 0, bytemuck::bytes_of(&intensity));
+    }
+
+    /// The environment cube map the lighting was derived from.
+    pub fn source(&self) -> &wgpu::Texture {
+        return &self.source;
+    }
+
+    /// Cube view of one mip of `map`.
+    pub fn cube_view(&self, map: EnvironmentMap) -> wgpu::TextureView {
+        let (texture, mip) = match map {
+            EnvironmentMap::Source => (&self.source, 0),
+            EnvironmentMap::Irradiance => (&self.irradiance, 0),
+            EnvironmentMap::Prefiltered(mip) => (&self.prefiltered, mip.min(Self::PREFILTER_MIPS - 1)),
+        };
+        return texture.create_view(&wgpu::TextureViewDescriptor {
+            label: Some("Environment Cube View"),
+            dimension: Some(wgpu::TextureViewDimension::Cube),
+            base_mip_level: mip,
+            mip_level_count: NonZeroU32::new(1),
+            ..Default::default()
+        });
+    }
+
+    /// GPU memory of the source and all derived maps in bytes.
+    pub fn memory_size(&self) -> u64 {
+        return self.source.size()
+            + self.irradiance.size()
+            + self.prefiltered.size()
+            + self.brdf_lut.size();
+    }
+
+    /// Layout entries of the environment, starting at `first_binding`, for
+    /// bind groups that also carry other lighting data.
+    pub fn layout_entries(first_binding: u32) -> [wgpu::BindGroupLayoutEntry; 5] {
+        let cube = |binding, view_dimension| wgpu::BindGroupLayoutEntry {
+            binding,
+            visibility: wgpu::ShaderStages::FRAGMENT,
+            ty: wgpu::BindingType::Texture {
+                multisampled: false,
+                view_dimension,
+                sample_type: wgpu::TextureSampleType::Float { filterable: true },
+            },
+            count: None,
+        };
+        return [
+            cube(first_binding, wgpu::TextureViewDimension::Cube),
+            cube(first_binding + 1, wgpu::TextureViewDimension::Cube),
+            cube(first_binding + 2, wgpu::TextureViewDimension::D2),
+            wgpu::BindGroupLayoutEntry {
+                binding: first_binding + 3,
+                visibility: wgpu::ShaderStages::FRAGMENT,
+                ty: wgpu::BindingType::Sampler(wgpu::SamplerBindingType::Filtering),
+                count: None,
+            },
+            wgpu::BindGroupLayoutEntry {
+                binding: first_binding + 4,
+                visibility: wgpu::ShaderStages::FRAGMENT,
+                ty: wgpu::BindingType::Buffer {
+                    ty: wgpu::BufferBindingType::Uniform,
+                    has_dynamic_offset: false,
+                    min_binding_size: None,
+                },
+                count: None,
+            },
+        ];
+    }
+
+    /// Bind group entries matching `layout_entries`.
+    pub fn bind_entries(&self, first_binding: u32) -> [wgpu::BindGroupEntry<'_>; 5] {
+        return [
+            wgpu::BindGroupEntry {
+                binding: first_binding,
+                resource: wgpu::BindingResource::TextureView(&self.irradiance_view),
+            },
+            wgpu::BindGroupEntry {
+                binding: first_binding + 1,
+                resource: wgpu::BindingResource::TextureView(&self.prefiltered_view),
+            },
+            wgpu::BindGroupEntry {
+                binding: first_binding + 2,
+                resource: wgpu::BindingResource::TextureView(&self.brdf_lut_view),
+            },
+            wgpu::BindGroupEntry {
+                binding: first_binding + 3,
+                resource: wgpu::BindingResource::Sampler(&self.sampler),
+            },
+            wgpu::BindGroupEntry {
+                binding: first_binding + 4,
+                resource: self.uniform_buffer.as_entire_binding(),
+            },
+        ];
+    }
+}
@@ -0,0 +1,81 @@
+use crate::{light::LightId, renderer::Renderer};
+
+/// Something that happened in the engine, published on the renderer's
+/// `EventBus`.
+#[derive(Debug, Clone, PartialEq)]
+pub enum EngineEvent {
+    /// The surface and render targets were resized, in physical pixels.
+    WindowResized { width: u32, height: u32 },
+    /// A material's textures were loaded again after their files changed.
+    AssetReloaded { material: String },
+    /// An instance was added at this index.
+    InstanceSpawned(usize),
+    /// An instance was hidden for good, its index stays taken.
+    InstanceDespawned(usize),
+    /// A light was moved or changed, by the simulation or the light gizmo.
+    LightChanged(LightId),
+}
+
+/// Runs for every event published on the bus.
+pub type EventHandler = Box<dyn FnMut(&mut Renderer, &EngineEvent)>;
+
+/// Handle of a handler to `EventBus::unsubscribe`.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+pub struct Subscription(u64);
+
+/// Publish/subscribe between subsystems and user code, so whoever cares
+/// about a resize, reload or spawn doesn't have to be called from where it
+/// happens. Events are queued and handed to the handlers at the start of
+/// the next `Renderer::update`, in the order they were published.
+#[derive(Default)]
+pub struct EventBus {
+    queued: Vec<EngineEvent>,
+    handlers: Vec<(Subscription, EventHandler)>,
+    // Unsubscribed while their handlers were taken out to run
+    removed: Vec<Subscription>,
+    next_id: u64,
+}
+
+impl EventBus {
+    pub fn publish(&mut self, event: EngineEvent) {
+        self.queued.push(event);
+    }
+
+    pub fn subscribe<F: FnMut(&mut Renderer, &EngineEvent) + 'static>(&mut self, handler: F) -> Subscription {
+        let subscription = Subscription(self.next_id);
+        self.next_id += 1;
+        self.handlers.push((subscription, Box::new(handler)));
+        return subscription;
+    }
+
+    pub fn unsubscribe(&mut self, subscription: Subscription) {
+        self.handlers.retain(|(s, _)| *s != subscription);
+        self.removed.push(subscription);
+    }
+
+    /// Events published since the last dispatch.
+    pub fn pending(&self) -> &[EngineEvent] {
+        return &self.queued;
+    }
+
+    // Handlers get the whole renderer, so they're taken out of its bus while
+    // running, like the scene's. Events they publish wait for the next update
+    pub(crate) fn dispatch(renderer: &mut Renderer) {
+        if renderer.events.queued.is_empty() {
+            return;
+        }
+        let events = std::mem::take(&mut renderer.events.queued);
+        let mut handlers = std::mem::take(&mut renderer.events.handlers);
+        for event in &events {
+            for (subscription, handler) in &mut handlers {
+                if !renderer.events.removed.contains(subscription) {
+                    handler(renderer, event);
+                }
+            }
+        }
+        let removed = std::mem::take(&mut renderer.events.removed);
+        handlers.retain(|(s, _)| !removed.contains(s));
+        handlers.append(&mut renderer.events.handlers);
+        renderer.events.handlers = handlers;
+    }
+}
@@ -0,0 +1,32 @@
+// Stand-in for scene materials while their pipelines compile: flat grey,
+// lit from above, with no textures or lights
+#include "camera.wgsl"
+@group(1) @binding(0)
+var<uniform> camera: Camera;
+
+#include "instance.wgsl"
+
+struct VertexInput {
+    @location(0) position: vec3<f32>,
+    @location(2) normal: vec3<f32>,
+};
+
+struct VertexOutput {
+    @builtin(position) clip_position: vec4<f32>,
+    @location(0) world_normal: vec3<f32>,
+};
+
+@vertex
+fn vs_main(model: VertexInput, instance: InstanceInput) -> VertexOutput {
+    let transform = instance_transform(instance);
+    var out: VertexOutput;
+    out.clip_position = camera.view_proj * transform.model * vec4<f32>(model.position, 1.0);
+    out.world_normal = transform.normal * model.normal;
+    return out;
+}
+
+@fragment
+fn fs_main(in: VertexOutput) -> @location(0) vec4<f32> {
+    let up = normalize(in.world_normal).y * 0.5 + 0.5;
+    return vec4<f32>(vec3<f32>(0.2 + 0.4 * up), 1.0);
+}
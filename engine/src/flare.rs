@@ -0,0 +1,468 @@
+use std::sync::Arc;
+
+use cgmath::{InnerSpace, Matrix4, Vector3, Vector4};
+
+use crate::{
+    light::{DirectionalLight, PositionalLight},
+    memory::{MemoryCategory, MemoryTracker, TrackedBuffer},
+    pipelines::{PipelineCache, PipelineDescriptor},
+    shader::ShaderPreprocessor,
+    texture::Texture,
+    viewport::ViewportRect,
+};
+
+/// One sprite in the chain of a lens flare.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct FlareElement {
+    // Position along the axis from the light (0) through the screen center (1)
+    pub offset: f32,
+    // Radius relative to the screen height
+    pub size: f32,
+    // 0 draws a soft disc, 1 a thin ring
+    pub ring: f32,
+    // Tint and opacity, multiplied with the light color
+    pub color: [f32; 4],
+}
+
+impl FlareElement {
+    pub fn disc(offset: f32, size: f32, color: [f32; 4]) -> Self {
+        return Self {
+            offset,
+            size,
+            ring: 0.0,
+            color,
+        };
+    }
+
+    pub fn ring(offset: f32, size: f32, color: [f32; 4]) -> Self {
+        return Self {
+            offset,
+            size,
+            ring: 1.0,
+            color,
+        };
+    }
+
+    /// Glow around the light followed by ghosts mirrored across the center.
+    pub fn default_chain() -> Vec<FlareElement> {
+        return vec![
+            FlareElement::disc(0.0, 0.3, [1.0, 1.0, 1.0, 0.6]),
+            FlareElement::disc(0.4, 0.05, [1.0, 0.8, 0.5, 0.3]),
+            FlareElement::disc(0.7, 0.08, [0.6, 1.0, 0.6, 0.2]),
+            FlareElement::ring(1.2, 0.12, [0.5, 0.7, 1.0, 0.25]),
+            FlareElement::disc(1.5, 0.04, [1.0, 0.6, 0.9, 0.3]),
+            FlareElement::ring(2.0, 0.35, [1.0, 0.9, 0.7, 0.15]),
+        ];
+    }
+}
+
+#[repr(C)]
+#[derive(Debug, Copy, Clone, bytemuck::Pod, bytemuck::Zeroable)]
+struct FlareElementRaw {
+    offset: f32,
+    size: f32,
+    ring: f32,
+    color: [f32; 4],
+}
+
+impl FlareElementRaw {
+    fn desc<'a>() -> wgpu::VertexBufferLayout<'a> {
+        use std::mem;
+        wgpu::VertexBufferLayout {
+            array_stride: mem::size_of::<FlareElementRaw>() as wgpu::BufferAddress,
+            step_mode: wgpu::VertexStepMode::Instance,
+            attributes: &[
+                wgpu::VertexAttribute {
+                    offset: 0,
+                    shader_location: 0,
+                    format: wgpu::VertexFormat::Float32,
+                },
+                wgpu::VertexAttribute {
+                    offset: mem::size_of::<f32>() as wgpu::BufferAddress,
+                    shader_location: 1,
+                    format: wgpu::VertexFormat::Float32,
+                },
+                wgpu::VertexAttribute {
+                    offset: mem::size_of::<[f32; 2]>() as wgpu::BufferAddress,
+                    shader_location: 2,
+                    format: wgpu::VertexFormat::Float32,
+                },
+                wgpu::VertexAttribute {
+                    offset: mem::size_of::<[f32; 3]>() as wgpu::BufferAddress,
+                    shader_location: 3,
+                    format: wgpu::VertexFormat::Float32x4,
+                },
+            ],
+        }
+    }
+}
+
+#[repr(C)]
+#[derive(Debug, Copy, Clone, Default, bytemuck::Pod, bytemuck::Zeroable)]
+struct FlareUniform {
+    light: [f32; 4],
+    color: [f32; 4],
+    params: [f32; 4],
+}
+
+/// A light the flare can follow.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum FlareSource {
+    // Infinitely far away, opposite to the direction the light travels
+    Directional { direction: Vector3<f32>, color: [f32; 3] },
+    Point { position: Vector3<f32>, color: [f32; 3] },
+}
+
+impl FlareSource {
+    pub fn from_directional(light: &DirectionalLight) -> Self {
+        let c = light.base.color;
+        let s = light.base.strength;
+        return FlareSource::Directional {
+            direction: light.direction,
+            color: [c[0] * s, c[1] * s, c[2] * s],
+        };
+    }
+
+    /// Point and spot lights as seen from `eye`; spot lights only when `eye`
+    /// is inside their cone.
+    pub fn from_positional(light: &PositionalLight, eye: Vector3<f32>) -> Option<Self> {
+        let (point, cone) = match light {
+            PositionalLight::Point(l) => (l, None),
+            PositionalLight::Spot(l) => (&l.base, Some((l.direction, l.cutoff))),
+            PositionalLight::Area(_) => return None,
+        };
+        let to_eye = eye - point.position;
+        if let Some((direction, cutoff)) = cone {
+            if to_eye.normalize().dot(direction.normalize()) < cutoff.0.cos() {
+                return None;
+            }
+        }
+        let d = to_eye.magnitude();
+        let att = &point.attenuation;
+        let falloff = 1.0 / (att.constant + att.linear * d + att.exp * d * d).max(1.0);
+        let c = point.color;
+        return Some(FlareSource::Point {
+            position: point.position,
+            color: [c[0] * falloff, c[1] * falloff, c[2] * falloff],
+        });
+    }
+
+    pub fn color(&self) -> [f32; 3] {
+        return match self {
+            FlareSource::Directional { color, .. } | FlareSource::Point { color, .. } => *color,
+        };
+    }
+
+    pub fn brightness(&self) -> f32 {
+        let c = self.color();
+        return 0.2126 * c[0] + 0.7152 * c[1] + 0.0722 * c[2];
+    }
+
+    // Position in normalized device coordinates and depth, `None` behind the camera
+    fn project(&self, view_proj: Matrix4<f32>) -> Option<Vector3<f32>> {
+        let (clip, directional) = match self {
+            FlareSource::Directional { direction, .. } => {
+                (view_proj * (-direction.normalize()).extend(0.0), true)
+            }
+            FlareSource::Point { position, .. } => (view_proj * position.extend(1.0), false),
+        };
+        if clip.w <= 0.0 {
+            return None;
+        }
+        let Vector4 { x, y, z, w } = clip;
+        // Only the cleared depth of the sky is as far as the sun
+        let depth = if directional { 1.0 } else { z / w };
+        return Some(Vector3::new(x / w, y / w, depth));
+    }
+}
+
+/// Sprites along the line from the brightest light through the screen
+/// center, drawn additively on top of the final image. Occlusion is read
+/// from the scene depth buffer around the light.
+pub struct LensFlare {
+    pub enabled: bool,
+    // Overall strength of the sprites
+    pub intensity: f32,
+    // Fade in and out per second
+    pub fade_speed: f32,
+    // Sources dimmer than this don't produce a flare
+    pub threshold: f32,
+    source: Option<FlareSource>,
+    // Last on-screen position, kept while fading out
+    light: Vector3<f32>,
+    fade: f32,
+    element_count: u32,
+    element_buffer: TrackedBuffer,
+    uniform: FlareUniform,
+    uniform_buffer: TrackedBuffer,
+    bind_group_layout: wgpu::BindGroupLayout,
+    bind_group: wgpu::BindGroup,
+    pipeline: Arc<wgpu::RenderPipeline>,
+}
+
+impl LensFlare {
+    // Spacing of the occlusion taps, 7 x 7 taps cover about 30 pixels
+    const TAP_SPACING: f32 = 5.0;
+
+    pub fn new(
+        device: &wgpu::Device,
+        memory: &MemoryTracker,
+        pipelines: &mut PipelineCache,
+        shaders: &ShaderPreprocessor,
+        format: wgpu::TextureFormat,
+        depth_texture: &Texture,
+        sample_count: u32,
+    ) -> Self {
+        let uniform_buffer = memory.create_buffer_init(
+            device,
+            &wgpu::util::BufferInitDescriptor {
+                label: Some("Flare Uniform Buffer"),
+                contents: bytemuck::cast_slice(&[FlareUniform::default()]),
+                usage: wgpu::BufferUsages::UNIFORM | wgpu::BufferUsages::COPY_DST,
+            },
+            MemoryCategory::Uniform,
+        );
+        let elements = FlareElement::default_chain();
+        let element_buffer = Self::create_element_buffer(device, memory, &elements);
+
+        let bind_group_layout = device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
+            entries: &[
+                wgpu::BindGroupLayoutEntry {
+                    binding: 0,
+                    visibility: wgpu::ShaderStages::VERTEX,
+                    ty: wgpu::BindingType::Buffer {
+                        ty: wgpu::BufferBindingType::Uniform,
+                        has_dynamic_offset: false,
+                        min_binding_size: None,
+                    },
+                    count: None,
+                },
+                wgpu::BindGroupLayoutEntry {
+                    binding: 1,
+                    visibility: wgpu::ShaderStages::VERTEX,
+                    ty: wgpu::BindingType::Texture {
+                        sample_type: wgpu::TextureSampleType::Depth,
+                        view_dimension: wgpu::TextureViewDimension::D2,
+                        multisampled: sample_count > 1,
+                    },
+                    count: None,
+                },
+            ],
+            label: Some("flare_bind_group_layout"),
+        });
+        let bind_group =
+            Self::create_bind_group(device, &bind_group_layout, &uniform_buffer, depth_texture);
+
+        let pipeline = {
+            let mut shaders = shaders.clone();
+            if sample_count > 1 {
+                shaders.enable("MULTISAMPLED_DEPTH");
+            }
+            let shader = shaders
+                .process("flare.wgsl")
+                .expect("Failed to preprocess flare.wgsl");
+            let additive = wgpu::BlendComponent {
+                src_factor: wgpu::BlendFactor::One,
+                dst_factor: wgpu::BlendFactor::One,
+                operation: wgpu::BlendOperation::Add,
+            };
+            pipelines.pipeline(&PipelineDescriptor {
+                label: "Flare Pipeline",
+                layout: "Flare Pipeline Layout",
+                bind_group_layouts: &[&bind_group_layout],
+                shader: &shader,
+                vertex_layouts: &[FlareElementRaw::desc()],
+                color_format: Some(format),
+                depth_format: None,
+                blend: wgpu::BlendState {
+                    color: additive,
+                    alpha: additive,
+                },
+                cull_mode: Some(wgpu::Face::Back),
+                multisample: wgpu::MultisampleState::default(),
+                order_independent: false,
+            })
+        };
+
+        return Self {
+            enabled: true,
+            intensity: 1.0,
+            fade_speed: 4.0,
+            threshold: 0.05,
+            source: None,
+            light: Vector3::new(0.0, 0.0, 1.0),
+            fade: 0.0,
+            element_count: elements.len() as u32,
+            element_buffer,
+            uniform: FlareUniform::default(),
+            uniform_buffer,
+            bind_group_layout,
+            bind_group,
+            pipeline,
+        };
+    }
+
+    fn create_element_buffer(
+        device: &wgpu::Device,
+        memory: &MemoryTracker,
+        elements: &[FlareElement],
+    ) -> TrackedBuffer {
+        let raw = elements
+            .iter()
+            .map(|e| FlareElementRaw {
+                offset: e.offset,
+                size: e.size,
+                ring: e.ring,
+                color: e.color,
+            })
+            .collect::<Vec<_>>();
+        // Keep the buffer non-empty so it can always be bound
+        let contents = if raw.is_empty() {
+            vec![0; std::mem::size_of::<FlareElementRaw>()]
+        } else {
+            bytemuck::cast_slice(&raw).to_vec()
+        };
+        return memory.create_buffer_init(
+            device,
+            &wgpu::util::BufferInitDescriptor {
+                label: Some("Flare Element Buffer"),
+                contents: &contents,
+                usage: wgpu::BufferUsages::VERTEX,
+            },
+            MemoryCategory::Mesh,
+        );
+    }
+
+    fn create_bind_group(
+        device: &wgpu::Device,
+        layout: &wgpu::BindGroupLayout,
+        uniform_buffer: &TrackedBuffer,
+        depth_texture: &Texture,
+    ) -> wgpu::BindGroup {
+        // Stencil formats can only be sampled one aspect at a time
+        let depth_view = depth_texture.texture.create_view(&wgpu::TextureViewDescriptor {
+            aspect: wgpu::TextureAspect::DepthOnly,
+            ..Default::default()
+        });
+        return device.create_bind_group(&wgpu::BindGroupDescriptor {
+            layout,
+            entries: &[
+                wgpu::BindGroupEntry {
+                    binding: 0,
+                    resource: uniform_buffer.as_entire_binding(),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 1,
+                    resource: wgpu::BindingResource::TextureView(&depth_view),
+                },
+            ],
+            label: Some("flare_bind_group"),
+        });
+    }
+
+    /// Replace the sprite chain.
+    pub fn set_elements(&mut self, device: &wgpu::Device, memory: &MemoryTracker, elements: &[FlareElement]) {
+        self.element_buffer = Self::create_element_buffer(device, memory, elements);
+        self.element_count = elements.len() as u32;
+    }
+
+    /// Must be called whenever the depth texture is recreated.
+    pub fn set_depth_texture(&mut self, device: &wgpu::Device, depth_texture: &Texture) {
+        self.bind_group = Self::create_bind_group(
+            device,
+            &self.bind_group_layout,
+            &self.uniform_buffer,
+            depth_texture,
+        );
+    }
+
+    pub fn source(&self) -> Option<FlareSource> {
+        return self.source;
+    }
+
+    /// True while the flare is fading in or out.
+    pub fn is_fading(&self) -> bool {
+        let target = if self.source.is_some() { 1.0 } else { 0.0 };
+        return self.enabled && self.fade != target;
+    }
+
+    /// Follow the brightest of `sources` that is in front of the camera and
+    /// fade towards it over `dt` seconds.
+    pub fn update<I: IntoIterator<Item = FlareSource>>(
+        &mut self,
+        queue: &wgpu::Queue,
+        dt: f32,
+        view_proj: Matrix4<f32>,
+        aspect: f32,
+        sources: I,
+    ) {
+        let visible = sources
+            .into_iter()
+            .filter(|s| s.brightness() >= self.threshold)
+            .filter_map(|s| s.project(view_proj).map(|p| (s, p)))
+            // Allow some margin so the glow can still reach into the screen
+            .filter(|(_, p)| p.x.abs() <= 1.5 && p.y.abs() <= 1.5)
+            .max_by(|(a, _), (b, _)| a.brightness().total_cmp(&b.brightness()));
+
+        self.source = visible.map(|(s, _)| s);
+        let target = match visible {
+            Some((_, position)) => {
+                self.light = position;
+                1.0
+            }
+            None => 0.0,
+        };
+        let step = self.fade_speed * dt;
+        self.fade = if self.fade < target {
+            (self.fade + step).min(target)
+        } else {
+            (self.fade - step).max(target)
+        };
+
+        let color = self.source.map_or([0.0; 3], |s| s.color());
+        // Normalize so that the brightest channel drives the sprites at `intensity`
+        let peak = color.iter().cloned().fold(0.0f32, f32::max).max(1.0);
+        self.uniform = FlareUniform {
+            light: [self.light.x, self.light.y, self.light.z, self.fade],
+            color: [
+                color[0] / peak * self.intensity,
+                color[1] / peak * self.intensity,
+                color[2] / peak * self.intensity,
+                aspect,
+            ],
+            params: [Self::TAP_SPACING, 0.0, 0.0, 0.0],
+        };
+        queue.write_buffer(&self.uniform_buffer, 0, bytemuck::cast_slice(&[self.uniform]));
+    }
+
+    /// Draw over `viewport` of the target, or all of it with `None`.
+    pub fn render(
+        &self,
+        encoder: &mut wgpu::CommandEncoder,
+        target: &wgpu::TextureView,
+        viewport: Option<ViewportRect>,
+    ) {
+        if !self.enabled || self.fade <= 0.0 || self.element_count == 0 {
+            return;
+        }
+        let mut render_pass = encoder.begin_render_pass(&wgpu::RenderPassDescriptor {
+            label: Some("Flare Pass"),
+            color_attachments: &[Some(wgpu::RenderPassColorAttachment {
+                view: target,
+                resolve_target: None,
+                ops: wgpu::Operations {
+                    load: wgpu::LoadOp::Load,
+                    store: true,
+                },
+            })],
+            depth_stencil_attachment: None,
+        });
+        if let Some(rect) = viewport {
+            render_pass.set_viewport(rect.x, rect.y, rect.width, rect.height, 0.0, 1.0);
+        }
+        render_pass.set_pipeline(&self.pipeline);
+        render_pass.set_bind_group(0, &self.bind_group, &[]);
+        render_pass.set_vertex_buffer(0, self.element_buffer.slice(..));
+        render_pass.draw(0..6, 0..self.element_count);
+    }
+}
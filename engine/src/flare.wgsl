@@ -0,0 +1,94 @@
+struct Flare {
+    // xy: light position in NDC, z: light depth, w: fade
+    light: vec4<f32>,
+    // rgb: light color times intensity, w: viewport aspect ratio
+    color: vec4<f32>,
+    // x: spacing of the occlusion taps in pixels
+    params: vec4<f32>,
+};
+@group(0) @binding(0)
+var<uniform> flare: Flare;
+
+#ifdef MULTISAMPLED_DEPTH
+@group(0) @binding(1)
+var t_depth: texture_depth_multisampled_2d;
+#else
+@group(0) @binding(1)
+var t_depth: texture_depth_2d;
+#endif
+
+struct ElementInput {
+    // Position along the axis from the light (0) through the screen center (1)
+    @location(0) offset: f32,
+    // Radius relative to the screen height
+    @location(1) size: f32,
+    // 0 draws a soft disc, 1 a thin ring
+    @location(2) ring: f32,
+    @location(3) color: vec4<f32>,
+};
+
+struct VertexOutput {
+    @builtin(position) clip_position: vec4<f32>,
+    @location(0) uv: vec2<f32>,
+    @location(1) color: vec4<f32>,
+    @location(2) ring: f32,
+};
+
+let OCCLUSION_TAPS: i32 = 3;
+
+// Fraction of depth samples around the light that nothing is in front of
+fn visibility() -> f32 {
+    let dimensions = textureDimensions(t_depth);
+    let uv = vec2<f32>(flare.light.x * 0.5 + 0.5, 0.5 - flare.light.y * 0.5);
+    let center = vec2<i32>(uv * vec2<f32>(dimensions));
+    let spacing = i32(flare.params.x);
+    var visible = 0.0;
+    for (var y = -OCCLUSION_TAPS; y <= OCCLUSION_TAPS; y++) {
+        for (var x = -OCCLUSION_TAPS; x <= OCCLUSION_TAPS; x++) {
+            let p = clamp(center + vec2<i32>(x, y) * spacing, vec2<i32>(0), dimensions - 1);
+            if (textureLoad(t_depth, p, 0) >= flare.light.z) {
+                visible += 1.0;
+            }
+        }
+    }
+    let count = f32((2 * OCCLUSION_TAPS + 1) * (2 * OCCLUSION_TAPS + 1));
+    return visible / count;
+}
+
+@vertex
+fn vs_main(
+    @builtin(vertex_index) vertex_index: u32,
+    element: ElementInput,
+) -> VertexOutput {
+    var corners = array<vec2<f32>, 6>(
+        vec2<f32>(-1.0, -1.0),
+        vec2<f32>(1.0, -1.0),
+        vec2<f32>(1.0, 1.0),
+        vec2<f32>(-1.0, -1.0),
+        vec2<f32>(1.0, 1.0),
+        vec2<f32>(-1.0, 1.0),
+    );
+    let corner = corners[vertex_index];
+
+    let center = flare.light.xy * (1.0 - element.offset);
+    // Fade out towards the screen edges as well as when occluded
+    let edge = clamp(2.0 - 2.0 * max(abs(flare.light.x), abs(flare.light.y)), 0.0, 1.0);
+    let strength = visibility() * flare.light.w * edge;
+
+    var out: VertexOutput;
+    let extent = vec2<f32>(element.size / flare.color.w, element.size);
+    out.clip_position = vec4<f32>(center + corner * extent, 0.0, 1.0);
+    out.uv = corner;
+    out.color = vec4<f32>(element.color.rgb * flare.color.rgb, element.color.a * strength);
+    out.ring = element.ring;
+    return out;
+}
+
+@fragment
+fn fs_main(in: VertexOutput) -> @location(0) vec4<f32> {
+    let r = length(in.uv);
+    let disc = pow(clamp(1.0 - r, 0.0, 1.0), 2.0);
+    let ring = clamp(1.0 - abs(r - 0.85) * 10.0, 0.0, 1.0);
+    let shape = mix(disc, ring, in.ring);
+    return vec4<f32>(in.color.rgb * shape * in.color.a, 0.0);
+}
@@ -0,0 +1,237 @@
+use std::{
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
+    time::{Duration, Instant},
+};
+
+use crate::memory::{MemoryCategory, MemoryTracker, TrackedBuffer};
+
+/// Number of frames the CPU may prepare while the GPU is still consuming earlier ones.
+pub const FRAMES_IN_FLIGHT: usize = 2;
+
+/// Frame caps sleep until this long before the next frame and spin the rest,
+/// sleeps waking up late by about a scheduler tick.
+pub const SPIN_MARGIN: Duration = Duration::from_millis(2);
+
+/// Busy-wait until `deadline`, for the last stretch of a frame cap.
+pub fn spin_until(deadline: Instant) {
+    while Instant::now() < deadline {
+        std::hint::spin_loop();
+    }
+}
+
+/// Keeps the CPU at most a set number of submitted frames ahead of the GPU,
+/// and measures how long it waits for the GPU to tell whether it's the
+/// bottleneck.
+pub struct FramePacer {
+    // Submitted frames the GPU hasn't finished, lowered from queue callbacks
+    in_flight: Arc<AtomicUsize>,
+    last_wait: Option<Instant>,
+    // Smoothed seconds per frame and of those spent waiting
+    frame_time: f32,
+    gpu_wait: f32,
+}
+
+impl Default for FramePacer {
+    fn default() -> Self {
+        return Self::new();
+    }
+}
+
+impl FramePacer {
+    // Waiting longer than this fraction of the frame means the GPU is behind
+    const GPU_BOUND_FRACTION: f32 = 0.2;
+    const SMOOTHING: f32 = 0.1;
+    // Between polls for finished frames. `Maintain::Wait` would wait for all
+    // submitted work rather than just the oldest frame
+    const POLL_INTERVAL: Duration = Duration::from_micros(100);
+
+    pub fn new() -> Self {
+        return Self {
+            in_flight: Arc::new(AtomicUsize::new(0)),
+            last_wait: None,
+            frame_time: 0.0,
+            gpu_wait: 0.0,
+        };
+    }
+
+    pub fn in_flight(&self) -> usize {
+        return self.in_flight.load(Ordering::Acquire);
+    }
+
+    /// Block until fewer than `max_in_flight` frames are in flight, clamped to
+    /// 1 to `FRAMES_IN_FLIGHT`. Call once per frame before recording it.
+    pub fn wait(&mut self, device: &wgpu::Device, max_in_flight: usize) -> Duration {
+        let start = Instant::now();
+        let max_in_flight = max_in_flight.clamp(1, FRAMES_IN_FLIGHT);
+        device.poll(wgpu::Maintain::Poll);
+        while self.in_flight() >= max_in_flight {
+            std::thread::sleep(Self::POLL_INTERVAL);
+            device.poll(wgpu::Maintain::Poll);
+        }
+        let end = Instant::now();
+        let waited = end - start;
+
+        if let Some(last) = self.last_wait {
+            let blend = |average: f32, value: f32| average + (value - average) * Self::SMOOTHING;
+            self.frame_time = blend(self.frame_time, (end - last).as_secs_f32());
+            self.gpu_wait = blend(self.gpu_wait, waited.as_secs_f32());
+        }
+        self.last_wait = Some(end);
+        return waited;
+    }
+
+    /// Count a frame as in flight, right after submitting it.
+    pub fn submitted(&self, queue: &wgpu::Queue) {
+        self.in_flight.fetch_add(1, Ordering::AcqRel);
+        let in_flight = self.in_flight.clone();
+        queue.on_submitted_work_done(move || {
+            in_flight.fetch_sub(1, Ordering::AcqRel);
+        });
+    }
+
+    /// Smoothed time per frame spent waiting for the GPU.
+    pub fn gpu_wait(&self) -> Duration {
+        return Duration::from_secs_f32(self.gpu_wait);
+    }
+
+    /// True when the CPU spends a noticeable part of each frame waiting for
+    /// the GPU, so lighter CPU work won't raise the frame rate.
+    pub fn is_gpu_bound(&self) -> bool {
+        return self.frame_time > 0.0 && self.gpu_wait > self.frame_time * Self::GPU_BOUND_FRACTION;
+    }
+}
+
+/// One GPU buffer per frame in flight. Each frame writes into the next buffer in
+/// the ring, so updates never target a buffer that a previous, still executing
+/// frame is reading from.
+pub struct FrameBuffers {
+    label: String,
+    usage: wgpu::BufferUsages,
+    category: MemoryCategory,
+    buffers: Vec<TrackedBuffer>,
+    versions: Vec<u64>,
+    current: usize,
+}
+
+impl FrameBuffers {
+    pub fn new(
+        device: &wgpu::Device,
+        memory: &MemoryTracker,
+        label: &str,
+        usage: wgpu::BufferUsages,
+        category: MemoryCategory,
+        contents: &[u8],
+    ) -> Self {
+        let usage = usage | wgpu::BufferUsages::COPY_DST;
+        let buffers = (0..FRAMES_IN_FLIGHT)
+            .map(|i| Self::create(device, memory, label, i, usage, category, contents))
+            .collect();
+
+        return Self {
+            label: label.to_string(),
+            usage,
+            category,
+            buffers,
+            versions: vec![0; FRAMES_IN_FLIGHT],
+            current: 0,
+        };
+    }
+
+    fn create(
+        device: &wgpu::Device,
+        memory: &MemoryTracker,
+        label: &str,
+        index: usize,
+        usage: wgpu::BufferUsages,
+        category: MemoryCategory,
+        contents: &[u8],
+    ) -> TrackedBuffer {
+        return memory.create_buffer_init(
+            device,
+            &wgpu::util::BufferInitDescriptor {
+                label: Some(&format!("{} #{}", label, index)),
+                contents,
+                usage,
+            },
+            category,
+        );
+    }
+
+    /// Rotate to the buffer used by the next frame.
+    pub fn advance(&mut self) {
+        self.current = (self.current + 1) % self.buffers.len();
+    }
+
+    pub fn index(&self) -> usize {
+        return self.current;
+    }
+
+    pub fn current(&self) -> &TrackedBuffer {
+        return &self.buffers[self.current];
+    }
+
+    pub fn buffers(&self) -> &[TrackedBuffer] {
+        return &self.buffers;
+    }
+
+    pub fn usage(&self) -> wgpu::BufferUsages {
+        return self.usage;
+    }
+
+    pub fn is_up_to_date(&self, version: u64) -> bool {
+        return self.versions[self.current] == version;
+    }
+
+    /// Upload `data` into the current buffer unless it already holds `version`.
+    /// The buffer is recreated when `data` no longer fits, in which case `true`
+    /// is returned so that dependent bind groups can be rebuilt.
+    pub fn write(
+        &mut self,
+        device: &wgpu::Device,
+        memory: &MemoryTracker,
+        queue: &wgpu::Queue,
+        data: &[u8],
+        version: u64,
+    ) -> bool {
+        if self.is_up_to_date(version) {
+            return false;
+        }
+        self.versions[self.current] = version;
+
+        if data.len() as u64 > self.buffers[self.current].size() {
+            self.buffers[self.current] = Self::create(
+                device,
+                memory,
+                &self.label,
+                self.current,
+                self.usage,
+                self.category,
+                data,
+            );
+            return true;
+        }
+        queue.write_buffer(&self.buffers[self.current], 0, data);
+        return false;
+    }
+
+    /// Grow the current buffer to at least `size` bytes, for buffers filled on
+    /// the GPU. Returns `true` when it was recreated, like `write`.
+    pub fn reserve(&mut self, device: &wgpu::Device, memory: &MemoryTracker, size: u64) -> bool {
+        if size <= self.buffers[self.current].size() {
+            return false;
+        }
+        self.buffers[self.current] = Self::create(
+            device,
+            memory,
+            &self.label,
+            self.current,
+            self.usage,
+            self.category,
+            &vec![0; size as usize],
+        );
+        return true;
+    }
+}
@@ -0,0 +1,64 @@
+use std::time::Duration;
+
+use anyhow::*;
+use gilrs::{Button, EventType, Gilrs};
+
+use crate::{input::GamepadButton, renderer::Renderer};
+
+/// Between gamepad polls while the event loop would otherwise sleep, as
+/// gamepads don't wake it.
+pub const IDLE_POLL: Duration = Duration::from_millis(16);
+
+/// Connected gamepads, read with gilrs and fed to `Renderer::gamepad_input`.
+pub struct Gamepads {
+    gilrs: Gilrs,
+}
+
+impl Gamepads {
+    pub fn new() -> Result<Self> {
+        let gilrs = Gilrs::new().map_err(|e| anyhow!("Failed to open gamepads: {}", e))?;
+        return Ok(Self { gilrs });
+    }
+
+    /// Forward the button presses and releases of every gamepad since the
+    /// last poll. True if the renderer used any of them.
+    pub fn poll(&mut self, renderer: &mut Renderer) -> bool {
+        let mut handled = false;
+        while let Some(event) = self.gilrs.next_event() {
+            let (button, pressed) = match event.event {
+                EventType::ButtonPressed(button, _) => (button, true),
+                EventType::ButtonReleased(button, _) => (button, false),
+                _ => continue,
+            };
+            if let Some(button) = gamepad_button(button) {
+                handled |= renderer.gamepad_input(button, pressed);
+            }
+        }
+        return handled;
+    }
+}
+
+// gilrs calls the shoulder buttons triggers and the analog triggers the
+// second ones
+fn gamepad_button(button: Button) -> Option<GamepadButton> {
+    return Some(match button {
+        Button::South => GamepadButton::South,
+        Button::East => GamepadButton::East,
+        Button::West => GamepadButton::West,
+        Button::North => GamepadButton::North,
+        Button::LeftTrigger => GamepadButton::LeftShoulder,
+        Button::RightTrigger => GamepadButton::RightShoulder,
+        Button::LeftTrigger2 => GamepadButton::LeftTrigger,
+        Button::RightTrigger2 => GamepadButton::RightTrigger,
+        Button::Select => GamepadButton::Select,
+        Button::Start => GamepadButton::Start,
+        Button::Mode => GamepadButton::Mode,
+        Button::LeftThumb => GamepadButton::LeftStick,
+        Button::RightThumb => GamepadButton::RightStick,
+        Button::DPadUp => GamepadButton::DPadUp,
+        Button::DPadDown => GamepadButton::DPadDown,
+        Button::DPadLeft => GamepadButton::DPadLeft,
+        Button::DPadRight => GamepadButton::DPadRight,
+        Button::C | Button::Z | Button::Unknown => return None,
+    });
+}
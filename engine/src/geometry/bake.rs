@@ -0,0 +1,158 @@
+//! Normal map baking. Rays are cast from every texel of a low-poly mesh along
+//! its interpolated normal to find the nearest surface of a high-poly mesh,
+//! whose normal is stored in the tangent space of the low-poly mesh.
+use cgmath::{InnerSpace, Matrix3, SquareMatrix, Vector2, Vector3};
+
+use super::{bvh::Bvh, Geometry};
+
+// Flat tangent-space normal for texels no triangle covers
+const FLAT_NORMAL: [u8; 4] = [128, 128, 255, 255];
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct NormalBakeSettings {
+    pub width: u32,
+    pub height: u32,
+    /// How far from the low-poly surface the high-poly surface is searched,
+    /// on both sides. `None` uses 5% of the high-poly bounds diagonal.
+    pub max_distance: Option<f32>,
+    /// Texels to grow the baked islands by, so filtering and mipmaps don't
+    /// pull in the flat background at UV seams.
+    pub padding: u32,
+}
+
+impl Default for NormalBakeSettings {
+    fn default() -> Self {
+        Self {
+            width: 1024,
+            height: 1024,
+            max_distance: None,
+            padding: 4,
+        }
+    }
+}
+
+// Texel color for a world space normal in the tangent frame of the low-poly
+// surface. Inverts the same matrix the shader builds, so the shader gets
+// `normal` back even if the frame isn't orthonormal.
+fn encode_normal(
+    normal: Vector3<f32>,
+    tangent: Vector3<f32>,
+    bitangent: Vector3<f32>,
+    surface_normal: Vector3<f32>,
+) -> [u8; 4] {
+    let frame = Matrix3::from_cols(tangent, bitangent, surface_normal);
+    let local = match frame.invert() {
+        Some(inverse) => inverse * normal,
+        None => Vector3::new(normal.dot(tangent), normal.dot(bitangent), normal.dot(surface_normal)),
+    };
+    let local = if local.magnitude2() > 0.0 {
+        local.normalize()
+    } else {
+        Vector3::unit_z()
+    };
+    let channel = |v: f32| ((v * 0.5 + 0.5).clamp(0.0, 1.0) * 255.0).round() as u8;
+    return [channel(local.x), channel(local.y), channel(local.z), 255];
+}
+
+// Grow baked texels into empty neighbors, one ring per pass
+fn dilate(pixels: &mut [Option<[u8; 4]>], width: usize, height: usize, passes: u32) {
+    for _ in 0..passes {
+        let source = pixels.to_vec();
+        for y in 0..height {
+            for x in 0..width {
+                if source[y * width + x].is_some() {
+                    continue;
+                }
+                let mut sum = [0u32; 4];
+                let mut count = 0;
+                let neighbors = [(-1, 0), (1, 0), (0, -1), (0, 1)];
+                for (dx, dy) in neighbors {
+                    let (nx, ny) = (x as i64 + dx, y as i64 + dy);
+                    if nx < 0 || ny < 0 || nx >= width as i64 || ny >= height as i64 {
+                        continue;
+                    }
+                    if let Some(p) = source[ny as usize * width + nx as usize] {
+                        for c in 0..4 {
+                            sum[c] += p[c] as u32;
+                        }
+                        count += 1;
+                    }
+                }
+                if count > 0 {
+                    pixels[y * width + x] = Some(sum.map(|s| (s / count) as u8));
+                }
+            }
+        }
+    }
+}
+
+/// Bake the surface detail of `high` into a tangent-space normal map for the
+/// texture layout of `low`. `low` needs texture coordinates, normals and
+/// tangents (see `calculate_tangents_bitangents`) and both meshes must be
+/// in the same space. Texels where no high-poly surface is found keep the
+/// low-poly normal.
+pub fn bake_normal_map(low: &Geometry, high: &Geometry, settings: &NormalBakeSettings) -> image::RgbaImage {
+    let (width, height) = (settings.width as usize, settings.height as usize);
+    let bounds = high.bounds();
+    let max_distance = settings
+        .max_distance
+        .unwrap_or_else(|| (bounds.max - bounds.min).magnitude() * 0.05);
+    let bvh = Bvh::new(high);
+    let mut pixels: Vec<Option<[u8; 4]>> = vec![None; width * height];
+
+    for c in low.indices.chunks_exact(3) {
+        let v = [c[0], c[1], c[2]].map(|i| low.vertices[i as usize]);
+        let uv = v.map(|v| Vector2::new(v.tex_coords[0] * width as f32, v.tex_coords[1] * height as f32));
+        let area = (uv[1] - uv[0]).perp_dot(uv[2] - uv[0]);
+        if area.abs() < 1e-8 {
+            continue;
+        }
+
+        let min_x = uv.iter().map(|p| p.x).fold(f32::MAX, f32::min).floor().max(0.0) as usize;
+        let min_y = uv.iter().map(|p| p.y).fold(f32::MAX, f32::min).floor().max(0.0) as usize;
+        let max_x = (uv.iter().map(|p| p.x).fold(f32::MIN, f32::max).ceil() as usize).min(width);
+        let max_y = (uv.iter().map(|p| p.y).fold(f32::MIN, f32::max).ceil() as usize).min(height);
+
+        for y in min_y..max_y {
+            for x in min_x..max_x {
+                // Barycentric coordinates of the texel center
+                let p = Vector2::new(x as f32 + 0.5, y as f32 + 0.5);
+                let w0 = (uv[2] - uv[1]).perp_dot(p - uv[1]) / area;
+                let w1 = (uv[0] - uv[2]).perp_dot(p - uv[2]) / area;
+                let w2 = 1.0 - w0 - w1;
+                if w0 < -1e-4 || w1 < -1e-4 || w2 < -1e-4 {
+                    continue;
+                }
+                let lerp = |a: [f32; 3], b: [f32; 3], c: [f32; 3]| {
+                    Vector3::from(a) * w0 + Vector3::from(b) * w1 + Vector3::from(c) * w2
+                };
+                let position = lerp(v[0].position, v[1].position, v[2].position);
+                let normal = lerp(v[0].normal, v[1].normal, v[2].normal);
+                let tangent = lerp(v[0].tangent, v[1].tangent, v[2].tangent);
+                let bitangent = lerp(v[0].bitangent, v[1].bitangent, v[2].bitangent);
+                if normal.magnitude2() == 0.0 {
+                    continue;
+                }
+                let direction = normal.normalize();
+
+                // Nearest high-poly surface on either side of the low-poly one
+                let outside = bvh.cast(position, direction, max_distance);
+                let inside = bvh.cast(position, -direction, max_distance);
+                let hit = match (outside, inside) {
+                    (Some(o), Some(i)) => Some(if o.0 <= i.0 { o.1 } else { i.1 }),
+                    (o, i) => o.or(i).map(|(_, n)| n),
+                };
+                let detail = hit.unwrap_or(normal);
+                pixels[y * width + x] = Some(encode_normal(detail, tangent, bitangent, normal));
+            }
+        }
+    }
+
+    dilate(&mut pixels, width, height, settings.padding);
+    let data = pixels
+        .into_iter()
+        .flat_map(|p| p.unwrap_or(FLAT_NORMAL))
+        .collect::<Vec<_>>();
+    return image::RgbaImage::from_raw(settings.width, settings.height, data)
+        .expect("Normal map size matches its pixels");
+}
@@ -0,0 +1,247 @@
+use cgmath::{InnerSpace, Vector3};
+
+use super::{Aabb, Geometry};
+
+// Triangles per BVH leaf
+const LEAF_SIZE: usize = 4;
+
+#[derive(Debug, Copy, Clone)]
+pub(crate) struct Triangle {
+    pub positions: [Vector3<f32>; 3],
+    pub normals: [Vector3<f32>; 3],
+}
+
+impl Triangle {
+    fn centroid(&self) -> Vector3<f32> {
+        return (self.positions[0] + self.positions[1] + self.positions[2]) / 3.0;
+    }
+
+    fn bounds(&self) -> Aabb {
+        let [a, b, c] = self.positions;
+        return Aabb::new(
+            Vector3::new(a.x.min(b.x).min(c.x), a.y.min(b.y).min(c.y), a.z.min(b.z).min(c.z)),
+            Vector3::new(a.x.max(b.x).max(c.x), a.y.max(b.y).max(c.y), a.z.max(b.z).max(c.z)),
+        );
+    }
+
+    // Möller-Trumbore, returns the distance and barycentric coordinates of b and c
+    fn intersect(&self, origin: Vector3<f32>, direction: Vector3<f32>) -> Option<(f32, f32, f32)> {
+        let [a, b, c] = self.positions;
+        let edge1 = b - a;
+        let edge2 = c - a;
+        let p = direction.cross(edge2);
+        let det = edge1.dot(p);
+        if det.abs() < 1e-12 {
+            return None;
+        }
+        let inv_det = 1.0 / det;
+        let s = origin - a;
+        let u = s.dot(p) * inv_det;
+        if !(0.0..=1.0).contains(&u) {
+            return None;
+        }
+        let q = s.cross(edge1);
+        let v = direction.dot(q) * inv_det;
+        if v < 0.0 || u + v > 1.0 {
+            return None;
+        }
+        return Some((edge2.dot(q) * inv_det, u, v));
+    }
+
+    fn normal_at(&self, u: f32, v: f32) -> Vector3<f32> {
+        let [a, b, c] = self.normals;
+        return a * (1.0 - u - v) + b * u + c * v;
+    }
+
+    // Point of the triangle nearest to `p`, by the Voronoi region of `p`
+    // (Ericson, Real-Time Collision Detection 5.1.5)
+    pub fn closest_point(&self, p: Vector3<f32>) -> Vector3<f32> {
+        let [a, b, c] = self.positions;
+        let (ab, ac, ap) = (b - a, c - a, p - a);
+        let (d1, d2) = (ab.dot(ap), ac.dot(ap));
+        if d1 <= 0.0 && d2 <= 0.0 {
+            return a;
+        }
+        let bp = p - b;
+        let (d3, d4) = (ab.dot(bp), ac.dot(bp));
+        if d3 >= 0.0 && d4 <= d3 {
+            return b;
+        }
+        let vc = d1 * d4 - d3 * d2;
+        if vc <= 0.0 && d1 >= 0.0 && d3 <= 0.0 {
+            return a + ab * (d1 / (d1 - d3));
+        }
+        let cp = p - c;
+        let (d5, d6) = (ab.dot(cp), ac.dot(cp));
+        if d6 >= 0.0 && d5 <= d6 {
+            return c;
+        }
+        let vb = d5 * d2 - d1 * d6;
+        if vb <= 0.0 && d2 >= 0.0 && d6 <= 0.0 {
+            return a + ac * (d2 / (d2 - d6));
+        }
+        let va = d3 * d6 - d5 * d4;
+        if va <= 0.0 && d4 - d3 >= 0.0 && d5 - d6 >= 0.0 {
+            return b + (c - b) * ((d4 - d3) / ((d4 - d3) + (d5 - d6)));
+        }
+        let denom = 1.0 / (va + vb + vc);
+        return a + ab * (vb * denom) + ac * (vc * denom);
+    }
+
+    pub fn face_normal(&self) -> Vector3<f32> {
+        let [a, b, c] = self.positions;
+        return (b - a).cross(c - a).normalize();
+    }
+}
+
+fn intersect_aabb(aabb: &Aabb, origin: Vector3<f32>, inv_direction: Vector3<f32>, max_t: f32) -> bool {
+    let mut t_min = 0.0f32;
+    let mut t_max = max_t;
+    for axis in 0..3 {
+        let t1 = (aabb.min[axis] - origin[axis]) * inv_direction[axis];
+        let t2 = (aabb.max[axis] - origin[axis]) * inv_direction[axis];
+        t_min = t_min.max(t1.min(t2));
+        t_max = t_max.min(t1.max(t2));
+    }
+    return t_min <= t_max;
+}
+
+struct Node {
+    bounds: Aabb,
+    // Leaves reference `count` triangles from `start`, inner nodes have
+    // `count` 0 and their children at `start` and `start + 1`
+    start: usize,
+    count: usize,
+}
+
+/// Bounding volume hierarchy over a mesh's triangles, for ray casts and
+/// nearest point queries.
+pub(crate) struct Bvh {
+    triangles: Vec<Triangle>,
+    nodes: Vec<Node>,
+}
+
+impl Bvh {
+    pub fn new(geometry: &Geometry) -> Self {
+        let vertex = |i: u32| geometry.vertices[i as usize];
+        let triangles = geometry
+            .indices
+            .chunks_exact(3)
+            .map(|c| {
+                let [a, b, c] = [vertex(c[0]), vertex(c[1]), vertex(c[2])];
+                Triangle {
+                    positions: [a.position.into(), b.position.into(), c.position.into()],
+                    normals: [a.normal.into(), b.normal.into(), c.normal.into()],
+                }
+            })
+            .collect::<Vec<_>>();
+        let mut bvh = Self {
+            triangles,
+            nodes: Vec::new(),
+        };
+        if !bvh.triangles.is_empty() {
+            let count = bvh.triangles.len();
+            bvh.nodes.push(Node {
+                bounds: bvh.bounds(0, count),
+                start: 0,
+                count,
+            });
+            bvh.split(0);
+        }
+        return bvh;
+    }
+
+    fn bounds(&self, start: usize, count: usize) -> Aabb {
+        return self.triangles[start..start + count]
+            .iter()
+            .map(Triangle::bounds)
+            .reduce(|a, b| a.union(&b))
+            .unwrap();
+    }
+
+    fn split(&mut self, index: usize) {
+        let Node { bounds, start, count } = self.nodes[index];
+        if count <= LEAF_SIZE {
+            return;
+        }
+        let extent = bounds.max - bounds.min;
+        let axis = if extent.x >= extent.y && extent.x >= extent.z {
+            0
+        } else if extent.y >= extent.z {
+            1
+        } else {
+            2
+        };
+        let half = count / 2;
+        self.triangles[start..start + count].select_nth_unstable_by(half, |a, b| {
+            a.centroid()[axis].total_cmp(&b.centroid()[axis])
+        });
+
+        let left = self.nodes.len();
+        for (child_start, child_count) in [(start, half), (start + half, count - half)] {
+            self.nodes.push(Node {
+                bounds: self.bounds(child_start, child_count),
+                start: child_start,
+                count: child_count,
+            });
+        }
+        self.nodes[index].start = left;
+        self.nodes[index].count = 0;
+        self.split(left);
+        self.split(left + 1);
+    }
+
+    // Closest hit within `max_t`, with the interpolated normal there
+    pub fn cast(&self, origin: Vector3<f32>, direction: Vector3<f32>, max_t: f32) -> Option<(f32, Vector3<f32>)> {
+        if self.nodes.is_empty() {
+            return None;
+        }
+        let inv_direction = Vector3::new(1.0 / direction.x, 1.0 / direction.y, 1.0 / direction.z);
+        let mut closest: Option<(f32, Vector3<f32>)> = None;
+        let mut stack = vec![0];
+        while let Some(index) = stack.pop() {
+            let node = &self.nodes[index];
+            let limit = closest.map_or(max_t, |(t, _)| t);
+            if !intersect_aabb(&node.bounds, origin, inv_direction, limit) {
+                continue;
+            }
+            if node.count == 0 {
+                stack.push(node.start);
+                stack.push(node.start + 1);
+                continue;
+            }
+            for triangle in &self.triangles[node.start..node.start + node.count] {
+                if let Some((t, u, v)) = triangle.intersect(origin, direction) {
+                    if t >= 0.0 && t <= closest.map_or(max_t, |(t, _)| t) {
+                        closest = Some((t, triangle.normal_at(u, v)));
+                    }
+                }
+            }
+        }
+        return closest;
+    }
+
+    /// Call `f` with every triangle whose bounds overlap `bounds`.
+    pub fn for_each_overlapping<F: FnMut(&Triangle)>(&self, bounds: &Aabb, mut f: F) {
+        if self.nodes.is_empty() {
+            return;
+        }
+        let mut stack = vec![0];
+        while let Some(index) = stack.pop() {
+            let node = &self.nodes[index];
+            if !node.bounds.intersects(bounds) {
+                continue;
+            }
+            if node.count == 0 {
+                stack.push(node.start);
+                stack.push(node.start + 1);
+                continue;
+            }
+            for triangle in &self.triangles[node.start..node.start + node.count] {
+                if triangle.bounds().intersects(bounds) {
+                    f(triangle);
+                }
+            }
+        }
+    }
+}
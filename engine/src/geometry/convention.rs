@@ -0,0 +1,96 @@
+use cgmath::{Matrix4, SquareMatrix, Vector4};
+
+use super::Geometry;
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum UpAxis {
+    Y,
+    Z,
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Handedness {
+    Right,
+    Left,
+}
+
+/// Axes and units assets were authored in. The engine is right-handed with
+/// +Y up in meters, see `camera::perspective_rh`. Only the up axis and
+/// handedness are converted, a model facing another way than the engine's
+/// -Z forward still needs rotating.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct CoordinateSystem {
+    pub up: UpAxis,
+    pub handedness: Handedness,
+    /// Engine units per source unit, e.g. 0.01 for centimeters.
+    pub unit_scale: f32,
+}
+
+impl CoordinateSystem {
+    /// The engine's own, and glTF's and most OBJ exporters'.
+    pub const Y_UP_RIGHT: Self = Self::new(UpAxis::Y, Handedness::Right);
+    /// Blender, 3ds Max and most CAD, STL and PLY files.
+    pub const Z_UP_RIGHT: Self = Self::new(UpAxis::Z, Handedness::Right);
+    /// Unity and Direct3D samples.
+    pub const Y_UP_LEFT: Self = Self::new(UpAxis::Y, Handedness::Left);
+    /// Unreal, whose units are centimeters.
+    pub const Z_UP_LEFT: Self = Self {
+        unit_scale: 0.01,
+        ..Self::new(UpAxis::Z, Handedness::Left)
+    };
+
+    pub const fn new(up: UpAxis, handedness: Handedness) -> Self {
+        return Self {
+            up,
+            handedness,
+            unit_scale: 1.0,
+        };
+    }
+
+    /// Transform from this system into the engine's. Z up turns onto Y the
+    /// way Blender's glTF exporter does it, (x, y, z) to (x, z, -y), and
+    /// left-handed systems are mirrored along their depth axis.
+    pub fn to_engine(&self) -> Matrix4<f32> {
+        let s = self.unit_scale;
+        let (x, y, z) = match (self.up, self.handedness) {
+            (UpAxis::Y, Handedness::Right) => ([s, 0.0, 0.0], [0.0, s, 0.0], [0.0, 0.0, s]),
+            (UpAxis::Y, Handedness::Left) => ([s, 0.0, 0.0], [0.0, s, 0.0], [0.0, 0.0, -s]),
+            (UpAxis::Z, Handedness::Right) => ([s, 0.0, 0.0], [0.0, 0.0, -s], [0.0, s, 0.0]),
+            (UpAxis::Z, Handedness::Left) => ([s, 0.0, 0.0], [0.0, 0.0, s], [0.0, s, 0.0]),
+        };
+        let column = |[a, b, c]: [f32; 3]| Vector4::new(a, b, c, 0.0);
+        return Matrix4::from_cols(column(x), column(y), column(z), Vector4::unit_w());
+    }
+
+    /// Transform from the engine's system into this one, e.g. for exports.
+    pub fn from_engine(&self) -> Matrix4<f32> {
+        return self.to_engine().invert().unwrap_or_else(Matrix4::identity);
+    }
+
+    /// Whether converting flips handedness, which reverses triangle winding.
+    pub fn is_mirrored(&self) -> bool {
+        return self.handedness == Handedness::Left;
+    }
+}
+
+impl Default for CoordinateSystem {
+    fn default() -> Self {
+        return Self::Y_UP_RIGHT;
+    }
+}
+
+impl Geometry {
+    /// Convert geometry authored in `system` into the engine's coordinates,
+    /// keeping triangles front facing when the conversion mirrors them.
+    pub fn convert_from(&mut self, system: CoordinateSystem) {
+        if system == CoordinateSystem::Y_UP_RIGHT {
+            return;
+        }
+        if system.is_mirrored() {
+            for triangle in self.indices.chunks_exact_mut(3) {
+                triangle.swap(1, 2);
+            }
+        }
+        self.transform(system.to_engine());
+    }
+}
@@ -0,0 +1,398 @@
+//! Constructive solid geometry on `Geometry` using BSP trees, following the
+//! approach of Evan Wallace's csg.js.
+use std::collections::HashMap;
+
+use cgmath::{InnerSpace, Vector2, Vector3};
+
+use super::Geometry;
+use crate::resources::ModelVertex;
+
+const EPSILON: f32 = 1e-5;
+
+#[derive(Debug, Copy, Clone)]
+struct CsgVertex {
+    position: Vector3<f32>,
+    normal: Vector3<f32>,
+    uv: Vector2<f32>,
+    uv1: Vector2<f32>,
+}
+
+impl CsgVertex {
+    fn flip(&mut self) {
+        self.normal = -self.normal;
+    }
+
+    fn interpolate(&self, other: &CsgVertex, t: f32) -> CsgVertex {
+        return CsgVertex {
+            position: self.position + (other.position - self.position) * t,
+            normal: self.normal + (other.normal - self.normal) * t,
+            uv: self.uv + (other.uv - self.uv) * t,
+            uv1: self.uv1 + (other.uv1 - self.uv1) * t,
+        };
+    }
+}
+
+#[derive(Debug, Copy, Clone)]
+struct Plane {
+    normal: Vector3<f32>,
+    w: f32,
+}
+
+const COPLANAR: u8 = 0;
+const FRONT: u8 = 1;
+const BACK: u8 = 2;
+const SPANNING: u8 = 3;
+
+impl Plane {
+    fn from_points(a: Vector3<f32>, b: Vector3<f32>, c: Vector3<f32>) -> Option<Plane> {
+        let normal = (b - a).cross(c - a);
+        if normal.magnitude2() < EPSILON * EPSILON {
+            return None;
+        }
+        let normal = normal.normalize();
+        return Some(Plane {
+            normal,
+            w: normal.dot(a),
+        });
+    }
+
+    fn flip(&mut self) {
+        self.normal = -self.normal;
+        self.w = -self.w;
+    }
+
+    /// Split `polygon` by this plane if needed, then put the polygon or polygon
+    /// fragments in the appropriate lists. Coplanar polygons go into either
+    /// `coplanar_front` or `coplanar_back` depending on their orientation.
+    fn split_polygon(
+        &self,
+        polygon: Polygon,
+        coplanar_front: &mut Vec<Polygon>,
+        coplanar_back: &mut Vec<Polygon>,
+        front: &mut Vec<Polygon>,
+        back: &mut Vec<Polygon>,
+    ) {
+        let mut polygon_type = 0;
+        let types: Vec<u8> = polygon
+            .vertices
+            .iter()
+            .map(|v| {
+                let t = self.normal.dot(v.position) - self.w;
+                let ty = if t < -EPSILON {
+                    BACK
+                } else if t > EPSILON {
+                    FRONT
+                } else {
+                    COPLANAR
+                };
+                polygon_type |= ty;
+                ty
+            })
+            .collect();
+
+        match polygon_type {
+            COPLANAR => {
+                if self.normal.dot(polygon.plane.normal) > 0.0 {
+                    coplanar_front.push(polygon);
+                } else {
+                    coplanar_back.push(polygon);
+                }
+            }
+            FRONT => front.push(polygon),
+            BACK => back.push(polygon),
+            _ => {
+                let mut f = Vec::new();
+                let mut b = Vec::new();
+                let n = polygon.vertices.len();
+                for i in 0..n {
+                    let j = (i + 1) % n;
+                    let (ti, tj) = (types[i], types[j]);
+                    let (vi, vj) = (polygon.vertices[i], polygon.vertices[j]);
+                    if ti != BACK {
+                        f.push(vi);
+                    }
+                    if ti != FRONT {
+                        b.push(vi);
+                    }
+                    if (ti | tj) == SPANNING {
+                        let t = (self.w - self.normal.dot(vi.position))
+                            / self.normal.dot(vj.position - vi.position);
+                        let v = vi.interpolate(&vj, t);
+                        f.push(v);
+                        b.push(v);
+                    }
+                }
+                if f.len() >= 3 {
+                    front.push(Polygon {
+                        vertices: f,
+                        plane: polygon.plane,
+                    });
+                }
+                if b.len() >= 3 {
+                    back.push(Polygon {
+                        vertices: b,
+                        plane: polygon.plane,
+                    });
+                }
+            }
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+struct Polygon {
+    vertices: Vec<CsgVertex>,
+    plane: Plane,
+}
+
+impl Polygon {
+    fn flip(&mut self) {
+        self.vertices.reverse();
+        for v in &mut self.vertices {
+            v.flip();
+        }
+        self.plane.flip();
+    }
+}
+
+#[derive(Default)]
+struct Node {
+    plane: Option<Plane>,
+    front: Option<Box<Node>>,
+    back: Option<Box<Node>>,
+    polygons: Vec<Polygon>,
+}
+
+impl Node {
+    fn new(polygons: Vec<Polygon>) -> Self {
+        let mut node = Node::default();
+        node.build(polygons);
+        return node;
+    }
+
+    /// Convert solid space to empty space and empty space to solid space.
+    fn invert(&mut self) {
+        for p in &mut self.polygons {
+            p.flip();
+        }
+        if let Some(plane) = &mut self.plane {
+            plane.flip();
+        }
+        if let Some(front) = &mut self.front {
+            front.invert();
+        }
+        if let Some(back) = &mut self.back {
+            back.invert();
+        }
+        std::mem::swap(&mut self.front, &mut self.back);
+    }
+
+    /// Remove all polygons in `polygons` that are inside this BSP tree.
+    fn clip_polygons(&self, polygons: Vec<Polygon>) -> Vec<Polygon> {
+        let plane = match self.plane {
+            Some(plane) => plane,
+            None => return polygons,
+        };
+
+        let mut coplanar_front = Vec::new();
+        let mut coplanar_back = Vec::new();
+        let mut front = Vec::new();
+        let mut back = Vec::new();
+        for p in polygons {
+            plane.split_polygon(
+                p,
+                &mut coplanar_front,
+                &mut coplanar_back,
+                &mut front,
+                &mut back,
+            );
+        }
+        front.append(&mut coplanar_front);
+        back.append(&mut coplanar_back);
+
+        let mut front = match &self.front {
+            Some(node) => node.clip_polygons(front),
+            None => front,
+        };
+        let back = match &self.back {
+            Some(node) => node.clip_polygons(back),
+            None => Vec::new(),
+        };
+        front.extend(back);
+        return front;
+    }
+
+    /// Remove all polygons in this BSP tree that are inside `bsp`.
+    fn clip_to(&mut self, bsp: &Node) {
+        self.polygons = bsp.clip_polygons(std::mem::take(&mut self.polygons));
+        if let Some(front) = &mut self.front {
+            front.clip_to(bsp);
+        }
+        if let Some(back) = &mut self.back {
+            back.clip_to(bsp);
+        }
+    }
+
+    fn all_polygons(&self) -> Vec<Polygon> {
+        let mut polygons = self.polygons.clone();
+        if let Some(front) = &self.front {
+            polygons.extend(front.all_polygons());
+        }
+        if let Some(back) = &self.back {
+            polygons.extend(back.all_polygons());
+        }
+        return polygons;
+    }
+
+    fn build(&mut self, polygons: Vec<Polygon>) {
+        if polygons.is_empty() {
+            return;
+        }
+        let plane = *self.plane.get_or_insert(polygons[0].plane);
+
+        let mut coplanar_front = Vec::new();
+        let mut coplanar_back = Vec::new();
+        let mut front = Vec::new();
+        let mut back = Vec::new();
+        for p in polygons {
+            plane.split_polygon(
+                p,
+                &mut coplanar_front,
+                &mut coplanar_back,
+                &mut front,
+                &mut back,
+            );
+        }
+        self.polygons.append(&mut coplanar_front);
+        self.polygons.append(&mut coplanar_back);
+
+        if !front.is_empty() {
+            self.front.get_or_insert_with(Default::default).build(front);
+        }
+        if !back.is_empty() {
+            self.back.get_or_insert_with(Default::default).build(back);
+        }
+    }
+}
+
+fn to_polygons(geometry: &Geometry) -> Vec<Polygon> {
+    return geometry
+        .indices
+        .chunks_exact(3)
+        .filter_map(|c| {
+            let vertices: Vec<CsgVertex> = c
+                .iter()
+                .map(|&i| {
+                    let v = &geometry.vertices[i as usize];
+                    CsgVertex {
+                        position: v.position.into(),
+                        normal: v.normal.into(),
+                        uv: v.tex_coords.into(),
+                        uv1: v.secondary_uv().into(),
+                    }
+                })
+                .collect();
+            let plane = Plane::from_points(
+                vertices[0].position,
+                vertices[1].position,
+                vertices[2].position,
+            )?;
+            Some(Polygon { vertices, plane })
+        })
+        .collect();
+}
+
+/// Triangulate polygons with flat normals taken from their planes and merge
+/// vertices that ended up identical after splitting.
+fn from_polygons(polygons: Vec<Polygon>) -> Geometry {
+    let quantize = |v: f32| (v / EPSILON).round() as i64;
+    let mut lookup: HashMap<[i64; 10], u32> = HashMap::new();
+    let mut geometry = Geometry::default();
+
+    for polygon in polygons {
+        let normal = polygon.plane.normal;
+        let indices: Vec<u32> = polygon
+            .vertices
+            .iter()
+            .map(|v| {
+                let key = [
+                    quantize(v.position.x),
+                    quantize(v.position.y),
+                    quantize(v.position.z),
+                    quantize(normal.x),
+                    quantize(normal.y),
+                    quantize(normal.z),
+                    quantize(v.uv.x),
+                    quantize(v.uv.y),
+                    quantize(v.uv1.x),
+                    quantize(v.uv1.y),
+                ];
+                *lookup.entry(key).or_insert_with(|| {
+                    geometry.vertices.push(ModelVertex {
+                        position: v.position.into(),
+                        tex_coords: v.uv.into(),
+                        normal: normal.into(),
+                        tangent: [0.0; 3],
+                        bitangent: [0.0; 3],
+                        #[cfg(feature = "secondary-uv")]
+                        tex_coords1: v.uv1.into(),
+                    });
+                    geometry.vertices.len() as u32 - 1
+                })
+            })
+            .collect();
+
+        for i in 1..indices.len() - 1 {
+            geometry
+                .indices
+                .extend_from_slice(&[indices[0], indices[i], indices[i + 1]]);
+        }
+    }
+
+    geometry.calculate_tangents_bitangents();
+    return geometry;
+}
+
+impl Geometry {
+    /// Solid containing the space of both `self` and `other`.
+    pub fn union(&self, other: &Geometry) -> Geometry {
+        let mut a = Node::new(to_polygons(self));
+        let mut b = Node::new(to_polygons(other));
+        a.clip_to(&b);
+        b.clip_to(&a);
+        b.invert();
+        b.clip_to(&a);
+        b.invert();
+        a.build(b.all_polygons());
+        return from_polygons(a.all_polygons());
+    }
+
+    /// Solid containing the space of `self` that is not inside `other`.
+    pub fn subtract(&self, other: &Geometry) -> Geometry {
+        let mut a = Node::new(to_polygons(self));
+        let mut b = Node::new(to_polygons(other));
+        a.invert();
+        a.clip_to(&b);
+        b.clip_to(&a);
+        b.invert();
+        b.clip_to(&a);
+        b.invert();
+        a.build(b.all_polygons());
+        a.invert();
+        return from_polygons(a.all_polygons());
+    }
+
+    /// Solid containing only the space shared by `self` and `other`.
+    pub fn intersect(&self, other: &Geometry) -> Geometry {
+        let mut a = Node::new(to_polygons(self));
+        let mut b = Node::new(to_polygons(other));
+        a.invert();
+        b.clip_to(&a);
+        b.invert();
+        a.clip_to(&b);
+        b.clip_to(&a);
+        a.build(b.all_polygons());
+        a.invert();
+        return from_polygons(a.all_polygons());
+    }
+}
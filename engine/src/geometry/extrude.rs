@@ -0,0 +1,119 @@
+use cgmath::{InnerSpace, Quaternion, Rad, Rotation, Rotation3, Vector2, Vector3};
+
+use super::Geometry;
+use crate::{math::Spline, resources::ModelVertex};
+
+// Rings placed along each segment of the spline
+const SAMPLES_PER_SEGMENT: usize = 16;
+
+// Up vector perpendicular to `tangent`, as close to world up as it can be
+fn initial_up(tangent: Vector3<f32>) -> Vector3<f32> {
+    let reference = if tangent.y.abs() > 0.99 { Vector3::unit_x() } else { Vector3::unit_y() };
+    return (reference - tangent * reference.dot(tangent)).normalize();
+}
+
+// Tangent at `t`, falling back to the previous one where the curve stops
+fn tangent(spline: &impl Spline, t: f32, previous: Vector3<f32>) -> Vector3<f32> {
+    let derivative = spline.derivative(t);
+    if derivative.magnitude2() < 1e-12 {
+        return previous;
+    }
+    return derivative.normalize();
+}
+
+impl Geometry {
+    /// Sweep a 2D cross-section along a spline into a road, ribbon or pipe.
+    /// Profile x points to the right of the direction of travel and y up;
+    /// faces point to the left of the order the profile is drawn in, so draw
+    /// a road from left to right and a pipe clockwise, repeating the first
+    /// point to close it. Frames are carried along by parallel transport so
+    /// the cross-section doesn't flip, with the twist evened out over curves
+    /// that end where they start. U runs across the profile from 0 to 1, v
+    /// along the curve by arc length in units of the profile's length.
+    pub fn extrude_along_spline(profile: &[Vector2<f32>], spline: &impl Spline) -> Self {
+        let segments = spline.segment_count();
+        if profile.len() < 2 || segments == 0 {
+            return Geometry::default();
+        }
+        let samples = segments * SAMPLES_PER_SEGMENT + 1;
+
+        // Position, tangent and up of each ring
+        let mut frames = Vec::with_capacity(samples);
+        let mut previous = tangent(spline, 0.0, Vector3::unit_z());
+        let mut up = initial_up(previous);
+        for i in 0..samples {
+            let t = i as f32 / (samples - 1) as f32;
+            let forward = tangent(spline, t, previous);
+            up = Quaternion::from_arc(previous, forward, None).rotate_vector(up);
+            up = (up - forward * up.dot(forward)).normalize();
+            frames.push((spline.point(t), forward, up));
+            previous = forward;
+        }
+
+        let (start, start_forward, start_up) = frames[0];
+        let (end, end_forward, end_up) = frames[samples - 1];
+        if (end - start).magnitude2() < 1e-8 && end_forward.dot(start_forward) > 0.999 {
+            let twist = start_forward.dot(end_up.cross(start_up)).atan2(end_up.dot(start_up));
+            for (i, (_, forward, up)) in frames.iter_mut().enumerate() {
+                let angle = twist * i as f32 / (samples - 1) as f32;
+                *up = Quaternion::from_axis_angle(*forward, Rad(angle)).rotate_vector(*up);
+            }
+        }
+
+        // Distance across the profile at each point, and its normal in 2D
+        let mut across = vec![0.0; profile.len()];
+        for j in 1..profile.len() {
+            across[j] = across[j - 1] + (profile[j] - profile[j - 1]).magnitude();
+        }
+        let width = across[profile.len() - 1].max(1e-6);
+        let normals: Vec<Vector2<f32>> = (0..profile.len())
+            .map(|j| {
+                let before = profile[j.saturating_sub(1)];
+                let after = profile[(j + 1).min(profile.len() - 1)];
+                let mut direction = after - before;
+                let closed = (profile[0] - profile[profile.len() - 1]).magnitude2() < 1e-10;
+                if closed && (j == 0 || j == profile.len() - 1) {
+                    direction = profile[1] - profile[profile.len() - 2];
+                }
+                let normal = Vector2::new(-direction.y, direction.x);
+                if normal.magnitude2() < 1e-12 {
+                    return Vector2::unit_y();
+                }
+                normal.normalize()
+            })
+            .collect();
+
+        let mut geometry = Geometry::default();
+        let mut length = 0.0;
+        for (i, &(position, forward, up)) in frames.iter().enumerate() {
+            if i > 0 {
+                length += (position - frames[i - 1].0).magnitude();
+            }
+            let side = forward.cross(up);
+            for (j, point) in profile.iter().enumerate() {
+                let p = position + side * point.x + up * point.y;
+                let n = side * normals[j].x + up * normals[j].y;
+                geometry.vertices.push(ModelVertex {
+                    position: p.into(),
+                    tex_coords: [across[j] / width, length / width],
+                    normal: n.into(),
+                    tangent: [0.0; 3],
+                    bitangent: [0.0; 3],
+                    #[cfg(feature = "secondary-uv")]
+                    tex_coords1: [0.0; 2],
+                });
+            }
+        }
+
+        let row = profile.len() as u32;
+        for i in 0..samples as u32 - 1 {
+            for j in 0..row - 1 {
+                let a = i * row + j;
+                let c = a + row;
+                geometry.indices.extend_from_slice(&[a, a + 1, c, a + 1, c + 1, c]);
+            }
+        }
+        geometry.calculate_tangents_bitangents();
+        return geometry;
+    }
+}
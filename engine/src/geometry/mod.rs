@@ -0,0 +1,283 @@
+mod bake;
+mod bvh;
+mod convention;
+mod csg;
+mod extrude;
+mod simplify;
+mod weld;
+mod unwrap;
+mod uv;
+
+pub use bake::{bake_normal_map, NormalBakeSettings};
+pub(crate) use bvh::Bvh;
+pub use convention::{CoordinateSystem, Handedness, UpAxis};
+pub use simplify::simplify;
+pub use weld::DEFAULT_SMOOTHING_ANGLE;
+pub use crate::math::{Aabb, BezierSpline, CatmullRom, Frustum, Plane, Spline};
+
+use cgmath::{InnerSpace, Vector3};
+
+use crate::{
+    memory::{MemoryCategory, MemoryTracker},
+    model::{Mesh, Submesh},
+    resources::ModelVertex,
+};
+
+/// CPU-side triangle mesh that can be generated, edited and finally uploaded as a `Mesh`.
+#[derive(Debug, Clone, Default)]
+pub struct Geometry {
+    pub vertices: Vec<ModelVertex>,
+    pub indices: Vec<u32>,
+}
+
+impl Geometry {
+    pub fn new(vertices: Vec<ModelVertex>, indices: Vec<u32>) -> Self {
+        return Self { vertices, indices };
+    }
+
+    /// Axis aligned box centered at the origin, with per-face vertices so every face
+    /// gets its own normal and full 0..1 texture coordinates.
+    pub fn cuboid(width: f32, height: f32, depth: f32) -> Self {
+        let (hx, hy, hz) = (width / 2.0, height / 2.0, depth / 2.0);
+        // (normal, u axis, v axis)
+        let faces: [([f32; 3], [f32; 3], [f32; 3]); 6] = [
+            ([1.0, 0.0, 0.0], [0.0, 0.0, -1.0], [0.0, -1.0, 0.0]),
+            ([-1.0, 0.0, 0.0], [0.0, 0.0, 1.0], [0.0, -1.0, 0.0]),
+            ([0.0, 1.0, 0.0], [1.0, 0.0, 0.0], [0.0, 0.0, 1.0]),
+            ([0.0, -1.0, 0.0], [1.0, 0.0, 0.0], [0.0, 0.0, -1.0]),
+            ([0.0, 0.0, 1.0], [1.0, 0.0, 0.0], [0.0, -1.0, 0.0]),
+            ([0.0, 0.0, -1.0], [-1.0, 0.0, 0.0], [0.0, -1.0, 0.0]),
+        ];
+
+        let half = Vector3::new(hx, hy, hz);
+        let mut geometry = Geometry::default();
+        for (normal, u, v) in faces {
+            let n = Vector3::from(normal);
+            let u = Vector3::from(u);
+            let v = Vector3::from(v);
+            let base = geometry.vertices.len() as u32;
+            for (su, sv) in [(-1.0, -1.0), (1.0, -1.0), (1.0, 1.0), (-1.0, 1.0)] {
+                let p = n + u * su + v * sv;
+                let position = [p.x * half.x, p.y * half.y, p.z * half.z];
+                geometry.vertices.push(ModelVertex {
+                    position,
+                    tex_coords: [(su + 1.0) / 2.0, (sv + 1.0) / 2.0],
+                    normal,
+                    tangent: [0.0; 3],
+                    bitangent: [0.0; 3],
+                    #[cfg(feature = "secondary-uv")]
+                    tex_coords1: [0.0; 2],
+                });
+            }
+            geometry
+                .indices
+                .extend_from_slice(&[base, base + 2, base + 1, base, base + 3, base + 2]);
+        }
+        geometry.calculate_tangents_bitangents();
+        return geometry;
+    }
+
+    /// UV sphere centered at the origin with `sectors` slices around the Y axis
+    /// and `stacks` slices from pole to pole.
+    pub fn uv_sphere(radius: f32, sectors: u32, stacks: u32) -> Self {
+        use std::f32::consts::PI;
+
+        let (sectors, stacks) = (sectors.max(3), stacks.max(2));
+        let mut geometry = Geometry::default();
+        for stack in 0..=stacks {
+            let v = stack as f32 / stacks as f32;
+            let phi = v * PI;
+            for sector in 0..=sectors {
+                let u = sector as f32 / sectors as f32;
+                let theta = u * 2.0 * PI;
+                let normal = [phi.sin() * theta.cos(), phi.cos(), -phi.sin() * theta.sin()];
+                geometry.vertices.push(ModelVertex {
+                    position: normal.map(|n| n * radius),
+                    tex_coords: [u, v],
+                    normal,
+                    tangent: [0.0; 3],
+                    bitangent: [0.0; 3],
+                    #[cfg(feature = "secondary-uv")]
+                    tex_coords1: [0.0; 2],
+                });
+            }
+        }
+
+        let row = sectors + 1;
+        for stack in 0..stacks {
+            for sector in 0..sectors {
+                let a = stack * row + sector;
+                let b = a + row;
+                if stack != 0 {
+                    geometry.indices.extend_from_slice(&[a, b, a + 1]);
+                }
+                if stack != stacks - 1 {
+                    geometry.indices.extend_from_slice(&[a + 1, b, b + 1]);
+                }
+            }
+        }
+        geometry.calculate_tangents_bitangents();
+        return geometry;
+    }
+
+    pub fn triangle_count(&self) -> usize {
+        return self.indices.len() / 3;
+    }
+
+    pub fn bounds(&self) -> Aabb {
+        let mut min = Vector3::new(f32::MAX, f32::MAX, f32::MAX);
+        let mut max = Vector3::new(f32::MIN, f32::MIN, f32::MIN);
+        for v in &self.vertices {
+            let [x, y, z] = v.position;
+            min = Vector3::new(min.x.min(x), min.y.min(y), min.z.min(z));
+            max = Vector3::new(max.x.max(x), max.y.max(y), max.z.max(z));
+        }
+        if self.vertices.is_empty() {
+            return Aabb::new(Vector3::new(0.0, 0.0, 0.0), Vector3::new(0.0, 0.0, 0.0));
+        }
+        return Aabb::new(min, max);
+    }
+
+    pub fn calculate_tangents_bitangents(&mut self) {
+        let vertices = &mut self.vertices;
+        for v in vertices.iter_mut() {
+            v.tangent = [0.0; 3];
+            v.bitangent = [0.0; 3];
+        }
+        let mut triangles_included = vec![0; vertices.len()];
+
+        for c in self.indices.chunks(3) {
+            let v0 = vertices[c[0] as usize];
+            let v1 = vertices[c[1] as usize];
+            let v2 = vertices[c[2] as usize];
+
+            let pos0: cgmath::Vector3<_> = v0.position.into();
+            let pos1: cgmath::Vector3<_> = v1.position.into();
+            let pos2: cgmath::Vector3<_> = v2.position.into();
+
+            let uv0: cgmath::Vector2<_> = v0.tex_coords.into();
+            let uv1: cgmath::Vector2<_> = v1.tex_coords.into();
+            let uv2: cgmath::Vector2<_> = v2.tex_coords.into();
+
+            // Calculate the edges of the triangle
+            let delta_pos1 = pos1 - pos0;
+            let delta_pos2 = pos2 - pos0;
+
+            let delta_uv1 = uv1 - uv0;
+            let delta_uv2 = uv2 - uv0;
+
+            // Solving the following system of equations will
+            // give us the tangent and bitangent.
+            //     delta_pos1 = delta_uv1.x * T + delta_u.y * B
+            //     delta_pos2 = delta_uv2.x * T + delta_uv2.y * B
+            let r = 1.0 / (delta_uv1.x * delta_uv2.y - delta_uv1.y * delta_uv2.x);
+            let tangent = (delta_pos1 * delta_uv2.y - delta_pos2 * delta_uv1.y) * r;
+
+            // Flip the bitangent to enable right-handed normal
+            // maps with wgpu texture coordinate system
+            let bitangent = (delta_pos2 * delta_uv1.x - delta_pos1 * delta_uv2.x) * -r;
+
+            // Degenerate UVs would poison every vertex of the triangle
+            if !tangent.x.is_finite() || !bitangent.x.is_finite() {
+                continue;
+            }
+
+            // Use the same tangent/bitangent for each vertex in the triangle
+            for &i in c {
+                let v = &mut vertices[i as usize];
+                v.tangent = (tangent + Vector3::from(v.tangent)).into();
+                v.bitangent = (bitangent + Vector3::from(v.bitangent)).into();
+
+                // Used to average the tangents/bitangents
+                triangles_included[i as usize] += 1;
+            }
+        }
+
+        // Average the tangents/bitangents
+        for (i, n) in triangles_included.into_iter().enumerate() {
+            if n == 0 {
+                continue;
+            }
+            let denom = 1.0 / n as f32;
+            let v = &mut vertices[i];
+            v.tangent = (Vector3::from(v.tangent) * denom).into();
+            v.bitangent = (Vector3::from(v.bitangent) * denom).into();
+        }
+    }
+
+    /// Apply a transform to positions and the matching normal transform to normals.
+    pub fn transform(&mut self, matrix: cgmath::Matrix4<f32>) {
+        use cgmath::{Matrix, SquareMatrix};
+
+        let normal_matrix = cgmath::Matrix3::from_cols(
+            matrix.x.truncate(),
+            matrix.y.truncate(),
+            matrix.z.truncate(),
+        )
+        .invert()
+        .map(|m| m.transpose())
+        .unwrap_or_else(cgmath::Matrix3::identity);
+
+        for v in &mut self.vertices {
+            let p = matrix * Vector3::from(v.position).extend(1.0);
+            v.position = p.truncate().into();
+            v.normal = (normal_matrix * Vector3::from(v.normal))
+                .normalize()
+                .into();
+        }
+        self.calculate_tangents_bitangents();
+    }
+
+    pub fn to_mesh(
+        &self,
+        device: &wgpu::Device,
+        memory: &MemoryTracker,
+        name: &str,
+        material: usize,
+    ) -> Mesh {
+        return self.to_mesh_with_usage(device, memory, name, material, wgpu::BufferUsages::empty());
+    }
+
+    // `vertex_usage` is added to the vertex buffer's usages, e.g. for compute passes
+    pub(crate) fn to_mesh_with_usage(
+        &self,
+        device: &wgpu::Device,
+        memory: &MemoryTracker,
+        name: &str,
+        material: usize,
+        vertex_usage: wgpu::BufferUsages,
+    ) -> Mesh {
+        let vertex_buffer = memory.create_buffer_init(
+            device,
+            &wgpu::util::BufferInitDescriptor {
+                label: Some(&format!("{:?} Vertex Buffer", name)),
+                contents: bytemuck::cast_slice(&self.vertices),
+                // Copied out by `Scene::export_gltf`
+                usage: wgpu::BufferUsages::VERTEX | wgpu::BufferUsages::COPY_SRC | vertex_usage,
+            },
+            MemoryCategory::Mesh,
+        );
+        let index_buffer = memory.create_buffer_init(
+            device,
+            &wgpu::util::BufferInitDescriptor {
+                label: Some(&format!("{:?} Index Buffer", name)),
+                contents: bytemuck::cast_slice(&self.indices),
+                usage: wgpu::BufferUsages::INDEX | wgpu::BufferUsages::COPY_SRC,
+            },
+            MemoryCategory::Mesh,
+        );
+
+        return Mesh {
+            name: name.to_string(),
+            vertex_buffer,
+            index_buffer,
+            num_elements: self.indices.len() as u32,
+            submeshes: vec![Submesh {
+                indices: 0..self.indices.len() as u32,
+                material,
+            }],
+            bounds: self.bounds(),
+            lightmap_uvs: None,
+        };
+    }
+}
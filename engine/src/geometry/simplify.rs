@@ -0,0 +1,293 @@
+//! Edge collapse simplification driven by quadric error metrics (Garland and
+//! Heckbert). Vertices on UV seams, normal seams and open borders are never
+//! moved, so texture layout and silhouettes of open meshes are kept intact.
+use std::{
+    cmp::Ordering,
+    collections::{BinaryHeap, HashMap},
+};
+
+use cgmath::{InnerSpace, Vector3};
+
+use super::Geometry;
+
+// Collapses that rotate a face normal further than this (cosine) are rejected
+const MIN_NORMAL_DOT: f64 = 0.2;
+
+#[derive(Debug, Copy, Clone, Default)]
+struct Quadric([f64; 10]);
+
+impl Quadric {
+    fn from_plane(n: Vector3<f64>, d: f64, weight: f64) -> Self {
+        let (a, b, c) = (n.x, n.y, n.z);
+        return Quadric([
+            a * a, a * b, a * c, a * d,
+            b * b, b * c, b * d,
+            c * c, c * d,
+            d * d,
+        ].map(|v| v * weight));
+    }
+
+    fn add(&mut self, other: &Quadric) {
+        for (a, b) in self.0.iter_mut().zip(other.0) {
+            *a += b;
+        }
+    }
+
+    fn error(&self, p: Vector3<f64>) -> f64 {
+        let q = &self.0;
+        let (x, y, z) = (p.x, p.y, p.z);
+        return q[0] * x * x + 2.0 * q[1] * x * y + 2.0 * q[2] * x * z + 2.0 * q[3] * x
+            + q[4] * y * y + 2.0 * q[5] * y * z + 2.0 * q[6] * y
+            + q[7] * z * z + 2.0 * q[8] * z
+            + q[9];
+    }
+}
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+struct Collapse {
+    cost: f64,
+    from: u32,
+    to: u32,
+}
+
+impl Eq for Collapse {}
+
+impl Ord for Collapse {
+    // Reversed so that the heap pops the cheapest collapse first
+    fn cmp(&self, other: &Self) -> Ordering {
+        return other.cost.total_cmp(&self.cost);
+    }
+}
+
+impl PartialOrd for Collapse {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        return Some(self.cmp(other));
+    }
+}
+
+struct Simplifier {
+    positions: Vec<Vector3<f64>>,
+    triangles: Vec<[u32; 3]>,
+    removed: Vec<bool>,
+    vertex_triangles: Vec<Vec<usize>>,
+    quadrics: Vec<Quadric>,
+    // Only interior vertices without attribute seams may move
+    movable: Vec<bool>,
+    collapsed: Vec<bool>,
+}
+
+impl Simplifier {
+    fn new(geometry: &Geometry) -> Self {
+        let positions: Vec<Vector3<f64>> = geometry
+            .vertices
+            .iter()
+            .map(|v| Vector3::from(v.position).cast().unwrap())
+            .collect();
+        let triangles: Vec<[u32; 3]> = geometry
+            .indices
+            .chunks_exact(3)
+            .map(|c| [c[0], c[1], c[2]])
+            .collect();
+
+        // Vertices sharing a position but not attributes form seams
+        let mut groups: HashMap<[u32; 3], u32> = HashMap::new();
+        let group: Vec<u32> = positions
+            .iter()
+            .map(|p| {
+                let key = [p.x as f32, p.y as f32, p.z as f32].map(f32::to_bits);
+                let next = groups.len() as u32;
+                *groups.entry(key).or_insert(next)
+            })
+            .collect();
+        let mut group_sizes = vec![0; groups.len()];
+        for &g in &group {
+            group_sizes[g as usize] += 1;
+        }
+
+        // Edges used by a single triangle are borders
+        let mut edge_uses: HashMap<(u32, u32), u32> = HashMap::new();
+        for t in &triangles {
+            for i in 0..3 {
+                let (a, b) = (group[t[i] as usize], group[t[(i + 1) % 3] as usize]);
+                *edge_uses.entry((a.min(b), a.max(b))).or_default() += 1;
+            }
+        }
+        let mut border_groups = vec![false; groups.len()];
+        for ((a, b), uses) in edge_uses {
+            if uses == 1 {
+                border_groups[a as usize] = true;
+                border_groups[b as usize] = true;
+            }
+        }
+
+        let mut group_quadrics = vec![Quadric::default(); groups.len()];
+        let mut vertex_triangles = vec![Vec::new(); positions.len()];
+        for (i, t) in triangles.iter().enumerate() {
+            let [a, b, c] = t.map(|v| positions[v as usize]);
+            let cross = (b - a).cross(c - a);
+            let area = cross.magnitude();
+            if area > 0.0 {
+                let n = cross / area;
+                let quadric = Quadric::from_plane(n, -n.dot(a), area);
+                for &v in t {
+                    group_quadrics[group[v as usize] as usize].add(&quadric);
+                }
+            }
+            for &v in t {
+                vertex_triangles[v as usize].push(i);
+            }
+        }
+
+        let movable = group
+            .iter()
+            .map(|&g| group_sizes[g as usize] == 1 && !border_groups[g as usize])
+            .collect();
+
+        return Self {
+            quadrics: group.iter().map(|&g| group_quadrics[g as usize]).collect(),
+            collapsed: vec![false; positions.len()],
+            removed: vec![false; triangles.len()],
+            positions,
+            triangles,
+            vertex_triangles,
+            movable,
+        };
+    }
+
+    fn cost(&self, from: u32, to: u32) -> f64 {
+        let mut quadric = self.quadrics[from as usize];
+        quadric.add(&self.quadrics[to as usize]);
+        return quadric.error(self.positions[to as usize]);
+    }
+
+    fn neighbors(&self, v: u32) -> Vec<u32> {
+        let mut neighbors: Vec<u32> = self.vertex_triangles[v as usize]
+            .iter()
+            .filter(|&&t| !self.removed[t])
+            .flat_map(|&t| self.triangles[t])
+            .filter(|&n| n != v)
+            .collect();
+        neighbors.sort_unstable();
+        neighbors.dedup();
+        return neighbors;
+    }
+
+    fn push_candidates(&self, v: u32, heap: &mut BinaryHeap<Collapse>) {
+        for n in self.neighbors(v) {
+            if self.movable[v as usize] {
+                heap.push(Collapse { cost: self.cost(v, n), from: v, to: n });
+            }
+            if self.movable[n as usize] {
+                heap.push(Collapse { cost: self.cost(n, v), from: n, to: v });
+            }
+        }
+    }
+
+    /// Moving `from` onto `to` must not fold any remaining triangle over.
+    fn is_valid(&self, from: u32, to: u32) -> bool {
+        let target = self.positions[to as usize];
+        for &t in &self.vertex_triangles[from as usize] {
+            let triangle = self.triangles[t];
+            if self.removed[t] || triangle.contains(&to) {
+                continue;
+            }
+            let before = triangle.map(|v| self.positions[v as usize]);
+            let after = triangle.map(|v| if v == from { target } else { self.positions[v as usize] });
+            let n0 = (before[1] - before[0]).cross(before[2] - before[0]);
+            let n1 = (after[1] - after[0]).cross(after[2] - after[0]);
+            let (m0, m1) = (n0.magnitude(), n1.magnitude());
+            if m1 <= f64::EPSILON || (m0 > f64::EPSILON && n0.dot(n1) / (m0 * m1) < MIN_NORMAL_DOT) {
+                return false;
+            }
+        }
+        return true;
+    }
+
+    /// Returns the number of triangles removed.
+    fn collapse(&mut self, from: u32, to: u32) -> usize {
+        let mut removed = 0;
+        for t in std::mem::take(&mut self.vertex_triangles[from as usize]) {
+            if self.removed[t] {
+                continue;
+            }
+            if self.triangles[t].contains(&to) {
+                self.removed[t] = true;
+                removed += 1;
+                continue;
+            }
+            for v in &mut self.triangles[t] {
+                if *v == from {
+                    *v = to;
+                }
+            }
+            self.vertex_triangles[to as usize].push(t);
+        }
+
+        let quadric = self.quadrics[from as usize];
+        self.quadrics[to as usize].add(&quadric);
+        self.collapsed[from as usize] = true;
+        return removed;
+    }
+}
+
+/// Reduce `geometry` to about `target_ratio` of its triangles. The result may
+/// keep more triangles when the remaining collapses would damage seams,
+/// borders or flip faces.
+pub fn simplify(geometry: &Geometry, target_ratio: f32) -> Geometry {
+    let mut simplifier = Simplifier::new(geometry);
+    let mut triangle_count = simplifier.triangles.len();
+    let target = (triangle_count as f32 * target_ratio.clamp(0.0, 1.0)).ceil() as usize;
+
+    let mut heap = BinaryHeap::new();
+    for v in 0..simplifier.positions.len() as u32 {
+        if simplifier.movable[v as usize] {
+            for n in simplifier.neighbors(v) {
+                heap.push(Collapse { cost: simplifier.cost(v, n), from: v, to: n });
+            }
+        }
+    }
+
+    while triangle_count > target {
+        let candidate = match heap.pop() {
+            Some(candidate) => candidate,
+            None => break,
+        };
+        let Collapse { cost, from, to } = candidate;
+        if simplifier.collapsed[from as usize] || simplifier.collapsed[to as usize] {
+            continue;
+        }
+        if !simplifier.neighbors(from).contains(&to) {
+            continue;
+        }
+        // Quadrics grow as neighbors collapse; requeue entries that became more expensive
+        let current = simplifier.cost(from, to);
+        if current > cost + 1e-12 {
+            heap.push(Collapse { cost: current, from, to });
+            continue;
+        }
+        if !simplifier.is_valid(from, to) {
+            continue;
+        }
+
+        triangle_count -= simplifier.collapse(from, to);
+        simplifier.push_candidates(to, &mut heap);
+    }
+
+    // Compact the vertices still referenced by live triangles
+    let mut remap = vec![u32::MAX; geometry.vertices.len()];
+    let mut result = Geometry::default();
+    for (t, triangle) in simplifier.triangles.iter().enumerate() {
+        if simplifier.removed[t] {
+            continue;
+        }
+        for &v in triangle {
+            if remap[v as usize] == u32::MAX {
+                remap[v as usize] = result.vertices.len() as u32;
+                result.vertices.push(geometry.vertices[v as usize]);
+            }
+            result.indices.push(remap[v as usize]);
+        }
+    }
+    result.calculate_tangents_bitangents();
+    return result;
+}
@@ -0,0 +1,165 @@
+use std::collections::HashMap;
+
+use cgmath::{InnerSpace, Vector3};
+
+use super::{
+    uv::{box_side, BOX_FACES},
+    Geometry,
+};
+
+// Triangles projected onto one side of a box, connected through shared vertices
+struct Chart {
+    side: usize,
+    triangles: Vec<usize>,
+    // Projected bounds in world units
+    min: [f32; 2],
+    max: [f32; 2],
+}
+
+impl Chart {
+    fn size(&self, scale: f32) -> [u32; 2] {
+        return [0, 1].map(|i| ((self.max[i] - self.min[i]) * scale).ceil() as u32 + 1);
+    }
+}
+
+// Disjoint sets of triangles
+fn find(parents: &mut [usize], mut i: usize) -> usize {
+    while parents[i] != i {
+        parents[i] = parents[parents[i]];
+        i = parents[i];
+    }
+    return i;
+}
+
+impl Geometry {
+    /// Whether any vertex has secondary texture coordinates, e.g. authored
+    /// lightmap coordinates from an importer. Always false without the
+    /// `secondary-uv` feature.
+    pub fn has_secondary_uvs(&self) -> bool {
+        return self.vertices.iter().any(|v| v.secondary_uv() != [0.0; 2]);
+    }
+
+    /// Unique texture coordinates for a `resolution` square lightmap, one per
+    /// vertex. Triangles are grouped into charts facing the same side of a box
+    /// and packed in rows with `padding` texels around each, keeping texel
+    /// density uniform across the geometry. Vertices shared by charts are
+    /// split, triangles keep their order.
+    pub fn lightmap_uvs(&mut self, resolution: u32, padding: u32) -> Vec<[f32; 2]> {
+        let triangle_count = self.indices.len() / 3;
+        let position = |index: u32| Vector3::from(self.vertices[index as usize].position);
+        let sides = (0..triangle_count)
+            .map(|t| {
+                let [a, b, c] = [0, 1, 2].map(|k| position(self.indices[t * 3 + k]));
+                return box_side((b - a).cross(c - a));
+            })
+            .collect::<Vec<_>>();
+
+        // Join triangles on the same side sharing a vertex
+        let mut parents = (0..triangle_count).collect::<Vec<_>>();
+        let mut first_user: HashMap<(u32, usize), usize> = HashMap::new();
+        for (t, &side) in sides.iter().enumerate() {
+            for k in 0..3 {
+                let key = (self.indices[t * 3 + k], side);
+                let other = *first_user.entry(key).or_insert(t);
+                let (a, b) = (find(&mut parents, t), find(&mut parents, other));
+                parents[a] = b;
+            }
+        }
+        let mut chart_of_root: HashMap<usize, usize> = HashMap::new();
+        let mut charts: Vec<Chart> = Vec::new();
+        for (t, &side) in sides.iter().enumerate() {
+            let root = find(&mut parents, t);
+            let chart = *chart_of_root.entry(root).or_insert_with(|| {
+                charts.push(Chart {
+                    side,
+                    triangles: Vec::new(),
+                    min: [f32::MAX; 2],
+                    max: [f32::MIN; 2],
+                });
+                return charts.len() - 1;
+            });
+            charts[chart].triangles.push(t);
+        }
+
+        let project = |side: usize, p: Vector3<f32>| {
+            let (_, u, v) = BOX_FACES[side];
+            return [p.dot(u.into()), p.dot(v.into())];
+        };
+        for chart in &mut charts {
+            for &t in &chart.triangles {
+                for k in 0..3 {
+                    let uv = project(chart.side, position(self.indices[t * 3 + k]));
+                    for (i, value) in uv.into_iter().enumerate() {
+                        chart.min[i] = chart.min[i].min(value);
+                        chart.max[i] = chart.max[i].max(value);
+                    }
+                }
+            }
+        }
+
+        // Texels per world unit, from a guess covering most of the map down
+        // until every chart fits
+        let area = charts
+            .iter()
+            .map(|c| (c.max[0] - c.min[0]) * (c.max[1] - c.min[1]))
+            .sum::<f32>()
+            .max(f32::EPSILON);
+        let mut scale = (resolution as f32 * resolution as f32 * 0.5 / area).sqrt();
+        let mut order = (0..charts.len()).collect::<Vec<_>>();
+        order.sort_by_key(|&c| std::cmp::Reverse(charts[c].size(scale)[1]));
+        let offsets = loop {
+            if let Some(offsets) = Self::pack_charts(&charts, &order, scale, resolution, padding) {
+                break offsets;
+            }
+            scale *= 0.9;
+            if scale * area.sqrt() < 1.0 {
+                log::warn!("Lightmap of {} texels too small for {} charts", resolution, charts.len());
+                break vec![[padding; 2]; charts.len()];
+            }
+        };
+
+        let mut split: HashMap<(u32, usize), u32> = HashMap::new();
+        let mut vertices = Vec::with_capacity(self.vertices.len());
+        let mut uvs = Vec::with_capacity(self.vertices.len());
+        let mut indices = self.indices.clone();
+        for (c, chart) in charts.iter().enumerate() {
+            for &t in &chart.triangles {
+                for k in 0..3 {
+                    let index = self.indices[t * 3 + k];
+                    indices[t * 3 + k] = *split.entry((index, c)).or_insert_with(|| {
+                        let uv = project(chart.side, position(index));
+                        let texel = [0, 1].map(|i| offsets[c][i] as f32 + (uv[i] - chart.min[i]) * scale + 0.5);
+                        vertices.push(self.vertices[index as usize]);
+                        uvs.push(texel.map(|x| x / resolution as f32));
+                        return (vertices.len() - 1) as u32;
+                    });
+                }
+            }
+        }
+        self.vertices = vertices;
+        self.indices = indices;
+        return uvs;
+    }
+
+    // Top left texel of each chart packed in rows of `order`, None if they
+    // don't fit at `scale`
+    fn pack_charts(charts: &[Chart], order: &[usize], scale: f32, resolution: u32, padding: u32) -> Option<Vec<[u32; 2]>> {
+        let mut offsets = vec![[0; 2]; charts.len()];
+        let (mut x, mut y, mut row_height) = (padding, padding, 0);
+        for &c in order {
+            let [width, height] = charts[c].size(scale);
+            if x + width + padding > resolution {
+                x = padding;
+                y += row_height + padding;
+                row_height = 0;
+            }
+            if x + width + padding > resolution || y + height + padding > resolution {
+                return None;
+            }
+            offsets[c] = [x, y];
+            x += width + padding;
+            row_height = row_height.max(height);
+        }
+        return Some(offsets);
+    }
+}
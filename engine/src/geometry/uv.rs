@@ -0,0 +1,168 @@
+use std::collections::HashMap;
+
+use cgmath::{InnerSpace, Vector3};
+
+use super::Geometry;
+
+// (normal, u axis, v axis) of the six box projections, matching `cuboid`
+pub(super) const BOX_FACES: [([f32; 3], [f32; 3], [f32; 3]); 6] = [
+    ([1.0, 0.0, 0.0], [0.0, 0.0, -1.0], [0.0, -1.0, 0.0]),
+    ([-1.0, 0.0, 0.0], [0.0, 0.0, 1.0], [0.0, -1.0, 0.0]),
+    ([0.0, 1.0, 0.0], [1.0, 0.0, 0.0], [0.0, 0.0, 1.0]),
+    ([0.0, -1.0, 0.0], [1.0, 0.0, 0.0], [0.0, 0.0, -1.0]),
+    ([0.0, 0.0, 1.0], [1.0, 0.0, 0.0], [0.0, -1.0, 0.0]),
+    ([0.0, 0.0, -1.0], [-1.0, 0.0, 0.0], [0.0, -1.0, 0.0]),
+];
+
+// Index into `BOX_FACES` of the side a triangle with `normal` faces most
+pub(super) fn box_side(normal: Vector3<f32>) -> usize {
+    return (0..BOX_FACES.len())
+        .max_by(|&i, &j| {
+            let dot = |k: usize| normal.dot(Vector3::from(BOX_FACES[k].0));
+            dot(i).total_cmp(&dot(j))
+        })
+        .unwrap_or(0);
+}
+
+// U and v axes of a plane facing `normal`, oriented like the faces of `cuboid`
+fn plane_axes(normal: Vector3<f32>) -> (Vector3<f32>, Vector3<f32>) {
+    let normal = normal.normalize();
+    let up = if normal.y.abs() > 0.99 {
+        Vector3::unit_z()
+    } else {
+        Vector3::unit_y()
+    };
+    let u = up.cross(normal).normalize();
+    return (u, u.cross(normal));
+}
+
+impl Geometry {
+    /// Project texture coordinates onto the plane facing `normal`, repeating
+    /// every `1 / scale` units.
+    pub fn planar_uv(&mut self, normal: Vector3<f32>, scale: f32) {
+        let (u, v) = plane_axes(normal);
+        for vertex in &mut self.vertices {
+            let p = Vector3::from(vertex.position);
+            vertex.tex_coords = [p.dot(u) * scale, p.dot(v) * scale];
+        }
+        self.calculate_tangents_bitangents();
+    }
+
+    /// Project each triangle onto the side of a box its normal faces most,
+    /// repeating every `1 / scale` units. Vertices shared by triangles facing
+    /// different sides are split.
+    pub fn box_uv(&mut self, scale: f32) {
+        let mut split: HashMap<(u32, usize), u32> = HashMap::new();
+        let mut vertices = Vec::with_capacity(self.vertices.len());
+        let mut indices = Vec::with_capacity(self.indices.len());
+        for triangle in self.indices.chunks_exact(3) {
+            let [a, b, c] = [0, 1, 2].map(|i| Vector3::from(self.vertices[triangle[i] as usize].position));
+            let normal = (b - a).cross(c - a);
+            let side = box_side(normal);
+            let (_, u, v) = BOX_FACES[side];
+            for &index in triangle {
+                let new_index = *split.entry((index, side)).or_insert_with(|| {
+                    let mut vertex = self.vertices[index as usize];
+                    let p = Vector3::from(vertex.position);
+                    vertex.tex_coords = [p.dot(u.into()) * scale, p.dot(v.into()) * scale];
+                    vertices.push(vertex);
+                    (vertices.len() - 1) as u32
+                });
+                indices.push(new_index);
+            }
+        }
+        self.vertices = vertices;
+        self.indices = indices;
+        self.calculate_tangents_bitangents();
+    }
+
+    /// Wrap texture coordinates around the Y axis through the center of the
+    /// bounds once, with v repeating every `1 / scale` units of height.
+    pub fn cylindrical_uv(&mut self, scale: f32) {
+        let center = self.bounds().center();
+        for vertex in &mut self.vertices {
+            let p = Vector3::from(vertex.position) - center;
+            vertex.tex_coords = [angle_around_y(p), -p.y * scale];
+        }
+        self.fix_uv_wrap();
+        self.calculate_tangents_bitangents();
+    }
+
+    /// Latitude and longitude around the center of the bounds, u wrapping
+    /// around the Y axis and v running from the top (0) to the bottom (1).
+    pub fn spherical_uv(&mut self) {
+        use std::f32::consts::PI;
+
+        let center = self.bounds().center();
+        for vertex in &mut self.vertices {
+            let p = Vector3::from(vertex.position) - center;
+            let length = p.magnitude();
+            let v = if length > 0.0 {
+                (p.y / length).clamp(-1.0, 1.0).acos() / PI
+            } else {
+                0.5
+            };
+            vertex.tex_coords = [angle_around_y(p), v];
+        }
+        self.fix_uv_wrap();
+        self.calculate_tangents_bitangents();
+    }
+
+    // Triangles spanning the seam where u wraps from 1 back to 0 would
+    // stretch across the whole texture. Their vertices on the low side get a
+    // copy with u moved past 1. Vertices on the axis have no u of their own
+    // (NaN) and get a copy per triangle in the middle of the other two
+    fn fix_uv_wrap(&mut self) {
+        let mut wrapped: HashMap<u32, u32> = HashMap::new();
+        for t in 0..self.indices.len() / 3 {
+            let mut us = [0, 1, 2].map(|k| self.vertices[self.indices[t * 3 + k] as usize].tex_coords[0]);
+            let known = us.iter().copied().filter(|u| !u.is_nan());
+            let max = known.clone().fold(f32::MIN, f32::max);
+            let min = known.fold(f32::MAX, f32::min);
+            let wraps = max - min > 0.5;
+            for (k, u) in us.iter_mut().enumerate() {
+                let index = self.indices[t * 3 + k];
+                if !wraps || u.is_nan() || *u >= 0.5 {
+                    continue;
+                }
+                let vertices = &mut self.vertices;
+                let copy = *wrapped.entry(index).or_insert_with(|| {
+                    let mut vertex = vertices[index as usize];
+                    vertex.tex_coords[0] += 1.0;
+                    vertices.push(vertex);
+                    (vertices.len() - 1) as u32
+                });
+                self.indices[t * 3 + k] = copy;
+                *u += 1.0;
+            }
+
+            let known = us.iter().copied().filter(|u| !u.is_nan()).collect::<Vec<_>>();
+            for (k, u) in us.iter().enumerate() {
+                let index = self.indices[t * 3 + k];
+                if !u.is_nan() {
+                    continue;
+                }
+                let mut vertex = self.vertices[index as usize];
+                vertex.tex_coords[0] = if known.is_empty() {
+                    0.5
+                } else {
+                    known.iter().sum::<f32>() / known.len() as f32
+                };
+                self.vertices.push(vertex);
+                self.indices[t * 3 + k] = (self.vertices.len() - 1) as u32;
+            }
+        }
+    }
+}
+
+// 0 to 1 counterclockwise around +Y starting at +X, like `uv_sphere`. NaN on
+// the axis itself, where any angle is as good as another
+fn angle_around_y(p: Vector3<f32>) -> f32 {
+    use std::f32::consts::PI;
+
+    if p.x.hypot(p.z) <= p.y.abs() * 1e-4 {
+        return f32::NAN;
+    }
+    let angle = (-p.z).atan2(p.x) / (2.0 * PI);
+    return if angle < 0.0 { angle + 1.0 } else { angle };
+}
@@ -0,0 +1,184 @@
+use std::collections::HashMap;
+
+use cgmath::{Deg, InnerSpace, Rad, Vector2, Vector3};
+
+use super::Geometry;
+use crate::resources::ModelVertex;
+
+/// Creases sharper than this stay hard when `weld` recomputes normals.
+pub const DEFAULT_SMOOTHING_ANGLE: Deg<f32> = Deg(60.0);
+
+fn cell(p: Vector3<f32>, size: f32) -> [i64; 3] {
+    return [p.x, p.y, p.z].map(|v| (v / size).floor() as i64);
+}
+
+impl Geometry {
+    /// Merge vertices whose positions and both texture coordinates are within
+    /// `epsilon` of each other and drop triangles that collapse in the process.
+    /// UV seams stay split. Normals are then recomputed with
+    /// `smooth_normals(DEFAULT_SMOOTHING_ANGLE)`.
+    pub fn weld(&mut self, epsilon: f32) {
+        let epsilon = epsilon.max(f32::EPSILON);
+        let mut grid: HashMap<[i64; 3], Vec<u32>> = HashMap::new();
+        let mut vertices: Vec<ModelVertex> = Vec::new();
+        let mut remap = Vec::with_capacity(self.vertices.len());
+
+        for v in &self.vertices {
+            let position = Vector3::from(v.position);
+            let uv = Vector2::from(v.tex_coords);
+            let uv1 = Vector2::from(v.secondary_uv());
+            let [x, y, z] = cell(position, epsilon);
+
+            // Candidates can sit in any neighboring cell
+            let mut found = None;
+            'search: for dx in -1..=1 {
+                for dy in -1..=1 {
+                    for dz in -1..=1 {
+                        let candidates = match grid.get(&[x + dx, y + dy, z + dz]) {
+                            Some(candidates) => candidates,
+                            None => continue,
+                        };
+                        for &c in candidates {
+                            let other = &vertices[c as usize];
+                            if (Vector3::from(other.position) - position).magnitude2() <= epsilon * epsilon
+                                && (Vector2::from(other.tex_coords) - uv).magnitude2() <= epsilon * epsilon
+                                && (Vector2::from(other.secondary_uv()) - uv1).magnitude2() <= epsilon * epsilon
+                            {
+                                found = Some(c);
+                                break 'search;
+                            }
+                        }
+                    }
+                }
+            }
+
+            let index = match found {
+                Some(index) => index,
+                None => {
+                    vertices.push(*v);
+                    let index = vertices.len() as u32 - 1;
+                    grid.entry([x, y, z]).or_default().push(index);
+                    index
+                }
+            };
+            remap.push(index);
+        }
+
+        let mut indices = Vec::with_capacity(self.indices.len());
+        for c in self.indices.chunks_exact(3) {
+            let [a, b, c] = [c[0], c[1], c[2]].map(|i| remap[i as usize]);
+            if a != b && b != c && a != c {
+                indices.extend_from_slice(&[a, b, c]);
+            }
+        }
+
+        self.vertices = vertices;
+        self.indices = indices;
+        self.smooth_normals(DEFAULT_SMOOTHING_ANGLE);
+    }
+
+    /// Recompute normals by averaging the faces around each position, only
+    /// across edges where faces meet at less than `max_angle`. Sharper edges
+    /// keep separate vertices so they shade hard.
+    pub fn smooth_normals<A: Into<Rad<f32>>>(&mut self, max_angle: A) {
+        let min_dot = max_angle.into().0.cos();
+
+        // Area weighted face normals
+        let face_normals: Vec<Vector3<f32>> = self
+            .indices
+            .chunks_exact(3)
+            .map(|c| {
+                let [a, b, c] = [c[0], c[1], c[2]].map(|i| Vector3::from(self.vertices[i as usize].position));
+                (b - a).cross(c - a)
+            })
+            .collect();
+
+        // Faces touching each position, regardless of UV splits
+        let mut position_faces: HashMap<[u32; 3], Vec<usize>> = HashMap::new();
+        let key = |i: u32| self.vertices[i as usize].position.map(f32::to_bits);
+        for (face, c) in self.indices.chunks_exact(3).enumerate() {
+            for &i in c {
+                position_faces.entry(key(i)).or_default().push(face);
+            }
+        }
+
+        let mut lookup: HashMap<(u32, [u32; 3]), u32> = HashMap::new();
+        let mut vertices = Vec::new();
+        let mut indices = Vec::with_capacity(self.indices.len());
+        for (face, c) in self.indices.chunks_exact(3).enumerate() {
+            let face_normal = face_normals[face];
+            let face_dir = if face_normal.magnitude2() > 0.0 {
+                face_normal.normalize()
+            } else {
+                face_normal
+            };
+
+            for &i in c {
+                let mut normal = Vector3::new(0.0, 0.0, 0.0);
+                for &other in &position_faces[&key(i)] {
+                    let n = face_normals[other];
+                    if n.magnitude2() > 0.0 && face_dir.dot(n.normalize()) >= min_dot {
+                        normal += n;
+                    }
+                }
+                let normal: [f32; 3] = if normal.magnitude2() > 0.0 {
+                    normal.normalize().into()
+                } else {
+                    self.vertices[i as usize].normal
+                };
+
+                let index = *lookup.entry((i, normal.map(f32::to_bits))).or_insert_with(|| {
+                    let mut v = self.vertices[i as usize];
+                    v.normal = normal;
+                    vertices.push(v);
+                    vertices.len() as u32 - 1
+                });
+                indices.push(index);
+            }
+        }
+
+        self.vertices = vertices;
+        self.indices = indices;
+        self.calculate_tangents_bitangents();
+    }
+
+    /// Recompute normals from the triangles alone, for geometry whose normals
+    /// are missing or wrong, e.g. after CSG, simplification or an import.
+    /// `smooth` averages faces like `smooth_normals(DEFAULT_SMOOTHING_ANGLE)`,
+    /// otherwise every face gets its own vertices with the face normal.
+    pub fn recalculate_normals(&mut self, smooth: bool) {
+        if smooth {
+            self.smooth_normals(DEFAULT_SMOOTHING_ANGLE);
+            return;
+        }
+
+        let mut lookup: HashMap<(u32, [u32; 3]), u32> = HashMap::new();
+        let mut vertices = Vec::new();
+        let mut indices = Vec::with_capacity(self.indices.len());
+        for c in self.indices.chunks_exact(3) {
+            let [a, b, d] = [c[0], c[1], c[2]].map(|i| Vector3::from(self.vertices[i as usize].position));
+            let face_normal = (b - a).cross(d - a);
+            // Degenerate faces keep what they had. Adding zero turns -0 into
+            // 0, so faces of the same plane share vertices
+            let normal = (face_normal.magnitude2() > 0.0).then(|| {
+                let normal: [f32; 3] = face_normal.normalize().into();
+                normal.map(|n| n + 0.0)
+            });
+
+            for &i in c {
+                let normal = normal.unwrap_or(self.vertices[i as usize].normal);
+                let index = *lookup.entry((i, normal.map(f32::to_bits))).or_insert_with(|| {
+                    let mut v = self.vertices[i as usize];
+                    v.normal = normal;
+                    vertices.push(v);
+                    vertices.len() as u32 - 1
+                });
+                indices.push(index);
+            }
+        }
+
+        self.vertices = vertices;
+        self.indices = indices;
+        self.calculate_tangents_bitangents();
+    }
+}
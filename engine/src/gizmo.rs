@@ -0,0 +1,284 @@
+use std::sync::Arc;
+
+use cgmath::{prelude::*, Point3, Vector3};
+
+use crate::{
+    frame::FrameBuffers,
+    geometry::Geometry,
+    light::{LightBufferManager, LightId},
+    math::Ray,
+    memory::{MemoryCategory, MemoryTracker},
+    model::Mesh,
+    post::PostProcess,
+    pipelines::{PipelineCache, PipelineDescriptor},
+    resources::{ModelVertex, Vertex},
+    shader::ShaderPreprocessor,
+};
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum GizmoMode {
+    // Drag the light in the plane facing the camera
+    Translate,
+    // Point the light at whatever is under the cursor
+    Aim,
+}
+
+#[repr(C)]
+#[derive(Debug, Copy, Clone, Default, bytemuck::Pod, bytemuck::Zeroable)]
+struct MarkerRaw {
+    center_radius: [f32; 4],
+    color: [f32; 4],
+}
+
+impl MarkerRaw {
+    fn desc<'a>() -> wgpu::VertexBufferLayout<'a> {
+        use std::mem;
+        wgpu::VertexBufferLayout {
+            array_stride: mem::size_of::<MarkerRaw>() as wgpu::BufferAddress,
+            step_mode: wgpu::VertexStepMode::Instance,
+            attributes: &[
+                wgpu::VertexAttribute {
+                    offset: 0,
+                    shader_location: 5,
+                    format: wgpu::VertexFormat::Float32x4,
+                },
+                wgpu::VertexAttribute {
+                    offset: mem::size_of::<[f32; 4]>() as wgpu::BufferAddress,
+                    shader_location: 6,
+                    format: wgpu::VertexFormat::Float32x4,
+                },
+            ],
+        }
+    }
+}
+
+struct Drag {
+    light: LightId,
+    plane_point: Point3<f32>,
+    plane_normal: Vector3<f32>,
+    // From the grabbed point to the light's center
+    offset: Vector3<f32>,
+}
+
+/// Debug spheres for positional lights that can be clicked to select a light
+/// and dragged to move or aim it.
+pub struct LightGizmo {
+    pub enabled: bool,
+    pub mode: GizmoMode,
+    pub marker_radius: f32,
+    selected: Option<LightId>,
+    drag: Option<Drag>,
+    sphere: Mesh,
+    marker_buffers: FrameBuffers,
+    marker_count: u32,
+    pipeline: Arc<wgpu::RenderPipeline>,
+}
+
+impl LightGizmo {
+    pub fn new(
+        device: &wgpu::Device,
+        memory: &MemoryTracker,
+        pipelines: &mut PipelineCache,
+        shaders: &ShaderPreprocessor,
+        camera_bind_group_layout: &wgpu::BindGroupLayout,
+        depth_format: wgpu::TextureFormat,
+        multisample: wgpu::MultisampleState,
+    ) -> Self {
+        let sphere = Geometry::uv_sphere(1.0, 16, 8).to_mesh(device, memory, "Light Marker", 0);
+        let marker_buffers = FrameBuffers::new(
+            device,
+            memory,
+            "Light Marker Buffer",
+            wgpu::BufferUsages::VERTEX,
+            MemoryCategory::Mesh,
+            bytemuck::cast_slice(&[MarkerRaw::default()]),
+        );
+
+        let shader = shaders
+            .process("gizmo.wgsl")
+            .expect("Failed to preprocess gizmo.wgsl");
+        let pipeline = pipelines.pipeline(&PipelineDescriptor {
+            label: "Gizmo Pipeline",
+            layout: "Gizmo Pipeline Layout",
+            bind_group_layouts: &[camera_bind_group_layout],
+            shader: &shader,
+            vertex_layouts: &[ModelVertex::desc(), MarkerRaw::desc()],
+            color_format: Some(PostProcess::SCENE_FORMAT),
+            depth_format: Some(depth_format),
+            blend: wgpu::BlendState::REPLACE,
+            cull_mode: Some(wgpu::Face::Back),
+            multisample,
+            order_independent: false,
+        });
+
+        return Self {
+            enabled: false,
+            mode: GizmoMode::Translate,
+            marker_radius: 0.25,
+            selected: None,
+            drag: None,
+            sphere,
+            marker_buffers,
+            marker_count: 0,
+            pipeline,
+        };
+    }
+
+    pub fn selected(&self) -> Option<LightId> {
+        return self.selected;
+    }
+
+    pub fn select(&mut self, light: Option<LightId>) {
+        self.selected = light;
+    }
+
+    pub fn is_dragging(&self) -> bool {
+        return self.drag.is_some();
+    }
+
+    /// Closest light whose marker is hit by `ray`, with the distance along the ray.
+    pub fn pick(&self, ray: &Ray, lights: &LightBufferManager) -> Option<(LightId, f32)> {
+        let mut closest: Option<(LightId, f32)> = None;
+        for (id, light) in lights.lights() {
+            // Ray-sphere intersection, the ray direction is normalized
+            let to_center = Point3::from_vec(light.position()) - ray.origin;
+            let along = to_center.dot(ray.direction);
+            let distance2 = to_center.magnitude2() - along * along;
+            let radius2 = self.marker_radius * self.marker_radius;
+            if along < 0.0 || distance2 > radius2 {
+                continue;
+            }
+            let t = along - (radius2 - distance2).sqrt();
+            if closest.is_none_or(|(_, best)| t < best) {
+                closest = Some((id, t));
+            }
+        }
+        return closest;
+    }
+
+    /// Select the light under `ray` and start dragging it. Returns false when
+    /// no light was hit.
+    pub fn begin_drag(&mut self, ray: &Ray, lights: &LightBufferManager) -> bool {
+        let (id, t) = match self.pick(ray, lights) {
+            Some(hit) => hit,
+            None => return false,
+        };
+        let position = lights.light(id).map(|l| l.position()).unwrap_or_else(Vector3::zero);
+        let grabbed = ray.at(t);
+
+        self.selected = Some(id);
+        self.drag = Some(Drag {
+            light: id,
+            plane_point: grabbed,
+            plane_normal: -ray.direction,
+            offset: position - grabbed.to_vec(),
+        });
+        return true;
+    }
+
+    /// Continue a drag with the cursor's `ray`; `aim_target` is the scene
+    /// point under the cursor, used in `GizmoMode::Aim`. The light is written
+    /// back to the GPU immediately.
+    pub fn drag(
+        &mut self,
+        queue: &wgpu::Queue,
+        lights: &mut LightBufferManager,
+        ray: &Ray,
+        aim_target: Option<Point3<f32>>,
+    ) {
+        let drag = match &self.drag {
+            Some(drag) => drag,
+            None => return,
+        };
+
+        match self.mode {
+            GizmoMode::Translate => {
+                if let Some(t) = ray.intersect_plane(drag.plane_point, drag.plane_normal) {
+                    let position = ray.at(t).to_vec() + drag.offset;
+                    lights.edit_light(queue, drag.light, |light| light.set_position(position));
+                }
+            }
+            GizmoMode::Aim => {
+                let position = match lights.light(drag.light) {
+                    Some(light) => light.position(),
+                    None => return,
+                };
+                if let Some(target) = aim_target {
+                    let direction = target.to_vec() - position;
+                    if direction.magnitude2() > f32::EPSILON {
+                        lights.edit_light(queue, drag.light, |light| light.set_direction(direction));
+                    }
+                }
+            }
+        }
+    }
+
+    /// Returns true if a drag was in progress.
+    pub fn end_drag(&mut self) -> bool {
+        return self.drag.take().is_some();
+    }
+
+    pub fn update(
+        &mut self,
+        device: &wgpu::Device,
+        memory: &MemoryTracker,
+        queue: &wgpu::Queue,
+        lights: &LightBufferManager,
+        version: u64,
+    ) {
+        self.marker_buffers.advance();
+        if !self.enabled {
+            self.marker_count = 0;
+            return;
+        }
+
+        let mut markers = Vec::new();
+        for (id, light) in lights.lights() {
+            let [r, g, b] = light.color();
+            let max = r.max(g).max(b).max(f32::EPSILON);
+            let mut color = [r / max, g / max, b / max];
+            let mut radius = self.marker_radius;
+            if self.selected == Some(id) {
+                color = color.map(|c| c * 0.5 + 0.5);
+                radius *= 1.3;
+            }
+
+            let position = light.position();
+            markers.push(MarkerRaw {
+                center_radius: position.extend(radius).into(),
+                color: [color[0], color[1], color[2], 1.0],
+            });
+            // Small satellite showing where the light points
+            if let Some(direction) = light.direction() {
+                let tip = position + direction * self.marker_radius * 3.0;
+                markers.push(MarkerRaw {
+                    center_radius: tip.extend(radius * 0.4).into(),
+                    color: [color[0], color[1], color[2], 1.0],
+                });
+            }
+        }
+
+        self.marker_count = markers.len() as u32;
+        if !markers.is_empty() {
+            self.marker_buffers.write(
+                device,
+                memory,
+                queue,
+                bytemuck::cast_slice(&markers),
+                version,
+            );
+        }
+    }
+
+    pub fn render<'a>(&'a self, render_pass: &mut wgpu::RenderPass<'a>, camera_bind_group: &'a wgpu::BindGroup) {
+        if self.marker_count == 0 {
+            return;
+        }
+        render_pass.set_pipeline(&self.pipeline);
+        render_pass.set_bind_group(0, camera_bind_group, &[]);
+        render_pass.set_vertex_buffer(0, self.sphere.vertex_buffer.slice(..));
+        render_pass.set_vertex_buffer(1, self.marker_buffers.current().slice(..));
+        render_pass.set_index_buffer(self.sphere.index_buffer.slice(..), wgpu::IndexFormat::Uint32);
+        render_pass.draw_indexed(0..self.sphere.num_elements, 0, 0..self.marker_count);
+    }
+}
@@ -0,0 +1,36 @@
+#include "camera.wgsl"
+@group(0) @binding(0)
+var<uniform> camera: Camera;
+
+struct VertexInput {
+    @location(0) position: vec3<f32>,
+    @location(2) normal: vec3<f32>,
+};
+
+struct MarkerInput {
+    @location(5) center_radius: vec4<f32>,
+    @location(6) color: vec4<f32>,
+};
+
+struct VertexOutput {
+    @builtin(position) clip_position: vec4<f32>,
+    @location(0) color: vec3<f32>,
+};
+
+@vertex
+fn vs_main(model: VertexInput, marker: MarkerInput) -> VertexOutput {
+    let world_position = marker.center_radius.xyz + model.position * marker.center_radius.w;
+    let view_dir = normalize(camera.view_pos.xyz - world_position);
+    // Unlit apart from a rim falloff, so markers read as spheres
+    let shade = 0.6 + 0.4 * max(dot(model.normal, view_dir), 0.0);
+
+    var out: VertexOutput;
+    out.clip_position = camera.view_proj * vec4<f32>(world_position, 1.0);
+    out.color = marker.color.rgb * shade;
+    return out;
+}
+
+@fragment
+fn fs_main(input: VertexOutput) -> @location(0) vec4<f32> {
+    return vec4<f32>(input.color, 1.0);
+}
@@ -0,0 +1,312 @@
+use std::{
+    collections::HashMap,
+    fmt::Write as _,
+    path::{Path, PathBuf},
+};
+
+use anyhow::*;
+use cgmath::Matrix4;
+
+use crate::{
+    material::ParamValue,
+    memory::TrackedBuffer,
+    model::{Material, Mesh, Model},
+    resources::{self, ModelVertex},
+};
+
+/// A model drawn with a transform, one node of an exported scene.
+pub struct GltfNode<'a> {
+    pub name: String,
+    pub model: &'a Model,
+    pub transform: Matrix4<f32>,
+}
+
+// glTF constants
+const FLOAT: u32 = 5126;
+const UNSIGNED_INT: u32 = 5125;
+const ARRAY_BUFFER: u32 = 34962;
+const ELEMENT_ARRAY_BUFFER: u32 = 34963;
+
+/// Write `nodes` to a glTF 2.0 file at `path`, with the mesh data in a `.bin`
+/// file next to it. Models shared by several nodes are written once. Vertex
+/// and index buffers are read back from the GPU, and texture files materials
+/// were loaded from are copied next to the file.
+pub fn write_gltf(device: &wgpu::Device, queue: &wgpu::Queue, nodes: &[GltfNode], path: &Path) -> Result<()> {
+    let dir = path.parent().map(Path::to_path_buf).unwrap_or_default();
+    let stem = path.file_stem().and_then(|s| s.to_str()).unwrap_or("scene");
+    let bin_name = format!("{}.bin", stem);
+
+    let mut models: Vec<&Model> = Vec::new();
+    for node in nodes {
+        if !models.iter().any(|m| std::ptr::eq(*m, node.model)) {
+            models.push(node.model);
+        }
+    }
+
+    let mut writer = Writer::default();
+    // First glTF mesh and material of each model
+    let mut model_meshes = Vec::new();
+    for model in &models {
+        let material_offset = writer.materials.len();
+        for material in &model.materials {
+            writer.material(material, &dir)?;
+        }
+        model_meshes.push(writer.meshes.len());
+        for mesh in &model.meshes {
+            let vertices = read_buffer(device, queue, &mesh.vertex_buffer)?;
+            let indices = read_buffer(device, queue, &mesh.index_buffer)?;
+            writer.mesh(mesh, &vertices, &indices, material_offset, model.materials.len());
+        }
+    }
+
+    let mut roots = Vec::new();
+    for node in nodes {
+        let model = models.iter().position(|m| std::ptr::eq(*m, node.model)).unwrap();
+        let children = (0..node.model.meshes.len())
+            .map(|i| {
+                let mesh = model_meshes[model] + i;
+                writer.nodes.push(format!(
+                    "{{\"name\":{},\"mesh\":{}}}",
+                    json_string(&node.model.meshes[i].name),
+                    mesh
+                ));
+                return writer.nodes.len() - 1;
+            })
+            .collect::<Vec<_>>();
+        let matrix: &[f32; 16] = node.transform.as_ref();
+        writer.nodes.push(format!(
+            "{{\"name\":{},\"matrix\":{},\"children\":{}}}",
+            json_string(&node.name),
+            json_array(matrix.iter()),
+            json_array(children.iter())
+        ));
+        roots.push(writer.nodes.len() - 1);
+    }
+
+    let mut json = String::from("{\"asset\":{\"version\":\"2.0\",\"generator\":\"wgpu-test\"}");
+    write!(json, ",\"scene\":0,\"scenes\":[{{\"nodes\":{}}}]", json_array(roots.iter()))?;
+    write!(json, ",\"nodes\":[{}]", writer.nodes.join(","))?;
+    write!(json, ",\"meshes\":[{}]", writer.meshes.join(","))?;
+    if !writer.materials.is_empty() {
+        write!(json, ",\"materials\":[{}]", writer.materials.join(","))?;
+    }
+    if !writer.images.is_empty() {
+        let images = writer.images.iter().map(|uri| format!("{{\"uri\":{}}}", json_string(uri)));
+        let textures = (0..writer.images.len()).map(|i| format!("{{\"source\":{}}}", i));
+        write!(json, ",\"images\":[{}]", images.collect::<Vec<_>>().join(","))?;
+        write!(json, ",\"textures\":[{}]", textures.collect::<Vec<_>>().join(","))?;
+    }
+    write!(json, ",\"accessors\":[{}]", writer.accessors.join(","))?;
+    write!(json, ",\"bufferViews\":[{}]", writer.views.join(","))?;
+    write!(
+        json,
+        ",\"buffers\":[{{\"uri\":{},\"byteLength\":{}}}]}}",
+        json_string(&bin_name),
+        writer.data.len()
+    )?;
+
+    if !dir.as_os_str().is_empty() {
+        std::fs::create_dir_all(&dir).with_context(|| format!("Failed to create {:?}", dir))?;
+    }
+    let bin_path = dir.join(&bin_name);
+    std::fs::write(&bin_path, &writer.data).with_context(|| format!("Failed to write {:?}", bin_path))?;
+    return std::fs::write(path, json).with_context(|| format!("Failed to write {:?}", path));
+}
+
+// JSON of every glTF array so far and the binary buffer they point into
+#[derive(Default)]
+struct Writer {
+    nodes: Vec<String>,
+    meshes: Vec<String>,
+    materials: Vec<String>,
+    // Image file names, with a texture each
+    images: Vec<String>,
+    accessors: Vec<String>,
+    views: Vec<String>,
+    data: Vec<u8>,
+    // Texture of each copied resource
+    textures: HashMap<String, usize>,
+}
+
+impl Writer {
+    fn view(&mut self, bytes: &[u8], stride: Option<usize>, target: u32) -> usize {
+        while !self.data.len().is_multiple_of(4) {
+            self.data.push(0);
+        }
+        let stride = stride.map(|s| format!(",\"byteStride\":{}", s)).unwrap_or_default();
+        self.views.push(format!(
+            "{{\"buffer\":0,\"byteOffset\":{},\"byteLength\":{}{},\"target\":{}}}",
+            self.data.len(),
+            bytes.len(),
+            stride,
+            target
+        ));
+        self.data.extend_from_slice(bytes);
+        return self.views.len() - 1;
+    }
+
+    fn accessor(&mut self, view: usize, offset: usize, component: u32, count: usize, ty: &str, extra: &str) -> usize {
+        self.accessors.push(format!(
+            "{{\"bufferView\":{},\"byteOffset\":{},\"componentType\":{},\"count\":{},\"type\":\"{}\"{}}}",
+            view, offset, component, count, ty, extra
+        ));
+        return self.accessors.len() - 1;
+    }
+
+    fn mesh(&mut self, mesh: &Mesh, vertices: &[u8], indices: &[u8], material_offset: usize, material_count: usize) {
+        let stride = std::mem::size_of::<ModelVertex>();
+        let vertices = &vertices[..vertices.len() / stride * stride];
+        // The readback isn't aligned for `ModelVertex`
+        let parsed = vertices
+            .chunks_exact(stride)
+            .map(bytemuck::pod_read_unaligned::<ModelVertex>)
+            .collect::<Vec<_>>();
+        let mut min = [f32::MAX; 3];
+        let mut max = [f32::MIN; 3];
+        for vertex in &parsed {
+            for i in 0..3 {
+                min[i] = min[i].min(vertex.position[i]);
+                max[i] = max[i].max(vertex.position[i]);
+            }
+        }
+        // Accessors must have bounds even without vertices
+        if parsed.is_empty() {
+            min = [0.0; 3];
+            max = [0.0; 3];
+        }
+
+        let vertex_view = self.view(vertices, Some(stride), ARRAY_BUFFER);
+        let bounds = format!(",\"min\":{},\"max\":{}", json_array(min.iter()), json_array(max.iter()));
+        let position = self.accessor(vertex_view, 0, FLOAT, parsed.len(), "VEC3", &bounds);
+        let tex_coords = self.accessor(vertex_view, 12, FLOAT, parsed.len(), "VEC2", "");
+        let normal = self.accessor(vertex_view, 20, FLOAT, parsed.len(), "VEC3", "");
+        let index_view = self.view(indices, None, ELEMENT_ARRAY_BUFFER);
+
+        let mut primitives = Vec::new();
+        for submesh in &mesh.submeshes {
+            let range = &submesh.indices;
+            let accessor = self.accessor(
+                index_view,
+                range.start as usize * 4,
+                UNSIGNED_INT,
+                range.len(),
+                "SCALAR",
+                "",
+            );
+            let material = if submesh.material < material_count {
+                format!(",\"material\":{}", material_offset + submesh.material)
+            } else {
+                String::new()
+            };
+            primitives.push(format!(
+                "{{\"attributes\":{{\"POSITION\":{},\"NORMAL\":{},\"TEXCOORD_0\":{}}},\"indices\":{}{}}}",
+                position, normal, tex_coords, accessor, material
+            ));
+        }
+        self.meshes.push(format!(
+            "{{\"name\":{},\"primitives\":[{}]}}",
+            json_string(&mesh.name),
+            primitives.join(",")
+        ));
+    }
+
+    fn material(&mut self, material: &Material, dir: &Path) -> Result<()> {
+        let tint = match material.get("tint") {
+            Some(ParamValue::Vec4(tint)) => tint,
+            _ => [1.0; 4],
+        };
+        let mut pbr = format!("\"baseColorFactor\":{}", json_array(tint.iter()));
+        if let Some(texture) = self.texture(material.diffuse_source.as_deref(), dir)? {
+            write!(pbr, ",\"baseColorTexture\":{{\"index\":{}}}", texture)?;
+        }
+        let mut json = format!(
+            "{{\"name\":{},\"pbrMetallicRoughness\":{{{}}}",
+            json_string(&material.name),
+            pbr
+        );
+        if let Some(texture) = self.texture(material.normal_source.as_deref(), dir)? {
+            write!(json, ",\"normalTexture\":{{\"index\":{}}}", texture)?;
+        }
+        json.push('}');
+        self.materials.push(json);
+        return Ok(());
+    }
+
+    // Copy the resource next to the export and reference it, once per file
+    fn texture(&mut self, source: Option<&str>, dir: &Path) -> Result<Option<usize>> {
+        let source = match source {
+            Some(source) => source,
+            None => return Ok(None),
+        };
+        if let Some(texture) = self.textures.get(source) {
+            return Ok(Some(*texture));
+        }
+        let from = resources::resource_path(source);
+        let name = match from.file_name().and_then(|n| n.to_str()) {
+            Some(name) => name.to_string(),
+            None => return Ok(None),
+        };
+        let to: PathBuf = dir.join(&name);
+        if from != to {
+            if let Some(parent) = to.parent().filter(|p| !p.as_os_str().is_empty()) {
+                std::fs::create_dir_all(parent).with_context(|| format!("Failed to create {:?}", parent))?;
+            }
+            std::fs::copy(&from, &to).with_context(|| format!("Failed to copy {:?} to {:?}", from, to))?;
+        }
+        self.images.push(name);
+        self.textures.insert(source.to_string(), self.images.len() - 1);
+        return Ok(Some(self.images.len() - 1));
+    }
+}
+
+// Contents of a buffer created with `COPY_SRC`
+fn read_buffer(device: &wgpu::Device, queue: &wgpu::Queue, buffer: &TrackedBuffer) -> Result<Vec<u8>> {
+    let size = buffer.size();
+    let readback = device.create_buffer(&wgpu::BufferDescriptor {
+        label: Some("glTF Readback Buffer"),
+        size,
+        usage: wgpu::BufferUsages::COPY_DST | wgpu::BufferUsages::MAP_READ,
+        mapped_at_creation: false,
+    });
+    let mut encoder = device.create_command_encoder(&wgpu::CommandEncoderDescriptor {
+        label: Some("glTF Readback Encoder"),
+    });
+    encoder.copy_buffer_to_buffer(buffer, 0, &readback, 0, size);
+    queue.submit(Some(encoder.finish()));
+
+    let slice = readback.slice(..);
+    let (sender, receiver) = std::sync::mpsc::channel();
+    slice.map_async(wgpu::MapMode::Read, move |result| {
+        let _ = sender.send(result);
+    });
+    device.poll(wgpu::Maintain::Wait);
+    receiver
+        .recv()
+        .context("glTF readback was dropped")?
+        .context("Failed to map glTF readback")?;
+    let data = slice.get_mapped_range().to_vec();
+    readback.unmap();
+    return Ok(data);
+}
+
+pub(crate) fn json_string(s: &str) -> String {
+    let mut out = String::with_capacity(s.len() + 2);
+    out.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            c if (c as u32) < 0x20 => {
+                let _ = write!(out, "\\u{:04x}", c as u32);
+            }
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+    return out;
+}
+
+pub(crate) fn json_array<T: ToString>(values: impl Iterator<Item = T>) -> String {
+    return format!("[{}]", values.map(|v| v.to_string()).collect::<Vec<_>>().join(","));
+}
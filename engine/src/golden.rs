@@ -0,0 +1,276 @@
+use std::{path::PathBuf, time::Duration};
+
+use anyhow::*;
+use image::RgbaImage;
+
+use crate::{
+    renderer::Renderer,
+    settings::{AtmosphereSettings, BackgroundMode, BackgroundSettings, PassSettings, Settings},
+    texture::srgb_to_linear,
+};
+
+/// How far a rendered image may stray from its golden image.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct Tolerance {
+    /// Largest CIE76 color difference of a pixel that still counts as equal,
+    /// about 2.3 being the smallest difference people notice.
+    pub max_delta_e: f32,
+    /// Fraction of pixels allowed over `max_delta_e`, for edges that other
+    /// GPUs and drivers rasterize slightly differently.
+    pub max_failing_fraction: f32,
+}
+
+impl Default for Tolerance {
+    fn default() -> Self {
+        Self {
+            max_delta_e: 3.0,
+            max_failing_fraction: 0.001,
+        }
+    }
+}
+
+/// Perceptual per-pixel comparison of two images of the same size.
+#[derive(Debug, Clone)]
+pub struct ImageDiff {
+    pub max_delta_e: f32,
+    pub mean_delta_e: f32,
+    /// Pixels over the tolerance's `max_delta_e`.
+    pub failing_pixels: u32,
+    pub pixel_count: u32,
+    /// Failing pixels in red over a dimmed copy of the expected image.
+    pub image: RgbaImage,
+}
+
+impl ImageDiff {
+    pub fn new(actual: &RgbaImage, expected: &RgbaImage, tolerance: &Tolerance) -> Result<Self> {
+        ensure!(
+            actual.dimensions() == expected.dimensions(),
+            "Image is {:?}, expected {:?}",
+            actual.dimensions(),
+            expected.dimensions()
+        );
+
+        let mut image = RgbaImage::new(expected.width(), expected.height());
+        let mut max_delta_e = 0.0f32;
+        let mut sum = 0.0f64;
+        let mut failing_pixels = 0;
+        for ((a, e), out) in actual.pixels().zip(expected.pixels()).zip(image.pixels_mut()) {
+            let difference = delta_e([a[0], a[1], a[2]], [e[0], e[1], e[2]]);
+            max_delta_e = max_delta_e.max(difference);
+            sum += difference as f64;
+            *out = if difference > tolerance.max_delta_e {
+                failing_pixels += 1;
+                image::Rgba([255, 0, 0, 255])
+            } else {
+                image::Rgba([e[0] / 4, e[1] / 4, e[2] / 4, 255])
+            };
+        }
+
+        let pixel_count = expected.width() * expected.height();
+        return Ok(Self {
+            max_delta_e,
+            mean_delta_e: (sum / pixel_count.max(1) as f64) as f32,
+            failing_pixels,
+            pixel_count,
+            image,
+        });
+    }
+
+    pub fn failing_fraction(&self) -> f32 {
+        return self.failing_pixels as f32 / self.pixel_count.max(1) as f32;
+    }
+
+    pub fn passes(&self, tolerance: &Tolerance) -> bool {
+        return self.failing_fraction() <= tolerance.max_failing_fraction;
+    }
+}
+
+/// CIE76 difference of two sRGB colors, the distance between them in CIELAB.
+pub fn delta_e(a: [u8; 3], b: [u8; 3]) -> f32 {
+    let (a, b) = (srgb_to_lab(a), srgb_to_lab(b));
+    return ((a[0] - b[0]).powi(2) + (a[1] - b[1]).powi(2) + (a[2] - b[2]).powi(2)).sqrt();
+}
+
+// CIELAB of an sRGB color, D65 white point
+fn srgb_to_lab(color: [u8; 3]) -> [f32; 3] {
+    let linear = color.map(|c| srgb_to_linear(c as f32 / 255.0));
+    let [r, g, b] = linear;
+    let x = (0.4124 * r + 0.3576 * g + 0.1805 * b) / 0.95047;
+    let y = 0.2126 * r + 0.7152 * g + 0.0722 * b;
+    let z = (0.0193 * r + 0.1192 * g + 0.9505 * b) / 1.08883;
+    let f = |t: f32| {
+        if t > 0.008856 {
+            t.cbrt()
+        } else {
+            7.787 * t + 16.0 / 116.0
+        }
+    };
+    let (fx, fy, fz) = (f(x), f(y), f(z));
+    return [116.0 * fy - 16.0, 500.0 * (fx - fy), 200.0 * (fy - fz)];
+}
+
+/// Golden images stored as `<name>.png` in a directory.
+pub struct GoldenImages {
+    directory: PathBuf,
+    pub tolerance: Tolerance,
+    update: bool,
+}
+
+impl GoldenImages {
+    /// Set to rewrite the golden images from the rendered ones instead of
+    /// comparing, after intended changes to the output.
+    pub const UPDATE_VAR: &'static str = "UPDATE_GOLDEN";
+
+    pub fn new<P: Into<PathBuf>>(directory: P) -> Self {
+        return Self {
+            directory: directory.into(),
+            tolerance: Tolerance::default(),
+            update: std::env::var_os(Self::UPDATE_VAR).is_some(),
+        };
+    }
+
+    /// Compare `image` with the golden image `name`, failing when there is
+    /// none. While `UPDATE_VAR` is set the golden images are recorded instead.
+    /// On a mismatch the image and the diff are written next to the golden
+    /// image as `<name>.actual.png` and `<name>.diff.png`.
+    pub fn check(&self, name: &str, image: &RgbaImage) -> Result<()> {
+        let path = self.directory.join(format!("{}.png", name));
+        if self.update {
+            std::fs::create_dir_all(&self.directory)
+                .with_context(|| format!("Failed to create {:?}", self.directory))?;
+            image
+                .save(&path)
+                .with_context(|| format!("Failed to write {:?}", path))?;
+            log::warn!("Recorded golden image {:?}", path);
+            return Ok(());
+        }
+        if !path.exists() {
+            let actual_path = self.directory.join(format!("{}.actual.png", name));
+            std::fs::create_dir_all(&self.directory)
+                .with_context(|| format!("Failed to create {:?}", self.directory))?;
+            image.save(&actual_path)?;
+            bail!(
+                "No golden image {:?}, rendered {:?}. Set {} to record it",
+                path,
+                actual_path,
+                Self::UPDATE_VAR
+            );
+        }
+
+        let expected = image::open(&path)
+            .with_context(|| format!("Failed to read {:?}", path))?
+            .to_rgba8();
+        let diff = ImageDiff::new(image, &expected, &self.tolerance).with_context(|| format!("Golden image {:?}", name))?;
+        if diff.passes(&self.tolerance) {
+            return Ok(());
+        }
+
+        let actual_path = self.directory.join(format!("{}.actual.png", name));
+        let diff_path = self.directory.join(format!("{}.diff.png", name));
+        image.save(&actual_path)?;
+        diff.image.save(&diff_path)?;
+        bail!(
+            "{:?} differs from its golden image: {} of {} pixels over a difference of {} ({:.3}%, {:.3}% allowed), \
+             largest {:.2}, mean {:.3}. See {:?} and {:?}, set {} to accept",
+            name,
+            diff.failing_pixels,
+            diff.pixel_count,
+            self.tolerance.max_delta_e,
+            diff.failing_fraction() * 100.0,
+            self.tolerance.max_failing_fraction * 100.0,
+            diff.max_delta_e,
+            diff.mean_delta_e,
+            actual_path,
+            diff_path,
+            Self::UPDATE_VAR
+        );
+    }
+}
+
+/// Predefined golden test scene: the renderer's startup scene drawn with
+/// `settings` after `frames` fixed steps.
+#[derive(Debug, Clone)]
+pub struct GoldenScene {
+    pub name: &'static str,
+    pub settings: Settings,
+    pub frames: u32,
+}
+
+impl GoldenScene {
+    pub const WIDTH: u32 = 320;
+    pub const HEIGHT: u32 = 240;
+    pub const STEP: Duration = Duration::from_micros(16_667);
+
+    /// Scenes covering lighting and shadows, backgrounds and fog, without the
+    /// overlay whose HUD shows timings.
+    pub fn predefined() -> Vec<Self> {
+        let base = Settings {
+            passes: PassSettings {
+                overlay: false,
+                ..PassSettings::default()
+            },
+            ..Settings::default()
+        };
+        return vec![
+            Self {
+                name: "lit",
+                settings: base.clone(),
+                frames: 1,
+            },
+            Self {
+                name: "unshadowed",
+                settings: Settings {
+                    passes: PassSettings {
+                        shadows: false,
+                        ..base.passes
+                    },
+                    ..base.clone()
+                },
+                frames: 1,
+            },
+            Self {
+                name: "gradient_fog",
+                settings: Settings {
+                    background: BackgroundSettings {
+                        mode: BackgroundMode::Gradient,
+                        ..BackgroundSettings::default()
+                    },
+                    atmosphere: AtmosphereSettings {
+                        fog_density: 0.05,
+                        ..AtmosphereSettings::default()
+                    },
+                    ..base.clone()
+                },
+                frames: 1,
+            },
+            Self {
+                name: "animated",
+                settings: base.clone(),
+                frames: 30,
+            },
+            Self {
+                name: "msaa",
+                settings: Settings {
+                    msaa_samples: 4,
+                    ..base
+                },
+                frames: 1,
+            },
+        ];
+    }
+
+    /// Whether there is a GPU adapter to render with, golden tests are skipped
+    /// without one.
+    pub async fn can_render() -> bool {
+        return Renderer::headless_adapter().await.is_some();
+    }
+
+    /// Render the scene with a headless renderer.
+    pub async fn render(&self) -> Result<RgbaImage> {
+        let mut renderer = Renderer::headless(Self::WIDTH, Self::HEIGHT, self.settings.clone()).await?;
+        for _ in 1..self.frames {
+            renderer.update(Self::STEP);
+        }
+        return renderer.render_image(Self::STEP);
+    }
+}
\ No newline at end of file
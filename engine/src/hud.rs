@@ -0,0 +1,224 @@
+use std::{
+    collections::VecDeque,
+    time::{Duration, Instant},
+};
+
+use crate::{overdraw::OverdrawEstimate, overlay::Overlay};
+
+/// Parts of a frame measured by the performance HUD.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum FrameStage {
+    Input,
+    Update,
+    Encode,
+    Present,
+}
+
+impl FrameStage {
+    pub const ALL: [FrameStage; 4] = [
+        FrameStage::Input,
+        FrameStage::Update,
+        FrameStage::Encode,
+        FrameStage::Present,
+    ];
+
+    fn index(self) -> usize {
+        return self as usize;
+    }
+
+    fn label(self) -> &'static str {
+        return match self {
+            FrameStage::Input => "InP",
+            FrameStage::Update => "UPd",
+            FrameStage::Encode => "Enc",
+            FrameStage::Present => "Pr",
+        };
+    }
+
+    fn color(self) -> [f32; 4] {
+        return match self {
+            FrameStage::Input => [0.9, 0.8, 0.2, 1.0],
+            FrameStage::Update => [0.3, 0.8, 0.3, 1.0],
+            FrameStage::Encode => [0.3, 0.5, 1.0, 1.0],
+            FrameStage::Present => [0.9, 0.3, 0.8, 1.0],
+        };
+    }
+}
+
+#[derive(Debug, Copy, Clone, Default, PartialEq)]
+pub struct FrameTimings {
+    pub stages: [Duration; 4],
+    /// Time between the end of the previous frame and the end of this one.
+    pub total: Duration,
+    pub draw_calls: u32,
+    pub instances: u32,
+    /// Estimated overdraw of the model instances, see `OverdrawEstimate`.
+    pub overdraw: OverdrawEstimate,
+}
+
+impl FrameTimings {
+    pub fn stage(&self, stage: FrameStage) -> Duration {
+        return self.stages[stage.index()];
+    }
+}
+
+/// Frame time graph and per-stage breakdown drawn with the overlay.
+pub struct PerformanceHud {
+    pub enabled: bool,
+    /// Number of frames kept for the graph.
+    pub history_len: usize,
+    /// Frame time that fills the graph's height.
+    pub graph_scale: Duration,
+    history: VecDeque<FrameTimings>,
+    current: FrameTimings,
+    frame_start: Instant,
+}
+
+impl Default for PerformanceHud {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            history_len: 120,
+            graph_scale: Duration::from_secs_f32(1.0 / 30.0),
+            history: VecDeque::new(),
+            current: FrameTimings::default(),
+            frame_start: Instant::now(),
+        }
+    }
+}
+
+const ROW_HEIGHT: f32 = 10.0;
+const GRAPH_HEIGHT: f32 = 60.0;
+const BAR_WIDTH: f32 = 2.0;
+const PADDING: f32 = 6.0;
+
+impl PerformanceHud {
+    /// Add time spent in `stage` to the current frame; stages may be
+    /// recorded several times, e.g. once per input event.
+    pub fn record(&mut self, stage: FrameStage, duration: Duration) {
+        self.current.stages[stage.index()] += duration;
+    }
+
+    pub fn set_counts(&mut self, draw_calls: u32, instances: u32) {
+        self.current.draw_calls = draw_calls;
+        self.current.instances = instances;
+    }
+
+    pub fn set_overdraw(&mut self, overdraw: OverdrawEstimate) {
+        self.current.overdraw = overdraw;
+    }
+
+    /// Close the current frame and move it into the history.
+    pub fn end_frame(&mut self) {
+        let now = Instant::now();
+        self.current.total = now - self.frame_start;
+        self.frame_start = now;
+
+        self.history.push_back(std::mem::take(&mut self.current));
+        while self.history.len() > self.history_len {
+            self.history.pop_front();
+        }
+    }
+
+    pub fn history(&self) -> impl Iterator<Item = &FrameTimings> {
+        return self.history.iter();
+    }
+
+    /// Timings averaged over the whole history.
+    pub fn average(&self) -> FrameTimings {
+        let mut average = FrameTimings::default();
+        let count = self.history.len().max(1) as u32;
+        for frame in &self.history {
+            for stage in FrameStage::ALL {
+                average.stages[stage.index()] += frame.stage(stage) / count;
+            }
+            average.total += frame.total / count;
+        }
+        if let Some(last) = self.history.back() {
+            average.draw_calls = last.draw_calls;
+            average.instances = last.instances;
+            average.overdraw = last.overdraw;
+        }
+        return average;
+    }
+
+    /// Queue the HUD into `overlay` with its top left corner at `position`.
+    pub fn draw(&self, overlay: &mut Overlay, position: [f32; 2]) {
+        if !self.enabled {
+            return;
+        }
+
+        let graph_width = self.history_len as f32 * BAR_WIDTH;
+        let rows = FrameStage::ALL.len() as f32 + 3.0;
+        let (x, y) = (position[0], position[1]);
+        let width = graph_width + PADDING * 2.0;
+        let height = GRAPH_HEIGHT + rows * ROW_HEIGHT * 1.5 + PADDING * 3.0;
+        overlay.fill_rect([x, y], [x + width, y + height], [0.0, 0.0, 0.0, 0.6]);
+
+        // Stacked bars per frame, whatever is left of the frame is waiting
+        let (graph_x, graph_bottom) = (x + PADDING, y + PADDING + GRAPH_HEIGHT);
+        let scale = GRAPH_HEIGHT / self.graph_scale.as_secs_f32();
+        let start = self.history_len.saturating_sub(self.history.len());
+        for (i, frame) in self.history.iter().enumerate() {
+            let left = graph_x + (start + i) as f32 * BAR_WIDTH;
+            let mut bottom = graph_bottom;
+            let total = (frame.total.as_secs_f32() * scale).min(GRAPH_HEIGHT);
+            overlay.fill_rect(
+                [left, graph_bottom - total],
+                [left + BAR_WIDTH, graph_bottom],
+                [0.5, 0.5, 0.5, 0.8],
+            );
+            for stage in FrameStage::ALL {
+                let top = (bottom - frame.stage(stage).as_secs_f32() * scale)
+                    .max(graph_bottom - GRAPH_HEIGHT);
+                overlay.fill_rect([left, top], [left + BAR_WIDTH, bottom], stage.color());
+                bottom = top;
+            }
+        }
+        // 60 fps reference line
+        let target = graph_bottom - scale / 60.0;
+        if target > graph_bottom - GRAPH_HEIGHT {
+            overlay.line(
+                [graph_x, target],
+                [graph_x + graph_width, target],
+                [1.0, 1.0, 1.0, 0.5],
+                1.0,
+            );
+        }
+
+        let average = self.average();
+        let white = [1.0, 1.0, 1.0, 1.0];
+        let ms = |d: Duration| format!("{:.2}", d.as_secs_f32() * 1000.0);
+        let mut row_y = graph_bottom + PADDING;
+        for stage in FrameStage::ALL {
+            overlay.fill_rect([graph_x, row_y], [graph_x + ROW_HEIGHT, row_y + ROW_HEIGHT], stage.color());
+            overlay.text([graph_x + ROW_HEIGHT * 2.0, row_y], stage.label(), ROW_HEIGHT, white);
+            overlay.text([graph_x + ROW_HEIGHT * 6.0, row_y], &ms(average.stage(stage)), ROW_HEIGHT, white);
+            row_y += ROW_HEIGHT * 1.5;
+        }
+
+        let fps = 1.0 / average.total.as_secs_f32().max(f32::EPSILON);
+        overlay.text(
+            [graph_x, row_y],
+            &format!("{} FPS {:.0}", ms(average.total), fps),
+            ROW_HEIGHT,
+            white,
+        );
+        row_y += ROW_HEIGHT * 1.5;
+        overlay.text(
+            [graph_x, row_y],
+            &format!("dC {} I {}", average.draw_calls, average.instances),
+            ROW_HEIGHT,
+            white,
+        );
+        // Fragments per pixel shaded after early depth testing, then rasterized
+        row_y += ROW_HEIGHT * 1.5;
+        let overdraw = average.overdraw;
+        overlay.text(
+            [graph_x, row_y],
+            &format!("od {:.2} {:.2}", overdraw.shaded, overdraw.depth_complexity),
+            ROW_HEIGHT,
+            white,
+        );
+    }
+}
@@ -0,0 +1,205 @@
+// Environment map generation and convolution for image based lighting.
+// Every pass draws a fullscreen triangle into one face and mip of a target.
+struct Params {
+    // Cube face being rendered, +X -X +Y -Y +Z -Z
+    face: u32,
+    roughness: f32,
+    // Size of one face of the source cube map in pixels
+    source_size: f32,
+    _padding: f32,
+    zenith: vec4<f32>,
+    horizon: vec4<f32>,
+    ground: vec4<f32>,
+    // xyz: direction towards the sun, w: angular radius in radians
+    sun_direction: vec4<f32>,
+    sun_color: vec4<f32>,
+};
+@group(0) @binding(0)
+var<uniform> params: Params;
+@group(0) @binding(1)
+var t_source: texture_cube<f32>;
+@group(0) @binding(2)
+var s_source: sampler;
+
+let PI: f32 = 3.14159265359;
+
+struct VertexOutput {
+    @builtin(position) clip_position: vec4<f32>,
+    @location(0) uv: vec2<f32>,
+};
+
+@vertex
+fn vs_main(@builtin(vertex_index) index: u32) -> VertexOutput {
+    let x = f32(index & 1u) * 4.0 - 1.0;
+    let y = f32(index >> 1u) * 4.0 - 1.0;
+
+    var out: VertexOutput;
+    out.clip_position = vec4<f32>(x, y, 0.0, 1.0);
+    out.uv = vec2<f32>((x + 1.0) * 0.5, (1.0 - y) * 0.5);
+    return out;
+}
+
+// Direction through a texel of a cube face, with uv going down the face
+fn face_direction(face: u32, uv: vec2<f32>) -> vec3<f32> {
+    let a = uv * 2.0 - 1.0;
+    var direction: vec3<f32>;
+    if (face == 0u) {
+        direction = vec3<f32>(1.0, -a.y, -a.x);
+    } else if (face == 1u) {
+        direction = vec3<f32>(-1.0, -a.y, a.x);
+    } else if (face == 2u) {
+        direction = vec3<f32>(a.x, 1.0, a.y);
+    } else if (face == 3u) {
+        direction = vec3<f32>(a.x, -1.0, -a.y);
+    } else if (face == 4u) {
+        direction = vec3<f32>(a.x, -a.y, 1.0);
+    } else {
+        direction = vec3<f32>(-a.x, -a.y, -1.0);
+    }
+    return normalize(direction);
+}
+
+// Rotation from a frame with z along `n` into world space
+fn tangent_basis(n: vec3<f32>) -> mat3x3<f32> {
+    var up = vec3<f32>(0.0, 1.0, 0.0);
+    if (abs(n.y) > 0.999) {
+        up = vec3<f32>(0.0, 0.0, 1.0);
+    }
+    let tangent = normalize(cross(up, n));
+    let bitangent = cross(n, tangent);
+    return mat3x3<f32>(tangent, bitangent, n);
+}
+
+fn radical_inverse(index: u32) -> f32 {
+    var bits = index;
+    bits = (bits << 16u) | (bits >> 16u);
+    bits = ((bits & 1431655765u) << 1u) | ((bits & 2863311530u) >> 1u);
+    bits = ((bits & 858993459u) << 2u) | ((bits & 3435973836u) >> 2u);
+    bits = ((bits & 252645135u) << 4u) | ((bits & 4042322160u) >> 4u);
+    bits = ((bits & 16711935u) << 8u) | ((bits & 4278255360u) >> 8u);
+    return f32(bits) * 2.3283064365386963e-10;
+}
+
+fn hammersley(i: u32, count: u32) -> vec2<f32> {
+    return vec2<f32>(f32(i) / f32(count), radical_inverse(i));
+}
+
+// GGX distributed half vector around the normal in tangent space
+fn importance_sample_ggx(xi: vec2<f32>, roughness: f32) -> vec3<f32> {
+    let a = roughness * roughness;
+    let phi = 2.0 * PI * xi.x;
+    let cos_theta = sqrt((1.0 - xi.y) / (1.0 + (a * a - 1.0) * xi.y));
+    let sin_theta = sqrt(1.0 - cos_theta * cos_theta);
+    return vec3<f32>(cos(phi) * sin_theta, sin(phi) * sin_theta, cos_theta);
+}
+
+fn distribution_ggx(n_dot_h: f32, roughness: f32) -> f32 {
+    let a2 = roughness * roughness * roughness * roughness;
+    let d = n_dot_h * n_dot_h * (a2 - 1.0) + 1.0;
+    return a2 / (PI * d * d);
+}
+
+fn geometry_smith(n_dot_v: f32, n_dot_l: f32, roughness: f32) -> f32 {
+    // k for image based lighting
+    let k = roughness * roughness / 2.0;
+    let g_v = n_dot_v / (n_dot_v * (1.0 - k) + k);
+    let g_l = n_dot_l / (n_dot_l * (1.0 - k) + k);
+    return g_v * g_l;
+}
+
+// Procedural gradient sky with a sun disc
+@fragment
+fn fs_sky(in: VertexOutput) -> @location(0) vec4<f32> {
+    let direction = face_direction(params.face, in.uv);
+
+    var color: vec3<f32>;
+    if (direction.y >= 0.0) {
+        color = mix(params.horizon.rgb, params.zenith.rgb, pow(direction.y, 0.5));
+    } else {
+        color = mix(params.horizon.rgb, params.ground.rgb, pow(-direction.y, 0.25));
+    }
+
+    let sun_angle = acos(clamp(dot(direction, normalize(params.sun_direction.xyz)), -1.0, 1.0));
+    let sun = 1.0 - smoothstep(params.sun_direction.w * 0.8, params.sun_direction.w, sun_angle);
+    color += params.sun_color.rgb * sun;
+
+    return vec4<f32>(color, 1.0);
+}
+
+// Cosine weighted hemisphere integral of incoming light
+@fragment
+fn fs_irradiance(in: VertexOutput) -> @location(0) vec4<f32> {
+    let normal = face_direction(params.face, in.uv);
+    let basis = tangent_basis(normal);
+
+    let delta = 0.05;
+    var irradiance = vec3<f32>(0.0, 0.0, 0.0);
+    var count = 0.0;
+    for (var phi = 0.0; phi < 2.0 * PI; phi += delta) {
+        for (var theta = 0.0; theta < 0.5 * PI; theta += delta) {
+            let local = vec3<f32>(sin(theta) * cos(phi), sin(theta) * sin(phi), cos(theta));
+            let radiance = textureSampleLevel(t_source, s_source, basis * local, 0.0).rgb;
+            irradiance += radiance * cos(theta) * sin(theta);
+            count += 1.0;
+        }
+    }
+    return vec4<f32>(PI * irradiance / count, 1.0);
+}
+
+// GGX prefiltered radiance for one roughness level, assuming view = normal
+@fragment
+fn fs_prefilter(in: VertexOutput) -> @location(0) vec4<f32> {
+    let normal = face_direction(params.face, in.uv);
+    let basis = tangent_basis(normal);
+    let roughness = params.roughness;
+
+    let sample_count = 256u;
+    var color = vec3<f32>(0.0, 0.0, 0.0);
+    var weight = 0.0;
+    for (var i = 0u; i < sample_count; i++) {
+        let half_vector = basis * importance_sample_ggx(hammersley(i, sample_count), roughness);
+        let light = normalize(2.0 * dot(normal, half_vector) * half_vector - normal);
+        let n_dot_l = dot(normal, light);
+        if (n_dot_l > 0.0) {
+            // Read from a blurrier level where samples are sparse to avoid fireflies
+            let n_dot_h = max(dot(normal, half_vector), 0.0);
+            let pdf = distribution_ggx(n_dot_h, roughness) / 4.0 + 0.0001;
+            let texel = 4.0 * PI / (6.0 * params.source_size * params.source_size);
+            let sample_solid_angle = 1.0 / (f32(sample_count) * pdf + 0.0001);
+            var lod = 0.0;
+            if (roughness > 0.0) {
+                lod = 0.5 * log2(sample_solid_angle / texel);
+            }
+            color += textureSampleLevel(t_source, s_source, light, lod).rgb * n_dot_l;
+            weight += n_dot_l;
+        }
+    }
+    return vec4<f32>(color / max(weight, 0.0001), 1.0);
+}
+
+// Split-sum BRDF scale (r) and bias (g) by n.v (u) and roughness (v)
+@fragment
+fn fs_brdf(in: VertexOutput) -> @location(0) vec4<f32> {
+    let n_dot_v = max(in.uv.x, 0.001);
+    let roughness = in.uv.y;
+    let view = vec3<f32>(sqrt(1.0 - n_dot_v * n_dot_v), 0.0, n_dot_v);
+
+    let sample_count = 256u;
+    var scale = 0.0;
+    var bias = 0.0;
+    for (var i = 0u; i < sample_count; i++) {
+        let half_vector = importance_sample_ggx(hammersley(i, sample_count), roughness);
+        let light = normalize(2.0 * dot(view, half_vector) * half_vector - view);
+        let n_dot_l = max(light.z, 0.0);
+        let n_dot_h = max(half_vector.z, 0.0);
+        let v_dot_h = max(dot(view, half_vector), 0.0);
+        if (n_dot_l > 0.0) {
+            let g = geometry_smith(n_dot_v, n_dot_l, roughness);
+            let g_vis = g * v_dot_h / (n_dot_h * n_dot_v);
+            let fresnel = pow(1.0 - v_dot_h, 5.0);
+            scale += (1.0 - fresnel) * g_vis;
+            bias += fresnel * g_vis;
+        }
+    }
+    return vec4<f32>(scale / f32(sample_count), bias / f32(sample_count), 0.0, 1.0);
+}
@@ -0,0 +1,489 @@
+use std::fmt;
+
+use serde::{Deserialize, Serialize};
+use winit::event::{ModifiersState, VirtualKeyCode};
+
+use crate::{overlay::Overlay, settings::KeyBindings};
+
+const ROW_HEIGHT: f32 = 12.0;
+const PADDING: f32 = 8.0;
+const TEXT_COLOR: [f32; 4] = [1.0, 1.0, 1.0, 1.0];
+const SELECTED_COLOR: [f32; 4] = [1.0, 0.9, 0.2, 1.0];
+const CONFLICT_COLOR: [f32; 4] = [1.0, 0.3, 0.2, 1.0];
+
+/// Engine commands that can be bound to keys and gamepad buttons.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub enum Action {
+    ToggleInputMode,
+    ToggleHud,
+    ToggleFrustums,
+    CycleEnvironmentView,
+    ToggleModelPass,
+    ToggleCrowdPass,
+    ToggleLensFlarePass,
+    ToggleOverlayPass,
+    ToggleVsync,
+    ToggleCapture,
+    Screenshot,
+    // View through each render target camera in turn, then the main camera again
+    CycleCamera,
+    ToggleLightGizmo,
+    // Only while the light gizmo is shown
+    ToggleGizmoMode,
+    TogglePlacement,
+    ToggleGrid,
+    ToggleMeasure,
+    ToggleBindings,
+    ToggleInspector,
+    ToggleConsole,
+}
+
+impl Action {
+    pub const ALL: [Action; 20] = [
+        Action::ToggleInputMode,
+        Action::ToggleHud,
+        Action::ToggleFrustums,
+        Action::CycleEnvironmentView,
+        Action::ToggleModelPass,
+        Action::ToggleCrowdPass,
+        Action::ToggleLensFlarePass,
+        Action::ToggleOverlayPass,
+        Action::ToggleVsync,
+        Action::ToggleCapture,
+        Action::Screenshot,
+        Action::CycleCamera,
+        Action::ToggleLightGizmo,
+        Action::ToggleGizmoMode,
+        Action::TogglePlacement,
+        Action::ToggleGrid,
+        Action::ToggleMeasure,
+        Action::ToggleBindings,
+        Action::ToggleInspector,
+        Action::ToggleConsole,
+    ];
+
+    pub fn label(self) -> &'static str {
+        return match self {
+            Action::ToggleInputMode => "Input mode",
+            Action::ToggleHud => "HUD",
+            Action::ToggleFrustums => "Frustums",
+            Action::CycleEnvironmentView => "Environment view",
+            Action::ToggleModelPass => "Model pass",
+            Action::ToggleCrowdPass => "Crowd pass",
+            Action::ToggleLensFlarePass => "Lens flare pass",
+            Action::ToggleOverlayPass => "Overlay pass",
+            Action::ToggleVsync => "Vsync",
+            Action::ToggleCapture => "Capture",
+            Action::Screenshot => "Screenshot",
+            Action::CycleCamera => "Camera",
+            Action::ToggleLightGizmo => "Light gizmo",
+            Action::ToggleGizmoMode => "Gizmo mode",
+            Action::TogglePlacement => "Placement",
+            Action::ToggleGrid => "Grid",
+            Action::ToggleMeasure => "Measure",
+            Action::ToggleBindings => "Bindings",
+            Action::ToggleInspector => "Inspector",
+            Action::ToggleConsole => "Console",
+        };
+    }
+}
+
+/// A key pressed while holding modifiers. Modifiers that aren't required may
+/// be held as well, the chord requiring the most of them wins.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub struct KeyChord {
+    pub key: VirtualKeyCode,
+    #[serde(default)]
+    pub ctrl: bool,
+    #[serde(default)]
+    pub shift: bool,
+    #[serde(default)]
+    pub alt: bool,
+}
+
+impl KeyChord {
+    pub const fn new(key: VirtualKeyCode) -> Self {
+        return Self {
+            key,
+            ctrl: false,
+            shift: false,
+            alt: false,
+        };
+    }
+
+    /// `key` with the modifiers of `modifiers`, e.g. one just pressed.
+    pub fn with_modifiers(key: VirtualKeyCode, modifiers: ModifiersState) -> Self {
+        return Self {
+            key,
+            ctrl: modifiers.ctrl(),
+            shift: modifiers.shift(),
+            alt: modifiers.alt(),
+        };
+    }
+
+    pub const fn ctrl(mut self) -> Self {
+        self.ctrl = true;
+        return self;
+    }
+
+    pub const fn shift(mut self) -> Self {
+        self.shift = true;
+        return self;
+    }
+
+    pub const fn alt(mut self) -> Self {
+        self.alt = true;
+        return self;
+    }
+
+    pub fn matches(&self, key: VirtualKeyCode, modifiers: ModifiersState) -> bool {
+        return self.key == key
+            && (!self.ctrl || modifiers.ctrl())
+            && (!self.shift || modifiers.shift())
+            && (!self.alt || modifiers.alt());
+    }
+
+    pub fn modifier_count(&self) -> usize {
+        return [self.ctrl, self.shift, self.alt].iter().filter(|m| **m).count();
+    }
+}
+
+impl fmt::Display for KeyChord {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.ctrl {
+            write!(f, "Ctrl+")?;
+        }
+        if self.shift {
+            write!(f, "Shift+")?;
+        }
+        if self.alt {
+            write!(f, "Alt+")?;
+        }
+        return write!(f, "{:?}", self.key);
+    }
+}
+
+/// Whether `key` only modifies other keys, it can't be the key of a chord.
+pub fn is_modifier(key: VirtualKeyCode) -> bool {
+    return matches!(
+        key,
+        VirtualKeyCode::LControl
+            | VirtualKeyCode::RControl
+            | VirtualKeyCode::LShift
+            | VirtualKeyCode::RShift
+            | VirtualKeyCode::LAlt
+            | VirtualKeyCode::RAlt
+            | VirtualKeyCode::LWin
+            | VirtualKeyCode::RWin
+    );
+}
+
+/// Gamepad buttons by position, the south button is A on Xbox and cross on
+/// PlayStation controllers. Read by `run` with the `gamepad` feature,
+/// otherwise fed to `Renderer::gamepad_input` by the application.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub enum GamepadButton {
+    South,
+    East,
+    West,
+    North,
+    LeftShoulder,
+    RightShoulder,
+    LeftTrigger,
+    RightTrigger,
+    Select,
+    Start,
+    Mode,
+    LeftStick,
+    RightStick,
+    DPadUp,
+    DPadDown,
+    DPadLeft,
+    DPadRight,
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub enum Binding {
+    Key(KeyChord),
+    Gamepad(GamepadButton),
+}
+
+impl Binding {
+    fn same_device(&self, other: &Binding) -> bool {
+        return matches!(
+            (self, other),
+            (Binding::Key(_), Binding::Key(_)) | (Binding::Gamepad(_), Binding::Gamepad(_))
+        );
+    }
+}
+
+impl fmt::Display for Binding {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        return match self {
+            Binding::Key(chord) => write!(f, "{}", chord),
+            Binding::Gamepad(button) => write!(f, "Pad {:?}", button),
+        };
+    }
+}
+
+/// A binding shared by several actions, or by an action and the keys moving
+/// the camera. Only the first action in the map runs.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Conflict {
+    pub binding: Binding,
+    pub actions: Vec<Action>,
+    /// The binding also moves the camera, see `KeyBindings`.
+    pub movement: bool,
+}
+
+/// Bindings of the engine's actions, several per action. Written in RON
+/// like the rest of the preferences:
+///
+/// ```ron
+/// (bindings: [
+///     (Screenshot, Key((key: S, ctrl: true))),
+///     (ToggleHud, Gamepad(Select)),
+/// ])
+/// ```
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct ActionMap {
+    pub bindings: Vec<(Action, Binding)>,
+}
+
+impl Default for ActionMap {
+    fn default() -> Self {
+        let key = |action, key| (action, Binding::Key(KeyChord::new(key)));
+        let pad = |action, button| (action, Binding::Gamepad(button));
+        Self {
+            bindings: vec![
+                key(Action::ToggleInputMode, VirtualKeyCode::Tab),
+                key(Action::ToggleHud, VirtualKeyCode::F3),
+                key(Action::ToggleFrustums, VirtualKeyCode::F2),
+                key(Action::CycleEnvironmentView, VirtualKeyCode::F4),
+                key(Action::ToggleModelPass, VirtualKeyCode::F5),
+                key(Action::ToggleCrowdPass, VirtualKeyCode::F6),
+                key(Action::ToggleLensFlarePass, VirtualKeyCode::F7),
+                key(Action::ToggleOverlayPass, VirtualKeyCode::F8),
+                key(Action::ToggleCapture, VirtualKeyCode::F9),
+                key(Action::ToggleVsync, VirtualKeyCode::F10),
+                key(Action::Screenshot, VirtualKeyCode::F12),
+                key(Action::CycleCamera, VirtualKeyCode::C),
+                key(Action::ToggleLightGizmo, VirtualKeyCode::L),
+                key(Action::ToggleGizmoMode, VirtualKeyCode::G),
+                key(Action::TogglePlacement, VirtualKeyCode::P),
+                key(Action::ToggleGrid, VirtualKeyCode::N),
+                key(Action::ToggleMeasure, VirtualKeyCode::M),
+                (Action::ToggleBindings, Binding::Key(KeyChord::new(VirtualKeyCode::B).ctrl())),
+                (Action::ToggleInspector, Binding::Key(KeyChord::new(VirtualKeyCode::I).ctrl())),
+                key(Action::ToggleConsole, VirtualKeyCode::Grave),
+                pad(Action::ToggleHud, GamepadButton::Select),
+                pad(Action::CycleCamera, GamepadButton::RightShoulder),
+                pad(Action::Screenshot, GamepadButton::LeftShoulder),
+                pad(Action::ToggleBindings, GamepadButton::Start),
+            ],
+        }
+    }
+}
+
+impl ActionMap {
+    /// Add `binding` to the bindings of `action`.
+    pub fn bind(&mut self, action: Action, binding: Binding) {
+        if !self.bindings.contains(&(action, binding)) {
+            self.bindings.push((action, binding));
+        }
+    }
+
+    /// Remove `binding` from every action.
+    pub fn unbind(&mut self, binding: Binding) {
+        self.bindings.retain(|(_, b)| *b != binding);
+    }
+
+    /// Remove every binding of `action`.
+    pub fn clear(&mut self, action: Action) {
+        self.bindings.retain(|(a, _)| *a != action);
+    }
+
+    /// Replace the bindings of `action` on the device of `binding`, keeping
+    /// e.g. its gamepad button when it's bound to another key. Returns the
+    /// other actions bound to `binding`, see `conflicts`.
+    pub fn rebind(&mut self, action: Action, binding: Binding) -> Vec<Action> {
+        self.bindings
+            .retain(|(a, b)| *a != action || !b.same_device(&binding));
+        self.bindings.push((action, binding));
+        return self
+            .bindings
+            .iter()
+            .filter(|(a, b)| *a != action && *b == binding)
+            .map(|(a, _)| *a)
+            .collect();
+    }
+
+    pub fn bindings_for(&self, action: Action) -> impl Iterator<Item = &Binding> {
+        return self
+            .bindings
+            .iter()
+            .filter(move |(a, _)| *a == action)
+            .map(|(_, b)| b);
+    }
+
+    /// Action of the chord matching `key` that requires the most of the held
+    /// `modifiers`.
+    pub fn action_for_key(&self, key: VirtualKeyCode, modifiers: ModifiersState) -> Option<Action> {
+        let mut best: Option<(Action, usize)> = None;
+        for (action, binding) in &self.bindings {
+            if let Binding::Key(chord) = binding {
+                let count = chord.modifier_count();
+                if chord.matches(key, modifiers) && best.is_none_or(|(_, c)| count > c) {
+                    best = Some((*action, count));
+                }
+            }
+        }
+        return best.map(|(action, _)| action);
+    }
+
+    pub fn action_for_button(&self, button: GamepadButton) -> Option<Action> {
+        return self
+            .bindings
+            .iter()
+            .find(|(_, b)| *b == Binding::Gamepad(button))
+            .map(|(a, _)| *a);
+    }
+
+    /// Bindings shared by several actions, and keys without modifiers that
+    /// also move the camera with `movement`.
+    pub fn conflicts(&self, movement: &KeyBindings) -> Vec<Conflict> {
+        let movement_keys = [
+            movement.forward,
+            movement.backward,
+            movement.left,
+            movement.right,
+            movement.up,
+            movement.down,
+            movement.roll_left,
+            movement.roll_right,
+        ];
+        let mut conflicts: Vec<Conflict> = Vec::new();
+        for (action, binding) in &self.bindings {
+            if let Some(conflict) = conflicts.iter_mut().find(|c| c.binding == *binding) {
+                if !conflict.actions.contains(action) {
+                    conflict.actions.push(*action);
+                }
+                continue;
+            }
+            let moves = match binding {
+                Binding::Key(chord) => {
+                    chord.modifier_count() == 0 && movement_keys.iter().any(|k| k.matches(chord.key))
+                }
+                Binding::Gamepad(_) => false,
+            };
+            conflicts.push(Conflict {
+                binding: *binding,
+                actions: vec![*action],
+                movement: moves,
+            });
+        }
+        conflicts.retain(|c| c.movement || c.actions.len() > 1);
+        return conflicts;
+    }
+}
+
+/// Debug UI listing the actions with their bindings, conflicts in red. Up and
+/// down or the D-pad select an action, Enter or the south button rebinds it
+/// to the next key chord or gamepad button, Delete clears it and Escape or
+/// the east button closes the panel.
+#[derive(Debug, Clone, Default)]
+pub struct BindingsPanel {
+    pub enabled: bool,
+    selected: usize,
+    // Action waiting for its new binding
+    rebinding: Option<Action>,
+}
+
+impl BindingsPanel {
+    /// Width of the panel in the overlay's logical pixels.
+    pub const WIDTH: f32 = ROW_HEIGHT * 30.0 + PADDING * 2.0;
+
+    pub fn selected(&self) -> Action {
+        return Action::ALL[self.selected];
+    }
+
+    pub fn select_next(&mut self) {
+        self.selected = (self.selected + 1) % Action::ALL.len();
+    }
+
+    pub fn select_previous(&mut self) {
+        self.selected = (self.selected + Action::ALL.len() - 1) % Action::ALL.len();
+    }
+
+    pub fn select(&mut self, action: Action) {
+        self.selected = Action::ALL.iter().position(|a| *a == action).unwrap_or(0);
+    }
+
+    /// Wait for a binding for `action`, see `take_rebinding`.
+    pub fn begin_rebind(&mut self, action: Action) {
+        self.select(action);
+        self.rebinding = Some(action);
+    }
+
+    pub fn cancel_rebind(&mut self) {
+        self.rebinding = None;
+    }
+
+    pub fn is_rebinding(&self) -> bool {
+        return self.rebinding.is_some();
+    }
+
+    /// The action to give the binding that was just pressed.
+    pub fn take_rebinding(&mut self) -> Option<Action> {
+        return self.rebinding.take();
+    }
+
+    /// Height of the panel drawn by `draw`.
+    pub fn height(&self) -> f32 {
+        return (Action::ALL.len() as f32 + 1.0) * ROW_HEIGHT * 1.5 + PADDING * 2.0;
+    }
+
+    /// Queue the panel into `overlay` with its top left corner at `position`.
+    pub fn draw(&self, overlay: &mut Overlay, position: [f32; 2], actions: &ActionMap, movement: &KeyBindings) {
+        if !self.enabled {
+            return;
+        }
+
+        let conflicts = actions.conflicts(movement);
+        let (x, y) = (position[0], position[1]);
+        let label_width = ROW_HEIGHT * 12.0;
+        let width = Self::WIDTH;
+        let height = self.height();
+        overlay.fill_rect([x, y], [x + width, y + height], [0.0, 0.0, 0.0, 0.6]);
+
+        let mut row_y = y + PADDING;
+        for (i, action) in Action::ALL.into_iter().enumerate() {
+            let conflicting = conflicts.iter().any(|c| c.actions.contains(&action));
+            let color = if i == self.selected {
+                overlay.fill_rect(
+                    [x, row_y - ROW_HEIGHT * 0.25],
+                    [x + width, row_y + ROW_HEIGHT * 1.25],
+                    [1.0, 1.0, 1.0, 0.15],
+                );
+                SELECTED_COLOR
+            } else {
+                TEXT_COLOR
+            };
+            overlay.text([x + PADDING, row_y], action.label(), ROW_HEIGHT, color);
+            let bindings = if self.rebinding == Some(action) {
+                "Press a key or button".to_string()
+            } else {
+                actions.bindings_for(action).map(|b| b.to_string()).collect::<Vec<_>>().join("  ")
+            };
+            let color = if conflicting { CONFLICT_COLOR } else { color };
+            overlay.text([x + PADDING + label_width, row_y], &bindings, ROW_HEIGHT, color);
+            row_y += ROW_HEIGHT * 1.5;
+        }
+        let conflict_count = conflicts.len();
+        if conflict_count > 0 {
+            let text = format!("{} conflicts", conflict_count);
+            overlay.text([x + PADDING, row_y], &text, ROW_HEIGHT, CONFLICT_COLOR);
+        }
+    }
+}
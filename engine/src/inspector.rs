@@ -0,0 +1,490 @@
+use std::fmt::Write as _;
+
+use crate::{
+    gltf::{json_array, json_string},
+    layers::RenderLayers,
+    light::{LightBufferManager, LightId, PositionalLight},
+    math::Aabb,
+    memory::{MemoryReport, TrackedTexture},
+    model::Model,
+    overlay::Overlay,
+    point_cloud::PointCloudRenderer,
+    resources::Instance,
+};
+
+const ROW_HEIGHT: f32 = 10.0;
+const PADDING: f32 = 6.0;
+const TEXT_COLOR: [f32; 4] = [1.0, 1.0, 1.0, 1.0];
+const DIM_COLOR: [f32; 4] = [0.7, 0.7, 0.7, 1.0];
+const MISSING_COLOR: [f32; 4] = [1.0, 0.35, 0.3, 1.0];
+
+/// Bytes as the largest unit that keeps the value at least 1, e.g. `1.50 MB`.
+pub fn format_bytes(bytes: u64) -> String {
+    let units = ["B", "KB", "MB", "GB"];
+    let mut value = bytes as f64;
+    let mut unit = 0;
+    while value >= 1024.0 && unit + 1 < units.len() {
+        value /= 1024.0;
+        unit += 1;
+    }
+    if unit == 0 {
+        return format!("{} B", bytes);
+    }
+    return format!("{:.2} {}", value, units[unit]);
+}
+
+/// GPU buffers of a mesh.
+#[derive(Debug, Clone)]
+pub struct MeshInfo {
+    /// What the mesh belongs to, e.g. `scene`, `lod 1` or `crowd 0`.
+    pub owner: String,
+    pub name: String,
+    pub vertex_bytes: u64,
+    pub index_bytes: u64,
+    /// Second texture coordinates for the lightmap, 0 without them.
+    pub lightmap_bytes: u64,
+    pub triangles: u32,
+    pub submeshes: usize,
+}
+
+impl MeshInfo {
+    pub fn bytes(&self) -> u64 {
+        return self.vertex_bytes + self.index_bytes + self.lightmap_bytes;
+    }
+}
+
+/// What happened to an instance of the scene model last frame.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum InstanceState {
+    /// Drawn with this level of detail, 0 being the scene model itself.
+    Drawn { level: usize },
+    /// On none of the layers the camera draws.
+    Hidden,
+    /// Outside of the view or behind a closed portal.
+    Culled,
+}
+
+#[derive(Debug, Clone)]
+pub struct InstanceInfo {
+    /// Index into the renderer's instances.
+    pub index: usize,
+    pub position: [f32; 3],
+    pub scale: [f32; 3],
+    pub layers: RenderLayers,
+    pub outline: bool,
+    pub state: InstanceState,
+    /// Positional lights whose range reaches the instance, `None` when the
+    /// scene model has no bounds.
+    pub lights: Option<usize>,
+}
+
+/// A skinned or vertex animated crowd, its meshes and textures are listed
+/// with the others.
+#[derive(Debug, Clone)]
+pub struct CrowdInfo {
+    /// `crowd 0`, `vat crowd 1`, ...
+    pub name: String,
+    pub instances: usize,
+    /// Whether `InstanceMotion` moves the instances on the GPU.
+    pub motion: bool,
+}
+
+#[derive(Debug, Clone)]
+pub struct LightInfo {
+    pub id: LightId,
+    pub color: [f32; 3],
+    /// `None` for directional lights.
+    pub position: Option<[f32; 3]>,
+    pub range: Option<f32>,
+    /// Lights past the uploaded light counts aren't drawn, see
+    /// `LightBufferManager::is_active`.
+    pub active: bool,
+    /// Side of the light's shadow atlas tile, `None` if it got no shadows
+    /// last frame.
+    pub shadow_size: Option<u32>,
+    pub volumetric: bool,
+}
+
+/// A material texture.
+#[derive(Debug, Clone)]
+pub struct TextureInfo {
+    /// What the material belongs to, see `MeshInfo::owner`.
+    pub owner: String,
+    pub material: String,
+    /// `diffuse` or `normal`.
+    pub slot: &'static str,
+    /// Resource path, `None` for generated textures.
+    pub source: Option<String>,
+    pub width: u32,
+    pub height: u32,
+    pub mip_levels: u32,
+    pub format: wgpu::TextureFormat,
+    pub bytes: u64,
+    /// Showing the placeholder because the file failed to load.
+    pub missing: bool,
+}
+
+#[derive(Debug, Clone)]
+pub struct PointCloudInfo {
+    /// Index into `PointCloudRenderer::clouds`.
+    pub index: usize,
+    pub points: u32,
+    pub bytes: u64,
+    pub visible: bool,
+}
+
+/// Something taking up GPU memory, see `SceneInspector::heaviest`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Footprint {
+    pub label: String,
+    pub bytes: u64,
+}
+
+/// Snapshot of everything in the scene with its memory footprint and state,
+/// for the debug overlay or dumped as JSON to find what's taking up memory.
+/// See `Renderer::inspect`.
+#[derive(Debug, Clone, Default)]
+pub struct SceneInspector {
+    pub meshes: Vec<MeshInfo>,
+    pub instances: Vec<InstanceInfo>,
+    pub crowds: Vec<CrowdInfo>,
+    pub lights: Vec<LightInfo>,
+    pub ambient_lights: u32,
+    pub textures: Vec<TextureInfo>,
+    pub point_clouds: Vec<PointCloudInfo>,
+    /// Everything allocated through the memory tracker, including render
+    /// targets and uniforms not listed above.
+    pub memory: MemoryReport,
+}
+
+impl SceneInspector {
+    /// Width of the panel drawn by `draw`.
+    pub const WIDTH: f32 = 330.0;
+
+    pub fn new(memory: MemoryReport) -> Self {
+        return Self {
+            memory,
+            ..Default::default()
+        };
+    }
+
+    /// Add the meshes and material textures of `model`.
+    pub fn add_model(&mut self, owner: &str, model: &Model) {
+        for mesh in &model.meshes {
+            self.meshes.push(MeshInfo {
+                owner: owner.to_string(),
+                name: mesh.name.clone(),
+                vertex_bytes: mesh.vertex_buffer.size(),
+                index_bytes: mesh.index_buffer.size(),
+                lightmap_bytes: mesh.lightmap_uvs.as_ref().map_or(0, |uvs| uvs.buffer.size()),
+                triangles: mesh.num_elements / 3,
+                submeshes: mesh.submeshes.len(),
+            });
+        }
+        for material in &model.materials {
+            let slots = [
+                ("diffuse", &material.diffuse_texture.texture, &material.diffuse_source, false),
+                ("normal", &material.normal_texture.texture, &material.normal_source, true),
+            ];
+            for (slot, texture, source, is_normal_map) in slots {
+                let missing = material.missing_textures.iter().any(|m| m.is_normal_map == is_normal_map);
+                self.add_texture(owner, &material.name, slot, texture, source.clone(), missing);
+            }
+        }
+    }
+
+    fn add_texture(
+        &mut self,
+        owner: &str,
+        material: &str,
+        slot: &'static str,
+        texture: &TrackedTexture,
+        source: Option<String>,
+        missing: bool,
+    ) {
+        let extent = texture.extent();
+        self.textures.push(TextureInfo {
+            owner: owner.to_string(),
+            material: material.to_string(),
+            slot,
+            source,
+            width: extent.width,
+            height: extent.height,
+            mip_levels: texture.mip_level_count(),
+            format: texture.format(),
+            bytes: texture.size(),
+            missing,
+        });
+    }
+
+    /// Add the instances of the scene model, `states` holding one state per
+    /// instance, counting the `lights` reaching the model's `bounds` at each.
+    pub fn add_instances(
+        &mut self,
+        instances: &[Instance],
+        states: &[InstanceState],
+        bounds: Option<Aabb>,
+        lights: &LightBufferManager,
+    ) {
+        for (index, (instance, state)) in instances.iter().zip(states).enumerate() {
+            let bounds = bounds.map(|bounds| bounds.transform(&instance.model_matrix()));
+            self.instances.push(InstanceInfo {
+                index,
+                position: instance.position.into(),
+                scale: instance.scale.into(),
+                layers: instance.layers,
+                outline: instance.outline,
+                state: *state,
+                lights: bounds.map(|bounds| lights.lights_affecting(&bounds).count()),
+            });
+        }
+    }
+
+    pub fn add_crowd(&mut self, name: String, model: &Model, instances: usize, motion: bool) {
+        self.add_model(&name, model);
+        self.crowds.push(CrowdInfo {
+            name,
+            instances,
+            motion,
+        });
+    }
+
+    /// Add the directional and positional lights stored in `lights`.
+    pub fn add_lights(&mut self, lights: &LightBufferManager) {
+        let tiles = lights.shadows.tiles();
+        let shadow_size = |id: LightId| tiles.iter().find(|tile| tile.light == id).map(|tile| tile.size);
+        for (id, light) in lights.directional_lights_by_id() {
+            self.lights.push(LightInfo {
+                id,
+                color: light.base.color,
+                position: None,
+                range: None,
+                active: lights.is_active(id),
+                shadow_size: shadow_size(id),
+                volumetric: light.volumetric.is_some_and(|v| v.density > 0.0),
+            });
+        }
+        for (id, light) in lights.lights() {
+            let volumetric = match light {
+                PositionalLight::Spot(spot) => spot.volumetric.is_some_and(|v| v.density > 0.0),
+                _ => false,
+            };
+            self.lights.push(LightInfo {
+                id,
+                color: light.color(),
+                position: Some(light.position().into()),
+                range: Some(light.range()),
+                active: lights.is_active(id),
+                shadow_size: shadow_size(id),
+                volumetric,
+            });
+        }
+        self.ambient_lights = lights.ambient_count;
+    }
+
+    pub fn add_point_clouds(&mut self, renderer: &PointCloudRenderer) {
+        for (index, cloud) in renderer.clouds.iter().enumerate() {
+            self.point_clouds.push(PointCloudInfo {
+                index,
+                points: cloud.point_count(),
+                bytes: cloud.buffer_size(),
+                visible: cloud.visible,
+            });
+        }
+    }
+
+    /// Bytes of all listed meshes, textures and point clouds.
+    pub fn listed_bytes(&self) -> u64 {
+        return self.footprints().map(|f| f.bytes).sum();
+    }
+
+    fn footprints(&self) -> impl Iterator<Item = Footprint> + '_ {
+        let meshes = self.meshes.iter().map(|m| Footprint {
+            label: format!("mesh {} {}", m.owner, m.name),
+            bytes: m.bytes(),
+        });
+        let textures = self.textures.iter().map(|t| Footprint {
+            label: format!("{} {} {} {}x{}", t.slot, t.owner, t.material, t.width, t.height),
+            bytes: t.bytes,
+        });
+        let clouds = self.point_clouds.iter().map(|c| Footprint {
+            label: format!("points {}", c.index),
+            bytes: c.bytes,
+        });
+        return meshes.chain(textures).chain(clouds);
+    }
+
+    /// The `count` largest meshes, textures and point clouds, largest first.
+    pub fn heaviest(&self, count: usize) -> Vec<Footprint> {
+        let mut footprints = self.footprints().collect::<Vec<_>>();
+        footprints.sort_by(|a, b| b.bytes.cmp(&a.bytes).then_with(|| a.label.cmp(&b.label)));
+        footprints.truncate(count);
+        return footprints;
+    }
+
+    pub fn to_json(&self) -> String {
+        let option = |value: Option<String>| value.unwrap_or_else(|| "null".to_string());
+        let meshes = self.meshes.iter().map(|m| {
+            format!(
+                "{{\"owner\":{},\"name\":{},\"vertexBytes\":{},\"indexBytes\":{},\"lightmapBytes\":{},\"triangles\":{},\"submeshes\":{}}}",
+                json_string(&m.owner),
+                json_string(&m.name),
+                m.vertex_bytes,
+                m.index_bytes,
+                m.lightmap_bytes,
+                m.triangles,
+                m.submeshes
+            )
+        });
+        let instances = self.instances.iter().map(|i| {
+            let level = match i.state {
+                InstanceState::Drawn { level } => level.to_string(),
+                _ => "null".to_string(),
+            };
+            let state = match i.state {
+                InstanceState::Drawn { .. } => "drawn",
+                InstanceState::Hidden => "hidden",
+                InstanceState::Culled => "culled",
+            };
+            format!(
+                "{{\"index\":{},\"position\":{},\"scale\":{},\"layers\":{},\"outline\":{},\"state\":\"{}\",\"lod\":{},\"lights\":{}}}",
+                i.index,
+                json_array(i.position.iter()),
+                json_array(i.scale.iter()),
+                i.layers.0,
+                i.outline,
+                state,
+                level,
+                option(i.lights.map(|l| l.to_string()))
+            )
+        });
+        let crowds = self.crowds.iter().map(|c| {
+            format!(
+                "{{\"name\":{},\"instances\":{},\"motion\":{}}}",
+                json_string(&c.name),
+                c.instances,
+                c.motion
+            )
+        });
+        let lights = self.lights.iter().map(|l| {
+            format!(
+                "{{\"kind\":\"{:?}\",\"index\":{},\"color\":{},\"position\":{},\"range\":{},\"active\":{},\"shadowSize\":{},\"volumetric\":{}}}",
+                l.id.kind,
+                l.id.index,
+                json_array(l.color.iter()),
+                option(l.position.map(|p| json_array(p.iter()))),
+                option(l.range.map(|r| r.to_string())),
+                l.active,
+                option(l.shadow_size.map(|s| s.to_string())),
+                l.volumetric
+            )
+        });
+        let textures = self.textures.iter().map(|t| {
+            format!(
+                "{{\"owner\":{},\"material\":{},\"slot\":\"{}\",\"source\":{},\"width\":{},\"height\":{},\"mipLevels\":{},\"format\":\"{:?}\",\"bytes\":{},\"missing\":{}}}",
+                json_string(&t.owner),
+                json_string(&t.material),
+                t.slot,
+                option(t.source.as_deref().map(json_string)),
+                t.width,
+                t.height,
+                t.mip_levels,
+                t.format,
+                t.bytes,
+                t.missing
+            )
+        });
+        let clouds = self.point_clouds.iter().map(|c| {
+            format!(
+                "{{\"index\":{},\"points\":{},\"bytes\":{},\"visible\":{}}}",
+                c.index, c.points, c.bytes, c.visible
+            )
+        });
+        let memory = &self.memory;
+
+        let mut json = format!("{{\"meshes\":[{}]", meshes.collect::<Vec<_>>().join(","));
+        let _ = write!(json, ",\"instances\":[{}]", instances.collect::<Vec<_>>().join(","));
+        let _ = write!(json, ",\"crowds\":[{}]", crowds.collect::<Vec<_>>().join(","));
+        let _ = write!(json, ",\"lights\":[{}]", lights.collect::<Vec<_>>().join(","));
+        let _ = write!(json, ",\"ambientLights\":{}", self.ambient_lights);
+        let _ = write!(json, ",\"textures\":[{}]", textures.collect::<Vec<_>>().join(","));
+        let _ = write!(json, ",\"pointClouds\":[{}]", clouds.collect::<Vec<_>>().join(","));
+        let _ = write!(
+            json,
+            ",\"memory\":{{\"meshes\":{},\"textures\":{},\"uniforms\":{},\"other\":{},\"total\":{},\"buffers\":{},\"textureCount\":{}}}}}",
+            memory.meshes,
+            memory.textures,
+            memory.uniforms,
+            memory.other,
+            memory.total(),
+            memory.buffer_count,
+            memory.texture_count
+        );
+        return json;
+    }
+
+    /// Queue a summary into `overlay` with its top left corner at `position`:
+    /// counts, tracked memory and the `rows` heaviest entries.
+    pub fn draw(&self, overlay: &mut Overlay, position: [f32; 2], rows: usize) {
+        let heaviest = self.heaviest(rows);
+        let missing = self.textures.iter().filter(|t| t.missing).count();
+        let (x, y) = (position[0], position[1]);
+        let line_count = 4 + heaviest.len() + (missing > 0) as usize;
+        let height = line_count as f32 * ROW_HEIGHT * 1.5 + PADDING * 2.0;
+        overlay.fill_rect([x, y], [x + Self::WIDTH, y + height], [0.0, 0.0, 0.0, 0.6]);
+
+        let drawn = self
+            .instances
+            .iter()
+            .filter(|i| matches!(i.state, InstanceState::Drawn { .. }))
+            .count();
+        let crowd_instances = self.crowds.iter().map(|c| c.instances).sum::<usize>();
+        let active_lights = self.lights.iter().filter(|l| l.active).count();
+        let memory = &self.memory;
+        let mut lines = vec![
+            (
+                format!("Meshes {}  Textures {}  Points {}", self.meshes.len(), self.textures.len(), self.point_clouds.len()),
+                TEXT_COLOR,
+            ),
+            (
+                format!(
+                    "Instances {}/{}  Crowd {}  Lights {}/{}",
+                    drawn,
+                    self.instances.len(),
+                    crowd_instances,
+                    active_lights,
+                    self.lights.len()
+                ),
+                TEXT_COLOR,
+            ),
+            (
+                format!(
+                    "Mesh {}  Tex {}",
+                    format_bytes(memory.meshes),
+                    format_bytes(memory.textures)
+                ),
+                TEXT_COLOR,
+            ),
+            (
+                format!(
+                    "Total {}  Listed {}",
+                    format_bytes(memory.total()),
+                    format_bytes(self.listed_bytes())
+                ),
+                TEXT_COLOR,
+            ),
+        ];
+        if missing > 0 {
+            lines.push((format!("{} missing textures", missing), MISSING_COLOR));
+        }
+        for footprint in &heaviest {
+            lines.push((format!("{}  {}", format_bytes(footprint.bytes), footprint.label), DIM_COLOR));
+        }
+
+        let mut row_y = y + PADDING;
+        for (text, color) in lines {
+            overlay.text([x + PADDING, row_y], &text, ROW_HEIGHT, color);
+            row_y += ROW_HEIGHT * 1.5;
+        }
+    }
+}
@@ -0,0 +1,85 @@
+// Per-instance vertex input in either `InstanceFormat`, select the compact
+// one by defining COMPACT_INSTANCES
+#ifdef COMPACT_INSTANCES
+struct InstanceInput {
+    // Unit quaternion, xyz vector part and w scalar part
+    @location(5) rotation: vec4<f32>,
+    // xyz: translation, w: LOD fade
+    @location(6) position_fade: vec4<f32>,
+    @location(7) scale: vec3<f32>,
+};
+
+fn quaternion_to_matrix(q: vec4<f32>) -> mat3x3<f32> {
+    let x2 = q.x + q.x;
+    let y2 = q.y + q.y;
+    let z2 = q.z + q.z;
+    let xx = q.x * x2;
+    let yy = q.y * y2;
+    let zz = q.z * z2;
+    let xy = q.x * y2;
+    let xz = q.x * z2;
+    let yz = q.y * z2;
+    let wx = q.w * x2;
+    let wy = q.w * y2;
+    let wz = q.w * z2;
+    return mat3x3<f32>(
+        vec3<f32>(1.0 - (yy + zz), xy + wz, xz - wy),
+        vec3<f32>(xy - wz, 1.0 - (xx + zz), yz + wx),
+        vec3<f32>(xz + wy, yz - wx, 1.0 - (xx + yy)),
+    );
+}
+#else
+struct InstanceInput {
+    @location(5) model_matrix_0: vec4<f32>,
+    @location(6) model_matrix_1: vec4<f32>,
+    @location(7) model_matrix_2: vec4<f32>,
+    @location(8) model_matrix_3: vec4<f32>,
+    @location(9) normal_matrix_0: vec4<f32>,
+    @location(10) normal_matrix_1: vec4<f32>,
+    // w: LOD fade
+    @location(11) normal_matrix_2: vec4<f32>
+};
+#endif
+
+struct InstanceTransform {
+    model: mat4x4<f32>,
+    normal: mat3x3<f32>,
+    // LOD cross-fade: positive covers this fraction of the pixels, negative
+    // the pixels left by the same positive fade
+    fade: f32,
+};
+
+fn instance_transform(instance: InstanceInput) -> InstanceTransform {
+    var out: InstanceTransform;
+#ifdef COMPACT_INSTANCES
+    let rotation = quaternion_to_matrix(instance.rotation);
+    let scale = instance.scale;
+    out.model = mat4x4<f32>(
+        vec4<f32>(rotation[0] * scale.x, 0.0),
+        vec4<f32>(rotation[1] * scale.y, 0.0),
+        vec4<f32>(rotation[2] * scale.z, 0.0),
+        vec4<f32>(instance.position_fade.xyz, 1.0),
+    );
+    // Inverse transpose of rotation times scale
+    out.normal = mat3x3<f32>(
+        rotation[0] * (1.0 / scale.x),
+        rotation[1] * (1.0 / scale.y),
+        rotation[2] * (1.0 / scale.z),
+    );
+    out.fade = instance.position_fade.w;
+#else
+    out.model = mat4x4<f32>(
+        instance.model_matrix_0,
+        instance.model_matrix_1,
+        instance.model_matrix_2,
+        instance.model_matrix_3,
+    );
+    out.normal = mat3x3<f32>(
+        instance.normal_matrix_0.xyz,
+        instance.normal_matrix_1.xyz,
+        instance.normal_matrix_2.xyz
+    );
+    out.fade = instance.normal_matrix_2.w;
+#endif
+    return out;
+}
@@ -0,0 +1,38 @@
+/// Bitmask of up to 32 render layers. An instance is drawn by a camera when
+/// their layers intersect, e.g. to keep editor helpers or first-person arms
+/// out of some views.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+pub struct RenderLayers(pub u32);
+
+impl RenderLayers {
+    pub const NONE: RenderLayers = RenderLayers(0);
+    pub const ALL: RenderLayers = RenderLayers(u32::MAX);
+    // Layer everything starts on
+    pub const DEFAULT: RenderLayers = RenderLayers::layer(0);
+
+    pub const fn layer(layer: u32) -> Self {
+        return RenderLayers(1 << layer);
+    }
+
+    pub const fn with(self, layer: u32) -> Self {
+        return RenderLayers(self.0 | (1 << layer));
+    }
+
+    pub const fn without(self, layer: u32) -> Self {
+        return RenderLayers(self.0 & !(1 << layer));
+    }
+
+    pub const fn contains(&self, layer: u32) -> bool {
+        return self.0 & (1 << layer) != 0;
+    }
+
+    pub const fn intersects(&self, other: RenderLayers) -> bool {
+        return self.0 & other.0 != 0;
+    }
+}
+
+impl Default for RenderLayers {
+    fn default() -> Self {
+        return RenderLayers::DEFAULT;
+    }
+}
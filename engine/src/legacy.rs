@@ -0,0 +1,176 @@
+use anyhow::*;
+use image::{Rgba, RgbaImage};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    material::MaterialParams,
+    texture::{linear_to_srgb, srgb_to_linear},
+};
+
+/// Reflectance of dielectrics at normal incidence, the specular color
+/// metallic/roughness materials assume when not metallic.
+pub const DIELECTRIC_SPECULAR: f32 = 0.04;
+
+/// Diffuse/specular (Blinn-Phong) values of an older asset, e.g. an MTL
+/// material, in linear RGB.
+#[derive(Debug, Copy, Clone, PartialEq, Serialize, Deserialize)]
+pub struct LegacyMaterial {
+    pub diffuse: [f32; 3],
+    pub specular: [f32; 3],
+    /// Blinn-Phong exponent, 1 to 1024.
+    pub shininess: f32,
+}
+
+impl Default for LegacyMaterial {
+    fn default() -> Self {
+        Self {
+            diffuse: [0.8; 3],
+            specular: [DIELECTRIC_SPECULAR; 3],
+            shininess: 32.0,
+        }
+    }
+}
+
+impl From<&tobj::Material> for LegacyMaterial {
+    fn from(material: &tobj::Material) -> Self {
+        return Self {
+            diffuse: material.diffuse,
+            specular: material.specular,
+            shininess: material.shininess,
+        };
+    }
+}
+
+/// Metallic/roughness values approximating a `LegacyMaterial`.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct MetallicRoughness {
+    /// Linear RGB, the diffuse albedo where not metallic and the specular
+    /// color where metallic.
+    pub base_color: [f32; 3],
+    pub metallic: f32,
+    pub roughness: f32,
+}
+
+impl MetallicRoughness {
+    /// Diffuse albedo, what's left of the base color after metals reflect it.
+    pub fn diffuse(&self) -> [f32; 3] {
+        return self.base_color.map(|c| c * (1.0 - self.metallic));
+    }
+
+    /// Set the basic shader's `tint` to the diffuse albedo and its
+    /// `roughness`. Its lighting has no metallic term, the rest is left to
+    /// the maps of `convert_textures`.
+    pub fn apply(&self, params: &mut MaterialParams) -> Result<()> {
+        let [r, g, b] = self.diffuse();
+        params.set("tint", [r, g, b, 1.0])?;
+        params.set("roughness", self.roughness)?;
+        return Ok(());
+    }
+}
+
+impl LegacyMaterial {
+    /// Solve for the metalness that reproduces both the diffuse and the
+    /// specular brightness, as the glTF specular-glossiness converters do,
+    /// and the roughness the basic shader turns back into `shininess`.
+    pub fn to_metallic_roughness(&self) -> MetallicRoughness {
+        return convert(self.diffuse, self.specular, shininess_to_roughness(self.shininess));
+    }
+}
+
+/// Roughness the basic shader's `exp2(10 * (1 - roughness))` maps to a
+/// Blinn-Phong `shininess`.
+pub fn shininess_to_roughness(shininess: f32) -> f32 {
+    return (1.0 - shininess.max(1.0).log2() / 10.0).clamp(0.0, 1.0);
+}
+
+fn convert(diffuse: [f32; 3], specular: [f32; 3], roughness: f32) -> MetallicRoughness {
+    let one_minus_specular = 1.0 - specular.iter().cloned().fold(0.0, f32::max);
+    let metallic = solve_metallic(brightness(diffuse), brightness(specular), one_minus_specular);
+
+    // Base color blended from what the diffuse and what the specular color
+    // imply, leaning on the specular as the surface gets metallic
+    let from_diffuse = diffuse.map(|c| c * one_minus_specular / (1.0 - DIELECTRIC_SPECULAR) / (1.0 - metallic).max(1e-4));
+    let from_specular = specular.map(|c| (c - DIELECTRIC_SPECULAR * (1.0 - metallic)) / metallic.max(1e-4));
+    let blend = metallic * metallic;
+    let base_color = [0, 1, 2].map(|i| (from_diffuse[i] + (from_specular[i] - from_diffuse[i]) * blend).clamp(0.0, 1.0));
+    return MetallicRoughness {
+        base_color,
+        metallic,
+        roughness,
+    };
+}
+
+// Perceived brightness of a linear color
+fn brightness([r, g, b]: [f32; 3]) -> f32 {
+    return (0.299 * r * r + 0.587 * g * g + 0.114 * b * b).sqrt();
+}
+
+// Root of the quadratic relating diffuse and specular brightness to metalness
+fn solve_metallic(diffuse: f32, specular: f32, one_minus_specular: f32) -> f32 {
+    if specular < DIELECTRIC_SPECULAR {
+        return 0.0;
+    }
+    let a = DIELECTRIC_SPECULAR;
+    let b = diffuse * one_minus_specular / (1.0 - a) + specular - 2.0 * a;
+    let c = a - specular;
+    let discriminant = (b * b - 4.0 * a * c).max(0.0);
+    return ((-b + discriminant.sqrt()) / (2.0 * a)).clamp(0.0, 1.0);
+}
+
+/// Textures for a metallic/roughness pipeline, laid out like glTF's.
+pub struct MetallicRoughnessMaps {
+    /// sRGB, alpha carried over from the diffuse map.
+    pub base_color: RgbaImage,
+    /// Roughness in green and metalness in blue, linear.
+    pub metallic_roughness: RgbaImage,
+}
+
+/// Convert legacy maps texel by texel at import, so older assets need no
+/// re-authoring. `diffuse` and `specular` are sRGB, the specular map and
+/// `shininess_map` (scaling `material.shininess` by its red channel) are
+/// sampled to the diffuse map's size. Without a map the material's value
+/// is used, the diffuse map multiplies its color like MTL's `map_Kd`.
+pub fn convert_textures(
+    material: &LegacyMaterial,
+    diffuse: &RgbaImage,
+    specular: Option<&RgbaImage>,
+    shininess_map: Option<&RgbaImage>,
+) -> MetallicRoughnessMaps {
+    let (width, height) = diffuse.dimensions();
+    // Nearest texel of `map` for a texel of the diffuse map
+    let sample = |map: &RgbaImage, x: u32, y: u32| -> Rgba<u8> {
+        let u = (x * map.width() / width).min(map.width() - 1);
+        let v = (y * map.height() / height).min(map.height() - 1);
+        return *map.get_pixel(u, v);
+    };
+    let linear = |texel: Rgba<u8>| [0, 1, 2].map(|i| srgb_to_linear(texel[i] as f32 / 255.0));
+
+    let mut base_color = RgbaImage::new(width, height);
+    let mut metallic_roughness = RgbaImage::new(width, height);
+    for (x, y, texel) in diffuse.enumerate_pixels() {
+        let texel_diffuse = linear(*texel);
+        let diffuse_color = [0, 1, 2].map(|i| texel_diffuse[i] * material.diffuse[i]);
+        let specular_color = match specular {
+            Some(map) => {
+                let texel_specular = linear(sample(map, x, y));
+                [0, 1, 2].map(|i| texel_specular[i] * material.specular[i])
+            }
+            None => material.specular,
+        };
+        let shininess = match shininess_map {
+            Some(map) => material.shininess * sample(map, x, y)[0] as f32 / 255.0,
+            None => material.shininess,
+        };
+
+        let converted = convert(diffuse_color, specular_color, shininess_to_roughness(shininess));
+        let [r, g, b] = converted.base_color.map(|c| (linear_to_srgb(c) * 255.0).round() as u8);
+        base_color.put_pixel(x, y, Rgba([r, g, b, texel[3]]));
+        let roughness = (converted.roughness * 255.0).round() as u8;
+        let metallic = (converted.metallic * 255.0).round() as u8;
+        metallic_roughness.put_pixel(x, y, Rgba([0, roughness, metallic, 255]));
+    }
+    return MetallicRoughnessMaps {
+        base_color,
+        metallic_roughness,
+    };
+}
@@ -1,20 +1,101 @@
+pub mod animation;
+pub mod atmosphere;
+pub mod background;
+pub mod batch;
+pub mod boids;
 pub mod camera;
+pub mod capture;
+pub mod collision;
+pub mod compress;
+pub mod console;
 mod controller;
-mod renderer;
-mod resources;
-mod texture;
-mod model;
+pub mod cubemap;
+pub mod debug;
+pub mod decode;
+pub mod depth_probe;
+pub mod environment;
+pub mod events;
+pub mod flare;
+pub mod frame;
+pub mod geometry;
+pub mod gizmo;
+pub mod golden;
+pub mod gltf;
+pub mod hud;
+pub mod input;
+pub mod inspector;
+pub mod renderer;
+pub mod resources;
+pub mod scene;
+pub mod settings;
+pub mod shader;
+pub mod shadow;
+pub mod simulation;
+pub mod skinning;
+pub mod tangents;
+pub mod texture;
+pub mod vat;
+pub mod viewport;
+pub mod volumetric;
+pub mod model;
+pub mod overlay;
+pub mod particles;
+pub mod picking;
+pub mod preferences;
+pub mod layers;
+pub mod legacy;
 pub mod light;
+pub mod lightmap;
+pub mod loading;
+pub mod lod;
+pub mod luminance;
+pub mod material;
+pub mod math;
+pub mod measure;
+pub mod memory;
+pub mod motion;
+pub mod oit;
+pub mod overdraw;
+pub mod pipelines;
+pub mod placement;
+pub mod point_cloud;
+pub mod portal;
+pub mod post;
+pub mod target;
+pub mod trail;
+#[cfg(feature = "virtual-texturing")]
+pub mod virtual_texture;
+#[cfg(feature = "xr")]
+pub mod xr;
+#[cfg(feature = "gamepad")]
+pub mod gamepad;
 
-use controller::{Controller, ControllerEvent};
+use controller::ControllerEvent;
+use hud::FrameStage;
+use preferences::PreferenceStore;
 use renderer::Renderer;
+use settings::{InputMode, RedrawMode, Settings};
 use winit::{
-    dpi::PhysicalPosition,
-    event::{DeviceEvent, Event, KeyboardInput, MouseScrollDelta, WindowEvent},
+    event::{Event, WindowEvent},
     event_loop::{ControlFlow, EventLoop},
-    window::WindowBuilder,
+    window::{CursorGrabMode, Window, WindowBuilder},
 };
 
+// Hide and confine the cursor for mouse look, release it for UI interaction
+fn apply_input_mode(window: &Window, mode: InputMode) {
+    let result = match mode {
+        // Some platforms can only lock the cursor in place
+        InputMode::GameLook => window
+            .set_cursor_grab(CursorGrabMode::Confined)
+            .or_else(|_| window.set_cursor_grab(CursorGrabMode::Locked)),
+        InputMode::UIInteract => window.set_cursor_grab(CursorGrabMode::None),
+    };
+    if let Err(e) = result {
+        log::warn!("Failed to change the cursor grab: {}", e);
+    }
+    window.set_cursor_visible(mode == InputMode::UIInteract);
+}
+
 pub async fn run() {
     env_logger::init();
 
@@ -23,19 +104,55 @@ pub async fn run() {
         .build(&event_loop)
         .expect("Failed to create window");
 
-    let mut renderer = Renderer::new(&window).await;
+    // Saved options go into the settings before anything is built with them
+    let preferences = PreferenceStore::open("wgpu-test");
+    let mut settings = Settings::default();
+    preferences.get().apply(&mut settings);
+    let mut renderer = Renderer::with_settings(&window, settings).await;
+    renderer.set_preference_store(preferences);
 
     let mut last_render_time = std::time::Instant::now();
+    let mut focused = true;
+    // Set by anything that may change the picture, used in on-demand mode
+    let mut redraw_pending = true;
+    let mut idle = false;
+    // Mode the cursor was last set up for, `None` to set it up again
+    let mut applied_mode = None;
+    #[cfg(feature = "gamepad")]
+    let mut gamepads = gamepad::Gamepads::new()
+        .map_err(|e| log::warn!("{:?}", e))
+        .ok();
     event_loop.run(move |event, _, control_flow| {
-        *control_flow = ControlFlow::Poll;
-        if !renderer.input(&event) {
+        match &event {
+            Event::WindowEvent { event, .. } => {
+                if let WindowEvent::Focused(f) = event {
+                    focused = *f;
+                    // Grabs are released while unfocused on some platforms
+                    applied_mode = None;
+                }
+                redraw_pending = true;
+            }
+            Event::DeviceEvent { .. } if focused => redraw_pending = true,
+            _ => {}
+        }
+        let events_cleared = matches!(event, Event::MainEventsCleared);
+
+        let input_start = std::time::Instant::now();
+        let handled = renderer.input(&event);
+        renderer.hud.record(FrameStage::Input, input_start.elapsed());
+
+        if focused && applied_mode != Some(renderer.input_mode()) {
+            applied_mode = Some(renderer.input_mode());
+            apply_input_mode(&window, renderer.input_mode());
+        }
+
+        if !handled {
+            // Camera input goes to the controller, everything else that
+            // reacts to the window subscribes to `renderer.events`
+            if let Some(input) = ControllerEvent::from_event(&event, renderer.input_mode()) {
+                renderer.simulator.input(input);
+            }
             match event {
-                Event::DeviceEvent { event, .. } => match event {
-                    DeviceEvent::MouseMotion { delta } => {
-                        renderer.camera.input(ControllerEvent::MouseMove(delta))
-                    }
-                    _ => {}
-                },
                 Event::WindowEvent { window_id, event } if window_id == window.id() => {
                     match event {
                         WindowEvent::CloseRequested => *control_flow = ControlFlow::Exit,
@@ -43,49 +160,95 @@ pub async fn run() {
                         WindowEvent::ScaleFactorChanged { new_inner_size, .. } => {
                             renderer.resize(*new_inner_size)
                         }
-                        WindowEvent::KeyboardInput {
-                            input:
-                                KeyboardInput {
-                                    state,
-                                    virtual_keycode: Some(key),
-                                    ..
-                                },
-                            ..
-                        } => renderer
-                            .camera
-                            .input(ControllerEvent::KeyboardInput(state, key)),
-                        WindowEvent::MouseInput { state, button, .. } => renderer
-                            .camera
-                            .input(ControllerEvent::MouseInput(state, button)),
-                        WindowEvent::MouseWheel { delta, .. } => {
-                            renderer
-                                .camera
-                                .input(ControllerEvent::MouseScroll(match delta {
-                                    MouseScrollDelta::LineDelta(_, scroll) => scroll * 100.0,
-                                    MouseScrollDelta::PixelDelta(PhysicalPosition {
-                                        y: scroll,
-                                        ..
-                                    }) => scroll as f32,
-                                }))
-                        }
                         _ => {}
                     }
                 }
+                Event::RedrawRequested(window_id)
+                    if window_id == window.id() && renderer.is_capturing() =>
+                {
+                    last_render_time = std::time::Instant::now();
+                    redraw_pending = false;
+                    if let Err(e) = renderer.capture_frame() {
+                        log::error!("{:?}", e);
+                    }
+                }
                 Event::RedrawRequested(window_id) if window_id == window.id() => {
                     let now = std::time::Instant::now();
-                    let dt = now - last_render_time;
+                    // Time spent sleeping must not be simulated in one step
+                    let dt = if idle {
+                        std::time::Duration::ZERO
+                    } else {
+                        now - last_render_time
+                    };
                     last_render_time = now;
+                    idle = false;
+                    redraw_pending = false;
                     renderer.update(dt);
                     match renderer.render() {
                         Ok(_) => {}
-                        Err(wgpu::SurfaceError::Lost) => renderer.resize(renderer.size),
+                        // Outdated during resize storms until the last size is applied
+                        Err(wgpu::SurfaceError::Lost | wgpu::SurfaceError::Outdated) => renderer.resize(renderer.size),
                         Err(wgpu::SurfaceError::OutOfMemory) => *control_flow = ControlFlow::Exit,
                         Err(e) => eprintln!("{:?}", e),
                     }
                 }
-                Event::MainEventsCleared => window.request_redraw(),
                 _ => {}
             }
         }
+
+        if events_cleared {
+            if matches!(*control_flow, ControlFlow::ExitWithCode(_)) {
+                return;
+            }
+            #[cfg(feature = "gamepad")]
+            if let Some(gamepads) = &mut gamepads {
+                if gamepads.poll(&mut renderer) {
+                    redraw_pending = true;
+                }
+            }
+
+            let frame = renderer.settings.frame;
+            // Captures run as fast as frames can be written, focused or not,
+            // other frames wait for a minimized window to be restored
+            let capturing = renderer.is_capturing();
+            let wants_frame = capturing
+                || (!renderer.is_minimized()
+                    && match frame.redraw_mode {
+                        RedrawMode::Continuous => true,
+                        RedrawMode::OnDemand => redraw_pending || renderer.is_animating(),
+                    });
+            let throttle = frame.background_fps.filter(|_| !focused && !capturing);
+            let cap = frame.target_fps.filter(|_| focused && !capturing);
+
+            if !wants_frame {
+                idle = true;
+                *control_flow = ControlFlow::Wait;
+                #[cfg(feature = "gamepad")]
+                if gamepads.is_some() {
+                    *control_flow = ControlFlow::WaitUntil(std::time::Instant::now() + gamepad::IDLE_POLL);
+                }
+            } else if let Some(fps) = throttle {
+                let next = last_render_time + std::time::Duration::from_secs_f32(1.0 / fps.max(0.1));
+                if std::time::Instant::now() >= next {
+                    window.request_redraw();
+                }
+                *control_flow = ControlFlow::WaitUntil(next);
+            } else if let Some(fps) = cap {
+                // Sleep in the event loop until shortly before the frame is
+                // due, it wakes up too late for high rates, and spin the rest
+                let next = last_render_time + std::time::Duration::from_secs_f32(1.0 / fps.max(1.0));
+                let wake = next - crate::frame::SPIN_MARGIN;
+                if std::time::Instant::now() < wake {
+                    *control_flow = ControlFlow::WaitUntil(wake);
+                } else {
+                    crate::frame::spin_until(next);
+                    *control_flow = ControlFlow::Poll;
+                    window.request_redraw();
+                }
+            } else {
+                *control_flow = ControlFlow::Poll;
+                window.request_redraw();
+            }
+        }
     });
 }
@@ -1,19 +1,34 @@
 use std::mem::size_of;
 
 use cgmath::Angle;
-use wgpu::util::DeviceExt;
 
+use crate::{
+    atmosphere::Atmosphere,
+    environment::Environment,
+    math::Aabb,
+    memory::{MemoryCategory, MemoryTracker, TrackedBuffer},
+    shadow::{ShadowAtlas, ShadowCaster, ShadowSettings, ShadowUniform, ShadowView, MAX_SHADOWS},
+    volumetric::VolumetricSettings,
+};
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
 pub enum LightKind {
     Ambient,
     Directional,
     Point,
     Spot,
+    Area,
 }
 
 pub const MAX_AMBIENT_LIGHTS: usize = 1;
 pub const MAX_DIRECTIONAL_LIGHTS: usize = 10;
 pub const MAX_POINT_LIGHTS: usize = 256;
 pub const MAX_SPOT_LIGHTS: usize = 256;
+pub const MAX_AREA_LIGHTS: usize = 64;
+
+/// Intensity below which a light is considered to no longer contribute,
+/// used to derive ranges from attenuation.
+pub const LIGHT_CUTOFF: f32 = 1.0 / 256.0;
 #[repr(C)]
 #[derive(Debug, Copy, Clone, bytemuck::Zeroable, bytemuck::Pod)]
 struct LightBuffer {
@@ -21,7 +36,9 @@ struct LightBuffer {
     pub dir_uniforms: [DirectionalLightUniform; MAX_DIRECTIONAL_LIGHTS],
     pub point_uniforms: [PointLightUniform; MAX_POINT_LIGHTS],
     pub spot_uniforms: [SpotLightUniform; MAX_SPOT_LIGHTS],
-    pub uniform_lens: [u32; 4],
+    pub area_uniforms: [AreaLightUniform; MAX_AREA_LIGHTS],
+    pub uniform_lens: [[u32; 4]; 2],
+    pub shadow_uniforms: [ShadowUniform; MAX_SHADOWS],
 }
 
 impl Default for LightBuffer {
@@ -31,71 +48,153 @@ impl Default for LightBuffer {
             dir_uniforms: [DirectionalLightUniform::default(); MAX_DIRECTIONAL_LIGHTS],
             point_uniforms: [PointLightUniform::default(); MAX_POINT_LIGHTS],
             spot_uniforms: [SpotLightUniform::default(); MAX_SPOT_LIGHTS],
-            uniform_lens: [0; 4],
+            area_uniforms: [AreaLightUniform::default(); MAX_AREA_LIGHTS],
+            uniform_lens: [[0; 4]; 2],
+            shadow_uniforms: [ShadowUniform::default(); MAX_SHADOWS],
         }
     }
 }
 
+/// Slot of a light in the light buffer.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+pub struct LightId {
+    pub kind: LightKind,
+    pub index: usize,
+}
+
 pub struct LightBufferManager {
-    light_buffer: wgpu::Buffer,
+    light_buffer: TrackedBuffer,
+    // CPU copies of lights that can be inspected and edited after upload
+    positional: Vec<(LightId, PositionalLight)>,
+    directional: Vec<(usize, DirectionalLight)>,
+    // Lights given a shadow tile by the last `update_shadows`
+    shadowed: Vec<LightId>,
+    pub shadows: ShadowAtlas,
     pub ambient_count: u32,
     pub directional_count: u32,
     pub point_count: u32,
     pub spot_count: u32,
+    pub area_count: u32,
     pub light_bind_group: wgpu::BindGroup,
     pub light_bind_group_layout: wgpu::BindGroupLayout,
 }
 
 impl LightBufferManager {
-    fn create_buffer(device: &wgpu::Device, label: &str, data: &[u8]) -> wgpu::Buffer {
-        return device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
-            label: Some(label),
-            contents: data,
-            usage: wgpu::BufferUsages::UNIFORM | wgpu::BufferUsages::COPY_DST,
-        });
+    fn create_buffer(
+        device: &wgpu::Device,
+        memory: &MemoryTracker,
+        label: &str,
+        data: &[u8],
+    ) -> TrackedBuffer {
+        return memory.create_buffer_init(
+            device,
+            &wgpu::util::BufferInitDescriptor {
+                label: Some(label),
+                contents: data,
+                usage: wgpu::BufferUsages::UNIFORM | wgpu::BufferUsages::COPY_DST,
+            },
+            MemoryCategory::Uniform,
+        );
     }
 
-    pub fn new(device: &wgpu::Device) -> Self {
+    /// Binding of the first environment entry in the light bind group.
+    pub const ENVIRONMENT_BINDING: u32 = 1;
+    /// Binding of the shadow atlas in the light bind group.
+    pub const SHADOW_BINDING: u32 = Self::ENVIRONMENT_BINDING + 5;
+    /// Binding of the first atmosphere entry in the light bind group.
+    pub const ATMOSPHERE_BINDING: u32 = Self::SHADOW_BINDING + 2;
+
+    pub fn new(
+        device: &wgpu::Device,
+        memory: &MemoryTracker,
+        environment: &Environment,
+        atmosphere: &Atmosphere,
+    ) -> Self {
         let light_buffer_data = LightBuffer::default();
         let light_buffer = LightBufferManager::create_buffer(
             device,
+            memory,
             "Light Buffer",
             bytemuck::cast_slice(&[light_buffer_data]),
         );
 
+        let mut entries = vec![wgpu::BindGroupLayoutEntry {
+            binding: 0,
+            visibility: wgpu::ShaderStages::VERTEX | wgpu::ShaderStages::FRAGMENT,
+            ty: wgpu::BindingType::Buffer {
+                ty: wgpu::BufferBindingType::Uniform,
+                has_dynamic_offset: false,
+                min_binding_size: None,
+            },
+            count: None,
+        }];
+        entries.extend(Environment::layout_entries(Self::ENVIRONMENT_BINDING));
+        entries.extend(ShadowAtlas::layout_entries(Self::SHADOW_BINDING));
+        entries.extend(Atmosphere::layout_entries(Self::ATMOSPHERE_BINDING));
         let light_bind_group_layout =
             device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
-                entries: &[wgpu::BindGroupLayoutEntry {
-                    binding: 0,
-                    visibility: wgpu::ShaderStages::VERTEX | wgpu::ShaderStages::FRAGMENT,
-                    ty: wgpu::BindingType::Buffer {
-                        ty: wgpu::BufferBindingType::Uniform,
-                        has_dynamic_offset: false,
-                        min_binding_size: None,
-                    },
-                    count: None,
-                }],
+                entries: &entries,
                 label: Some("light_bind_group_layout"),
             });
-        let light_bind_group = device.create_bind_group(&wgpu::BindGroupDescriptor {
-            layout: &light_bind_group_layout,
-            entries: &[wgpu::BindGroupEntry {
-                binding: 0,
-                resource: light_buffer.as_entire_binding(),
-            }],
-            label: Some("light_bind_group"),
-        });
+        let shadows = ShadowAtlas::new(device, memory);
+        let light_bind_group = Self::create_bind_group(
+            device,
+            &light_bind_group_layout,
+            &light_buffer,
+            environment,
+            atmosphere,
+            &shadows,
+        );
         Self {
             ambient_count: 0,
             directional_count: 0,
             point_count: 0,
             spot_count: 0,
+            area_count: 0,
             light_buffer,
+            positional: Vec::new(),
+            directional: Vec::new(),
+            shadowed: Vec::new(),
+            shadows,
             light_bind_group,
             light_bind_group_layout,
         }
     }
 
+    fn create_bind_group(
+        device: &wgpu::Device,
+        layout: &wgpu::BindGroupLayout,
+        light_buffer: &TrackedBuffer,
+        environment: &Environment,
+        atmosphere: &Atmosphere,
+        shadows: &ShadowAtlas,
+    ) -> wgpu::BindGroup {
+        let mut entries = vec![wgpu::BindGroupEntry {
+            binding: 0,
+            resource: light_buffer.as_entire_binding(),
+        }];
+        entries.extend(environment.bind_entries(Self::ENVIRONMENT_BINDING));
+        entries.extend(shadows.bind_entries(Self::SHADOW_BINDING));
+        entries.extend(atmosphere.bind_entries(Self::ATMOSPHERE_BINDING));
+        return device.create_bind_group(&wgpu::BindGroupDescriptor {
+            layout,
+            entries: &entries,
+            label: Some("light_bind_group"),
+        });
+    }
+
+    /// Light the scene with `environment` from now on.
+    pub fn set_environment(&mut self, device: &wgpu::Device, environment: &Environment, atmosphere: &Atmosphere) {
+        self.light_bind_group = Self::create_bind_group(
+            device,
+            &self.light_bind_group_layout,
+            &self.light_buffer,
+            environment,
+            atmosphere,
+            &self.shadows,
+        );
+    }
+
     const fn calculate_buffer_offset(&self, kind: &LightKind, index: usize) -> usize {
         return match kind {
             LightKind::Ambient => size_of::<[f32; 4]>() * index,
@@ -114,9 +213,20 @@ impl LightBufferManager {
                     + size_of::<[PointLightUniform; MAX_POINT_LIGHTS]>()
                     + size_of::<SpotLightUniform>() * index
             }
+            LightKind::Area => {
+                size_of::<[[f32; 4]; MAX_AMBIENT_LIGHTS]>()
+                    + size_of::<[DirectionalLightUniform; MAX_DIRECTIONAL_LIGHTS]>()
+                    + size_of::<[PointLightUniform; MAX_POINT_LIGHTS]>()
+                    + size_of::<[SpotLightUniform; MAX_SPOT_LIGHTS]>()
+                    + size_of::<AreaLightUniform>() * index
+            }
         };
     }
 
+    const fn shadow_buffer_offset(&self) -> usize {
+        return self.calculate_buffer_offset(&LightKind::Area, MAX_AREA_LIGHTS) + size_of::<[[u32; 4]; 2]>();
+    }
+
     pub fn update_light_buffer<L>(
         &self,
         queue: &wgpu::Queue,
@@ -130,9 +240,216 @@ impl LightBufferManager {
         queue.write_buffer(&self.light_buffer, offset as _, &light.buffer_data());
     }
 
+    /// Upload a light and keep a copy, so it can be picked and edited later.
+    pub fn set_light(&mut self, queue: &wgpu::Queue, index: usize, light: PositionalLight) {
+        let id = LightId {
+            kind: light.kind(),
+            index,
+        };
+        self.write_positional(queue, id, &light);
+        match self.positional.iter_mut().find(|(i, _)| *i == id) {
+            Some((_, existing)) => *existing = light,
+            None => self.positional.push((id, light)),
+        }
+    }
+
+    /// Upload a directional light and keep a copy, e.g. for the lens flare.
+    pub fn set_directional_light(&mut self, queue: &wgpu::Queue, index: usize, light: DirectionalLight) {
+        self.update_light_buffer(queue, LightKind::Directional, index, &light);
+        match self.directional.iter_mut().find(|(i, _)| *i == index) {
+            Some((_, existing)) => *existing = light,
+            None => self.directional.push((index, light)),
+        }
+    }
+
+    pub fn directional_lights(&self) -> impl Iterator<Item = &DirectionalLight> {
+        return self.directional.iter().map(|(_, light)| light);
+    }
+
+    /// Directional lights with their slot in the light buffer.
+    pub fn directional_lights_by_id(&self) -> impl Iterator<Item = (LightId, &DirectionalLight)> {
+        return self.directional.iter().map(|(index, light)| {
+            let id = LightId {
+                kind: LightKind::Directional,
+                index: *index,
+            };
+            return (id, light);
+        });
+    }
+
+    /// Whether the light's slot is below the uploaded count of its kind, see
+    /// `update_light_counts`. Lights past it aren't drawn.
+    pub fn is_active(&self, id: LightId) -> bool {
+        let count = match id.kind {
+            LightKind::Ambient => self.ambient_count,
+            LightKind::Directional => self.directional_count,
+            LightKind::Point => self.point_count,
+            LightKind::Spot => self.spot_count,
+            LightKind::Area => self.area_count,
+        };
+        return id.index < count as usize;
+    }
+
+    /// Directional and spot lights that scatter light, in buffer order.
+    pub fn volumetric_lights(&self) -> impl Iterator<Item = (LightId, VolumetricSettings)> + '_ {
+        let directional = self
+            .directional
+            .iter()
+            .filter(|(index, _)| *index < self.directional_count as usize)
+            .filter_map(|(index, light)| {
+                let id = LightId {
+                    kind: LightKind::Directional,
+                    index: *index,
+                };
+                return light.volumetric.map(|settings| (id, settings));
+            });
+        let spots = self
+            .positional
+            .iter()
+            .filter(|(id, _)| id.index < self.spot_count as usize)
+            .filter_map(|(id, light)| match light {
+                PositionalLight::Spot(spot) => spot.volumetric.map(|settings| (*id, settings)),
+                _ => None,
+            });
+        return directional
+            .chain(spots)
+            .filter(|(_, settings)| settings.density > 0.0);
+    }
+
+    pub fn lights(&self) -> impl Iterator<Item = (LightId, &PositionalLight)> {
+        return self.positional.iter().map(|(id, light)| (*id, light));
+    }
+
+    pub fn light(&self, id: LightId) -> Option<&PositionalLight> {
+        return self.positional.iter().find(|(i, _)| *i == id).map(|(_, l)| l);
+    }
+
+    /// Stored lights whose range reaches into `bounds`.
+    pub fn lights_affecting<'a>(&'a self, bounds: &'a Aabb) -> impl Iterator<Item = LightId> + 'a {
+        return self
+            .lights()
+            .filter(|(_, light)| light.affects(bounds))
+            .map(|(id, _)| id);
+    }
+
+    /// Modify a stored light and write it back to the GPU immediately.
+    pub fn edit_light<F: FnOnce(&mut PositionalLight)>(&mut self, queue: &wgpu::Queue, id: LightId, f: F) -> bool {
+        let offset = self.calculate_buffer_offset(&id.kind, id.index);
+        let light = match self.positional.iter_mut().find(|(i, _)| *i == id) {
+            Some((_, light)) => light,
+            None => return false,
+        };
+        f(light);
+        let data = match &*light {
+            PositionalLight::Point(l) => l.buffer_data(),
+            PositionalLight::Spot(l) => l.buffer_data(),
+            PositionalLight::Area(l) => l.buffer_data(),
+        };
+        queue.write_buffer(&self.light_buffer, offset as _, &data);
+        return true;
+    }
+
+    fn write_positional(&self, queue: &wgpu::Queue, id: LightId, light: &PositionalLight) {
+        match light {
+            PositionalLight::Point(l) => self.update_light_buffer(queue, id.kind, id.index, l),
+            PositionalLight::Spot(l) => self.update_light_buffer(queue, id.kind, id.index, l),
+            PositionalLight::Area(l) => self.update_light_buffer(queue, id.kind, id.index, l),
+        }
+    }
+
+    /// Lay out this frame's shadow maps for the directional and spot lights
+    /// that cast shadows, and point the lights at their tiles. Past the
+    /// atlas' `max_shadowed_lights` the most important lights are kept, see
+    /// `ShadowCaster::importance`. With `enabled` false no light is shadowed.
+    pub(crate) fn update_shadows(&mut self, queue: &wgpu::Queue, view: &ShadowView, enabled: bool) {
+        let mut casters = Vec::new();
+        let mut importance = Vec::new();
+        let directional = self
+            .directional
+            .iter()
+            .filter(|(index, _)| enabled && *index < self.directional_count as usize);
+        for (index, light) in directional {
+            if let (Some(settings), true) = (light.shadow, light.casts_shadows) {
+                let id = LightId {
+                    kind: LightKind::Directional,
+                    index: *index,
+                };
+                let caster = ShadowCaster::Directional { direction: light.direction };
+                importance.push(caster.importance(light.base.luminance(), view.position));
+                casters.push((id, caster, settings));
+            }
+        }
+        let spots = self
+            .positional
+            .iter()
+            .filter(|(id, _)| enabled && id.index < self.spot_count as usize);
+        for (id, light) in spots {
+            if let PositionalLight::Spot(SpotLight {
+                base,
+                direction,
+                cutoff,
+                shadow: Some(settings),
+                casts_shadows: true,
+                ..
+            }) = light
+            {
+                // Spots reaching none of the scene have nothing to shadow
+                if view.scene_bounds.is_some_and(|bounds| !light.affects(&bounds)) {
+                    continue;
+                }
+                let caster = ShadowCaster::Spot {
+                    position: base.position,
+                    direction: *direction,
+                    cutoff: *cutoff,
+                    range: base.range(),
+                };
+                importance.push(caster.importance(base.luminance(), view.position));
+                casters.push((*id, caster, *settings));
+            }
+        }
+
+        let budget = self.shadows.max_shadowed_lights.min(MAX_SHADOWS);
+        if casters.len() > budget {
+            let mut order = (0..casters.len()).collect::<Vec<_>>();
+            order.sort_by(|&a, &b| importance[b].total_cmp(&importance[a]));
+            for &i in &order[budget..] {
+                log::debug!("{:?} is over the shadow budget", casters[i].0);
+            }
+            casters = order[..budget].iter().map(|&i| casters[i]).collect();
+        }
+
+        let uniforms = self.shadows.allocate(queue, &casters, view);
+        if !uniforms.is_empty() {
+            queue.write_buffer(
+                &self.light_buffer,
+                self.shadow_buffer_offset() as _,
+                bytemuck::cast_slice(&uniforms),
+            );
+        }
+        let shadowed = self.shadows.tiles().iter().map(|tile| tile.light).collect::<Vec<_>>();
+        for id in self.shadowed.iter().filter(|id| !shadowed.contains(id)) {
+            self.write_shadow_index(queue, *id, -1);
+        }
+        for (i, id) in shadowed.iter().enumerate() {
+            self.write_shadow_index(queue, *id, i as i32);
+        }
+        self.shadowed = shadowed;
+    }
+
+    fn write_shadow_index(&self, queue: &wgpu::Queue, id: LightId, shadow: i32) {
+        // Offset of `shadow` in `DirectionalLightUniform` and `PointLightUniform`
+        let field = match id.kind {
+            LightKind::Directional => 28,
+            LightKind::Point | LightKind::Spot => 44,
+            LightKind::Ambient | LightKind::Area => return,
+        };
+        let offset = self.calculate_buffer_offset(&id.kind, id.index) + field;
+        queue.write_buffer(&self.light_buffer, offset as _, bytemuck::cast_slice(&[shadow]));
+    }
+
     pub fn update_light_counts(&self, queue: &wgpu::Queue)
     {
-        let offset: usize = self.calculate_buffer_offset(&LightKind::Spot, MAX_SPOT_LIGHTS);
+        let offset: usize = self.calculate_buffer_offset(&LightKind::Area, MAX_AREA_LIGHTS);
         queue.write_buffer(
             &self.light_buffer,
             offset as _,
@@ -141,6 +458,10 @@ impl LightBufferManager {
                 self.directional_count,
                 self.point_count,
                 self.spot_count,
+                self.area_count,
+                0,
+                0,
+                0,
             ]),
         );
     }
@@ -150,6 +471,7 @@ pub trait Light {
     fn buffer_data(&self) -> Vec<u8>;
 }
 
+#[derive(Debug, Clone)]
 pub struct BaseLight {
     pub color: [f32; 3],
     pub strength: f32,
@@ -166,11 +488,21 @@ impl BaseLight {
         }
     }
 
+    /// Perceived brightness of the color times the strength.
+    pub fn luminance(&self) -> f32 {
+        return luminance(self.color) * self.strength;
+    }
+
     fn uniform(&self) -> [f32; 4] {
         return [self.color[0], self.color[1], self.color[2], self.strength];
     }
 }
 
+// Rec. 709 luminance of a linear color
+fn luminance(color: [f32; 3]) -> f32 {
+    return 0.2126 * color[0] + 0.7152 * color[1] + 0.0722 * color[2];
+}
+
 impl Light for BaseLight {
     fn buffer_data(&self) -> Vec<u8> {
         return bytemuck::cast_slice(&[self.uniform()]).to_vec();
@@ -182,12 +514,20 @@ impl Light for BaseLight {
 struct DirectionalLightUniform {
     base: [f32; 4],
     direction: [f32; 3],
-    _padding: u32,
+    // Index into the shadow uniforms, -1 if unshadowed
+    shadow: i32,
 }
 
+#[derive(Debug, Clone)]
 pub struct DirectionalLight {
     pub base: BaseLight,
     pub direction: cgmath::Vector3<f32>,
+    /// `None` if the light casts no shadows.
+    pub shadow: Option<ShadowSettings>,
+    /// Turns the shadows off while keeping `shadow`.
+    pub casts_shadows: bool,
+    /// `None` if the light doesn't scatter in the air.
+    pub volumetric: Option<VolumetricSettings>,
 }
 
 impl DirectionalLight {
@@ -199,14 +539,27 @@ impl DirectionalLight {
         Self {
             base: BaseLight::new(color, strength),
             direction: direction.into(),
+            shadow: None,
+            casts_shadows: true,
+            volumetric: None,
         }
     }
 
+    pub fn with_shadows(mut self, settings: ShadowSettings) -> Self {
+        self.shadow = Some(settings);
+        return self;
+    }
+
+    pub fn with_volumetric(mut self, settings: VolumetricSettings) -> Self {
+        self.volumetric = Some(settings);
+        return self;
+    }
+
     fn uniform(&self) -> DirectionalLightUniform {
         return DirectionalLightUniform {
             base: self.base.uniform(),
             direction: self.direction.into(),
-            _padding: 0,
+            shadow: -1,
         };
     }
 }
@@ -221,23 +574,48 @@ impl Light for DirectionalLight {
 #[derive(Debug, Copy, Clone, Default, bytemuck::Pod, bytemuck::Zeroable)]
 struct PointLightUniform {
     color: [f32; 3],
-    _padding1: u32,
+    range: f32,
     attenuation: [f32; 3],
     _padding2: u32,
     position: [f32; 3],
-    _padding3: u32,
+    // Index into the shadow uniforms, -1 if unshadowed
+    shadow: i32,
 }
 
+#[derive(Debug, Clone)]
 pub struct Attenuation {
     pub constant: f32,
     pub linear: f32,
     pub exp: f32,
 }
 
+impl Attenuation {
+    /// Distance at which a light of `intensity` falls below `LIGHT_CUTOFF`.
+    /// Constant attenuation never falls off and gives `f32::MAX`.
+    pub fn range(&self, intensity: f32) -> f32 {
+        // Solve constant + linear * d + exp * d^2 = intensity / cutoff
+        let target = intensity / LIGHT_CUTOFF - self.constant;
+        if target <= 0.0 {
+            return 0.0;
+        }
+        if self.exp > 0.0 {
+            let discriminant = self.linear * self.linear + 4.0 * self.exp * target;
+            return (discriminant.sqrt() - self.linear) / (2.0 * self.exp);
+        }
+        if self.linear > 0.0 {
+            return target / self.linear;
+        }
+        return f32::MAX;
+    }
+}
+
+#[derive(Debug, Clone)]
 pub struct PointLight {
     pub color: [f32; 3],
     pub attenuation: Attenuation,
     pub position: cgmath::Vector3<f32>,
+    /// Overrides the range derived from `attenuation`.
+    pub range: Option<f32>,
 }
 
 impl PointLight {
@@ -254,13 +632,33 @@ impl PointLight {
                 exp: e_att,
             },
             position: position.into(),
+            range: None,
         }
     }
 
+    pub fn with_range(mut self, range: f32) -> Self {
+        self.range = Some(range);
+        return self;
+    }
+
+    /// Distance past which shading ignores the light and spot lights stop
+    /// being picked for shadow maps.
+    pub fn range(&self) -> f32 {
+        let intensity = self.color[0].max(self.color[1]).max(self.color[2]);
+        return self
+            .range
+            .unwrap_or_else(|| self.attenuation.range(intensity));
+    }
+
+    /// Perceived brightness of the color, which holds the light's strength.
+    pub fn luminance(&self) -> f32 {
+        return luminance(self.color);
+    }
+
     fn uniform(&self) -> PointLightUniform {
         return PointLightUniform {
             color: self.color,
-            _padding1: 0,
+            range: self.range(),
             attenuation: [
                 self.attenuation.constant,
                 self.attenuation.linear,
@@ -268,7 +666,7 @@ impl PointLight {
             ],
             _padding2: 0,
             position: self.position.into(),
-            _padding3: 0,
+            shadow: -1,
         };
     }
 }
@@ -286,10 +684,17 @@ struct SpotLightUniform {
     direction_cutoffcos: [f32; 4],
 }
 
+#[derive(Debug, Clone)]
 pub struct SpotLight {
     pub base: PointLight,
     pub direction: cgmath::Vector3<f32>,
     pub cutoff: cgmath::Rad<f32>,
+    /// `None` if the light casts no shadows.
+    pub shadow: Option<ShadowSettings>,
+    /// Turns the shadows off while keeping `shadow`.
+    pub casts_shadows: bool,
+    /// `None` if the light doesn't scatter in the air.
+    pub volumetric: Option<VolumetricSettings>,
 }
 
 impl SpotLight {
@@ -312,9 +717,22 @@ impl SpotLight {
             base: PointLight::new(color, position, c_att, l_att, e_att),
             direction: direction.into(),
             cutoff: cutoff.into(),
+            shadow: None,
+            casts_shadows: true,
+            volumetric: None,
         }
     }
 
+    pub fn with_shadows(mut self, settings: ShadowSettings) -> Self {
+        self.shadow = Some(settings);
+        return self;
+    }
+
+    pub fn with_volumetric(mut self, settings: VolumetricSettings) -> Self {
+        self.volumetric = Some(settings);
+        return self;
+    }
+
     fn uniform(&self) -> SpotLightUniform {
         return SpotLightUniform {
             base_uniform: self.base.uniform(),
@@ -333,3 +751,190 @@ impl Light for SpotLight {
         return bytemuck::cast_slice(&[self.uniform()]).to_vec();
     }
 }
+
+#[repr(C)]
+#[derive(Debug, Copy, Clone, Default, bytemuck::Pod, bytemuck::Zeroable)]
+struct AreaLightUniform {
+    color_intensity: [f32; 4],
+    position_two_sided: [f32; 4],
+    half_right: [f32; 4],
+    half_up: [f32; 4],
+}
+
+/// Rectangular light emitting from the side its normal (`right` x `up`) points to.
+#[derive(Debug, Clone)]
+pub struct AreaLight {
+    pub color: [f32; 3],
+    pub intensity: f32,
+    pub position: cgmath::Vector3<f32>,
+    pub right: cgmath::Vector3<f32>,
+    pub up: cgmath::Vector3<f32>,
+    pub width: f32,
+    pub height: f32,
+    pub two_sided: bool,
+}
+
+impl AreaLight {
+    pub fn new<C, P, R, U>(
+        color: C,
+        intensity: f32,
+        position: P,
+        right: R,
+        up: U,
+        width: f32,
+        height: f32,
+    ) -> Self
+    where
+        C: Into<[f32; 3]>,
+        P: Into<cgmath::Vector3<f32>>,
+        R: Into<cgmath::Vector3<f32>>,
+        U: Into<cgmath::Vector3<f32>>,
+    {
+        Self {
+            color: color.into(),
+            intensity,
+            position: position.into(),
+            right: right.into(),
+            up: up.into(),
+            width,
+            height,
+            two_sided: false,
+        }
+    }
+
+    pub fn normal(&self) -> cgmath::Vector3<f32> {
+        use cgmath::InnerSpace;
+        return self.right.cross(self.up).normalize();
+    }
+
+    /// Distance from the center at which the falloff used in the shader drops
+    /// below `LIGHT_CUTOFF`, plus the extent of the rectangle.
+    pub fn range(&self) -> f32 {
+        let area = self.width * self.height;
+        let intensity = self.color[0].max(self.color[1]).max(self.color[2]) * self.intensity;
+        // area / (area + d^2) * intensity = cutoff
+        let distance = (area * (intensity / LIGHT_CUTOFF - 1.0)).max(0.0).sqrt();
+        return distance + 0.5 * (self.width * self.width + self.height * self.height).sqrt();
+    }
+
+    fn uniform(&self) -> AreaLightUniform {
+        use cgmath::InnerSpace;
+        let half_right = self.right.normalize() * (self.width / 2.0);
+        let half_up = self.up.normalize() * (self.height / 2.0);
+        return AreaLightUniform {
+            color_intensity: [self.color[0], self.color[1], self.color[2], self.intensity],
+            position_two_sided: [
+                self.position.x,
+                self.position.y,
+                self.position.z,
+                if self.two_sided { 1.0 } else { 0.0 },
+            ],
+            half_right: half_right.extend(0.0).into(),
+            half_up: half_up.extend(0.0).into(),
+        };
+    }
+}
+
+impl Light for AreaLight {
+    fn buffer_data(&self) -> Vec<u8> {
+        return bytemuck::cast_slice(&[self.uniform()]).to_vec();
+    }
+}
+
+/// Lights with a position in the scene, which editors can select and move.
+#[derive(Debug, Clone)]
+pub enum PositionalLight {
+    Point(PointLight),
+    Spot(SpotLight),
+    Area(AreaLight),
+}
+
+impl PositionalLight {
+    pub fn kind(&self) -> LightKind {
+        return match self {
+            PositionalLight::Point(_) => LightKind::Point,
+            PositionalLight::Spot(_) => LightKind::Spot,
+            PositionalLight::Area(_) => LightKind::Area,
+        };
+    }
+
+    pub fn color(&self) -> [f32; 3] {
+        return match self {
+            PositionalLight::Point(l) => l.color,
+            PositionalLight::Spot(l) => l.base.color,
+            PositionalLight::Area(l) => l.color,
+        };
+    }
+
+    pub fn position(&self) -> cgmath::Vector3<f32> {
+        return match self {
+            PositionalLight::Point(l) => l.position,
+            PositionalLight::Spot(l) => l.base.position,
+            PositionalLight::Area(l) => l.position,
+        };
+    }
+
+    pub fn set_position(&mut self, position: cgmath::Vector3<f32>) {
+        match self {
+            PositionalLight::Point(l) => l.position = position,
+            PositionalLight::Spot(l) => l.base.position = position,
+            PositionalLight::Area(l) => l.position = position,
+        }
+    }
+
+    pub fn range(&self) -> f32 {
+        return match self {
+            PositionalLight::Point(l) => l.range(),
+            PositionalLight::Spot(l) => l.base.range(),
+            PositionalLight::Area(l) => l.range(),
+        };
+    }
+
+    /// Whether the sphere the light reaches overlaps `bounds`.
+    pub fn affects(&self, bounds: &Aabb) -> bool {
+        return bounds.intersects_sphere(self.position(), self.range());
+    }
+
+    /// Emission direction, `None` for omnidirectional lights.
+    pub fn direction(&self) -> Option<cgmath::Vector3<f32>> {
+        use cgmath::InnerSpace;
+        return match self {
+            PositionalLight::Point(_) => None,
+            PositionalLight::Spot(l) => Some(l.direction.normalize()),
+            PositionalLight::Area(l) => Some(l.normal()),
+        };
+    }
+
+    pub fn set_direction(&mut self, direction: cgmath::Vector3<f32>) {
+        use cgmath::{InnerSpace, Rotation};
+        let direction = direction.normalize();
+        match self {
+            PositionalLight::Point(_) => {}
+            PositionalLight::Spot(l) => l.direction = direction,
+            PositionalLight::Area(l) => {
+                // Rotate the rectangle so its normal follows the new direction
+                let rotation = cgmath::Quaternion::from_arc(l.normal(), direction, None);
+                l.right = rotation.rotate_vector(l.right);
+                l.up = rotation.rotate_vector(l.up);
+            }
+        }
+    }
+}
+
+impl From<PointLight> for PositionalLight {
+    fn from(light: PointLight) -> Self {
+        PositionalLight::Point(light)
+    }
+}
+
+impl From<SpotLight> for PositionalLight {
+    fn from(light: SpotLight) -> Self {
+        PositionalLight::Spot(light)
+    }
+}
+
+impl From<AreaLight> for PositionalLight {
+    fn from(light: AreaLight) -> Self {
+        PositionalLight::Area(light)
+    }
+}
@@ -1,24 +1,8 @@
-struct Camera {
-    view_pos: vec4<f32>,
-    view_proj: mat4x4<f32>,
-};
+#include "camera.wgsl"
 @group(0) @binding(0)
 var<uniform> camera: Camera;
 
-// Lights
-struct DirectionalLight {
-    color_strength: vec4<f32>,
-    direction: vec3<f32>
-};
-struct PointLight {
-    color: vec3<f32>,
-    attenuation: vec3<f32>,
-    position: vec3<f32>
-};
-struct SpotLight {
-    base: PointLight,
-    direction_ccos: vec4<f32>
-}
+#include "lights.wgsl"
 @group(1) @binding(0)
 var<uniform> ambient_light: vec4<f32>;
 @group(1) @binding(1)
@@ -0,0 +1,603 @@
+use anyhow::*;
+use cgmath::{EuclideanSpace, InnerSpace, Matrix4, Point3, Vector3};
+
+use crate::{
+    camera::ortho_rh,
+    light::DirectionalLight,
+    math::Aabb,
+    memory::{MemoryTracker, TrackedBuffer},
+    model::Mesh,
+    resources::Vertex,
+    shader::ShaderPreprocessor,
+    texture::Texture,
+};
+
+#[repr(C)]
+#[derive(Debug, Copy, Clone, bytemuck::Pod, bytemuck::Zeroable)]
+struct BakeUniform {
+    view_proj: [[f32; 4]; 4],
+    direction: [f32; 4],
+    color: [f32; 4],
+    params: [f32; 4],
+}
+
+/// Lightmap texture coordinates of a vertex, streamed next to `ModelVertex`
+/// from a buffer of their own.
+#[repr(C)]
+#[derive(Debug, Copy, Clone, bytemuck::Pod, bytemuck::Zeroable)]
+pub struct LightmapVertex {
+    pub uv: [f32; 2],
+}
+
+impl Vertex for LightmapVertex {
+    fn desc<'a>() -> wgpu::VertexBufferLayout<'a> {
+        return wgpu::VertexBufferLayout {
+            array_stride: std::mem::size_of::<LightmapVertex>() as wgpu::BufferAddress,
+            step_mode: wgpu::VertexStepMode::Vertex,
+            attributes: &[wgpu::VertexAttribute {
+                offset: 0,
+                shader_location: 12,
+                format: wgpu::VertexFormat::Float32x2,
+            }],
+        };
+    }
+}
+
+/// Lightmap texture coordinates of a mesh, see `Geometry::lightmap_uvs`.
+pub struct LightmapUvs {
+    pub buffer: TrackedBuffer,
+    /// Size of the lightmap the coordinates were packed for.
+    pub resolution: u32,
+}
+
+/// What `Lightmap::bake` stores.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum BakeMode {
+    /// Only how much of the sky each texel sees.
+    AmbientOcclusion,
+    /// Occlusion plus light from directional lights and one bounce of it off
+    /// the baked geometry.
+    Lighting,
+}
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct BakeSettings {
+    pub mode: BakeMode,
+    /// Directions light is gathered from, spread over the sphere.
+    pub samples: u32,
+    /// Size of the depth maps rendered along each direction.
+    pub depth_resolution: u32,
+    /// Fraction of the light surfaces reflect for the bounce.
+    pub albedo: f32,
+    /// Texels around charts filled from their edges.
+    pub dilation: u32,
+}
+
+impl Default for BakeSettings {
+    fn default() -> Self {
+        Self {
+            mode: BakeMode::Lighting,
+            samples: 128,
+            depth_resolution: 1024,
+            albedo: 0.5,
+            dilation: 4,
+        }
+    }
+}
+
+/// Baked light of static geometry, sampled by the basic shader through the
+/// mesh's `LightmapUvs`. Color holds light added to the dynamic lights, alpha
+/// the occlusion scaling ambient and environment light.
+pub struct Lightmap {
+    pub texture: Texture,
+    pub bind_group: wgpu::BindGroup,
+    pub mode: BakeMode,
+}
+
+impl Lightmap {
+    pub const FORMAT: wgpu::TextureFormat = wgpu::TextureFormat::Rgba16Float;
+    const GBUFFER_FORMAT: wgpu::TextureFormat = wgpu::TextureFormat::Rgba32Float;
+    const DEPTH_FORMAT: wgpu::TextureFormat = wgpu::TextureFormat::Depth32Float;
+
+    pub fn bind_group_layout(device: &wgpu::Device) -> wgpu::BindGroupLayout {
+        return device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
+            entries: &[
+                wgpu::BindGroupLayoutEntry {
+                    binding: 0,
+                    visibility: wgpu::ShaderStages::FRAGMENT,
+                    ty: wgpu::BindingType::Texture {
+                        multisampled: false,
+                        view_dimension: wgpu::TextureViewDimension::D2,
+                        sample_type: wgpu::TextureSampleType::Float { filterable: true },
+                    },
+                    count: None,
+                },
+                wgpu::BindGroupLayoutEntry {
+                    binding: 1,
+                    visibility: wgpu::ShaderStages::FRAGMENT,
+                    ty: wgpu::BindingType::Sampler(wgpu::SamplerBindingType::Filtering),
+                    count: None,
+                },
+            ],
+            label: Some("lightmap_bind_group_layout"),
+        });
+    }
+
+    /// Bake the lightmap of `mesh` on the GPU. Light is gathered from
+    /// `settings.samples` directions through depth maps covering the whole
+    /// mesh, so only the mesh itself occludes and bounces light. `lights` are
+    /// only used with `BakeMode::Lighting`; leave them out of the light buffer
+    /// afterwards, or lower their strength to the dynamic part, to avoid
+    /// lighting twice.
+    pub fn bake(
+        device: &wgpu::Device,
+        queue: &wgpu::Queue,
+        memory: &MemoryTracker,
+        layout: &wgpu::BindGroupLayout,
+        mesh: &Mesh,
+        lights: &[DirectionalLight],
+        settings: &BakeSettings,
+    ) -> Result<Self> {
+        let uvs = mesh
+            .lightmap_uvs
+            .as_ref()
+            .with_context(|| format!("Mesh {:?} has no lightmap coordinates", mesh.name))?;
+        ensure!(settings.samples > 0, "Lightmap bakes need at least one sample");
+        let baker = Baker::new(device, memory, uvs.resolution, settings.depth_resolution);
+
+        let radius = (mesh.bounds.max - mesh.bounds.min).magnitude() * 0.5 + f32::EPSILON;
+        let texel = 2.0 * radius / settings.depth_resolution as f32;
+        let params = [
+            texel * 2.0,
+            1.5 / settings.depth_resolution as f32,
+            settings.albedo,
+            settings.dilation as f32,
+        ];
+        let uniform = |direction: Vector3<f32>, weight: f32, color: [f32; 3], gather: bool| BakeUniform {
+            view_proj: direction_view_proj(&mesh.bounds, direction).into(),
+            direction: [direction.x, direction.y, direction.z, weight],
+            color: [color[0], color[1], color[2], if gather { 1.0 } else { 0.0 }],
+            params,
+        };
+
+        baker.draw_gbuffer(device, queue, mesh, uvs);
+        if settings.mode == BakeMode::Lighting {
+            for light in lights {
+                let direction = -light.direction.normalize();
+                let color = light.base.color.map(|c| c * light.base.strength);
+                baker.gather(device, queue, mesh, uvs, &uniform(direction, 1.0, color, false));
+            }
+        }
+        baker.resolve(device, queue, &baker.direct, &uniform(Vector3::unit_y(), 0.0, [0.0; 3], false));
+
+        // Cosine weighted over the sphere, an open hemisphere sums to 1
+        let weight = 4.0 / settings.samples as f32;
+        for direction in sphere_directions(settings.samples) {
+            baker.gather(device, queue, mesh, uvs, &uniform(direction, weight, [0.0; 3], true));
+        }
+
+        let texture = Texture::create_render_target(
+            device,
+            memory,
+            uvs.resolution,
+            uvs.resolution,
+            Self::FORMAT,
+            1,
+            "Lightmap",
+        );
+        baker.resolve(device, queue, &texture, &uniform(Vector3::unit_y(), 0.0, [0.0; 3], false));
+
+        let bind_group = device.create_bind_group(&wgpu::BindGroupDescriptor {
+            layout,
+            entries: &[
+                wgpu::BindGroupEntry {
+                    binding: 0,
+                    resource: wgpu::BindingResource::TextureView(&texture.view),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 1,
+                    resource: wgpu::BindingResource::Sampler(&texture.sampler),
+                },
+            ],
+            label: Some("lightmap_bind_group"),
+        });
+        return Ok(Self {
+            texture,
+            bind_group,
+            mode: settings.mode,
+        });
+    }
+}
+
+// Orthographic camera looking at the whole of `bounds` against `direction`
+fn direction_view_proj(bounds: &Aabb, direction: Vector3<f32>) -> Matrix4<f32> {
+    let center = Point3::from_vec(bounds.center());
+    let radius = (bounds.max - bounds.min).magnitude() * 0.5 + f32::EPSILON;
+    let up = if direction.y.abs() > 0.99 {
+        Vector3::unit_z()
+    } else {
+        Vector3::unit_y()
+    };
+    let view = Matrix4::look_at_rh(center + direction * radius * 2.0, center, up);
+    let proj = ortho_rh(-radius, radius, -radius, radius, radius, radius * 3.0);
+    return proj * view;
+}
+
+// Evenly spread unit vectors on a Fibonacci spiral
+fn sphere_directions(count: u32) -> impl Iterator<Item = Vector3<f32>> {
+    let golden_angle = std::f32::consts::PI * (3.0 - 5.0f32.sqrt());
+    return (0..count).map(move |i| {
+        let y = 1.0 - (2.0 * i as f32 + 1.0) / count as f32;
+        let r = (1.0 - y * y).max(0.0).sqrt();
+        let phi = golden_angle * i as f32;
+        return Vector3::new(r * phi.cos(), y, r * phi.sin());
+    });
+}
+
+// Render targets and pipelines of one bake
+struct Baker {
+    uniform_buffer: wgpu::Buffer,
+    uniform_bind_group: wgpu::BindGroup,
+    position: Texture,
+    normal: Texture,
+    // Sum of everything gathered so far
+    accum: Texture,
+    // Dilated direct light, reflected by the surfaces seen from each direction
+    direct: Texture,
+    depth: Texture,
+    color: Texture,
+    gbuffer_pipeline: wgpu::RenderPipeline,
+    direction_pipeline: wgpu::RenderPipeline,
+    direction_bind_group: wgpu::BindGroup,
+    accumulate_pipeline: wgpu::RenderPipeline,
+    accumulate_bind_group: wgpu::BindGroup,
+    resolve_pipeline: wgpu::RenderPipeline,
+    resolve_bind_group: wgpu::BindGroup,
+}
+
+impl Baker {
+    fn new(device: &wgpu::Device, memory: &MemoryTracker, resolution: u32, depth_resolution: u32) -> Self {
+        let target = |size: u32, format: wgpu::TextureFormat, label: &str| {
+            return Texture::create_render_target(device, memory, size, size, format, 1, label);
+        };
+        let position = target(resolution, Lightmap::GBUFFER_FORMAT, "Lightmap Bake Positions");
+        let normal = target(resolution, Lightmap::GBUFFER_FORMAT, "Lightmap Bake Normals");
+        let accum = target(resolution, Lightmap::FORMAT, "Lightmap Bake Accumulation");
+        let direct = target(resolution, Lightmap::FORMAT, "Lightmap Bake Direct Light");
+        let depth = target(depth_resolution, Lightmap::DEPTH_FORMAT, "Lightmap Bake Depth");
+        let color = target(depth_resolution, Lightmap::FORMAT, "Lightmap Bake Color");
+        let depth_sampler = device.create_sampler(&wgpu::SamplerDescriptor {
+            label: Some("Lightmap Bake Depth Sampler"),
+            address_mode_u: wgpu::AddressMode::ClampToEdge,
+            address_mode_v: wgpu::AddressMode::ClampToEdge,
+            mag_filter: wgpu::FilterMode::Linear,
+            min_filter: wgpu::FilterMode::Linear,
+            compare: Some(wgpu::CompareFunction::LessEqual),
+            ..Default::default()
+        });
+
+        let uniform_buffer = device.create_buffer(&wgpu::BufferDescriptor {
+            label: Some("Lightmap Bake Uniform Buffer"),
+            size: std::mem::size_of::<BakeUniform>() as u64,
+            usage: wgpu::BufferUsages::UNIFORM | wgpu::BufferUsages::COPY_DST,
+            mapped_at_creation: false,
+        });
+        let uniform_layout = device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
+            entries: &[wgpu::BindGroupLayoutEntry {
+                binding: 0,
+                visibility: wgpu::ShaderStages::VERTEX | wgpu::ShaderStages::FRAGMENT,
+                ty: wgpu::BindingType::Buffer {
+                    ty: wgpu::BufferBindingType::Uniform,
+                    has_dynamic_offset: false,
+                    min_binding_size: None,
+                },
+                count: None,
+            }],
+            label: Some("lightmap_bake_uniform_layout"),
+        });
+        let uniform_bind_group = device.create_bind_group(&wgpu::BindGroupDescriptor {
+            layout: &uniform_layout,
+            entries: &[wgpu::BindGroupEntry {
+                binding: 0,
+                resource: uniform_buffer.as_entire_binding(),
+            }],
+            label: Some("lightmap_bake_uniform_bind_group"),
+        });
+
+        // Bindings of group 1 in lightmap.wgsl
+        let texture_entry = |binding: u32, sample_type: wgpu::TextureSampleType| wgpu::BindGroupLayoutEntry {
+            binding,
+            visibility: wgpu::ShaderStages::FRAGMENT,
+            ty: wgpu::BindingType::Texture {
+                multisampled: false,
+                view_dimension: wgpu::TextureViewDimension::D2,
+                sample_type,
+            },
+            count: None,
+        };
+        let filterable = wgpu::TextureSampleType::Float { filterable: true };
+        let unfilterable = wgpu::TextureSampleType::Float { filterable: false };
+        let linear_sampler = wgpu::BindGroupLayoutEntry {
+            binding: 1,
+            visibility: wgpu::ShaderStages::FRAGMENT,
+            ty: wgpu::BindingType::Sampler(wgpu::SamplerBindingType::Filtering),
+            count: None,
+        };
+        fn view(binding: u32, texture: &Texture) -> wgpu::BindGroupEntry<'_> {
+            return wgpu::BindGroupEntry {
+                binding,
+                resource: wgpu::BindingResource::TextureView(&texture.view),
+            };
+        }
+        let create_group = |label: &str, layout_entries: &[wgpu::BindGroupLayoutEntry], entries: &[wgpu::BindGroupEntry]| {
+            let layout = device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
+                entries: layout_entries,
+                label: Some(label),
+            });
+            let group = device.create_bind_group(&wgpu::BindGroupDescriptor {
+                layout: &layout,
+                entries,
+                label: Some(label),
+            });
+            return (layout, group);
+        };
+        let (direction_layout, direction_bind_group) = create_group(
+            "lightmap_bake_direction_bind_group",
+            &[texture_entry(0, filterable), linear_sampler],
+            &[
+                view(0, &direct),
+                wgpu::BindGroupEntry {
+                    binding: 1,
+                    resource: wgpu::BindingResource::Sampler(&direct.sampler),
+                },
+            ],
+        );
+        let (accumulate_layout, accumulate_bind_group) = create_group(
+            "lightmap_bake_accumulate_bind_group",
+            &[
+                linear_sampler,
+                texture_entry(2, unfilterable),
+                texture_entry(3, unfilterable),
+                texture_entry(4, wgpu::TextureSampleType::Depth),
+                wgpu::BindGroupLayoutEntry {
+                    binding: 5,
+                    visibility: wgpu::ShaderStages::FRAGMENT,
+                    ty: wgpu::BindingType::Sampler(wgpu::SamplerBindingType::Comparison),
+                    count: None,
+                },
+                texture_entry(6, filterable),
+            ],
+            &[
+                wgpu::BindGroupEntry {
+                    binding: 1,
+                    resource: wgpu::BindingResource::Sampler(&color.sampler),
+                },
+                view(2, &position),
+                view(3, &normal),
+                view(4, &depth),
+                wgpu::BindGroupEntry {
+                    binding: 5,
+                    resource: wgpu::BindingResource::Sampler(&depth_sampler),
+                },
+                view(6, &color),
+            ],
+        );
+        let (resolve_layout, resolve_bind_group) = create_group(
+            "lightmap_bake_resolve_bind_group",
+            &[texture_entry(2, unfilterable), texture_entry(7, unfilterable)],
+            &[view(2, &position), view(7, &accum)],
+        );
+
+        let shader = ShaderPreprocessor::new()
+            .descriptor("Lightmap Bake Shader", "lightmap.wgsl")
+            .expect("Failed to preprocess lightmap.wgsl");
+        let shader = device.create_shader_module(shader);
+        let mesh_layouts = [crate::resources::ModelVertex::desc(), LightmapVertex::desc()];
+        let pipeline = |label: &str,
+                        group: Option<&wgpu::BindGroupLayout>,
+                        vertex: (&str, &[wgpu::VertexBufferLayout]),
+                        fragment: &str,
+                        targets: &[Option<wgpu::ColorTargetState>],
+                        depth: bool| {
+            let groups = [Some(&uniform_layout), group].into_iter().flatten().collect::<Vec<_>>();
+            let layout = device.create_pipeline_layout(&wgpu::PipelineLayoutDescriptor {
+                label: Some(label),
+                bind_group_layouts: &groups,
+                push_constant_ranges: &[],
+            });
+            return device.create_render_pipeline(&wgpu::RenderPipelineDescriptor {
+                label: Some(label),
+                layout: Some(&layout),
+                vertex: wgpu::VertexState {
+                    module: &shader,
+                    entry_point: vertex.0,
+                    buffers: vertex.1,
+                },
+                fragment: Some(wgpu::FragmentState {
+                    module: &shader,
+                    entry_point: fragment,
+                    targets,
+                }),
+                primitive: wgpu::PrimitiveState::default(),
+                depth_stencil: depth.then(|| wgpu::DepthStencilState {
+                    format: Lightmap::DEPTH_FORMAT,
+                    depth_write_enabled: true,
+                    depth_compare: wgpu::CompareFunction::Less,
+                    stencil: wgpu::StencilState::default(),
+                    bias: wgpu::DepthBiasState::default(),
+                }),
+                multisample: wgpu::MultisampleState::default(),
+                multiview: None,
+            });
+        };
+        let replace = |format: wgpu::TextureFormat| Some(wgpu::ColorTargetState::from(format));
+        let additive = wgpu::BlendComponent {
+            src_factor: wgpu::BlendFactor::One,
+            dst_factor: wgpu::BlendFactor::One,
+            operation: wgpu::BlendOperation::Add,
+        };
+        let gbuffer_pipeline = pipeline(
+            "Lightmap G-Buffer Pipeline",
+            None,
+            ("vs_gbuffer", &mesh_layouts),
+            "fs_gbuffer",
+            &[replace(Lightmap::GBUFFER_FORMAT), replace(Lightmap::GBUFFER_FORMAT)],
+            false,
+        );
+        let direction_pipeline = pipeline(
+            "Lightmap Direction Pipeline",
+            Some(&direction_layout),
+            ("vs_direction", &mesh_layouts),
+            "fs_direction",
+            &[replace(Lightmap::FORMAT)],
+            true,
+        );
+        let accumulate_pipeline = pipeline(
+            "Lightmap Accumulate Pipeline",
+            Some(&accumulate_layout),
+            ("vs_fullscreen", &[]),
+            "fs_accumulate",
+            &[Some(wgpu::ColorTargetState {
+                format: Lightmap::FORMAT,
+                blend: Some(wgpu::BlendState {
+                    color: additive,
+                    alpha: additive,
+                }),
+                write_mask: wgpu::ColorWrites::ALL,
+            })],
+            false,
+        );
+        let resolve_pipeline = pipeline(
+            "Lightmap Resolve Pipeline",
+            Some(&resolve_layout),
+            ("vs_fullscreen", &[]),
+            "fs_resolve",
+            &[replace(Lightmap::FORMAT)],
+            false,
+        );
+
+        return Self {
+            uniform_buffer,
+            uniform_bind_group,
+            position,
+            normal,
+            accum,
+            direct,
+            depth,
+            color,
+            gbuffer_pipeline,
+            direction_pipeline,
+            direction_bind_group,
+            accumulate_pipeline,
+            accumulate_bind_group,
+            resolve_pipeline,
+            resolve_bind_group,
+        };
+    }
+
+    fn color_attachment(view: &wgpu::TextureView, clear: bool) -> Option<wgpu::RenderPassColorAttachment<'_>> {
+        let load = if clear {
+            wgpu::LoadOp::Clear(wgpu::Color::TRANSPARENT)
+        } else {
+            wgpu::LoadOp::Load
+        };
+        return Some(wgpu::RenderPassColorAttachment {
+            view,
+            resolve_target: None,
+            ops: wgpu::Operations { load, store: true },
+        });
+    }
+
+    // Surface position and normal under every lightmap texel, and a cleared
+    // accumulation target
+    fn draw_gbuffer(&self, device: &wgpu::Device, queue: &wgpu::Queue, mesh: &Mesh, uvs: &LightmapUvs) {
+        let mut encoder = device.create_command_encoder(&wgpu::CommandEncoderDescriptor {
+            label: Some("Lightmap G-Buffer Encoder"),
+        });
+        {
+            let mut pass = encoder.begin_render_pass(&wgpu::RenderPassDescriptor {
+                label: Some("Lightmap G-Buffer Pass"),
+                color_attachments: &[
+                    Self::color_attachment(&self.position.view, true),
+                    Self::color_attachment(&self.normal.view, true),
+                ],
+                depth_stencil_attachment: None,
+            });
+            pass.set_pipeline(&self.gbuffer_pipeline);
+            pass.set_bind_group(0, &self.uniform_bind_group, &[]);
+            Self::draw_mesh(&mut pass, mesh, uvs);
+        }
+        encoder.begin_render_pass(&wgpu::RenderPassDescriptor {
+            label: Some("Lightmap Clear Pass"),
+            color_attachments: &[Self::color_attachment(&self.accum.view, true)],
+            depth_stencil_attachment: None,
+        });
+        queue.submit(std::iter::once(encoder.finish()));
+    }
+
+    // Render the depth and reflected light along `bake.direction`, then add
+    // what reaches each texel to the accumulation target
+    fn gather(&self, device: &wgpu::Device, queue: &wgpu::Queue, mesh: &Mesh, uvs: &LightmapUvs, bake: &BakeUniform) {
+        queue.write_buffer(&self.uniform_buffer, 0, bytemuck::cast_slice(&[*bake]));
+        let mut encoder = device.create_command_encoder(&wgpu::CommandEncoderDescriptor {
+            label: Some("Lightmap Gather Encoder"),
+        });
+        {
+            let mut pass = encoder.begin_render_pass(&wgpu::RenderPassDescriptor {
+                label: Some("Lightmap Direction Pass"),
+                color_attachments: &[Self::color_attachment(&self.color.view, true)],
+                depth_stencil_attachment: Some(wgpu::RenderPassDepthStencilAttachment {
+                    view: &self.depth.view,
+                    depth_ops: Some(wgpu::Operations {
+                        load: wgpu::LoadOp::Clear(1.0),
+                        store: true,
+                    }),
+                    stencil_ops: None,
+                }),
+            });
+            pass.set_pipeline(&self.direction_pipeline);
+            pass.set_bind_group(0, &self.uniform_bind_group, &[]);
+            pass.set_bind_group(1, &self.direction_bind_group, &[]);
+            Self::draw_mesh(&mut pass, mesh, uvs);
+        }
+        {
+            let mut pass = encoder.begin_render_pass(&wgpu::RenderPassDescriptor {
+                label: Some("Lightmap Accumulate Pass"),
+                color_attachments: &[Self::color_attachment(&self.accum.view, false)],
+                depth_stencil_attachment: None,
+            });
+            pass.set_pipeline(&self.accumulate_pipeline);
+            pass.set_bind_group(0, &self.uniform_bind_group, &[]);
+            pass.set_bind_group(1, &self.accumulate_bind_group, &[]);
+            pass.draw(0..3, 0..1);
+        }
+        queue.submit(std::iter::once(encoder.finish()));
+    }
+
+    // Copy the accumulated light into `target`, dilated past chart edges
+    fn resolve(&self, device: &wgpu::Device, queue: &wgpu::Queue, target: &Texture, bake: &BakeUniform) {
+        queue.write_buffer(&self.uniform_buffer, 0, bytemuck::cast_slice(&[*bake]));
+        let mut encoder = device.create_command_encoder(&wgpu::CommandEncoderDescriptor {
+            label: Some("Lightmap Resolve Encoder"),
+        });
+        {
+            let mut pass = encoder.begin_render_pass(&wgpu::RenderPassDescriptor {
+                label: Some("Lightmap Resolve Pass"),
+                color_attachments: &[Self::color_attachment(&target.view, true)],
+                depth_stencil_attachment: None,
+            });
+            pass.set_pipeline(&self.resolve_pipeline);
+            pass.set_bind_group(0, &self.uniform_bind_group, &[]);
+            pass.set_bind_group(1, &self.resolve_bind_group, &[]);
+            pass.draw(0..3, 0..1);
+        }
+        queue.submit(std::iter::once(encoder.finish()));
+    }
+
+    fn draw_mesh<'a>(pass: &mut wgpu::RenderPass<'a>, mesh: &'a Mesh, uvs: &'a LightmapUvs) {
+        pass.set_vertex_buffer(0, mesh.vertex_buffer.slice(..));
+        pass.set_vertex_buffer(1, uvs.buffer.slice(..));
+        pass.set_index_buffer(mesh.index_buffer.slice(..), wgpu::IndexFormat::Uint32);
+        pass.draw_indexed(0..mesh.num_elements, 0, 0..1);
+    }
+}
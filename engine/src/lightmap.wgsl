@@ -0,0 +1,149 @@
+// Lightmap baking passes, see lightmap.rs. Geometry is drawn once into
+// lightmap space to find the surface under every texel, then light is
+// gathered from directions by comparing texels with depth maps rendered
+// along each direction
+struct Bake {
+    // Orthographic camera looking along -direction over the whole mesh
+    view_proj: mat4x4<f32>,
+    // xyz: unit direction towards the light or sample, w: weight of the sample
+    direction: vec4<f32>,
+    // rgb: light color times strength, w: 0 for direct light, 1 for
+    // gathering bounce light and occlusion
+    color: vec4<f32>,
+    // x: normal offset in world units, y: depth bias, z: albedo, w: dilation
+    // radius in texels
+    params: vec4<f32>,
+};
+@group(0) @binding(0)
+var<uniform> bake: Bake;
+
+@group(1) @binding(0)
+var t_direct: texture_2d<f32>;
+@group(1) @binding(1)
+var s_linear: sampler;
+@group(1) @binding(2)
+var t_position: texture_2d<f32>;
+@group(1) @binding(3)
+var t_normal: texture_2d<f32>;
+@group(1) @binding(4)
+var t_depth: texture_depth_2d;
+@group(1) @binding(5)
+var s_depth: sampler_comparison;
+@group(1) @binding(6)
+var t_color: texture_2d<f32>;
+@group(1) @binding(7)
+var t_accum: texture_2d<f32>;
+
+struct GBufferOutput {
+    @builtin(position) clip_position: vec4<f32>,
+    @location(0) world_position: vec3<f32>,
+    @location(1) normal: vec3<f32>,
+};
+
+@vertex
+fn vs_gbuffer(
+    @location(0) position: vec3<f32>,
+    @location(2) normal: vec3<f32>,
+    @location(12) lightmap_uv: vec2<f32>,
+) -> GBufferOutput {
+    var out: GBufferOutput;
+    out.clip_position = vec4<f32>(lightmap_uv.x * 2.0 - 1.0, 1.0 - lightmap_uv.y * 2.0, 0.0, 1.0);
+    out.world_position = position;
+    out.normal = normal;
+    return out;
+}
+
+struct GBufferTargets {
+    // w: 1 where a surface covers the texel
+    @location(0) position: vec4<f32>,
+    @location(1) normal: vec4<f32>,
+};
+
+@fragment
+fn fs_gbuffer(in: GBufferOutput) -> GBufferTargets {
+    var out: GBufferTargets;
+    out.position = vec4<f32>(in.world_position, 1.0);
+    out.normal = vec4<f32>(normalize(in.normal), 0.0);
+    return out;
+}
+
+struct DirectionOutput {
+    @builtin(position) clip_position: vec4<f32>,
+    @location(0) lightmap_uv: vec2<f32>,
+};
+
+@vertex
+fn vs_direction(@location(0) position: vec3<f32>, @location(12) lightmap_uv: vec2<f32>) -> DirectionOutput {
+    var out: DirectionOutput;
+    out.clip_position = bake.view_proj * vec4<f32>(position, 1.0);
+    out.lightmap_uv = lightmap_uv;
+    return out;
+}
+
+// Light reflected by the surface seen along the direction
+@fragment
+fn fs_direction(in: DirectionOutput) -> @location(0) vec4<f32> {
+    let direct = textureSampleLevel(t_direct, s_linear, in.lightmap_uv, 0.0).rgb;
+    return vec4<f32>(direct * bake.params.z, 1.0);
+}
+
+@vertex
+fn vs_fullscreen(@builtin(vertex_index) index: u32) -> @builtin(position) vec4<f32> {
+    let uv = vec2<f32>(f32((index << 1u) & 2u), f32(index & 2u));
+    return vec4<f32>(uv * 2.0 - 1.0, 0.0, 1.0);
+}
+
+// Light reaching each texel from the direction, added up over all of them
+@fragment
+fn fs_accumulate(@builtin(position) position: vec4<f32>) -> @location(0) vec4<f32> {
+    let texel = vec2<i32>(position.xy);
+    let surface = textureLoad(t_position, texel, 0);
+    let normal = textureLoad(t_normal, texel, 0).xyz;
+    let cos_theta = dot(normal, bake.direction.xyz);
+    if (surface.w < 0.5 || cos_theta <= 0.0) {
+        discard;
+    }
+
+    let clip = bake.view_proj * vec4<f32>(surface.xyz + normal * bake.params.x, 1.0);
+    let ndc = clip.xyz * (1.0 / clip.w);
+    let uv = ndc.xy * vec2<f32>(0.5, -0.5) + vec2<f32>(0.5);
+    let visible = textureSampleCompareLevel(t_depth, s_depth, uv, ndc.z - bake.params.y);
+    if (bake.color.w < 0.5) {
+        return vec4<f32>(bake.color.rgb * cos_theta * visible, 0.0);
+    }
+
+    // Blocked directions see the surface closest to the depth map's camera,
+    // open ones the sky, which the renderer's ambient light stands in for
+    let bounce = textureSampleLevel(t_color, s_linear, uv, 0.0).rgb;
+    let weight = bake.direction.w * cos_theta;
+    return vec4<f32>(bounce * (1.0 - visible) * weight, visible * weight);
+}
+
+// Texels no surface covers take the average of the covered ones in the
+// closest ring around them, so bilinear filtering doesn't bleed black in
+@fragment
+fn fs_resolve(@builtin(position) position: vec4<f32>) -> @location(0) vec4<f32> {
+    let texel = vec2<i32>(position.xy);
+    if (textureLoad(t_position, texel, 0).w > 0.5) {
+        return textureLoad(t_accum, texel, 0);
+    }
+    let size = vec2<i32>(textureDimensions(t_accum));
+    let radius = i32(bake.params.w);
+    for (var r = 1; r <= radius; r++) {
+        var sum = vec4<f32>(0.0);
+        var count = 0.0;
+        for (var y = -r; y <= r; y++) {
+            for (var x = -r; x <= r; x++) {
+                let p = clamp(texel + vec2<i32>(x, y), vec2<i32>(0), size - vec2<i32>(1));
+                if (textureLoad(t_position, p, 0).w > 0.5) {
+                    sum += textureLoad(t_accum, p, 0);
+                    count += 1.0;
+                }
+            }
+        }
+        if (count > 0.0) {
+            return sum * (1.0 / count);
+        }
+    }
+    return vec4<f32>(0.0, 0.0, 0.0, 1.0);
+}
@@ -0,0 +1,50 @@
+// Lights, array sizes are specialized with the MAX_*_LIGHTS constants in
+// light.rs and MAX_SHADOWS in shadow.rs
+override MAX_AMBIENT_LIGHTS: u32 = 1u;
+override MAX_DIRECTIONAL_LIGHTS: u32 = 10u;
+override MAX_POINT_LIGHTS: u32 = 256u;
+override MAX_SPOT_LIGHTS: u32 = 256u;
+override MAX_AREA_LIGHTS: u32 = 64u;
+override MAX_SHADOWS: u32 = 16u;
+struct DirectionalLight {
+    color_strength: vec4<f32>,
+    direction: vec3<f32>,
+    // Index into `LightBuffer::shadows`, -1 if unshadowed
+    shadow: i32,
+};
+struct PointLight {
+    color: vec3<f32>,
+    // Distance past which the light contributes nothing
+    range: f32,
+    attenuation: vec3<f32>,
+    position: vec3<f32>,
+    // Index into `LightBuffer::shadows`, -1 if unshadowed
+    shadow: i32,
+};
+struct SpotLight {
+    base: PointLight,
+    direction_ccos: vec4<f32>,
+};
+struct AreaLight {
+    color_intensity: vec4<f32>,
+    position_two_sided: vec4<f32>,
+    half_right: vec4<f32>,
+    half_up: vec4<f32>,
+};
+// Shadow map of one light in the shadow atlas, see shadow.rs
+struct Shadow {
+    view_proj: mat4x4<f32>,
+    // Tile in atlas UVs, offset in xy and size in zw
+    rect: vec4<f32>,
+    // Depth bias, normal bias in world units, PCF taps per side, texel size
+    params: vec4<f32>,
+};
+struct LightBuffer {
+    ambients: array<vec4<f32>, MAX_AMBIENT_LIGHTS>,
+    dirs: array<DirectionalLight, MAX_DIRECTIONAL_LIGHTS>,
+    points: array<PointLight, MAX_POINT_LIGHTS>,
+    spots: array<SpotLight, MAX_SPOT_LIGHTS>,
+    areas: array<AreaLight, MAX_AREA_LIGHTS>,
+    lens: array<vec4<u32>, 2>,
+    shadows: array<Shadow, MAX_SHADOWS>,
+}
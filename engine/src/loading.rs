@@ -0,0 +1,139 @@
+use std::{
+    future::Future,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        mpsc::{self, Receiver, TryRecvError},
+        Arc,
+    },
+};
+
+use anyhow::*;
+
+use crate::{decode::DecodeQueue, memory::MemoryTracker, overlay::Overlay};
+
+/// GPU handles a background load creates its buffers and textures with.
+#[derive(Clone)]
+pub struct LoadContext {
+    pub device: Arc<wgpu::Device>,
+    pub queue: Arc<wgpu::Queue>,
+    pub memory: MemoryTracker,
+    /// Worker pool to hand texture decoding to, see `resources::load_material`.
+    pub decoder: DecodeQueue,
+}
+
+/// Result of a load running on a background thread.
+pub struct AssetHandle<T> {
+    name: String,
+    receiver: Receiver<Result<T>>,
+}
+
+impl<T> AssetHandle<T> {
+    /// The result once the load is done, `None` while it runs. Poll until it
+    /// returns `Some`, the result is handed out once.
+    pub fn poll(&self) -> Option<Result<T>> {
+        return match self.receiver.try_recv() {
+            Result::Ok(result) => Some(result),
+            Err(TryRecvError::Empty) => None,
+            Err(TryRecvError::Disconnected) => Some(Err(anyhow!("Loading {} stopped", self.name))),
+        };
+    }
+
+    /// Block until the load is done.
+    pub fn wait(self) -> Result<T> {
+        return self
+            .receiver
+            .recv()
+            .map_err(|_| anyhow!("Loading {} stopped", self.name))?;
+    }
+}
+
+/// Whether the renderer still waits for the assets it needs to draw the scene.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum LoadingState {
+    /// A progress screen is drawn instead of the scene.
+    Loading,
+    /// The loaded assets were swapped into the scene.
+    Running,
+}
+
+/// Runs asset loads on background threads, one per load, so file reads,
+/// decoding and uploads stay off the render thread.
+pub struct AssetLoader {
+    context: LoadContext,
+    started: usize,
+    // Shared with the load threads
+    finished: Arc<AtomicUsize>,
+}
+
+impl AssetLoader {
+    pub fn new(context: LoadContext) -> Self {
+        return Self {
+            context,
+            started: 0,
+            finished: Arc::new(AtomicUsize::new(0)),
+        };
+    }
+
+    pub fn context(&self) -> &LoadContext {
+        return &self.context;
+    }
+
+    /// Run `load` on its own thread, e.g.
+    /// `loader.spawn("cube.obj", move |cx| async move { load_model("cube.obj", &cx.device, ...).await })`.
+    pub fn spawn<T, F, Fut>(&mut self, name: &str, load: F) -> AssetHandle<T>
+    where
+        T: Send + 'static,
+        F: FnOnce(LoadContext) -> Fut + Send + 'static,
+        Fut: Future<Output = Result<T>>,
+    {
+        let (sender, receiver) = mpsc::channel();
+        let context = self.context.clone();
+        let finished = self.finished.clone();
+        let task = name.to_string();
+        self.started += 1;
+        let spawned = std::thread::Builder::new()
+            .name(format!("load {}", name))
+            .spawn(move || {
+                let result = pollster::block_on(load(context)).with_context(|| format!("Failed to load {}", task));
+                finished.fetch_add(1, Ordering::Relaxed);
+                let _ = sender.send(result);
+            });
+        // The handle reports the dropped sender as an error
+        if let Err(e) = spawned {
+            log::error!("Failed to start loading {}: {}", name, e);
+            self.finished.fetch_add(1, Ordering::Relaxed);
+        }
+        return AssetHandle {
+            name: name.to_string(),
+            receiver,
+        };
+    }
+
+    /// Finished and started loads.
+    pub fn progress(&self) -> (usize, usize) {
+        return (self.finished.load(Ordering::Relaxed), self.started);
+    }
+
+    pub fn is_idle(&self) -> bool {
+        let (finished, started) = self.progress();
+        return finished == started;
+    }
+}
+
+/// Progress bar with a `Loading finished/total` caption in the middle of a
+/// `size` logical pixel screen.
+pub fn draw_loading_screen(overlay: &mut Overlay, size: [f32; 2], finished: usize, total: usize) {
+    let [width, height] = size;
+    let bar_width = (width * 0.5).min(400.0);
+    let min = [(width - bar_width) * 0.5, height * 0.5 - 6.0];
+    let max = [min[0] + bar_width, min[1] + 12.0];
+    let done = if total == 0 {
+        1.0
+    } else {
+        finished as f32 / total as f32
+    };
+    overlay.fill_rect(min, [min[0] + bar_width * done, max[1]], [1.0, 1.0, 1.0, 0.8]);
+    overlay.rect(min, max, [1.0, 1.0, 1.0, 1.0], 1.0);
+    let text = format!("Loading {}/{}", finished, total);
+    overlay.text([min[0], min[1] - 28.0], &text, 16.0, [1.0, 1.0, 1.0, 1.0]);
+}
@@ -0,0 +1,92 @@
+use crate::model::Model;
+
+/// Lower detail replacement of the scene model.
+pub struct LodLevel {
+    pub model: Model,
+    /// Camera distance from which this level replaces the one before it.
+    pub distance: f32,
+}
+
+/// Levels of detail of the scene model, level 0 being the model itself.
+/// Instances cross-fade between neighbouring levels with a screen-door dither
+/// while their distance is within `fade_band` around a switch distance.
+pub struct Lods {
+    levels: Vec<LodLevel>,
+    /// Width of the cross-fade band around each switch distance, 0 pops.
+    pub fade_band: f32,
+}
+
+/// Levels an instance is drawn with.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum LodSelection {
+    Single(usize),
+    /// Halfway through the band `t` is 0.5, each level covering half of the pixels.
+    CrossFade { from: usize, to: usize, t: f32 },
+}
+
+impl LodSelection {
+    /// Levels with the fade stored in their instance data: positive fades in
+    /// and covers that fraction of pixels, negative covers the rest.
+    pub fn fades(self) -> [Option<(usize, f32)>; 2] {
+        return match self {
+            LodSelection::Single(level) => [Some((level, 1.0)), None],
+            LodSelection::CrossFade { from, to, t } => [Some((from, -t)), Some((to, t))],
+        };
+    }
+}
+
+impl Lods {
+    pub fn new(fade_band: f32) -> Self {
+        return Self {
+            levels: Vec::new(),
+            fade_band,
+        };
+    }
+
+    /// Add a level, kept sorted by switch distance.
+    pub fn push(&mut self, model: Model, distance: f32) {
+        let index = self.levels.partition_point(|l| l.distance <= distance);
+        self.levels.insert(index, LodLevel { model, distance });
+    }
+
+    pub fn levels(&self) -> &[LodLevel] {
+        return &self.levels;
+    }
+
+    pub fn levels_mut(&mut self) -> &mut [LodLevel] {
+        return &mut self.levels;
+    }
+
+    pub fn is_empty(&self) -> bool {
+        return self.levels.is_empty();
+    }
+
+    /// Number of levels including the scene model.
+    pub fn level_count(&self) -> usize {
+        return self.levels.len() + 1;
+    }
+
+    pub fn select(&self, distance: f32) -> LodSelection {
+        let half_band = self.fade_band.max(0.0) * 0.5;
+        for (i, level) in self.levels.iter().enumerate() {
+            if distance < level.distance - half_band {
+                return LodSelection::Single(i);
+            }
+            if distance < level.distance + half_band {
+                let t = (distance - (level.distance - half_band)) / (2.0 * half_band);
+                return LodSelection::CrossFade {
+                    from: i,
+                    to: i + 1,
+                    t,
+                };
+            }
+        }
+        return LodSelection::Single(self.levels.len());
+    }
+}
+
+impl Default for Lods {
+    fn default() -> Self {
+        return Self::new(2.0);
+    }
+}
@@ -0,0 +1,317 @@
+use std::sync::{Arc, Mutex};
+
+use crate::{
+    memory::{MemoryCategory, MemoryTracker, TrackedBuffer},
+    shader::ShaderPreprocessor,
+    texture::Texture,
+};
+
+#[repr(C)]
+#[derive(Debug, Copy, Clone, bytemuck::Pod, bytemuck::Zeroable)]
+struct HistogramParams {
+    min_log_luminance: f32,
+    log_luminance_range: f32,
+    width: u32,
+    height: u32,
+}
+
+/// Luminance histogram of one rendered frame, before display calibration.
+#[derive(Debug, Clone, PartialEq)]
+pub struct SceneLuminance {
+    /// Pixel counts, bin 0 for pixels below `min_log_luminance` (black
+    /// included), the rest splitting the range evenly.
+    pub histogram: Vec<u32>,
+    pub min_log_luminance: f32,
+    pub max_log_luminance: f32,
+    /// Geometric mean of the luminance of the pixels within the range, 0 if
+    /// there are none.
+    pub average: f32,
+    /// Frame the histogram was computed after.
+    pub frame: u64,
+}
+
+impl SceneLuminance {
+    fn from_histogram(histogram: Vec<u32>, min_log_luminance: f32, max_log_luminance: f32, frame: u64) -> Self {
+        let mut luminance = Self {
+            histogram,
+            min_log_luminance,
+            max_log_luminance,
+            average: 0.0,
+            frame,
+        };
+        let lit = luminance.histogram[1..].iter().map(|&c| c as f64).sum::<f64>();
+        if lit > 0.0 {
+            let log_sum = (1..luminance.histogram.len())
+                .map(|i| {
+                    let (low, high) = luminance.bin_range(i);
+                    luminance.histogram[i] as f64 * (low + high) as f64 * 0.5
+                })
+                .sum::<f64>();
+            luminance.average = (log_sum / lit).exp2() as f32;
+        }
+        return luminance;
+    }
+
+    pub fn pixel_count(&self) -> u32 {
+        return self.histogram.iter().sum();
+    }
+
+    /// Log2 luminance range covered by bin `index`, bin 0 reaching down to
+    /// negative infinity.
+    pub fn bin_range(&self, index: usize) -> (f32, f32) {
+        let step = (self.max_log_luminance - self.min_log_luminance) / (self.histogram.len() - 1) as f32;
+        return match index {
+            0 => (f32::NEG_INFINITY, self.min_log_luminance),
+            _ => (
+                self.min_log_luminance + step * (index - 1) as f32,
+                self.min_log_luminance + step * index as f32,
+            ),
+        };
+    }
+
+    /// Fraction of pixels darker than the range, black ones included.
+    pub fn dark_fraction(&self) -> f32 {
+        return self.histogram[0] as f32 / self.pixel_count().max(1) as f32;
+    }
+}
+
+type MapResult = Arc<Mutex<Option<Result<(), wgpu::BufferAsyncError>>>>;
+
+// Readback buffer state between submitting the copy and reading it, with the
+// frame and log2 luminance range it was recorded with
+enum Readback {
+    Idle,
+    Pending { frame: u64, range: (f32, f32) },
+    Mapping { frame: u64, range: (f32, f32), result: MapResult },
+}
+
+/// Computes luminance histograms of the scene color in a compute pass and
+/// reads them back without stalling, at most one frame in flight.
+pub struct LuminanceHistogram {
+    /// Log2 luminance range of the histogram, wider ranges get coarser bins.
+    pub min_log_luminance: f32,
+    pub max_log_luminance: f32,
+    bind_group_layout: wgpu::BindGroupLayout,
+    pipeline: wgpu::ComputePipeline,
+    params_buffer: TrackedBuffer,
+    bins_buffer: TrackedBuffer,
+    readback_buffer: TrackedBuffer,
+    readback: Readback,
+    latest: Option<SceneLuminance>,
+}
+
+impl LuminanceHistogram {
+    pub const BINS: usize = 64;
+    const WORKGROUP_SIZE: u32 = 16;
+
+    pub fn new(device: &wgpu::Device, memory: &MemoryTracker) -> Self {
+        let bind_group_layout = device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
+            entries: &[
+                wgpu::BindGroupLayoutEntry {
+                    binding: 0,
+                    visibility: wgpu::ShaderStages::COMPUTE,
+                    ty: wgpu::BindingType::Texture {
+                        multisampled: false,
+                        view_dimension: wgpu::TextureViewDimension::D2,
+                        sample_type: wgpu::TextureSampleType::Float { filterable: false },
+                    },
+                    count: None,
+                },
+                wgpu::BindGroupLayoutEntry {
+                    binding: 1,
+                    visibility: wgpu::ShaderStages::COMPUTE,
+                    ty: wgpu::BindingType::Buffer {
+                        ty: wgpu::BufferBindingType::Uniform,
+                        has_dynamic_offset: false,
+                        min_binding_size: None,
+                    },
+                    count: None,
+                },
+                wgpu::BindGroupLayoutEntry {
+                    binding: 2,
+                    visibility: wgpu::ShaderStages::COMPUTE,
+                    ty: wgpu::BindingType::Buffer {
+                        ty: wgpu::BufferBindingType::Storage { read_only: false },
+                        has_dynamic_offset: false,
+                        min_binding_size: None,
+                    },
+                    count: None,
+                },
+            ],
+            label: Some("luminance_bind_group_layout"),
+        });
+
+        let shader = ShaderPreprocessor::new()
+            .descriptor("Luminance Shader", "luminance.wgsl")
+            .expect("Failed to preprocess luminance.wgsl");
+        let shader = device.create_shader_module(shader);
+        let layout = device.create_pipeline_layout(&wgpu::PipelineLayoutDescriptor {
+            label: Some("Luminance Pipeline Layout"),
+            bind_group_layouts: &[&bind_group_layout],
+            push_constant_ranges: &[],
+        });
+        let pipeline = device.create_compute_pipeline(&wgpu::ComputePipelineDescriptor {
+            label: Some("Luminance Pipeline"),
+            layout: Some(&layout),
+            module: &shader,
+            entry_point: "histogram_main",
+        });
+
+        let params_buffer = memory.create_buffer(
+            device,
+            &wgpu::BufferDescriptor {
+                label: Some("Luminance Params Buffer"),
+                size: std::mem::size_of::<HistogramParams>() as u64,
+                usage: wgpu::BufferUsages::UNIFORM | wgpu::BufferUsages::COPY_DST,
+                mapped_at_creation: false,
+            },
+            MemoryCategory::Uniform,
+        );
+        let bins_size = (Self::BINS * std::mem::size_of::<u32>()) as u64;
+        let bins_buffer = memory.create_buffer(
+            device,
+            &wgpu::BufferDescriptor {
+                label: Some("Luminance Bins Buffer"),
+                size: bins_size,
+                usage: wgpu::BufferUsages::STORAGE | wgpu::BufferUsages::COPY_SRC | wgpu::BufferUsages::COPY_DST,
+                mapped_at_creation: false,
+            },
+            MemoryCategory::Other,
+        );
+        let readback_buffer = memory.create_buffer(
+            device,
+            &wgpu::BufferDescriptor {
+                label: Some("Luminance Readback Buffer"),
+                size: bins_size,
+                usage: wgpu::BufferUsages::COPY_DST | wgpu::BufferUsages::MAP_READ,
+                mapped_at_creation: false,
+            },
+            MemoryCategory::Other,
+        );
+
+        return Self {
+            min_log_luminance: -10.0,
+            max_log_luminance: 6.0,
+            bind_group_layout,
+            pipeline,
+            params_buffer,
+            bins_buffer,
+            readback_buffer,
+            readback: Readback::Idle,
+            latest: None,
+        };
+    }
+
+    /// Most recent histogram read back, a few frames behind the screen.
+    pub fn latest(&self) -> Option<&SceneLuminance> {
+        return self.latest.as_ref();
+    }
+
+    /// Whether `encode` would record a new histogram.
+    pub fn is_idle(&self) -> bool {
+        return matches!(self.readback, Readback::Idle);
+    }
+
+    /// Record the histogram of `scene`, `width` by `height` pixels, and its
+    /// copy for reading back. Does nothing while the previous histogram is
+    /// still being read.
+    #[allow(clippy::too_many_arguments)]
+    pub fn encode(
+        &mut self,
+        device: &wgpu::Device,
+        queue: &wgpu::Queue,
+        encoder: &mut wgpu::CommandEncoder,
+        scene: &Texture,
+        width: u32,
+        height: u32,
+        frame: u64,
+    ) {
+        if !self.is_idle() {
+            return;
+        }
+        let params = HistogramParams {
+            min_log_luminance: self.min_log_luminance,
+            log_luminance_range: (self.max_log_luminance - self.min_log_luminance).max(0.001),
+            width,
+            height,
+        };
+        queue.write_buffer(&self.params_buffer, 0, bytemuck::cast_slice(&[params]));
+        queue.write_buffer(&self.bins_buffer, 0, &[0; Self::BINS * 4]);
+
+        // The scene texture is recreated on resize, so bind it each time
+        let bind_group = device.create_bind_group(&wgpu::BindGroupDescriptor {
+            layout: &self.bind_group_layout,
+            entries: &[
+                wgpu::BindGroupEntry {
+                    binding: 0,
+                    resource: wgpu::BindingResource::TextureView(&scene.view),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 1,
+                    resource: self.params_buffer.as_entire_binding(),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 2,
+                    resource: self.bins_buffer.as_entire_binding(),
+                },
+            ],
+            label: Some("luminance_bind_group"),
+        });
+        {
+            let mut pass = encoder.begin_compute_pass(&wgpu::ComputePassDescriptor {
+                label: Some("Luminance Pass"),
+            });
+            pass.set_pipeline(&self.pipeline);
+            pass.set_bind_group(0, &bind_group, &[]);
+            pass.dispatch_workgroups(
+                width.div_ceil(Self::WORKGROUP_SIZE),
+                height.div_ceil(Self::WORKGROUP_SIZE),
+                1,
+            );
+        }
+        encoder.copy_buffer_to_buffer(&self.bins_buffer, 0, &self.readback_buffer, 0, self.bins_buffer.size());
+        self.readback = Readback::Pending {
+            frame,
+            range: (self.min_log_luminance, self.min_log_luminance + params.log_luminance_range),
+        };
+    }
+
+    /// Start mapping the readback, once the encoder from `encode` was submitted.
+    pub fn map(&mut self) {
+        if let Readback::Pending { frame, range } = self.readback {
+            let result = Arc::new(Mutex::new(None));
+            let sender = result.clone();
+            self.readback_buffer
+                .slice(..)
+                .map_async(wgpu::MapMode::Read, move |r| {
+                    *sender.lock().unwrap() = Some(r);
+                });
+            self.readback = Readback::Mapping { frame, range, result };
+        }
+    }
+
+    /// Pick up a finished readback, needs the device to have been polled.
+    /// Returns true if `latest` changed.
+    pub fn poll(&mut self) -> bool {
+        let (frame, range, mapped) = match &self.readback {
+            Readback::Mapping { frame, range, result } => match result.lock().unwrap().take() {
+                Some(mapped) => (*frame, *range, mapped),
+                None => return false,
+            },
+            _ => return false,
+        };
+        self.readback = Readback::Idle;
+        if let Err(e) = mapped {
+            log::warn!("Failed to read back the luminance histogram: {:?}", e);
+            return false;
+        }
+
+        let histogram = {
+            let data = self.readback_buffer.slice(..).get_mapped_range();
+            bytemuck::cast_slice::<u8, u32>(&data).to_vec()
+        };
+        self.readback_buffer.unmap();
+        self.latest = Some(SceneLuminance::from_histogram(histogram, range.0, range.1, frame));
+        return true;
+    }
+}
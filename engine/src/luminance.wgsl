@@ -0,0 +1,35 @@
+// Histogram of the log2 luminance of the HDR scene color, one thread per
+// pixel. Bin 0 counts pixels below the range, black ones included, the other
+// bins split the range evenly.
+
+struct Params {
+    min_log_luminance: f32,
+    log_luminance_range: f32,
+    width: u32,
+    height: u32,
+};
+@group(0) @binding(0)
+var t_scene: texture_2d<f32>;
+@group(0) @binding(1)
+var<uniform> params: Params;
+@group(0) @binding(2)
+var<storage, read_write> bins: array<atomic<u32>, 64>;
+
+let BINS: u32 = 64u;
+
+@compute @workgroup_size(16, 16)
+fn histogram_main(@builtin(global_invocation_id) id: vec3<u32>) {
+    if (id.x >= params.width || id.y >= params.height) {
+        return;
+    }
+    let color = textureLoad(t_scene, vec2<i32>(id.xy), 0).rgb;
+    let luminance = dot(color, vec3<f32>(0.2126, 0.7152, 0.0722));
+    var bin = 0u;
+    if (luminance > 0.0) {
+        let t = (log2(luminance) - params.min_log_luminance) / params.log_luminance_range;
+        if (t >= 0.0) {
+            bin = min(u32(t * f32(BINS - 1u)), BINS - 2u) + 1u;
+        }
+    }
+    atomicAdd(&bins[bin], 1u);
+}
@@ -0,0 +1,370 @@
+use std::{collections::BTreeMap, sync::Arc};
+
+use anyhow::*;
+use serde::{ser::SerializeMap, Deserialize, Serialize};
+
+use crate::legacy::LegacyMaterial;
+
+/// Name of the uniform struct in material shaders that holds tweakable parameters.
+pub const MATERIAL_PARAMS_STRUCT: &str = "MaterialParams";
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum ParamType {
+    Float,
+    Int,
+    UInt,
+    Vec2,
+    Vec3,
+    Vec4,
+}
+
+impl ParamType {
+    fn from_naga(inner: &naga::TypeInner) -> Option<Self> {
+        return match *inner {
+            naga::TypeInner::Scalar { kind, width: 4 } => match kind {
+                naga::ScalarKind::Float => Some(ParamType::Float),
+                naga::ScalarKind::Sint => Some(ParamType::Int),
+                naga::ScalarKind::Uint => Some(ParamType::UInt),
+                naga::ScalarKind::Bool => None,
+            },
+            naga::TypeInner::Vector {
+                size,
+                kind: naga::ScalarKind::Float,
+                width: 4,
+            } => match size {
+                naga::VectorSize::Bi => Some(ParamType::Vec2),
+                naga::VectorSize::Tri => Some(ParamType::Vec3),
+                naga::VectorSize::Quad => Some(ParamType::Vec4),
+            },
+            _ => None,
+        };
+    }
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(untagged)]
+pub enum ParamValue {
+    Float(f32),
+    Int(i32),
+    UInt(u32),
+    Vec2([f32; 2]),
+    Vec3([f32; 3]),
+    Vec4([f32; 4]),
+}
+
+impl ParamValue {
+    pub fn ty(&self) -> ParamType {
+        return match self {
+            ParamValue::Float(_) => ParamType::Float,
+            ParamValue::Int(_) => ParamType::Int,
+            ParamValue::UInt(_) => ParamType::UInt,
+            ParamValue::Vec2(_) => ParamType::Vec2,
+            ParamValue::Vec3(_) => ParamType::Vec3,
+            ParamValue::Vec4(_) => ParamType::Vec4,
+        };
+    }
+
+    fn bytes(&self) -> Vec<u8> {
+        return match self {
+            ParamValue::Float(v) => bytemuck::bytes_of(v).to_vec(),
+            ParamValue::Int(v) => bytemuck::bytes_of(v).to_vec(),
+            ParamValue::UInt(v) => bytemuck::bytes_of(v).to_vec(),
+            ParamValue::Vec2(v) => bytemuck::cast_slice(v).to_vec(),
+            ParamValue::Vec3(v) => bytemuck::cast_slice(v).to_vec(),
+            ParamValue::Vec4(v) => bytemuck::cast_slice(v).to_vec(),
+        };
+    }
+
+    fn read(ty: ParamType, bytes: &[u8]) -> Self {
+        let floats = |n: usize| -> Vec<f32> {
+            return bytes[..n * 4]
+                .chunks_exact(4)
+                .map(|c| f32::from_ne_bytes([c[0], c[1], c[2], c[3]]))
+                .collect();
+        };
+        let word = [bytes[0], bytes[1], bytes[2], bytes[3]];
+        return match ty {
+            ParamType::Float => ParamValue::Float(f32::from_ne_bytes(word)),
+            ParamType::Int => ParamValue::Int(i32::from_ne_bytes(word)),
+            ParamType::UInt => ParamValue::UInt(u32::from_ne_bytes(word)),
+            ParamType::Vec2 => ParamValue::Vec2(floats(2).try_into().unwrap()),
+            ParamType::Vec3 => ParamValue::Vec3(floats(3).try_into().unwrap()),
+            ParamType::Vec4 => ParamValue::Vec4(floats(4).try_into().unwrap()),
+        };
+    }
+
+    /// Values coming from untyped sources (scene files, consoles) may not
+    /// match the declared type exactly, e.g. `1` for a float field.
+    fn coerce(self, ty: ParamType) -> Option<Self> {
+        if self.ty() == ty {
+            return Some(self);
+        }
+        return match (self, ty) {
+            (ParamValue::Int(v), ParamType::Float) => Some(ParamValue::Float(v as f32)),
+            (ParamValue::UInt(v), ParamType::Float) => Some(ParamValue::Float(v as f32)),
+            (ParamValue::Int(v), ParamType::UInt) if v >= 0 => Some(ParamValue::UInt(v as u32)),
+            (ParamValue::UInt(v), ParamType::Int) => Some(ParamValue::Int(v as i32)),
+            _ => None,
+        };
+    }
+}
+
+impl From<f32> for ParamValue {
+    fn from(v: f32) -> Self {
+        ParamValue::Float(v)
+    }
+}
+
+impl From<i32> for ParamValue {
+    fn from(v: i32) -> Self {
+        ParamValue::Int(v)
+    }
+}
+
+impl From<u32> for ParamValue {
+    fn from(v: u32) -> Self {
+        ParamValue::UInt(v)
+    }
+}
+
+impl From<[f32; 2]> for ParamValue {
+    fn from(v: [f32; 2]) -> Self {
+        ParamValue::Vec2(v)
+    }
+}
+
+impl From<[f32; 3]> for ParamValue {
+    fn from(v: [f32; 3]) -> Self {
+        ParamValue::Vec3(v)
+    }
+}
+
+impl From<[f32; 4]> for ParamValue {
+    fn from(v: [f32; 4]) -> Self {
+        ParamValue::Vec4(v)
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct ParamField {
+    pub name: String,
+    pub ty: ParamType,
+    pub offset: u32,
+}
+
+/// Layout of a material uniform block, reflected from WGSL source.
+#[derive(Debug, Clone)]
+pub struct MaterialLayout {
+    pub fields: Vec<ParamField>,
+    pub size: u32,
+}
+
+impl MaterialLayout {
+    pub fn reflect(source: &str, struct_name: &str) -> Result<Self> {
+        let module = naga::front::wgsl::parse_str(source)
+            .map_err(|e| anyhow!("Failed to parse shader: {}", e.emit_to_string(source)))?;
+
+        let (members, span) = module
+            .types
+            .iter()
+            .find_map(|(_, ty)| match &ty.inner {
+                naga::TypeInner::Struct { members, span }
+                    if ty.name.as_deref() == Some(struct_name) =>
+                {
+                    Some((members, *span))
+                }
+                _ => None,
+            })
+            .with_context(|| format!("Struct `{}` not found in shader", struct_name))?;
+
+        let mut fields = Vec::new();
+        for member in members {
+            let name = member.name.clone().unwrap_or_default();
+            let ty = ParamType::from_naga(&module.types[member.ty].inner)
+                .with_context(|| format!("Unsupported type for material parameter `{}`", name))?;
+            fields.push(ParamField {
+                name,
+                ty,
+                offset: member.offset,
+            });
+        }
+
+        return Ok(Self {
+            fields,
+            size: span,
+        });
+    }
+
+    pub fn field(&self, name: &str) -> Option<&ParamField> {
+        return self.fields.iter().find(|f| f.name == name);
+    }
+}
+
+/// CPU copy of a material's uniform block whose fields can be set by name.
+#[derive(Debug, Clone)]
+pub struct MaterialParams {
+    layout: Arc<MaterialLayout>,
+    data: Vec<u8>,
+    dirty: bool,
+}
+
+impl MaterialParams {
+    pub fn new(layout: Arc<MaterialLayout>) -> Self {
+        let data = vec![0; layout.size as usize];
+        return Self {
+            layout,
+            data,
+            dirty: true,
+        };
+    }
+
+    pub fn layout(&self) -> &MaterialLayout {
+        return &self.layout;
+    }
+
+    pub fn set<V: Into<ParamValue>>(&mut self, name: &str, value: V) -> Result<()> {
+        let field = self
+            .layout
+            .field(name)
+            .with_context(|| format!("Unknown material parameter `{}`", name))?;
+        let value = value.into().coerce(field.ty).with_context(|| {
+            format!("Material parameter `{}` expects {:?}", name, field.ty)
+        })?;
+
+        let bytes = value.bytes();
+        let offset = field.offset as usize;
+        self.data[offset..offset + bytes.len()].copy_from_slice(&bytes);
+        self.dirty = true;
+        Ok(())
+    }
+
+    pub fn get(&self, name: &str) -> Option<ParamValue> {
+        let field = self.layout.field(name)?;
+        return Some(ParamValue::read(field.ty, &self.data[field.offset as usize..]));
+    }
+
+    /// All parameters in declaration order, for inspection and editing UIs.
+    pub fn values(&self) -> Vec<(String, ParamValue)> {
+        return self
+            .layout
+            .fields
+            .iter()
+            .map(|f| {
+                (
+                    f.name.clone(),
+                    ParamValue::read(f.ty, &self.data[f.offset as usize..]),
+                )
+            })
+            .collect();
+    }
+
+    /// Apply previously serialized values; unknown names are reported but skipped.
+    pub fn apply(&mut self, values: &BTreeMap<String, ParamValue>) {
+        for (name, value) in values {
+            if let Err(e) = self.set(name, *value) {
+                log::warn!("{}", e);
+            }
+        }
+    }
+
+    pub fn data(&self) -> &[u8] {
+        return &self.data;
+    }
+
+    pub fn is_dirty(&self) -> bool {
+        return self.dirty;
+    }
+
+    pub fn clear_dirty(&mut self) {
+        self.dirty = false;
+    }
+}
+
+impl Serialize for MaterialParams {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let values = self.values();
+        let mut map = serializer.serialize_map(Some(values.len()))?;
+        for (name, value) in &values {
+            map.serialize_entry(name, value)?;
+        }
+        map.end()
+    }
+}
+
+/// How a material is drawn by the basic shader.
+#[derive(Debug, Copy, Clone, PartialEq, Default, Serialize, Deserialize)]
+pub enum ShadingModel {
+    #[default]
+    Opaque,
+    // Alpha below `cutoff` is discarded
+    Cutout { cutoff: f32 },
+    /// Blended by its alpha with weighted blended order independent
+    /// transparency after the opaque scene, see `WeightedBlendedOit`. Drawn
+    /// opaque by crowds and render targets.
+    Transparent,
+}
+
+/// Sides of a material's triangles that are drawn.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Default, Serialize, Deserialize)]
+pub enum CullMode {
+    /// Front faces only, for closed meshes.
+    #[default]
+    Back,
+    /// Back faces only, for imported meshes wound the other way.
+    Front,
+    /// Both sides, back faces lit as seen, e.g. foliage cards and cloth.
+    DoubleSided,
+}
+
+/// Contents of a `.mat` file, written in RON:
+///
+/// ```ron
+/// (
+///     name: "Happy-Tree",
+///     diffuse: Some("happy-tree.png"),
+///     normal: None,
+///     shading: Cutout(cutoff: 0.5),
+///     cull: DoubleSided,
+///     params: { "tint": (1.0, 0.9, 0.8, 1.0), "roughness": 0.7 },
+/// )
+/// ```
+///
+/// Texture paths are relative to the resource directory; parameters not
+/// listed keep `Material::default_params`. Older assets can give their
+/// Phong values instead, e.g. `legacy: Some((diffuse: (0.8, 0.8, 0.8),
+/// specular: (0.5, 0.5, 0.5), shininess: 324.0))`, converted into `tint`
+/// and `roughness` on load, see `LegacyMaterial`.
+#[derive(Debug, Clone, PartialEq, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct MaterialDesc {
+    pub name: String,
+    pub diffuse: Option<String>,
+    pub normal: Option<String>,
+    pub shading: ShadingModel,
+    pub cull: CullMode,
+    pub legacy: Option<LegacyMaterial>,
+    pub params: BTreeMap<String, ParamValue>,
+}
+
+impl MaterialDesc {
+    pub fn parse(source: &str) -> Result<Self> {
+        return ron::from_str(source).context("Invalid material description");
+    }
+
+    /// Parameter values for `layout`, `params` override what `legacy`
+    /// converts to and the shading model overrides `params`.
+    pub fn build_params(&self, mut params: MaterialParams) -> Result<MaterialParams> {
+        if let Some(legacy) = &self.legacy {
+            legacy.to_metallic_roughness().apply(&mut params)?;
+        }
+        for (name, value) in &self.params {
+            params.set(name, *value)?;
+        }
+        let cutoff = match self.shading {
+            ShadingModel::Opaque | ShadingModel::Transparent => 0.0,
+            ShadingModel::Cutout { cutoff } => cutoff,
+        };
+        params.set("alpha_cutoff", cutoff)?;
+        return Ok(params);
+    }
+}
@@ -0,0 +1,536 @@
+use cgmath::{prelude::*, Decomposed, Matrix3, Matrix4, Point3, Quaternion, Vector3, Vector4};
+
+/// Planes past this many are left out of `FrustumRaw`.
+pub const MAX_FRUSTUM_PLANES: usize = 8;
+
+/// Axis aligned bounding box.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct Aabb {
+    pub min: Vector3<f32>,
+    pub max: Vector3<f32>,
+}
+
+impl Aabb {
+    pub fn new(min: Vector3<f32>, max: Vector3<f32>) -> Self {
+        return Self { min, max };
+    }
+
+    pub fn center(&self) -> Vector3<f32> {
+        return (self.min + self.max) * 0.5;
+    }
+
+    pub fn union(&self, other: &Aabb) -> Aabb {
+        return Aabb {
+            min: Vector3::new(
+                self.min.x.min(other.min.x),
+                self.min.y.min(other.min.y),
+                self.min.z.min(other.min.z),
+            ),
+            max: Vector3::new(
+                self.max.x.max(other.max.x),
+                self.max.y.max(other.max.y),
+                self.max.z.max(other.max.z),
+            ),
+        };
+    }
+
+    pub fn intersects(&self, other: &Aabb) -> bool {
+        return (0..3).all(|i| self.min[i] <= other.max[i] && other.min[i] <= self.max[i]);
+    }
+
+    pub fn intersects_sphere(&self, center: Vector3<f32>, radius: f32) -> bool {
+        let closest = Vector3::new(
+            center.x.clamp(self.min.x, self.max.x),
+            center.y.clamp(self.min.y, self.max.y),
+            center.z.clamp(self.min.z, self.max.z),
+        );
+        return (closest - center).magnitude2() <= radius * radius;
+    }
+
+    /// Bounds of the box's corners after `matrix`.
+    pub fn transform(&self, matrix: &Matrix4<f32>) -> Aabb {
+        let corners = (0..8).map(|i| {
+            let pick = |bit: usize, axis: usize| if i & bit != 0 { self.max[axis] } else { self.min[axis] };
+            (matrix * Vector3::new(pick(1, 0), pick(2, 1), pick(4, 2)).extend(1.0)).truncate()
+        });
+        return corners
+            .map(|c| Aabb::new(c, c))
+            .reduce(|a, b| a.union(&b))
+            .unwrap_or(*self);
+    }
+
+    /// Distance from the origin to the farthest point of the box along `direction`.
+    pub fn support(&self, direction: Vector3<f32>) -> f32 {
+        let pick = |d: f32, min: f32, max: f32| if d >= 0.0 { max } else { min };
+        let corner = Vector3::new(
+            pick(direction.x, self.min.x, self.max.x),
+            pick(direction.y, self.min.y, self.max.y),
+            pick(direction.z, self.min.z, self.max.z),
+        );
+        return corner.dot(direction);
+    }
+
+    pub fn to_raw(&self) -> AabbRaw {
+        return AabbRaw {
+            min: self.min.extend(0.0).into(),
+            max: self.max.extend(0.0).into(),
+        };
+    }
+}
+
+/// Points with `normal.dot(p) >= distance` are in front of the plane.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct Plane {
+    pub normal: Vector3<f32>,
+    pub distance: f32,
+}
+
+impl Plane {
+    pub fn new(normal: Vector3<f32>, distance: f32) -> Self {
+        let length = normal.magnitude();
+        return Self {
+            normal: normal / length,
+            distance: distance / length,
+        };
+    }
+
+    pub fn from_point_normal(point: Vector3<f32>, normal: Vector3<f32>) -> Self {
+        let normal = normal.normalize();
+        return Self {
+            normal,
+            distance: normal.dot(point),
+        };
+    }
+
+    pub fn signed_distance(&self, point: Vector3<f32>) -> f32 {
+        return self.normal.dot(point) - self.distance;
+    }
+
+    /// The same plane facing the other way.
+    pub fn flipped(&self) -> Self {
+        return Self {
+            normal: -self.normal,
+            distance: -self.distance,
+        };
+    }
+
+    /// `(a, b, c, d)` with `ax + by + cz + d >= 0` in front, as used by shaders.
+    pub fn equation(&self) -> [f32; 4] {
+        return [self.normal.x, self.normal.y, self.normal.z, -self.distance];
+    }
+}
+
+impl From<Plane> for Vector4<f32> {
+    fn from(plane: Plane) -> Self {
+        return plane.equation().into();
+    }
+}
+
+/// From an `(a, b, c, d)` equation, normalized.
+impl From<Vector4<f32>> for Plane {
+    fn from(equation: Vector4<f32>) -> Self {
+        return Plane::new(equation.truncate(), -equation.w);
+    }
+}
+
+/// Convex volume bounded by planes facing inwards, like a camera's view.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Frustum {
+    pub planes: Vec<Plane>,
+}
+
+impl Frustum {
+    pub fn new(planes: Vec<Plane>) -> Self {
+        return Self { planes };
+    }
+
+    /// The six planes of a view projection with wgpu's 0 to 1 clip depth.
+    pub fn from_view_proj(view_proj: &Matrix4<f32>) -> Self {
+        let [r0, r1, r2, r3] = [0, 1, 2, 3].map(|i| view_proj.row(i));
+        let planes = [r3 + r0, r3 - r0, r3 + r1, r3 - r1, r2, r3 - r2]
+            .into_iter()
+            .map(|p| Plane::new(p.truncate(), -p.w))
+            .collect();
+        return Self { planes };
+    }
+
+    pub fn contains_point(&self, point: Vector3<f32>) -> bool {
+        return self.planes.iter().all(|p| p.signed_distance(point) >= 0.0);
+    }
+
+    /// False only if the box is entirely behind one of the planes, so boxes
+    /// near corners may pass without touching the volume.
+    pub fn intersects_aabb(&self, aabb: &Aabb) -> bool {
+        return self.planes.iter().all(|p| aabb.support(p.normal) >= p.distance);
+    }
+
+    /// The first `MAX_FRUSTUM_PLANES` planes, the rest left all zero so they
+    /// contain everything. Dropped planes only make tests more conservative.
+    pub fn to_raw(&self) -> FrustumRaw {
+        let mut raw = FrustumRaw::default();
+        for (slot, plane) in raw.planes.iter_mut().zip(&self.planes) {
+            *slot = plane.equation();
+        }
+        return raw;
+    }
+}
+
+#[derive(Debug, Copy, Clone)]
+pub struct Ray {
+    pub origin: Point3<f32>,
+    pub direction: Vector3<f32>,
+}
+
+impl Ray {
+    /// Ray through a pixel of a `width` x `height` viewport, unprojected with
+    /// the camera's inverse view-projection matrix.
+    pub fn from_screen(
+        x: f32,
+        y: f32,
+        width: u32,
+        height: u32,
+        inv_view_proj: Matrix4<f32>,
+    ) -> Self {
+        let ndc_x = 2.0 * x / width as f32 - 1.0;
+        let ndc_y = 1.0 - 2.0 * y / height as f32;
+
+        let unproject = |z: f32| {
+            let p = inv_view_proj * Vector4::new(ndc_x, ndc_y, z, 1.0);
+            Point3::from_homogeneous(p)
+        };
+        let near = unproject(0.0);
+        let far = unproject(1.0);
+
+        return Self {
+            origin: near,
+            direction: (far - near).normalize(),
+        };
+    }
+
+    pub fn at(&self, t: f32) -> Point3<f32> {
+        return self.origin + self.direction * t;
+    }
+
+    /// Distance along the ray to the plane through `point` with `normal`.
+    pub fn intersect_plane(&self, point: Point3<f32>, normal: Vector3<f32>) -> Option<f32> {
+        let denom = normal.dot(self.direction);
+        if denom.abs() < 1e-6 {
+            return None;
+        }
+        let t = normal.dot(point - self.origin) / denom;
+        return (t >= 0.0).then_some(t);
+    }
+
+    /// Slab test against a box; returns the entry distance and the normal of the face hit.
+    pub fn intersect_aabb(&self, aabb: &Aabb) -> Option<(f32, Vector3<f32>)> {
+        let mut t_min = 0.0_f32;
+        let mut t_max = f32::MAX;
+        let mut normal = Vector3::zero();
+
+        for axis in 0..3 {
+            let origin = self.origin[axis];
+            let direction = self.direction[axis];
+            if direction.abs() < 1e-8 {
+                if origin < aabb.min[axis] || origin > aabb.max[axis] {
+                    return None;
+                }
+                continue;
+            }
+
+            let inv = 1.0 / direction;
+            let mut t0 = (aabb.min[axis] - origin) * inv;
+            let mut t1 = (aabb.max[axis] - origin) * inv;
+            let mut sign = -1.0;
+            if t0 > t1 {
+                std::mem::swap(&mut t0, &mut t1);
+                sign = 1.0;
+            }
+            if t0 > t_min {
+                t_min = t0;
+                normal = Vector3::zero();
+                normal[axis] = sign;
+            }
+            t_max = t_max.min(t1);
+            if t_min > t_max {
+                return None;
+            }
+        }
+
+        return Some((t_min, normal));
+    }
+
+    /// The same ray expressed in the local space of `transform`, e.g. an
+    /// instance's.
+    // Scaling the direction along with the origin keeps ray distances the same in both spaces
+    pub fn in_space_of(&self, transform: &Transform) -> Ray {
+        let inv_rotation = transform.rotation.invert();
+        let inv_scale = Vector3::new(1.0, 1.0, 1.0).div_element_wise(transform.scale);
+        return Ray {
+            origin: Point3::from_vec(
+                inv_rotation
+                    .rotate_vector(self.origin - Point3::from_vec(transform.position))
+                    .mul_element_wise(inv_scale),
+            ),
+            direction: inv_rotation.rotate_vector(self.direction).mul_element_wise(inv_scale),
+        };
+    }
+
+    pub fn to_raw(&self) -> RayRaw {
+        return RayRaw {
+            origin: self.origin.to_homogeneous().into(),
+            direction: self.direction.extend(0.0).into(),
+        };
+    }
+}
+
+impl From<(Point3<f32>, Vector3<f32>)> for Ray {
+    fn from((origin, direction): (Point3<f32>, Vector3<f32>)) -> Self {
+        return Self { origin, direction };
+    }
+}
+
+
+/// Translation, rotation and scale along the rotated axes, applied scale
+/// first like an instance's.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct Transform {
+    pub position: Vector3<f32>,
+    pub rotation: Quaternion<f32>,
+    pub scale: Vector3<f32>,
+}
+
+impl Transform {
+    pub fn new(position: Vector3<f32>, rotation: Quaternion<f32>, scale: Vector3<f32>) -> Self {
+        return Self {
+            position,
+            rotation,
+            scale,
+        };
+    }
+
+    pub fn from_position(position: Vector3<f32>) -> Self {
+        return Self {
+            position,
+            ..Self::default()
+        };
+    }
+
+    pub fn matrix(&self) -> Matrix4<f32> {
+        return Matrix4::from_translation(self.position)
+            * Matrix4::from(self.rotation)
+            * Matrix4::from_nonuniform_scale(self.scale.x, self.scale.y, self.scale.z);
+    }
+
+    /// Transforms normals, the inverse transpose of the matrix's upper 3x3.
+    /// Falls back to the rotation for degenerate (zero) scale.
+    pub fn normal_matrix(&self) -> Matrix3<f32> {
+        let m = self.matrix();
+        let linear = Matrix3::from_cols(m.x.truncate(), m.y.truncate(), m.z.truncate());
+        return match linear.invert() {
+            Some(inverse) => inverse.transpose(),
+            None => Matrix3::from(self.rotation),
+        };
+    }
+
+    pub fn transform_point(&self, point: Point3<f32>) -> Point3<f32> {
+        let local = point.to_vec().mul_element_wise(self.scale);
+        return Point3::from_vec(self.position + self.rotation.rotate_vector(local));
+    }
+
+    pub fn to_raw(&self) -> TransformRaw {
+        let normal = self.normal_matrix();
+        return TransformRaw {
+            model: self.matrix().into(),
+            normal: [normal.x, normal.y, normal.z].map(|c| c.extend(0.0).into()),
+        };
+    }
+}
+
+impl Default for Transform {
+    fn default() -> Self {
+        return Self {
+            position: Vector3::zero(),
+            rotation: Quaternion::one(),
+            scale: Vector3::new(1.0, 1.0, 1.0),
+        };
+    }
+}
+
+impl From<Transform> for Matrix4<f32> {
+    fn from(transform: Transform) -> Self {
+        return transform.matrix();
+    }
+}
+
+impl From<Decomposed<Vector3<f32>, Quaternion<f32>>> for Transform {
+    fn from(decomposed: Decomposed<Vector3<f32>, Quaternion<f32>>) -> Self {
+        let scale = decomposed.scale;
+        return Self::new(decomposed.disp, decomposed.rot, Vector3::new(scale, scale, scale));
+    }
+}
+
+/// Curve through space made of cubic segments, evaluated at `t` from 0 at
+/// its start to 1 at its end, each segment taking an equal share of `t`.
+pub trait Spline {
+    fn segment_count(&self) -> usize;
+
+    fn point(&self, t: f32) -> Vector3<f32>;
+
+    /// Derivative of `point` with respect to `t`, along the curve.
+    fn derivative(&self, t: f32) -> Vector3<f32>;
+}
+
+// Segment of a curve of `segments` equal parts at `t`, and the position in it
+fn locate(t: f32, segments: usize) -> (usize, f32) {
+    let x = t.clamp(0.0, 1.0) * segments as f32;
+    let index = (x.floor() as usize).min(segments.saturating_sub(1));
+    return (index, x - index as f32);
+}
+
+/// Spline passing through all of its points, with each point's tangent
+/// parallel to the line between its neighbors.
+#[derive(Debug, Clone, PartialEq)]
+pub struct CatmullRom {
+    pub points: Vec<Vector3<f32>>,
+    /// Continue from the last point back to the first.
+    pub closed: bool,
+}
+
+impl CatmullRom {
+    pub fn new(points: Vec<Vector3<f32>>, closed: bool) -> Self {
+        return Self { points, closed };
+    }
+
+    // Points around segment `index`, open ends mirrored so the curve starts
+    // and ends heading at the next point
+    fn controls(&self, index: usize) -> [Vector3<f32>; 4] {
+        let n = self.points.len();
+        let at = |i: isize| -> Vector3<f32> {
+            if self.closed {
+                return self.points[i.rem_euclid(n as isize) as usize];
+            }
+            if i < 0 {
+                return self.points[0] * 2.0 - self.points[1];
+            }
+            if i as usize >= n {
+                return self.points[n - 1] * 2.0 - self.points[n - 2];
+            }
+            return self.points[i as usize];
+        };
+        let i = index as isize;
+        return [at(i - 1), at(i), at(i + 1), at(i + 2)];
+    }
+}
+
+impl Spline for CatmullRom {
+    fn segment_count(&self) -> usize {
+        let n = self.points.len();
+        if n < 2 {
+            return 0;
+        }
+        return if self.closed { n } else { n - 1 };
+    }
+
+    fn point(&self, t: f32) -> Vector3<f32> {
+        let segments = self.segment_count();
+        if segments == 0 {
+            return self.points.first().copied().unwrap_or_else(Vector3::zero);
+        }
+        let (index, u) = locate(t, segments);
+        let [p0, p1, p2, p3] = self.controls(index);
+        let (u2, u3) = (u * u, u * u * u);
+        return (p1 * 2.0
+            + (p2 - p0) * u
+            + (p0 * 2.0 - p1 * 5.0 + p2 * 4.0 - p3) * u2
+            + (p1 * 3.0 - p0 - p2 * 3.0 + p3) * u3)
+            * 0.5;
+    }
+
+    fn derivative(&self, t: f32) -> Vector3<f32> {
+        let segments = self.segment_count();
+        if segments == 0 {
+            return Vector3::zero();
+        }
+        let (index, u) = locate(t, segments);
+        let [p0, p1, p2, p3] = self.controls(index);
+        let d = (p2 - p0)
+            + (p0 * 2.0 - p1 * 5.0 + p2 * 4.0 - p3) * (2.0 * u)
+            + (p1 * 3.0 - p0 - p2 * 3.0 + p3) * (3.0 * u * u);
+        return d * 0.5 * segments as f32;
+    }
+}
+
+/// Cubic Bezier segments joined end to end: a point the curve passes
+/// through, two control points, the next point it passes through and so
+/// on, `3 * segments + 1` points in all. Repeat the first point at the end
+/// to close it.
+#[derive(Debug, Clone, PartialEq)]
+pub struct BezierSpline {
+    pub points: Vec<Vector3<f32>>,
+}
+
+impl BezierSpline {
+    pub fn new(points: Vec<Vector3<f32>>) -> Self {
+        return Self { points };
+    }
+}
+
+impl Spline for BezierSpline {
+    fn segment_count(&self) -> usize {
+        return self.points.len().saturating_sub(1) / 3;
+    }
+
+    fn point(&self, t: f32) -> Vector3<f32> {
+        let segments = self.segment_count();
+        if segments == 0 {
+            return self.points.first().copied().unwrap_or_else(Vector3::zero);
+        }
+        let (index, u) = locate(t, segments);
+        let [p0, p1, p2, p3] = [0, 1, 2, 3].map(|k| self.points[index * 3 + k]);
+        let v = 1.0 - u;
+        return p0 * (v * v * v) + p1 * (3.0 * v * v * u) + p2 * (3.0 * v * u * u) + p3 * (u * u * u);
+    }
+
+    fn derivative(&self, t: f32) -> Vector3<f32> {
+        let segments = self.segment_count();
+        if segments == 0 {
+            return Vector3::zero();
+        }
+        let (index, u) = locate(t, segments);
+        let [p0, p1, p2, p3] = [0, 1, 2, 3].map(|k| self.points[index * 3 + k]);
+        let v = 1.0 - u;
+        let d = (p1 - p0) * (3.0 * v * v) + (p2 - p1) * (6.0 * v * u) + (p3 - p2) * (3.0 * u * u);
+        return d * segments as f32;
+    }
+}
+
+// GPU layouts, padded to WGSL's vec4 alignment
+
+#[repr(C)]
+#[derive(Debug, Copy, Clone, Default, PartialEq, bytemuck::Pod, bytemuck::Zeroable)]
+pub struct AabbRaw {
+    pub min: [f32; 4],
+    pub max: [f32; 4],
+}
+
+#[repr(C)]
+#[derive(Debug, Copy, Clone, Default, PartialEq, bytemuck::Pod, bytemuck::Zeroable)]
+pub struct FrustumRaw {
+    /// Plane equations as `Plane::equation`.
+    pub planes: [[f32; 4]; MAX_FRUSTUM_PLANES],
+}
+
+#[repr(C)]
+#[derive(Debug, Copy, Clone, Default, PartialEq, bytemuck::Pod, bytemuck::Zeroable)]
+pub struct RayRaw {
+    pub origin: [f32; 4],
+    pub direction: [f32; 4],
+}
+
+/// `mat4x4<f32>` model matrix and a `mat3x3<f32>` normal matrix.
+#[repr(C)]
+#[derive(Debug, Copy, Clone, Default, PartialEq, bytemuck::Pod, bytemuck::Zeroable)]
+pub struct TransformRaw {
+    pub model: [[f32; 4]; 4],
+    pub normal: [[f32; 4]; 3],
+}
@@ -0,0 +1,111 @@
+use cgmath::{prelude::*, Matrix4, Point3, Vector3};
+
+use crate::{math::Aabb, overlay::Overlay, viewport::Viewport};
+
+const LINE_COLOR: [f32; 4] = [1.0, 0.9, 0.2, 1.0];
+const BOUNDS_COLOR: [f32; 4] = [0.3, 1.0, 0.5, 1.0];
+const LABEL_HEIGHT: f32 = 14.0;
+
+/// Editor tool measuring the distance between two clicked points.
+#[derive(Debug, Clone, Default)]
+pub struct MeasureTool {
+    pub enabled: bool,
+    start: Option<Point3<f32>>,
+    end: Option<Point3<f32>>,
+}
+
+impl MeasureTool {
+    /// Take a clicked point: the first one starts a measurement, the second
+    /// ends it and the one after that starts over.
+    pub fn click(&mut self, point: Point3<f32>) {
+        if self.start.is_none() || self.end.is_some() {
+            self.start = Some(point);
+            self.end = None;
+        } else {
+            self.end = Some(point);
+        }
+    }
+
+    pub fn clear(&mut self) {
+        self.start = None;
+        self.end = None;
+    }
+
+    /// Measured points, the end following `hover` until it's clicked.
+    pub fn segment(&self, hover: Option<Point3<f32>>) -> Option<(Point3<f32>, Point3<f32>)> {
+        return Some((self.start?, self.end.or(hover)?));
+    }
+
+    /// Length of the finished measurement.
+    pub fn distance(&self) -> Option<f32> {
+        return Some((self.end? - self.start?).magnitude());
+    }
+
+    /// Line between the points labeled with its length, seen through
+    /// `view_proj` in the scene's area of the window.
+    pub fn draw(
+        &self,
+        overlay: &mut Overlay,
+        viewport: &Viewport,
+        view_proj: &Matrix4<f32>,
+        hover: Option<Point3<f32>>,
+    ) {
+        let (start, end) = match self.segment(hover) {
+            Some(segment) => segment,
+            None => return,
+        };
+        overlay.world_line(viewport, view_proj, start, end, LINE_COLOR, 2.0);
+        for point in [start, end] {
+            if let Some(p) = overlay.project(viewport, view_proj, point) {
+                overlay.fill_circle(p, 3.0, LINE_COLOR);
+            }
+        }
+        let length = (end - start).magnitude();
+        label(overlay, viewport, view_proj, start.midpoint(end), &format!("{:.2}", length), LINE_COLOR);
+    }
+}
+
+/// Edges of `bounds`, with the length along each axis next to one of the
+/// edges running along it.
+pub fn draw_bounds_size(overlay: &mut Overlay, viewport: &Viewport, view_proj: &Matrix4<f32>, bounds: &Aabb) {
+    let corner = |x: bool, y: bool, z: bool| {
+        Point3::new(
+            if x { bounds.max.x } else { bounds.min.x },
+            if y { bounds.max.y } else { bounds.min.y },
+            if z { bounds.max.z } else { bounds.min.z },
+        )
+    };
+    for a in [false, true] {
+        for b in [false, true] {
+            overlay.world_line(viewport, view_proj, corner(false, a, b), corner(true, a, b), BOUNDS_COLOR, 1.5);
+            overlay.world_line(viewport, view_proj, corner(a, false, b), corner(a, true, b), BOUNDS_COLOR, 1.5);
+            overlay.world_line(viewport, view_proj, corner(a, b, false), corner(a, b, true), BOUNDS_COLOR, 1.5);
+        }
+    }
+
+    let extent: Vector3<f32> = bounds.max - bounds.min;
+    let edges = [
+        (corner(false, false, true), corner(true, false, true), extent.x),
+        (corner(false, false, true), corner(false, true, true), extent.y),
+        (corner(true, false, false), corner(true, false, true), extent.z),
+    ];
+    for (from, to, length) in edges {
+        label(overlay, viewport, view_proj, from.midpoint(to), &format!("{:.2}", length), BOUNDS_COLOR);
+    }
+}
+
+// Text centered above a world point
+fn label(
+    overlay: &mut Overlay,
+    viewport: &Viewport,
+    view_proj: &Matrix4<f32>,
+    point: Point3<f32>,
+    text: &str,
+    color: [f32; 4],
+) {
+    if let Some([x, y]) = overlay.project(viewport, view_proj, point) {
+        // Seven-segment characters are about three quarters of their height wide
+        let width = text.len() as f32 * LABEL_HEIGHT * 0.75;
+        overlay.text([x - width * 0.5, y - LABEL_HEIGHT - 6.0], text, LABEL_HEIGHT, color);
+    }
+}
@@ -0,0 +1,295 @@
+use std::{
+    ops::Deref,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+};
+
+use wgpu::util::DeviceExt;
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum MemoryCategory {
+    Mesh,
+    Texture,
+    Uniform,
+    Other,
+}
+
+impl MemoryCategory {
+    const COUNT: usize = 4;
+
+    const fn index(&self) -> usize {
+        return match self {
+            MemoryCategory::Mesh => 0,
+            MemoryCategory::Texture => 1,
+            MemoryCategory::Uniform => 2,
+            MemoryCategory::Other => 3,
+        };
+    }
+}
+
+/// Byte limits per category; `None` means unlimited.
+#[derive(Debug, Copy, Clone, Default)]
+pub struct MemoryBudget {
+    pub meshes: Option<u64>,
+    pub textures: Option<u64>,
+    pub uniforms: Option<u64>,
+    pub total: Option<u64>,
+}
+
+#[derive(Debug, Copy, Clone, Default)]
+pub struct MemoryReport {
+    pub meshes: u64,
+    pub textures: u64,
+    pub uniforms: u64,
+    pub other: u64,
+    pub buffer_count: u64,
+    pub texture_count: u64,
+}
+
+impl MemoryReport {
+    pub fn total(&self) -> u64 {
+        return self.meshes + self.textures + self.uniforms + self.other;
+    }
+}
+
+struct TrackerInner {
+    usage: [AtomicU64; MemoryCategory::COUNT],
+    buffer_count: AtomicU64,
+    texture_count: AtomicU64,
+    budget: Mutex<MemoryBudget>,
+}
+
+/// Central allocator wrapper: every buffer and texture created through it
+/// is accounted for until the returned handle is dropped.
+#[derive(Clone)]
+pub struct MemoryTracker {
+    inner: Arc<TrackerInner>,
+}
+
+impl Default for MemoryTracker {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl MemoryTracker {
+    pub fn new() -> Self {
+        Self {
+            inner: Arc::new(TrackerInner {
+                usage: Default::default(),
+                buffer_count: AtomicU64::new(0),
+                texture_count: AtomicU64::new(0),
+                budget: Mutex::new(MemoryBudget::default()),
+            }),
+        }
+    }
+
+    pub fn create_buffer(
+        &self,
+        device: &wgpu::Device,
+        desc: &wgpu::BufferDescriptor,
+        category: MemoryCategory,
+    ) -> TrackedBuffer {
+        let buffer = device.create_buffer(desc);
+        return self.track_buffer(buffer, desc.size, category);
+    }
+
+    pub fn create_buffer_init(
+        &self,
+        device: &wgpu::Device,
+        desc: &wgpu::util::BufferInitDescriptor,
+        category: MemoryCategory,
+    ) -> TrackedBuffer {
+        let buffer = device.create_buffer_init(desc);
+        return self.track_buffer(buffer, desc.contents.len() as u64, category);
+    }
+
+    pub fn create_texture(
+        &self,
+        device: &wgpu::Device,
+        desc: &wgpu::TextureDescriptor,
+        category: MemoryCategory,
+    ) -> TrackedTexture {
+        let texture = device.create_texture(desc);
+        let size = texture_size(desc);
+        self.inner.texture_count.fetch_add(1, Ordering::Relaxed);
+        self.allocate(category, size);
+        return TrackedTexture {
+            texture,
+            size,
+            extent: desc.size,
+            format: desc.format,
+            mip_level_count: desc.mip_level_count,
+            category,
+            tracker: self.clone(),
+        };
+    }
+
+    pub fn set_budget(&self, budget: MemoryBudget) {
+        *self.inner.budget.lock().unwrap() = budget;
+        self.check_budget();
+    }
+
+    pub fn budget(&self) -> MemoryBudget {
+        return *self.inner.budget.lock().unwrap();
+    }
+
+    pub fn report(&self) -> MemoryReport {
+        let usage = |c: MemoryCategory| self.inner.usage[c.index()].load(Ordering::Relaxed);
+        return MemoryReport {
+            meshes: usage(MemoryCategory::Mesh),
+            textures: usage(MemoryCategory::Texture),
+            uniforms: usage(MemoryCategory::Uniform),
+            other: usage(MemoryCategory::Other),
+            buffer_count: self.inner.buffer_count.load(Ordering::Relaxed),
+            texture_count: self.inner.texture_count.load(Ordering::Relaxed),
+        };
+    }
+
+    fn track_buffer(
+        &self,
+        buffer: wgpu::Buffer,
+        size: u64,
+        category: MemoryCategory,
+    ) -> TrackedBuffer {
+        self.inner.buffer_count.fetch_add(1, Ordering::Relaxed);
+        self.allocate(category, size);
+        return TrackedBuffer {
+            buffer,
+            size,
+            category,
+            tracker: self.clone(),
+        };
+    }
+
+    fn allocate(&self, category: MemoryCategory, size: u64) {
+        self.inner.usage[category.index()].fetch_add(size, Ordering::Relaxed);
+        self.check_budget();
+    }
+
+    fn free(&self, category: MemoryCategory, size: u64) {
+        self.inner.usage[category.index()].fetch_sub(size, Ordering::Relaxed);
+    }
+
+    fn check_budget(&self) {
+        let budget = self.budget();
+        let report = self.report();
+        let checks = [
+            ("meshes", report.meshes, budget.meshes),
+            ("textures", report.textures, budget.textures),
+            ("uniforms", report.uniforms, budget.uniforms),
+            ("total", report.total(), budget.total),
+        ];
+        for (name, used, limit) in checks {
+            if let Some(limit) = limit {
+                if used > limit {
+                    log::warn!(
+                        "GPU memory budget for {} exceeded: {} / {} bytes",
+                        name,
+                        used,
+                        limit
+                    );
+                }
+            }
+        }
+    }
+}
+
+/// Estimated size of a texture including all mip levels.
+fn texture_size(desc: &wgpu::TextureDescriptor) -> u64 {
+    let info = desc.format.describe();
+    let (block_w, block_h) = info.block_dimensions;
+    let layers = desc.size.depth_or_array_layers as u64;
+
+    let mut size = 0;
+    for level in 0..desc.mip_level_count {
+        let width = (desc.size.width >> level).max(1);
+        let height = (desc.size.height >> level).max(1);
+        let blocks_w = width.div_ceil(block_w as u32) as u64;
+        let blocks_h = height.div_ceil(block_h as u32) as u64;
+        size += blocks_w * blocks_h * info.block_size as u64 * layers;
+    }
+    return size * desc.sample_count as u64;
+}
+
+pub struct TrackedBuffer {
+    buffer: wgpu::Buffer,
+    size: u64,
+    category: MemoryCategory,
+    tracker: MemoryTracker,
+}
+
+impl TrackedBuffer {
+    pub fn size(&self) -> u64 {
+        return self.size;
+    }
+
+    pub fn category(&self) -> MemoryCategory {
+        return self.category;
+    }
+}
+
+impl Deref for TrackedBuffer {
+    type Target = wgpu::Buffer;
+
+    fn deref(&self) -> &Self::Target {
+        return &self.buffer;
+    }
+}
+
+impl Drop for TrackedBuffer {
+    fn drop(&mut self) {
+        self.tracker.inner.buffer_count.fetch_sub(1, Ordering::Relaxed);
+        self.tracker.free(self.category, self.size);
+    }
+}
+
+pub struct TrackedTexture {
+    texture: wgpu::Texture,
+    size: u64,
+    extent: wgpu::Extent3d,
+    format: wgpu::TextureFormat,
+    mip_level_count: u32,
+    category: MemoryCategory,
+    tracker: MemoryTracker,
+}
+
+impl TrackedTexture {
+    pub fn size(&self) -> u64 {
+        return self.size;
+    }
+
+    /// Size of the first mip level.
+    pub fn extent(&self) -> wgpu::Extent3d {
+        return self.extent;
+    }
+
+    pub fn format(&self) -> wgpu::TextureFormat {
+        return self.format;
+    }
+
+    pub fn mip_level_count(&self) -> u32 {
+        return self.mip_level_count;
+    }
+
+    pub fn category(&self) -> MemoryCategory {
+        return self.category;
+    }
+}
+
+impl Deref for TrackedTexture {
+    type Target = wgpu::Texture;
+
+    fn deref(&self) -> &Self::Target {
+        return &self.texture;
+    }
+}
+
+impl Drop for TrackedTexture {
+    fn drop(&mut self) {
+        self.tracker.inner.texture_count.fetch_sub(1, Ordering::Relaxed);
+        self.tracker.free(self.category, self.size);
+    }
+}
@@ -1,31 +1,130 @@
-use std::ops::Range;
+use std::{ops::Range, sync::Arc};
 
-use crate::texture::Texture;
+use crate::{
+    animation::{Flipbook, FlipbookState},
+    decode::PendingTexture,
+    lightmap::LightmapUvs,
+    material::{CullMode, MaterialLayout, MaterialParams, ParamValue, ShadingModel},
+    math::Aabb,
+    memory::{MemoryCategory, MemoryTracker, TrackedBuffer},
+    resources::MissingTexture,
+    texture::Texture,
+};
+
+/// Range of a mesh's index buffer drawn with one material.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Submesh {
+    pub indices: Range<u32>,
+    // Index into the model's materials
+    pub material: usize,
+}
 
 pub struct Mesh {
     pub name: String,
-    pub vertex_buffer: wgpu::Buffer,
-    pub index_buffer: wgpu::Buffer,
+    pub vertex_buffer: TrackedBuffer,
+    pub index_buffer: TrackedBuffer,
     pub num_elements: u32,
-    pub material: usize,
+    // Sharing the buffers above, together covering all indices
+    pub submeshes: Vec<Submesh>,
+    pub bounds: Aabb,
+    /// Second texture coordinates for baked lighting, see `Lightmap`.
+    pub lightmap_uvs: Option<LightmapUvs>,
 }
 
 pub struct Material {
     pub name: String,
     pub diffuse_texture: Texture,
     pub normal_texture: Texture,
+    pub params: MaterialParams,
+    pub params_buffer: TrackedBuffer,
     pub bind_group: wgpu::BindGroup,
+    pub flipbook: Option<FlipbookState>,
+    pub cull_mode: CullMode,
+    pub shading: ShadingModel,
+    /// Resource paths the textures were loaded from, `None` for generated ones.
+    pub diffuse_source: Option<String>,
+    pub normal_source: Option<String>,
+    /// Slots showing `Texture::placeholder` because their file failed to
+    /// load, see `resources::reload_missing_textures`.
+    pub missing_textures: Vec<MissingTexture>,
+    /// Slots showing a plain placeholder until their file is decoded, see
+    /// `resources::poll_pending_textures`.
+    pub pending_textures: Vec<PendingTexture>,
 }
 
 impl Material {
     pub fn new(
         device: &wgpu::Device,
+        memory: &MemoryTracker,
         name: &str,
         diffuse_texture: Texture,
         normal_texture: Texture,
+        params: MaterialParams,
         layout: &wgpu::BindGroupLayout,
     ) -> Self {
-        let bind_group = device.create_bind_group(&wgpu::BindGroupDescriptor {
+        let params_buffer = memory.create_buffer_init(
+            device,
+            &wgpu::util::BufferInitDescriptor {
+                label: Some(&format!("{} Params Buffer", name)),
+                contents: params.data(),
+                usage: wgpu::BufferUsages::UNIFORM | wgpu::BufferUsages::COPY_DST,
+            },
+            MemoryCategory::Uniform,
+        );
+        let bind_group = Self::create_bind_group(
+            device,
+            name,
+            &diffuse_texture,
+            &normal_texture,
+            &params_buffer,
+            layout,
+        );
+
+        return Self {
+            name: String::from(name),
+            diffuse_texture,
+            normal_texture,
+            params,
+            params_buffer,
+            bind_group,
+            flipbook: None,
+            cull_mode: CullMode::Back,
+            shading: ShadingModel::Opaque,
+            diffuse_source: None,
+            normal_source: None,
+            missing_textures: Vec::new(),
+            pending_textures: Vec::new(),
+        };
+    }
+
+    /// Drawn by the order independent transparency pass instead of with
+    /// the opaque scene.
+    pub fn is_transparent(&self) -> bool {
+        return self.shading == ShadingModel::Transparent;
+    }
+
+    /// Parameters for the basic shader matching the values it used before they were exposed.
+    pub fn default_params(layout: Arc<MaterialLayout>) -> MaterialParams {
+        let mut params = MaterialParams::new(layout);
+        let _ = params.set("tint", [1.0, 1.0, 1.0, 1.0]);
+        let _ = params.set("roughness", 0.5);
+        let _ = params.set("specular", 1.0);
+        let _ = params.set("alpha_cutoff", 0.0);
+        let _ = params.set("uv_transform", [1.0, 1.0, 0.0, 0.0]);
+        let _ = params.set("outline_width", 2.0);
+        let _ = params.set("outline_color", [0.0, 0.0, 0.0, 1.0]);
+        return params;
+    }
+
+    fn create_bind_group(
+        device: &wgpu::Device,
+        name: &str,
+        diffuse_texture: &Texture,
+        normal_texture: &Texture,
+        params_buffer: &TrackedBuffer,
+        layout: &wgpu::BindGroupLayout,
+    ) -> wgpu::BindGroup {
+        return device.create_bind_group(&wgpu::BindGroupDescriptor {
             label: Some(name),
             layout,
             entries: &[
@@ -45,15 +144,84 @@ impl Material {
                     binding: 3,
                     resource: wgpu::BindingResource::Sampler(&normal_texture.sampler)
                 },
+                wgpu::BindGroupEntry {
+                    binding: 4,
+                    resource: params_buffer.as_entire_binding(),
+                },
             ]
         });
+    }
 
-        return Self {
-            name: String::from(name),
-            diffuse_texture,
-            normal_texture,
-            bind_group
-        };
+    pub fn set_diffuse_texture(
+        &mut self,
+        device: &wgpu::Device,
+        texture: Texture,
+        layout: &wgpu::BindGroupLayout,
+    ) {
+        self.diffuse_texture = texture;
+        self.bind_group = Self::create_bind_group(
+            device,
+            &self.name,
+            &self.diffuse_texture,
+            &self.normal_texture,
+            &self.params_buffer,
+            layout,
+        );
+    }
+
+    pub fn set_normal_texture(
+        &mut self,
+        device: &wgpu::Device,
+        texture: Texture,
+        layout: &wgpu::BindGroupLayout,
+    ) {
+        self.normal_texture = texture;
+        self.bind_group = Self::create_bind_group(
+            device,
+            &self.name,
+            &self.diffuse_texture,
+            &self.normal_texture,
+            &self.params_buffer,
+            layout,
+        );
+    }
+
+    /// Play `atlas` as a flipbook in the diffuse slot, starting at `start_time`
+    /// (in seconds of renderer time).
+    pub fn attach_flipbook(
+        &mut self,
+        device: &wgpu::Device,
+        atlas: Texture,
+        flipbook: Flipbook,
+        start_time: f32,
+        layout: &wgpu::BindGroupLayout,
+    ) {
+        self.set_diffuse_texture(device, atlas, layout);
+        self.flipbook = Some(FlipbookState::new(flipbook, start_time));
+    }
+
+    pub fn detach_flipbook(&mut self) {
+        self.flipbook = None;
+        let _ = self.params.set("uv_transform", [1.0, 1.0, 0.0, 0.0]);
+    }
+
+    pub fn set<V: Into<ParamValue>>(&mut self, name: &str, value: V) -> anyhow::Result<()> {
+        return self.params.set(name, value);
+    }
+
+    pub fn get(&self, name: &str) -> Option<ParamValue> {
+        return self.params.get(name);
+    }
+
+    // Advance animations and upload parameters changed since the last call
+    pub fn update(&mut self, queue: &wgpu::Queue, time: f32) {
+        if let Some(uv_transform) = self.flipbook.as_mut().and_then(|f| f.advance(time)) {
+            let _ = self.params.set("uv_transform", uv_transform);
+        }
+        if self.params.is_dirty() {
+            queue.write_buffer(&self.params_buffer, 0, self.params.data());
+            self.params.clear_dirty();
+        }
     }
 }
 
@@ -73,6 +241,24 @@ pub trait DrawModel<'a> {
         camera_bind_group: &'a wgpu::BindGroup,
         light_bind_group: &'a wgpu::BindGroup,
     );
+    fn draw_submesh_instanced(
+        &mut self,
+        mesh: &'a Mesh,
+        submesh: &Submesh,
+        material: &'a Material,
+        instances: Range<u32>,
+        camera_bind_group: &'a wgpu::BindGroup,
+        light_bind_group: &'a wgpu::BindGroup,
+    );
+    // Every submesh with its material from `materials`
+    fn draw_mesh_materials_instanced(
+        &mut self,
+        mesh: &'a Mesh,
+        materials: &'a [Material],
+        instances: Range<u32>,
+        camera_bind_group: &'a wgpu::BindGroup,
+        light_bind_group: &'a wgpu::BindGroup,
+    );
 
     fn draw_model(
         &mut self,
@@ -94,6 +280,18 @@ pub struct Model {
     pub materials: Vec<Material>,
 }
 
+impl Model {
+    // Bounds of all meshes in model space
+    pub fn bounds(&self) -> Option<Aabb> {
+        return self.meshes.iter().map(|m| m.bounds).reduce(|a, b| a.union(&b));
+    }
+
+    // One draw per submesh
+    pub fn draw_count(&self) -> usize {
+        return self.meshes.iter().map(|m| m.submeshes.len()).sum();
+    }
+}
+
 impl<'a, 'b> DrawModel<'b> for wgpu::RenderPass<'a>
 where
     'b: 'a,
@@ -115,13 +313,49 @@ where
         instances: Range<u32>,
         camera_bind_group: &'b wgpu::BindGroup,
         light_bind_group: &'b wgpu::BindGroup,
+    ) {
+        let whole = Submesh {
+            indices: 0..mesh.num_elements,
+            material: 0,
+        };
+        self.draw_submesh_instanced(mesh, &whole, material, instances, camera_bind_group, light_bind_group);
+    }
+
+    fn draw_submesh_instanced(
+        &mut self,
+        mesh: &'b Mesh,
+        submesh: &Submesh,
+        material: &'b Material,
+        instances: Range<u32>,
+        camera_bind_group: &'b wgpu::BindGroup,
+        light_bind_group: &'b wgpu::BindGroup,
     ) {
         self.set_vertex_buffer(0, mesh.vertex_buffer.slice(..));
         self.set_index_buffer(mesh.index_buffer.slice(..), wgpu::IndexFormat::Uint32);
         self.set_bind_group(0, &material.bind_group, &[]);
         self.set_bind_group(1, camera_bind_group, &[]);
         self.set_bind_group(2, light_bind_group, &[]);
-        self.draw_indexed(0..mesh.num_elements, 0, instances);
+        self.draw_indexed(submesh.indices.clone(), 0, instances);
+    }
+
+    fn draw_mesh_materials_instanced(
+        &mut self,
+        mesh: &'b Mesh,
+        materials: &'b [Material],
+        instances: Range<u32>,
+        camera_bind_group: &'b wgpu::BindGroup,
+        light_bind_group: &'b wgpu::BindGroup,
+    ) {
+        for submesh in &mesh.submeshes {
+            self.draw_submesh_instanced(
+                mesh,
+                submesh,
+                &materials[submesh.material],
+                instances.clone(),
+                camera_bind_group,
+                light_bind_group,
+            );
+        }
     }
 
     fn draw_model(
@@ -141,10 +375,9 @@ where
         light_bind_group: &'a wgpu::BindGroup,
     ) {
         for mesh in &model.meshes {
-            let material = &model.materials[mesh.material];
-            self.draw_mesh_instanced(
+            self.draw_mesh_materials_instanced(
                 mesh,
-                material,
+                &model.materials,
                 instances.clone(),
                 camera_bind_group,
                 light_bind_group,
@@ -0,0 +1,282 @@
+use cgmath::{EuclideanSpace, Point3, Quaternion, Rad, Rotation, Rotation3};
+use itertools::Itertools;
+
+use crate::{
+    frame::FrameBuffers,
+    memory::{MemoryCategory, MemoryTracker, TrackedBuffer},
+    resources::{Instance, InstanceFormat},
+    shader::ShaderPreprocessor,
+};
+
+#[repr(C)]
+#[derive(Debug, Copy, Clone, bytemuck::Pod, bytemuck::Zeroable)]
+struct MotionParams {
+    count: u32,
+    row: u32,
+    orbit_angle: f32,
+    spin_angle: f32,
+    center: [f32; 4],
+}
+
+/// Motion a crowd adds to its instances every frame, computed on the GPU so
+/// that animation-heavy scenes don't upload every instance every frame. The
+/// instances keep the transforms they were given and are moved when drawn,
+/// CPU-side code such as picking sees them unmoved.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct InstanceMotion {
+    /// Instances orbit the vertical axis through this point.
+    pub center: Point3<f32>,
+    /// Radians per second, counter-clockwise seen from above.
+    pub orbit_speed: f32,
+    /// Radians per second each instance turns around its own up axis.
+    pub spin_speed: f32,
+}
+
+impl InstanceMotion {
+    /// `instance` orbited and turned by the given angles, as the GPU does it.
+    pub fn apply(&self, instance: &Instance, orbit_angle: f32, spin_angle: f32) -> Instance {
+        let orbit = Quaternion::from_angle_y(Rad(orbit_angle));
+        let center = self.center.to_vec();
+        return Instance {
+            position: center + orbit.rotate_vector(instance.position - center),
+            rotation: orbit * instance.rotation * Quaternion::from_angle_y(Rad(spin_angle)),
+            ..*instance
+        };
+    }
+}
+
+/// Compute kernel moving crowd instances by their `InstanceMotion`, writing
+/// straight into the instance vertex buffers.
+pub struct MotionKernel {
+    format: InstanceFormat,
+    bind_group_layout: wgpu::BindGroupLayout,
+    pipeline: wgpu::ComputePipeline,
+}
+
+impl MotionKernel {
+    const WORKGROUP_SIZE: u32 = 64;
+    const STORAGE_BUFFERS: u32 = 2;
+
+    /// Kernel for instances in `format`, `None` if the device can't run
+    /// compute passes, e.g. on WebGL.
+    pub fn new(device: &wgpu::Device, format: InstanceFormat) -> Option<Self> {
+        if !Self::is_supported(device) {
+            return None;
+        }
+
+        let storage = |binding: u32, read_only: bool| wgpu::BindGroupLayoutEntry {
+            binding,
+            visibility: wgpu::ShaderStages::COMPUTE,
+            ty: wgpu::BindingType::Buffer {
+                ty: wgpu::BufferBindingType::Storage { read_only },
+                has_dynamic_offset: false,
+                min_binding_size: None,
+            },
+            count: None,
+        };
+        let bind_group_layout = device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
+            entries: &[
+                wgpu::BindGroupLayoutEntry {
+                    binding: 0,
+                    visibility: wgpu::ShaderStages::COMPUTE,
+                    ty: wgpu::BindingType::Buffer {
+                        ty: wgpu::BufferBindingType::Uniform,
+                        has_dynamic_offset: false,
+                        min_binding_size: None,
+                    },
+                    count: None,
+                },
+                storage(1, true),
+                storage(2, false),
+            ],
+            label: Some("motion_bind_group_layout"),
+        });
+
+        let mut shaders = ShaderPreprocessor::new();
+        if let Some(define) = format.shader_define() {
+            shaders.enable(define);
+        }
+        let shader = shaders
+            .descriptor("Motion Shader", "motion.wgsl")
+            .expect("Failed to preprocess motion.wgsl");
+        let shader = device.create_shader_module(shader);
+        let layout = device.create_pipeline_layout(&wgpu::PipelineLayoutDescriptor {
+            label: Some("Motion Pipeline Layout"),
+            bind_group_layouts: &[&bind_group_layout],
+            push_constant_ranges: &[],
+        });
+        let pipeline = device.create_compute_pipeline(&wgpu::ComputePipelineDescriptor {
+            label: Some("Motion Pipeline"),
+            layout: Some(&layout),
+            module: &shader,
+            entry_point: "motion_main",
+        });
+
+        return Some(Self {
+            format,
+            bind_group_layout,
+            pipeline,
+        });
+    }
+
+    pub fn is_supported(device: &wgpu::Device) -> bool {
+        let limits = device.limits();
+        return limits.max_storage_buffers_per_shader_stage >= Self::STORAGE_BUFFERS
+            && limits.max_compute_workgroups_per_dimension > 0;
+    }
+
+    /// Usage of instance buffers the kernel may write into.
+    pub fn instance_usage(device: &wgpu::Device) -> wgpu::BufferUsages {
+        if Self::is_supported(device) {
+            return wgpu::BufferUsages::VERTEX | wgpu::BufferUsages::STORAGE;
+        }
+        return wgpu::BufferUsages::VERTEX;
+    }
+
+    pub fn format(&self) -> InstanceFormat {
+        return self.format;
+    }
+
+    fn encode(
+        &self,
+        device: &wgpu::Device,
+        encoder: &mut wgpu::CommandEncoder,
+        params: &wgpu::Buffer,
+        base: &wgpu::Buffer,
+        instances: &wgpu::Buffer,
+        groups: (u32, u32),
+    ) {
+        let bind_group = device.create_bind_group(&wgpu::BindGroupDescriptor {
+            layout: &self.bind_group_layout,
+            entries: &[
+                wgpu::BindGroupEntry {
+                    binding: 0,
+                    resource: params.as_entire_binding(),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 1,
+                    resource: base.as_entire_binding(),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 2,
+                    resource: instances.as_entire_binding(),
+                },
+            ],
+            label: Some("motion_bind_group"),
+        });
+        let mut pass = encoder.begin_compute_pass(&wgpu::ComputePassDescriptor {
+            label: Some("Motion Pass"),
+        });
+        pass.set_pipeline(&self.pipeline);
+        pass.set_bind_group(0, &bind_group, &[]);
+        pass.dispatch_workgroups(groups.0, groups.1, 1);
+    }
+
+    // Workgroups in x and y covering `threads`, wrapping rows at the device limit
+    fn dispatch_size(device: &wgpu::Device, threads: u32) -> (u32, u32) {
+        let groups = threads.div_ceil(Self::WORKGROUP_SIZE);
+        let x = groups.min(device.limits().max_compute_workgroups_per_dimension);
+        return (x, groups.div_ceil(x));
+    }
+}
+
+// Progress of a crowd's `InstanceMotion` and the GPU copy of its unmoved instances
+#[derive(Default)]
+pub(crate) struct MotionState {
+    orbit_angle: f32,
+    spin_angle: f32,
+    // Instances in `base_buffer`, uploaded again when the visible ones change
+    base: Vec<Instance>,
+    base_buffer: Option<TrackedBuffer>,
+    params_buffer: Option<TrackedBuffer>,
+}
+
+impl MotionState {
+    // Upload `visible` into the current instance buffer, moved by `motion`.
+    // With a kernel for `format` the instances are only uploaded when they
+    // change and moved by a dispatch recorded into the encoder, otherwise
+    // they're moved on the CPU
+    #[allow(clippy::too_many_arguments)]
+    pub(crate) fn upload(
+        &mut self,
+        device: &wgpu::Device,
+        memory: &MemoryTracker,
+        queue: &wgpu::Queue,
+        kernel: Option<(&MotionKernel, &mut wgpu::CommandEncoder)>,
+        motion: Option<InstanceMotion>,
+        instance_buffers: &mut FrameBuffers,
+        format: InstanceFormat,
+        visible: &[Instance],
+        dt: f32,
+        version: u64,
+    ) {
+        let motion = match motion {
+            Some(motion) => motion,
+            None => {
+                instance_buffers.write(device, memory, queue, &format.encode(visible), version);
+                return;
+            }
+        };
+        let tau = std::f32::consts::TAU;
+        self.orbit_angle = (self.orbit_angle + motion.orbit_speed * dt).rem_euclid(tau);
+        self.spin_angle = (self.spin_angle + motion.spin_speed * dt).rem_euclid(tau);
+
+        let usable = instance_buffers.usage().contains(wgpu::BufferUsages::STORAGE);
+        let (kernel, encoder) = match kernel.filter(|(k, _)| k.format == format && usable) {
+            Some(kernel) => kernel,
+            None => {
+                let moved = visible
+                    .iter()
+                    .map(|i| motion.apply(i, self.orbit_angle, self.spin_angle))
+                    .collect_vec();
+                instance_buffers.write(device, memory, queue, &format.encode(&moved), version);
+                return;
+            }
+        };
+
+        if self.base != visible || self.base_buffer.is_none() {
+            let data = format.encode(visible);
+            match &self.base_buffer {
+                Some(buffer) if buffer.size() >= data.len() as u64 => queue.write_buffer(buffer, 0, &data),
+                _ => {
+                    self.base_buffer = Some(memory.create_buffer_init(
+                        device,
+                        &wgpu::util::BufferInitDescriptor {
+                            label: Some("Motion Base Buffer"),
+                            contents: &data,
+                            usage: wgpu::BufferUsages::STORAGE | wgpu::BufferUsages::COPY_DST,
+                        },
+                        MemoryCategory::Mesh,
+                    ));
+                }
+            }
+            self.base = visible.to_vec();
+        }
+        instance_buffers.reserve(device, memory, (visible.len() * format.stride()) as u64);
+
+        let groups = MotionKernel::dispatch_size(device, visible.len() as u32);
+        let params = MotionParams {
+            count: visible.len() as u32,
+            row: groups.0 * MotionKernel::WORKGROUP_SIZE,
+            orbit_angle: self.orbit_angle,
+            spin_angle: self.spin_angle,
+            center: motion.center.to_homogeneous().into(),
+        };
+        let params_buffer = self.params_buffer.get_or_insert_with(|| {
+            memory.create_buffer_init(
+                device,
+                &wgpu::util::BufferInitDescriptor {
+                    label: Some("Motion Params Buffer"),
+                    contents: bytemuck::cast_slice(&[params]),
+                    usage: wgpu::BufferUsages::UNIFORM | wgpu::BufferUsages::COPY_DST,
+                },
+                MemoryCategory::Uniform,
+            )
+        });
+        queue.write_buffer(params_buffer, 0, bytemuck::cast_slice(&[params]));
+
+        if let Some(base_buffer) = &self.base_buffer {
+            kernel.encode(device, encoder, params_buffer, base_buffer, instance_buffers.current(), groups);
+        }
+    }
+}
\ No newline at end of file
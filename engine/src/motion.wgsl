@@ -0,0 +1,98 @@
+// Moves crowd instances on the GPU: one thread per instance reads its base
+// transform, orbits it around the center's vertical axis, turns it around its
+// own up axis and writes it into the instance vertex buffer. Mirrors
+// `InstanceMotion::apply`. Define COMPACT_INSTANCES for `InstanceFormat::Compact`.
+
+struct Params {
+    count: u32,
+    // Threads per row of workgroups, dispatches too wide for x wrap into y
+    row: u32,
+    orbit_angle: f32,
+    spin_angle: f32,
+    center: vec4<f32>,
+};
+@group(0) @binding(0)
+var<uniform> params: Params;
+// Instances as uploaded from the CPU, in the instance format as flat floats
+@group(0) @binding(1)
+var<storage, read> base: array<f32>;
+@group(0) @binding(2)
+var<storage, read_write> instances: array<f32>;
+
+#ifdef COMPACT_INSTANCES
+let STRIDE: u32 = 11u;
+#else
+let STRIDE: u32 = 28u;
+#endif
+
+fn read_vec4(offset: u32) -> vec4<f32> {
+    return vec4<f32>(base[offset], base[offset + 1u], base[offset + 2u], base[offset + 3u]);
+}
+
+fn write_vec4(offset: u32, value: vec4<f32>) {
+    instances[offset] = value.x;
+    instances[offset + 1u] = value.y;
+    instances[offset + 2u] = value.z;
+    instances[offset + 3u] = value.w;
+}
+
+// Rotation of a direction around the y axis by the angle with cosine `c` and sine `s`
+fn rotate_y(v: vec3<f32>, c: f32, s: f32) -> vec3<f32> {
+    return vec3<f32>(c * v.x + s * v.z, v.y, c * v.z - s * v.x);
+}
+
+#ifdef COMPACT_INSTANCES
+// Hamilton product, xyz vector part and w scalar part
+fn quaternion_mul(a: vec4<f32>, b: vec4<f32>) -> vec4<f32> {
+    return vec4<f32>(
+        a.w * b.xyz + b.w * a.xyz + cross(a.xyz, b.xyz),
+        a.w * b.w - dot(a.xyz, b.xyz),
+    );
+}
+#endif
+
+@compute @workgroup_size(64)
+fn motion_main(@builtin(global_invocation_id) id: vec3<u32>) {
+    let i = id.x + id.y * params.row;
+    if (i >= params.count) {
+        return;
+    }
+    let offset = i * STRIDE;
+    let center = params.center.xyz;
+    let orbit_cos = cos(params.orbit_angle);
+    let orbit_sin = sin(params.orbit_angle);
+
+#ifdef COMPACT_INSTANCES
+    let orbit = vec4<f32>(0.0, sin(params.orbit_angle * 0.5), 0.0, cos(params.orbit_angle * 0.5));
+    let spin = vec4<f32>(0.0, sin(params.spin_angle * 0.5), 0.0, cos(params.spin_angle * 0.5));
+    let rotation = quaternion_mul(quaternion_mul(orbit, read_vec4(offset)), spin);
+    let position_fade = read_vec4(offset + 4u);
+    let position = center + rotate_y(position_fade.xyz - center, orbit_cos, orbit_sin);
+    write_vec4(offset, normalize(rotation));
+    write_vec4(offset + 4u, vec4<f32>(position, position_fade.w));
+    instances[offset + 8u] = base[offset + 8u];
+    instances[offset + 9u] = base[offset + 9u];
+    instances[offset + 10u] = base[offset + 10u];
+#else
+    let spin_cos = cos(params.spin_angle);
+    let spin_sin = sin(params.spin_angle);
+    // Columns 0 to 3 of the model matrix, then 0 to 2 of the normal matrix.
+    // Turning first mixes columns 0 and 2, orbiting then rotates every column
+    // and moves the translation around the center
+    for (var m = 0u; m < 2u; m++) {
+        let first = offset + m * 16u;
+        let x = read_vec4(first);
+        let y = read_vec4(first + 4u);
+        let z = read_vec4(first + 8u);
+        let turned_x = x.xyz * spin_cos - z.xyz * spin_sin;
+        let turned_z = x.xyz * spin_sin + z.xyz * spin_cos;
+        // The normal matrix's last w holds the LOD fade, kept as is
+        write_vec4(first, vec4<f32>(rotate_y(turned_x, orbit_cos, orbit_sin), x.w));
+        write_vec4(first + 4u, vec4<f32>(rotate_y(y.xyz, orbit_cos, orbit_sin), y.w));
+        write_vec4(first + 8u, vec4<f32>(rotate_y(turned_z, orbit_cos, orbit_sin), z.w));
+    }
+    let translation = read_vec4(offset + 12u);
+    let moved = center + rotate_y(translation.xyz - center, orbit_cos, orbit_sin);
+    write_vec4(offset + 12u, vec4<f32>(moved, translation.w));
+#endif
+}
\ No newline at end of file
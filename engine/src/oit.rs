@@ -0,0 +1,248 @@
+use std::sync::Arc;
+
+use crate::{
+    memory::MemoryTracker,
+    pipelines::{PipelineCache, PipelineDescriptor},
+    post::PostProcess,
+    shader::ShaderPreprocessor,
+    texture::Texture,
+};
+
+/// Weighted blended order independent transparency (McGuire and Bavoil
+/// 2013), an alternative to sorting: transparent surfaces add their weighted
+/// colors into an accumulation target and their coverage into a revealage
+/// target in any order, testing but not writing the opaque scene's depth.
+/// `composite` then blends the weighted average over the scene color.
+/// Pipelines drawing into the targets set
+/// `PipelineDescriptor::order_independent`.
+pub struct WeightedBlendedOit {
+    accumulation: Texture,
+    revealage: Texture,
+    // Multisampled targets, resolved into the two above
+    msaa: Option<(Texture, Texture)>,
+    sample_count: u32,
+    bind_group_layout: wgpu::BindGroupLayout,
+    bind_group: wgpu::BindGroup,
+    pipeline: Arc<wgpu::RenderPipeline>,
+}
+
+impl WeightedBlendedOit {
+    pub const ACCUMULATION_FORMAT: wgpu::TextureFormat = wgpu::TextureFormat::Rgba16Float;
+    pub const REVEALAGE_FORMAT: wgpu::TextureFormat = wgpu::TextureFormat::R16Float;
+
+    /// Accumulation added up, revealage multiplied by one minus each
+    /// surface's coverage.
+    pub fn color_targets() -> [wgpu::ColorTargetState; 2] {
+        let add = wgpu::BlendComponent {
+            src_factor: wgpu::BlendFactor::One,
+            dst_factor: wgpu::BlendFactor::One,
+            operation: wgpu::BlendOperation::Add,
+        };
+        let reveal = wgpu::BlendComponent {
+            src_factor: wgpu::BlendFactor::Zero,
+            dst_factor: wgpu::BlendFactor::OneMinusSrc,
+            operation: wgpu::BlendOperation::Add,
+        };
+        return [
+            wgpu::ColorTargetState {
+                format: Self::ACCUMULATION_FORMAT,
+                blend: Some(wgpu::BlendState { color: add, alpha: add }),
+                write_mask: wgpu::ColorWrites::ALL,
+            },
+            wgpu::ColorTargetState {
+                format: Self::REVEALAGE_FORMAT,
+                blend: Some(wgpu::BlendState {
+                    color: reveal,
+                    alpha: reveal,
+                }),
+                write_mask: wgpu::ColorWrites::ALL,
+            },
+        ];
+    }
+
+    pub fn new(
+        device: &wgpu::Device,
+        memory: &MemoryTracker,
+        pipelines: &mut PipelineCache,
+        shaders: &ShaderPreprocessor,
+        width: u32,
+        height: u32,
+        sample_count: u32,
+    ) -> Self {
+        let (accumulation, revealage, msaa) = Self::create_targets(device, memory, width, height, sample_count);
+        let texture_entry = |binding| wgpu::BindGroupLayoutEntry {
+            binding,
+            visibility: wgpu::ShaderStages::FRAGMENT,
+            ty: wgpu::BindingType::Texture {
+                sample_type: wgpu::TextureSampleType::Float { filterable: false },
+                view_dimension: wgpu::TextureViewDimension::D2,
+                multisampled: false,
+            },
+            count: None,
+        };
+        let bind_group_layout = device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
+            entries: &[texture_entry(0), texture_entry(1)],
+            label: Some("oit_bind_group_layout"),
+        });
+        let bind_group = Self::create_bind_group(device, &bind_group_layout, &accumulation, &revealage);
+
+        let shader = shaders
+            .process("oit_composite.wgsl")
+            .expect("Failed to preprocess oit_composite.wgsl");
+        let pipeline = pipelines.pipeline(&PipelineDescriptor {
+            label: "OIT Composite Pipeline",
+            layout: "OIT Composite Pipeline Layout",
+            bind_group_layouts: &[&bind_group_layout],
+            shader: &shader,
+            vertex_layouts: &[],
+            color_format: Some(PostProcess::SCENE_FORMAT),
+            depth_format: None,
+            // Over the scene color by the transparent surfaces' coverage,
+            // its alpha is kept
+            blend: wgpu::BlendState {
+                color: wgpu::BlendComponent {
+                    src_factor: wgpu::BlendFactor::SrcAlpha,
+                    dst_factor: wgpu::BlendFactor::OneMinusSrcAlpha,
+                    operation: wgpu::BlendOperation::Add,
+                },
+                alpha: wgpu::BlendComponent {
+                    src_factor: wgpu::BlendFactor::Zero,
+                    dst_factor: wgpu::BlendFactor::One,
+                    operation: wgpu::BlendOperation::Add,
+                },
+            },
+            cull_mode: Some(wgpu::Face::Back),
+            multisample: wgpu::MultisampleState::default(),
+            order_independent: false,
+        });
+
+        return Self {
+            accumulation,
+            revealage,
+            msaa,
+            sample_count,
+            bind_group_layout,
+            bind_group,
+            pipeline,
+        };
+    }
+
+    fn create_targets(
+        device: &wgpu::Device,
+        memory: &MemoryTracker,
+        width: u32,
+        height: u32,
+        sample_count: u32,
+    ) -> (Texture, Texture, Option<(Texture, Texture)>) {
+        let target = |format, samples, label| {
+            Texture::create_render_target(device, memory, width, height, format, samples, label)
+        };
+        let accumulation = target(Self::ACCUMULATION_FORMAT, 1, "oit_accumulation_texture");
+        let revealage = target(Self::REVEALAGE_FORMAT, 1, "oit_revealage_texture");
+        let msaa = (sample_count > 1).then(|| {
+            (
+                target(Self::ACCUMULATION_FORMAT, sample_count, "oit_accumulation_msaa_texture"),
+                target(Self::REVEALAGE_FORMAT, sample_count, "oit_revealage_msaa_texture"),
+            )
+        });
+        return (accumulation, revealage, msaa);
+    }
+
+    fn create_bind_group(
+        device: &wgpu::Device,
+        layout: &wgpu::BindGroupLayout,
+        accumulation: &Texture,
+        revealage: &Texture,
+    ) -> wgpu::BindGroup {
+        return device.create_bind_group(&wgpu::BindGroupDescriptor {
+            layout,
+            entries: &[
+                wgpu::BindGroupEntry {
+                    binding: 0,
+                    resource: wgpu::BindingResource::TextureView(&accumulation.view),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 1,
+                    resource: wgpu::BindingResource::TextureView(&revealage.view),
+                },
+            ],
+            label: Some("oit_bind_group"),
+        });
+    }
+
+    /// Must be called whenever the scene's render targets are resized.
+    pub fn resize(&mut self, device: &wgpu::Device, memory: &MemoryTracker, width: u32, height: u32) {
+        (self.accumulation, self.revealage, self.msaa) =
+            Self::create_targets(device, memory, width, height, self.sample_count);
+        self.bind_group = Self::create_bind_group(device, &self.bind_group_layout, &self.accumulation, &self.revealage);
+    }
+
+    /// Clear the targets and start drawing transparent surfaces, tested
+    /// against `depth_view`, the opaque scene's depth.
+    pub fn begin_pass<'a>(
+        &'a self,
+        encoder: &'a mut wgpu::CommandEncoder,
+        depth_view: &'a wgpu::TextureView,
+        has_stencil: bool,
+    ) -> wgpu::RenderPass<'a> {
+        let (accumulation, revealage) = match &self.msaa {
+            Some((accumulation, revealage)) => (
+                (&accumulation.view, Some(&self.accumulation.view)),
+                (&revealage.view, Some(&self.revealage.view)),
+            ),
+            None => ((&self.accumulation.view, None), (&self.revealage.view, None)),
+        };
+        return encoder.begin_render_pass(&wgpu::RenderPassDescriptor {
+            label: Some("OIT Pass"),
+            color_attachments: &[
+                Some(wgpu::RenderPassColorAttachment {
+                    view: accumulation.0,
+                    resolve_target: accumulation.1,
+                    ops: wgpu::Operations {
+                        load: wgpu::LoadOp::Clear(wgpu::Color::TRANSPARENT),
+                        store: true,
+                    },
+                }),
+                Some(wgpu::RenderPassColorAttachment {
+                    view: revealage.0,
+                    resolve_target: revealage.1,
+                    ops: wgpu::Operations {
+                        load: wgpu::LoadOp::Clear(wgpu::Color::WHITE),
+                        store: true,
+                    },
+                }),
+            ],
+            depth_stencil_attachment: Some(wgpu::RenderPassDepthStencilAttachment {
+                view: depth_view,
+                depth_ops: Some(wgpu::Operations {
+                    load: wgpu::LoadOp::Load,
+                    store: true,
+                }),
+                stencil_ops: has_stencil.then_some(wgpu::Operations {
+                    load: wgpu::LoadOp::Load,
+                    store: true,
+                }),
+            }),
+        });
+    }
+
+    /// Blend what was drawn since `begin_pass` over `target`, the resolved
+    /// scene color.
+    pub fn composite(&self, encoder: &mut wgpu::CommandEncoder, target: &wgpu::TextureView) {
+        let mut render_pass = encoder.begin_render_pass(&wgpu::RenderPassDescriptor {
+            label: Some("OIT Composite Pass"),
+            color_attachments: &[Some(wgpu::RenderPassColorAttachment {
+                view: target,
+                resolve_target: None,
+                ops: wgpu::Operations {
+                    load: wgpu::LoadOp::Load,
+                    store: true,
+                },
+            })],
+            depth_stencil_attachment: None,
+        });
+        render_pass.set_pipeline(&self.pipeline);
+        render_pass.set_bind_group(0, &self.bind_group, &[]);
+        render_pass.draw(0..3, 0..1);
+    }
+}
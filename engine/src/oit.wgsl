@@ -0,0 +1,19 @@
+// Outputs of surfaces drawn with weighted blended order independent
+// transparency, see oit.rs
+struct OitOutput {
+    // Weighted premultiplied color, added up
+    @location(0) accumulation: vec4<f32>,
+    // Coverage, the target is multiplied by one minus it
+    @location(1) revealage: f32,
+};
+
+// `color` is not premultiplied, `distance` is from the eye in world units.
+// Nearer surfaces weigh more, McGuire and Bavoil 2013, equation 9
+fn oit_output(color: vec3<f32>, alpha: f32, distance: f32) -> OitOutput {
+    let z = abs(distance);
+    let weight = alpha * clamp(10.0 / (0.00001 + pow(z / 5.0, 2.0) + pow(z / 200.0, 6.0)), 0.01, 3000.0);
+    var out: OitOutput;
+    out.accumulation = vec4<f32>(color * alpha, alpha) * weight;
+    out.revealage = alpha;
+    return out;
+}
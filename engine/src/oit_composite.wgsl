@@ -0,0 +1,33 @@
+// Blends the weighted average of the transparent surfaces over the resolved
+// scene color. See oit.rs
+@group(0) @binding(0)
+var t_accumulation: texture_2d<f32>;
+@group(0) @binding(1)
+var t_revealage: texture_2d<f32>;
+
+struct VertexOutput {
+    @builtin(position) clip_position: vec4<f32>,
+};
+
+@vertex
+fn vs_main(@builtin(vertex_index) index: u32) -> VertexOutput {
+    // Fullscreen triangle
+    let uv = vec2<f32>(f32((index << 1u) & 2u), f32(index & 2u));
+    var out: VertexOutput;
+    out.clip_position = vec4<f32>(uv * 2.0 - 1.0, 0.0, 1.0);
+    return out;
+}
+
+@fragment
+fn fs_main(in: VertexOutput) -> @location(0) vec4<f32> {
+    let position = vec2<i32>(in.clip_position.xy);
+    let revealage = textureLoad(t_revealage, position, 0).r;
+    // No transparent surface covers the pixel
+    if (revealage >= 1.0) {
+        discard;
+    }
+    // Many bright layers overflow the half floats
+    let accumulation = min(textureLoad(t_accumulation, position, 0), vec4<f32>(65504.0));
+    let average = accumulation.rgb / max(accumulation.a, 0.00001);
+    return vec4<f32>(average, 1.0 - revealage);
+}
@@ -0,0 +1,89 @@
+use cgmath::{Matrix4, Vector3};
+
+use crate::math::Aabb;
+
+// Cells of the coarse screen grid the estimate rasterizes bounds into
+const GRID_WIDTH: usize = 64;
+const GRID_HEIGHT: usize = 36;
+
+/// Fragments per pixel of a set of draws, estimated on the CPU from their
+/// screen rectangles, e.g. to see what `Settings::sort_front_to_back` saves.
+#[derive(Debug, Copy, Clone, Default, PartialEq)]
+pub struct OverdrawEstimate {
+    /// Fragments rasterized per pixel, whatever the draw order.
+    pub depth_complexity: f32,
+    /// Fragments per pixel left after early depth testing in the order the
+    /// draws were given, at best `depth_complexity` divided by it.
+    pub shaded: f32,
+}
+
+impl OverdrawEstimate {
+    /// Estimate the overdraw of world `bounds` drawn in order through
+    /// `view_proj`, treating each box as solid over its screen rectangle.
+    /// Its nearest view depth has to beat the farthest of a box drawn before
+    /// over the same cell to pass the depth test.
+    pub fn from_bounds(view_proj: &Matrix4<f32>, bounds: impl IntoIterator<Item = Aabb>) -> Self {
+        // Farthest depth of the nearest box covering each cell so far
+        let mut depths = vec![f32::INFINITY; GRID_WIDTH * GRID_HEIGHT];
+        let (mut rasterized, mut shaded) = (0, 0);
+        for rect in bounds.into_iter().filter_map(|aabb| screen_rect(view_proj, &aabb)) {
+            for y in rect.min[1]..rect.max[1] {
+                for x in rect.min[0]..rect.max[0] {
+                    let depth = &mut depths[y * GRID_WIDTH + x];
+                    rasterized += 1;
+                    if rect.near < *depth {
+                        shaded += 1;
+                        *depth = depth.min(rect.far);
+                    }
+                }
+            }
+        }
+        let cells = (GRID_WIDTH * GRID_HEIGHT) as f32;
+        return Self {
+            depth_complexity: rasterized as f32 / cells,
+            shaded: shaded as f32 / cells,
+        };
+    }
+}
+
+// Grid cells covered by a box and its view depth range
+struct ScreenRect {
+    min: [usize; 2],
+    max: [usize; 2],
+    near: f32,
+    far: f32,
+}
+
+fn screen_rect(view_proj: &Matrix4<f32>, aabb: &Aabb) -> Option<ScreenRect> {
+    let (mut min, mut max) = ([1.0f32, 1.0], [-1.0f32, -1.0]);
+    let (mut near, mut far) = (f32::INFINITY, 0.0f32);
+    let mut crosses_near = false;
+    for i in 0..8 {
+        let pick = |bit: usize, axis: usize| if i & bit != 0 { aabb.max[axis] } else { aabb.min[axis] };
+        let clip = view_proj * Vector3::new(pick(1, 0), pick(2, 1), pick(4, 2)).extend(1.0);
+        if clip.w <= f32::EPSILON {
+            crosses_near = true;
+            continue;
+        }
+        for axis in 0..2 {
+            min[axis] = min[axis].min(clip[axis] / clip.w);
+            max[axis] = max[axis].max(clip[axis] / clip.w);
+        }
+        near = near.min(clip.w);
+        far = far.max(clip.w);
+    }
+    // Boxes around the camera may cover anything, right from the near plane
+    if crosses_near {
+        (min, max, near) = ([-1.0, -1.0], [1.0, 1.0], 0.0);
+    }
+    if far <= 0.0 || min[0] >= 1.0 || min[1] >= 1.0 || max[0] <= -1.0 || max[1] <= -1.0 {
+        return None;
+    }
+    let cell = |ndc: f32, cells: usize| (((ndc + 1.0) * 0.5 * cells as f32).round().max(0.0) as usize).min(cells);
+    return Some(ScreenRect {
+        min: [cell(min[0], GRID_WIDTH), cell(min[1], GRID_HEIGHT)],
+        max: [cell(max[0], GRID_WIDTH), cell(max[1], GRID_HEIGHT)],
+        near,
+        far,
+    });
+}
@@ -0,0 +1,645 @@
+use std::sync::Arc;
+
+use cgmath::{Matrix4, Point3, SquareMatrix, Vector2, Vector4};
+
+use crate::{
+    frame::FrameBuffers,
+    memory::{MemoryCategory, MemoryTracker, TrackedBuffer},
+    pipelines::{PipelineCache, PipelineDescriptor},
+    shader::ShaderPreprocessor,
+    viewport::Viewport,
+};
+
+#[repr(C)]
+#[derive(Debug, Copy, Clone, bytemuck::Pod, bytemuck::Zeroable)]
+struct OverlayVertex {
+    position: [f32; 2],
+    color: [f32; 4],
+}
+
+impl OverlayVertex {
+    fn desc<'a>() -> wgpu::VertexBufferLayout<'a> {
+        use std::mem;
+        wgpu::VertexBufferLayout {
+            array_stride: mem::size_of::<OverlayVertex>() as wgpu::BufferAddress,
+            step_mode: wgpu::VertexStepMode::Vertex,
+            attributes: &[
+                wgpu::VertexAttribute {
+                    offset: 0,
+                    shader_location: 0,
+                    format: wgpu::VertexFormat::Float32x2,
+                },
+                wgpu::VertexAttribute {
+                    offset: mem::size_of::<[f32; 2]>() as wgpu::BufferAddress,
+                    shader_location: 1,
+                    format: wgpu::VertexFormat::Float32x4,
+                },
+            ],
+        }
+    }
+}
+
+/// Lit segments per character, bit 0 is the top segment followed by the
+/// others clockwise and the middle one last.
+const SEGMENT_GLYPHS: &[(char, u8)] = &[
+    ('0', 0b0111111),
+    ('1', 0b0000110),
+    ('2', 0b1011011),
+    ('3', 0b1001111),
+    ('4', 0b1100110),
+    ('5', 0b1101101),
+    ('6', 0b1111101),
+    ('7', 0b0000111),
+    ('8', 0b1111111),
+    ('9', 0b1101111),
+    ('-', 0b1000000),
+    ('A', 0b1110111),
+    ('a', 0b1011111),
+    ('b', 0b1111100),
+    ('C', 0b0111001),
+    ('c', 0b1011000),
+    ('d', 0b1011110),
+    ('E', 0b1111001),
+    ('F', 0b1110001),
+    ('g', 0b1101111),
+    ('H', 0b1110110),
+    ('I', 0b0110000),
+    ('i', 0b0010000),
+    ('L', 0b0111000),
+    ('n', 0b1010100),
+    ('o', 0b1011100),
+    ('P', 0b1110011),
+    ('r', 0b1010000),
+    ('S', 0b1101101),
+    ('t', 0b1111000),
+    ('U', 0b0111110),
+    ('y', 0b1101110),
+];
+
+/// How the alpha of overlay colors is interpreted.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum OverlayAlpha {
+    // Colors are blended in with their alpha, the usual for UI
+    Straight,
+    // Colors are already multiplied by their alpha, an alpha of 0 with a
+    // color adds light, e.g. for glows
+    Premultiplied,
+}
+
+impl OverlayAlpha {
+    fn blend(self) -> wgpu::BlendState {
+        return match self {
+            OverlayAlpha::Straight => wgpu::BlendState::ALPHA_BLENDING,
+            OverlayAlpha::Premultiplied => wgpu::BlendState::PREMULTIPLIED_ALPHA_BLENDING,
+        };
+    }
+}
+
+/// How many physical pixels a logical pixel of the UI covers: the window's
+/// DPI scale factor times the user's own UI scale.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct UiScale {
+    /// From winit, changes when the window moves to another monitor.
+    pub scale_factor: f64,
+    /// `Settings::ui_scale`.
+    pub user_scale: f32,
+}
+
+impl Default for UiScale {
+    fn default() -> Self {
+        Self {
+            scale_factor: 1.0,
+            user_scale: 1.0,
+        }
+    }
+}
+
+impl UiScale {
+    pub fn factor(&self) -> f32 {
+        return (self.scale_factor as f32 * self.user_scale).max(0.01);
+    }
+
+    pub fn to_logical(&self, physical: [f32; 2]) -> [f32; 2] {
+        let factor = self.factor();
+        return [physical[0] / factor, physical[1] / factor];
+    }
+
+    pub fn to_physical(&self, logical: [f32; 2]) -> [f32; 2] {
+        let factor = self.factor();
+        return [logical[0] * factor, logical[1] * factor];
+    }
+}
+
+/// Immediate-mode screen-space shapes drawn on top of the final image.
+/// Coordinates are in logical pixels from the top left corner, multiplied by
+/// the scale from `set_scale` to get physical ones. Colors are sRGB like a
+/// color picker's, decoded to linear for sRGB targets so they show as given
+/// and blend in the target's space. Everything queued during
+/// a frame is drawn with a single draw call and then discarded.
+pub struct Overlay {
+    vertices: Vec<OverlayVertex>,
+    // Physical pixels per logical pixel
+    scale: f32,
+    vertex_buffers: FrameBuffers,
+    vertex_count: u32,
+    screen_buffer: TrackedBuffer,
+    bind_group: wgpu::BindGroup,
+    pipeline: Arc<wgpu::RenderPipeline>,
+    version: u64,
+    cubemap_layout: wgpu::BindGroupLayout,
+    cubemap_pipeline: Arc<wgpu::RenderPipeline>,
+    cubemap_buffer: TrackedBuffer,
+    cubemap_sampler: wgpu::Sampler,
+    // Queued cube map and its corner, face size and exposure
+    cubemap: Option<(wgpu::BindGroup, [f32; 4])>,
+    // Cube map drawn by `render`
+    cubemap_drawn: Option<wgpu::BindGroup>,
+}
+
+impl Overlay {
+    pub fn new(
+        device: &wgpu::Device,
+        memory: &MemoryTracker,
+        pipelines: &mut PipelineCache,
+        shaders: &ShaderPreprocessor,
+        format: wgpu::TextureFormat,
+        alpha: OverlayAlpha,
+    ) -> Self {
+        let vertex_buffers = FrameBuffers::new(
+            device,
+            memory,
+            "Overlay Vertex Buffer",
+            wgpu::BufferUsages::VERTEX,
+            MemoryCategory::Mesh,
+            &[0; std::mem::size_of::<OverlayVertex>() * 6],
+        );
+        let screen_buffer = memory.create_buffer_init(
+            device,
+            &wgpu::util::BufferInitDescriptor {
+                label: Some("Overlay Screen Buffer"),
+                contents: bytemuck::cast_slice(&[[1.0f32; 4]]),
+                usage: wgpu::BufferUsages::UNIFORM | wgpu::BufferUsages::COPY_DST,
+            },
+            MemoryCategory::Uniform,
+        );
+
+        let bind_group_layout = device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
+            entries: &[wgpu::BindGroupLayoutEntry {
+                binding: 0,
+                visibility: wgpu::ShaderStages::VERTEX,
+                ty: wgpu::BindingType::Buffer {
+                    ty: wgpu::BufferBindingType::Uniform,
+                    has_dynamic_offset: false,
+                    min_binding_size: None,
+                },
+                count: None,
+            }],
+            label: Some("overlay_bind_group_layout"),
+        });
+        let bind_group = device.create_bind_group(&wgpu::BindGroupDescriptor {
+            layout: &bind_group_layout,
+            entries: &[wgpu::BindGroupEntry {
+                binding: 0,
+                resource: screen_buffer.as_entire_binding(),
+            }],
+            label: Some("overlay_bind_group"),
+        });
+
+        let mut overlay_shaders = shaders.clone();
+        if format.describe().srgb {
+            overlay_shaders.enable("DECODE_SRGB");
+        }
+        if alpha == OverlayAlpha::Premultiplied {
+            overlay_shaders.enable("PREMULTIPLIED_ALPHA");
+        }
+        let shader = overlay_shaders
+            .process("overlay.wgsl")
+            .expect("Failed to preprocess overlay.wgsl");
+        let pipeline = pipelines.pipeline(&PipelineDescriptor {
+            label: "Overlay Pipeline",
+            layout: "Overlay Pipeline Layout",
+            bind_group_layouts: &[&bind_group_layout],
+            shader: &shader,
+            vertex_layouts: &[OverlayVertex::desc()],
+            color_format: Some(format),
+            depth_format: None,
+            blend: alpha.blend(),
+            cull_mode: Some(wgpu::Face::Back),
+            multisample: wgpu::MultisampleState::default(),
+            order_independent: false,
+        });
+
+        let cubemap_buffer = memory.create_buffer_init(
+            device,
+            &wgpu::util::BufferInitDescriptor {
+                label: Some("Overlay Cube Map Buffer"),
+                contents: bytemuck::cast_slice(&[[0.0f32; 4]; 2]),
+                usage: wgpu::BufferUsages::UNIFORM | wgpu::BufferUsages::COPY_DST,
+            },
+            MemoryCategory::Uniform,
+        );
+        let cubemap_layout = device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
+            entries: &[
+                wgpu::BindGroupLayoutEntry {
+                    binding: 0,
+                    visibility: wgpu::ShaderStages::VERTEX_FRAGMENT,
+                    ty: wgpu::BindingType::Buffer {
+                        ty: wgpu::BufferBindingType::Uniform,
+                        has_dynamic_offset: false,
+                        min_binding_size: None,
+                    },
+                    count: None,
+                },
+                wgpu::BindGroupLayoutEntry {
+                    binding: 1,
+                    visibility: wgpu::ShaderStages::FRAGMENT,
+                    ty: wgpu::BindingType::Texture {
+                        multisampled: false,
+                        view_dimension: wgpu::TextureViewDimension::Cube,
+                        sample_type: wgpu::TextureSampleType::Float { filterable: true },
+                    },
+                    count: None,
+                },
+                wgpu::BindGroupLayoutEntry {
+                    binding: 2,
+                    visibility: wgpu::ShaderStages::FRAGMENT,
+                    ty: wgpu::BindingType::Sampler(wgpu::SamplerBindingType::Filtering),
+                    count: None,
+                },
+            ],
+            label: Some("overlay_cubemap_bind_group_layout"),
+        });
+        let cubemap_sampler = device.create_sampler(&wgpu::SamplerDescriptor {
+            label: Some("Overlay Cube Map Sampler"),
+            mag_filter: wgpu::FilterMode::Linear,
+            min_filter: wgpu::FilterMode::Linear,
+            ..Default::default()
+        });
+        let cubemap_shader = shaders
+            .process("cubemap.wgsl")
+            .expect("Failed to preprocess cubemap.wgsl");
+        let cubemap_pipeline = pipelines.pipeline(&PipelineDescriptor {
+            label: "Overlay Cube Map Pipeline",
+            layout: "Overlay Cube Map Pipeline Layout",
+            bind_group_layouts: &[&cubemap_layout],
+            shader: &cubemap_shader,
+            vertex_layouts: &[],
+            color_format: Some(format),
+            depth_format: None,
+            blend: wgpu::BlendState::REPLACE,
+            cull_mode: None,
+            multisample: wgpu::MultisampleState::default(),
+            order_independent: false,
+        });
+
+        return Self {
+            vertices: Vec::new(),
+            scale: 1.0,
+            vertex_buffers,
+            vertex_count: 0,
+            screen_buffer,
+            bind_group,
+            pipeline,
+            version: 0,
+            cubemap_layout,
+            cubemap_pipeline,
+            cubemap_buffer,
+            cubemap_sampler,
+            cubemap: None,
+            cubemap_drawn: None,
+        };
+    }
+
+    /// Physical pixels per logical pixel for shapes queued from now on, see
+    /// `UiScale::factor`.
+    pub fn set_scale(&mut self, scale: f32) {
+        self.scale = scale;
+    }
+
+    pub fn scale(&self) -> f32 {
+        return self.scale;
+    }
+
+    fn triangle(&mut self, a: [f32; 2], b: [f32; 2], c: [f32; 2], color: [f32; 4]) {
+        let [a, b, c] = [a, b, c].map(|p| [p[0] * self.scale, p[1] * self.scale]);
+        // Keep triangles counter-clockwise once y is flipped to point up in
+        // clip space, so they survive back-face culling
+        let cross = (b[0] - a[0]) * (c[1] - a[1]) - (b[1] - a[1]) * (c[0] - a[0]);
+        let (b, c) = if cross > 0.0 { (c, b) } else { (b, c) };
+        for position in [a, b, c] {
+            self.vertices.push(OverlayVertex { position, color });
+        }
+    }
+
+    fn quad(&mut self, corners: [[f32; 2]; 4], color: [f32; 4]) {
+        self.triangle(corners[0], corners[1], corners[2], color);
+        self.triangle(corners[0], corners[2], corners[3], color);
+    }
+
+    pub fn fill_rect(&mut self, min: [f32; 2], max: [f32; 2], color: [f32; 4]) {
+        self.quad(
+            [min, [max[0], min[1]], max, [min[0], max[1]]],
+            color,
+        );
+    }
+
+    /// Rectangle outline with the border drawn inside `min`..`max`.
+    pub fn rect(&mut self, min: [f32; 2], max: [f32; 2], color: [f32; 4], thickness: f32) {
+        let t = thickness.min((max[0] - min[0]) / 2.0).min((max[1] - min[1]) / 2.0);
+        self.fill_rect(min, [max[0], min[1] + t], color);
+        self.fill_rect([min[0], max[1] - t], max, color);
+        self.fill_rect([min[0], min[1] + t], [min[0] + t, max[1] - t], color);
+        self.fill_rect([max[0] - t, min[1] + t], [max[0], max[1] - t], color);
+    }
+
+    pub fn line(&mut self, from: [f32; 2], to: [f32; 2], color: [f32; 4], thickness: f32) {
+        let (dx, dy) = (to[0] - from[0], to[1] - from[1]);
+        let length = (dx * dx + dy * dy).sqrt();
+        if length <= f32::EPSILON {
+            return;
+        }
+        // Offset perpendicular to the line by half the thickness
+        let (nx, ny) = (-dy / length * thickness / 2.0, dx / length * thickness / 2.0);
+        self.quad(
+            [
+                [from[0] + nx, from[1] + ny],
+                [to[0] + nx, to[1] + ny],
+                [to[0] - nx, to[1] - ny],
+                [from[0] - nx, from[1] - ny],
+            ],
+            color,
+        );
+    }
+
+    /// Logical pixel position of a world point seen through `view_proj` in
+    /// the scene's area of the window, `None` behind the camera.
+    pub fn project(&self, viewport: &Viewport, view_proj: &Matrix4<f32>, point: Point3<f32>) -> Option<[f32; 2]> {
+        let clip = view_proj * point.to_homogeneous();
+        if clip.z < 0.0 || clip.w <= 0.0 {
+            return None;
+        }
+        return Some(self.clip_to_logical(viewport, clip));
+    }
+
+    // Clip space position in front of the camera to logical window pixels
+    fn clip_to_logical(&self, viewport: &Viewport, clip: Vector4<f32>) -> [f32; 2] {
+        let w = clip.w.max(f32::EPSILON);
+        let [x, y] = viewport.ndc_to_window(Vector2::new(clip.x / w, clip.y / w));
+        return [x / self.scale, y / self.scale];
+    }
+
+    /// Line between two world space points seen through `view_proj` in the
+    /// scene's area of the window, cut off at the near plane.
+    #[allow(clippy::too_many_arguments)]
+    pub fn world_line(
+        &mut self,
+        viewport: &Viewport,
+        view_proj: &Matrix4<f32>,
+        from: Point3<f32>,
+        to: Point3<f32>,
+        color: [f32; 4],
+        thickness: f32,
+    ) {
+        let mut a = view_proj * from.to_homogeneous();
+        let mut b = view_proj * to.to_homogeneous();
+        // Depth runs from 0 at the near plane, clip the part in front of it
+        if a.z < 0.0 && b.z < 0.0 {
+            return;
+        }
+        if a.z < 0.0 {
+            a = a + (b - a) * (a.z / (a.z - b.z));
+        } else if b.z < 0.0 {
+            b = b + (a - b) * (b.z / (b.z - a.z));
+        }
+        let (a, b) = (self.clip_to_logical(viewport, a), self.clip_to_logical(viewport, b));
+        self.line(a, b, color, thickness);
+    }
+
+    /// Edges of the frustum of a camera with `frustum_view_proj`, e.g. a
+    /// shadow map or render target camera, seen through `view_proj`.
+    pub fn frustum(
+        &mut self,
+        viewport: &Viewport,
+        view_proj: &Matrix4<f32>,
+        frustum_view_proj: &Matrix4<f32>,
+        color: [f32; 4],
+        thickness: f32,
+    ) {
+        let inverse = match frustum_view_proj.invert() {
+            Some(inverse) => inverse,
+            None => return,
+        };
+        // Near corners then far corners, counter-clockwise from bottom left
+        let corners = [0.0, 1.0].map(|z| {
+            [(-1.0, -1.0), (1.0, -1.0), (1.0, 1.0), (-1.0, 1.0)].map(|(x, y)| {
+                let p = inverse * Vector4::new(x, y, z, 1.0);
+                return Point3::from_homogeneous(p);
+            })
+        });
+        for i in 0..4 {
+            let j = (i + 1) % 4;
+            self.world_line(viewport, view_proj, corners[0][i], corners[0][j], color, thickness);
+            self.world_line(viewport, view_proj, corners[1][i], corners[1][j], color, thickness);
+            self.world_line(viewport, view_proj, corners[0][i], corners[1][i], color, thickness);
+        }
+    }
+
+    /// Connected line segments through `points`.
+    pub fn polyline(&mut self, points: &[[f32; 2]], color: [f32; 4], thickness: f32) {
+        for pair in points.windows(2) {
+            self.line(pair[0], pair[1], color, thickness);
+        }
+    }
+
+    fn circle_segments(radius: f32) -> u32 {
+        return ((radius * 0.5).ceil() as u32).clamp(12, 64);
+    }
+
+    fn circle_point(center: [f32; 2], radius: f32, i: u32, segments: u32) -> [f32; 2] {
+        let angle = i as f32 / segments as f32 * std::f32::consts::TAU;
+        return [center[0] + angle.cos() * radius, center[1] + angle.sin() * radius];
+    }
+
+    pub fn fill_circle(&mut self, center: [f32; 2], radius: f32, color: [f32; 4]) {
+        let segments = Self::circle_segments(radius);
+        for i in 0..segments {
+            let a = Self::circle_point(center, radius, i, segments);
+            let b = Self::circle_point(center, radius, i + 1, segments);
+            self.triangle(center, a, b, color);
+        }
+    }
+
+    /// Circle outline with the ring centered on `radius`.
+    pub fn circle(&mut self, center: [f32; 2], radius: f32, color: [f32; 4], thickness: f32) {
+        let segments = Self::circle_segments(radius);
+        let (inner, outer) = ((radius - thickness / 2.0).max(0.0), radius + thickness / 2.0);
+        for i in 0..segments {
+            self.quad(
+                [
+                    Self::circle_point(center, inner, i, segments),
+                    Self::circle_point(center, outer, i, segments),
+                    Self::circle_point(center, outer, i + 1, segments),
+                    Self::circle_point(center, inner, i + 1, segments),
+                ],
+                color,
+            );
+        }
+    }
+
+    /// Seven-segment text of `height` pixels starting at its top left corner.
+    /// Covers digits, `.`, `:`, `-` and the letters in `SEGMENT_GLYPHS`;
+    /// anything else is drawn as a space.
+    pub fn text(&mut self, position: [f32; 2], text: &str, height: f32, color: [f32; 4]) {
+        let (width, thickness) = (height * 0.5, (height / 8.0).max(1.0));
+        let mut x = position[0];
+        for ch in text.chars() {
+            let y = position[1];
+            let dot = |overlay: &mut Self, y: f32| {
+                overlay.fill_rect([x, y - thickness], [x + thickness, y], color);
+            };
+            match ch {
+                '.' => {
+                    dot(self, y + height);
+                    x += thickness * 3.0;
+                    continue;
+                }
+                ':' => {
+                    dot(self, y + height * 0.35);
+                    dot(self, y + height);
+                    x += thickness * 3.0;
+                    continue;
+                }
+                _ => {}
+            }
+
+            let segments = SEGMENT_GLYPHS
+                .iter()
+                .find(|(c, _)| *c == ch)
+                .map_or(0, |(_, segments)| *segments);
+            let (l, r) = (x, x + width);
+            let (t, m, b) = (y, y + height / 2.0, y + height);
+            let lines = [
+                ([l, t], [r, t]),
+                ([r, t], [r, m]),
+                ([r, m], [r, b]),
+                ([l, b], [r, b]),
+                ([l, m], [l, b]),
+                ([l, t], [l, m]),
+                ([l, m], [r, m]),
+            ];
+            for (i, (from, to)) in lines.into_iter().enumerate() {
+                if segments & (1 << i) != 0 {
+                    self.line(from, to, color, thickness);
+                }
+            }
+            x += width + height * 0.25;
+        }
+    }
+
+    /// Cube map `view` unwrapped into a horizontal cross of `face_size` pixel
+    /// faces with its top left corner at `position`: +Y above -X, +Z, +X, -Z
+    /// and -Y below, as seen from inside the cube. Colors are scaled by
+    /// `exposure` and clamped. Drawn below the shapes, one cube map per frame.
+    pub fn cubemap(
+        &mut self,
+        device: &wgpu::Device,
+        view: &wgpu::TextureView,
+        position: [f32; 2],
+        face_size: f32,
+        exposure: f32,
+    ) {
+        let bind_group = device.create_bind_group(&wgpu::BindGroupDescriptor {
+            layout: &self.cubemap_layout,
+            entries: &[
+                wgpu::BindGroupEntry {
+                    binding: 0,
+                    resource: self.cubemap_buffer.as_entire_binding(),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 1,
+                    resource: wgpu::BindingResource::TextureView(view),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 2,
+                    resource: wgpu::BindingResource::Sampler(&self.cubemap_sampler),
+                },
+            ],
+            label: Some("overlay_cubemap_bind_group"),
+        });
+        let s = self.scale;
+        self.cubemap = Some((bind_group, [position[0] * s, position[1] * s, face_size * s, exposure]));
+
+        // Face borders, column and row of each face in the cross
+        let border = [0.5, 0.5, 0.5, 1.0];
+        for (column, row) in [(1.0, 0.0), (0.0, 1.0), (1.0, 1.0), (2.0, 1.0), (3.0, 1.0), (1.0, 2.0)] {
+            let min = [position[0] + column * face_size, position[1] + row * face_size];
+            self.rect(min, [min[0] + face_size, min[1] + face_size], border, 1.0);
+        }
+    }
+
+    /// Upload everything queued since the last frame for a target of
+    /// `width` x `height` pixels, and start collecting the next frame.
+    pub fn prepare(
+        &mut self,
+        device: &wgpu::Device,
+        memory: &MemoryTracker,
+        queue: &wgpu::Queue,
+        width: u32,
+        height: u32,
+    ) {
+        self.vertex_buffers.advance();
+        self.vertex_count = self.vertices.len() as u32;
+        self.cubemap_drawn = self.cubemap.take().map(|(bind_group, [x, y, face_size, exposure])| {
+            let panel = [[width as f32, height as f32, x, y], [face_size, exposure, 0.0, 0.0]];
+            queue.write_buffer(&self.cubemap_buffer, 0, bytemuck::cast_slice(&panel));
+            bind_group
+        });
+        if self.vertices.is_empty() {
+            return;
+        }
+
+        self.version += 1;
+        self.vertex_buffers.write(
+            device,
+            memory,
+            queue,
+            bytemuck::cast_slice(&self.vertices),
+            self.version,
+        );
+        queue.write_buffer(
+            &self.screen_buffer,
+            0,
+            bytemuck::cast_slice(&[[width as f32, height as f32, 0.0, 0.0]]),
+        );
+        self.vertices.clear();
+    }
+
+    pub fn render(&self, encoder: &mut wgpu::CommandEncoder, target: &wgpu::TextureView) {
+        if self.vertex_count == 0 && self.cubemap_drawn.is_none() {
+            return;
+        }
+        let mut render_pass = encoder.begin_render_pass(&wgpu::RenderPassDescriptor {
+            label: Some("Overlay Pass"),
+            color_attachments: &[Some(wgpu::RenderPassColorAttachment {
+                view: target,
+                resolve_target: None,
+                ops: wgpu::Operations {
+                    load: wgpu::LoadOp::Load,
+                    store: true,
+                },
+            })],
+            depth_stencil_attachment: None,
+        });
+        if let Some(bind_group) = &self.cubemap_drawn {
+            render_pass.set_pipeline(&self.cubemap_pipeline);
+            render_pass.set_bind_group(0, bind_group, &[]);
+            render_pass.draw(0..6, 0..1);
+        }
+        if self.vertex_count > 0 {
+            render_pass.set_pipeline(&self.pipeline);
+            render_pass.set_bind_group(0, &self.bind_group, &[]);
+            render_pass.set_vertex_buffer(0, self.vertex_buffers.current().slice(..));
+            render_pass.draw(0..self.vertex_count, 0..1);
+        }
+    }
+}
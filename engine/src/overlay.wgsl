@@ -0,0 +1,52 @@
+// Screen-space shapes, positions are in pixels from the top left corner
+struct Screen {
+    // xy: target size in pixels
+    size: vec4<f32>,
+};
+@group(0) @binding(0)
+var<uniform> screen: Screen;
+
+struct VertexInput {
+    @location(0) position: vec2<f32>,
+    @location(1) color: vec4<f32>,
+};
+
+struct VertexOutput {
+    @builtin(position) clip_position: vec4<f32>,
+    @location(0) color: vec4<f32>,
+};
+
+@vertex
+fn vs_main(model: VertexInput) -> VertexOutput {
+    let ndc = model.position / screen.size.xy * 2.0 - 1.0;
+
+    var out: VertexOutput;
+    out.clip_position = vec4<f32>(ndc.x, -ndc.y, 0.0, 1.0);
+    out.color = model.color;
+    return out;
+}
+
+// sRGB to linear transfer function, undone by sRGB targets on write
+fn srgb_to_linear(color: vec3<f32>) -> vec3<f32> {
+    let low = color / 12.92;
+    let high = pow((color + 0.055) / 1.055, vec3<f32>(2.4));
+    return select(high, low, color <= vec3<f32>(0.04045));
+}
+
+@fragment
+fn fs_main(in: VertexOutput) -> @location(0) vec4<f32> {
+    var color = in.color;
+#ifdef DECODE_SRGB
+#ifdef PREMULTIPLIED_ALPHA
+    // Decoded before the alpha multiply, which happens in linear space
+    if (color.a > 0.0) {
+        color = vec4<f32>(srgb_to_linear(color.rgb / color.a) * color.a, color.a);
+    } else {
+        color = vec4<f32>(srgb_to_linear(color.rgb), 0.0);
+    }
+#else
+    color = vec4<f32>(srgb_to_linear(color.rgb), color.a);
+#endif
+#endif
+    return color;
+}
@@ -0,0 +1,452 @@
+use std::sync::Arc;
+
+use cgmath::{prelude::*, Point3, Quaternion, Rad, Vector3};
+
+use crate::{
+    frame::FrameBuffers,
+    memory::{MemoryCategory, MemoryTracker},
+    pipelines::{PipelineCache, PipelineDescriptor},
+    post::PostProcess,
+    shader::ShaderPreprocessor,
+};
+
+/// Volume new particles start in. Cones emit along their axis, the other
+/// shapes in all directions.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum EmitterShape {
+    Point,
+    Sphere { radius: f32 },
+    // Opening by `angle` around the emitter's +Y, from a disc of `radius`
+    Cone { angle: Rad<f32>, radius: f32 },
+    Box { half_extents: Vector3<f32> },
+}
+
+/// Values that can be blended along a `Curve`.
+pub trait Lerp: Copy {
+    fn lerp(self, other: Self, t: f32) -> Self;
+}
+
+impl Lerp for f32 {
+    fn lerp(self, other: Self, t: f32) -> Self {
+        return self + (other - self) * t;
+    }
+}
+
+impl Lerp for [f32; 3] {
+    fn lerp(self, other: Self, t: f32) -> Self {
+        return [0, 1, 2].map(|i| self[i].lerp(other[i], t));
+    }
+}
+
+impl Lerp for [f32; 4] {
+    fn lerp(self, other: Self, t: f32) -> Self {
+        return [0, 1, 2, 3].map(|i| self[i].lerp(other[i], t));
+    }
+}
+
+/// Value over a particle's life, linear between keys at ages from 0 (born)
+/// to 1 (dead).
+#[derive(Debug, Clone, PartialEq)]
+pub struct Curve<T> {
+    keys: Vec<(f32, T)>,
+}
+
+impl<T: Lerp> Curve<T> {
+    pub fn constant(value: T) -> Self {
+        return Self {
+            keys: vec![(0.0, value)],
+        };
+    }
+
+    pub fn linear(start: T, end: T) -> Self {
+        return Self {
+            keys: vec![(0.0, start), (1.0, end)],
+        };
+    }
+
+    /// Keys are sorted by age, ages outside of 0 to 1 are clamped.
+    pub fn new(mut keys: Vec<(f32, T)>) -> Self {
+        assert!(!keys.is_empty(), "A curve needs at least one key");
+        for key in &mut keys {
+            key.0 = key.0.clamp(0.0, 1.0);
+        }
+        keys.sort_by(|a, b| a.0.total_cmp(&b.0));
+        return Self { keys };
+    }
+
+    pub fn sample(&self, age: f32) -> T {
+        let next = self.keys.partition_point(|(key_age, _)| *key_age <= age);
+        if next == 0 {
+            return self.keys[0].1;
+        }
+        if next == self.keys.len() {
+            return self.keys[next - 1].1;
+        }
+        let (a, b) = (self.keys[next - 1], self.keys[next]);
+        return a.1.lerp(b.1, (age - a.0) / (b.0 - a.0));
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct EmitterSettings {
+    pub shape: EmitterShape,
+    /// Particles per second while emitting.
+    pub rate: f32,
+    /// Range the lifetime of each particle is picked from, in seconds.
+    pub lifetime: (f32, f32),
+    /// Range the initial speed is picked from.
+    pub speed: (f32, f32),
+    pub gravity: Vector3<f32>,
+    /// Fraction of the velocity lost per second.
+    pub drag: f32,
+    pub color: Curve<[f32; 4]>,
+    /// Half the side of the billboard.
+    pub size: Curve<f32>,
+    pub max_particles: usize,
+}
+
+impl Default for EmitterSettings {
+    fn default() -> Self {
+        Self {
+            shape: EmitterShape::Cone {
+                angle: Rad(0.4),
+                radius: 0.1,
+            },
+            rate: 50.0,
+            lifetime: (1.5, 2.5),
+            speed: (2.0, 4.0),
+            gravity: Vector3::new(0.0, -4.9, 0.0),
+            drag: 0.5,
+            color: Curve::linear([1.0, 1.0, 1.0, 1.0], [1.0, 1.0, 1.0, 0.0]),
+            size: Curve::linear(0.1, 0.3),
+            max_particles: 1000,
+        }
+    }
+}
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct Particle {
+    pub position: Vector3<f32>,
+    pub velocity: Vector3<f32>,
+    pub age: f32,
+    pub lifetime: f32,
+}
+
+impl Particle {
+    /// Age from 0 (born) to 1 (dead), what curves are sampled with.
+    pub fn life(&self) -> f32 {
+        return (self.age / self.lifetime).min(1.0);
+    }
+}
+
+// xorshift32, particles and generated test scenes don't need better
+// randomness. Seeds must not be zero
+#[derive(Debug, Clone)]
+pub(crate) struct Rng(pub(crate) u32);
+
+impl Rng {
+    pub(crate) fn next(&mut self) -> f32 {
+        self.0 ^= self.0 << 13;
+        self.0 ^= self.0 >> 17;
+        self.0 ^= self.0 << 5;
+        return (self.0 >> 8) as f32 / (1 << 24) as f32;
+    }
+
+    pub(crate) fn range(&mut self, (min, max): (f32, f32)) -> f32 {
+        return min + (max - min) * self.next();
+    }
+
+    pub(crate) fn unit_vector(&mut self) -> Vector3<f32> {
+        let y = self.next() * 2.0 - 1.0;
+        let angle = self.next() * std::f32::consts::TAU;
+        let r = (1.0 - y * y).sqrt();
+        return Vector3::new(r * angle.cos(), y, r * angle.sin());
+    }
+}
+
+/// Spawns and simulates particles on the CPU.
+#[derive(Debug, Clone)]
+pub struct Emitter {
+    pub settings: EmitterSettings,
+    pub position: Vector3<f32>,
+    pub rotation: Quaternion<f32>,
+    /// Whether particles are spawned at `settings.rate`, live ones keep
+    /// simulating either way.
+    pub emitting: bool,
+    particles: Vec<Particle>,
+    // Fraction of a particle carried over between updates
+    pending: f32,
+    rng: Rng,
+}
+
+impl Emitter {
+    pub fn new<P: Into<Vector3<f32>>>(settings: EmitterSettings, position: P) -> Self {
+        return Self {
+            settings,
+            position: position.into(),
+            rotation: Quaternion::one(),
+            emitting: true,
+            particles: Vec::new(),
+            pending: 0.0,
+            rng: Rng(0x9e37_79b9),
+        };
+    }
+
+    pub fn particles(&self) -> &[Particle] {
+        return &self.particles;
+    }
+
+    pub fn clear(&mut self) {
+        self.particles.clear();
+    }
+
+    /// Spawn `count` particles at once, up to `max_particles`.
+    pub fn burst(&mut self, count: usize) {
+        let count = count.min(self.settings.max_particles.saturating_sub(self.particles.len()));
+        for _ in 0..count {
+            let particle = self.spawn();
+            self.particles.push(particle);
+        }
+    }
+
+    pub fn update(&mut self, dt: f32) {
+        let settings = &self.settings;
+        let damping = (-settings.drag * dt).exp();
+        for particle in &mut self.particles {
+            particle.age += dt;
+            particle.velocity = (particle.velocity + settings.gravity * dt) * damping;
+            particle.position += particle.velocity * dt;
+        }
+        self.particles.retain(|p| p.age < p.lifetime);
+
+        if self.emitting {
+            self.pending += settings.rate * dt;
+            let count = self.pending.floor();
+            self.pending -= count;
+            self.burst(count as usize);
+        }
+    }
+
+    fn spawn(&mut self) -> Particle {
+        let rng = &mut self.rng;
+        let (offset, direction) = match self.settings.shape {
+            EmitterShape::Point => (Vector3::zero(), rng.unit_vector()),
+            EmitterShape::Sphere { radius } => {
+                let direction = rng.unit_vector();
+                // Cube root spreads particles evenly through the volume
+                (direction * radius * rng.next().cbrt(), direction)
+            }
+            EmitterShape::Cone { angle, radius } => {
+                let cos = 1.0 + (angle.cos() - 1.0) * rng.next();
+                let sin = (1.0 - cos * cos).max(0.0).sqrt();
+                let around = rng.next() * std::f32::consts::TAU;
+                let direction = Vector3::new(sin * around.cos(), cos, sin * around.sin());
+                let r = radius * rng.next().sqrt();
+                let around = rng.next() * std::f32::consts::TAU;
+                (Vector3::new(r * around.cos(), 0.0, r * around.sin()), direction)
+            }
+            EmitterShape::Box { half_extents } => {
+                let offset = Vector3::new(
+                    (rng.next() * 2.0 - 1.0) * half_extents.x,
+                    (rng.next() * 2.0 - 1.0) * half_extents.y,
+                    (rng.next() * 2.0 - 1.0) * half_extents.z,
+                );
+                (offset, rng.unit_vector())
+            }
+        };
+        let speed = rng.range(self.settings.speed);
+        let lifetime = rng.range(self.settings.lifetime).max(f32::EPSILON);
+        return Particle {
+            position: self.position + self.rotation.rotate_vector(offset),
+            velocity: self.rotation.rotate_vector(direction) * speed,
+            age: 0.0,
+            lifetime,
+        };
+    }
+}
+
+#[repr(C)]
+#[derive(Debug, Copy, Clone, Default, bytemuck::Pod, bytemuck::Zeroable)]
+struct ParticleRaw {
+    position_size: [f32; 4],
+    color: [f32; 4],
+}
+
+impl ParticleRaw {
+    fn desc<'a>() -> wgpu::VertexBufferLayout<'a> {
+        use std::mem;
+        wgpu::VertexBufferLayout {
+            array_stride: mem::size_of::<ParticleRaw>() as wgpu::BufferAddress,
+            step_mode: wgpu::VertexStepMode::Instance,
+            attributes: &[
+                wgpu::VertexAttribute {
+                    offset: 0,
+                    shader_location: 0,
+                    format: wgpu::VertexFormat::Float32x4,
+                },
+                wgpu::VertexAttribute {
+                    offset: mem::size_of::<[f32; 4]>() as wgpu::BufferAddress,
+                    shader_location: 1,
+                    format: wgpu::VertexFormat::Float32x4,
+                },
+            ],
+        }
+    }
+}
+
+/// CPU simulated emitters drawn as camera facing, alpha blended billboards.
+/// Slower than simulating on the GPU, but easy to debug and works on downlevel
+/// targets.
+pub struct ParticleSystem {
+    pub emitters: Vec<Emitter>,
+    particle_buffers: FrameBuffers,
+    particle_count: u32,
+    /// Blend with weighted blended order independent transparency instead
+    /// of sorting back to front, see `render_order_independent`.
+    pub order_independent: bool,
+    pipeline: Arc<wgpu::RenderPipeline>,
+    oit_pipeline: Arc<wgpu::RenderPipeline>,
+}
+
+impl ParticleSystem {
+    pub fn new(
+        device: &wgpu::Device,
+        memory: &MemoryTracker,
+        pipelines: &mut PipelineCache,
+        shaders: &ShaderPreprocessor,
+        camera_bind_group_layout: &wgpu::BindGroupLayout,
+        depth_format: wgpu::TextureFormat,
+        multisample: wgpu::MultisampleState,
+    ) -> Self {
+        let particle_buffers = FrameBuffers::new(
+            device,
+            memory,
+            "Particle Buffer",
+            wgpu::BufferUsages::VERTEX,
+            MemoryCategory::Mesh,
+            bytemuck::cast_slice(&[ParticleRaw::default()]),
+        );
+
+        let shader = shaders
+            .process("particles.wgsl")
+            .expect("Failed to preprocess particles.wgsl");
+        let desc = PipelineDescriptor {
+            label: "Particle Pipeline",
+            layout: "Particle Pipeline Layout",
+            bind_group_layouts: &[camera_bind_group_layout],
+            shader: &shader,
+            vertex_layouts: &[ParticleRaw::desc()],
+            color_format: Some(PostProcess::SCENE_FORMAT),
+            depth_format: Some(depth_format),
+            blend: wgpu::BlendState::ALPHA_BLENDING,
+            cull_mode: None,
+            // Soft edges are blended, coverage would dither them
+            multisample: wgpu::MultisampleState {
+                alpha_to_coverage_enabled: false,
+                ..multisample
+            },
+            order_independent: false,
+        };
+        let pipeline = pipelines.pipeline(&desc);
+        let oit_shader = {
+            let mut shaders = shaders.clone();
+            shaders.enable("ORDER_INDEPENDENT");
+            shaders
+                .process("particles.wgsl")
+                .expect("Failed to preprocess order independent particles.wgsl")
+        };
+        let oit_pipeline = pipelines.pipeline(&PipelineDescriptor {
+            label: "Particle OIT Pipeline",
+            shader: &oit_shader,
+            order_independent: true,
+            ..desc
+        });
+
+        return Self {
+            emitters: Vec::new(),
+            particle_buffers,
+            particle_count: 0,
+            order_independent: false,
+            pipeline,
+            oit_pipeline,
+        };
+    }
+
+    pub fn particle_count(&self) -> u32 {
+        return self.particle_count;
+    }
+
+    /// Simulate all emitters and upload their particles, sorted back to front
+    /// as seen from `eye` unless they're blended order independently.
+    pub fn update(
+        &mut self,
+        device: &wgpu::Device,
+        memory: &MemoryTracker,
+        queue: &wgpu::Queue,
+        dt: f32,
+        eye: Point3<f32>,
+        version: u64,
+    ) {
+        self.particle_buffers.advance();
+        let mut particles = Vec::new();
+        for emitter in &mut self.emitters {
+            emitter.update(dt);
+            let settings = &emitter.settings;
+            particles.extend(emitter.particles().iter().map(|p| {
+                let life = p.life();
+                let distance = (p.position - eye.to_vec()).magnitude2();
+                let raw = ParticleRaw {
+                    position_size: p.position.extend(settings.size.sample(life)).into(),
+                    color: settings.color.sample(life),
+                };
+                (distance, raw)
+            }));
+        }
+        if !self.order_independent {
+            particles.sort_by(|a, b| b.0.total_cmp(&a.0));
+        }
+
+        self.particle_count = particles.len() as u32;
+        if !particles.is_empty() {
+            let raw = particles.into_iter().map(|(_, raw)| raw).collect::<Vec<_>>();
+            self.particle_buffers
+                .write(device, memory, queue, bytemuck::cast_slice(&raw), version);
+        }
+    }
+
+    /// Draw sorted particles into the scene color, nothing if they're blended
+    /// order independently.
+    pub fn render<'a>(&'a self, render_pass: &mut wgpu::RenderPass<'a>, camera_bind_group: &'a wgpu::BindGroup) {
+        if !self.order_independent {
+            self.draw(render_pass, &self.pipeline, camera_bind_group);
+        }
+    }
+
+    /// Draw into the targets of a `WeightedBlendedOit` pass if the particles
+    /// are blended order independently.
+    pub fn render_order_independent<'a>(
+        &'a self,
+        render_pass: &mut wgpu::RenderPass<'a>,
+        camera_bind_group: &'a wgpu::BindGroup,
+    ) {
+        if self.order_independent {
+            self.draw(render_pass, &self.oit_pipeline, camera_bind_group);
+        }
+    }
+
+    fn draw<'a>(
+        &'a self,
+        render_pass: &mut wgpu::RenderPass<'a>,
+        pipeline: &'a wgpu::RenderPipeline,
+        camera_bind_group: &'a wgpu::BindGroup,
+    ) {
+        if self.particle_count == 0 {
+            return;
+        }
+        render_pass.set_pipeline(pipeline);
+        render_pass.set_bind_group(0, camera_bind_group, &[]);
+        render_pass.set_vertex_buffer(0, self.particle_buffers.current().slice(..));
+        // Two triangles per particle, corners come from the vertex index
+        render_pass.draw(0..6, 0..self.particle_count);
+    }
+}
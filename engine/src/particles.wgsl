@@ -0,0 +1,66 @@
+// Camera facing billboards for CPU simulated particles, see particles.rs
+#include "camera.wgsl"
+@group(0) @binding(0)
+var<uniform> camera: Camera;
+#ifdef ORDER_INDEPENDENT
+#include "oit.wgsl"
+#endif
+
+struct ParticleInput {
+    // xyz: world position, w: half the side of the billboard
+    @location(0) position_size: vec4<f32>,
+    @location(1) color: vec4<f32>,
+};
+
+struct VertexOutput {
+    @builtin(position) clip_position: vec4<f32>,
+    @location(0) corner: vec2<f32>,
+    @location(1) color: vec4<f32>,
+    @location(2) distance: f32,
+};
+
+@vertex
+fn vs_main(@builtin(vertex_index) index: u32, particle: ParticleInput) -> VertexOutput {
+    var corners = array<vec2<f32>, 6>(
+        vec2<f32>(-1.0, -1.0),
+        vec2<f32>(1.0, -1.0),
+        vec2<f32>(1.0, 1.0),
+        vec2<f32>(-1.0, -1.0),
+        vec2<f32>(1.0, 1.0),
+        vec2<f32>(-1.0, 1.0),
+    );
+    let corner = corners[index];
+    let center = particle.position_size.xyz;
+
+    // Face the eye, with a fallback when looking straight up or down
+    let forward = normalize(center - camera.view_pos.xyz);
+    var right = cross(forward, vec3<f32>(0.0, 1.0, 0.0));
+    if (dot(right, right) < 0.0001) {
+        right = vec3<f32>(1.0, 0.0, 0.0);
+    }
+    right = normalize(right);
+    let up = cross(right, forward);
+    let world_position = center + (right * corner.x + up * corner.y) * particle.position_size.w;
+
+    var out: VertexOutput;
+    out.clip_position = camera.view_proj * vec4<f32>(world_position, 1.0);
+    out.corner = corner;
+    out.color = particle.color;
+    out.distance = distance(world_position, camera.view_pos.xyz);
+    return out;
+}
+
+#ifdef ORDER_INDEPENDENT
+@fragment
+fn fs_main(input: VertexOutput) -> OitOutput {
+    let falloff = 1.0 - smoothstep(0.5, 1.0, length(input.corner));
+    return oit_output(input.color.rgb, input.color.a * falloff, input.distance);
+}
+#else
+@fragment
+fn fs_main(input: VertexOutput) -> @location(0) vec4<f32> {
+    // Soft disc
+    let falloff = 1.0 - smoothstep(0.5, 1.0, length(input.corner));
+    return vec4<f32>(input.color.rgb, input.color.a * falloff);
+}
+#endif
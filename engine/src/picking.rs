@@ -0,0 +1,360 @@
+use std::{ops::Range, sync::Arc};
+
+use anyhow::*;
+use wgpu::util::DeviceExt;
+
+use crate::{
+    memory::{MemoryCategory, MemoryTracker, TrackedTexture},
+    model::Model,
+    pipelines::{PipelineCache, PipelineDescriptor},
+    resources::{InstanceFormat, ModelVertex, Vertex},
+    shader::ShaderPreprocessor,
+    texture::Texture,
+};
+
+/// Index of an instance in `Renderer::instances`.
+pub type InstanceId = usize;
+
+/// Region of the window in physical pixels, see `Renderer::pick_gpu`.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct PickRect {
+    pub x: u32,
+    pub y: u32,
+    pub width: u32,
+    pub height: u32,
+}
+
+impl PickRect {
+    /// The single pixel under a point.
+    pub fn point(x: f32, y: f32) -> Self {
+        return Self {
+            x: x.max(0.0) as u32,
+            y: y.max(0.0) as u32,
+            width: 1,
+            height: 1,
+        };
+    }
+
+    /// Rectangle spanned by two opposite corners, e.g. of a mouse drag.
+    pub fn from_corners(a: (f32, f32), b: (f32, f32)) -> Self {
+        let (x0, x1) = (a.0.min(b.0).max(0.0), a.0.max(b.0).max(0.0));
+        let (y0, y1) = (a.1.min(b.1).max(0.0), a.1.max(b.1).max(0.0));
+        return Self {
+            x: x0 as u32,
+            y: y0 as u32,
+            width: (x1.ceil() as u32 - x0 as u32).max(1),
+            height: (y1.ceil() as u32 - y0 as u32).max(1),
+        };
+    }
+
+    /// Smallest rectangle holding all points.
+    pub fn bounding(points: &[(f32, f32)]) -> Option<Self> {
+        let first = *points.first()?;
+        let (min, max) = points.iter().fold((first, first), |(min, max), p| {
+            ((min.0.min(p.0), min.1.min(p.1)), (max.0.max(p.0), max.1.max(p.1)))
+        });
+        return Some(Self::from_corners(min, max));
+    }
+
+    // Part of the rectangle inside a target of the given size
+    fn clamp(self, width: u32, height: u32) -> Option<Self> {
+        let x1 = (self.x + self.width).min(width);
+        let y1 = (self.y + self.height).min(height);
+        if self.x >= x1 || self.y >= y1 {
+            return None;
+        }
+        return Some(Self {
+            x: self.x,
+            y: self.y,
+            width: x1 - self.x,
+            height: y1 - self.y,
+        });
+    }
+}
+
+/// Instance IDs of a region, row by row.
+pub struct IdImage {
+    pub rect: PickRect,
+    // 0 where no instance was drawn, otherwise the instance ID plus one
+    pub ids: Vec<u32>,
+}
+
+impl IdImage {
+    pub fn get(&self, x: u32, y: u32) -> Option<InstanceId> {
+        if x < self.rect.x || y < self.rect.y || x >= self.rect.x + self.rect.width || y >= self.rect.y + self.rect.height {
+            return None;
+        }
+        let id = self.ids[((y - self.rect.y) * self.rect.width + x - self.rect.x) as usize];
+        return id.checked_sub(1).map(|id| id as InstanceId);
+    }
+
+    /// Distinct instances covering any pixel where `inside` holds, in order of
+    /// their first pixel.
+    pub fn instances(&self, mut inside: impl FnMut(u32, u32) -> bool) -> Vec<InstanceId> {
+        let mut found = Vec::new();
+        for (i, &id) in self.ids.iter().enumerate() {
+            let x = self.rect.x + i as u32 % self.rect.width;
+            let y = self.rect.y + i as u32 / self.rect.width;
+            if id == 0 || found.contains(&(id as InstanceId - 1)) || !inside(x, y) {
+                continue;
+            }
+            found.push(id as InstanceId - 1);
+        }
+        return found;
+    }
+}
+
+/// Per instance ID, bound next to the instance buffer of an ID pass.
+#[repr(C)]
+#[derive(Debug, Copy, Clone, bytemuck::Pod, bytemuck::Zeroable)]
+pub struct PickId {
+    pub id: u32,
+}
+
+impl Vertex for PickId {
+    fn desc<'a>() -> wgpu::VertexBufferLayout<'a> {
+        return wgpu::VertexBufferLayout {
+            array_stride: std::mem::size_of::<PickId>() as wgpu::BufferAddress,
+            step_mode: wgpu::VertexStepMode::Instance,
+            attributes: &[wgpu::VertexAttribute {
+                offset: 0,
+                shader_location: 12,
+                format: wgpu::VertexFormat::Uint32,
+            }],
+        };
+    }
+}
+
+/// Instances drawn by an ID pass. `ids` holds the instance ID of every slot
+/// of `instance_buffer`, or `None` for geometry that only occludes.
+pub struct IdDraw<'a> {
+    pub model: &'a Model,
+    pub instances: Range<u32>,
+    pub instance_buffer: &'a wgpu::Buffer,
+    pub ids: Option<&'a [u32]>,
+}
+
+struct IdTarget {
+    texture: TrackedTexture,
+    view: wgpu::TextureView,
+    depth: Texture,
+    width: u32,
+    height: u32,
+}
+
+/// Renders instance IDs into an integer target on demand and reads regions
+/// of it back, for per-pixel and rectangle selection.
+pub struct GpuPicker {
+    pipeline: Arc<wgpu::RenderPipeline>,
+    target: Option<IdTarget>,
+}
+
+impl GpuPicker {
+    pub const FORMAT: wgpu::TextureFormat = wgpu::TextureFormat::R32Uint;
+    const DEPTH_FORMAT: wgpu::TextureFormat = wgpu::TextureFormat::Depth32Float;
+
+    pub fn new(
+        pipelines: &mut PipelineCache,
+        shaders: &ShaderPreprocessor,
+        camera_bind_group_layout: &wgpu::BindGroupLayout,
+        instance_format: InstanceFormat,
+    ) -> Self {
+        let mut shaders = shaders.clone();
+        if let Some(define) = instance_format.shader_define() {
+            shaders.enable(define);
+        }
+        let shader = shaders
+            .process("picking.wgsl")
+            .expect("Failed to preprocess picking.wgsl");
+        let pipeline = pipelines.pipeline(&PipelineDescriptor {
+            label: "Picking Pipeline",
+            layout: "Picking Pipeline Layout",
+            bind_group_layouts: &[camera_bind_group_layout],
+            shader: &shader,
+            vertex_layouts: &[ModelVertex::desc(), instance_format.desc(), PickId::desc()],
+            color_format: Some(Self::FORMAT),
+            depth_format: Some(Self::DEPTH_FORMAT),
+            blend: wgpu::BlendState::REPLACE,
+            cull_mode: Some(wgpu::Face::Back),
+            multisample: wgpu::MultisampleState::default(),
+            order_independent: false,
+        });
+        return Self { pipeline, target: None };
+    }
+
+    /// Draw `draws` from the camera and read back the IDs inside `rect`.
+    /// Blocks until the GPU is done. The target follows the window size and
+    /// is kept for following picks.
+    #[allow(clippy::too_many_arguments)]
+    pub fn read(
+        &mut self,
+        device: &wgpu::Device,
+        memory: &MemoryTracker,
+        queue: &wgpu::Queue,
+        size: (u32, u32),
+        camera_bind_group: &wgpu::BindGroup,
+        draws: &[IdDraw],
+        rect: PickRect,
+    ) -> Result<IdImage> {
+        let rect = rect.clamp(size.0, size.1).context("Pick region is outside of the window")?;
+        if self.target.as_ref().is_none_or(|t| (t.width, t.height) != size) {
+            self.target = Some(Self::create_target(device, memory, size));
+        }
+        let target = self.target.as_ref().unwrap();
+
+        // Slots of occluders keep ID 0 so they hide instances behind them
+        let id_buffers = draws
+            .iter()
+            .map(|draw| {
+                let ids = match draw.ids {
+                    Some(ids) => ids.iter().map(|&id| PickId { id: id + 1 }).collect(),
+                    None => vec![PickId { id: 0 }; draw.instances.end as usize],
+                };
+                device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
+                    label: Some("Pick ID Buffer"),
+                    contents: bytemuck::cast_slice(&ids),
+                    usage: wgpu::BufferUsages::VERTEX,
+                })
+            })
+            .collect::<Vec<_>>();
+
+        let mut encoder = device.create_command_encoder(&wgpu::CommandEncoderDescriptor {
+            label: Some("Picking Encoder"),
+        });
+        {
+            let mut render_pass = encoder.begin_render_pass(&wgpu::RenderPassDescriptor {
+                label: Some("Picking Pass"),
+                color_attachments: &[Some(wgpu::RenderPassColorAttachment {
+                    view: &target.view,
+                    resolve_target: None,
+                    ops: wgpu::Operations {
+                        load: wgpu::LoadOp::Clear(wgpu::Color::TRANSPARENT),
+                        store: true,
+                    },
+                })],
+                depth_stencil_attachment: Some(wgpu::RenderPassDepthStencilAttachment {
+                    view: &target.depth.view,
+                    depth_ops: Some(wgpu::Operations {
+                        load: wgpu::LoadOp::Clear(1.0),
+                        store: false,
+                    }),
+                    stencil_ops: None,
+                }),
+            });
+            render_pass.set_pipeline(&self.pipeline);
+            render_pass.set_bind_group(0, camera_bind_group, &[]);
+            render_pass.set_scissor_rect(rect.x, rect.y, rect.width, rect.height);
+            for (draw, ids) in draws.iter().zip(&id_buffers) {
+                render_pass.set_vertex_buffer(1, draw.instance_buffer.slice(..));
+                render_pass.set_vertex_buffer(2, ids.slice(..));
+                for mesh in &draw.model.meshes {
+                    render_pass.set_vertex_buffer(0, mesh.vertex_buffer.slice(..));
+                    render_pass.set_index_buffer(mesh.index_buffer.slice(..), wgpu::IndexFormat::Uint32);
+                    render_pass.draw_indexed(0..mesh.num_elements, 0, draw.instances.clone());
+                }
+            }
+        }
+
+        // Rows of a texture copy have to be aligned
+        let align = wgpu::COPY_BYTES_PER_ROW_ALIGNMENT;
+        let padded_bytes_per_row = (rect.width * 4).div_ceil(align) * align;
+        let readback = device.create_buffer(&wgpu::BufferDescriptor {
+            label: Some("Picking Readback Buffer"),
+            size: (padded_bytes_per_row * rect.height) as wgpu::BufferAddress,
+            usage: wgpu::BufferUsages::COPY_DST | wgpu::BufferUsages::MAP_READ,
+            mapped_at_creation: false,
+        });
+        encoder.copy_texture_to_buffer(
+            wgpu::ImageCopyTexture {
+                texture: &target.texture,
+                mip_level: 0,
+                origin: wgpu::Origin3d { x: rect.x, y: rect.y, z: 0 },
+                aspect: wgpu::TextureAspect::All,
+            },
+            wgpu::ImageCopyBuffer {
+                buffer: &readback,
+                layout: wgpu::ImageDataLayout {
+                    offset: 0,
+                    bytes_per_row: std::num::NonZeroU32::new(padded_bytes_per_row),
+                    rows_per_image: None,
+                },
+            },
+            wgpu::Extent3d {
+                width: rect.width,
+                height: rect.height,
+                depth_or_array_layers: 1,
+            },
+        );
+        queue.submit(Some(encoder.finish()));
+
+        let slice = readback.slice(..);
+        let (sender, receiver) = std::sync::mpsc::channel();
+        slice.map_async(wgpu::MapMode::Read, move |result| {
+            let _ = sender.send(result);
+        });
+        device.poll(wgpu::Maintain::Wait);
+        receiver
+            .recv()
+            .context("Picking readback was dropped")?
+            .context("Failed to map picking readback")?;
+
+        let mut ids = Vec::with_capacity((rect.width * rect.height) as usize);
+        {
+            let data = slice.get_mapped_range();
+            for row in data.chunks_exact(padded_bytes_per_row as usize) {
+                ids.extend_from_slice(bytemuck::cast_slice(&row[..(rect.width * 4) as usize]));
+            }
+        }
+        readback.unmap();
+        return Ok(IdImage { rect, ids });
+    }
+
+    fn create_target(device: &wgpu::Device, memory: &MemoryTracker, size: (u32, u32)) -> IdTarget {
+        let texture = memory.create_texture(
+            device,
+            &wgpu::TextureDescriptor {
+                label: Some("Picking Texture"),
+                size: wgpu::Extent3d {
+                    width: size.0,
+                    height: size.1,
+                    depth_or_array_layers: 1,
+                },
+                mip_level_count: 1,
+                sample_count: 1,
+                dimension: wgpu::TextureDimension::D2,
+                format: Self::FORMAT,
+                usage: wgpu::TextureUsages::RENDER_ATTACHMENT | wgpu::TextureUsages::COPY_SRC,
+            },
+            MemoryCategory::Texture,
+        );
+        let view = texture.create_view(&wgpu::TextureViewDescriptor::default());
+        let depth = Texture::create_render_target(
+            device,
+            memory,
+            size.0,
+            size.1,
+            Self::DEPTH_FORMAT,
+            1,
+            "Picking Depth Texture",
+        );
+        return IdTarget {
+            texture,
+            view,
+            depth,
+            width: size.0,
+            height: size.1,
+        };
+    }
+}
+
+/// Even-odd test of a point against a polygon, e.g. a lasso drawn with the mouse.
+pub fn polygon_contains(polygon: &[(f32, f32)], x: f32, y: f32) -> bool {
+    let mut inside = false;
+    for (i, a) in polygon.iter().enumerate() {
+        let b = polygon[(i + 1) % polygon.len()];
+        if (a.1 > y) != (b.1 > y) && x < a.0 + (y - a.1) / (b.1 - a.1) * (b.0 - a.0) {
+            inside = !inside;
+        }
+    }
+    return inside;
+}
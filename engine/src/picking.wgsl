@@ -0,0 +1,36 @@
+// Instance IDs for GPU picking, see picking.rs. 0 is left for pixels
+// without an instance
+#include "camera.wgsl"
+@group(0) @binding(0)
+var<uniform> camera: Camera;
+#include "instance.wgsl"
+
+struct VertexInput {
+    @location(0) position: vec3<f32>,
+};
+
+struct VertexOutput {
+    @builtin(position) clip_position: vec4<f32>,
+    @location(0) world_position: vec3<f32>,
+    @location(1) @interpolate(flat) id: u32,
+};
+
+@vertex
+fn vs_main(model: VertexInput, instance: InstanceInput, @location(12) id: u32) -> VertexOutput {
+    let transform = instance_transform(instance);
+    let world_position = transform.model * vec4<f32>(model.position, 1.0);
+    var out: VertexOutput;
+    out.clip_position = camera.view_proj * world_position;
+    out.world_position = world_position.xyz;
+    out.id = id;
+    return out;
+}
+
+@fragment
+fn fs_main(in: VertexOutput) -> @location(0) u32 {
+    // Same clipping as the main pass, so hidden geometry can't be picked
+    if (dot(camera.clip_plane.xyz, in.world_position) + camera.clip_plane.w < 0.0) {
+        discard;
+    }
+    return in.id;
+}
@@ -0,0 +1,390 @@
+use std::{
+    collections::{hash_map::DefaultHasher, HashMap},
+    hash::{Hash, Hasher},
+    sync::{
+        mpsc::{self, Receiver, Sender},
+        Arc,
+    },
+    thread::JoinHandle,
+};
+
+use crate::oit::WeightedBlendedOit;
+
+/// Handle to a pipeline in a `PipelineCache`.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+pub struct PipelineId(usize);
+
+/// Render state a pipeline is built from. Pipelines with equal keys are
+/// interchangeable, so each is built once and shared.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct PipelineKey {
+    // Hash of the preprocessed WGSL
+    pub shader: u64,
+    pub layout: String,
+    pub vertex_layouts: Vec<wgpu::VertexBufferLayout<'static>>,
+    pub blend: wgpu::BlendState,
+    pub depth_format: Option<wgpu::TextureFormat>,
+    pub cull_mode: Option<wgpu::Face>,
+    pub color_format: Option<wgpu::TextureFormat>,
+    pub multisample: wgpu::MultisampleState,
+    pub order_independent: bool,
+}
+
+/// A pipeline drawing triangle lists with `vs_main` and `fs_main` into one
+/// color target or the two order independent transparency targets, or only
+/// `vs_main` into depth without a color target.
+#[derive(Copy, Clone)]
+pub struct PipelineDescriptor<'a> {
+    pub label: &'a str,
+    // Pipelines naming the same layout must use the same bind group layouts
+    pub layout: &'a str,
+    pub bind_group_layouts: &'a [&'a wgpu::BindGroupLayout],
+    // Preprocessed WGSL
+    pub shader: &'a str,
+    pub vertex_layouts: &'a [wgpu::VertexBufferLayout<'static>],
+    pub color_format: Option<wgpu::TextureFormat>,
+    pub depth_format: Option<wgpu::TextureFormat>,
+    pub blend: wgpu::BlendState,
+    pub cull_mode: Option<wgpu::Face>,
+    pub multisample: wgpu::MultisampleState,
+    /// Draw into the accumulation and revealage targets of
+    /// `WeightedBlendedOit` instead of `color_format` with `blend`, testing
+    /// depth without writing it.
+    pub order_independent: bool,
+}
+
+impl<'a> PipelineDescriptor<'a> {
+    pub fn key(&self) -> PipelineKey {
+        let mut hasher = DefaultHasher::new();
+        self.shader.hash(&mut hasher);
+        return PipelineKey {
+            shader: hasher.finish(),
+            layout: self.layout.to_string(),
+            vertex_layouts: self.vertex_layouts.to_vec(),
+            blend: self.blend,
+            depth_format: self.depth_format,
+            cull_mode: self.cull_mode,
+            color_format: self.color_format,
+            multisample: self.multisample,
+            order_independent: self.order_independent,
+        };
+    }
+}
+
+/// How often pipelines were found in the cache rather than built.
+#[derive(Debug, Copy, Clone, Default, PartialEq, Eq)]
+pub struct PipelineStats {
+    pub hits: usize,
+    pub misses: usize,
+}
+
+// Everything the worker needs to build a pipeline
+struct Job {
+    id: PipelineId,
+    label: String,
+    layout: Arc<wgpu::PipelineLayout>,
+    key: PipelineKey,
+    shader: String,
+}
+
+impl Job {
+    fn build(self, device: &wgpu::Device) -> wgpu::RenderPipeline {
+        let shader = device.create_shader_module(wgpu::ShaderModuleDescriptor {
+            label: Some(&self.label),
+            source: wgpu::ShaderSource::Wgsl(self.shader.into()),
+        });
+        let key = self.key;
+        // Integer formats can't be blended
+        let targets = if key.order_independent {
+            WeightedBlendedOit::color_targets().map(Some).to_vec()
+        } else {
+            vec![key.color_format.map(|format| wgpu::ColorTargetState {
+                format,
+                blend: (!matches!(
+                    format.describe().sample_type,
+                    wgpu::TextureSampleType::Uint | wgpu::TextureSampleType::Sint
+                ))
+                .then_some(key.blend),
+                write_mask: wgpu::ColorWrites::ALL,
+            })]
+        };
+        return device.create_render_pipeline(&wgpu::RenderPipelineDescriptor {
+            label: Some(&self.label),
+            layout: Some(&self.layout),
+            vertex: wgpu::VertexState {
+                module: &shader,
+                entry_point: "vs_main",
+                buffers: &key.vertex_layouts,
+            },
+            fragment: key.color_format.map(|_| wgpu::FragmentState {
+                module: &shader,
+                entry_point: "fs_main",
+                targets: &targets,
+            }),
+            primitive: wgpu::PrimitiveState {
+                topology: wgpu::PrimitiveTopology::TriangleList,
+                strip_index_format: None,
+                front_face: wgpu::FrontFace::Ccw,
+                cull_mode: key.cull_mode,
+                unclipped_depth: false,
+                polygon_mode: wgpu::PolygonMode::Fill,
+                conservative: false,
+            },
+            depth_stencil: key.depth_format.map(|format| wgpu::DepthStencilState {
+                format,
+                depth_write_enabled: !key.order_independent,
+                depth_compare: wgpu::CompareFunction::Less,
+                stencil: wgpu::StencilState::default(),
+                bias: wgpu::DepthBiasState::default(),
+            }),
+            multisample: key.multisample,
+            multiview: None,
+        });
+    }
+}
+
+/// Reported each time a pipeline finishes compiling.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PipelineProgress {
+    pub ready: usize,
+    pub total: usize,
+    // Label of the pipeline that just finished
+    pub label: String,
+}
+
+impl PipelineProgress {
+    pub fn is_complete(&self) -> bool {
+        return self.ready == self.total;
+    }
+}
+
+type ProgressCallback = Box<dyn FnMut(&PipelineProgress)>;
+
+struct Slot {
+    label: String,
+    pipeline: Option<Arc<wgpu::RenderPipeline>>,
+    // Drawn instead while `pipeline` compiles
+    fallback: Option<PipelineId>,
+}
+
+/// Builds render pipelines keyed by their render state, so subsystems and
+/// material variants asking for the same pipeline share it. Pipelines can
+/// compile on a worker thread so that startup doesn't stall on shader
+/// compilation; until one is ready, `get` hands out its fallback, typically a
+/// cheap stand-in material compiled up front.
+pub struct PipelineCache {
+    device: Arc<wgpu::Device>,
+    slots: Vec<Slot>,
+    keys: HashMap<PipelineKey, PipelineId>,
+    layouts: HashMap<String, Arc<wgpu::PipelineLayout>>,
+    stats: PipelineStats,
+    // `None` if the worker couldn't be started, pipelines then compile inline
+    sender: Option<Sender<Job>>,
+    receiver: Receiver<(PipelineId, wgpu::RenderPipeline)>,
+    worker: Option<JoinHandle<()>>,
+    on_progress: Option<ProgressCallback>,
+}
+
+impl PipelineCache {
+    pub fn new(device: Arc<wgpu::Device>) -> Self {
+        let (job_sender, jobs) = mpsc::channel::<Job>();
+        let (done, receiver) = mpsc::channel();
+        let worker_device = device.clone();
+        let worker = std::thread::Builder::new()
+            .name("pipeline compiler".into())
+            .spawn(move || {
+                for job in jobs {
+                    if done.send((job.id, job.build(&worker_device))).is_err() {
+                        return;
+                    }
+                }
+            });
+        let (sender, worker) = match worker {
+            Ok(worker) => (Some(job_sender), Some(worker)),
+            Err(e) => {
+                log::warn!("Compiling pipelines on the render thread: {}", e);
+                (None, None)
+            }
+        };
+        return Self {
+            device,
+            slots: Vec::new(),
+            keys: HashMap::new(),
+            layouts: HashMap::new(),
+            stats: PipelineStats::default(),
+            sender,
+            receiver,
+            worker,
+            on_progress: None,
+        };
+    }
+
+    /// The pipeline described by `desc`, built on this thread unless the
+    /// cache has it, without waiting behind the pipelines queued on the
+    /// worker. Ready once this returns.
+    pub fn create(&mut self, desc: &PipelineDescriptor) -> PipelineId {
+        let (id, job) = self.add(desc, None);
+        match job {
+            Some(job) => {
+                let pipeline = job.build(&self.device);
+                self.finish(id, pipeline);
+            }
+            // Requested with `compile` before, it may still be queued
+            None => self.wait_for(id),
+        }
+        return id;
+    }
+
+    /// Like `create`, for owners that keep the pipeline to themselves.
+    pub fn pipeline(&mut self, desc: &PipelineDescriptor) -> Arc<wgpu::RenderPipeline> {
+        let id = self.create(desc);
+        return self.slots[id.0].pipeline.clone().expect("Pipeline was not built");
+    }
+
+    /// Queue the pipeline described by `desc` on the worker unless the cache
+    /// has it. `get` returns `fallback` until it's done. A pipeline already in
+    /// the cache keeps its own fallback.
+    pub fn compile(&mut self, desc: &PipelineDescriptor, fallback: Option<PipelineId>) -> PipelineId {
+        let (id, job) = self.add(desc, fallback);
+        let job = match (job, &self.sender) {
+            (Some(job), Some(sender)) => sender.send(job).err().map(|e| e.0),
+            (job, _) => job,
+        };
+        // No worker to take it
+        if let Some(job) = job {
+            let pipeline = job.build(&self.device);
+            self.finish(id, pipeline);
+        }
+        return id;
+    }
+
+    // Slot of the pipeline described by `desc`, and the job building it
+    // unless the cache already has it
+    fn add(&mut self, desc: &PipelineDescriptor, fallback: Option<PipelineId>) -> (PipelineId, Option<Job>) {
+        let key = desc.key();
+        if let Some(&id) = self.keys.get(&key) {
+            self.stats.hits += 1;
+            return (id, None);
+        }
+        self.stats.misses += 1;
+
+        let id = PipelineId(self.slots.len());
+        self.slots.push(Slot {
+            label: desc.label.to_string(),
+            pipeline: None,
+            fallback,
+        });
+        self.keys.insert(key.clone(), id);
+        let job = Job {
+            id,
+            label: desc.label.to_string(),
+            layout: self.layout(desc),
+            key,
+            shader: desc.shader.to_string(),
+        };
+        return (id, Some(job));
+    }
+
+    fn layout(&mut self, desc: &PipelineDescriptor) -> Arc<wgpu::PipelineLayout> {
+        let device = &self.device;
+        let layout = self.layouts.entry(desc.layout.to_string()).or_insert_with(|| {
+            Arc::new(device.create_pipeline_layout(&wgpu::PipelineLayoutDescriptor {
+                label: Some(desc.layout),
+                bind_group_layouts: desc.bind_group_layouts,
+                push_constant_ranges: &[],
+            }))
+        });
+        return layout.clone();
+    }
+
+    /// Called once per frame to pick up pipelines the worker finished.
+    pub fn poll(&mut self) {
+        while let Ok((id, pipeline)) = self.receiver.try_recv() {
+            self.finish(id, pipeline);
+        }
+    }
+
+    /// Block until every queued pipeline is compiled, e.g. before a capture.
+    pub fn wait(&mut self) {
+        while !self.is_complete() {
+            if !self.receive() {
+                return;
+            }
+        }
+    }
+
+    fn wait_for(&mut self, id: PipelineId) {
+        while !self.is_ready(id) {
+            if !self.receive() {
+                panic!("Pipeline compiler stopped before {}", self.slots[id.0].label);
+            }
+        }
+    }
+
+    // False once the worker died, its pipelines fall back for good
+    fn receive(&mut self) -> bool {
+        return match self.receiver.recv() {
+            Ok((id, pipeline)) => {
+                self.finish(id, pipeline);
+                true
+            }
+            Err(_) => false,
+        };
+    }
+
+    /// The pipeline, or the nearest fallback that is ready.
+    pub fn get(&self, id: PipelineId) -> Option<&wgpu::RenderPipeline> {
+        let slot = &self.slots[id.0];
+        return match &slot.pipeline {
+            Some(pipeline) => Some(pipeline.as_ref()),
+            None => slot.fallback.and_then(|fallback| self.get(fallback)),
+        };
+    }
+
+    pub fn is_ready(&self, id: PipelineId) -> bool {
+        return self.slots[id.0].pipeline.is_some();
+    }
+
+    pub fn is_complete(&self) -> bool {
+        return self.slots.iter().all(|s| s.pipeline.is_some());
+    }
+
+    pub fn stats(&self) -> PipelineStats {
+        return self.stats;
+    }
+
+    /// Ready and total pipelines, e.g. for a loading screen.
+    pub fn progress(&self) -> (usize, usize) {
+        let ready = self.slots.iter().filter(|s| s.pipeline.is_some()).count();
+        return (ready, self.slots.len());
+    }
+
+    /// Call `callback` from `poll` or `wait` whenever a pipeline finishes.
+    pub fn on_progress<F: FnMut(&PipelineProgress) + 'static>(&mut self, callback: F) {
+        self.on_progress = Some(Box::new(callback));
+    }
+
+    fn finish(&mut self, id: PipelineId, pipeline: wgpu::RenderPipeline) {
+        self.slots[id.0].pipeline = Some(Arc::new(pipeline));
+        let (ready, total) = self.progress();
+        log::debug!("Compiled {} ({}/{})", self.slots[id.0].label, ready, total);
+        if let Some(callback) = &mut self.on_progress {
+            callback(&PipelineProgress {
+                ready,
+                total,
+                label: self.slots[id.0].label.clone(),
+            });
+        }
+    }
+}
+
+impl Drop for PipelineCache {
+    fn drop(&mut self) {
+        // Closing both channels ends the worker after its current pipeline
+        self.sender = None;
+        drop(std::mem::replace(&mut self.receiver, mpsc::channel().1));
+        if let Some(worker) = self.worker.take() {
+            let _ = worker.join();
+        }
+    }
+}
@@ -0,0 +1,156 @@
+use cgmath::{prelude::*, Matrix4, Point3, Quaternion, Vector3};
+
+pub use crate::math::Ray;
+use crate::{layers::RenderLayers, math::Aabb, overlay::Overlay, resources::Instance, viewport::Viewport};
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct PlacementHit {
+    pub point: Point3<f32>,
+    pub normal: Vector3<f32>,
+    pub distance: f32,
+    // Instance under the cursor, None for the ground plane
+    pub instance: Option<usize>,
+}
+
+/// Closest hit of `ray` against the bounds of every instance on `layers` and the ground plane.
+pub fn raycast(
+    ray: &Ray,
+    instances: &[Instance],
+    layers: RenderLayers,
+    bounds: Option<Aabb>,
+    ground_height: f32,
+) -> Option<PlacementHit> {
+    let mut closest = ray
+        .intersect_plane(Point3::new(0.0, ground_height, 0.0), Vector3::unit_y())
+        .map(|distance| PlacementHit {
+            point: ray.at(distance),
+            normal: Vector3::unit_y(),
+            distance,
+            instance: None,
+        });
+
+    if let Some(bounds) = bounds {
+        for (i, instance) in instances.iter().enumerate() {
+            if !instance.layers.intersects(layers) {
+                continue;
+            }
+            let local = ray.in_space_of(&instance.transform());
+            if let Some((distance, normal)) = local.intersect_aabb(&bounds) {
+                if closest.is_none_or(|hit| distance < hit.distance) {
+                    closest = Some(PlacementHit {
+                        point: ray.at(distance),
+                        normal: instance.rotation.rotate_vector(normal),
+                        distance,
+                        instance: Some(i),
+                    });
+                }
+            }
+        }
+    }
+
+    return closest;
+}
+
+/// Editor helper that previews a ghost instance under the cursor and spawns it on click.
+#[derive(Debug, Clone)]
+pub struct PlacementTool {
+    pub enabled: bool,
+    pub ground_height: f32,
+    pub rotation: Quaternion<f32>,
+    /// Cell size the ghost snaps to along the surface it rests on, `None`
+    /// for free placement.
+    pub grid: Option<f32>,
+    cursor: Option<(f32, f32)>,
+    ghost: Option<Instance>,
+}
+
+impl Default for PlacementTool {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            ground_height: 0.0,
+            rotation: Quaternion::one(),
+            grid: None,
+            cursor: None,
+            ghost: None,
+        }
+    }
+}
+
+impl PlacementTool {
+    pub fn set_cursor(&mut self, x: f32, y: f32) {
+        self.cursor = Some((x, y));
+    }
+
+    pub fn cursor(&self) -> Option<(f32, f32)> {
+        return self.cursor;
+    }
+
+    pub fn ghost(&self) -> Option<Instance> {
+        return self.ghost.filter(|_| self.enabled);
+    }
+
+    /// Move the ghost to rest on `hit` without intersecting the surface, given
+    /// the model's bounds. Returns true if the ghost changed.
+    pub fn update(&mut self, hit: Option<PlacementHit>, bounds: Option<Aabb>) -> bool {
+        let ghost = hit.filter(|_| self.enabled).map(|hit| {
+            // Ground hits keep the model origin on the plane, like the initial grid
+            let offset = match (hit.instance, bounds) {
+                (Some(_), Some(bounds)) => {
+                    let local = self.rotation.invert().rotate_vector(-hit.normal);
+                    bounds.support(local)
+                }
+                _ => 0.0,
+            };
+            Instance {
+                position: self.snap(hit.point.to_vec(), hit.normal) + hit.normal * offset,
+                rotation: self.rotation,
+                scale: Vector3::new(1.0, 1.0, 1.0),
+                layers: RenderLayers::ALL,
+                outline: false,
+            }
+        });
+
+        let changed = ghost != self.ghost;
+        self.ghost = ghost;
+        return changed;
+    }
+
+    /// `point` moved to the closest grid line crossing on the axes along a
+    /// surface with `normal`, unchanged without a grid.
+    pub fn snap(&self, point: Vector3<f32>, normal: Vector3<f32>) -> Vector3<f32> {
+        let cell = match self.grid {
+            Some(cell) if cell > 0.0 => cell,
+            _ => return point,
+        };
+        let mut snapped = point;
+        for axis in 0..3 {
+            // Snapping along the normal would lift the ghost off the surface
+            if normal[axis].abs() < 0.5 {
+                snapped[axis] = (point[axis] / cell).round() * cell;
+            }
+        }
+        return snapped;
+    }
+
+    /// Horizontal grid cells around the ghost while snapping, seen through
+    /// `view_proj` in the scene's area of the window.
+    pub fn draw_grid(&self, overlay: &mut Overlay, viewport: &Viewport, view_proj: &Matrix4<f32>) {
+        const CELLS: i32 = 5;
+        let (ghost, cell) = match (self.ghost(), self.grid) {
+            (Some(ghost), Some(cell)) if cell > 0.0 => (ghost, cell),
+            _ => return,
+        };
+        let center = self.snap(ghost.position, Vector3::unit_y());
+        let extent = CELLS as f32 * cell;
+        let color = [1.0, 1.0, 1.0, 0.35];
+        for i in -CELLS..=CELLS {
+            let offset = i as f32 * cell;
+            let x = Point3::new(center.x + offset, ghost.position.y, center.z);
+            let z = Point3::new(center.x, ghost.position.y, center.z + offset);
+            let (along_x, along_z) = (Vector3::unit_x() * extent, Vector3::unit_z() * extent);
+            overlay.world_line(viewport, view_proj, x - along_z, x + along_z, color, 1.0);
+            overlay.world_line(viewport, view_proj, z - along_x, z + along_x, color, 1.0);
+        }
+    }
+}
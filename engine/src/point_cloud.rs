@@ -0,0 +1,338 @@
+use std::sync::Arc;
+
+use anyhow::*;
+use cgmath::{Matrix4, SquareMatrix};
+
+use crate::{
+    memory::{MemoryCategory, MemoryTracker, TrackedBuffer},
+    pipelines::{PipelineCache, PipelineDescriptor},
+    post::PostProcess,
+    resources::{self, ModelVertex, PlyReader},
+    shader::ShaderPreprocessor,
+    texture::srgb_to_linear,
+};
+
+/// A point of a point cloud with its linear color.
+#[repr(C)]
+#[derive(Debug, Copy, Clone, PartialEq, bytemuck::Pod, bytemuck::Zeroable)]
+pub struct CloudPoint {
+    pub position: [f32; 3],
+    pub color: [f32; 3],
+}
+
+impl CloudPoint {
+    fn desc<'a>() -> wgpu::VertexBufferLayout<'a> {
+        use std::mem;
+        wgpu::VertexBufferLayout {
+            array_stride: mem::size_of::<CloudPoint>() as wgpu::BufferAddress,
+            step_mode: wgpu::VertexStepMode::Instance,
+            attributes: &[
+                wgpu::VertexAttribute {
+                    offset: 0,
+                    shader_location: 0,
+                    format: wgpu::VertexFormat::Float32x3,
+                },
+                wgpu::VertexAttribute {
+                    offset: mem::size_of::<[f32; 3]>() as wgpu::BufferAddress,
+                    shader_location: 1,
+                    format: wgpu::VertexFormat::Float32x3,
+                },
+            ],
+        }
+    }
+}
+
+// Points without colors in the file
+const DEFAULT_COLOR: [f32; 3] = [1.0, 1.0, 1.0];
+
+/// Points of an XYZ file: one point per line as `x y z`, optionally followed
+/// by an sRGB color from 0 to 1, or from 0 to 255 if any channel is above 1.
+/// Lines with fewer values, like the point count some tools start with, and
+/// lines starting with `#` are skipped.
+pub fn parse_xyz(source: &str) -> Result<Vec<CloudPoint>> {
+    let mut points = Vec::new();
+    for (number, line) in source.lines().enumerate() {
+        let line = line.trim();
+        if line.starts_with('#') {
+            continue;
+        }
+        let values = line
+            .split(|c: char| c.is_whitespace() || c == ',' || c == ';')
+            .filter(|v| !v.is_empty())
+            .map(|v| v.parse::<f32>())
+            .collect::<std::result::Result<Vec<_>, _>>()
+            .with_context(|| format!("Invalid value on line {}", number + 1))?;
+        if values.len() < 3 {
+            continue;
+        }
+        let color = match values.get(3..6) {
+            Some(rgb) => {
+                let scale = if rgb.iter().any(|c| *c > 1.0) { 255.0 } else { 1.0 };
+                [0, 1, 2].map(|i| srgb_to_linear(rgb[i] / scale))
+            }
+            None => DEFAULT_COLOR,
+        };
+        points.push(CloudPoint {
+            position: [values[0], values[1], values[2]],
+            color,
+        });
+    }
+    return Ok(points);
+}
+
+/// Vertices of a PLY file in ASCII or binary format, with their colors if
+/// they have `red`, `green` and `blue` properties. Faces and other elements
+/// are ignored.
+pub fn parse_ply(data: &[u8]) -> Result<Vec<CloudPoint>> {
+    let (mut reader, elements) = PlyReader::new(data)?;
+    let mut list = Vec::new();
+    let mut points = Vec::new();
+    for element in &elements {
+        if element.name != "vertex" {
+            reader.skip_element(element)?;
+            continue;
+        }
+
+        let position = [element.property(&["x"]), element.property(&["y"]), element.property(&["z"])];
+        ensure!(position.iter().all(Option::is_some), "PLY vertices have no x, y and z");
+        let color = [
+            element.property(&["red", "diffuse_red"]),
+            element.property(&["green", "diffuse_green"]),
+            element.property(&["blue", "diffuse_blue"]),
+        ];
+        let mut values = vec![0.0; element.properties.len()];
+        points.reserve(reader.max_rows(element));
+        for _ in 0..element.count {
+            for (value, property) in values.iter_mut().zip(&element.properties) {
+                *value = reader.read_property(property, &mut list)?;
+            }
+            let color = match color {
+                [Some(r), Some(g), Some(b)] => [r, g, b].map(|i| {
+                    let scale = element.properties[i].ty.color_scale();
+                    srgb_to_linear(values[i] as f32 / scale)
+                }),
+                _ => DEFAULT_COLOR,
+            };
+            points.push(CloudPoint {
+                position: position.map(|i| values[i.unwrap()] as f32),
+                color,
+            });
+        }
+        // Elements after the vertices don't need to be read
+        break;
+    }
+    return Ok(points);
+}
+
+/// Load an `.xyz` or `.ply` file from the resources, see `parse_xyz` and
+/// `parse_ply`.
+pub async fn load_points(file_name: &str) -> Result<Vec<CloudPoint>> {
+    let extension = std::path::Path::new(file_name)
+        .extension()
+        .and_then(|e| e.to_str())
+        .map(|e| e.to_ascii_lowercase());
+    let points = match extension.as_deref() {
+        Some("ply") => parse_ply(&resources::load_binary(file_name).await?),
+        Some("xyz") | Some("txt") => parse_xyz(&resources::load_string(file_name).await?),
+        _ => bail!("Unknown point cloud format {:?}", file_name),
+    };
+    return points.with_context(|| format!("Failed to load {:?}", file_name));
+}
+
+/// A point per vertex colored by its normal, for looking at vertex data
+/// without the triangles hiding it.
+pub fn points_from_vertices(vertices: &[ModelVertex]) -> Vec<CloudPoint> {
+    return vertices
+        .iter()
+        .map(|v| CloudPoint {
+            position: v.position,
+            color: v.normal.map(|n| n * 0.5 + 0.5),
+        })
+        .collect();
+}
+
+/// Side of the squares points are drawn as.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum PointSize {
+    /// The same number of pixels at any distance.
+    Pixels(f32),
+    /// World units, smaller further away like the rest of the scene.
+    World(f32),
+}
+
+#[repr(C)]
+#[derive(Debug, Copy, Clone, Default, bytemuck::Pod, bytemuck::Zeroable)]
+struct PointCloudUniform {
+    model: [[f32; 4]; 4],
+    params: [f32; 4],
+}
+
+/// Points uploaded once and drawn with their own transform and size.
+pub struct PointCloud {
+    pub transform: Matrix4<f32>,
+    pub size: PointSize,
+    /// Smallest side in pixels, keeps far points of `PointSize::World` visible.
+    pub min_pixels: f32,
+    pub visible: bool,
+    point_count: u32,
+    point_buffer: TrackedBuffer,
+    uniform_buffer: TrackedBuffer,
+    bind_group: wgpu::BindGroup,
+}
+
+impl PointCloud {
+    pub fn point_count(&self) -> u32 {
+        return self.point_count;
+    }
+
+    /// Bytes of the point buffer.
+    pub fn buffer_size(&self) -> u64 {
+        return self.point_buffer.size();
+    }
+}
+
+/// Point clouds drawn as opaque, round, screen aligned squares in the scene
+/// pass, e.g. for scans or to look at vertex data.
+pub struct PointCloudRenderer {
+    pub clouds: Vec<PointCloud>,
+    bind_group_layout: wgpu::BindGroupLayout,
+    pipeline: Arc<wgpu::RenderPipeline>,
+}
+
+impl PointCloudRenderer {
+    pub fn new(
+        device: &wgpu::Device,
+        pipelines: &mut PipelineCache,
+        shaders: &ShaderPreprocessor,
+        camera_bind_group_layout: &wgpu::BindGroupLayout,
+        depth_format: wgpu::TextureFormat,
+        multisample: wgpu::MultisampleState,
+    ) -> Self {
+        let bind_group_layout = device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
+            entries: &[wgpu::BindGroupLayoutEntry {
+                binding: 0,
+                visibility: wgpu::ShaderStages::VERTEX,
+                ty: wgpu::BindingType::Buffer {
+                    ty: wgpu::BufferBindingType::Uniform,
+                    has_dynamic_offset: false,
+                    min_binding_size: None,
+                },
+                count: None,
+            }],
+            label: Some("point_cloud_bind_group_layout"),
+        });
+
+        let shader = shaders
+            .process("point_cloud.wgsl")
+            .expect("Failed to preprocess point_cloud.wgsl");
+        let pipeline = pipelines.pipeline(&PipelineDescriptor {
+            label: "Point Cloud Pipeline",
+            layout: "Point Cloud Pipeline Layout",
+            bind_group_layouts: &[camera_bind_group_layout, &bind_group_layout],
+            shader: &shader,
+            vertex_layouts: &[CloudPoint::desc()],
+            color_format: Some(PostProcess::SCENE_FORMAT),
+            depth_format: Some(depth_format),
+            blend: wgpu::BlendState::REPLACE,
+            cull_mode: None,
+            multisample: wgpu::MultisampleState {
+                alpha_to_coverage_enabled: false,
+                ..multisample
+            },
+            order_independent: false,
+        });
+
+        return Self {
+            clouds: Vec::new(),
+            bind_group_layout,
+            pipeline,
+        };
+    }
+
+    /// Upload `points` as a new cloud drawn 2 pixels wide, returning its
+    /// index into `clouds`.
+    pub fn add(&mut self, device: &wgpu::Device, memory: &MemoryTracker, points: &[CloudPoint]) -> usize {
+        // Empty clouds still need a buffer to bind
+        let contents: &[CloudPoint] = if points.is_empty() {
+            &[CloudPoint {
+                position: [0.0; 3],
+                color: [0.0; 3],
+            }]
+        } else {
+            points
+        };
+        let point_buffer = memory.create_buffer_init(
+            device,
+            &wgpu::util::BufferInitDescriptor {
+                label: Some("Point Cloud Buffer"),
+                contents: bytemuck::cast_slice(contents),
+                usage: wgpu::BufferUsages::VERTEX,
+            },
+            MemoryCategory::Mesh,
+        );
+        let uniform_buffer = memory.create_buffer_init(
+            device,
+            &wgpu::util::BufferInitDescriptor {
+                label: Some("Point Cloud Uniform Buffer"),
+                contents: bytemuck::cast_slice(&[PointCloudUniform::default()]),
+                usage: wgpu::BufferUsages::UNIFORM | wgpu::BufferUsages::COPY_DST,
+            },
+            MemoryCategory::Uniform,
+        );
+        let bind_group = device.create_bind_group(&wgpu::BindGroupDescriptor {
+            layout: &self.bind_group_layout,
+            entries: &[wgpu::BindGroupEntry {
+                binding: 0,
+                resource: uniform_buffer.as_entire_binding(),
+            }],
+            label: Some("point_cloud_bind_group"),
+        });
+        self.clouds.push(PointCloud {
+            transform: Matrix4::identity(),
+            size: PointSize::Pixels(2.0),
+            min_pixels: 1.0,
+            visible: true,
+            point_count: points.len() as u32,
+            point_buffer,
+            uniform_buffer,
+            bind_group,
+        });
+        return self.clouds.len() - 1;
+    }
+
+    /// Points of the visible clouds.
+    pub fn point_count(&self) -> u32 {
+        return self.clouds.iter().filter(|c| c.visible).map(|c| c.point_count).sum();
+    }
+
+    /// Upload transforms and sizes. `projection` and the render target's
+    /// `height` turn world sizes into pixels.
+    pub fn update(&self, queue: &wgpu::Queue, projection: &Matrix4<f32>, height: u32) {
+        let pixels_per_unit = height as f32 * 0.5 * projection[1][1];
+        for cloud in self.clouds.iter().filter(|c| c.visible) {
+            let (size, world) = match cloud.size {
+                PointSize::Pixels(size) => (size, 0.0),
+                PointSize::World(size) => (size, 1.0),
+            };
+            let uniform = PointCloudUniform {
+                model: cloud.transform.into(),
+                params: [size, world, cloud.min_pixels, pixels_per_unit],
+            };
+            queue.write_buffer(&cloud.uniform_buffer, 0, bytemuck::cast_slice(&[uniform]));
+        }
+    }
+
+    pub fn render<'a>(&'a self, render_pass: &mut wgpu::RenderPass<'a>, camera_bind_group: &'a wgpu::BindGroup) {
+        let clouds = self.clouds.iter().filter(|c| c.visible && c.point_count > 0);
+        for (i, cloud) in clouds.enumerate() {
+            if i == 0 {
+                render_pass.set_pipeline(&self.pipeline);
+                render_pass.set_bind_group(0, camera_bind_group, &[]);
+            }
+            render_pass.set_bind_group(1, &cloud.bind_group, &[]);
+            render_pass.set_vertex_buffer(0, cloud.point_buffer.slice(..));
+            // Two triangles per point, corners come from the vertex index
+            render_pass.draw(0..6, 0..cloud.point_count);
+        }
+    }
+}
@@ -0,0 +1,65 @@
+// Point clouds as round, screen aligned squares, see point_cloud.rs
+#include "camera.wgsl"
+#include "scene.wgsl"
+@group(0) @binding(0)
+var<uniform> camera: Camera;
+@group(0) @binding(1)
+var<uniform> scene: Scene;
+
+struct PointCloud {
+    model: mat4x4<f32>,
+    // x: size, y: 1 if the size is in world units and 0 if in pixels,
+    // z: minimum size in pixels, w: pixels per world unit at a depth of 1
+    params: vec4<f32>,
+};
+@group(1) @binding(0)
+var<uniform> cloud: PointCloud;
+
+struct PointInput {
+    @location(0) position: vec3<f32>,
+    @location(1) color: vec3<f32>,
+};
+
+struct VertexOutput {
+    @builtin(position) clip_position: vec4<f32>,
+    @location(0) corner: vec2<f32>,
+    @location(1) color: vec3<f32>,
+};
+
+@vertex
+fn vs_main(@builtin(vertex_index) index: u32, vertex: PointInput) -> VertexOutput {
+    var corners = array<vec2<f32>, 6>(
+        vec2<f32>(-1.0, -1.0),
+        vec2<f32>(1.0, -1.0),
+        vec2<f32>(1.0, 1.0),
+        vec2<f32>(-1.0, -1.0),
+        vec2<f32>(1.0, 1.0),
+        vec2<f32>(-1.0, 1.0),
+    );
+    let corner = corners[index];
+    let center = camera.view_proj * cloud.model * vec4<f32>(vertex.position, 1.0);
+
+    var size = cloud.params.x;
+    if (cloud.params.y > 0.5) {
+        size = size * cloud.params.w / max(center.w, 0.0001);
+    }
+    size = max(size, cloud.params.z);
+    // Pixels to clip space, scaled by w to survive the perspective divide
+    let offset = corner * size / scene.screen_size * center.w;
+
+    var out: VertexOutput;
+    out.clip_position = vec4<f32>(center.xy + offset, center.zw);
+    // Points of a few pixels stay square, cut round they could miss every
+    // pixel center
+    out.corner = corner * step(3.0, size);
+    out.color = vertex.color;
+    return out;
+}
+
+@fragment
+fn fs_main(in: VertexOutput) -> @location(0) vec4<f32> {
+    if (dot(in.corner, in.corner) > 1.0) {
+        discard;
+    }
+    return vec4<f32>(in.color, 1.0);
+}
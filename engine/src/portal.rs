@@ -0,0 +1,265 @@
+use std::collections::HashMap;
+
+use cgmath::{InnerSpace, Vector3};
+
+use crate::math::{Aabb, Frustum, Plane};
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct RoomId(pub usize);
+
+/// Convex volume of an indoor scene, the inside of all its planes.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Room {
+    pub planes: Vec<Plane>,
+}
+
+impl Room {
+    pub fn new(planes: Vec<Plane>) -> Self {
+        return Self { planes };
+    }
+
+    pub fn from_aabb(aabb: &Aabb) -> Self {
+        let planes = (0..3)
+            .flat_map(|axis| {
+                let mut normal = Vector3::new(0.0, 0.0, 0.0);
+                normal[axis] = 1.0;
+                [
+                    Plane::new(normal, aabb.min[axis]),
+                    Plane::new(-normal, -aabb.max[axis]),
+                ]
+            })
+            .collect();
+        return Self { planes };
+    }
+
+    pub fn contains(&self, point: Vector3<f32>) -> bool {
+        return self.planes.iter().all(|p| p.signed_distance(point) >= 0.0);
+    }
+
+    /// False only if the box is entirely behind one of the walls, like
+    /// `Frustum::intersects_aabb`.
+    pub fn intersects_aabb(&self, aabb: &Aabb) -> bool {
+        return self.planes.iter().all(|p| aabb.support(p.normal) >= p.distance);
+    }
+}
+
+/// Convex polygon connecting two rooms, seen through from either side.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Portal {
+    pub vertices: Vec<Vector3<f32>>,
+    pub rooms: (RoomId, RoomId),
+    // Facing from the first room into the second
+    plane: Plane,
+}
+
+/// Rooms reached through portals from the camera, each with the frustums
+/// they are seen through.
+#[derive(Debug, Clone, Default)]
+pub struct RoomVisibility {
+    frustums: HashMap<RoomId, Vec<Frustum>>,
+}
+
+impl RoomVisibility {
+    pub fn is_room_visible(&self, room: RoomId) -> bool {
+        return self.frustums.contains_key(&room);
+    }
+
+    pub fn rooms(&self) -> impl Iterator<Item = RoomId> + '_ {
+        return self.frustums.keys().copied();
+    }
+
+    /// True if `bounds` in `room` can be seen through one of its portals.
+    pub fn is_visible(&self, room: RoomId, bounds: &Aabb) -> bool {
+        return match self.frustums.get(&room) {
+            Some(frustums) => frustums.iter().any(|f| f.intersects_aabb(bounds)),
+            None => false,
+        };
+    }
+}
+
+/// Rooms connected by portals. Rooms behind portals outside the view, or
+/// hidden behind walls between portals, are culled.
+#[derive(Debug, Clone)]
+pub struct PortalGraph {
+    rooms: Vec<Room>,
+    portals: Vec<Portal>,
+    // Portals of each room
+    adjacency: Vec<Vec<usize>>,
+    /// Rooms deeper than this behind the camera's room are never visible.
+    pub max_depth: usize,
+}
+
+impl Default for PortalGraph {
+    fn default() -> Self {
+        return Self::new();
+    }
+}
+
+impl PortalGraph {
+    // Cameras closer to a portal than this see through it unclipped
+    const PORTAL_EPSILON: f32 = 1e-3;
+
+    pub fn new() -> Self {
+        return Self {
+            rooms: Vec::new(),
+            portals: Vec::new(),
+            adjacency: Vec::new(),
+            max_depth: 16,
+        };
+    }
+
+    pub fn add_room(&mut self, room: Room) -> RoomId {
+        self.rooms.push(room);
+        self.adjacency.push(Vec::new());
+        return RoomId(self.rooms.len() - 1);
+    }
+
+    /// Connect `a` and `b` through a convex polygon on their shared wall.
+    pub fn add_portal(&mut self, a: RoomId, b: RoomId, vertices: Vec<Vector3<f32>>) {
+        let center = vertices.iter().sum::<Vector3<f32>>() / vertices.len().max(1) as f32;
+        // Newell's method, robust for slightly non-planar polygons
+        let mut normal = Vector3::new(0.0, 0.0, 0.0);
+        for (i, v) in vertices.iter().enumerate() {
+            let next = vertices[(i + 1) % vertices.len()];
+            normal += v.cross(next);
+        }
+        // The wall of `a` the portal lies on faces into `a`, flip towards `b`
+        let wall = self.rooms[a.0].planes.iter().min_by(|p, q| {
+            p.signed_distance(center).abs().total_cmp(&q.signed_distance(center).abs())
+        });
+        if let Some(wall) = wall {
+            if wall.normal.dot(normal) > 0.0 {
+                normal = -normal;
+            }
+        }
+
+        self.portals.push(Portal {
+            vertices,
+            rooms: (a, b),
+            plane: Plane::from_point_normal(center, normal),
+        });
+        let index = self.portals.len() - 1;
+        self.adjacency[a.0].push(index);
+        self.adjacency[b.0].push(index);
+    }
+
+    pub fn rooms(&self) -> &[Room] {
+        return &self.rooms;
+    }
+
+    pub fn portals(&self) -> &[Portal] {
+        return &self.portals;
+    }
+
+    pub fn room_at(&self, point: Vector3<f32>) -> Option<RoomId> {
+        return self.rooms.iter().position(|r| r.contains(point)).map(RoomId);
+    }
+
+    /// Rooms `bounds` reaches into, e.g. every room an instance in a doorway
+    /// can be seen from.
+    pub fn rooms_overlapping<'a>(&'a self, bounds: &'a Aabb) -> impl Iterator<Item = RoomId> + 'a {
+        return self
+            .rooms
+            .iter()
+            .enumerate()
+            .filter(|(_, room)| room.intersects_aabb(bounds))
+            .map(|(index, _)| RoomId(index));
+    }
+
+    /// Rooms visible from `eye` within `frustum`, `None` when the eye is in
+    /// no room and portals can't tell.
+    pub fn visible_rooms(&self, eye: Vector3<f32>, frustum: &Frustum) -> Option<RoomVisibility> {
+        let start = self.room_at(eye)?;
+        let mut visibility = RoomVisibility::default();
+        let mut path = vec![start];
+        self.visit(eye, start, frustum, &mut path, &mut visibility);
+        return Some(visibility);
+    }
+
+    fn visit(
+        &self,
+        eye: Vector3<f32>,
+        room: RoomId,
+        frustum: &Frustum,
+        path: &mut Vec<RoomId>,
+        visibility: &mut RoomVisibility,
+    ) {
+        visibility.frustums.entry(room).or_default().push(frustum.clone());
+        if path.len() > self.max_depth {
+            return;
+        }
+
+        for &index in &self.adjacency[room.0] {
+            let portal = &self.portals[index];
+            let (next, plane) = if portal.rooms.0 == room {
+                (portal.rooms.1, portal.plane)
+            } else {
+                (portal.rooms.0, portal.plane.flipped())
+            };
+            if path.contains(&next) {
+                continue;
+            }
+            // Seen from behind, only possible through another portal
+            let eye_distance = plane.signed_distance(eye);
+            if eye_distance > Self::PORTAL_EPSILON {
+                continue;
+            }
+
+            let narrowed = if eye_distance.abs() <= Self::PORTAL_EPSILON {
+                // Standing in the doorway
+                frustum.clone()
+            } else {
+                let clipped = frustum
+                    .planes
+                    .iter()
+                    .fold(portal.vertices.clone(), |polygon, p| clip_polygon(&polygon, p));
+                if clipped.len() < 3 {
+                    continue;
+                }
+                portal_frustum(eye, &clipped, plane)
+            };
+            path.push(next);
+            self.visit(eye, next, &narrowed, path, visibility);
+            path.pop();
+        }
+    }
+}
+
+// Part of a convex polygon in front of `plane` (Sutherland-Hodgman)
+fn clip_polygon(polygon: &[Vector3<f32>], plane: &Plane) -> Vec<Vector3<f32>> {
+    let mut clipped = Vec::with_capacity(polygon.len() + 1);
+    for (i, &a) in polygon.iter().enumerate() {
+        let b = polygon[(i + 1) % polygon.len()];
+        let (da, db) = (plane.signed_distance(a), plane.signed_distance(b));
+        if da >= 0.0 {
+            clipped.push(a);
+        }
+        if (da >= 0.0) != (db >= 0.0) {
+            clipped.push(a + (b - a) * (da / (da - db)));
+        }
+    }
+    return clipped;
+}
+
+// Frustum from `eye` through the edges of a portal polygon, starting at the
+// portal so that nothing in front of it is included
+fn portal_frustum(eye: Vector3<f32>, polygon: &[Vector3<f32>], portal_plane: Plane) -> Frustum {
+    let center = polygon.iter().sum::<Vector3<f32>>() / polygon.len() as f32;
+    let mut planes = Vec::with_capacity(polygon.len() + 1);
+    for (i, &a) in polygon.iter().enumerate() {
+        let b = polygon[(i + 1) % polygon.len()];
+        let normal = (a - eye).cross(b - eye);
+        // Edges of clipped polygons may collapse to a point
+        if normal.magnitude2() <= f32::EPSILON {
+            continue;
+        }
+        let plane = Plane::from_point_normal(eye, normal);
+        planes.push(if plane.signed_distance(center) < 0.0 {
+            plane.flipped()
+        } else {
+            plane
+        });
+    }
+    planes.push(portal_plane);
+    return Frustum::new(planes);
+}
@@ -0,0 +1,260 @@
+use std::sync::Arc;
+
+use crate::{
+    memory::{MemoryCategory, MemoryTracker, TrackedBuffer},
+    pipelines::{PipelineCache, PipelineDescriptor},
+    settings::DisplaySettings,
+    texture::Texture,
+    viewport::ViewportRect,
+};
+
+#[repr(C)]
+#[derive(Debug, Copy, Clone, bytemuck::Pod, bytemuck::Zeroable)]
+struct DisplayUniform {
+    gamma: f32,
+    brightness: f32,
+    contrast: f32,
+    saturation: f32,
+    // 1 to apply the sRGB transfer function in the shader
+    encode_srgb: u32,
+    _padding: [u32; 3],
+}
+
+impl DisplayUniform {
+    fn new(settings: DisplaySettings, encode_srgb: bool) -> Self {
+        return Self {
+            gamma: settings.gamma.max(0.01),
+            brightness: settings.brightness,
+            contrast: settings.contrast,
+            saturation: settings.saturation,
+            encode_srgb: encode_srgb as u32,
+            _padding: [0; 3],
+        };
+    }
+}
+
+/// Final pass that resolves the offscreen scene color into the surface,
+/// applying display calibration on the way. The scene is linear, sRGB
+/// surfaces encode it on write and others get it encoded by the shader, so
+/// the overlay drawn on top always lands on sRGB-encoded colors.
+pub struct PostProcess {
+    pub scene_texture: Texture,
+    // Multisampled scene color, resolved into `scene_texture`
+    msaa_texture: Option<Texture>,
+    sample_count: u32,
+    display_buffer: TrackedBuffer,
+    display: DisplaySettings,
+    // The surface isn't an sRGB format, so the shader encodes
+    encode_srgb: bool,
+    bind_group_layout: wgpu::BindGroupLayout,
+    bind_group: wgpu::BindGroup,
+    pipeline: Arc<wgpu::RenderPipeline>,
+}
+
+impl PostProcess {
+    /// Format of the offscreen scene color target; float so that lighting
+    /// is not clamped before calibration.
+    pub const SCENE_FORMAT: wgpu::TextureFormat = wgpu::TextureFormat::Rgba16Float;
+
+    pub fn new(
+        device: &wgpu::Device,
+        memory: &MemoryTracker,
+        pipelines: &mut PipelineCache,
+        config: &wgpu::SurfaceConfiguration,
+        sample_count: u32,
+        display: DisplaySettings,
+    ) -> Self {
+        let (scene_texture, msaa_texture) = Self::create_targets(device, memory, config, sample_count);
+        let encode_srgb = !config.format.describe().srgb;
+        let display_buffer = memory.create_buffer_init(
+            device,
+            &wgpu::util::BufferInitDescriptor {
+                label: Some("Display Buffer"),
+                contents: bytemuck::cast_slice(&[DisplayUniform::new(display, encode_srgb)]),
+                usage: wgpu::BufferUsages::UNIFORM | wgpu::BufferUsages::COPY_DST,
+            },
+            MemoryCategory::Uniform,
+        );
+
+        let bind_group_layout = device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
+            entries: &[
+                wgpu::BindGroupLayoutEntry {
+                    binding: 0,
+                    visibility: wgpu::ShaderStages::FRAGMENT,
+                    ty: wgpu::BindingType::Texture {
+                        multisampled: false,
+                        view_dimension: wgpu::TextureViewDimension::D2,
+                        sample_type: wgpu::TextureSampleType::Float { filterable: true },
+                    },
+                    count: None,
+                },
+                wgpu::BindGroupLayoutEntry {
+                    binding: 1,
+                    visibility: wgpu::ShaderStages::FRAGMENT,
+                    ty: wgpu::BindingType::Sampler(wgpu::SamplerBindingType::Filtering),
+                    count: None,
+                },
+                wgpu::BindGroupLayoutEntry {
+                    binding: 2,
+                    visibility: wgpu::ShaderStages::FRAGMENT,
+                    ty: wgpu::BindingType::Buffer {
+                        ty: wgpu::BufferBindingType::Uniform,
+                        has_dynamic_offset: false,
+                        min_binding_size: None,
+                    },
+                    count: None,
+                },
+            ],
+            label: Some("post_bind_group_layout"),
+        });
+        let bind_group =
+            Self::create_bind_group(device, &bind_group_layout, &scene_texture, &display_buffer);
+
+        let pipeline = pipelines.pipeline(&PipelineDescriptor {
+            label: "Post Pipeline",
+            layout: "Post Pipeline Layout",
+            bind_group_layouts: &[&bind_group_layout],
+            shader: include_str!("post.wgsl"),
+            vertex_layouts: &[],
+            color_format: Some(config.format),
+            depth_format: None,
+            blend: wgpu::BlendState::REPLACE,
+            cull_mode: Some(wgpu::Face::Back),
+            multisample: wgpu::MultisampleState::default(),
+            order_independent: false,
+        });
+
+        return Self {
+            scene_texture,
+            msaa_texture,
+            sample_count,
+            display_buffer,
+            display,
+            encode_srgb,
+            bind_group_layout,
+            bind_group,
+            pipeline,
+        };
+    }
+
+    fn create_targets(
+        device: &wgpu::Device,
+        memory: &MemoryTracker,
+        config: &wgpu::SurfaceConfiguration,
+        sample_count: u32,
+    ) -> (Texture, Option<Texture>) {
+        let scene_texture = Texture::create_render_target(
+            device,
+            memory,
+            config.width,
+            config.height,
+            Self::SCENE_FORMAT,
+            1,
+            "scene_texture",
+        );
+        let msaa_texture = (sample_count > 1).then(|| {
+            Texture::create_render_target(
+                device,
+                memory,
+                config.width,
+                config.height,
+                Self::SCENE_FORMAT,
+                sample_count,
+                "scene_msaa_texture",
+            )
+        });
+        return (scene_texture, msaa_texture);
+    }
+
+    /// View to render the scene into and the view it resolves to, if multisampled.
+    pub fn color_attachment(&self) -> (&wgpu::TextureView, Option<&wgpu::TextureView>) {
+        return match &self.msaa_texture {
+            Some(msaa) => (&msaa.view, Some(&self.scene_texture.view)),
+            None => (&self.scene_texture.view, None),
+        };
+    }
+
+    pub fn sample_count(&self) -> u32 {
+        return self.sample_count;
+    }
+
+    fn create_bind_group(
+        device: &wgpu::Device,
+        layout: &wgpu::BindGroupLayout,
+        scene_texture: &Texture,
+        display_buffer: &TrackedBuffer,
+    ) -> wgpu::BindGroup {
+        return device.create_bind_group(&wgpu::BindGroupDescriptor {
+            layout,
+            entries: &[
+                wgpu::BindGroupEntry {
+                    binding: 0,
+                    resource: wgpu::BindingResource::TextureView(&scene_texture.view),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 1,
+                    resource: wgpu::BindingResource::Sampler(&scene_texture.sampler),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 2,
+                    resource: display_buffer.as_entire_binding(),
+                },
+            ],
+            label: Some("post_bind_group"),
+        });
+    }
+
+    pub fn resize(
+        &mut self,
+        device: &wgpu::Device,
+        memory: &MemoryTracker,
+        config: &wgpu::SurfaceConfiguration,
+    ) {
+        (self.scene_texture, self.msaa_texture) =
+            Self::create_targets(device, memory, config, self.sample_count);
+        self.bind_group = Self::create_bind_group(
+            device,
+            &self.bind_group_layout,
+            &self.scene_texture,
+            &self.display_buffer,
+        );
+    }
+
+    pub fn update(&mut self, queue: &wgpu::Queue, display: DisplaySettings) {
+        if self.display != display {
+            self.display = display;
+            queue.write_buffer(
+                &self.display_buffer,
+                0,
+                bytemuck::cast_slice(&[DisplayUniform::new(display, self.encode_srgb)]),
+            );
+        }
+    }
+
+    /// Draw the scene into `viewport` of the target, or all of it with `None`.
+    pub fn render(
+        &self,
+        encoder: &mut wgpu::CommandEncoder,
+        target: &wgpu::TextureView,
+        viewport: Option<ViewportRect>,
+    ) {
+        let mut render_pass = encoder.begin_render_pass(&wgpu::RenderPassDescriptor {
+            label: Some("Post Pass"),
+            color_attachments: &[Some(wgpu::RenderPassColorAttachment {
+                view: target,
+                resolve_target: None,
+                ops: wgpu::Operations {
+                    load: wgpu::LoadOp::Clear(wgpu::Color::BLACK),
+                    store: true,
+                },
+            })],
+            depth_stencil_attachment: None,
+        });
+        if let Some(rect) = viewport {
+            render_pass.set_viewport(rect.x, rect.y, rect.width, rect.height, 0.0, 1.0);
+        }
+        render_pass.set_pipeline(&self.pipeline);
+        render_pass.set_bind_group(0, &self.bind_group, &[]);
+        render_pass.draw(0..3, 0..1);
+    }
+}
@@ -0,0 +1,58 @@
+struct Display {
+    gamma: f32,
+    brightness: f32,
+    contrast: f32,
+    saturation: f32,
+    // 1 when the target isn't an sRGB format and won't encode on write
+    encode_srgb: u32,
+};
+@group(0) @binding(0)
+var t_scene: texture_2d<f32>;
+@group(0) @binding(1)
+var s_scene: sampler;
+@group(0) @binding(2)
+var<uniform> display: Display;
+
+struct VertexOutput {
+    @builtin(position) clip_position: vec4<f32>,
+    @location(0) tex_coord: vec2<f32>,
+};
+
+// Linear to sRGB transfer function, what sRGB formats apply on write
+fn linear_to_srgb(color: vec3<f32>) -> vec3<f32> {
+    let low = color * 12.92;
+    let high = 1.055 * pow(color, vec3<f32>(1.0 / 2.4)) - 0.055;
+    return select(high, low, color <= vec3<f32>(0.0031308));
+}
+
+// Fullscreen triangle, no vertex buffer needed
+@vertex
+fn vs_main(@builtin(vertex_index) index: u32) -> VertexOutput {
+    let x = f32(index & 1u) * 4.0 - 1.0;
+    let y = f32(index >> 1u) * 4.0 - 1.0;
+
+    var out: VertexOutput;
+    out.clip_position = vec4<f32>(x, y, 0.0, 1.0);
+    out.tex_coord = vec2<f32>((x + 1.0) * 0.5, (1.0 - y) * 0.5);
+    return out;
+}
+
+@fragment
+fn fs_main(input: VertexOutput) -> @location(0) vec4<f32> {
+    var color = max(textureSample(t_scene, s_scene, input.tex_coord).rgb, vec3<f32>(0.0));
+
+    // Brightness and contrast are adjusted in perceptual space so that
+    // the contrast pivot sits at middle grey
+    var perceptual = pow(color, vec3<f32>(1.0 / 2.2));
+    perceptual = (perceptual - 0.5) * display.contrast + 0.5 + display.brightness;
+    color = pow(max(perceptual, vec3<f32>(0.0)), vec3<f32>(2.2));
+
+    let luma = dot(color, vec3<f32>(0.2126, 0.7152, 0.0722));
+    color = mix(vec3<f32>(luma), color, display.saturation);
+
+    color = pow(max(color, vec3<f32>(0.0)), vec3<f32>(1.0 / display.gamma));
+    if (display.encode_srgb != 0u) {
+        color = linear_to_srgb(min(color, vec3<f32>(1.0)));
+    }
+    return vec4<f32>(color, 1.0);
+}
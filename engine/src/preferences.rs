@@ -0,0 +1,169 @@
+use std::path::{Path, PathBuf};
+
+use anyhow::*;
+use directories::ProjectDirs;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    input::ActionMap,
+    settings::{DisplaySettings, InputMode, KeyBindings, ScrollMode, Settings},
+};
+
+/// Options a user picks in an options menu, kept between runs. Written in
+/// RON, options missing from the file keep their defaults:
+///
+/// ```ron
+/// (
+///     vsync: false,
+///     msaa_samples: 4,
+///     look_sensitivity: 1.5,
+///     keys: (forward: (key: Z, alternate: Some(Up))),
+/// )
+/// ```
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Preferences {
+    pub vsync: bool,
+    /// Applies after a restart, pipelines are built for one sample count.
+    pub msaa_samples: u32,
+    /// Frame rate cap while focused, `None` for uncapped.
+    pub target_fps: Option<f32>,
+    pub display: DisplaySettings,
+    /// Size of the HUD and other overlay drawings on top of the DPI scale.
+    pub ui_scale: f32,
+    /// Multiplies the camera's mouse look sensitivity.
+    pub look_sensitivity: f32,
+    pub scroll_mode: ScrollMode,
+    pub keys: KeyBindings,
+    pub actions: ActionMap,
+    /// Mode at startup.
+    pub input_mode: InputMode,
+}
+
+impl Default for Preferences {
+    fn default() -> Self {
+        return Self::from_settings(&Settings::default());
+    }
+}
+
+impl Preferences {
+    /// The persisted part of `settings`.
+    pub fn from_settings(settings: &Settings) -> Self {
+        return Self {
+            vsync: settings.frame.vsync,
+            msaa_samples: settings.msaa_samples,
+            target_fps: settings.frame.target_fps,
+            display: settings.display,
+            ui_scale: settings.ui_scale,
+            look_sensitivity: settings.controller.look_sensitivity,
+            scroll_mode: settings.controller.scroll_mode,
+            keys: settings.controller.keys,
+            actions: settings.actions.clone(),
+            input_mode: settings.input_mode,
+        };
+    }
+
+    /// Write the preferences over the matching fields of `settings`.
+    pub fn apply(&self, settings: &mut Settings) {
+        settings.frame.vsync = self.vsync;
+        settings.msaa_samples = self.msaa_samples;
+        settings.frame.target_fps = self.target_fps;
+        settings.display = self.display;
+        settings.ui_scale = self.ui_scale;
+        settings.controller.look_sensitivity = self.look_sensitivity;
+        settings.controller.scroll_mode = self.scroll_mode;
+        settings.controller.keys = self.keys;
+        settings.actions = self.actions.clone();
+        settings.input_mode = self.input_mode;
+    }
+
+    pub fn parse(source: &str) -> Result<Self> {
+        return ron::from_str(source).context("Invalid preferences");
+    }
+}
+
+/// Called with the old and the new preferences after every change.
+pub type PreferencesHandler = Box<dyn FnMut(&Preferences, &Preferences)>;
+
+/// Loads preferences at startup, saves them on every change and tells
+/// whoever registered with `on_change` about it.
+#[derive(Default)]
+pub struct PreferenceStore {
+    // `None` keeps the preferences in memory only
+    path: Option<PathBuf>,
+    preferences: Preferences,
+    handlers: Vec<PreferencesHandler>,
+}
+
+impl PreferenceStore {
+    /// Preferences of `app` in the platform's per-user configuration
+    /// directory for it. Missing or unreadable files fall back to the defaults.
+    pub fn open(app: &str) -> Self {
+        let path = ProjectDirs::from("", "", app).map(|dirs| dirs.config_dir().join("preferences.ron"));
+        if path.is_none() {
+            log::warn!("No configuration directory, preferences won't be saved");
+        }
+        return Self::at(path);
+    }
+
+    pub fn at(path: Option<PathBuf>) -> Self {
+        let preferences = match &path {
+            Some(path) if path.exists() => Self::load(path).unwrap_or_else(|e| {
+                log::warn!("{:?}", e);
+                Preferences::default()
+            }),
+            _ => Preferences::default(),
+        };
+        return Self {
+            path,
+            preferences,
+            handlers: Vec::new(),
+        };
+    }
+
+    pub fn load(path: &Path) -> Result<Preferences> {
+        let source = std::fs::read_to_string(path).with_context(|| format!("Failed to read {:?}", path))?;
+        return Preferences::parse(&source).with_context(|| format!("Failed to load {:?}", path));
+    }
+
+    pub fn path(&self) -> Option<&Path> {
+        return self.path.as_deref();
+    }
+
+    pub fn get(&self) -> &Preferences {
+        return &self.preferences;
+    }
+
+    pub fn on_change<F: FnMut(&Preferences, &Preferences) + 'static>(&mut self, handler: F) {
+        self.handlers.push(Box::new(handler));
+    }
+
+    /// Change the preferences with `f`, then notify the handlers and save if
+    /// anything changed. Returns whether it did.
+    pub fn update<F: FnOnce(&mut Preferences)>(&mut self, f: F) -> Result<bool> {
+        let mut preferences = self.preferences.clone();
+        f(&mut preferences);
+        if preferences == self.preferences {
+            return Ok(false);
+        }
+        let old = std::mem::replace(&mut self.preferences, preferences);
+        for handler in &mut self.handlers {
+            handler(&old, &self.preferences);
+        }
+        self.save()?;
+        return Ok(true);
+    }
+
+    pub fn save(&self) -> Result<()> {
+        let path = match &self.path {
+            Some(path) => path,
+            None => return Ok(()),
+        };
+        if let Some(dir) = path.parent() {
+            std::fs::create_dir_all(dir).with_context(|| format!("Failed to create {:?}", dir))?;
+        }
+        let source = ron::ser::to_string_pretty(&self.preferences, ron::ser::PrettyConfig::default())
+            .context("Failed to serialize preferences")?;
+        return std::fs::write(path, source).with_context(|| format!("Failed to write {:?}", path));
+    }
+}
@@ -1,16 +1,143 @@
-use cgmath::{prelude::*, Deg};
+use std::{ops::Range, sync::Arc};
+
+use anyhow::Context;
+
+use cgmath::{prelude::*, Deg, Matrix4, Point3, Rad, Vector3};
 use itertools::Itertools;
-use wgpu::util::DeviceExt;
-use winit::{event::Event, window::Window};
+use winit::{
+    event::{ElementState, Event, KeyboardInput, ModifiersState, MouseButton, VirtualKeyCode, WindowEvent},
+    window::Window,
+};
 
 use crate::{
-    camera::{Camera, FPSCamera, Projection},
-    controller::Controller,
-    light::{LightBufferManager, LightKind, PointLight, BaseLight, SpotLight},
-    model::{DrawLight, DrawModel, Model},
-    resources::{load_model, Instance, InstanceRaw, ModelVertex, Vertex},
+    atmosphere::{Atmosphere, AtmosphereModel},
+    background::Background,
+    boids::{BoidsSettings, Flock},
+    camera::{Camera, CameraUniform, FPSCamera, Projection},
+    controller::ControllerEvent,
+    capture::{CaptureOutput, CaptureSettings, FrameCapture},
+    collision::{Colliders, MeshCollider},
+    console::Console,
+    flare::{FlareSource, LensFlare},
+    frame::{FrameBuffers, FramePacer},
+    gizmo::{GizmoMode, LightGizmo},
+    hud::{FrameStage, PerformanceHud},
+    input::{is_modifier, Action, Binding, BindingsPanel, GamepadButton, KeyChord},
+    inspector::{InstanceState, SceneInspector},
+    layers::RenderLayers,
+    decode::ImageDecoder,
+    depth_probe::{DepthProbe, DepthSample},
+    events::{EngineEvent, EventBus},
+    loading::{draw_loading_screen, AssetHandle, AssetLoader, LoadContext, LoadingState},
+    lod::Lods,
+    measure::{draw_bounds_size, MeasureTool},
+    luminance::{LuminanceHistogram, SceneLuminance},
+    motion::MotionKernel,
+    oit::WeightedBlendedOit,
+    overdraw::OverdrawEstimate,
+    overlay::{Overlay, UiScale},
+    particles::ParticleSystem,
+    point_cloud::{CloudPoint, PointCloudRenderer},
+    preferences::{PreferenceStore, Preferences},
+    picking::{polygon_contains, GpuPicker, IdDraw, IdImage, InstanceId, PickRect},
+    pipelines::{PipelineCache, PipelineDescriptor, PipelineId},
+    placement::{raycast, PlacementHit, PlacementTool},
+    portal::PortalGraph,
+    post::PostProcess,
+    scene::{Entity, PickEvent, Scene, SceneUniform},
+    settings::{DepthFormat, InputMode, Settings},
+    simulation::{SceneState, Simulation, Simulator},
+    shader::ShaderPreprocessor,
+    shadow::{ShadowAtlas, ShadowView, MAX_PCF_KERNEL, MAX_SHADOWS},
+    skinning::{AnimationInstanceRaw, BakedAnimations, Crowd, SkinVertex},
+    target::{RenderTargetCamera, TargetMaterial},
+    debug::DebugGroup,
+    environment::{Environment, EnvironmentMap, SkySettings},
+    material::{CullMode, MaterialLayout, MATERIAL_PARAMS_STRUCT},
+    lightmap::{BakeSettings, Lightmap, LightmapVertex},
+    math::{Aabb, Frustum, Plane, Ray},
+    light::{
+        LightBufferManager, PointLight, BaseLight, SpotLight, MAX_AMBIENT_LIGHTS,
+        MAX_AREA_LIGHTS, MAX_DIRECTIONAL_LIGHTS, MAX_POINT_LIGHTS, MAX_SPOT_LIGHTS,
+    },
+    memory::{MemoryBudget, MemoryCategory, MemoryReport, MemoryTracker, TrackedBuffer},
+    model::{DrawLight, DrawModel, Material, Mesh, Model, Submesh},
+    resources::{
+        load_material, load_model, poll_pending_textures, reload_missing_textures, Instance, InstanceFormat, ModelVertex,
+        Vertex,
+    },
     texture::Texture,
+    trail::TrailRenderer,
+    vat::{VatCrowd, VertexAnimation},
+    viewport::{Viewport, ViewportRect},
+    volumetric::VolumetricLighting,
 };
+#[cfg(feature = "virtual-texturing")]
+use crate::virtual_texture::{PageSource, VirtualTerrain, VirtualTextureSettings};
+#[cfg(feature = "xr")]
+use crate::xr::{XrFrame, XrSession};
+
+// Variants of a scene pipeline for each material cull mode
+#[derive(Debug, Copy, Clone)]
+struct CullPipelines {
+    back: PipelineId,
+    front: PipelineId,
+    double_sided: PipelineId,
+}
+
+impl CullPipelines {
+    // Variants of `desc`, which `back` was compiled from, each falling back
+    // to it until compiled
+    fn compile(
+        pipelines: &mut PipelineCache,
+        desc: &PipelineDescriptor,
+        double_sided_shader: &str,
+        back: PipelineId,
+    ) -> Self {
+        let front_label = format!("{} (Front Culled)", desc.label);
+        let front = pipelines.compile(
+            &PipelineDescriptor {
+                label: &front_label,
+                cull_mode: Some(wgpu::Face::Front),
+                ..*desc
+            },
+            Some(back),
+        );
+        let double_sided_label = format!("{} (Double-Sided)", desc.label);
+        let double_sided = pipelines.compile(
+            &PipelineDescriptor {
+                label: &double_sided_label,
+                shader: double_sided_shader,
+                cull_mode: None,
+                ..*desc
+            },
+            Some(back),
+        );
+        return Self {
+            back,
+            front,
+            double_sided,
+        };
+    }
+
+    fn get(&self, mode: CullMode) -> PipelineId {
+        return match mode {
+            CullMode::Back => self.back,
+            CullMode::Front => self.front,
+            CullMode::DoubleSided => self.double_sided,
+        };
+    }
+}
+
+// Order a level's draws by distance of their instance from `eye`, nearest
+// first, so early depth testing rejects the fragments they hide. Stable, so
+// later sorts by key keep the order within their groups
+fn sort_front_to_back_from<T>(eye: Vector3<f32>, draws: &mut [T], instance: impl Fn(&T) -> &Instance) {
+    draws.sort_by(|a, b| {
+        let distance = |draw: &T| (instance(draw).position - eye).magnitude2();
+        distance(a).total_cmp(&distance(b))
+    });
+}
 
 #[repr(C)]
 #[derive(Debug, Copy, Clone, bytemuck::Pod, bytemuck::Zeroable)]
@@ -22,29 +149,177 @@ pub struct LightUniform {
 }
 
 pub struct Renderer {
-    surface: wgpu::Surface,
+    // `None` for headless renderers
+    surface: Option<wgpu::Surface>,
     config: wgpu::SurfaceConfiguration,
-    device: wgpu::Device,
-    queue: wgpu::Queue,
+    // Present modes of the surface, vsync falls back to `Fifo` without it
+    present_modes: Vec<wgpu::PresentMode>,
+    // Window, scene area, render resolution and DPI scale, see `viewport`
+    viewport: Viewport,
+    // Area of the window the scene is shown in, all of it when `None`
+    viewport_rect: Option<ViewportRect>,
+    device: Arc<wgpu::Device>,
+    // Shared with asset loads on background threads
+    queue: Arc<wgpu::Queue>,
 
-    instance_buffer: wgpu::Buffer,
-    camera_buffer: wgpu::Buffer,
+    memory: MemoryTracker,
+    instance_buffers: FrameBuffers,
+    camera_buffers: FrameBuffers,
+    scene_buffers: FrameBuffers,
+    pacer: FramePacer,
 
     depth_texture: Texture,
+    post: PostProcess,
 
-    camera_bind_group: wgpu::BindGroup,
+    camera_bind_groups: Vec<wgpu::BindGroup>,
+    camera_bind_group_layout: wgpu::BindGroupLayout,
+    texture_bind_group_layout: Arc<wgpu::BindGroupLayout>,
+    material_layout: Arc<MaterialLayout>,
+    loader: AssetLoader,
+    decoder: ImageDecoder,
+    loading: LoadingState,
+    // Swapped into `obj_model` when loaded
+    pending_model: Option<AssetHandle<Model>>,
+    // Render target textures for materials of the model still loading
+    pending_target_textures: Vec<(usize, Texture)>,
 
-    render_pipeline: wgpu::RenderPipeline,
+    render_pipelines: CullPipelines,
+    ghost_pipeline: PipelineId,
+    outline_pipeline: PipelineId,
+    skinned_pipelines: CullPipelines,
+    vat_pipelines: CullPipelines,
+    lightmap_pipelines: CullPipelines,
+    shadow_pipelines: CullPipelines,
+    // Transparent materials, not drawn until ready
+    oit_pipelines: CullPipelines,
+    crowd_bind_group_layout: wgpu::BindGroupLayout,
+    vat_bind_group_layout: wgpu::BindGroupLayout,
+    lightmap_bind_group_layout: wgpu::BindGroupLayout,
     //light_render_pipeline: wgpu::RenderPipeline,
     pub size: winit::dpi::PhysicalSize<u32>,
-    pub instances: Vec<Instance>,
+    // Latest size asked for since the last reconfigure, see `apply_resize`
+    pending_size: Option<winit::dpi::PhysicalSize<u32>>,
+    minimized: bool,
+    instances: Vec<Instance>,
+    instances_version: u64,
+    // Instances matching `instance_layers` that were uploaded, the ghost follows them
+    visible_instances: u32,
+    // Uploaded instances of each LOD level, together `0..visible_instances`
+    lod_ranges: Vec<Range<u32>>,
+    // Index in `instances` of each uploaded instance
+    instance_slots: Vec<u32>,
+    // Outlined instances at the start of each LOD level's range
+    outline_ranges: Vec<Range<u32>>,
+    // Instances culled by portals of each LOD level, uploaded after the
+    // ghost so they still cast shadows
+    hidden_ranges: Vec<Range<u32>>,
+    // Camera position the LOD levels were selected and sorted from
+    lod_eye: Point3<f32>,
+    // Whether the uploaded levels are sorted front to back
+    sorted_front_to_back: bool,
+    // Of the uploaded instances from `lod_eye`
+    overdraw: OverdrawEstimate,
+    // World bounds of the instances matching `instance_layers`, culled or not
+    instance_bounds: Option<Aabb>,
+    instance_layers: RenderLayers,
+    frame_count: u64,
+    elapsed: std::time::Duration,
+    // Render-side copy, its pose follows the simulation's camera
     pub camera: FPSCamera,
+    camera_moving: bool,
+    input_mode: InputMode,
+    // Camera control and other per-step updates, possibly on an update thread
+    pub simulator: Simulator,
+    camera_uniform: CameraUniform,
+    clip_plane: Option<Plane>,
     pub obj_model: Model,
+    lods: Lods,
+    // Immovable geometry merged in world space, drawn with `static_instance`
+    static_batch: Option<Model>,
+    static_instance: TrackedBuffer,
+    // Baked light of the static batch
+    lightmap: Option<Lightmap>,
+    // Culls instances in rooms hidden from the camera's room when set
+    portals: Option<PortalGraph>,
+    pub crowds: Vec<Crowd>,
+    pub vat_crowds: Vec<VatCrowd>,
+    // Drawn before the main pass in order, into their materials' diffuse maps
+    render_targets: Vec<RenderTargetCamera>,
     pub light_manager: LightBufferManager,
+    environment: Environment,
+    atmosphere: Atmosphere,
+    // Emit debug groups/markers for GPU debuggers
+    pub debug_labels: bool,
+    pub settings: Settings,
+    depth_format: DepthFormat,
+    model_instance_format: InstanceFormat,
+    crowd_instance_format: InstanceFormat,
+    // Moves crowd instances with `InstanceMotion`, `None` without compute support
+    motion_kernel: Option<MotionKernel>,
+    pub placement: PlacementTool,
+    pub measure: MeasureTool,
+    /// Collision shapes of `instances`, see `Colliders`.
+    pub colliders: Colliders,
+    pub light_gizmo: LightGizmo,
+    picker: GpuPicker,
+    // In memory until `set_preference_store`
+    preferences: PreferenceStore,
+    /// Names, tags and event handlers for demo logic.
+    pub scene: Scene,
+    /// Resizes, reloads, spawns and light changes for subsystems and user
+    /// code to subscribe to.
+    pub events: EventBus,
+    background: Background,
+    pub particles: ParticleSystem,
+    pub trails: TrailRenderer,
+    oit: WeightedBlendedOit,
+    pub point_clouds: PointCloudRenderer,
+    /// Virtually textured terrain, see `set_virtual_terrain`.
+    #[cfg(feature = "virtual-texturing")]
+    pub virtual_terrain: Option<VirtualTerrain>,
+    pub luminance: LuminanceHistogram,
+    pub overlay: Overlay,
+    pub flare: LensFlare,
+    depth_probe: DepthProbe,
+    // Camera the depth buffer was last drawn from
+    depth_inv_view_proj: Matrix4<f32>,
+    // Window position and camera of the depth readback in flight
+    depth_request: Option<([f32; 2], Matrix4<f32>)>,
+    pub volumetric: VolumetricLighting,
+    pub hud: PerformanceHud,
+    /// Environment cube map unwrapped in the bottom right corner of the
+    /// overlay, F4 cycles through the maps.
+    pub environment_view: Option<EnvironmentMap>,
+    /// Draw the frustums of shadow maps and render target cameras through
+    /// the overlay, toggled with F2.
+    pub show_frustums: bool,
+    /// Actions and their bindings, see `Action::ToggleBindings`.
+    pub bindings_panel: BindingsPanel,
+    pub console: Console,
+    /// Draw the `SceneInspector` summary below the bindings panel.
+    pub show_inspector: bool,
+    // Held modifier keys, for key chords
+    modifiers: ModifiersState,
+    // Render target camera viewed through instead of the main camera, see
+    // `Action::CycleCamera`
+    view_target: Option<usize>,
+    /// Scene pipelines, compiled in the background after startup.
+    pub pipelines: PipelineCache,
+    capture: Option<FrameCapture>,
+    // Headset frame being rendered, and each eye's camera of the last one
+    #[cfg(feature = "xr")]
+    xr_frame: Option<XrFrame>,
+    #[cfg(feature = "xr")]
+    xr_eyes: [CameraUniform; 2],
+    shaders: ShaderPreprocessor,
 }
 
 impl Renderer {
     pub async fn new(window: &Window) -> Self {
+        return Self::with_settings(window, Settings::default()).await;
+    }
+
+    pub async fn with_settings(window: &Window, settings: Settings) -> Self {
         let size = window.inner_size();
 
         let instance = wgpu::Instance::new(wgpu::Backends::all());
@@ -59,11 +334,70 @@ impl Renderer {
             })
             .await
             .unwrap();
+        let format = surface.get_supported_formats(&adapter)[0];
+
+        let mut renderer = Self::from_adapter(adapter, Some(surface), format, size, settings).await;
+        renderer.viewport.scale_factor = window.scale_factor();
+        return renderer;
+    }
+
+    /// Renderer without a window, drawing `width` by `height` images with
+    /// `render_image`, e.g. for tests. Fails without a GPU adapter.
+    pub async fn headless(width: u32, height: u32, settings: Settings) -> anyhow::Result<Self> {
+        let adapter = Self::headless_adapter()
+            .await
+            .ok_or_else(|| anyhow::anyhow!("No GPU adapter for headless rendering"))?;
+        let size = winit::dpi::PhysicalSize::new(width.max(1), height.max(1));
+        let format = wgpu::TextureFormat::Rgba8UnormSrgb;
+
+        return Ok(Self::from_adapter(adapter, None, format, size, settings).await);
+    }
+
+    // Adapter of a primary backend, GLSL can't express every scene shader
+    pub(crate) async fn headless_adapter() -> Option<wgpu::Adapter> {
+        let instance = wgpu::Instance::new(wgpu::Backends::PRIMARY);
+        return instance
+            .request_adapter(&wgpu::RequestAdapterOptions {
+                power_preference: wgpu::PowerPreference::default(),
+                compatible_surface: None,
+                force_fallback_adapter: false,
+            })
+            .await;
+    }
+
+    async fn from_adapter(
+        adapter: wgpu::Adapter,
+        surface: Option<wgpu::Surface>,
+        format: wgpu::TextureFormat,
+        size: winit::dpi::PhysicalSize<u32>,
+        mut settings: Settings,
+    ) -> Self {
+        let sample_count = settings.msaa_samples.max(1);
+        let depth_format = if settings.depth_format.is_supported(&adapter, sample_count) {
+            settings.depth_format
+        } else {
+            log::warn!(
+                "Depth format {:?} is not supported, using {:?}",
+                settings.depth_format,
+                DepthFormat::Depth32Float
+            );
+            DepthFormat::Depth32Float
+        };
+        settings.depth_format = depth_format;
+        let compression = wgpu::Features::TEXTURE_COMPRESSION_BC;
+        if settings.decode.compress && !adapter.features().contains(compression) {
+            log::info!("BC textures are not supported, textures stay uncompressed");
+            settings.decode.compress = false;
+        }
+        let mut features = depth_format.required_features();
+        if settings.decode.compress {
+            features |= compression;
+        }
 
         let (device, queue) = adapter
             .request_device(
                 &wgpu::DeviceDescriptor {
-                    features: wgpu::Features::empty(),
+                    features,
                     limits: wgpu::Limits::default(),
                     label: None,
                 },
@@ -71,20 +405,71 @@ impl Renderer {
             )
             .await
             .expect("Failed to create device and/or queue");
+        let device = Arc::new(device);
+        let queue = Arc::new(queue);
 
+        let present_modes = surface
+            .as_ref()
+            .map_or_else(Vec::new, |surface| surface.get_supported_modes(&adapter));
         let config = wgpu::SurfaceConfiguration {
             usage: wgpu::TextureUsages::RENDER_ATTACHMENT,
-            format: surface.get_supported_formats(&adapter)[0],
+            format,
             width: size.width,
             height: size.height,
-            present_mode: wgpu::PresentMode::Fifo,
+            present_mode: Self::present_mode(settings.frame.vsync, &present_modes),
+        };
+        if let Some(surface) = &surface {
+            surface.configure(&device, &config);
+        }
+
+        let memory = MemoryTracker::new();
+
+        // ====================== Shader Variants ======================
+        let mut shaders = ShaderPreprocessor::new();
+        shaders.set_constant("MAX_AMBIENT_LIGHTS", MAX_AMBIENT_LIGHTS);
+        shaders.set_constant("MAX_DIRECTIONAL_LIGHTS", MAX_DIRECTIONAL_LIGHTS);
+        shaders.set_constant("MAX_POINT_LIGHTS", MAX_POINT_LIGHTS);
+        shaders.set_constant("MAX_SPOT_LIGHTS", MAX_SPOT_LIGHTS);
+        shaders.set_constant("MAX_AREA_LIGHTS", MAX_AREA_LIGHTS);
+        shaders.set_constant("MAX_SHADOWS", MAX_SHADOWS);
+        shaders.set_constant("MAX_PCF_KERNEL", MAX_PCF_KERNEL);
+        shaders.set_constant("FOG_MODE", settings.atmosphere.fog_mode.shader_constant());
+        shaders.enable("NORMAL_MAPPING");
+        if sample_count > 1 {
+            shaders.enable("ALPHA_TO_COVERAGE");
+        }
+        let model_instance_format = settings.model_instance_format;
+        let crowd_instance_format = settings.crowd_instance_format;
+        let basic_shader = {
+            let mut model_shaders = shaders.clone();
+            if let Some(define) = model_instance_format.shader_define() {
+                model_shaders.enable(define);
+            }
+            model_shaders
+                .process("basic.wgsl")
+                .expect("Failed to preprocess basic.wgsl")
         };
-        surface.configure(&device, &config);
+        let outline_shader = {
+            let mut outline_shaders = shaders.clone();
+            outline_shaders.enable("OUTLINE");
+            if let Some(define) = model_instance_format.shader_define() {
+                outline_shaders.enable(define);
+            }
+            outline_shaders
+                .process("basic.wgsl")
+                .expect("Failed to preprocess outline basic.wgsl")
+        };
+        // =============================================================
 
         // ====================== Create lights ======================
+        let mut environment =
+            Environment::from_sky(&device, &memory, &queue, &shaders, &SkySettings::default());
+        // Keep the scene's own lights dominant
+        environment.set_intensity(&queue, 0.3);
         const NUM_LIGHTS_PER_ROW: u32 = 10;
         const SPACE_BETWEEN_LIGHTS: f32 = 5.0;
-        let mut light_manager = LightBufferManager::new(&device);
+        let atmosphere = Atmosphere::new(&device, &memory, &queue, AtmosphereModel::default());
+        let mut light_manager = LightBufferManager::new(&device, &memory, &environment, &atmosphere);
         for z in 0..NUM_LIGHTS_PER_ROW {
             for x in 0..NUM_LIGHTS_PER_ROW {
                 let idx = z * NUM_LIGHTS_PER_ROW + x;
@@ -98,11 +483,10 @@ impl Renderer {
                     1 => [0.0, 1.0, 0.0],
                     _ => [0.0, 0.0, 1.0],
                 };
-                light_manager.update_light_buffer(
+                light_manager.set_light(
                     &queue,
-                    LightKind::Spot,
-                    (idx as u32) as usize,
-                    &SpotLight::new(light_color, light_position, [0.0, -1.0, 0.0], Deg(45.0), 0.1, 0.1, 0.1),
+                    idx as usize,
+                    SpotLight::new(light_color, light_position, [0.0, -1.0, 0.0], Deg(45.0), 0.1, 0.1, 0.1).into(),
                 );
                 light_manager.spot_count += 1;
             }
@@ -113,7 +497,7 @@ impl Renderer {
         // ====================== Create Instances ======================
         const NUM_INSTANCES_PER_ROW: u32 = 20;
         const SPACE_BETWEEN: f32 = 2.0;
-        let mut instances = (0..NUM_INSTANCES_PER_ROW)
+        let instances = (0..NUM_INSTANCES_PER_ROW)
             .flat_map(|z| {
                 (0..NUM_INSTANCES_PER_ROW).map(move |x| {
                     let x = SPACE_BETWEEN * (x as f32 - NUM_INSTANCES_PER_ROW as f32 / 2.0);
@@ -134,15 +518,21 @@ impl Renderer {
                         cgmath::Deg(0.0),
                     );
 
-                    Instance { position, rotation }
+                    Instance {
+                        position,
+                        rotation,
+                        scale: cgmath::Vector3::new(1.0, 1.0, 1.0),
+                        layers: RenderLayers::DEFAULT,
+                        outline: false,
+                    }
                 })
             })
             .collect::<Vec<_>>();
-        let instance_data = instances.iter().map(Instance::to_raw).collect_vec();
+        let instance_data = model_instance_format.encode(&instances);
         // ==============================================================
 
         // ====================== Create Camera ======================
-        let camera = FPSCamera::new(
+        let mut camera = FPSCamera::new(
             (0.0, 10.0, 20.0),
             Deg(-90.0),
             Deg(-20.0),
@@ -150,26 +540,74 @@ impl Renderer {
             4.0,
             0.4,
         );
+        camera.controls = settings.controller;
+        let mut simulator = Simulator::new(Simulation::new(camera.clone()));
+        if let Some(policy) = settings.update_thread {
+            if let Err(e) = simulator.detach(policy) {
+                log::error!("{:?}", e);
+            }
+        }
         // ==========================================================
 
         // Create textures
-        let depth_texture = Texture::create_depth_texture(&device, &config, "depth_texture");
+        let depth_texture = Texture::create_depth_texture(
+            &device,
+            &memory,
+            &config,
+            depth_format.format(),
+            sample_count,
+            "depth_texture",
+        );
+        let mut pipelines = PipelineCache::new(device.clone());
+        let post = PostProcess::new(&device, &memory, &mut pipelines, &config, sample_count, settings.display);
 
         // Create buffers
-        let instance_buffer = device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
-            label: Some("Instance Buffer"),
-            contents: bytemuck::cast_slice(&instance_data),
-            usage: wgpu::BufferUsages::VERTEX,
-        });
-        let camera_buffer = device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
-            label: Some("Camera Buffer"),
-            contents: bytemuck::cast_slice(&[camera.uniform()]),
-            usage: wgpu::BufferUsages::UNIFORM | wgpu::BufferUsages::COPY_DST,
-        });
+        let instance_buffers = FrameBuffers::new(
+            &device,
+            &memory,
+            "Instance Buffer",
+            wgpu::BufferUsages::VERTEX,
+            MemoryCategory::Mesh,
+            bytemuck::cast_slice(&instance_data),
+        );
+        // Static batches are already in world space
+        let identity = Instance {
+            position: cgmath::Vector3::new(0.0, 0.0, 0.0),
+            rotation: cgmath::Quaternion::one(),
+            scale: cgmath::Vector3::new(1.0, 1.0, 1.0),
+            layers: RenderLayers::ALL,
+            outline: false,
+        };
+        let static_instance = memory.create_buffer_init(
+            &device,
+            &wgpu::util::BufferInitDescriptor {
+                label: Some("Static Batch Instance Buffer"),
+                contents: &model_instance_format.encode([&identity]),
+                usage: wgpu::BufferUsages::VERTEX,
+            },
+            MemoryCategory::Mesh,
+        );
+        let camera_buffers = FrameBuffers::new(
+            &device,
+            &memory,
+            "Camera Buffer",
+            wgpu::BufferUsages::UNIFORM,
+            MemoryCategory::Uniform,
+            bytemuck::cast_slice(&[camera.uniform()]),
+        );
+        let scene_uniform = SceneUniform::new(0.0, 0.0, [config.width, config.height], &settings.atmosphere, None);
+        let scene_buffers = FrameBuffers::new(
+            &device,
+            &memory,
+            "Scene Buffer",
+            wgpu::BufferUsages::UNIFORM,
+            MemoryCategory::Uniform,
+            bytemuck::cast_slice(&[scene_uniform]),
+        );
 
         // Create bind groups
         let texture_bind_group_layout =
-            device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
+            Arc::new(device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
                 entries: &[
                     wgpu::BindGroupLayoutEntry {
                         binding: 0,
@@ -203,65 +641,490 @@ impl Renderer {
                         ty: wgpu::BindingType::Sampler(wgpu::SamplerBindingType::Filtering),
                         count: None,
                     },
+                    // Material parameters, outlines read their width when extruding
+                    wgpu::BindGroupLayoutEntry {
+                        binding: 4,
+                        visibility: wgpu::ShaderStages::VERTEX | wgpu::ShaderStages::FRAGMENT,
+                        ty: wgpu::BindingType::Buffer {
+                            ty: wgpu::BufferBindingType::Uniform,
+                            has_dynamic_offset: false,
+                            min_binding_size: None,
+                        },
+                        count: None,
+                    },
                 ],
                 label: Some("texture_bind_group_layout"),
-            });
+            }));
 
         let camera_bind_group_layout =
+            device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
+                entries: &[
+                    wgpu::BindGroupLayoutEntry {
+                        binding: 0,
+                        visibility: wgpu::ShaderStages::VERTEX | wgpu::ShaderStages::FRAGMENT,
+                        ty: wgpu::BindingType::Buffer {
+                            ty: wgpu::BufferBindingType::Uniform,
+                            has_dynamic_offset: false,
+                            min_binding_size: None,
+                        },
+                        count: None,
+                    },
+                    // Scene uniform, available to every pipeline binding the camera
+                    wgpu::BindGroupLayoutEntry {
+                        binding: 1,
+                        visibility: wgpu::ShaderStages::VERTEX | wgpu::ShaderStages::FRAGMENT,
+                        ty: wgpu::BindingType::Buffer {
+                            ty: wgpu::BufferBindingType::Uniform,
+                            has_dynamic_offset: false,
+                            min_binding_size: None,
+                        },
+                        count: None,
+                    },
+                ],
+                label: Some("camera_bind_group_layout"),
+            });
+        let camera_bind_groups = camera_buffers
+            .buffers()
+            .iter()
+            .zip(scene_buffers.buffers())
+            .map(|(camera_buffer, scene_buffer)| {
+                device.create_bind_group(&wgpu::BindGroupDescriptor {
+                    layout: &camera_bind_group_layout,
+                    entries: &[
+                        wgpu::BindGroupEntry {
+                            binding: 0,
+                            resource: camera_buffer.as_entire_binding(),
+                        },
+                        wgpu::BindGroupEntry {
+                            binding: 1,
+                            resource: scene_buffer.as_entire_binding(),
+                        },
+                    ],
+                    label: Some("camera_bind_group"),
+                })
+            })
+            .collect_vec();
+
+        // ====================== Create Models ======================
+        let material_layout = Arc::new(
+            MaterialLayout::reflect(&basic_shader, MATERIAL_PARAMS_STRUCT)
+                .expect("Failed to reflect material parameters"),
+        );
+        // Loaded in the background, instances draw nothing until then and
+        // materials show placeholders until their textures are decoded
+        let decoder = ImageDecoder::new(settings.decode);
+        let mut loader = AssetLoader::new(LoadContext {
+            device: device.clone(),
+            queue: queue.clone(),
+            memory: memory.clone(),
+            decoder: decoder.queue().clone(),
+        });
+        let pending_model = Some(Self::spawn_model_load(
+            &mut loader,
+            "cube.obj",
+            &texture_bind_group_layout,
+            &material_layout,
+        ));
+        let obj_model = Model {
+            meshes: Vec::new(),
+            materials: Vec::new(),
+        };
+        // ===========================================================
+
+        // Create pipelines
+        let multisample = wgpu::MultisampleState {
+            count: sample_count,
+            mask: !0,
+            alpha_to_coverage_enabled: sample_count > 1,
+        };
+        let crowd_bind_group_layout =
             device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
                 entries: &[wgpu::BindGroupLayoutEntry {
                     binding: 0,
-                    visibility: wgpu::ShaderStages::VERTEX | wgpu::ShaderStages::FRAGMENT,
+                    visibility: wgpu::ShaderStages::VERTEX,
                     ty: wgpu::BindingType::Buffer {
-                        ty: wgpu::BufferBindingType::Uniform,
+                        ty: wgpu::BufferBindingType::Storage { read_only: true },
                         has_dynamic_offset: false,
                         min_binding_size: None,
                     },
                     count: None,
                 }],
-                label: Some("camera_bind_group_layout"),
+                label: Some("crowd_bind_group_layout"),
             });
-        let camera_bind_group = device.create_bind_group(&wgpu::BindGroupDescriptor {
-            layout: &camera_bind_group_layout,
-            entries: &[wgpu::BindGroupEntry {
-                binding: 0,
-                resource: camera_buffer.as_entire_binding(),
-            }],
-            label: Some("camera_bind_group"),
+        // Scene pipelines compile on a worker thread, flat shaded fallbacks
+        // compiled up front stand in until they are ready
+        let depth = depth_format.format();
+        let model_bind_groups = [
+            texture_bind_group_layout.as_ref(),
+            &camera_bind_group_layout,
+            &light_manager.light_bind_group_layout,
+        ];
+        let crowd_bind_groups = [
+            texture_bind_group_layout.as_ref(),
+            &camera_bind_group_layout,
+            &light_manager.light_bind_group_layout,
+            &crowd_bind_group_layout,
+        ];
+        let vat_bind_group_layout = VatCrowd::bind_group_layout(&device);
+        let vat_bind_groups = [
+            texture_bind_group_layout.as_ref(),
+            &camera_bind_group_layout,
+            &light_manager.light_bind_group_layout,
+            &vat_bind_group_layout,
+        ];
+        let lightmap_bind_group_layout = Lightmap::bind_group_layout(&device);
+        let lightmap_bind_groups = [
+            texture_bind_group_layout.as_ref(),
+            &camera_bind_group_layout,
+            &light_manager.light_bind_group_layout,
+            &lightmap_bind_group_layout,
+        ];
+        let model_layouts = [ModelVertex::desc(), model_instance_format.desc()];
+        let lightmap_layouts = [
+            ModelVertex::desc(),
+            model_instance_format.desc(),
+            LightmapVertex::desc(),
+        ];
+        let crowd_layouts = [
+            ModelVertex::desc(),
+            crowd_instance_format.desc(),
+            SkinVertex::desc(),
+            AnimationInstanceRaw::desc(),
+        ];
+        let vat_layouts = [
+            ModelVertex::desc(),
+            crowd_instance_format.desc(),
+            AnimationInstanceRaw::desc(),
+        ];
+        let fallback_shader = |instance_format: InstanceFormat| {
+            let mut shaders = shaders.clone();
+            if let Some(define) = instance_format.shader_define() {
+                shaders.enable(define);
+            }
+            shaders
+                .process("fallback.wgsl")
+                .expect("Failed to preprocess fallback.wgsl")
+        };
+        let skinned_shader = {
+            let mut skinned_shaders = shaders.clone();
+            skinned_shaders.enable("SKINNING");
+            if let Some(define) = crowd_instance_format.shader_define() {
+                skinned_shaders.enable(define);
+            }
+            skinned_shaders
+                .process("basic.wgsl")
+                .expect("Failed to preprocess skinned basic.wgsl")
+        };
+        let vat_shader = {
+            let mut vat_shaders = shaders.clone();
+            vat_shaders.enable("VERTEX_ANIMATION");
+            if let Some(define) = crowd_instance_format.shader_define() {
+                vat_shaders.enable(define);
+            }
+            vat_shaders
+                .process("basic.wgsl")
+                .expect("Failed to preprocess vertex animated basic.wgsl")
+        };
+
+        let model_pipeline = PipelineDescriptor {
+            label: "Render Pipeline",
+            layout: "Model Pipeline Layout",
+            bind_group_layouts: &model_bind_groups,
+            shader: &basic_shader,
+            vertex_layouts: &model_layouts,
+            color_format: Some(PostProcess::SCENE_FORMAT),
+            depth_format: Some(depth),
+            blend: wgpu::BlendState::REPLACE,
+            cull_mode: Some(wgpu::Face::Back),
+            multisample,
+            order_independent: false,
+        };
+        let crowd_pipeline = PipelineDescriptor {
+            label: "Skinned Pipeline",
+            layout: "Skinned Pipeline Layout",
+            bind_group_layouts: &crowd_bind_groups,
+            shader: &skinned_shader,
+            vertex_layouts: &crowd_layouts,
+            ..model_pipeline
+        };
+        let lightmap_shader = {
+            let mut lightmap_shaders = shaders.clone();
+            lightmap_shaders.enable("LIGHTMAP");
+            if let Some(define) = model_instance_format.shader_define() {
+                lightmap_shaders.enable(define);
+            }
+            lightmap_shaders
+                .process("basic.wgsl")
+                .expect("Failed to preprocess lightmapped basic.wgsl")
+        };
+        let lightmap_pipeline = PipelineDescriptor {
+            label: "Lightmap Pipeline",
+            layout: "Lightmap Pipeline Layout",
+            bind_group_layouts: &lightmap_bind_groups,
+            shader: &lightmap_shader,
+            vertex_layouts: &lightmap_layouts,
+            ..model_pipeline
+        };
+        let vat_pipeline = PipelineDescriptor {
+            label: "Vertex Animation Pipeline",
+            layout: "Vertex Animation Pipeline Layout",
+            bind_group_layouts: &vat_bind_groups,
+            shader: &vat_shader,
+            vertex_layouts: &vat_layouts,
+            ..model_pipeline
+        };
+
+        let model_fallback = pipelines.create(&PipelineDescriptor {
+            label: "Fallback Pipeline",
+            shader: &fallback_shader(model_instance_format),
+            ..model_pipeline
+        });
+        // Skinning streams are bound but unused, crowds stand in their bind pose
+        let crowd_fallback = pipelines.create(&PipelineDescriptor {
+            label: "Skinned Fallback Pipeline",
+            shader: &fallback_shader(crowd_instance_format),
+            ..crowd_pipeline
+        });
+        // Basic shader with back faces lit as seen, for double-sided materials
+        let double_sided_shader = |defines: &[Option<&str>]| {
+            let mut double_sided_shaders = shaders.clone();
+            double_sided_shaders.enable("DOUBLE_SIDED");
+            for define in defines.iter().flatten() {
+                double_sided_shaders.enable(define);
+            }
+            double_sided_shaders
+                .process("basic.wgsl")
+                .expect("Failed to preprocess double-sided basic.wgsl")
+        };
+        let render_pipeline = pipelines.compile(&model_pipeline, Some(model_fallback));
+        let render_pipelines = CullPipelines::compile(
+            &mut pipelines,
+            &model_pipeline,
+            &double_sided_shader(&[model_instance_format.shader_define()]),
+            render_pipeline,
+        );
+        let skinned_pipeline = pipelines.compile(&crowd_pipeline, Some(crowd_fallback));
+        let skinned_pipelines = CullPipelines::compile(
+            &mut pipelines,
+            &crowd_pipeline,
+            &double_sided_shader(&[Some("SKINNING"), crowd_instance_format.shader_define()]),
+            skinned_pipeline,
+        );
+        // Baked frames are bound but unused, the rest pose stands in
+        let vat_fallback = pipelines.create(&PipelineDescriptor {
+            label: "Vertex Animation Fallback Pipeline",
+            shader: &fallback_shader(crowd_instance_format),
+            ..vat_pipeline
         });
+        let vat_base = pipelines.compile(&vat_pipeline, Some(vat_fallback));
+        let vat_pipelines = CullPipelines::compile(
+            &mut pipelines,
+            &vat_pipeline,
+            &double_sided_shader(&[Some("VERTEX_ANIMATION"), crowd_instance_format.shader_define()]),
+            vat_base,
+        );
 
-        // ====================== Create Models ======================
-        let obj_model = load_model("cube.obj", &device, &queue, &texture_bind_group_layout)
-            .await
-            .unwrap();
-        // ===========================================================
+        // Lightmaps are bound but unused, dynamic lights stand in
+        let lightmap_fallback = pipelines.create(&PipelineDescriptor {
+            label: "Lightmap Fallback Pipeline",
+            shader: &fallback_shader(model_instance_format),
+            ..lightmap_pipeline
+        });
+        let lightmap_base = pipelines.compile(&lightmap_pipeline, Some(lightmap_fallback));
+        let lightmap_pipelines = CullPipelines::compile(
+            &mut pipelines,
+            &lightmap_pipeline,
+            &double_sided_shader(&[Some("LIGHTMAP"), model_instance_format.shader_define()]),
+            lightmap_base,
+        );
 
-        // Create pipelines
-        let render_pipeline = {
-            let shader = wgpu::ShaderModuleDescriptor {
-                label: Some("Basic Shader"),
-                source: wgpu::ShaderSource::Wgsl(include_str!("basic.wgsl").into()),
+        // Same shading as the scene, blended by the pass blend constant. Not
+        // drawn until ready, an opaque fallback would hide what's behind it
+        let ghost_pipeline = {
+            let blend = wgpu::BlendComponent {
+                src_factor: wgpu::BlendFactor::Constant,
+                dst_factor: wgpu::BlendFactor::OneMinusConstant,
+                operation: wgpu::BlendOperation::Add,
             };
-            let layout = device.create_pipeline_layout(&wgpu::PipelineLayoutDescriptor {
-                label: Some("Render Pipeline Layout"),
-                bind_group_layouts: &[
-                    &texture_bind_group_layout,
-                    &camera_bind_group_layout,
-                    &light_manager.light_bind_group_layout,
-                ],
-                push_constant_ranges: &[],
-            });
-            create_render_pipeline(
-                "Render Pipeline",
-                &device,
-                &layout,
-                config.format,
-                Some(Texture::DEPTH_FORMAT),
-                &[ModelVertex::desc(), InstanceRaw::desc()],
-                shader,
+            pipelines.compile(
+                &PipelineDescriptor {
+                    label: "Ghost Pipeline",
+                    blend: wgpu::BlendState {
+                        color: blend,
+                        alpha: blend,
+                    },
+                    ..model_pipeline
+                },
+                None,
             )
         };
 
+        // Inverted hull, back faces pushed out along their normals. Not drawn
+        // until ready, instances just go without outlines meanwhile
+        let outline_pipeline = pipelines.compile(
+            &PipelineDescriptor {
+                label: "Outline Pipeline",
+                shader: &outline_shader,
+                cull_mode: Some(wgpu::Face::Front),
+                ..model_pipeline
+            },
+            None,
+        );
+
+        // Depth only, lights stay unshadowed until it's ready
+        let shadow_pipelines = {
+            let mut shadow_shaders = shaders.clone();
+            if let Some(define) = model_instance_format.shader_define() {
+                shadow_shaders.enable(define);
+            }
+            let shader = shadow_shaders
+                .process("shadow.wgsl")
+                .expect("Failed to preprocess shadow.wgsl");
+            let desc = PipelineDescriptor {
+                label: "Shadow Pipeline",
+                layout: "Shadow Pipeline Layout",
+                bind_group_layouts: &[&light_manager.shadows.pass_bind_group_layout],
+                shader: &shader,
+                vertex_layouts: &model_layouts,
+                color_format: None,
+                depth_format: Some(ShadowAtlas::FORMAT),
+                blend: wgpu::BlendState::REPLACE,
+                cull_mode: Some(wgpu::Face::Back),
+                multisample: wgpu::MultisampleState::default(),
+                order_independent: false,
+            };
+            let base = pipelines.compile(&desc, None);
+            CullPipelines::compile(&mut pipelines, &desc, &shader, base)
+        };
+
+        // Transparent materials into the OIT targets. Not drawn until ready,
+        // an opaque fallback would hide what's behind them
+        let oit_pipelines = {
+            let mut oit_shaders = shaders.clone();
+            oit_shaders.enable("ORDER_INDEPENDENT");
+            if let Some(define) = model_instance_format.shader_define() {
+                oit_shaders.enable(define);
+            }
+            let shader = oit_shaders
+                .process("basic.wgsl")
+                .expect("Failed to preprocess order independent basic.wgsl");
+            let desc = PipelineDescriptor {
+                label: "OIT Pipeline",
+                shader: &shader,
+                multisample: wgpu::MultisampleState {
+                    alpha_to_coverage_enabled: false,
+                    ..multisample
+                },
+                order_independent: true,
+                ..model_pipeline
+            };
+            let base = pipelines.compile(&desc, None);
+            let double_sided = double_sided_shader(&[Some("ORDER_INDEPENDENT"), model_instance_format.shader_define()]);
+            CullPipelines::compile(&mut pipelines, &desc, &double_sided, base)
+        };
+        let oit = WeightedBlendedOit::new(
+            &device,
+            &memory,
+            &mut pipelines,
+            &shaders,
+            config.width,
+            config.height,
+            sample_count,
+        );
+
+        let light_gizmo = LightGizmo::new(
+            &device,
+            &memory,
+            &mut pipelines,
+            &shaders,
+            &camera_bind_group_layout,
+            depth_format.format(),
+            multisample,
+        );
+        let picker = GpuPicker::new(&mut pipelines, &shaders, &camera_bind_group_layout, model_instance_format);
+        let background = Background::new(
+            &device,
+            &memory,
+            &queue,
+            &mut pipelines,
+            &shaders,
+            &camera_bind_group_layout,
+            &light_manager.light_bind_group_layout,
+            depth_format.format(),
+            multisample,
+            settings.background,
+        );
+        let particles = ParticleSystem::new(
+            &device,
+            &memory,
+            &mut pipelines,
+            &shaders,
+            &camera_bind_group_layout,
+            depth_format.format(),
+            multisample,
+        );
+        let trails = TrailRenderer::new(
+            &device,
+            &memory,
+            &mut pipelines,
+            &shaders,
+            &camera_bind_group_layout,
+            depth_format.format(),
+            multisample,
+        );
+        let point_clouds = PointCloudRenderer::new(
+            &device,
+            &mut pipelines,
+            &shaders,
+            &camera_bind_group_layout,
+            depth_format.format(),
+            multisample,
+        );
+
+        let overlay = Overlay::new(&device, &memory, &mut pipelines, &shaders, config.format, settings.overlay_alpha);
+        let flare = LensFlare::new(
+            &device,
+            &memory,
+            &mut pipelines,
+            &shaders,
+            config.format,
+            &depth_texture,
+            sample_count,
+        );
+        let depth_probe = DepthProbe::new(&device, &memory, &shaders, &depth_texture, sample_count);
+        let volumetric = VolumetricLighting::new(
+            &device,
+            &memory,
+            &mut pipelines,
+            &shaders,
+            &camera_bind_group_layout,
+            &light_manager.light_bind_group_layout,
+            &depth_texture,
+            sample_count,
+        );
+        let stats = pipelines.stats();
+        log::debug!("Pipeline cache: {} built, {} shared", stats.misses, stats.hits);
+
+        let mut console = Console::new();
+        console.register("set_fov", |renderer, args| {
+            let degrees: f32 = args.first().context("Usage: set_fov <degrees>")?.parse()?;
+            renderer.set_camera_fovy(Deg(degrees.clamp(1.0, 170.0)));
+            Ok(format!("Field of view {}", degrees))
+        });
+        console.register("render_scale", |renderer, args| {
+            let scale: f32 = args.first().context("Usage: render_scale <scale>")?.parse()?;
+            renderer.settings.render_scale = scale.clamp(0.25, 2.0);
+            Ok(format!("Render scale {}", renderer.settings.render_scale))
+        });
+        console.register("boids", |renderer, args| {
+            let mut settings = BoidsSettings::default();
+            if let Some(count) = args.first() {
+                settings.count = count.parse().context("Usage: boids [count]")?;
+            }
+            Flock::spawn(renderer, settings).attach(renderer);
+            Ok(format!("Spawned a flock of {}", settings.count))
+        });
+
         //let light_render_pipeline = {
         //    let shader = wgpu::ShaderModuleDescriptor {
         //        label: Some("Light Shader"),
@@ -277,172 +1140,2694 @@ impl Renderer {
         //        &device,
         //        &layout,
         //        config.format,
-        //        Some(Texture::DEPTH_FORMAT),
+        //        Some(depth_format.format()),
         //        &[ModelVertex::desc()],
         //        shader,
         //    )
         //};
 
+        let luminance = LuminanceHistogram::new(&device, &memory);
+        let motion_kernel = MotionKernel::new(&device, crowd_instance_format);
+
         return Self {
             surface,
             config,
+            present_modes,
+            viewport: Viewport::new(size.width, size.height, 1.0),
+            viewport_rect: None,
             device,
             queue,
+            memory,
             depth_texture,
-            instance_buffer,
-            camera_buffer,
-            camera_bind_group,
-            render_pipeline,
+            post,
+            instance_buffers,
+            camera_buffers,
+            scene_buffers,
+            pacer: FramePacer::new(),
+            camera_bind_groups,
+            camera_bind_group_layout,
+            texture_bind_group_layout,
+            material_layout,
+            loader,
+            decoder,
+            loading: LoadingState::Loading,
+            pending_model,
+            pending_target_textures: Vec::new(),
+            render_pipelines,
+            ghost_pipeline,
+            outline_pipeline,
+            skinned_pipelines,
+            vat_pipelines,
+            lightmap_pipelines,
+            shadow_pipelines,
+            oit_pipelines,
+            pipelines,
+            crowd_bind_group_layout,
+            vat_bind_group_layout,
+            lightmap_bind_group_layout,
             //light_render_pipeline,
             size,
+            pending_size: None,
+            minimized: false,
+            visible_instances: instances.len() as u32,
+            lod_ranges: std::iter::once(0..instances.len() as u32).collect(),
+            instance_slots: (0..instances.len() as u32).collect(),
+            outline_ranges: Vec::new(),
+            hidden_ranges: Vec::new(),
             instances,
+            lod_eye: camera.position,
+            sorted_front_to_back: false,
+            overdraw: OverdrawEstimate::default(),
+            instance_bounds: None,
+            instance_layers: camera.render_layers,
+            instances_version: 0,
+            frame_count: 0,
+            elapsed: std::time::Duration::ZERO,
+            camera_uniform: camera.uniform(),
+            #[cfg(feature = "xr")]
+            xr_frame: None,
+            #[cfg(feature = "xr")]
+            xr_eyes: [camera.uniform(); 2],
+            clip_plane: None,
             camera,
+            camera_moving: false,
+            input_mode: settings.input_mode,
+            simulator,
             obj_model,
+            lods: Lods::default(),
+            static_batch: None,
+            lightmap: None,
+            luminance,
+            static_instance,
+            portals: None,
+            crowds: Vec::new(),
+            vat_crowds: Vec::new(),
+            render_targets: Vec::new(),
             light_manager,
+            environment,
+            atmosphere,
+            debug_labels: cfg!(debug_assertions),
+            settings,
+            depth_format,
+            model_instance_format,
+            crowd_instance_format,
+            motion_kernel,
+            placement: PlacementTool::default(),
+            measure: MeasureTool::default(),
+            colliders: Colliders::default(),
+            light_gizmo,
+            picker,
+            preferences: PreferenceStore::default(),
+            scene: Scene::default(),
+            events: EventBus::default(),
+            background,
+            particles,
+            trails,
+            oit,
+            point_clouds,
+            #[cfg(feature = "virtual-texturing")]
+            virtual_terrain: None,
+            overlay,
+            flare,
+            depth_probe,
+            depth_inv_view_proj: Matrix4::identity(),
+            depth_request: None,
+            volumetric,
+            hud: PerformanceHud::default(),
+            environment_view: None,
+            show_frustums: false,
+            bindings_panel: BindingsPanel::default(),
+            console,
+            show_inspector: false,
+            modifiers: ModifiersState::empty(),
+            view_target: None,
+            capture: None,
+            shaders,
+        };
+    }
+
+    // Fifo always waits for vertical blank and is always supported
+    fn present_mode(vsync: bool, supported: &[wgpu::PresentMode]) -> wgpu::PresentMode {
+        let modes = [wgpu::PresentMode::Immediate, wgpu::PresentMode::Mailbox];
+        return modes
+            .into_iter()
+            .find(|mode| !vsync && supported.contains(mode))
+            .unwrap_or(wgpu::PresentMode::Fifo);
+    }
+
+    pub fn preferences(&self) -> &Preferences {
+        return self.preferences.get();
+    }
+
+    /// Keep preferences in `store`, usually loaded before the renderer was
+    /// created with settings they were applied to, and apply them.
+    pub fn set_preference_store(&mut self, store: PreferenceStore) {
+        self.preferences = store;
+        self.apply_preferences();
+    }
+
+    /// Change the preferences and apply them right away. Handlers registered
+    /// with `on_preferences_changed` run and the store saves them.
+    pub fn update_preferences<F: FnOnce(&mut Preferences)>(&mut self, f: F) -> anyhow::Result<bool> {
+        let changed = self.preferences.update(f);
+        self.apply_preferences();
+        return changed;
+    }
+
+    pub fn on_preferences_changed<F: FnMut(&Preferences, &Preferences) + 'static>(&mut self, handler: F) {
+        self.preferences.on_change(handler);
+    }
+
+    // Bring settings, surface and camera controls in line with the
+    // preferences. The sample count stays, pipelines are built for it
+    fn apply_preferences(&mut self) {
+        let preferences = self.preferences.get().clone();
+        if preferences.msaa_samples != self.settings.msaa_samples {
+            log::info!("{}x MSAA applies after a restart", preferences.msaa_samples);
+        }
+        let msaa_samples = self.settings.msaa_samples;
+        preferences.apply(&mut self.settings);
+        self.settings.msaa_samples = msaa_samples;
+
+        let present_mode = Self::present_mode(self.settings.frame.vsync, &self.present_modes);
+        if present_mode != self.config.present_mode {
+            self.config.present_mode = present_mode;
+            if let Some(surface) = &self.surface {
+                surface.configure(&self.device, &self.config);
+            }
+        }
+        self.camera.controls = self.settings.controller;
+        self.simulator
+            .input(ControllerEvent::Configure(self.settings.controller));
+    }
+
+    /// Scale of the overlay, see `Overlay`.
+    pub fn ui_scale(&self) -> UiScale {
+        return UiScale {
+            scale_factor: self.viewport.scale_factor,
+            user_scale: self.settings.ui_scale,
         };
     }
 
+    /// Size of the window in the overlay's logical pixels.
+    pub fn logical_size(&self) -> [f32; 2] {
+        let [width, height] = self.viewport.window;
+        return self.ui_scale().to_logical([width as f32, height as f32]);
+    }
+
+    /// Ask for the surface to be resized. Window drags send many resizes a
+    /// frame, only the last one is applied by the next `update` or `render`.
+    /// A zero size means the window was minimized, rendering is suspended
+    /// until it gets a size again.
     pub fn resize(&mut self, new_size: winit::dpi::PhysicalSize<u32>) {
-        if new_size.width > 0 && new_size.height > 0 {
-            self.size = new_size;
-            self.config.width = new_size.width;
-            self.config.height = new_size.height;
-            self.surface.configure(&self.device, &self.config);
-            self.depth_texture =
-                Texture::create_depth_texture(&self.device, &self.config, "depth_texture");
-            self.camera
-                .projection_mut()
-                .resize(new_size.width, new_size.height);
+        if new_size.width == 0 || new_size.height == 0 {
+            self.minimized = true;
+            return;
         }
+        self.minimized = false;
+        self.pending_size = Some(new_size);
     }
 
-    // True if event was fully processed
-    pub fn input(&mut self, _: &Event<()>) -> bool {
-        return false;
+    /// Whether the window has no area to render into.
+    pub fn is_minimized(&self) -> bool {
+        return self.minimized;
     }
 
-    pub fn update(&mut self, dt: std::time::Duration) {
-        // Update camera
-        self.camera.update(dt);
-        self.queue.write_buffer(
-            &self.camera_buffer,
-            0,
-            bytemuck::cast_slice(&[self.camera.uniform()]),
+    /// Reconfigure the surface and render targets for the last size passed
+    /// to `resize`, if any. Called at the start of `update` and `render`.
+    pub fn apply_resize(&mut self) {
+        let new_size = match self.pending_size.take() {
+            Some(new_size) => new_size,
+            None => return,
+        };
+        self.size = new_size;
+        self.config.width = new_size.width;
+        self.config.height = new_size.height;
+        if let Some(surface) = &self.surface {
+            surface.configure(&self.device, &self.config);
+        }
+        self.viewport.window = [new_size.width, new_size.height];
+        self.sync_viewport();
+        self.events.publish(EngineEvent::WindowResized {
+            width: new_size.width,
+            height: new_size.height,
+        });
+    }
+
+    /// Where the scene is shown and at what resolution, for converting
+    /// between window positions, render target pixels and the camera.
+    pub fn viewport(&self) -> Viewport {
+        return self.viewport;
+    }
+
+    /// Show the scene in an area of the window, in physical pixels, or all
+    /// of it with `None`. The overlay still covers the whole window.
+    pub fn set_viewport_rect(&mut self, rect: Option<ViewportRect>) {
+        self.viewport_rect = rect;
+        self.sync_viewport();
+    }
+
+    // Fit the viewport to the window and resize the render targets when the
+    // render scale changed. A running capture keeps its own resolution
+    fn sync_viewport(&mut self) {
+        let [width, height] = self.viewport.window.map(|s| s as f32);
+        let full = ViewportRect::new(0.0, 0.0, width, height);
+        self.viewport.rect = match self.viewport_rect {
+            Some(rect) => {
+                let (x, y) = (rect.x.clamp(0.0, width - 1.0), rect.y.clamp(0.0, height - 1.0));
+                ViewportRect::new(x, y, rect.width.clamp(1.0, width - x), rect.height.clamp(1.0, height - y))
+            }
+            None => full,
+        };
+        // Headset frames are rendered at the eye resolution
+        #[cfg(feature = "xr")]
+        if self.xr_frame.is_some() {
+            return;
+        }
+        let render = Viewport::scaled_size(self.viewport.rect, self.settings.render_scale);
+        if self.capture.is_none() && render != self.viewport.render {
+            self.resize_targets(render[0], render[1]);
+        }
+    }
+
+    // Resize everything the scene is rendered into, independent of the surface
+    fn resize_targets(&mut self, width: u32, height: u32) {
+        self.viewport.render = [width, height];
+        let config = wgpu::SurfaceConfiguration {
+            width,
+            height,
+            ..self.config.clone()
+        };
+        self.depth_texture = Texture::create_depth_texture(
+            &self.device,
+            &self.memory,
+            &config,
+            self.depth_format.format(),
+            self.post.sample_count(),
+            "depth_texture",
         );
+        self.post.resize(&self.device, &self.memory, &config);
+        self.oit.resize(&self.device, &self.memory, width, height);
+        self.flare.set_depth_texture(&self.device, &self.depth_texture);
+        self.depth_probe.set_depth_texture(&self.device, &self.depth_texture);
+        self.volumetric.set_depth_texture(&self.device, &self.depth_texture);
+        self.camera.projection_mut().resize(width, height);
     }
 
-    pub fn render(&self) -> Result<(), wgpu::SurfaceError> {
-        let output = self.surface.get_current_texture()?;
-        let view = output
-            .texture
-            .create_view(&wgpu::TextureViewDescriptor::default());
-        let mut encoder = self
-            .device
-            .create_command_encoder(&wgpu::CommandEncoderDescriptor {
-                label: Some("Render Encoder"),
-            });
+    pub fn environment(&self) -> &Environment {
+        return &self.environment;
+    }
 
-        {
-            let mut render_pass = encoder.begin_render_pass(&wgpu::RenderPassDescriptor {
-                label: Some("Render Pass"),
-                color_attachments: &[Some(wgpu::RenderPassColorAttachment {
-                    view: &view,
-                    resolve_target: None,
-                    ops: wgpu::Operations {
-                        load: wgpu::LoadOp::Clear(wgpu::Color {
-                            r: 0.1,
-                            g: 0.2,
-                            b: 0.3,
-                            a: 1.0,
-                        }),
-                        store: true,
-                    },
-                })],
-                depth_stencil_attachment: Some(wgpu::RenderPassDepthStencilAttachment {
-                    view: &self.depth_texture.view,
-                    depth_ops: Some(wgpu::Operations {
-                        load: wgpu::LoadOp::Clear(1.0),
-                        store: true,
-                    }),
-                    stencil_ops: None,
-                }),
-            });
+    pub fn environment_mut(&mut self) -> &mut Environment {
+        return &mut self.environment;
+    }
 
-            // Render light (for debbuging)
-            //render_pass.set_pipeline(&self.light_render_pipeline);
-            //render_pass.draw_light_model(
-            //    &self.obj_model,
-            //    &self.camera_bind_group,
-            //    &self.light_bind_group,
-            //);
+    /// Replace the image based lighting, e.g. after loading a new environment.
+    pub fn set_environment(&mut self, environment: Environment) {
+        self.light_manager.set_environment(&self.device, &environment, &self.atmosphere);
+        self.environment = environment;
+    }
 
-            // Render models
-            render_pass.set_pipeline(&self.render_pipeline);
-            render_pass.set_vertex_buffer(1, self.instance_buffer.slice(..));
-            render_pass.draw_model_instanced(
-                &self.obj_model,
-                0..self.instances.len() as _,
-                &self.camera_bind_group,
-                &self.light_manager.light_bind_group,
-            );
+    pub fn atmosphere(&self) -> &AtmosphereModel {
+        return self.atmosphere.model();
+    }
+
+    /// Planet and air the atmosphere background and `AtmosphereSettings::scattering`
+    /// are computed for. Recomputes its lookup tables, which takes a moment.
+    pub fn set_atmosphere(&mut self, model: AtmosphereModel) {
+        self.atmosphere.set_model(&self.device, &self.queue, model);
+    }
+
+    pub fn depth_format(&self) -> DepthFormat {
+        return self.depth_format;
+    }
+
+    pub fn depth_texture(&self) -> &Texture {
+        return &self.depth_texture;
+    }
+
+    /// View of only the stencil aspect of the depth texture, for effects that
+    /// read the mask written by earlier passes. `None` without a stencil format.
+    pub fn stencil_view(&self) -> Option<wgpu::TextureView> {
+        if !self.depth_format.has_stencil() {
+            return None;
         }
+        return Some(self.depth_texture.texture.create_view(&wgpu::TextureViewDescriptor {
+            label: Some("Stencil View"),
+            aspect: wgpu::TextureAspect::StencilOnly,
+            ..Default::default()
+        }));
+    }
 
-        self.queue.submit(std::iter::once(encoder.finish()));
-        output.present();
+    /// Start rendering fixed-timestep frames offscreen and writing them out
+    /// as described by `settings`, see `capture_frame`.
+    pub fn start_capture(&mut self, settings: CaptureSettings) -> anyhow::Result<()> {
+        if self.capture.is_some() {
+            anyhow::bail!("A capture is already running");
+        }
+        // Captured frames must not depend on how fast pipelines compiled
+        self.pipelines.wait();
+        let (width, height) = (settings.width, settings.height);
+        let capture = FrameCapture::new(&self.device, &self.memory, self.config.format, settings)?;
+        self.resize_targets(width, height);
+        self.capture = Some(capture);
+        return Ok(());
+    }
 
-        Ok(())
+    /// Stop the running capture, returning the number of frames written.
+    pub fn stop_capture(&mut self) -> anyhow::Result<u32> {
+        let capture = match self.capture.take() {
+            Some(capture) => capture,
+            None => return Ok(0),
+        };
+        let render = Viewport::scaled_size(self.viewport.rect, self.settings.render_scale);
+        self.resize_targets(render[0], render[1]);
+        return capture.finish();
     }
-}
 
-fn create_render_pipeline(
-    label: &str,
-    device: &wgpu::Device,
-    layout: &wgpu::PipelineLayout,
-    color_format: wgpu::TextureFormat,
-    depth_format: Option<wgpu::TextureFormat>,
-    vertex_layouts: &[wgpu::VertexBufferLayout],
-    shader: wgpu::ShaderModuleDescriptor,
-) -> wgpu::RenderPipeline {
-    let shader = device.create_shader_module(shader);
-
-    return device.create_render_pipeline(&wgpu::RenderPipelineDescriptor {
-        label: Some(label),
-        layout: Some(&layout),
-        vertex: wgpu::VertexState {
-            module: &shader,
-            entry_point: "vs_main",
-            buffers: vertex_layouts,
-        },
-        fragment: Some(wgpu::FragmentState {
-            module: &shader,
-            entry_point: "fs_main",
-            targets: &[Some(wgpu::ColorTargetState {
-                format: color_format,
-                blend: Some(wgpu::BlendState::REPLACE),
-                write_mask: wgpu::ColorWrites::ALL,
-            })],
-        }),
-        primitive: wgpu::PrimitiveState {
-            topology: wgpu::PrimitiveTopology::TriangleList,
-            strip_index_format: None,
-            front_face: wgpu::FrontFace::Ccw,
-            cull_mode: Some(wgpu::Face::Back),
-            unclipped_depth: false,
-            polygon_mode: wgpu::PolygonMode::Fill,
-            conservative: false,
-        },
-        depth_stencil: depth_format.map(|format| wgpu::DepthStencilState {
-            format,
-            depth_write_enabled: true,
-            depth_compare: wgpu::CompareFunction::Less,
-            stencil: wgpu::StencilState::default(),
-            bias: wgpu::DepthBiasState::default(),
-        }),
-        multisample: wgpu::MultisampleState {
-            count: 1,
-            mask: !0,
-            alpha_to_coverage_enabled: false,
-        },
-        multiview: None,
-    });
-}
+    pub fn is_capturing(&self) -> bool {
+        return self.capture.is_some();
+    }
+
+    /// Advance the simulation by one capture step, render it offscreen and
+    /// write it out. Returns false once the capture has finished.
+    pub fn capture_frame(&mut self) -> anyhow::Result<bool> {
+        let dt = match &self.capture {
+            Some(capture) => capture.settings().frame_time(),
+            None => return Ok(false),
+        };
+        self.update(dt);
+
+        let capture = self.capture.as_ref().unwrap();
+        let (width, height) = (capture.settings().width, capture.settings().height);
+        self.overlay
+            .prepare(&self.device, &self.memory, &self.queue, width, height);
+        let mut encoder = self
+            .device
+            .create_command_encoder(&wgpu::CommandEncoderDescriptor {
+                label: Some("Capture Encoder"),
+            });
+        self.encode_frame(&mut encoder, capture.view(), None);
+        capture.copy_to_buffer(&mut encoder);
+        self.queue.submit(std::iter::once(encoder.finish()));
+
+        let capture = self.capture.as_mut().unwrap();
+        let result = capture.write_frame(&self.device);
+        if result.is_err() || capture.is_finished() {
+            let frames = self.stop_capture()?;
+            log::info!("Captured {} frames", frames);
+            return result.map(|_| false);
+        }
+        return Ok(true);
+    }
+
+    /// Advance the simulation by `dt`, then render a frame at the surface size
+    /// offscreen and read it back, once every pipeline is compiled. How
+    /// headless renderers draw; slow, since it waits for the GPU.
+    pub fn render_image(&mut self, dt: std::time::Duration) -> anyhow::Result<image::RgbaImage> {
+        if self.capture.is_some() {
+            anyhow::bail!("Cannot render images while a capture is running");
+        }
+        self.pipelines.wait();
+        self.update(dt);
+
+        let (width, height) = (self.config.width, self.config.height);
+        let settings = CaptureSettings {
+            width,
+            height,
+            output: CaptureOutput::Memory,
+            ..CaptureSettings::default()
+        };
+        let snapshot = FrameCapture::new(&self.device, &self.memory, self.config.format, settings)?;
+        self.overlay
+            .prepare(&self.device, &self.memory, &self.queue, width, height);
+        let mut encoder = self
+            .device
+            .create_command_encoder(&wgpu::CommandEncoderDescriptor {
+                label: Some("Image Encoder"),
+            });
+        self.encode_frame(&mut encoder, snapshot.view(), Some(self.viewport.rect));
+        snapshot.copy_to_buffer(&mut encoder);
+        self.queue.submit(std::iter::once(encoder.finish()));
+        return snapshot.read_frame(&self.device);
+    }
+
+    /// Advance the simulation by `dt` and render the session's next frame
+    /// into its eye swapchain images, the head looking around from the
+    /// camera's position. Used instead of `update` and `render` while the
+    /// session runs; returns false once it ended.
+    #[cfg(feature = "xr")]
+    pub fn render_xr(&mut self, session: &mut impl XrSession, dt: std::time::Duration) -> anyhow::Result<bool> {
+        if !session.poll_events() {
+            return Ok(false);
+        }
+        if session.swapchain_format() != self.config.format {
+            anyhow::bail!(
+                "XR swapchain format {:?} differs from the surface format {:?}",
+                session.swapchain_format(),
+                self.config.format
+            );
+        }
+        let frame = match session.begin_frame() {
+            Some(frame) => frame,
+            None => return Ok(true),
+        };
+        let (width, height) = session.eye_resolution();
+        self.xr_frame = Some(frame);
+        if self.viewport.render != [width, height] {
+            self.resize_targets(width, height);
+        }
+        self.update(dt);
+
+        let shadows = self.settings.passes.shadows && self.pipelines.is_ready(self.shadow_pipelines.back);
+        let shadow_view = ShadowView {
+            position: self.camera.position + frame.head.position,
+            inv_view_proj: self.camera_uniform.inv_view_proj(),
+            scene_bounds: self.scene_bounds(),
+        };
+        self.light_manager.update_shadows(&self.queue, &shadow_view, shadows);
+        self.depth_inv_view_proj = self.camera_uniform.inv_view_proj();
+        self.overlay
+            .prepare(&self.device, &self.memory, &self.queue, width, height);
+
+        // Eyes share the camera buffer, so each is submitted before the next
+        // one's camera is written
+        let projection = self.camera.projection();
+        let (znear, zfar) = (projection.znear(), projection.zfar());
+        let origin = Matrix4::from_translation(self.camera.position.to_vec());
+        for (eye, view) in frame.views.iter().enumerate() {
+            let uniform = view
+                .uniform(&origin, znear, zfar)
+                .with_previous(&self.xr_eyes[eye])
+                .with_clip_plane(self.clip_plane);
+            self.xr_eyes[eye] = uniform;
+            self.queue
+                .write_buffer(self.camera_buffers.current(), 0, bytemuck::cast_slice(&[uniform]));
+            let mut encoder = self
+                .device
+                .create_command_encoder(&wgpu::CommandEncoderDescriptor {
+                    label: Some("XR Eye Encoder"),
+                });
+            self.encode_frame(&mut encoder, session.eye_target(eye), None);
+            self.queue.submit(std::iter::once(encoder.finish()));
+        }
+        session.end_frame(&frame);
+        self.xr_frame = None;
+        return Ok(true);
+    }
+
+    /// Frames in flight and time spent waiting for the GPU.
+    pub fn frame_pacer(&self) -> &FramePacer {
+        return &self.pacer;
+    }
+
+    /// Whether the GPU, rather than the CPU, limits the frame rate.
+    pub fn is_gpu_bound(&self) -> bool {
+        return self.pacer.is_gpu_bound();
+    }
+
+    pub fn device(&self) -> &wgpu::Device {
+        return &self.device;
+    }
+
+    pub fn queue(&self) -> &wgpu::Queue {
+        return &self.queue;
+    }
+
+    pub fn memory(&self) -> &MemoryTracker {
+        return &self.memory;
+    }
+
+    // Layout shared by all material bind groups
+    pub fn material_bind_group_layout(&self) -> &wgpu::BindGroupLayout {
+        return &self.texture_bind_group_layout;
+    }
+
+    // Preprocessor with the defines used by the engine's own pipelines
+    pub fn shaders(&self) -> &ShaderPreprocessor {
+        return &self.shaders;
+    }
+
+    /// Create a crowd of `model`, whose meshes are skinned by `skins`, playing
+    /// clips from `animations`. Returns the index into `crowds`.
+    pub fn add_crowd(
+        &mut self,
+        model: Model,
+        skins: &[Vec<SkinVertex>],
+        animations: BakedAnimations,
+    ) -> anyhow::Result<usize> {
+        let crowd = Crowd::new(
+            &self.device,
+            &self.memory,
+            &self.crowd_bind_group_layout,
+            model,
+            skins,
+            animations,
+            self.crowd_instance_format,
+        )?;
+        self.crowds.push(crowd);
+        return Ok(self.crowds.len() - 1);
+    }
+
+    /// Create a crowd of `model` playing baked vertex animation clips from
+    /// `animation`. Returns the index into `vat_crowds`.
+    pub fn add_vertex_animation(&mut self, model: Model, animation: VertexAnimation) -> anyhow::Result<usize> {
+        let crowd = VatCrowd::new(
+            &self.device,
+            &self.memory,
+            &self.queue,
+            &self.vat_bind_group_layout,
+            model,
+            animation,
+            self.crowd_instance_format,
+        )?;
+        self.vat_crowds.push(crowd);
+        return Ok(self.vat_crowds.len() - 1);
+    }
+
+    /// Image shown with `BackgroundMode::Image`, `None` removes it.
+    pub fn set_background_image(&mut self, image: Option<&image::DynamicImage>) -> anyhow::Result<()> {
+        return self.background.set_image(&self.device, &self.memory, &self.queue, image);
+    }
+
+    /// Terrain plane textured from `source` through a virtual texture, `None`
+    /// removes it. Place it through the returned terrain's `origin` and `extent`.
+    #[cfg(feature = "virtual-texturing")]
+    pub fn set_virtual_terrain(
+        &mut self,
+        source: Option<Box<dyn PageSource>>,
+        settings: VirtualTextureSettings,
+    ) -> anyhow::Result<Option<&mut VirtualTerrain>> {
+        let source = match source {
+            Some(source) => source,
+            None => {
+                self.virtual_terrain = None;
+                return Ok(None);
+            }
+        };
+        let sample_count = self.post.sample_count();
+        let terrain = VirtualTerrain::new(
+            &self.device,
+            &self.memory,
+            &self.queue,
+            &mut self.pipelines,
+            &self.shaders,
+            &self.camera_bind_group_layout,
+            self.depth_format.format(),
+            wgpu::MultisampleState {
+                count: sample_count,
+                mask: !0,
+                alpha_to_coverage_enabled: sample_count > 1,
+            },
+            source,
+            settings,
+        )?;
+        return Ok(Some(self.virtual_terrain.insert(terrain)));
+    }
+
+    /// Upload `points` as a new point cloud, see `PointCloudRenderer`.
+    /// Returns the index into `point_clouds.clouds`.
+    pub fn add_point_cloud(&mut self, points: &[CloudPoint]) -> usize {
+        return self.point_clouds.add(&self.device, &self.memory, points);
+    }
+
+    /// Render the scene from a new camera into a `width` by `height` texture
+    /// that replaces the diffuse map of `material`. Returns the index into
+    /// `render_targets`. Materials of the instanced model can be asked for
+    /// while it is loading, they get the texture once it is swapped in.
+    pub fn add_render_target_camera(
+        &mut self,
+        material: TargetMaterial,
+        width: u32,
+        height: u32,
+    ) -> anyhow::Result<usize> {
+        let model_loading = self.pending_model.is_some() && matches!(material, TargetMaterial::Instances(_));
+        anyhow::ensure!(
+            model_loading || self.target_material(material).is_some(),
+            "No material {:?} to render into",
+            material
+        );
+        let (camera, texture) = RenderTargetCamera::new(
+            &self.device,
+            &self.memory,
+            &self.camera_bind_group_layout,
+            &self.scene_buffers,
+            material,
+            width,
+            height,
+            self.post.sample_count(),
+            self.depth_format.format(),
+        );
+        let layout = &self.texture_bind_group_layout;
+        match material {
+            TargetMaterial::Instances(i) if model_loading => self.pending_target_textures.push((i, texture)),
+            TargetMaterial::Instances(i) => self.obj_model.materials[i].set_diffuse_texture(&self.device, texture, layout),
+            TargetMaterial::StaticBatch(i) => {
+                let batch = self.static_batch.as_mut().unwrap();
+                batch.materials[i].set_diffuse_texture(&self.device, texture, layout);
+            }
+        }
+        self.render_targets.push(camera);
+        return Ok(self.render_targets.len() - 1);
+    }
+
+    pub fn render_targets(&self) -> &[RenderTargetCamera] {
+        return &self.render_targets;
+    }
+
+    pub fn render_targets_mut(&mut self) -> &mut Vec<RenderTargetCamera> {
+        return &mut self.render_targets;
+    }
+
+    fn target_material(&self, material: TargetMaterial) -> Option<&Material> {
+        return match material {
+            TargetMaterial::Instances(i) => self.obj_model.materials.get(i),
+            TargetMaterial::StaticBatch(i) => self.static_batch.as_ref()?.materials.get(i),
+        };
+    }
+
+    // Parameter layout for materials drawn with the engine's pipelines
+    pub fn material_layout(&self) -> &Arc<MaterialLayout> {
+        return &self.material_layout;
+    }
+
+    /// Background loads, e.g. for assets a demo needs before its first frame.
+    /// Loads started while `loading_state` is `Loading` hold the progress
+    /// screen up until they finish.
+    pub fn loader(&mut self) -> &mut AssetLoader {
+        return &mut self.loader;
+    }
+
+    /// Workers decoding the textures of background loads.
+    pub fn decoder(&self) -> &ImageDecoder {
+        return &self.decoder;
+    }
+
+    /// Estimated overdraw of the model instances uploaded last, in the order
+    /// they're drawn, see `Settings::sort_front_to_back`.
+    pub fn overdraw(&self) -> OverdrawEstimate {
+        return self.overdraw;
+    }
+
+    pub fn loading_state(&self) -> LoadingState {
+        return self.loading;
+    }
+
+    /// Load an `.obj` model for the engine's pipelines in the background.
+    pub fn load_model_async(&mut self, file_name: &str) -> AssetHandle<Model> {
+        return Self::spawn_model_load(
+            &mut self.loader,
+            file_name,
+            &self.texture_bind_group_layout,
+            &self.material_layout,
+        );
+    }
+
+    /// Load a `.mat` material for the engine's pipelines in the background.
+    pub fn load_material_async(&mut self, file_name: &str) -> AssetHandle<Material> {
+        let layout = self.texture_bind_group_layout.clone();
+        let params_layout = self.material_layout.clone();
+        let file_name = file_name.to_string();
+        return self.loader.spawn(&file_name.clone(), move |cx| async move {
+            let decoder = Some(&cx.decoder);
+            load_material(&file_name, &cx.device, &cx.memory, &cx.queue, &layout, &params_layout, decoder).await
+        });
+    }
+
+    fn spawn_model_load(
+        loader: &mut AssetLoader,
+        file_name: &str,
+        layout: &Arc<wgpu::BindGroupLayout>,
+        params_layout: &Arc<MaterialLayout>,
+    ) -> AssetHandle<Model> {
+        let (layout, params_layout) = (layout.clone(), params_layout.clone());
+        let file_name = file_name.to_string();
+        return loader.spawn(&file_name.clone(), move |cx| async move {
+            let decoder = Some(&cx.decoder);
+            load_model(&file_name, &cx.device, &cx.memory, &cx.queue, &layout, &params_layout, decoder).await
+        });
+    }
+
+    // Swap finished startup loads into the scene, and leave the loading
+    // screen once nothing is left
+    fn poll_loading(&mut self) {
+        if let Some(result) = self.pending_model.as_ref().and_then(AssetHandle::poll) {
+            self.pending_model = None;
+            match result {
+                Ok(model) => {
+                    self.obj_model = model;
+                    self.instances_version += 1;
+                }
+                Err(e) => log::error!("{:?}", e),
+            }
+            for (index, texture) in std::mem::take(&mut self.pending_target_textures) {
+                let layout = &self.texture_bind_group_layout;
+                match self.obj_model.materials.get_mut(index) {
+                    Some(material) => material.set_diffuse_texture(&self.device, texture, layout),
+                    None => log::warn!("No material {} to render into in the loaded model", index),
+                }
+            }
+        }
+        if self.loading == LoadingState::Loading && self.pending_model.is_none() && self.loader.is_idle() {
+            self.loading = LoadingState::Running;
+        }
+    }
+
+    pub fn memory_report(&self) -> MemoryReport {
+        return self.memory.report();
+    }
+
+    /// Meshes, instances, lights and textures of the scene with their memory
+    /// footprints and what they did last frame.
+    pub fn inspect(&self) -> SceneInspector {
+        let mut inspector = SceneInspector::new(self.memory.report());
+        inspector.add_model("scene", &self.obj_model);
+        for (i, level) in self.lods.levels().iter().enumerate() {
+            inspector.add_model(&format!("lod {}", i + 1), &level.model);
+        }
+        if let Some(batch) = &self.static_batch {
+            inspector.add_model("static batch", batch);
+        }
+        for (i, crowd) in self.crowds.iter().enumerate() {
+            let name = format!("crowd {}", i);
+            inspector.add_crowd(name, &crowd.model, crowd.instances.len(), crowd.motion.is_some());
+        }
+        for (i, crowd) in self.vat_crowds.iter().enumerate() {
+            let name = format!("vat crowd {}", i);
+            inspector.add_crowd(name, &crowd.model, crowd.instances.len(), crowd.motion.is_some());
+        }
+
+        // Cross-fading instances are in two levels, the lower one is listed
+        let mut states = self
+            .instances
+            .iter()
+            .map(|i| {
+                if i.layers.intersects(self.instance_layers) {
+                    InstanceState::Culled
+                } else {
+                    InstanceState::Hidden
+                }
+            })
+            .collect::<Vec<_>>();
+        for (level, range) in self.lod_ranges.iter().enumerate().rev() {
+            let slots = self.instance_slots.get(range.start as usize..range.end as usize).unwrap_or_default();
+            for slot in slots {
+                if let Some(state) = states.get_mut(*slot as usize) {
+                    *state = InstanceState::Drawn { level };
+                }
+            }
+        }
+        inspector.add_instances(&self.instances, &states, self.obj_model.bounds(), &self.light_manager);
+        inspector.add_lights(&self.light_manager);
+        inspector.add_point_clouds(&self.point_clouds);
+        return inspector;
+    }
+
+    pub fn set_memory_budget(&self, budget: MemoryBudget) {
+        self.memory.set_budget(budget);
+    }
+
+    pub fn input_mode(&self) -> InputMode {
+        return self.input_mode;
+    }
+
+    /// Route mouse motion to the camera or free the cursor for the UI. The
+    /// event loop grabs or releases the cursor to match.
+    pub fn set_input_mode(&mut self, mode: InputMode) {
+        self.input_mode = mode;
+    }
+
+    // True if event was fully processed
+    pub fn input(&mut self, event: &Event<()>) -> bool {
+        let event = match event {
+            Event::WindowEvent { event, .. } => event,
+            _ => return false,
+        };
+        match event {
+            // Not handled, the event loop still resizes to the new inner size
+            WindowEvent::ScaleFactorChanged { scale_factor, .. } => {
+                self.viewport.scale_factor = *scale_factor;
+                return false;
+            }
+            WindowEvent::CursorMoved { position, .. } => {
+                let (x, y) = (position.x as f32, position.y as f32);
+                self.placement.set_cursor(x, y);
+                if self.light_gizmo.is_dragging() {
+                    let ray = self.screen_ray(x, y);
+                    let target = self.pick(x, y).map(|hit| hit.point);
+                    self.light_gizmo
+                        .drag(&self.queue, &mut self.light_manager, &ray, target);
+                    if let Some(id) = self.light_gizmo.selected() {
+                        self.events.publish(EngineEvent::LightChanged(id));
+                    }
+                    return true;
+                }
+                return false;
+            }
+            WindowEvent::ModifiersChanged(modifiers) => {
+                self.modifiers = *modifiers;
+                return false;
+            }
+            WindowEvent::ReceivedCharacter(ch) if self.console.open => {
+                self.console.receive_char(*ch);
+                return true;
+            }
+            WindowEvent::KeyboardInput {
+                input:
+                    KeyboardInput {
+                        state: ElementState::Pressed,
+                        virtual_keycode: Some(key),
+                        ..
+                    },
+                ..
+            } => {
+                return self.key_pressed(*key);
+            }
+            WindowEvent::MouseInput {
+                state: ElementState::Pressed,
+                button: MouseButton::Left,
+                ..
+            } => {
+                if let (true, Some((x, y))) = (self.light_gizmo.enabled, self.placement.cursor()) {
+                    let ray = self.screen_ray(x, y);
+                    let light = self.light_gizmo.pick(&ray, &self.light_manager);
+                    if self.light_gizmo.begin_drag(&ray, &self.light_manager) {
+                        if let Some((id, t)) = light {
+                            let event = PickEvent {
+                                entity: Entity::Light(id),
+                                point: ray.at(t),
+                                distance: t,
+                            };
+                            Scene::run_picked(self, &event);
+                        }
+                        return true;
+                    }
+                }
+                if self.measure.enabled {
+                    let hit = self.placement.cursor().and_then(|(x, y)| self.pick(x, y));
+                    if let Some(hit) = hit {
+                        self.measure.click(hit.point);
+                    }
+                    return true;
+                }
+                if self.placement.enabled {
+                    return self.place().is_some();
+                }
+                return self.pick_entity();
+            }
+            WindowEvent::MouseInput {
+                state: ElementState::Released,
+                button: MouseButton::Left,
+                ..
+            } => {
+                return self.light_gizmo.end_drag();
+            }
+            _ => return false,
+        }
+    }
+
+    // Navigate the bindings panel, take a new binding or run the action bound
+    // to `key` with the held modifiers
+    fn key_pressed(&mut self, key: VirtualKeyCode) -> bool {
+        // The console takes every key but its toggle while open
+        if self.console.open {
+            if self.settings.actions.action_for_key(key, self.modifiers) == Some(Action::ToggleConsole) {
+                self.console.toggle();
+            } else if let Some(line) = self.console.key_pressed(key) {
+                Console::execute(self, &line);
+            }
+            return true;
+        }
+        if self.bindings_panel.is_rebinding() {
+            // Modifiers are held for the chord's key
+            if is_modifier(key) {
+                return true;
+            }
+            if key == VirtualKeyCode::Escape {
+                self.bindings_panel.cancel_rebind();
+            } else {
+                self.finish_rebind(Binding::Key(KeyChord::with_modifiers(key, self.modifiers)));
+            }
+            return true;
+        }
+        if self.bindings_panel.enabled && self.modifiers.is_empty() {
+            let action = self.bindings_panel.selected();
+            match key {
+                VirtualKeyCode::Up => self.bindings_panel.select_previous(),
+                VirtualKeyCode::Down => self.bindings_panel.select_next(),
+                VirtualKeyCode::Return => self.bindings_panel.begin_rebind(action),
+                VirtualKeyCode::Delete => {
+                    let result = self.update_preferences(|p| p.actions.clear(action));
+                    if let Err(e) = result {
+                        log::error!("{:?}", e);
+                    }
+                }
+                VirtualKeyCode::Escape => self.bindings_panel.enabled = false,
+                _ => {
+                    return match self.settings.actions.action_for_key(key, self.modifiers) {
+                        Some(action) => self.run_action(action),
+                        None => false,
+                    };
+                }
+            }
+            return true;
+        }
+        return match self.settings.actions.action_for_key(key, self.modifiers) {
+            Some(action) => self.run_action(action),
+            None => false,
+        };
+    }
+
+    /// Feed a gamepad button press or release, see `gamepad::Gamepads`.
+    /// Navigates the bindings panel while it's shown and runs the bound
+    /// action otherwise. True if the button was used.
+    pub fn gamepad_input(&mut self, button: GamepadButton, pressed: bool) -> bool {
+        if !pressed {
+            return false;
+        }
+        if self.bindings_panel.is_rebinding() {
+            self.finish_rebind(Binding::Gamepad(button));
+            return true;
+        }
+        if self.bindings_panel.enabled {
+            let action = self.bindings_panel.selected();
+            match button {
+                GamepadButton::DPadUp => self.bindings_panel.select_previous(),
+                GamepadButton::DPadDown => self.bindings_panel.select_next(),
+                GamepadButton::South => self.bindings_panel.begin_rebind(action),
+                GamepadButton::East => self.bindings_panel.enabled = false,
+                _ => {}
+            }
+            if matches!(
+                button,
+                GamepadButton::DPadUp | GamepadButton::DPadDown | GamepadButton::South | GamepadButton::East
+            ) {
+                return true;
+            }
+        }
+        return match self.settings.actions.action_for_button(button) {
+            Some(action) => self.run_action(action),
+            None => false,
+        };
+    }
+
+    /// Show the bindings panel and bind the next key chord or gamepad button
+    /// to `action`, replacing its binding on that device. Escape cancels.
+    pub fn begin_rebind(&mut self, action: Action) {
+        self.bindings_panel.enabled = true;
+        self.bindings_panel.begin_rebind(action);
+    }
+
+    // Save the binding the panel was waiting for into the preferences
+    fn finish_rebind(&mut self, binding: Binding) {
+        let action = match self.bindings_panel.take_rebinding() {
+            Some(action) => action,
+            None => return,
+        };
+        let mut shared = Vec::new();
+        let result = self.update_preferences(|p| shared = p.actions.rebind(action, binding));
+        if let Err(e) = result {
+            log::error!("{:?}", e);
+        }
+        log::info!("{} runs {:?}", binding, action);
+        if !shared.is_empty() {
+            log::warn!("{} is also bound to {:?}", binding, shared);
+        }
+    }
+
+    /// Run `action` as if its binding was pressed. False if it did nothing,
+    /// like switching the gizmo mode while the gizmo is hidden.
+    pub fn run_action(&mut self, action: Action) -> bool {
+        match action {
+            Action::ToggleInputMode => self.set_input_mode(self.input_mode.toggled()),
+            Action::ToggleHud => self.hud.enabled = !self.hud.enabled,
+            Action::ToggleFrustums => self.show_frustums = !self.show_frustums,
+            Action::CycleEnvironmentView => {
+                self.environment_view = match self.environment_view {
+                    None => Some(EnvironmentMap::Source),
+                    Some(EnvironmentMap::Source) => Some(EnvironmentMap::Irradiance),
+                    Some(EnvironmentMap::Irradiance) => Some(EnvironmentMap::Prefiltered(0)),
+                    Some(EnvironmentMap::Prefiltered(mip)) if mip + 1 < Environment::PREFILTER_MIPS => {
+                        Some(EnvironmentMap::Prefiltered(mip + 1))
+                    }
+                    Some(EnvironmentMap::Prefiltered(_)) => None,
+                };
+                log::info!("Environment view {:?}", self.environment_view);
+            }
+            // Pass toggles for bisecting frame cost
+            Action::ToggleModelPass
+            | Action::ToggleCrowdPass
+            | Action::ToggleLensFlarePass
+            | Action::ToggleOverlayPass => {
+                let passes = &mut self.settings.passes;
+                let (name, enabled) = match action {
+                    Action::ToggleModelPass => ("Model", &mut passes.models),
+                    Action::ToggleCrowdPass => ("Crowd", &mut passes.crowds),
+                    Action::ToggleLensFlarePass => ("Lens flare", &mut passes.lens_flare),
+                    _ => ("Overlay", &mut passes.overlay),
+                };
+                *enabled = !*enabled;
+                log::info!("{} pass {}", name, if *enabled { "enabled" } else { "disabled" });
+            }
+            Action::ToggleVsync => {
+                let result = self.update_preferences(|p| p.vsync = !p.vsync);
+                if let Err(e) = result {
+                    log::error!("{:?}", e);
+                }
+                log::info!("Vsync {}", if self.settings.frame.vsync { "on" } else { "off" });
+            }
+            Action::ToggleCapture => {
+                let result = if self.is_capturing() {
+                    self.stop_capture()
+                        .map(|frames| log::info!("Captured {} frames", frames))
+                } else {
+                    self.start_capture(self.settings.capture.clone())
+                };
+                if let Err(e) = result {
+                    log::error!("{:?}", e);
+                }
+            }
+            Action::Screenshot => match self.screenshot() {
+                Ok(path) => log::info!("Saved {:?}", path),
+                Err(e) => log::error!("{:?}", e),
+            },
+            Action::CycleCamera => self.cycle_camera(),
+            Action::ToggleLightGizmo => self.light_gizmo.enabled = !self.light_gizmo.enabled,
+            Action::ToggleGizmoMode => {
+                if !self.light_gizmo.enabled {
+                    return false;
+                }
+                self.light_gizmo.mode = match self.light_gizmo.mode {
+                    GizmoMode::Translate => GizmoMode::Aim,
+                    GizmoMode::Aim => GizmoMode::Translate,
+                };
+            }
+            Action::TogglePlacement => self.placement.enabled = !self.placement.enabled,
+            Action::ToggleGrid => {
+                self.placement.grid = match self.placement.grid {
+                    Some(_) => None,
+                    None => Some(1.0),
+                };
+            }
+            Action::ToggleMeasure => {
+                self.measure.enabled = !self.measure.enabled;
+                self.measure.clear();
+            }
+            Action::ToggleBindings => {
+                self.bindings_panel.enabled = !self.bindings_panel.enabled;
+                self.bindings_panel.cancel_rebind();
+            }
+            Action::ToggleInspector => {
+                self.show_inspector = !self.show_inspector;
+                if self.show_inspector {
+                    log::debug!("Scene: {}", self.inspect().to_json());
+                }
+            }
+            Action::ToggleConsole => self.console.toggle(),
+        }
+        return true;
+    }
+
+    /// Render the current view without the overlay and write it as a PNG
+    /// into `Settings::screenshot_directory`, returning the file's path.
+    pub fn screenshot(&mut self) -> anyhow::Result<std::path::PathBuf> {
+        let image = self.render_image(std::time::Duration::ZERO)?;
+        let directory = &self.settings.screenshot_directory;
+        std::fs::create_dir_all(directory).with_context(|| format!("Failed to create {:?}", directory))?;
+        let time = std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .unwrap_or_default();
+        let path = directory.join(format!("screenshot_{}.png", time.as_millis()));
+        image
+            .save(&path)
+            .with_context(|| format!("Failed to write {:?}", path))?;
+        return Ok(path);
+    }
+
+    // View through the next render target camera, the main camera after the
+    // last one
+    fn cycle_camera(&mut self) {
+        let next = self.view_target.map_or(0, |i| i + 1);
+        self.view_target = Some(next).filter(|i| *i < self.render_targets.len());
+        // Culling follows the view
+        self.instances_version += 1;
+        match self.view_target {
+            Some(i) => log::info!("Viewing through render target camera {}", i),
+            None => log::info!("Viewing through the main camera"),
+        }
+    }
+
+    /// Ray through a point of the window, in physical pixels.
+    pub fn screen_ray(&self, x: f32, y: f32) -> Ray {
+        return self.viewport.ray(x, y, self.camera_uniform.inv_view_proj());
+    }
+
+    /// Start reading back the depth of the last frame drawn at a point of the
+    /// window, in physical pixels. The result comes from `poll_depth` in a
+    /// later frame, without stalling on the GPU. False while an earlier
+    /// request is still in flight.
+    pub fn request_depth(&mut self, x: f32, y: f32) -> bool {
+        let [rx, ry] = self.viewport.window_to_render(x, y);
+        if !self.depth_probe.request(&self.device, &self.queue, rx.floor() as i32, ry.floor() as i32) {
+            return false;
+        }
+        self.depth_request = Some(([x, y], self.depth_inv_view_proj));
+        return true;
+    }
+
+    /// The depth and world position asked for with `request_depth`, once the
+    /// GPU has copied them.
+    pub fn poll_depth(&mut self) -> Option<DepthSample> {
+        if self.depth_probe.is_idle() {
+            return None;
+        }
+        self.device.poll(wgpu::Maintain::Poll);
+        let depths = self.depth_probe.poll();
+        if !self.depth_probe.is_idle() {
+            return None;
+        }
+        // A failed readback is logged by the probe and drops the request
+        let ([x, y], inv_view_proj) = self.depth_request.take()?;
+        let depth = DepthProbe::center_depth(&depths?);
+        return Some(DepthSample {
+            position: [x, y],
+            depth,
+            point: depth.map(|depth| self.depth_point(x, y, depth, inv_view_proj)),
+        });
+    }
+
+    // World position of a depth read at a window position, unprojected at
+    // the center of the render target pixel it was read from
+    fn depth_point(&self, x: f32, y: f32, depth: f32, inv_view_proj: Matrix4<f32>) -> Point3<f32> {
+        let [rx, ry] = self.viewport.window_to_render(x, y);
+        let [x, y] = self.viewport.render_to_window(rx.floor() + 0.5, ry.floor() + 0.5);
+        return self.viewport.unproject(x, y, depth, inv_view_proj);
+    }
+
+    /// Depth buffer value of the last frame drawn at a point of the window,
+    /// in physical pixels, read back from the GPU. `None` where nothing was
+    /// drawn. Blocks until the GPU is done with all submitted work, prefer
+    /// `request_depth` outside of one-off tools.
+    pub fn depth_at_blocking(&self, x: f32, y: f32) -> Option<f32> {
+        let [x, y] = self.viewport.window_to_render(x, y);
+        let result = self
+            .depth_probe
+            .depth_at_blocking(&self.device, &self.queue, x.floor() as i32, y.floor() as i32);
+        return match result {
+            Ok(depth) => depth,
+            Err(e) => {
+                log::warn!("Depth readback failed: {:?}", e);
+                None
+            }
+        };
+    }
+
+    /// World position of what was drawn last frame at a point of the window,
+    /// `depth_at_blocking` unprojected with the camera it was drawn from.
+    /// Unlike `pick` it hits the actual surfaces of any geometry, e.g. to
+    /// place objects on or focus the camera at. `DepthSample::point` gives
+    /// the same without blocking.
+    pub fn world_at_blocking(&self, x: f32, y: f32) -> Option<Point3<f32>> {
+        let depth = self.depth_at_blocking(x, y)?;
+        return Some(self.depth_point(x, y, depth, self.depth_inv_view_proj));
+    }
+
+    /// Closest instance or ground plane point under a point of the window.
+    pub fn pick(&self, x: f32, y: f32) -> Option<PlacementHit> {
+        return raycast(
+            &self.screen_ray(x, y),
+            &self.instances,
+            self.camera.render_layers,
+            self.obj_model.bounds(),
+            self.placement.ground_height,
+        );
+    }
+
+    // Send the instance under the cursor to the scene's picked handlers
+    fn pick_entity(&mut self) -> bool {
+        if !self.scene.has_picked_handlers() {
+            return false;
+        }
+        let hit = self.placement.cursor().and_then(|(x, y)| self.pick(x, y));
+        let (index, hit) = match hit {
+            Some(hit) => match hit.instance {
+                Some(index) => (index, hit),
+                None => return false,
+            },
+            None => return false,
+        };
+        let event = PickEvent {
+            entity: Entity::Instance(index),
+            point: hit.point,
+            distance: hit.distance,
+        };
+        Scene::run_picked(self, &event);
+        return true;
+    }
+
+    /// Instances covering any pixel of `rect`, read back from an ID pass of
+    /// the current frame's instances. Static geometry hides what's behind it.
+    pub fn pick_gpu(&mut self, rect: PickRect) -> Vec<InstanceId> {
+        let (x, y) = (rect.x as f32, rect.y as f32);
+        let corners = ((x, y), (x + rect.width as f32, y + rect.height as f32));
+        return match self.read_ids(corners) {
+            Some(image) => image.instances(|_, _| true),
+            None => Vec::new(),
+        };
+    }
+
+    /// Like `pick_gpu` for the pixels inside a lasso, in physical pixels.
+    pub fn pick_gpu_lasso(&mut self, lasso: &[(f32, f32)]) -> Vec<InstanceId> {
+        let bounds = PickRect::bounding(lasso).map(|r| {
+            let (x, y) = (r.x as f32, r.y as f32);
+            ((x, y), (x + r.width as f32, y + r.height as f32))
+        });
+        let image = match bounds.and_then(|corners| self.read_ids(corners)) {
+            Some(image) => image,
+            None => return Vec::new(),
+        };
+        let rect = self.viewport.rect;
+        return image.instances(|x, y| polygon_contains(lasso, rect.x + x as f32 + 0.5, rect.y + y as f32 + 0.5));
+    }
+
+    // Draw the uploaded instances and the static batch into the picker, one
+    // ID per window pixel of the viewport rect. Corners are window positions
+    fn read_ids(&mut self, corners: ((f32, f32), (f32, f32))) -> Option<IdImage> {
+        let viewport = self.viewport.rect;
+        let local = |(x, y): (f32, f32)| (x - viewport.x, y - viewport.y);
+        let (end_x, end_y) = local(corners.1);
+        if end_x <= 0.0 || end_y <= 0.0 {
+            return None;
+        }
+        let rect = PickRect::from_corners(local(corners.0), (end_x, end_y));
+        let size = (viewport.width.round() as u32, viewport.height.round() as u32);
+        // Like `lod_draws`, borrowing fields so the picker stays mutable
+        let instance_buffer = self.instance_buffers.current();
+        let models = std::iter::once(&self.obj_model).chain(self.lods.levels().iter().map(|l| &l.model));
+        let mut draws = models
+            .zip(self.lod_ranges.iter().cloned())
+            .filter(|(_, range)| !range.is_empty())
+            .map(|(model, range)| IdDraw {
+                model,
+                instances: range,
+                instance_buffer,
+                ids: Some(&self.instance_slots),
+            })
+            .collect_vec();
+        if let Some(batch) = &self.static_batch {
+            draws.push(IdDraw {
+                model: batch,
+                instances: 0..1,
+                instance_buffer: &self.static_instance,
+                ids: None,
+            });
+        }
+        let result = self.picker.read(
+            &self.device,
+            &self.memory,
+            &self.queue,
+            size,
+            &self.camera_bind_groups[self.camera_buffers.index()],
+            &draws,
+            rect,
+        );
+        return match result {
+            Ok(image) => Some(image),
+            Err(e) => {
+                log::warn!("GPU picking failed: {:?}", e);
+                None
+            }
+        };
+    }
+
+    pub fn spawn_instance(&mut self, instance: Instance) -> usize {
+        let instances = self.instances_mut();
+        instances.push(instance);
+        let index = instances.len() - 1;
+        self.events.publish(EngineEvent::InstanceSpawned(index));
+        return index;
+    }
+
+    /// Hide the instance at `index` on every layer. Instances are never
+    /// removed, so the indices of the others stay valid.
+    pub fn despawn_instance(&mut self, index: usize) {
+        if let Some(instance) = self.instances_mut().get_mut(index) {
+            instance.layers = RenderLayers::NONE;
+            self.events.publish(EngineEvent::InstanceDespawned(index));
+        }
+    }
+
+    // Spawn an instance where the placement ghost currently is
+    pub fn place(&mut self) -> Option<usize> {
+        let ghost = self.placement.ghost()?;
+        return Some(self.spawn_instance(ghost));
+    }
+
+    // Uniform uploaded for the current frame, including previous-frame matrices
+    pub fn camera_uniform(&self) -> &CameraUniform {
+        return &self.camera_uniform;
+    }
+
+    /// Discard scene geometry behind `plane`, e.g. for planar reflections or
+    /// section views. Applies from the next update.
+    pub fn set_clip_plane(&mut self, plane: Option<Plane>) {
+        self.clip_plane = plane;
+    }
+
+    pub fn clip_plane(&self) -> Option<Plane> {
+        return self.clip_plane;
+    }
+
+    /// Keep the camera a `ControllerSettings::collision_radius` sphere out of
+    /// `collider`, sliding along it when moved into it. `None` lets the
+    /// camera fly through everything again.
+    pub fn set_camera_collider(&mut self, collider: Option<MeshCollider>) {
+        self.simulator.input(ControllerEvent::Collide(collider.map(Arc::new)));
+    }
+
+    /// Zoom the camera smoothly to `fovy`, see `Projection::zoom_time`.
+    pub fn set_camera_fovy<F: Into<Rad<f32>>>(&mut self, fovy: F) {
+        self.simulator.input(ControllerEvent::Zoom(fovy.into()));
+    }
+
+    // Time accumulated over all updates, used to drive animations
+    pub fn elapsed(&self) -> std::time::Duration {
+        return self.elapsed;
+    }
+
+    // True if the next frame would differ from the last one without new input
+    pub fn is_animating(&self) -> bool {
+        return self.camera_moving
+            || self.camera_uniform.view_proj() != self.camera_uniform.prev_view_proj()
+            || self.obj_model.materials.iter().any(|m| m.flipbook.is_some())
+            || (self.settings.passes.lens_flare && self.flare.is_fading())
+            || (self.settings.passes.trails && self.trails.is_active())
+            || !self.pipelines.is_complete();
+    }
+
+    pub fn instances(&self) -> &[Instance] {
+        return &self.instances;
+    }
+
+    pub fn lods(&self) -> &Lods {
+        return &self.lods;
+    }
+
+    // Marks instances as changed, as LOD levels are selected on upload
+    pub fn lods_mut(&mut self) -> &mut Lods {
+        self.instances_version += 1;
+        return &mut self.lods;
+    }
+
+    /// Luminance histogram of a recent frame, read back a few frames late
+    /// while `PassSettings::luminance` is enabled.
+    pub fn scene_luminance(&self) -> Option<&SceneLuminance> {
+        return self.luminance.latest();
+    }
+
+    pub fn static_batch(&self) -> Option<&Model> {
+        return self.static_batch.as_ref();
+    }
+
+    /// Replace the merged level geometry, see `StaticBatcher`. Render target
+    /// cameras drawing into the old batch's materials are removed.
+    pub fn set_static_batch(&mut self, batch: Option<Model>) {
+        self.static_batch = batch;
+        self.lightmap = None;
+        self.render_targets
+            .retain(|t| !matches!(t.material(), TargetMaterial::StaticBatch(_)));
+    }
+
+    /// Bake light into the static batch, which must have been built with
+    /// `StaticBatcher::build_lightmapped`. Lighting bakes use the current
+    /// directional lights. Blocks until the GPU has drawn every direction.
+    pub fn bake_lightmap(&mut self, settings: &BakeSettings) -> anyhow::Result<()> {
+        let mesh = self
+            .static_batch
+            .iter()
+            .flat_map(|batch| &batch.meshes)
+            .find(|mesh| mesh.lightmap_uvs.is_some())
+            .ok_or_else(|| anyhow::anyhow!("No lightmapped static batch to bake"))?;
+        let lights = self.light_manager.directional_lights().cloned().collect_vec();
+        let start = std::time::Instant::now();
+        let lightmap = Lightmap::bake(
+            &self.device,
+            &self.queue,
+            &self.memory,
+            &self.lightmap_bind_group_layout,
+            mesh,
+            &lights,
+            settings,
+        )?;
+        self.device.poll(wgpu::Maintain::Wait);
+        log::info!("Baked {:?} lightmap in {:?}", settings.mode, start.elapsed());
+        self.lightmap = Some(lightmap);
+        return Ok(());
+    }
+
+    pub fn lightmap(&self) -> Option<&Lightmap> {
+        return self.lightmap.as_ref();
+    }
+
+    /// Drop the baked light, the static batch is lit dynamically again.
+    pub fn clear_lightmap(&mut self) {
+        self.lightmap = None;
+    }
+
+    pub fn portals(&self) -> Option<&PortalGraph> {
+        return self.portals.as_ref();
+    }
+
+    /// Set to cull instances in rooms not visible through portals from the
+    /// camera's room. Culled instances still cast shadows.
+    pub fn portals_mut(&mut self) -> &mut Option<PortalGraph> {
+        self.instances_version += 1;
+        return &mut self.portals;
+    }
+
+    // Marks instances as changed; they are uploaded on the next update
+    pub fn instances_mut(&mut self) -> &mut Vec<Instance> {
+        self.instances_version += 1;
+        return &mut self.instances;
+    }
+
+    pub fn update(&mut self, dt: std::time::Duration) {
+        let update_start = std::time::Instant::now();
+        self.apply_resize();
+        self.sync_viewport();
+        self.pipelines.poll();
+        self.poll_loading();
+        self.frame_count += 1;
+        self.elapsed += dt;
+        self.instance_buffers.advance();
+        self.camera_buffers.advance();
+        self.scene_buffers.advance();
+        if !self.luminance.is_idle() {
+            self.device.poll(wgpu::Maintain::Poll);
+            self.luminance.poll();
+        }
+        #[cfg(feature = "virtual-texturing")]
+        if let Some(terrain) = &mut self.virtual_terrain {
+            let size = self.viewport.render;
+            terrain.update(&self.device, &self.memory, &self.queue, size);
+        }
+
+        // Update camera and simulated lights
+        if let Some(state) = self.simulator.step(dt) {
+            self.apply_scene_state(state);
+        }
+        EventBus::dispatch(self);
+        Scene::run_update(self, dt);
+        let uniform = match self.view_target.and_then(|i| self.render_targets.get(i)) {
+            Some(target) => CameraUniform::new(
+                target.eye,
+                self.camera.projection().calc_matrix() * target.view_matrix(),
+            ),
+            None => self.camera.uniform(),
+        };
+        // In a headset the head looks around from the camera's position
+        #[cfg(feature = "xr")]
+        let uniform = match &self.xr_frame {
+            Some(frame) => {
+                let projection = self.camera.projection();
+                let origin = Matrix4::from_translation(self.camera.position.to_vec());
+                frame.head_view().uniform(&origin, projection.znear(), projection.zfar())
+            }
+            None => uniform,
+        };
+        self.camera_uniform = uniform
+            .with_previous(&self.camera_uniform)
+            .with_clip_plane(self.clip_plane);
+        self.camera_buffers.write(
+            &self.device,
+            &self.memory,
+            &self.queue,
+            bytemuck::cast_slice(&[self.camera_uniform]),
+            self.frame_count,
+        );
+        let scene_uniform = SceneUniform::new(
+            self.elapsed.as_secs_f32(),
+            dt.as_secs_f32(),
+            self.viewport.render,
+            &self.settings.atmosphere,
+            self.light_manager.directional_lights().next(),
+        );
+        self.scene_buffers.write(
+            &self.device,
+            &self.memory,
+            &self.queue,
+            bytemuck::cast_slice(&[scene_uniform]),
+            self.frame_count,
+        );
+        self.background.update(&self.queue, self.settings.background);
+        self.atmosphere.set_aerial_perspective(&self.queue, self.settings.atmosphere.scattering);
+
+        if self.settings.passes.volumetric {
+            self.volumetric.update(&self.queue, &self.light_manager);
+        }
+
+        // Follow the brightest light with the lens flare
+        if self.flare.enabled && self.settings.passes.lens_flare {
+            let eye = self.camera.position.to_vec();
+            let directional = self
+                .light_manager
+                .directional_lights()
+                .map(FlareSource::from_directional);
+            let positional = self
+                .light_manager
+                .lights()
+                .filter_map(|(_, light)| FlareSource::from_positional(light, eye));
+            let sources = directional.chain(positional).collect_vec();
+            self.flare.update(
+                &self.queue,
+                dt.as_secs_f32(),
+                self.camera_uniform.view_proj(),
+                self.camera.projection().aspect(),
+                sources,
+            );
+        }
+
+        // Update placement preview
+        let hit = self.placement.cursor().and_then(|(x, y)| self.pick(x, y));
+        if self.placement.update(hit, self.obj_model.bounds()) {
+            self.instances_version += 1;
+        }
+
+        if self.camera.render_layers != self.instance_layers {
+            self.instance_layers = self.camera.render_layers;
+            self.instances_version += 1;
+        }
+
+        // LOD levels, fades and front to back order follow the camera,
+        // visible rooms its view
+        let sort_front_to_back = self.settings.sort_front_to_back;
+        if sort_front_to_back != self.sorted_front_to_back {
+            self.sorted_front_to_back = sort_front_to_back;
+            self.instances_version += 1;
+        }
+        if (!self.lods.is_empty() || sort_front_to_back) && self.camera.position != self.lod_eye {
+            self.lod_eye = self.camera.position;
+            self.instances_version += 1;
+        }
+        let view_proj = self.camera_uniform.view_proj();
+        if self.portals.is_some() && view_proj != self.camera_uniform.prev_view_proj() {
+            self.instances_version += 1;
+        }
+
+        // Update instances grouped by LOD level, the ghost goes after the
+        // visible instances. Cross-fading instances are in two levels
+        if !self.instance_buffers.is_up_to_date(self.instances_version) {
+            let layers = self.instance_layers;
+            let eye = self.camera.position.to_vec();
+            let bounds = self.obj_model.bounds();
+            let frustum = Frustum::from_view_proj(&view_proj);
+            let rooms = self
+                .portals
+                .as_ref()
+                .and_then(|portals| Some((portals, portals.visible_rooms(eye, &frustum)?)));
+            // Instances are seen from every room their bounds reach into,
+            // those outside of all rooms are only frustum culled
+            let in_view = |instance: &Instance| match (rooms.as_ref(), bounds) {
+                (Some((portals, visibility)), Some(bounds)) => {
+                    let bounds = bounds.transform(&instance.model_matrix());
+                    let overlapping = portals.rooms_overlapping(&bounds).collect_vec();
+                    if overlapping.is_empty() {
+                        frustum.intersects_aabb(&bounds)
+                    } else {
+                        overlapping.into_iter().any(|room| visibility.is_visible(room, &bounds))
+                    }
+                }
+                _ => true,
+            };
+            self.instance_bounds = bounds.and_then(|bounds| {
+                self.instances
+                    .iter()
+                    .filter(|i| i.layers.intersects(layers))
+                    .map(|i| bounds.transform(&i.model_matrix()))
+                    .reduce(|a, b| a.union(&b))
+            });
+            let mut levels = vec![Vec::new(); self.lods.level_count()];
+            let mut hidden_levels = vec![Vec::new(); self.lods.level_count()];
+            let matching = self.instances.iter().enumerate().filter(|(_, i)| i.layers.intersects(layers));
+            for (index, instance) in matching {
+                let selection = self.lods.select((instance.position - eye).magnitude());
+                let target = if in_view(instance) { &mut levels } else { &mut hidden_levels };
+                for (level, fade) in selection.fades().into_iter().flatten() {
+                    target[level].push((index as u32, instance, fade));
+                }
+            }
+            for level in &mut levels {
+                if sort_front_to_back {
+                    sort_front_to_back_from(eye, level, |(_, instance, _)| instance);
+                }
+                level.sort_by_key(|(_, instance, _)| !instance.outline);
+            }
+            self.overdraw = match bounds {
+                Some(bounds) => {
+                    let drawn = levels.iter().flatten().map(|(_, i, _)| bounds.transform(&i.model_matrix()));
+                    OverdrawEstimate::from_bounds(&view_proj, drawn)
+                }
+                None => OverdrawEstimate::default(),
+            };
+            let mut start = 0;
+            self.lod_ranges = levels
+                .iter()
+                .map(|level| {
+                    let range = start..start + level.len() as u32;
+                    start = range.end;
+                    range
+                })
+                .collect();
+            self.outline_ranges = levels
+                .iter()
+                .zip(&self.lod_ranges)
+                .map(|(level, range)| {
+                    let outlined = level.iter().take_while(|(_, instance, _)| instance.outline).count();
+                    range.start..range.start + outlined as u32
+                })
+                .collect();
+            self.visible_instances = start;
+            self.instance_slots = levels.iter().flatten().map(|(index, _, _)| *index).collect();
+            let ghost = self.placement.ghost();
+            let mut start = start + ghost.is_some() as u32;
+            self.hidden_ranges = hidden_levels
+                .iter()
+                .map(|level| {
+                    let range = start..start + level.len() as u32;
+                    start = range.end;
+                    range
+                })
+                .collect();
+            let instances = levels.into_iter().flatten().map(|(_, instance, fade)| (instance, fade));
+            let hidden = hidden_levels.into_iter().flatten().map(|(_, instance, fade)| (instance, fade));
+            let instance_data = self
+                .model_instance_format
+                .encode_faded(instances.chain(ghost.iter().map(|g| (g, 1.0))).chain(hidden));
+            self.instance_buffers.write(
+                &self.device,
+                &self.memory,
+                &self.queue,
+                &instance_data,
+                self.instances_version,
+            );
+        }
+
+        // Update render target cameras, each culling and selecting LOD levels
+        // for its own view. Disabled ones still advance with the scene buffers
+        let mut targets = std::mem::take(&mut self.render_targets);
+        for target in &mut targets {
+            if !target.enabled {
+                target.update(&self.device, &self.memory, &self.queue, &[], Vec::new(), self.frame_count);
+                continue;
+            }
+            let eye = target.eye.to_vec();
+            let frustum = target.frustum();
+            let bounds = self.obj_model.bounds();
+            let mut levels = vec![Vec::new(); self.lods.level_count()];
+            let visible = self.instances.iter().filter(|i| {
+                let in_view = bounds.is_none_or(|b| frustum.intersects_aabb(&b.transform(&i.model_matrix())));
+                i.layers.intersects(target.render_layers) && in_view
+            });
+            for instance in visible {
+                let selection = self.lods.select((instance.position - eye).magnitude());
+                for (level, fade) in selection.fades().into_iter().flatten() {
+                    levels[level].push((instance, fade));
+                }
+            }
+            if self.settings.sort_front_to_back {
+                for level in &mut levels {
+                    sort_front_to_back_from(eye, level, |(instance, _)| instance);
+                }
+            }
+            let mut start = 0;
+            let lod_ranges = levels
+                .iter()
+                .map(|level| {
+                    let range = start..start + level.len() as u32;
+                    start = range.end;
+                    range
+                })
+                .collect();
+            let instance_data = self.model_instance_format.encode_faded(levels.into_iter().flatten());
+            target.update(
+                &self.device,
+                &self.memory,
+                &self.queue,
+                &instance_data,
+                lod_ranges,
+                self.frame_count,
+            );
+        }
+        self.render_targets = targets;
+
+        // Update crowds, recording the dispatches of GPU-moved ones to submit
+        // ahead of the frame
+        let mut motion_encoder = self.device.create_command_encoder(&wgpu::CommandEncoderDescriptor {
+            label: Some("Motion Encoder"),
+        });
+        for crowd in &mut self.crowds {
+            crowd.update(
+                &self.device,
+                &self.memory,
+                &self.queue,
+                dt.as_secs_f32(),
+                self.camera.render_layers,
+                self.motion_kernel.as_ref().map(|k| (k, &mut motion_encoder)),
+                self.frame_count,
+            );
+        }
+        for crowd in &mut self.vat_crowds {
+            crowd.update(
+                &self.device,
+                &self.memory,
+                &self.queue,
+                dt.as_secs_f32(),
+                self.camera.render_layers,
+                self.motion_kernel.as_ref().map(|k| (k, &mut motion_encoder)),
+                self.frame_count,
+            );
+        }
+        self.queue.submit(std::iter::once(motion_encoder.finish()));
+
+        self.light_gizmo.update(
+            &self.device,
+            &self.memory,
+            &self.queue,
+            &self.light_manager,
+            self.frame_count,
+        );
+        if self.settings.passes.particles {
+            self.particles.update(
+                &self.device,
+                &self.memory,
+                &self.queue,
+                dt.as_secs_f32(),
+                self.camera.position,
+                self.frame_count,
+            );
+        }
+        if self.settings.passes.trails {
+            self.trails.update(
+                &self.device,
+                &self.memory,
+                &self.queue,
+                dt.as_secs_f32(),
+                self.camera.position,
+                &self.instances,
+                self.frame_count,
+            );
+        }
+        if self.settings.passes.point_clouds {
+            let projection = self.camera.projection().calc_matrix();
+            self.point_clouds.update(&self.queue, &projection, self.viewport.render[1]);
+        }
+
+        // Update materials
+        self.post.update(&self.queue, self.settings.display);
+
+        // Textures of what was drawn last frame are decoded first
+        for (model, _) in self.lod_draws() {
+            for slot in model.materials.iter().flat_map(|m| &m.pending_textures) {
+                slot.prioritize();
+            }
+        }
+        let time = self.elapsed.as_secs_f32();
+        let crowd_materials = self.crowds.iter_mut().flat_map(|c| &mut c.model.materials);
+        let vat_materials = self.vat_crowds.iter_mut().flat_map(|c| &mut c.model.materials);
+        let lod_materials = self.lods.levels_mut().iter_mut().flat_map(|l| &mut l.model.materials);
+        let static_materials = self.static_batch.iter_mut().flat_map(|m| &mut m.materials);
+        let materials = self
+            .obj_model
+            .materials
+            .iter_mut()
+            .chain(lod_materials)
+            .chain(static_materials);
+        for material in materials.chain(crowd_materials).chain(vat_materials) {
+            material.update(&self.queue, time);
+            poll_pending_textures(
+                material,
+                &self.device,
+                &self.memory,
+                &self.queue,
+                &self.texture_bind_group_layout,
+            );
+            let reloaded = reload_missing_textures(
+                material,
+                &self.device,
+                &self.memory,
+                &self.queue,
+                &self.texture_bind_group_layout,
+            );
+            if reloaded {
+                self.events.publish(EngineEvent::AssetReloaded {
+                    material: material.name.clone(),
+                });
+            }
+        }
+        self.hud.record(FrameStage::Update, update_start.elapsed());
+    }
+
+    fn apply_scene_state(&mut self, state: SceneState) {
+        self.camera.set_pose(state.camera);
+        self.camera_moving = state.camera_moving;
+        for (id, light) in state.lights {
+            self.light_manager.set_light(&self.queue, id.index, light);
+            self.events.publish(EngineEvent::LightChanged(id));
+        }
+    }
+
+    // Draw calls and instances submitted by `encode_frame`
+    fn draw_counts(&self) -> (u32, u32) {
+        let passes = self.settings.passes;
+        let mut draws = 0;
+        let mut instances = 0;
+        let shadow_maps = self.light_manager.shadows.tiles().len();
+        for (model, range) in self.lod_draws().filter(|_| passes.models) {
+            draws += model.meshes.len() * shadow_maps + model.draw_count();
+            instances += range.len() as u32 * (shadow_maps as u32 + 1);
+        }
+        if let Some(batch) = self.static_batch.as_ref().filter(|_| passes.models) {
+            draws += batch.meshes.len() * shadow_maps + batch.draw_count();
+            instances += shadow_maps as u32 + 1;
+        }
+        let crowds = self.crowds.iter().filter(|c| passes.crowds && !c.instance_range().is_empty());
+        for crowd in crowds {
+            draws += crowd.model.draw_count();
+            instances += crowd.instance_range().len() as u32;
+        }
+        let vat_crowds = self.vat_crowds.iter().filter(|c| passes.crowds && !c.instance_range().is_empty());
+        for crowd in vat_crowds {
+            draws += crowd.model.draw_count();
+            instances += crowd.instance_range().len() as u32;
+        }
+        if passes.models && self.pipelines.is_ready(self.outline_pipeline) {
+            for (model, range) in self.outline_draws() {
+                draws += model.draw_count();
+                instances += range.len() as u32;
+            }
+        }
+        if passes.models && self.placement.ghost().is_some() && self.pipelines.is_ready(self.ghost_pipeline) {
+            draws += self.obj_model.draw_count();
+            instances += 1;
+        }
+        if self.light_gizmo.enabled {
+            draws += 1;
+        }
+        if self.background.is_drawn() {
+            draws += 1;
+        }
+        if passes.particles && self.particles.particle_count() > 0 {
+            draws += 1;
+            instances += self.particles.particle_count();
+        }
+        if passes.trails {
+            draws += self.trails.draw_count();
+            instances += self.trails.draw_count() as u32;
+        }
+        if passes.point_clouds {
+            let clouds = self.point_clouds.clouds.iter().filter(|c| c.visible && c.point_count() > 0);
+            draws += clouds.count();
+            instances += self.point_clouds.point_count();
+        }
+        if passes.volumetric && self.volumetric.is_active() {
+            draws += 1;
+        }
+        // Post pass, plus the flare and overlay passes when enabled
+        draws += 1 + passes.lens_flare as usize + passes.overlay as usize;
+        return (draws as u32, instances);
+    }
+
+    // World bounds of the instances and the static batch
+    fn scene_bounds(&self) -> Option<Aabb> {
+        let batch = self.static_batch.as_ref().and_then(Model::bounds);
+        return match (self.instance_bounds, batch) {
+            (Some(a), Some(b)) => Some(a.union(&b)),
+            (a, b) => a.or(b),
+        };
+    }
+
+    // Model and uploaded instances of each LOD level with instances
+    fn lod_draws(&self) -> impl Iterator<Item = (&Model, Range<u32>)> {
+        let models = std::iter::once(&self.obj_model).chain(self.lods.levels().iter().map(|l| &l.model));
+        return models
+            .zip(self.lod_ranges.iter().cloned())
+            .filter(|(_, range)| !range.is_empty());
+    }
+
+    // `lod_draws` and the instances culled by portals, which still cast shadows
+    fn shadow_draws(&self) -> impl Iterator<Item = (&Model, Range<u32>)> {
+        let models = std::iter::once(&self.obj_model).chain(self.lods.levels().iter().map(|l| &l.model));
+        let hidden = models
+            .zip(self.hidden_ranges.iter().cloned())
+            .filter(|(_, range)| !range.is_empty());
+        return self.lod_draws().chain(hidden);
+    }
+
+    // Model of each LOD level with the indices in `instances` of what it
+    // drew last frame
+    pub(crate) fn visible_models(&self) -> impl Iterator<Item = (&Model, &[u32])> {
+        return self
+            .lod_draws()
+            .map(|(model, range)| (model, &self.instance_slots[range.start as usize..range.end as usize]));
+    }
+
+    // Model and outlined instances of each LOD level with outlined instances
+    fn outline_draws(&self) -> impl Iterator<Item = (&Model, Range<u32>)> {
+        let models = std::iter::once(&self.obj_model).chain(self.lods.levels().iter().map(|l| &l.model));
+        return models
+            .zip(self.outline_ranges.iter().cloned())
+            .filter(|(_, range)| !range.is_empty());
+    }
+
+    fn debug_label(&self, label: &'static str) -> Option<&'static str> {
+        return self.debug_labels.then_some(label);
+    }
+
+    // Shadow map frustums in orange and render target cameras in cyan
+    fn draw_frustums(&mut self) {
+        let view_proj = self.camera_uniform.view_proj();
+        let viewport = self.viewport;
+        for shadow in self.light_manager.shadows.view_projs() {
+            self.overlay
+                .frustum(&viewport, &view_proj, shadow, [1.0, 0.6, 0.1, 1.0], 1.5);
+        }
+        for target in self.render_targets.iter().filter(|t| t.enabled) {
+            let target_view_proj = target.uniform().view_proj();
+            self.overlay
+                .frustum(&viewport, &view_proj, &target_view_proj, [0.2, 0.9, 1.0, 1.0], 1.5);
+        }
+    }
+
+    // Placement grid, measurement and the size of the selected instances
+    fn draw_editor_tools(&mut self) {
+        let view_proj = self.camera_uniform.view_proj();
+        let viewport = self.viewport;
+        self.placement.draw_grid(&mut self.overlay, &viewport, &view_proj);
+        if !self.measure.enabled {
+            return;
+        }
+        let hover = self.placement.cursor().and_then(|(x, y)| self.pick(x, y));
+        self.measure
+            .draw(&mut self.overlay, &viewport, &view_proj, hover.map(|hit| hit.point));
+        if let Some(bounds) = self.selection_bounds() {
+            draw_bounds_size(&mut self.overlay, &viewport, &view_proj, &bounds);
+        }
+    }
+
+    /// World bounds of the selected (outlined) instances.
+    pub fn selection_bounds(&self) -> Option<Aabb> {
+        let bounds = self.obj_model.bounds()?;
+        return self
+            .instances
+            .iter()
+            .filter(|i| i.outline)
+            .map(|i| bounds.transform(&i.model_matrix()))
+            .reduce(|a, b| a.union(&b));
+    }
+
+    /// Draw a frame to the window. Headless renderers have no surface to
+    /// present to and fail with `SurfaceError::Lost`, see `render_image`.
+    pub fn render(&mut self) -> Result<(), wgpu::SurfaceError> {
+        // Zero sized surfaces can't be configured or acquired
+        if self.minimized {
+            return Ok(());
+        }
+        self.apply_resize();
+        let surface = self.surface.as_ref().ok_or(wgpu::SurfaceError::Lost)?;
+        self.pacer.wait(&self.device, self.settings.frame.frames_in_flight);
+        let acquire_start = std::time::Instant::now();
+        let output = surface.get_current_texture()?;
+        let encode_start = std::time::Instant::now();
+
+        let shadows = self.settings.passes.shadows && self.pipelines.is_ready(self.shadow_pipelines.back);
+        let shadow_view = ShadowView {
+            position: self.camera.position,
+            inv_view_proj: self.camera_uniform.inv_view_proj(),
+            scene_bounds: self.scene_bounds(),
+        };
+        self.light_manager.update_shadows(&self.queue, &shadow_view, shadows);
+        self.depth_inv_view_proj = self.camera_uniform.inv_view_proj();
+        let (draws, instances) = self.draw_counts();
+        self.hud.set_counts(draws, instances);
+        self.hud.set_overdraw(self.overdraw);
+        if self.settings.passes.overlay {
+            self.overlay.set_scale(self.ui_scale().factor());
+            self.hud.draw(&mut self.overlay, [10.0, 10.0]);
+            if self.show_frustums {
+                self.draw_frustums();
+            }
+            self.draw_editor_tools();
+            let position = [self.logical_size()[0] - BindingsPanel::WIDTH - 10.0, 10.0];
+            self.bindings_panel.draw(
+                &mut self.overlay,
+                position,
+                &self.settings.actions,
+                &self.settings.controller.keys,
+            );
+            if self.show_inspector {
+                let mut position = [self.logical_size()[0] - SceneInspector::WIDTH - 10.0, 10.0];
+                if self.bindings_panel.enabled {
+                    position[1] += self.bindings_panel.height() + 10.0;
+                }
+                self.inspect().draw(&mut self.overlay, position, 8);
+            }
+            let width = self.logical_size()[0];
+            self.console.draw(&mut self.overlay, width);
+            if let Some(map) = self.environment_view {
+                let [width, height] = self.logical_size();
+                let face_size = (width * 0.1).min(height * 0.13).min(128.0).floor();
+                let position = [width - face_size * 4.0 - 10.0, height - face_size * 3.0 - 10.0];
+                let view = self.environment.cube_view(map);
+                self.overlay
+                    .cubemap(&self.device, &view, position, face_size, 1.0);
+            }
+            let (ready, total) = self.pipelines.progress();
+            if ready < total && self.loading == LoadingState::Running {
+                let text = format!("Compiling pipelines {}/{}", ready, total);
+                let position = [10.0, self.logical_size()[1] - 26.0];
+                self.overlay.text(position, &text, 16.0, [1.0, 1.0, 1.0, 1.0]);
+            }
+        }
+        let loading = self.loading == LoadingState::Loading;
+        if loading {
+            let (finished, total) = self.loader.progress();
+            let size = self.logical_size();
+            self.overlay.set_scale(self.ui_scale().factor());
+            draw_loading_screen(&mut self.overlay, size, finished, total);
+        }
+        self.overlay.prepare(
+            &self.device,
+            &self.memory,
+            &self.queue,
+            self.config.width,
+            self.config.height,
+        );
+        let view = output
+            .texture
+            .create_view(&wgpu::TextureViewDescriptor::default());
+        let mut encoder = self
+            .device
+            .create_command_encoder(&wgpu::CommandEncoderDescriptor {
+                label: Some("Render Encoder"),
+            });
+        if loading {
+            self.encode_loading_screen(&mut encoder, &view);
+        } else {
+            self.encode_frame(&mut encoder, &view, Some(self.viewport.rect));
+        }
+        if self.settings.passes.luminance && !loading {
+            self.luminance.encode(
+                &self.device,
+                &self.queue,
+                &mut encoder,
+                &self.post.scene_texture,
+                self.viewport.render[0],
+                self.viewport.render[1],
+                self.frame_count,
+            );
+        }
+        #[cfg(feature = "virtual-texturing")]
+        if let (Some(terrain), false) = (&mut self.virtual_terrain, loading) {
+            terrain.encode_feedback(&mut encoder, &self.camera_bind_groups[self.camera_buffers.index()]);
+        }
+        let command_buffer = encoder.finish();
+
+        let present_start = std::time::Instant::now();
+        self.queue.submit(std::iter::once(command_buffer));
+        self.pacer.submitted(&self.queue);
+        self.luminance.map();
+        #[cfg(feature = "virtual-texturing")]
+        if let Some(terrain) = &mut self.virtual_terrain {
+            terrain.map_feedback();
+        }
+        output.present();
+
+        self.hud.record(FrameStage::Encode, present_start - encode_start);
+        self.hud.record(FrameStage::Present, encode_start - acquire_start + present_start.elapsed());
+        self.hud.end_frame();
+
+        Ok(())
+    }
+
+    // The overlay's progress screen over the background color, while the
+    // scene's assets load
+    fn encode_loading_screen(&self, encoder: &mut wgpu::CommandEncoder, target: &wgpu::TextureView) {
+        encoder.begin_render_pass(&wgpu::RenderPassDescriptor {
+            label: Some("Loading Screen Pass"),
+            color_attachments: &[Some(wgpu::RenderPassColorAttachment {
+                view: target,
+                resolve_target: None,
+                ops: wgpu::Operations {
+                    load: wgpu::LoadOp::Clear(self.background.clear_color()),
+                    store: true,
+                },
+            })],
+            depth_stencil_attachment: None,
+        });
+        self.overlay.render(encoder, target);
+    }
+
+    // Scene and post passes, ending in `target`
+    // Scene depth from each shadowed light into its tile of the atlas. Crowds
+    // don't cast shadows yet
+    fn encode_shadows(&self, encoder: &mut wgpu::CommandEncoder) {
+        let shadows = &self.light_manager.shadows;
+        let mut render_pass = encoder.begin_render_pass(&wgpu::RenderPassDescriptor {
+            label: Some("Shadow Pass"),
+            color_attachments: &[],
+            depth_stencil_attachment: Some(wgpu::RenderPassDepthStencilAttachment {
+                view: &shadows.atlas.view,
+                depth_ops: Some(wgpu::Operations {
+                    load: wgpu::LoadOp::Clear(1.0),
+                    store: true,
+                }),
+                stencil_ops: None,
+            }),
+        });
+        let pipeline = match self.pipelines.get(self.shadow_pipelines.back) {
+            Some(pipeline) if self.settings.passes.models => pipeline,
+            _ => return,
+        };
+        render_pass.set_pipeline(pipeline);
+        let mut cull_mode = CullMode::Back;
+        let static_draws = self.static_batch.iter().map(|batch| (batch, 0..1));
+        let draws = self
+            .shadow_draws()
+            .map(|draw| (draw, self.instance_buffers.current()))
+            .chain(static_draws.map(|draw| (draw, &self.static_instance)))
+            .collect_vec();
+        for (i, tile) in shadows.tiles().iter().enumerate() {
+            let size = tile.size as f32;
+            render_pass.set_viewport(tile.x as f32, tile.y as f32, size, size, 0.0, 1.0);
+            render_pass.set_scissor_rect(tile.x, tile.y, tile.size, tile.size);
+            render_pass.set_bind_group(0, &shadows.pass_bind_group, &[shadows.pass_offset(i)]);
+            for ((model, range), instance_buffer) in &draws {
+                render_pass.set_vertex_buffer(1, instance_buffer.slice(..));
+                for mesh in &model.meshes {
+                    render_pass.set_vertex_buffer(0, mesh.vertex_buffer.slice(..));
+                    render_pass.set_index_buffer(mesh.index_buffer.slice(..), wgpu::IndexFormat::Uint32);
+                    // One draw unless the mesh's materials disagree on culling
+                    let mode = |submesh: &Submesh| model.materials[submesh.material].cull_mode;
+                    if mesh.submeshes.iter().map(mode).all_equal() {
+                        let mesh_mode = mesh.submeshes.first().map_or(CullMode::Back, mode);
+                        self.set_cull_mode(&mut render_pass, &self.shadow_pipelines, &mut cull_mode, mesh_mode);
+                        render_pass.draw_indexed(0..mesh.num_elements, 0, range.clone());
+                        continue;
+                    }
+                    for submesh in &mesh.submeshes {
+                        self.set_cull_mode(&mut render_pass, &self.shadow_pipelines, &mut cull_mode, mode(submesh));
+                        render_pass.draw_indexed(submesh.indices.clone(), 0, range.clone());
+                    }
+                }
+            }
+        }
+    }
+
+    // Switch to the variant of `variants` for `mode` unless `current` is it already
+    fn set_cull_mode<'a>(
+        &'a self,
+        render_pass: &mut wgpu::RenderPass<'a>,
+        variants: &CullPipelines,
+        current: &mut CullMode,
+        mode: CullMode,
+    ) {
+        if *current == mode {
+            return;
+        }
+        *current = mode;
+        if let Some(pipeline) = self.pipelines.get(variants.get(mode)) {
+            render_pass.set_pipeline(pipeline);
+        }
+    }
+
+    // Bind the lightmap of a static batch mesh that has one and switch to the
+    // pipelines sampling it, in the current cull mode
+    fn bind_lightmap<'a>(
+        &'a self,
+        render_pass: &mut wgpu::RenderPass<'a>,
+        mesh: &'a Mesh,
+        cull_mode: CullMode,
+    ) -> &'a CullPipelines {
+        let variants = match (&self.lightmap, &mesh.lightmap_uvs) {
+            (Some(lightmap), Some(uvs)) => {
+                render_pass.set_vertex_buffer(2, uvs.buffer.slice(..));
+                render_pass.set_bind_group(3, &lightmap.bind_group, &[]);
+                &self.lightmap_pipelines
+            }
+            _ => &self.render_pipelines,
+        };
+        if let Some(pipeline) = self.pipelines.get(variants.get(cull_mode)) {
+            render_pass.set_pipeline(pipeline);
+        }
+        return variants;
+    }
+
+    // Every submesh of `mesh` with its material from `materials`, in the
+    // material's cull mode. Transparent materials are left to the OIT pass
+    // with `skip_transparent`
+    #[allow(clippy::too_many_arguments)]
+    fn draw_mesh_culled<'a>(
+        &'a self,
+        render_pass: &mut wgpu::RenderPass<'a>,
+        variants: &CullPipelines,
+        current: &mut CullMode,
+        mesh: &'a Mesh,
+        materials: &'a [Material],
+        instances: Range<u32>,
+        camera_bind_group: &'a wgpu::BindGroup,
+        skip_transparent: bool,
+    ) {
+        for submesh in &mesh.submeshes {
+            let material = &materials[submesh.material];
+            if skip_transparent && material.is_transparent() {
+                continue;
+            }
+            self.set_cull_mode(render_pass, variants, current, material.cull_mode);
+            render_pass.draw_submesh_instanced(
+                mesh,
+                submesh,
+                material,
+                instances.clone(),
+                camera_bind_group,
+                &self.light_manager.light_bind_group,
+            );
+        }
+    }
+
+    // Whether the OIT pass draws anything this frame
+    fn has_transparency(&self) -> bool {
+        let passes = self.settings.passes;
+        let transparent = |model: &Model| model.materials.iter().any(|m| m.is_transparent());
+        let models = passes.models
+            && self.pipelines.is_ready(self.oit_pipelines.back)
+            && (self.lod_draws().any(|(model, _)| transparent(model))
+                || self.static_batch.as_ref().is_some_and(transparent));
+        let particles = passes.particles && self.particles.order_independent && self.particles.particle_count() > 0;
+        return passes.transparency && (models || particles);
+    }
+
+    // Transparent materials of the scene model and static batch and order
+    // independent particles, composited over the resolved scene color
+    fn encode_transparency(&self, encoder: &mut wgpu::CommandEncoder) {
+        let passes = self.settings.passes;
+        let camera_bind_group = &self.camera_bind_groups[self.camera_buffers.index()];
+        {
+            let mut render_pass =
+                self.oit
+                    .begin_pass(encoder, &self.depth_texture.view, self.depth_format.has_stencil());
+            let model_pipeline = self.pipelines.get(self.oit_pipelines.back).filter(|_| passes.models);
+            if let Some(pipeline) = model_pipeline {
+                render_pass.set_pipeline(pipeline);
+                let mut cull_mode = CullMode::Back;
+                // The static batch is lit without its lightmap
+                let lods = self
+                    .lod_draws()
+                    .map(|(model, range)| (model, range, self.instance_buffers.current()));
+                let batch = self.static_batch.iter().map(|batch| (batch, 0..1, &self.static_instance));
+                for (model, range, instance_buffer) in lods.chain(batch) {
+                    render_pass.set_vertex_buffer(1, instance_buffer.slice(..));
+                    for mesh in &model.meshes {
+                        for submesh in &mesh.submeshes {
+                            let material = &model.materials[submesh.material];
+                            if !material.is_transparent() {
+                                continue;
+                            }
+                            self.set_cull_mode(&mut render_pass, &self.oit_pipelines, &mut cull_mode, material.cull_mode);
+                            render_pass.draw_submesh_instanced(
+                                mesh,
+                                submesh,
+                                material,
+                                range.clone(),
+                                camera_bind_group,
+                                &self.light_manager.light_bind_group,
+                            );
+                        }
+                    }
+                }
+            }
+            if passes.particles {
+                self.particles.render_order_independent(&mut render_pass, camera_bind_group);
+            }
+        }
+        self.oit.composite(encoder, &self.post.scene_texture.view);
+    }
+
+    // Models and static geometry seen by each enabled render target camera,
+    // into its material's diffuse map. Targets skip their own material
+    fn encode_render_targets(&self, encoder: &mut wgpu::CommandEncoder) {
+        let pipeline = match self.pipelines.get(self.render_pipelines.back) {
+            Some(pipeline) => pipeline,
+            None => return,
+        };
+        for target in self.render_targets.iter().filter(|t| t.enabled) {
+            let texture = match self.target_material(target.material()) {
+                Some(material) => &material.diffuse_texture,
+                None => continue,
+            };
+            let (view, resolve_target) = target.color_attachment(texture);
+            let mut render_pass = encoder.begin_render_pass(&wgpu::RenderPassDescriptor {
+                label: Some("Render Target Pass"),
+                color_attachments: &[Some(wgpu::RenderPassColorAttachment {
+                    view,
+                    resolve_target,
+                    ops: wgpu::Operations {
+                        load: wgpu::LoadOp::Clear(self.background.clear_color()),
+                        store: true,
+                    },
+                })],
+                depth_stencil_attachment: Some(wgpu::RenderPassDepthStencilAttachment {
+                    view: target.depth_view(),
+                    depth_ops: Some(wgpu::Operations {
+                        load: wgpu::LoadOp::Clear(1.0),
+                        store: true,
+                    }),
+                    stencil_ops: self.depth_format.has_stencil().then_some(wgpu::Operations {
+                        load: wgpu::LoadOp::Clear(0),
+                        store: true,
+                    }),
+                }),
+            });
+            render_pass.set_pipeline(pipeline);
+            let mut cull_mode = CullMode::Back;
+
+            let models = std::iter::once(&self.obj_model).chain(self.lods.levels().iter().map(|l| &l.model));
+            let draws = models
+                .zip(target.lod_ranges().iter().cloned())
+                .enumerate()
+                .filter(|(_, (_, range))| !range.is_empty());
+            render_pass.set_vertex_buffer(1, target.instance_buffer().slice(..));
+            for (level, (model, range)) in draws {
+                for mesh in &model.meshes {
+                    for submesh in &mesh.submeshes {
+                        if level == 0 && target.material() == TargetMaterial::Instances(submesh.material) {
+                            continue;
+                        }
+                        let material = &model.materials[submesh.material];
+                        self.set_cull_mode(&mut render_pass, &self.render_pipelines, &mut cull_mode, material.cull_mode);
+                        render_pass.draw_submesh_instanced(
+                            mesh,
+                            submesh,
+                            material,
+                            range.clone(),
+                            target.bind_group(),
+                            &self.light_manager.light_bind_group,
+                        );
+                    }
+                }
+            }
+
+            if let Some(batch) = &self.static_batch {
+                render_pass.set_vertex_buffer(1, self.static_instance.slice(..));
+                for mesh in &batch.meshes {
+                    let variants = self.bind_lightmap(&mut render_pass, mesh, cull_mode);
+                    for submesh in &mesh.submeshes {
+                        if target.material() == TargetMaterial::StaticBatch(submesh.material) {
+                            continue;
+                        }
+                        let material = &batch.materials[submesh.material];
+                        self.set_cull_mode(&mut render_pass, variants, &mut cull_mode, material.cull_mode);
+                        render_pass.draw_submesh_instanced(
+                            mesh,
+                            submesh,
+                            material,
+                            0..1,
+                            target.bind_group(),
+                            &self.light_manager.light_bind_group,
+                        );
+                    }
+                }
+            }
+        }
+    }
+
+    // Viewport is the area of the target the scene goes in, all of it when `None`
+    fn encode_frame(
+        &self,
+        encoder: &mut wgpu::CommandEncoder,
+        target: &wgpu::TextureView,
+        viewport: Option<ViewportRect>,
+    ) {
+        let passes = self.settings.passes;
+        let (scene_view, resolve_target) = self.post.color_attachment();
+        let shadows = &self.light_manager.shadows;
+        if !shadows.tiles().is_empty() {
+            encoder.debug_group(self.debug_label("Shadow Pass"), |encoder| {
+                self.encode_shadows(encoder);
+            });
+        }
+        if passes.models && self.render_targets.iter().any(|t| t.enabled) {
+            encoder.debug_group(self.debug_label("Render Target Passes"), |encoder| {
+                self.encode_render_targets(encoder);
+            });
+        }
+        encoder.debug_group(self.debug_label("Main Pass"), |encoder| {
+            let mut render_pass = encoder.begin_render_pass(&wgpu::RenderPassDescriptor {
+                label: Some("Render Pass"),
+                color_attachments: &[Some(wgpu::RenderPassColorAttachment {
+                    view: scene_view,
+                    resolve_target,
+                    ops: wgpu::Operations {
+                        load: wgpu::LoadOp::Clear(self.background.clear_color()),
+                        store: true,
+                    },
+                })],
+                depth_stencil_attachment: Some(wgpu::RenderPassDepthStencilAttachment {
+                    view: &self.depth_texture.view,
+                    depth_ops: Some(wgpu::Operations {
+                        load: wgpu::LoadOp::Clear(1.0),
+                        store: true,
+                    }),
+                    stencil_ops: self.depth_format.has_stencil().then_some(wgpu::Operations {
+                        load: wgpu::LoadOp::Clear(0),
+                        store: true,
+                    }),
+                }),
+            });
+
+            self.background.render(
+                &mut render_pass,
+                &self.camera_bind_groups[self.camera_buffers.index()],
+                &self.light_manager.light_bind_group,
+            );
+
+            // Render light (for debbuging)
+            //render_pass.set_pipeline(&self.light_render_pipeline);
+            //render_pass.draw_light_model(
+            //    &self.obj_model,
+            //    &self.camera_bind_group,
+            //    &self.light_bind_group,
+            //);
+
+            // Render models
+            let model_pipeline = self.pipelines.get(self.render_pipelines.back).filter(|_| passes.models);
+            if let Some(pipeline) = model_pipeline {
+                render_pass.set_pipeline(pipeline);
+            }
+            let mut cull_mode = CullMode::Back;
+            render_pass.set_vertex_buffer(1, self.instance_buffers.current().slice(..));
+            let submeshes = self.lod_draws().flat_map(|(model, range)| {
+                model.meshes.iter().flat_map(move |mesh| {
+                    let range = range.clone();
+                    mesh.submeshes.iter().map(move |submesh| (model, mesh, submesh, range.clone()))
+                })
+            });
+            let submeshes = submeshes.filter(|(model, _, submesh, _)| !model.materials[submesh.material].is_transparent());
+            for (model, mesh, submesh, range) in submeshes.filter(|_| model_pipeline.is_some()) {
+                let material = &model.materials[submesh.material];
+                self.set_cull_mode(&mut render_pass, &self.render_pipelines, &mut cull_mode, material.cull_mode);
+                let label = self
+                    .debug_labels
+                    .then(|| format!("Draw {} ({})", mesh.name, material.name));
+                render_pass.debug_group(label, |render_pass| {
+                    render_pass.draw_submesh_instanced(
+                        mesh,
+                        submesh,
+                        material,
+                        range,
+                        &self.camera_bind_groups[self.camera_buffers.index()],
+                        &self.light_manager.light_bind_group,
+                    );
+                });
+            }
+
+            // Render outlines around the outlined instances, with their materials' width and color
+            let outline_pipeline = self.pipelines.get(self.outline_pipeline).filter(|_| passes.models);
+            if let (Some(pipeline), Some(_)) = (outline_pipeline, self.outline_draws().next()) {
+                render_pass.set_pipeline(pipeline);
+                for (model, range) in self.outline_draws() {
+                    for mesh in &model.meshes {
+                        render_pass.draw_mesh_materials_instanced(
+                            mesh,
+                            &model.materials,
+                            range.clone(),
+                            &self.camera_bind_groups[self.camera_buffers.index()],
+                            &self.light_manager.light_bind_group,
+                        );
+                    }
+                }
+                if let Some(pipeline) = model_pipeline {
+                    render_pass.set_pipeline(pipeline);
+                }
+                cull_mode = CullMode::Back;
+            }
+
+            // Render static level geometry, one draw per material
+            if let (Some(batch), Some(_)) = (&self.static_batch, model_pipeline) {
+                render_pass.set_vertex_buffer(1, self.static_instance.slice(..));
+                for mesh in &batch.meshes {
+                    let variants = self.bind_lightmap(&mut render_pass, mesh, cull_mode);
+                    self.draw_mesh_culled(
+                        &mut render_pass,
+                        variants,
+                        &mut cull_mode,
+                        mesh,
+                        &batch.materials,
+                        0..1,
+                        &self.camera_bind_groups[self.camera_buffers.index()],
+                        true,
+                    );
+                }
+            }
+
+            // Render crowds, one draw per mesh for all of their instances
+            let crowd_pipeline = self.pipelines.get(self.skinned_pipelines.back).filter(|_| passes.crowds);
+            if let (Some(pipeline), false) = (crowd_pipeline, self.crowds.is_empty()) {
+                render_pass.set_pipeline(pipeline);
+            }
+            let mut cull_mode = CullMode::Back;
+            let crowds = self
+                .crowds
+                .iter()
+                .filter(|c| crowd_pipeline.is_some() && !c.instance_range().is_empty());
+            for crowd in crowds {
+                render_pass.set_vertex_buffer(1, crowd.instance_buffer().slice(..));
+                render_pass.set_vertex_buffer(3, crowd.animation_buffer().slice(..));
+                render_pass.set_bind_group(3, &crowd.bind_group, &[]);
+                for (i, mesh) in crowd.model.meshes.iter().enumerate() {
+                    render_pass.set_vertex_buffer(2, crowd.skin(i).slice(..));
+                    self.draw_mesh_culled(
+                        &mut render_pass,
+                        &self.skinned_pipelines,
+                        &mut cull_mode,
+                        mesh,
+                        &crowd.model.materials,
+                        crowd.instance_range(),
+                        &self.camera_bind_groups[self.camera_buffers.index()],
+                        false,
+                    );
+                }
+            }
+
+            // Render vertex animated crowds the same way, frames come from textures
+            let vat_pipeline = self.pipelines.get(self.vat_pipelines.back).filter(|_| passes.crowds);
+            if let (Some(pipeline), false) = (vat_pipeline, self.vat_crowds.is_empty()) {
+                render_pass.set_pipeline(pipeline);
+            }
+            let mut cull_mode = CullMode::Back;
+            let vat_crowds = self
+                .vat_crowds
+                .iter()
+                .filter(|c| vat_pipeline.is_some() && !c.instance_range().is_empty());
+            for crowd in vat_crowds {
+                render_pass.set_vertex_buffer(1, crowd.instance_buffer().slice(..));
+                render_pass.set_vertex_buffer(2, crowd.animation_buffer().slice(..));
+                render_pass.set_bind_group(3, &crowd.bind_group, &[]);
+                for mesh in &crowd.model.meshes {
+                    self.draw_mesh_culled(
+                        &mut render_pass,
+                        &self.vat_pipelines,
+                        &mut cull_mode,
+                        mesh,
+                        &crowd.model.materials,
+                        crowd.instance_range(),
+                        &self.camera_bind_groups[self.camera_buffers.index()],
+                        false,
+                    );
+                }
+            }
+
+            #[cfg(feature = "virtual-texturing")]
+            if let (Some(terrain), true) = (&self.virtual_terrain, passes.models) {
+                terrain.render(&mut render_pass, &self.camera_bind_groups[self.camera_buffers.index()]);
+            }
+
+            self.light_gizmo
+                .render(&mut render_pass, &self.camera_bind_groups[self.camera_buffers.index()]);
+
+            // Render placement preview
+            let ghost_pipeline = self
+                .pipelines
+                .get(self.ghost_pipeline)
+                .filter(|_| passes.models && self.placement.ghost().is_some());
+            if let Some(pipeline) = ghost_pipeline {
+                let ghost = self.visible_instances;
+                render_pass.set_vertex_buffer(1, self.instance_buffers.current().slice(..));
+                render_pass.set_pipeline(pipeline);
+                render_pass.set_blend_constant(wgpu::Color {
+                    r: 0.5,
+                    g: 0.5,
+                    b: 0.5,
+                    a: 0.5,
+                });
+                for mesh in &self.obj_model.meshes {
+                    render_pass.draw_mesh_materials_instanced(
+                        mesh,
+                        &self.obj_model.materials,
+                        ghost..ghost + 1,
+                        &self.camera_bind_groups[self.camera_buffers.index()],
+                        &self.light_manager.light_bind_group,
+                    );
+                }
+            }
+
+            if passes.point_clouds {
+                self.point_clouds
+                    .render(&mut render_pass, &self.camera_bind_groups[self.camera_buffers.index()]);
+            }
+
+            // Transparent, so after everything opaque
+            if passes.trails {
+                self.trails
+                    .render(&mut render_pass, &self.camera_bind_groups[self.camera_buffers.index()]);
+            }
+            if passes.particles {
+                self.particles
+                    .render(&mut render_pass, &self.camera_bind_groups[self.camera_buffers.index()]);
+            }
+        });
+
+        if self.has_transparency() {
+            encoder.debug_group(self.debug_label("OIT Pass"), |encoder| {
+                self.encode_transparency(encoder);
+            });
+        }
+
+        if passes.volumetric {
+            encoder.debug_group(self.debug_label("Volumetric Pass"), |encoder| {
+                self.volumetric.render(
+                    encoder,
+                    &self.post.scene_texture.view,
+                    &self.camera_bind_groups[self.camera_buffers.index()],
+                    &self.light_manager.light_bind_group,
+                );
+            });
+        }
+
+        encoder.debug_group(self.debug_label("Post Pass"), |encoder| {
+            self.post.render(encoder, target, viewport);
+        });
+
+        if passes.lens_flare {
+            encoder.debug_group(self.debug_label("Flare Pass"), |encoder| {
+                self.flare.render(encoder, target, viewport);
+            });
+        }
+
+        if passes.overlay {
+            encoder.debug_group(self.debug_label("Overlay Pass"), |encoder| {
+                self.overlay.render(encoder, target);
+            });
+        }
+    }
+}
+
@@ -1,11 +1,23 @@
 use anyhow::*;
 use itertools::Itertools;
-use std::io::{BufReader, Cursor};
-use wgpu::util::DeviceExt;
+use std::{
+    collections::HashMap,
+    io::{BufReader, Cursor},
+    sync::Arc,
+    time::SystemTime,
+};
 
 use crate::{
-    model::{Material, Mesh, Model},
-    texture::Texture,
+    decode::{DecodePriority, DecodeQueue, PendingTexture},
+    geometry::Geometry,
+    layers::RenderLayers,
+    legacy::{convert_textures, LegacyMaterial},
+    material::{MaterialDesc, MaterialLayout},
+    math::Transform,
+    memory::MemoryTracker,
+    model::{Material, Model, Submesh},
+    tangents::TangentGenerator,
+    texture::{linear_to_srgb, srgb_to_linear, Texture},
 };
 
 pub trait Vertex {
@@ -20,6 +32,29 @@ pub struct ModelVertex {
     pub normal: [f32; 3],
     pub tangent: [f32; 3],
     pub bitangent: [f32; 3],
+    /// Second texture coordinates, e.g. authored lightmap coordinates. Zero
+    /// where an importer found none. Not part of the default layout, which
+    /// only steps over them.
+    #[cfg(feature = "secondary-uv")]
+    pub tex_coords1: [f32; 2],
+}
+
+impl ModelVertex {
+    /// `tex_coords1`, zero without the `secondary-uv` feature.
+    pub fn secondary_uv(&self) -> [f32; 2] {
+        #[cfg(feature = "secondary-uv")]
+        return self.tex_coords1;
+        #[cfg(not(feature = "secondary-uv"))]
+        return [0.0; 2];
+    }
+
+    /// Set `tex_coords1`, ignored without the `secondary-uv` feature.
+    pub fn set_secondary_uv(&mut self, _uv: [f32; 2]) {
+        #[cfg(feature = "secondary-uv")]
+        {
+            self.tex_coords1 = _uv;
+        }
+    }
 }
 
 impl Vertex for ModelVertex {
@@ -58,27 +93,151 @@ impl Vertex for ModelVertex {
     }
 }
 
+#[derive(Debug, Copy, Clone, PartialEq)]
 pub struct Instance {
     pub position: cgmath::Vector3<f32>,
     pub rotation: cgmath::Quaternion<f32>,
+    /// Scale along the model's axes, all equal for uniform scale.
+    pub scale: cgmath::Vector3<f32>,
+    pub layers: RenderLayers,
+    /// Draw the silhouette outline of the instance's materials around it.
+    pub outline: bool,
 }
 
 impl Instance {
-    pub fn to_raw(&self) -> InstanceRaw {
-        let model =
-            cgmath::Matrix4::from_translation(self.position) * cgmath::Matrix4::from(self.rotation);
+    pub fn transform(&self) -> Transform {
+        return Transform::new(self.position, self.rotation, self.scale);
+    }
+
+    pub fn model_matrix(&self) -> cgmath::Matrix4<f32> {
+        return self.transform().matrix();
+    }
+
+    /// Transforms normals, see `Transform::normal_matrix`.
+    pub fn normal_matrix(&self) -> cgmath::Matrix3<f32> {
+        return self.transform().normal_matrix();
+    }
+
+    // `fade` is the LOD cross-fade factor, see `LodSelection::fades`
+    pub fn to_raw(&self, fade: f32) -> InstanceRaw {
+        let normal: [[f32; 3]; 3] = self.normal_matrix().into();
         InstanceRaw {
-            model: model.into(),
-            normal: cgmath::Matrix3::from(self.rotation).into(),
+            model: self.model_matrix().into(),
+            normal: [
+                [normal[0][0], normal[0][1], normal[0][2], 0.0],
+                [normal[1][0], normal[1][1], normal[1][2], 0.0],
+                [normal[2][0], normal[2][1], normal[2][2], fade],
+            ],
+        }
+    }
+
+    pub fn to_compact_raw(&self, fade: f32) -> CompactInstanceRaw {
+        let q = self.rotation;
+        CompactInstanceRaw {
+            rotation: [q.v.x, q.v.y, q.v.z, q.s],
+            position_fade: self.position.extend(fade).into(),
+            scale: self.scale.into(),
         }
     }
 }
 
+/// Layout of per-instance vertex data, chosen per pipeline.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum InstanceFormat {
+    // Model and normal matrices, 112 bytes
+    Matrix,
+    // Rotation, translation and scale, 44 bytes, matrices are rebuilt in the
+    // vertex shader
+    Compact,
+}
+
+impl InstanceFormat {
+    pub fn desc<'a>(self) -> wgpu::VertexBufferLayout<'a> {
+        return match self {
+            InstanceFormat::Matrix => InstanceRaw::desc(),
+            InstanceFormat::Compact => CompactInstanceRaw::desc(),
+        };
+    }
+
+    pub fn stride(self) -> usize {
+        return match self {
+            InstanceFormat::Matrix => std::mem::size_of::<InstanceRaw>(),
+            InstanceFormat::Compact => std::mem::size_of::<CompactInstanceRaw>(),
+        };
+    }
+
+    /// Shader define selecting the matching `InstanceInput` in basic.wgsl.
+    pub fn shader_define(self) -> Option<&'static str> {
+        return match self {
+            InstanceFormat::Matrix => None,
+            InstanceFormat::Compact => Some("COMPACT_INSTANCES"),
+        };
+    }
+
+    pub fn encode<'a, I: IntoIterator<Item = &'a Instance>>(self, instances: I) -> Vec<u8> {
+        return self.encode_faded(instances.into_iter().map(|i| (i, 1.0)));
+    }
+
+    /// Encode instances with their LOD cross-fade factors.
+    pub fn encode_faded<'a, I: IntoIterator<Item = (&'a Instance, f32)>>(self, instances: I) -> Vec<u8> {
+        return match self {
+            InstanceFormat::Matrix => {
+                let raw = instances.into_iter().map(|(i, fade)| i.to_raw(fade)).collect_vec();
+                bytemuck::cast_slice(&raw).to_vec()
+            }
+            InstanceFormat::Compact => {
+                let raw = instances
+                    .into_iter()
+                    .map(|(i, fade)| i.to_compact_raw(fade))
+                    .collect_vec();
+                bytemuck::cast_slice(&raw).to_vec()
+            }
+        };
+    }
+}
+
 #[repr(C)]
 #[derive(Copy, Clone, bytemuck::Pod, bytemuck::Zeroable)]
 pub struct InstanceRaw {
     model: [[f32; 4]; 4],
-    normal: [[f32; 3]; 3],
+    // Columns padded to vec4, the last w holds the LOD fade. There is no
+    // attribute location left for it next to skinning
+    normal: [[f32; 4]; 3],
+}
+
+#[repr(C)]
+#[derive(Copy, Clone, bytemuck::Pod, bytemuck::Zeroable)]
+pub struct CompactInstanceRaw {
+    rotation: [f32; 4],
+    position_fade: [f32; 4],
+    scale: [f32; 3],
+}
+
+impl CompactInstanceRaw {
+    pub fn desc<'a>() -> wgpu::VertexBufferLayout<'a> {
+        use std::mem;
+        wgpu::VertexBufferLayout {
+            array_stride: mem::size_of::<CompactInstanceRaw>() as wgpu::BufferAddress,
+            step_mode: wgpu::VertexStepMode::Instance,
+            attributes: &[
+                wgpu::VertexAttribute {
+                    offset: 0,
+                    shader_location: 5,
+                    format: wgpu::VertexFormat::Float32x4,
+                },
+                wgpu::VertexAttribute {
+                    offset: mem::size_of::<[f32; 4]>() as wgpu::BufferAddress,
+                    shader_location: 6,
+                    format: wgpu::VertexFormat::Float32x4,
+                },
+                wgpu::VertexAttribute {
+                    offset: mem::size_of::<[f32; 8]>() as wgpu::BufferAddress,
+                    shader_location: 7,
+                    format: wgpu::VertexFormat::Float32x3,
+                },
+            ],
+        }
+    }
 }
 
 impl InstanceRaw {
@@ -111,36 +270,37 @@ impl InstanceRaw {
                 wgpu::VertexAttribute {
                     offset: mem::size_of::<[f32; 16]>() as wgpu::BufferAddress,
                     shader_location: 9,
-                    format: wgpu::VertexFormat::Float32x3,
+                    format: wgpu::VertexFormat::Float32x4,
                 },
                 wgpu::VertexAttribute {
-                    offset: mem::size_of::<[f32; 19]>() as wgpu::BufferAddress,
+                    offset: mem::size_of::<[f32; 20]>() as wgpu::BufferAddress,
                     shader_location: 10,
-                    format: wgpu::VertexFormat::Float32x3,
+                    format: wgpu::VertexFormat::Float32x4,
                 },
                 wgpu::VertexAttribute {
-                    offset: mem::size_of::<[f32; 22]>() as wgpu::BufferAddress,
+                    offset: mem::size_of::<[f32; 24]>() as wgpu::BufferAddress,
                     shader_location: 11,
-                    format: wgpu::VertexFormat::Float32x3,
+                    format: wgpu::VertexFormat::Float32x4,
                 },
             ],
         }
     }
 }
 
+/// Where `load_string` and friends read `file_name` from.
+pub fn resource_path(file_name: &str) -> std::path::PathBuf {
+    return std::path::Path::new(env!("OUT_DIR")).join("res").join(file_name);
+}
+
 pub async fn load_string(file_name: &str) -> anyhow::Result<String> {
-    let path = std::path::Path::new(env!("OUT_DIR"))
-        .join("res")
-        .join(file_name);
+    let path = resource_path(file_name);
     let txt = std::fs::read_to_string(path)?;
 
     Ok(txt)
 }
 
 pub async fn load_binary(file_name: &str) -> anyhow::Result<Vec<u8>> {
-    let path = std::path::Path::new(env!("OUT_DIR"))
-        .join("res")
-        .join(file_name);
+    let path = resource_path(file_name);
     let data = std::fs::read(path)?;
 
     Ok(data)
@@ -150,17 +310,309 @@ pub async fn load_texture(
     file_name: &str,
     is_normal_map: bool,
     device: &wgpu::Device,
+    memory: &MemoryTracker,
     queue: &wgpu::Queue,
 ) -> anyhow::Result<Texture> {
-    let data = load_binary(file_name).await?;
-    Texture::from_bytes(device, queue, &data, file_name, is_normal_map)
+    let data = load_binary(file_name)
+        .await
+        .with_context(|| format!("Failed to read texture `{}`", file_name))?;
+    Texture::from_bytes(device, memory, queue, &data, file_name, is_normal_map)
+        .with_context(|| format!("Failed to decode texture `{}`", file_name))
+}
+
+/// Material texture slot showing `Texture::placeholder` until its file loads.
+#[derive(Debug, Clone)]
+pub struct MissingTexture {
+    pub source: String,
+    /// Normal map slot if set, diffuse otherwise.
+    pub is_normal_map: bool,
+    // Modification time of the file at the last attempt, `None` if missing
+    modified: Option<SystemTime>,
+}
+
+// The texture, or a placeholder and what it stands in for: the request on
+// `decoder` if given, otherwise what failed after logging the error
+async fn load_texture_or_placeholder(
+    file_name: &str,
+    is_normal_map: bool,
+    device: &wgpu::Device,
+    memory: &MemoryTracker,
+    queue: &wgpu::Queue,
+    decoder: Option<&DecodeQueue>,
+) -> anyhow::Result<(Texture, Option<MissingTexture>, Option<PendingTexture>)> {
+    if let Some(decoder) = decoder {
+        let pending = decoder.request(file_name, is_normal_map, DecodePriority::Background);
+        let placeholder = loading_texture(device, memory, queue, is_normal_map)?;
+        return Ok((placeholder, None, Some(pending)));
+    }
+    match load_texture(file_name, is_normal_map, device, memory, queue).await {
+        Result::Ok(texture) => Ok((texture, None, None)),
+        Err(e) => {
+            let (placeholder, missing) = missing_placeholder(file_name, is_normal_map, device, memory, queue, &e)?;
+            Ok((placeholder, Some(missing), None))
+        }
+    }
+}
+
+// The placeholder and the slot to retry after logging why `file_name` failed
+fn missing_placeholder(
+    file_name: &str,
+    is_normal_map: bool,
+    device: &wgpu::Device,
+    memory: &MemoryTracker,
+    queue: &wgpu::Queue,
+    error: &anyhow::Error,
+) -> anyhow::Result<(Texture, MissingTexture)> {
+    log::error!("{:?}", error);
+    let missing = MissingTexture {
+        source: file_name.to_string(),
+        is_normal_map,
+        modified: modified_time(file_name),
+    };
+    let placeholder = Texture::placeholder(device, memory, queue, is_normal_map)?;
+    Ok((placeholder, missing))
+}
+
+// Plain grey, or a flat normal, while the file is decoded
+fn loading_texture(
+    device: &wgpu::Device,
+    memory: &MemoryTracker,
+    queue: &wgpu::Queue,
+    is_normal_map: bool,
+) -> anyhow::Result<Texture> {
+    if is_normal_map {
+        return solid_texture(device, memory, queue, [128, 128, 255, 255], "loading normal", true);
+    }
+    return solid_texture(device, memory, queue, [160, 160, 160, 255], "loading", false);
+}
+
+fn modified_time(file_name: &str) -> Option<SystemTime> {
+    return std::fs::metadata(resource_path(file_name)).and_then(|m| m.modified()).ok();
+}
+
+/// Load the material's missing textures again if their files appeared or
+/// changed since the last attempt, replacing the placeholders. Returns
+/// whether any texture was replaced.
+pub fn reload_missing_textures(
+    material: &mut Material,
+    device: &wgpu::Device,
+    memory: &MemoryTracker,
+    queue: &wgpu::Queue,
+    layout: &wgpu::BindGroupLayout,
+) -> bool {
+    if material.missing_textures.is_empty() {
+        return false;
+    }
+    let mut replaced = false;
+    let mut missing = std::mem::take(&mut material.missing_textures);
+    missing.retain_mut(|slot| {
+        let modified = modified_time(&slot.source);
+        if modified.is_none() || modified == slot.modified {
+            return true;
+        }
+        slot.modified = modified;
+        let texture = pollster::block_on(load_texture(&slot.source, slot.is_normal_map, device, memory, queue));
+        match texture {
+            Result::Ok(texture) if slot.is_normal_map => material.set_normal_texture(device, texture, layout),
+            Result::Ok(texture) => material.set_diffuse_texture(device, texture, layout),
+            Err(e) => {
+                log::error!("{:?}", e);
+                return true;
+            }
+        }
+        log::info!("Replaced the placeholder of `{}`", slot.source);
+        replaced = true;
+        return false;
+    });
+    material.missing_textures = missing;
+    return replaced;
+}
+
+/// Swap the material's decoded textures in for their placeholders, and the
+/// failure placeholder in for those that failed. Returns whether any slot
+/// changed.
+pub fn poll_pending_textures(
+    material: &mut Material,
+    device: &wgpu::Device,
+    memory: &MemoryTracker,
+    queue: &wgpu::Queue,
+    layout: &wgpu::BindGroupLayout,
+) -> bool {
+    if material.pending_textures.is_empty() {
+        return false;
+    }
+    let mut changed = false;
+    let mut pending = std::mem::take(&mut material.pending_textures);
+    pending.retain(|slot| {
+        let texture = match slot.poll() {
+            Some(Result::Ok(image)) => {
+                Texture::from_decoded(device, memory, queue, &image, &slot.source, slot.is_normal_map)
+            }
+            Some(Err(e)) => match missing_placeholder(&slot.source, slot.is_normal_map, device, memory, queue, &e) {
+                Result::Ok((placeholder, missing)) => {
+                    material.missing_textures.push(missing);
+                    placeholder
+                }
+                Err(e) => {
+                    log::error!("{:?}", e);
+                    return false;
+                }
+            },
+            None => return true,
+        };
+        if slot.is_normal_map {
+            material.set_normal_texture(device, texture, layout);
+        } else {
+            material.set_diffuse_texture(device, texture, layout);
+        }
+        changed = true;
+        return false;
+    });
+    material.pending_textures = pending;
+    return changed;
+}
+
+// 1x1 texture for material slots a `.mat` file leaves empty
+pub(crate) fn solid_texture(
+    device: &wgpu::Device,
+    memory: &MemoryTracker,
+    queue: &wgpu::Queue,
+    color: [u8; 4],
+    label: &str,
+    is_normal_map: bool,
+) -> anyhow::Result<Texture> {
+    let img = image::DynamicImage::ImageRgba8(image::RgbaImage::from_pixel(
+        1,
+        1,
+        image::Rgba(color),
+    ));
+    Texture::from_image(device, memory, queue, &img, Some(label), is_normal_map)
 }
 
+/// Load a material from a `.mat` description, see `MaterialDesc` for the
+/// format. With a `decoder` its textures are decoded by its workers and
+/// placeholders stand in until `poll_pending_textures` swaps them in.
+pub async fn load_material(
+    file_name: &str,
+    device: &wgpu::Device,
+    memory: &MemoryTracker,
+    queue: &wgpu::Queue,
+    layout: &wgpu::BindGroupLayout,
+    params_layout: &Arc<MaterialLayout>,
+    decoder: Option<&DecodeQueue>,
+) -> anyhow::Result<Material> {
+    let source = load_string(file_name).await?;
+    let desc = MaterialDesc::parse(&source).with_context(|| format!("In `{}`", file_name))?;
+    let name = if desc.name.is_empty() {
+        file_name
+    } else {
+        &desc.name
+    };
+
+    let mut missing_textures = Vec::new();
+    let mut pending_textures = Vec::new();
+    let diffuse_texture = match &desc.diffuse {
+        Some(path) => {
+            let (texture, missing, pending) =
+                load_texture_or_placeholder(path, false, device, memory, queue, decoder).await?;
+            missing_textures.extend(missing);
+            pending_textures.extend(pending);
+            texture
+        }
+        None => solid_texture(device, memory, queue, [255; 4], "white", false)?,
+    };
+    // Flat tangent-space normal
+    let normal_texture = match &desc.normal {
+        Some(path) => {
+            let (texture, missing, pending) =
+                load_texture_or_placeholder(path, true, device, memory, queue, decoder).await?;
+            missing_textures.extend(missing);
+            pending_textures.extend(pending);
+            texture
+        }
+        None => solid_texture(device, memory, queue, [128, 128, 255, 255], "flat normal", true)?,
+    };
+    let params = desc
+        .build_params(Material::default_params(params_layout.clone()))
+        .with_context(|| format!("In `{}`", file_name))?;
+
+    let mut material = Material::new(
+        device,
+        memory,
+        name,
+        diffuse_texture,
+        normal_texture,
+        params,
+        layout,
+    );
+    material.cull_mode = desc.cull;
+    material.shading = desc.shading;
+    material.diffuse_source = desc.diffuse.clone();
+    material.normal_source = desc.normal.clone();
+    material.missing_textures = missing_textures;
+    material.pending_textures = pending_textures;
+    Ok(material)
+}
+
+// Diffuse texture of an MTL material with a specular map, its Phong maps
+// converted texel by texel. The basic shader has no metallic term, so the
+// base color is darkened to the diffuse albedo metals leave
+async fn convert_legacy_maps(
+    m: &tobj::Material,
+    legacy: &LegacyMaterial,
+    device: &wgpu::Device,
+    memory: &MemoryTracker,
+    queue: &wgpu::Queue,
+) -> Result<Texture> {
+    let specular = image::load_from_memory(&load_binary(&m.specular_texture).await?)?.to_rgba8();
+    let diffuse = if m.diffuse_texture.is_empty() {
+        image::RgbaImage::from_pixel(specular.width(), specular.height(), image::Rgba([255; 4]))
+    } else {
+        image::load_from_memory(&load_binary(&m.diffuse_texture).await?)?.to_rgba8()
+    };
+    let shininess = if m.shininess_texture.is_empty() {
+        None
+    } else {
+        Some(image::load_from_memory(&load_binary(&m.shininess_texture).await?)?.to_rgba8())
+    };
+
+    let maps = convert_textures(legacy, &diffuse, Some(&specular), shininess.as_ref());
+    let mut albedo = maps.base_color;
+    for (texel, metallic_roughness) in albedo.pixels_mut().zip(maps.metallic_roughness.pixels()) {
+        let diffuse_fraction = 1.0 - metallic_roughness[2] as f32 / 255.0;
+        for c in 0..3 {
+            let linear = srgb_to_linear(texel[c] as f32 / 255.0) * diffuse_fraction;
+            texel[c] = (linear_to_srgb(linear) * 255.0).round() as u8;
+        }
+    }
+    let label = format!("{} (converted)", m.name);
+    return Texture::from_image(device, memory, queue, &image::DynamicImage::ImageRgba8(albedo), Some(&label), false);
+}
+
+// `.mat` file next to an OBJ describing one of its materials, named after
+// the material, or after the OBJ when it has only that one
+fn material_file(obj_file: &str, material: &str, only_material: bool) -> Option<String> {
+    let obj = std::path::Path::new(obj_file);
+    let named = obj.with_file_name(format!("{}.mat", material));
+    return std::iter::once(named)
+        .chain(only_material.then(|| obj.with_extension("mat")))
+        .map(|path| path.to_string_lossy().into_owned())
+        .find(|path| resource_path(path).is_file());
+}
+
+/// Load an `.obj` model, decoding its textures on `decoder`'s workers if
+/// given, see `load_material`. A `.mat` file next to the model named after
+/// a material, or after the model if it has one material, replaces what the
+/// MTL file says about it. MTL materials are converted from their Phong
+/// values, see `LegacyMaterial`.
 pub async fn load_model(
     file_name: &str,
     device: &wgpu::Device,
+    memory: &MemoryTracker,
     queue: &wgpu::Queue,
     layout: &wgpu::BindGroupLayout,
+    params_layout: &Arc<MaterialLayout>,
+    decoder: Option<&DecodeQueue>,
 ) -> anyhow::Result<Model> {
     let obj_text = load_string(file_name).await?;
     let obj_cursor = Cursor::new(obj_text);
@@ -181,24 +633,72 @@ pub async fn load_model(
     .await?;
 
     let mut materials = Vec::new();
-    for m in obj_materials? {
-        let diffuse_texture = load_texture(&m.diffuse_texture, false, device, queue).await?;
-        let normal_texture = load_texture(&m.normal_texture, true, device, queue).await?;
+    let obj_materials = obj_materials?;
+    let only_material = obj_materials.len() == 1;
+    for m in obj_materials {
+        if let Some(path) = material_file(file_name, &m.name, only_material) {
+            let material = load_material(&path, device, memory, queue, layout, params_layout, decoder).await?;
+            materials.push(material);
+            continue;
+        }
+        let legacy = LegacyMaterial::from(&m);
+        let mut params = Material::default_params(params_layout.clone());
+        legacy.to_metallic_roughness().apply(&mut params)?;
+        // With a specular map the diffuse color varies per texel, the tint
+        // is baked into the converted texture
+        let converted = if m.specular_texture.is_empty() {
+            None
+        } else {
+            convert_legacy_maps(&m, &legacy, device, memory, queue)
+                .await
+                .map_err(|e| log::warn!("Failed to convert the maps of `{}`: {:?}", m.name, e))
+                .ok()
+        };
+        let (diffuse_texture, missing_diffuse, pending_diffuse) = match converted {
+            Some(texture) => {
+                params.set("tint", [1.0f32; 4])?;
+                (texture, None, None)
+            }
+            None => load_texture_or_placeholder(&m.diffuse_texture, false, device, memory, queue, decoder).await?,
+        };
+        let (normal_texture, missing_normal, pending_normal) =
+            load_texture_or_placeholder(&m.normal_texture, true, device, memory, queue, decoder).await?;
 
-        materials.push(Material::new(
+        let mut material = Material::new(
             device,
+            memory,
             &m.name,
             diffuse_texture,
             normal_texture,
+            params,
             layout,
-        ))
+        );
+        material.diffuse_source = Some(m.diffuse_texture);
+        material.normal_source = Some(m.normal_texture);
+        material.missing_textures = missing_diffuse.into_iter().chain(missing_normal).collect();
+        material.pending_textures = pending_diffuse.into_iter().chain(pending_normal).collect();
+        materials.push(material);
     }
 
-    let meshes = models
+    // Only compiled if some mesh may be big enough to need it
+    let tangent_generator = (models.iter().map(|m| m.mesh.indices.len()).sum::<usize>()
+        >= TangentGenerator::MIN_INDICES)
+        .then(|| TangentGenerator::new(device))
+        .flatten();
+
+    // tobj splits objects with several materials into one model per material,
+    // all with the object's name. Merge them back into one mesh with submeshes
+    let objects = models.into_iter().group_by(|m| m.name.clone());
+    let meshes = objects
         .into_iter()
-        .map(|m| {
-            let mut vertices = (0..m.mesh.positions.len() / 3)
-                .map(|i| ModelVertex {
+        .map(|(_, parts)| {
+            let mut vertices = Vec::new();
+            let mut indices = Vec::new();
+            let mut submeshes = Vec::new();
+            for m in parts {
+                let base = vertices.len() as u32;
+                let start = indices.len() as u32;
+                vertices.extend((0..m.mesh.positions.len() / 3).map(|i| ModelVertex {
                     position: [
                         m.mesh.positions[i * 3],
                         m.mesh.positions[i * 3 + 1],
@@ -212,91 +712,409 @@ pub async fn load_model(
                     ],
                     tangent: [0.0; 3],
                     bitangent: [0.0; 3],
-                })
-                .collect_vec();
-
-            let indices = &m.mesh.indices;
-            let mut triangles_included = vec![0; vertices.len()];
-
-            for c in indices.chunks(3) {
-                let v0 = vertices[c[0] as usize];
-                let v1 = vertices[c[1] as usize];
-                let v2 = vertices[c[2] as usize];
-
-                let pos0: cgmath::Vector3<_> = v0.position.into();
-                let pos1: cgmath::Vector3<_> = v1.position.into();
-                let pos2: cgmath::Vector3<_> = v2.position.into();
-
-                let uv0: cgmath::Vector2<_> = v0.tex_coords.into();
-                let uv1: cgmath::Vector2<_> = v1.tex_coords.into();
-                let uv2: cgmath::Vector2<_> = v2.tex_coords.into();
-
-                // Calculate the edges of the triangle
-                let delta_pos1 = pos1 - pos0;
-                let delta_pos2 = pos2 - pos0;
-
-                let delta_uv1 = uv1 - uv0;
-                let delta_uv2 = uv2 - uv0;
-
-                // Solving the following system of equations will
-                // give us the tangent and bitangent.
-                //     delta_pos1 = delta_uv1.x * T + delta_u.y * B
-                //     delta_pos2 = delta_uv2.x * T + delta_uv2.y * B
-                let r = 1.0 / (delta_uv1.x * delta_uv2.y - delta_uv1.y * delta_uv2.x);
-                let tangent = (delta_pos1 * delta_uv2.y - delta_pos2 * delta_uv1.y) * r;
-
-                // Flip the bitangent to enable right-handed normal
-                // maps with wgpu texture coordinate system
-                let bitangent = (delta_pos2 * delta_uv1.x - delta_pos1 * delta_uv2.x) * -r;
-
-                // Use the same tangent/bitangent for each vertex in the triangle
-                vertices[c[0] as usize].tangent =
-                    (tangent + cgmath::Vector3::from(vertices[c[0] as usize].tangent)).into();
-                vertices[c[1] as usize].tangent =
-                    (tangent + cgmath::Vector3::from(vertices[c[1] as usize].tangent)).into();
-                vertices[c[2] as usize].tangent =
-                    (tangent + cgmath::Vector3::from(vertices[c[2] as usize].tangent)).into();
-                vertices[c[0] as usize].bitangent =
-                    (bitangent + cgmath::Vector3::from(vertices[c[0] as usize].bitangent)).into();
-                vertices[c[1] as usize].bitangent =
-                    (bitangent + cgmath::Vector3::from(vertices[c[1] as usize].bitangent)).into();
-                vertices[c[2] as usize].bitangent =
-                    (bitangent + cgmath::Vector3::from(vertices[c[2] as usize].bitangent)).into();
-
-                // Used to average the tangents/bitangents
-                triangles_included[c[0] as usize] += 1;
-                triangles_included[c[1] as usize] += 1;
-                triangles_included[c[2] as usize] += 1;
+                    #[cfg(feature = "secondary-uv")]
+                    tex_coords1: [0.0; 2],
+                }));
+                indices.extend(m.mesh.indices.iter().map(|i| i + base));
+                submeshes.push(Submesh {
+                    indices: start..indices.len() as u32,
+                    material: m.mesh.material_id.unwrap_or(0),
+                });
             }
 
-            // Average the tangents/bitangents
-            for (i, n) in triangles_included.into_iter().enumerate() {
-                let denom = 1.0 / n as f32;
-                let mut v = &mut vertices[i];
-                v.tangent = (cgmath::Vector3::from(v.tangent) * denom).into();
-                v.bitangent = (cgmath::Vector3::from(v.bitangent) * denom).into();
-            }
-
-            let vertex_buffer = device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
-                label: Some(&format!("{:?} Vertex Buffer", file_name)),
-                contents: bytemuck::cast_slice(&vertices),
-                usage: wgpu::BufferUsages::VERTEX,
-            });
-            let index_buffer = device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
-                label: Some(&format!("{:?} Index Buffer", file_name)),
-                contents: bytemuck::cast_slice(&m.mesh.indices),
-                usage: wgpu::BufferUsages::INDEX,
-            });
-
-            Mesh {
-                name: file_name.to_string(),
-                vertex_buffer,
-                index_buffer,
-                num_elements: m.mesh.indices.len() as u32,
-                material: m.mesh.material_id.unwrap_or(0),
-            }
+            let mut geometry = Geometry::new(vertices, indices);
+            let mut mesh = match &tangent_generator {
+                Some(generator) if generator.accepts(device, &geometry) => {
+                    generator.generate(device, memory, queue, &geometry, file_name, 0)
+                }
+                _ => {
+                    geometry.calculate_tangents_bitangents();
+                    geometry.to_mesh(device, memory, file_name, 0)
+                }
+            };
+            mesh.submeshes = submeshes;
+            mesh
         })
         .collect_vec();
 
     Ok(Model { meshes, materials })
 }
+
+/// Triangles of a binary or ASCII STL file. STL normals are per face and
+/// often left zero, so they're recomputed from the winding.
+pub fn parse_stl(data: &[u8]) -> Result<Geometry> {
+    let mut positions: Vec<[f32; 3]> = Vec::new();
+    // Binary files may start with "solid" too, their size gives them away
+    let binary_count = data
+        .get(80..84)
+        .map(|b| u32::from_le_bytes([b[0], b[1], b[2], b[3]]) as usize);
+    match binary_count {
+        Some(count) if data.len() == 84 + count * 50 => {
+            for triangle in data[84..].chunks_exact(50) {
+                // Skip the normal, read three vertices
+                for corner in triangle[12..48].chunks_exact(12) {
+                    let value = |i: usize| f32::from_le_bytes([corner[i], corner[i + 1], corner[i + 2], corner[i + 3]]);
+                    positions.push([value(0), value(4), value(8)]);
+                }
+            }
+        }
+        _ => {
+            let text = std::str::from_utf8(data).context("STL file is neither binary nor text")?;
+            ensure!(text.trim_start().starts_with("solid"), "Not an STL file");
+            let mut words = text.split_ascii_whitespace();
+            while let Some(word) = words.next() {
+                if word != "vertex" {
+                    continue;
+                }
+                let mut position = [0.0; 3];
+                for value in &mut position {
+                    let word = words.next().context("STL vertex has fewer than 3 coordinates")?;
+                    *value = word.parse().with_context(|| format!("Invalid STL coordinate {}", word))?;
+                }
+                positions.push(position);
+            }
+            ensure!(positions.len().is_multiple_of(3), "STL facet without 3 vertices");
+        }
+    }
+
+    // Shared corners become shared vertices, recalculating the normals splits
+    // them again between faces of different planes
+    let mut lookup: HashMap<[u32; 3], u32> = HashMap::new();
+    let mut vertices = Vec::new();
+    let indices = positions
+        .iter()
+        .map(|position| {
+            *lookup.entry(position.map(f32::to_bits)).or_insert_with(|| {
+                vertices.push(ModelVertex {
+                    position: *position,
+                    tex_coords: [0.0; 2],
+                    normal: [0.0; 3],
+                    tangent: [0.0; 3],
+                    bitangent: [0.0; 3],
+                    #[cfg(feature = "secondary-uv")]
+                    tex_coords1: [0.0; 2],
+                });
+                vertices.len() as u32 - 1
+            })
+        })
+        .collect();
+    let mut geometry = Geometry::new(vertices, indices);
+    geometry.recalculate_normals(false);
+    return Ok(geometry);
+}
+
+/// Vertices and faces of a PLY file in ASCII or binary format. Texture
+/// coordinates are read from `u`/`v` or `s`/`t` properties, polygons are
+/// triangulated as fans and normals are smoothed from the faces when the
+/// vertices have none.
+pub fn parse_ply_geometry(data: &[u8]) -> Result<Geometry> {
+    let (mut reader, elements) = PlyReader::new(data)?;
+    let mut list = Vec::new();
+    let mut vertices = Vec::new();
+    let mut indices = Vec::new();
+    let mut has_normals = false;
+    let mut has_faces = false;
+    for element in &elements {
+        match element.name.as_str() {
+            "vertex" => {
+                let position = [element.property(&["x"]), element.property(&["y"]), element.property(&["z"])];
+                ensure!(position.iter().all(Option::is_some), "PLY vertices have no x, y and z");
+                let normal = [element.property(&["nx"]), element.property(&["ny"]), element.property(&["nz"])];
+                let tex_coords = [
+                    element.property(&["u", "s", "texture_u", "texture_s"]),
+                    element.property(&["v", "t", "texture_v", "texture_t"]),
+                ];
+                #[cfg(feature = "secondary-uv")]
+                let tex_coords1 = [
+                    element.property(&["u1", "s1", "texture_u1", "texture_s1"]),
+                    element.property(&["v1", "t1", "texture_v1", "texture_t1"]),
+                ];
+                has_normals = normal.iter().all(Option::is_some);
+                let mut values = vec![0.0; element.properties.len()];
+                vertices.reserve(reader.max_rows(element));
+                for _ in 0..element.count {
+                    for (value, property) in values.iter_mut().zip(&element.properties) {
+                        *value = reader.read_property(property, &mut list)?;
+                    }
+                    let value = |i: Option<usize>| i.map_or(0.0, |i| values[i] as f32);
+                    vertices.push(ModelVertex {
+                        position: position.map(value),
+                        tex_coords: tex_coords.map(value),
+                        normal: normal.map(value),
+                        tangent: [0.0; 3],
+                        bitangent: [0.0; 3],
+                        #[cfg(feature = "secondary-uv")]
+                        tex_coords1: tex_coords1.map(value),
+                    });
+                }
+            }
+            "face" => {
+                let corners = element
+                    .property(&["vertex_indices", "vertex_index"])
+                    .context("PLY faces have no vertex_indices")?;
+                has_faces = true;
+                let mut polygon = Vec::new();
+                for _ in 0..element.count {
+                    for (i, property) in element.properties.iter().enumerate() {
+                        if i == corners {
+                            reader.read_property(property, &mut polygon)?;
+                        } else {
+                            reader.read_property(property, &mut list)?;
+                        }
+                    }
+                    for i in 1..polygon.len().saturating_sub(1) {
+                        indices.extend([polygon[0], polygon[i], polygon[i + 1]].map(|c| c as u32));
+                    }
+                }
+            }
+            _ => reader.skip_element(element)?,
+        }
+    }
+    ensure!(has_faces, "PLY file has no faces, see point_cloud::parse_ply for point clouds");
+    ensure!(
+        indices.iter().all(|i| (*i as usize) < vertices.len()),
+        "PLY face refers to a missing vertex"
+    );
+
+    let mut geometry = Geometry::new(vertices, indices);
+    if has_normals {
+        geometry.calculate_tangents_bitangents();
+    } else {
+        geometry.recalculate_normals(true);
+    }
+    return Ok(geometry);
+}
+
+/// Load an `.stl` or `.ply` mesh from the resources, see `parse_stl` and
+/// `parse_ply_geometry`.
+pub async fn load_geometry(file_name: &str) -> Result<Geometry> {
+    let extension = std::path::Path::new(file_name)
+        .extension()
+        .and_then(|e| e.to_str())
+        .map(|e| e.to_ascii_lowercase());
+    let data = load_binary(file_name).await?;
+    let geometry = match extension.as_deref() {
+        Some("stl") => parse_stl(&data),
+        Some("ply") => parse_ply_geometry(&data),
+        _ => bail!("Unknown mesh format {:?}", file_name),
+    };
+    return geometry.with_context(|| format!("Failed to load {:?}", file_name));
+}
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+enum PlyFormat {
+    Ascii,
+    BinaryLittleEndian,
+    BinaryBigEndian,
+}
+
+/// Type of a PLY property's values.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub(crate) enum PlyType {
+    I8,
+    U8,
+    I16,
+    U16,
+    I32,
+    U32,
+    F32,
+    F64,
+}
+
+impl PlyType {
+    fn parse(name: &str) -> Result<Self> {
+        return Ok(match name {
+            "char" | "int8" => PlyType::I8,
+            "uchar" | "uint8" => PlyType::U8,
+            "short" | "int16" => PlyType::I16,
+            "ushort" | "uint16" => PlyType::U16,
+            "int" | "int32" => PlyType::I32,
+            "uint" | "uint32" => PlyType::U32,
+            "float" | "float32" => PlyType::F32,
+            "double" | "float64" => PlyType::F64,
+            _ => bail!("Unknown PLY type {}", name),
+        });
+    }
+
+    fn size(self) -> usize {
+        return match self {
+            PlyType::I8 | PlyType::U8 => 1,
+            PlyType::I16 | PlyType::U16 => 2,
+            PlyType::I32 | PlyType::U32 | PlyType::F32 => 4,
+            PlyType::F64 => 8,
+        };
+    }
+
+    /// Largest value of integer colors, float colors are from 0 to 1.
+    pub(crate) fn color_scale(self) -> f32 {
+        return match self {
+            PlyType::U8 | PlyType::I8 => 255.0,
+            PlyType::U16 | PlyType::I16 => 65535.0,
+            PlyType::U32 | PlyType::I32 => u32::MAX as f32,
+            PlyType::F32 | PlyType::F64 => 1.0,
+        };
+    }
+}
+
+#[derive(Debug, Clone)]
+pub(crate) struct PlyProperty {
+    pub name: String,
+    pub ty: PlyType,
+    // Type of the item count, for list properties
+    pub count: Option<PlyType>,
+}
+
+#[derive(Debug, Clone)]
+pub(crate) struct PlyElement {
+    pub name: String,
+    pub count: usize,
+    pub properties: Vec<PlyProperty>,
+}
+
+impl PlyElement {
+    /// Index of the first property named one of `names`.
+    pub(crate) fn property(&self, names: &[&str]) -> Option<usize> {
+        return self.properties.iter().position(|p| names.contains(&p.name.as_str()));
+    }
+}
+
+/// Walks the values of a PLY file's body, element by element in the order
+/// of the header.
+pub(crate) struct PlyReader<'a> {
+    format: PlyFormat,
+    data: &'a [u8],
+    offset: usize,
+    words: std::str::SplitAsciiWhitespace<'a>,
+}
+
+impl<'a> PlyReader<'a> {
+    /// Parse the header of `data`, returning a reader at the start of the
+    /// body and the elements it contains.
+    pub(crate) fn new(data: &'a [u8]) -> Result<(Self, Vec<PlyElement>)> {
+        const END: &[u8] = b"end_header";
+        ensure!(data.starts_with(b"ply"), "Not a PLY file");
+        let end = data
+            .windows(END.len())
+            .position(|w| w == END)
+            .context("PLY header has no end_header")?;
+        let body_start = data[end..]
+            .iter()
+            .position(|b| *b == b'\n')
+            .map_or(data.len(), |i| end + i + 1);
+        let header = std::str::from_utf8(&data[..end]).context("PLY header is not text")?;
+
+        let mut format = None;
+        let mut elements: Vec<PlyElement> = Vec::new();
+        for line in header.lines().skip(1) {
+            let words = line.split_whitespace().collect::<Vec<_>>();
+            match words.as_slice() {
+                ["format", "ascii", ..] => format = Some(PlyFormat::Ascii),
+                ["format", "binary_little_endian", ..] => format = Some(PlyFormat::BinaryLittleEndian),
+                ["format", "binary_big_endian", ..] => format = Some(PlyFormat::BinaryBigEndian),
+                ["element", name, count] => elements.push(PlyElement {
+                    name: name.to_string(),
+                    count: count.parse().with_context(|| format!("Invalid element count {}", count))?,
+                    properties: Vec::new(),
+                }),
+                ["property", "list", count, ty, name] => {
+                    let element = elements.last_mut().context("PLY property outside of an element")?;
+                    element.properties.push(PlyProperty {
+                        name: name.to_string(),
+                        ty: PlyType::parse(ty)?,
+                        count: Some(PlyType::parse(count)?),
+                    });
+                }
+                ["property", ty, name] => {
+                    let element = elements.last_mut().context("PLY property outside of an element")?;
+                    element.properties.push(PlyProperty {
+                        name: name.to_string(),
+                        ty: PlyType::parse(ty)?,
+                        count: None,
+                    });
+                }
+                _ => {}
+            }
+        }
+        let format = format.context("PLY header has no format")?;
+
+        let data = &data[body_start..];
+        let text = match format {
+            PlyFormat::Ascii => std::str::from_utf8(data).context("PLY body is not text")?,
+            _ => "",
+        };
+        let reader = Self {
+            format,
+            data,
+            offset: 0,
+            words: text.split_ascii_whitespace(),
+        };
+        return Ok((reader, elements));
+    }
+
+    fn read(&mut self, ty: PlyType) -> Result<f64> {
+        if self.format == PlyFormat::Ascii {
+            let word = self.words.next().context("PLY body ends early")?;
+            return word.parse::<f64>().with_context(|| format!("Invalid PLY value {}", word));
+        }
+        let size = ty.size();
+        let bytes = self
+            .data
+            .get(self.offset..self.offset + size)
+            .context("PLY body ends early")?;
+        self.offset += size;
+        let mut buffer = [0u8; 8];
+        buffer[..size].copy_from_slice(bytes);
+        if self.format == PlyFormat::BinaryBigEndian {
+            buffer[..size].reverse();
+        }
+        let value = match ty {
+            PlyType::I8 => buffer[0] as i8 as f64,
+            PlyType::U8 => buffer[0] as f64,
+            PlyType::I16 => i16::from_le_bytes([buffer[0], buffer[1]]) as f64,
+            PlyType::U16 => u16::from_le_bytes([buffer[0], buffer[1]]) as f64,
+            PlyType::I32 => i32::from_le_bytes([buffer[0], buffer[1], buffer[2], buffer[3]]) as f64,
+            PlyType::U32 => u32::from_le_bytes([buffer[0], buffer[1], buffer[2], buffer[3]]) as f64,
+            PlyType::F32 => f32::from_le_bytes([buffer[0], buffer[1], buffer[2], buffer[3]]) as f64,
+            PlyType::F64 => f64::from_le_bytes(buffer),
+        };
+        return Ok(value);
+    }
+
+    /// The next value of `property`. Lists return their length and leave
+    /// their items in `list`.
+    pub(crate) fn read_property(&mut self, property: &PlyProperty, list: &mut Vec<f64>) -> Result<f64> {
+        let count_type = match property.count {
+            Some(count_type) => count_type,
+            None => return self.read(property.ty),
+        };
+        let count = self.read(count_type)? as usize;
+        list.clear();
+        for _ in 0..count {
+            list.push(self.read(property.ty)?);
+        }
+        return Ok(count as f64);
+    }
+
+    /// Most rows of `element` the rest of the body can hold, to bound
+    /// allocations by the data rather than the header's count. Values take
+    /// at least a digit and a separator in ASCII, lists at least their length.
+    pub(crate) fn max_rows(&self, element: &PlyElement) -> usize {
+        let row_size: usize = match self.format {
+            PlyFormat::Ascii => 2 * element.properties.len(),
+            _ => element.properties.iter().map(|p| p.count.unwrap_or(p.ty).size()).sum(),
+        };
+        let remaining = self.data.len().saturating_sub(self.offset);
+        return element.count.min(remaining / row_size.max(1));
+    }
+
+    /// Read past every row of `element`.
+    pub(crate) fn skip_element(&mut self, element: &PlyElement) -> Result<()> {
+        let mut list = Vec::new();
+        for _ in 0..element.count {
+            for property in &element.properties {
+                self.read_property(property, &mut list)?;
+            }
+        }
+        return Ok(());
+    }
+}
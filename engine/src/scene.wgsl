@@ -0,0 +1,32 @@
+// Per-frame values shared by all scene pipelines, see scene.rs. Bound at
+// binding 1 of the camera group.
+struct Scene {
+    // Seconds since the renderer started and since the previous frame
+    time: f32,
+    delta_time: f32,
+    // Render target size in pixels
+    screen_size: vec2<f32>,
+    fog_color: vec3<f32>,
+    // Fog per world unit beyond fog_start, 0 without fog
+    fog_density: f32,
+    ambient_color: vec3<f32>,
+    fog_start: f32,
+    // Direction the first directional light shines in, its strength in w,
+    // all zero without one
+    sun_direction: vec4<f32>,
+};
+
+// 0 without fog, 1 exponential, 2 exponential squared, see `FogMode`
+override FOG_MODE: u32 = 1u;
+
+// Fraction of a surface at `distance` from the eye hidden by fog
+fn fog_amount(scene: Scene, distance: f32) -> f32 {
+    let depth = scene.fog_density * max(distance - scene.fog_start, 0.0);
+    if (FOG_MODE == 0u) {
+        return 0.0;
+    }
+    if (FOG_MODE == 2u) {
+        return 1.0 - exp(-depth * depth);
+    }
+    return 1.0 - exp(-depth);
+}
@@ -0,0 +1,201 @@
+pub mod stress;
+
+use std::{collections::HashMap, path::Path, time::Duration};
+
+use cgmath::{Point3, SquareMatrix};
+
+use crate::{
+    gltf::{write_gltf, GltfNode},
+    light::{DirectionalLight, LightId},
+    renderer::Renderer,
+    settings::AtmosphereSettings,
+};
+
+/// Per-frame values shared by all scene pipelines, bound next to the camera
+/// at binding 1 of the camera group. Matches `Scene` in scene.wgsl.
+#[repr(C)]
+#[derive(Debug, Copy, Clone, PartialEq, bytemuck::Pod, bytemuck::Zeroable)]
+pub struct SceneUniform {
+    time: f32,
+    delta_time: f32,
+    screen_size: [f32; 2],
+    fog_color: [f32; 3],
+    fog_density: f32,
+    ambient_color: [f32; 3],
+    fog_start: f32,
+    // Direction the sun shines in, its strength in w
+    sun_direction: [f32; 4],
+}
+
+impl SceneUniform {
+    /// `time` and `delta_time` in seconds, `sun` the first directional light.
+    pub fn new(
+        time: f32,
+        delta_time: f32,
+        screen_size: [u32; 2],
+        atmosphere: &AtmosphereSettings,
+        sun: Option<&DirectionalLight>,
+    ) -> Self {
+        let sun_direction = match sun {
+            Some(light) => {
+                let d = light.direction;
+                [d.x, d.y, d.z, light.base.strength]
+            }
+            None => [0.0; 4],
+        };
+        return Self {
+            time,
+            delta_time,
+            screen_size: [screen_size[0] as f32, screen_size[1] as f32],
+            fog_color: atmosphere.fog_color,
+            fog_density: atmosphere.fog_density.max(0.0),
+            ambient_color: atmosphere.ambient_color,
+            fog_start: atmosphere.fog_start,
+            sun_direction,
+        };
+    }
+}
+
+/// Something in the scene demo logic can refer to. Instances are never
+/// removed, so their indices stay valid.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+pub enum Entity {
+    Instance(usize),
+    Light(LightId),
+}
+
+/// An entity clicked in the window.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct PickEvent {
+    pub entity: Entity,
+    pub point: Point3<f32>,
+    pub distance: f32,
+}
+
+/// Runs at the start of every update, before instances and lights are uploaded.
+pub type UpdateHandler = Box<dyn FnMut(&mut Renderer, Duration)>;
+/// Runs when an instance or a light gizmo marker is clicked.
+pub type PickedHandler = Box<dyn FnMut(&mut Renderer, &PickEvent)>;
+
+/// Names, tags and event handlers of scene entities, so demo logic can use
+/// stable identifiers instead of indices into the instance or light buffers.
+#[derive(Default)]
+pub struct Scene {
+    names: HashMap<String, Entity>,
+    // Entities of each tag in the order they were tagged
+    tags: HashMap<String, Vec<Entity>>,
+    update_handlers: Vec<UpdateHandler>,
+    picked_handlers: Vec<PickedHandler>,
+}
+
+impl Scene {
+    /// Name `entity`, replacing whatever had the name before.
+    pub fn set_name(&mut self, entity: Entity, name: &str) {
+        self.names.retain(|_, e| *e != entity);
+        self.names.insert(name.to_string(), entity);
+    }
+
+    pub fn find_by_name(&self, name: &str) -> Option<Entity> {
+        return self.names.get(name).copied();
+    }
+
+    pub fn name_of(&self, entity: Entity) -> Option<&str> {
+        return self
+            .names
+            .iter()
+            .find(|(_, e)| **e == entity)
+            .map(|(name, _)| name.as_str());
+    }
+
+    pub fn tag(&mut self, entity: Entity, tag: &str) {
+        let entities = self.tags.entry(tag.to_string()).or_default();
+        if !entities.contains(&entity) {
+            entities.push(entity);
+        }
+    }
+
+    pub fn untag(&mut self, entity: Entity, tag: &str) {
+        if let Some(entities) = self.tags.get_mut(tag) {
+            entities.retain(|e| *e != entity);
+        }
+    }
+
+    pub fn has_tag(&self, entity: Entity, tag: &str) -> bool {
+        return self.find_by_tag(tag).contains(&entity);
+    }
+
+    /// Entities with `tag`, in the order they were tagged.
+    pub fn find_by_tag(&self, tag: &str) -> &[Entity] {
+        return self.tags.get(tag).map(|e| e.as_slice()).unwrap_or(&[]);
+    }
+
+    pub fn tags_of(&self, entity: Entity) -> impl Iterator<Item = &str> {
+        return self
+            .tags
+            .iter()
+            .filter(move |(_, entities)| entities.contains(&entity))
+            .map(|(tag, _)| tag.as_str());
+    }
+
+    pub fn on_update<F: FnMut(&mut Renderer, Duration) + 'static>(&mut self, handler: F) {
+        self.update_handlers.push(Box::new(handler));
+    }
+
+    pub fn on_picked<F: FnMut(&mut Renderer, &PickEvent) + 'static>(&mut self, handler: F) {
+        self.picked_handlers.push(Box::new(handler));
+    }
+
+    pub fn has_picked_handlers(&self) -> bool {
+        return !self.picked_handlers.is_empty();
+    }
+
+    // Handlers get the whole renderer, so they're taken out of its scene
+    // while running. Handlers they add are kept after the existing ones
+    pub(crate) fn run_update(renderer: &mut Renderer, dt: Duration) {
+        let mut handlers = std::mem::take(&mut renderer.scene.update_handlers);
+        for handler in &mut handlers {
+            handler(renderer, dt);
+        }
+        handlers.append(&mut renderer.scene.update_handlers);
+        renderer.scene.update_handlers = handlers;
+    }
+
+    pub(crate) fn run_picked(renderer: &mut Renderer, event: &PickEvent) {
+        let mut handlers = std::mem::take(&mut renderer.scene.picked_handlers);
+        for handler in &mut handlers {
+            handler(renderer, event);
+        }
+        handlers.append(&mut renderer.scene.picked_handlers);
+        renderer.scene.picked_handlers = handlers;
+    }
+
+    /// Write what the renderer drew last frame to a glTF file for inspection
+    /// in other tools: a node per visible instance at its current transform
+    /// and LOD, named like its entity, and the static batch. Crowds and
+    /// particles are left out.
+    pub fn export_gltf(renderer: &Renderer, path: &Path) -> anyhow::Result<()> {
+        let mut nodes = Vec::new();
+        for (model, slots) in renderer.visible_models() {
+            for &slot in slots {
+                let index = slot as usize;
+                let name = match renderer.scene.name_of(Entity::Instance(index)) {
+                    Some(name) => name.to_string(),
+                    None => format!("Instance {}", index),
+                };
+                nodes.push(GltfNode {
+                    name,
+                    model,
+                    transform: renderer.instances()[index].model_matrix(),
+                });
+            }
+        }
+        if let Some(batch) = renderer.static_batch() {
+            nodes.push(GltfNode {
+                name: "Static Batch".to_string(),
+                model: batch,
+                transform: cgmath::Matrix4::identity(),
+            });
+        }
+        return write_gltf(renderer.device(), renderer.queue(), &nodes, path);
+    }
+}
@@ -0,0 +1,295 @@
+use std::{collections::BTreeMap, f32::consts::TAU};
+
+use anyhow::*;
+use cgmath::{prelude::*, Quaternion, Rad, Vector3};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    batch::StaticBatcher,
+    geometry::Geometry,
+    layers::RenderLayers,
+    light::{LightId, LightKind, PointLight, LIGHT_CUTOFF, MAX_POINT_LIGHTS},
+    material::{MaterialDesc, ParamValue},
+    model::{Material, Model},
+    particles::Rng,
+    renderer::Renderer,
+    resources::{solid_texture, Instance},
+};
+
+/// Where generated instances go, all centered on the origin.
+#[derive(Debug, Copy, Clone, PartialEq, Serialize, Deserialize)]
+pub enum StressLayout {
+    /// Square grid on the ground, `spacing` apart.
+    Grid { spacing: f32 },
+    /// Spread evenly over a sphere's surface.
+    Sphere { radius: f32 },
+}
+
+/// How generated instances are drawn.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum StressDraw {
+    /// The renderer's model instanced, culled and sorted per LOD level. Its
+    /// own materials are used.
+    Instanced,
+    /// Unit cubes merged into the static batch, a submesh per generated
+    /// material.
+    Batched,
+}
+
+/// Parameters of a generated stress scene, e.g. in RON:
+/// `(instances: 10000, layout: Sphere(radius: 50.0), lights: 128, materials: 16, draw: Batched)`.
+/// The same description and seed always generate the same scene.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct StressSceneDesc {
+    pub seed: u32,
+    pub instances: usize,
+    pub layout: StressLayout,
+    /// Random yaw and up to this much larger or smaller scale per instance,
+    /// 0 for identical copies.
+    pub jitter: f32,
+    pub draw: StressDraw,
+    /// Point lights, at most `MAX_POINT_LIGHTS`.
+    pub lights: usize,
+    /// Distance at which a light's contribution fades out.
+    pub light_range: f32,
+    /// Radius of the circle each light moves around its start position.
+    pub light_orbit: f32,
+    /// Radians per second lights move around their circles, 0 keeps them still.
+    pub light_speed: f32,
+    /// Materials of distinct colors and roughness, used by `StressDraw::Batched`.
+    pub materials: usize,
+}
+
+impl Default for StressSceneDesc {
+    fn default() -> Self {
+        Self {
+            seed: 1,
+            instances: 1000,
+            layout: StressLayout::Grid { spacing: 2.0 },
+            jitter: 0.0,
+            draw: StressDraw::Instanced,
+            lights: 32,
+            light_range: 8.0,
+            light_orbit: 2.0,
+            light_speed: 1.0,
+            materials: 8,
+        }
+    }
+}
+
+impl StressSceneDesc {
+    pub fn parse(source: &str) -> Result<Self> {
+        return ron::from_str(source).context("Invalid stress scene description");
+    }
+}
+
+/// A light of a stress scene, circling `center` from `phase`.
+#[derive(Debug, Clone)]
+pub struct StressLight {
+    pub center: Vector3<f32>,
+    pub phase: f32,
+    pub light: PointLight,
+}
+
+/// Instances, lights and materials generated from a `StressSceneDesc`, so
+/// performance features like clustering, culling and batching are measured
+/// on the same scene every time.
+#[derive(Debug, Clone)]
+pub struct StressScene {
+    pub desc: StressSceneDesc,
+    pub instances: Vec<Instance>,
+    pub lights: Vec<StressLight>,
+    pub materials: Vec<MaterialDesc>,
+    /// Index into `materials` of each instance.
+    pub instance_materials: Vec<usize>,
+}
+
+impl StressScene {
+    pub fn generate(desc: &StressSceneDesc) -> Self {
+        let mut rng = Rng(desc.seed.max(1));
+        let positions = match desc.layout {
+            StressLayout::Grid { spacing } => grid(desc.instances, spacing),
+            StressLayout::Sphere { radius } => sphere(desc.instances, radius),
+        };
+        let instances = positions
+            .into_iter()
+            .map(|position| {
+                let yaw = rng.next() * TAU * desc.jitter.min(1.0);
+                let scale = 1.0 + rng.range((-desc.jitter, desc.jitter));
+                Instance {
+                    position,
+                    rotation: Quaternion::from_angle_y(Rad(yaw)),
+                    scale: Vector3::from_value(scale.max(0.01)),
+                    layers: RenderLayers::DEFAULT,
+                    outline: false,
+                }
+            })
+            .collect::<Vec<_>>();
+        let material_count = desc.materials.max(1);
+        let instance_materials = (0..instances.len()).map(|_| (rng.next() * material_count as f32) as usize).collect();
+
+        // Spread over the instances' bounds, a little above them
+        let (min, max) = instances.iter().fold(
+            (Vector3::from_value(f32::INFINITY), Vector3::from_value(f32::NEG_INFINITY)),
+            |(min, max), i| {
+                let p = i.position;
+                (
+                    Vector3::new(min.x.min(p.x), min.y.min(p.y), min.z.min(p.z)),
+                    Vector3::new(max.x.max(p.x), max.y.max(p.y), max.z.max(p.z)),
+                )
+            },
+        );
+        let (min, max) = if instances.is_empty() {
+            (Vector3::zero(), Vector3::zero())
+        } else {
+            (min, max + Vector3::unit_y() * 2.0)
+        };
+        // Falls off to the cutoff at `light_range`
+        let exp = (1.0 / LIGHT_CUTOFF - 1.0) / desc.light_range.max(0.1).powi(2);
+        let lights = (0..desc.lights.min(MAX_POINT_LIGHTS))
+            .map(|_| {
+                let center = Vector3::new(
+                    rng.range((min.x, max.x)),
+                    rng.range((min.y, max.y)),
+                    rng.range((min.z, max.z)),
+                );
+                let phase = rng.next() * TAU;
+                StressLight {
+                    center,
+                    phase,
+                    light: PointLight::new(hue(rng.next()), center, 1.0, 0.0, exp),
+                }
+            })
+            .collect();
+
+        let materials = (0..material_count)
+            .map(|i| {
+                // Golden ratio steps keep neighbouring materials apart in hue
+                let [r, g, b] = hue((i as f32 * 0.618_034).fract());
+                let mut params = BTreeMap::new();
+                params.insert("tint".to_string(), ParamValue::Vec4([r, g, b, 1.0]));
+                params.insert("roughness".to_string(), ParamValue::Float(rng.range((0.2, 0.9))));
+                MaterialDesc {
+                    name: format!("Stress {}", i),
+                    params,
+                    ..MaterialDesc::default()
+                }
+            })
+            .collect();
+
+        return Self {
+            desc: desc.clone(),
+            instances,
+            lights,
+            materials,
+            instance_materials,
+        };
+    }
+
+    /// Light `index` at `time` seconds into its orbit.
+    pub fn light_at(&self, index: usize, time: f32) -> PointLight {
+        return orbit(&self.lights[index], self.desc.light_orbit, self.desc.light_speed, time);
+    }
+
+    /// Replace the renderer's instances, point lights and static batch with
+    /// the scene's. The lights are animated by a simulation system, so the
+    /// simulation can't be detached.
+    pub fn apply(&self, renderer: &mut Renderer) -> Result<()> {
+        let simulation = renderer
+            .simulator
+            .simulation_mut()
+            .context("Stress scene lights need the simulation attached")?;
+        let (radius, speed) = (self.desc.light_orbit, self.desc.light_speed);
+        let lights = self.lights.clone();
+        simulation.add_system(move |state, _| {
+            let time = state.elapsed.as_secs_f32();
+            state.lights.retain(|(id, _)| id.kind != LightKind::Point || id.index >= lights.len());
+            for (index, light) in lights.iter().enumerate() {
+                let id = LightId {
+                    kind: LightKind::Point,
+                    index,
+                };
+                state.lights.push((id, orbit(light, radius, speed, time).into()));
+            }
+        });
+        renderer.light_manager.point_count = self.lights.len() as u32;
+        renderer.light_manager.update_light_counts(renderer.queue());
+
+        match self.desc.draw {
+            StressDraw::Instanced => {
+                *renderer.instances_mut() = self.instances.clone();
+                renderer.set_static_batch(None);
+            }
+            StressDraw::Batched => {
+                let batch = self.build_batch(renderer)?;
+                renderer.instances_mut().clear();
+                renderer.set_static_batch(Some(batch));
+            }
+        }
+        return Ok(());
+    }
+
+    // Unit cubes at the instances, one submesh per material
+    fn build_batch(&self, renderer: &Renderer) -> Result<Model> {
+        let (device, memory, queue) = (renderer.device(), renderer.memory(), renderer.queue());
+        let cube = Geometry::cuboid(1.0, 1.0, 1.0);
+        let mut batcher = StaticBatcher::new();
+        for (instance, material) in self.instances.iter().zip(&self.instance_materials) {
+            batcher.add(&cube, instance.model_matrix(), *material);
+        }
+        let mut materials = Vec::new();
+        for desc in &self.materials {
+            let params = desc.build_params(Material::default_params(renderer.material_layout().clone()))?;
+            materials.push(Material::new(
+                device,
+                memory,
+                &desc.name,
+                solid_texture(device, memory, queue, [255; 4], "white", false)?,
+                solid_texture(device, memory, queue, [128, 128, 255, 255], "flat normal", true)?,
+                params,
+                renderer.material_bind_group_layout(),
+            ));
+        }
+        return Ok(batcher.build(device, memory, "Stress Batch", materials));
+    }
+}
+
+fn orbit(light: &StressLight, radius: f32, speed: f32, time: f32) -> PointLight {
+    let angle = light.phase + speed * time;
+    let offset = Vector3::new(angle.cos(), 0.0, angle.sin()) * radius;
+    let mut moved = light.light.clone();
+    moved.position = light.center + offset;
+    return moved;
+}
+
+// Rows of the square that fits `count`, the last one partly filled
+fn grid(count: usize, spacing: f32) -> Vec<Vector3<f32>> {
+    let side = (count as f32).sqrt().ceil() as usize;
+    let offset = (side.saturating_sub(1)) as f32 * spacing * 0.5;
+    return (0..count)
+        .map(|i| Vector3::new((i % side) as f32 * spacing - offset, 0.0, (i / side) as f32 * spacing - offset))
+        .collect();
+}
+
+// Fibonacci lattice, about equal area per point
+fn sphere(count: usize, radius: f32) -> Vec<Vector3<f32>> {
+    let golden_angle = TAU * (1.0 - 0.618_034);
+    return (0..count)
+        .map(|i| {
+            let y = 1.0 - 2.0 * (i as f32 + 0.5) / count as f32;
+            let r = (1.0 - y * y).sqrt();
+            let angle = i as f32 * golden_angle;
+            Vector3::new(r * angle.cos(), y, r * angle.sin()) * radius
+        })
+        .collect();
+}
+
+// Fully saturated color of `hue` in 0..1
+fn hue(hue: f32) -> [f32; 3] {
+    let channel = |offset: f32| {
+        let k = (hue * 6.0 + offset) % 6.0;
+        1.0 - (k.min(4.0 - k).clamp(0.0, 1.0))
+    };
+    return [channel(5.0), channel(3.0), channel(1.0)];
+}
@@ -0,0 +1,332 @@
+use serde::{Deserialize, Serialize};
+
+use std::path::PathBuf;
+
+use crate::{
+    capture::CaptureSettings, decode::DecodeSettings, frame::FRAMES_IN_FLIGHT, input::ActionMap, overlay::OverlayAlpha,
+    resources::InstanceFormat, simulation::SyncPolicy,
+};
+
+pub use crate::controller::{ControllerSettings, InputMode, KeyBinding, KeyBindings, LookMode, ScrollMode};
+
+/// Display calibration applied in the final post pass, as usually exposed in
+/// a game's video options.
+#[derive(Debug, Copy, Clone, PartialEq, Serialize, Deserialize)]
+pub struct DisplaySettings {
+    pub gamma: f32,
+    pub brightness: f32,
+    pub contrast: f32,
+    pub saturation: f32,
+}
+
+impl Default for DisplaySettings {
+    fn default() -> Self {
+        Self {
+            gamma: 1.0,
+            brightness: 0.0,
+            contrast: 1.0,
+            saturation: 1.0,
+        }
+    }
+}
+
+/// When the event loop asks for new frames.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum RedrawMode {
+    // Render as fast as presentation allows
+    Continuous,
+    // Sleep until input arrives or something is animating
+    OnDemand,
+}
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct FrameSettings {
+    pub redraw_mode: RedrawMode,
+    /// Frame rate cap while the window is unfocused; `None` keeps the normal rate.
+    pub background_fps: Option<f32>,
+    /// Frame rate cap while focused; `None` renders as fast as presentation allows.
+    pub target_fps: Option<f32>,
+    /// Frames the CPU may submit ahead of the GPU, 1 to `FRAMES_IN_FLIGHT`.
+    /// Fewer lowers latency, more keeps the GPU busier.
+    pub frames_in_flight: usize,
+    /// Wait for vertical blank to present. Without it frames are presented
+    /// immediately where the surface allows, tearing for lower latency.
+    pub vsync: bool,
+}
+
+impl Default for FrameSettings {
+    fn default() -> Self {
+        Self {
+            redraw_mode: RedrawMode::Continuous,
+            background_fps: Some(10.0),
+            target_fps: None,
+            frames_in_flight: FRAMES_IN_FLIGHT,
+            vsync: true,
+        }
+    }
+}
+
+/// Passes and draw groups of a frame that can be switched off at runtime,
+/// e.g. to bisect performance problems or to run on weak hardware. Read every
+/// frame; the post pass always runs since it resolves the scene to the surface.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct PassSettings {
+    /// Shadow maps of lights with shadow settings.
+    pub shadows: bool,
+    pub models: bool,
+    pub crowds: bool,
+    pub particles: bool,
+    /// Ribbons of `TrailRenderer::trails`.
+    pub trails: bool,
+    /// Materials and particles blended with order independent transparency.
+    pub transparency: bool,
+    pub point_clouds: bool,
+    pub lens_flare: bool,
+    /// Light shafts of lights with `VolumetricSettings`.
+    pub volumetric: bool,
+    /// HUD and everything else drawn through the overlay.
+    pub overlay: bool,
+    /// Scene luminance histogram, see `Renderer::scene_luminance`.
+    pub luminance: bool,
+}
+
+impl Default for PassSettings {
+    fn default() -> Self {
+        Self {
+            shadows: true,
+            models: true,
+            crowds: true,
+            particles: true,
+            trails: true,
+            transparency: true,
+            point_clouds: true,
+            lens_flare: true,
+            volumetric: true,
+            overlay: true,
+            luminance: false,
+        }
+    }
+}
+
+/// Format of the scene's depth buffer. Formats with a stencil aspect allow
+/// outline and mask effects.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum DepthFormat {
+    Depth32Float,
+    Depth24PlusStencil8,
+    // Needs `Features::DEPTH32FLOAT_STENCIL8`
+    Depth32FloatStencil8,
+}
+
+impl DepthFormat {
+    pub fn format(self) -> wgpu::TextureFormat {
+        return match self {
+            DepthFormat::Depth32Float => wgpu::TextureFormat::Depth32Float,
+            DepthFormat::Depth24PlusStencil8 => wgpu::TextureFormat::Depth24PlusStencil8,
+            DepthFormat::Depth32FloatStencil8 => wgpu::TextureFormat::Depth32FloatStencil8,
+        };
+    }
+
+    pub fn has_stencil(self) -> bool {
+        return self != DepthFormat::Depth32Float;
+    }
+
+    /// Device features that have to be requested to use the format.
+    pub fn required_features(self) -> wgpu::Features {
+        return match self {
+            DepthFormat::Depth32FloatStencil8 => wgpu::Features::DEPTH32FLOAT_STENCIL8,
+            _ => wgpu::Features::empty(),
+        };
+    }
+
+    /// Whether `adapter` can render into the format with `sample_count` samples.
+    pub fn is_supported(self, adapter: &wgpu::Adapter, sample_count: u32) -> bool {
+        if !adapter.features().contains(self.required_features()) {
+            return false;
+        }
+        let features = adapter.get_texture_format_features(self.format());
+        return features
+            .allowed_usages
+            .contains(wgpu::TextureUsages::RENDER_ATTACHMENT)
+            && (sample_count <= 1
+                || features
+                    .flags
+                    .contains(wgpu::TextureFormatFeatureFlags::MULTISAMPLE));
+    }
+}
+
+/// Scene-wide shading values, given to shaders through the scene uniform.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct AtmosphereSettings {
+    /// Light added to every lit surface, in linear RGB.
+    pub ambient_color: [f32; 3],
+    pub fog_color: [f32; 3],
+    /// Fog density per world unit, 0 disables fog.
+    pub fog_density: f32,
+    /// Distance from the eye where fog starts.
+    pub fog_start: f32,
+    /// Haze lit surfaces with the renderer's atmosphere, see
+    /// `Renderer::set_atmosphere`. Lit by the first directional light.
+    pub scattering: bool,
+    /// Read when the shaders are built, unlike the rest.
+    pub fog_mode: FogMode,
+}
+
+impl Default for AtmosphereSettings {
+    fn default() -> Self {
+        Self {
+            ambient_color: [0.0; 3],
+            fog_color: [0.5, 0.6, 0.7],
+            fog_density: 0.0,
+            fog_start: 0.0,
+            scattering: false,
+            fog_mode: FogMode::Exponential,
+        }
+    }
+}
+
+/// Falloff of `AtmosphereSettings` fog with distance, compiled into the
+/// scene shaders.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum FogMode {
+    /// Fog compiled out, whatever its density.
+    Off,
+    Exponential,
+    /// Clearer close to `fog_start` and thicker further out than exponential.
+    ExponentialSquared,
+}
+
+impl FogMode {
+    // Value of the shaders' `FOG_MODE` constant
+    pub(crate) fn shader_constant(self) -> u32 {
+        return match self {
+            FogMode::Off => 0,
+            FogMode::Exponential => 1,
+            FogMode::ExponentialSquared => 2,
+        };
+    }
+}
+
+/// What the scene is drawn over where no geometry covers it.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum BackgroundMode {
+    /// Clear to `BackgroundSettings::color`.
+    Color,
+    /// Blend from the horizon color to the zenith color with the view
+    /// direction's elevation, the horizon color continues below.
+    Gradient,
+    /// Screen-fixed image set with `Renderer::set_background_image`, the
+    /// color where there is none.
+    Image,
+    /// Sky scattered by the renderer's atmosphere, lit by the first
+    /// directional light.
+    Atmosphere,
+}
+
+/// How a background image is scaled to the screen.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum ImageFit {
+    /// Fill the screen, ignoring the image's aspect ratio.
+    Stretch,
+    /// Show the whole image, bars of the background color around it.
+    Contain,
+    /// Fill the screen, cropping what doesn't fit.
+    Cover,
+}
+
+/// Lightweight alternative to a skybox, read every frame. Colors in linear RGB.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct BackgroundSettings {
+    pub mode: BackgroundMode,
+    pub color: [f32; 3],
+    pub zenith: [f32; 3],
+    pub horizon: [f32; 3],
+    pub image_fit: ImageFit,
+}
+
+impl Default for BackgroundSettings {
+    fn default() -> Self {
+        Self {
+            mode: BackgroundMode::Color,
+            color: [0.1, 0.2, 0.3],
+            zenith: [0.15, 0.3, 0.6],
+            horizon: [0.6, 0.7, 0.8],
+            image_fit: ImageFit::Contain,
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Settings {
+    pub display: DisplaySettings,
+    pub frame: FrameSettings,
+    pub passes: PassSettings,
+    pub atmosphere: AtmosphereSettings,
+    pub background: BackgroundSettings,
+    /// Read when the renderer creates the camera.
+    pub controller: ControllerSettings,
+    /// Multiplies the window's DPI scale factor for everything drawn through
+    /// the overlay, read every frame.
+    pub ui_scale: f32,
+    /// Resolution the scene is rendered at relative to the size it is shown
+    /// at, read every frame. The final pass scales it to the window.
+    pub render_scale: f32,
+    /// Whether overlay colors are premultiplied by their alpha, read when
+    /// the renderer is created.
+    pub overlay_alpha: OverlayAlpha,
+    /// Mode at startup, Tab or `Renderer::set_input_mode` switch it later.
+    pub input_mode: InputMode,
+    /// Used when a capture is started from the keyboard.
+    pub capture: CaptureSettings,
+    /// Keys and gamepad buttons running engine actions, read on every press.
+    pub actions: ActionMap,
+    /// Where `Action::Screenshot` writes its images.
+    pub screenshot_directory: PathBuf,
+    /// Samples per pixel of the scene pass, read when pipelines are built.
+    /// Alpha-cutout materials use alpha-to-coverage above 1 and dithering otherwise.
+    pub msaa_samples: u32,
+    /// Read when the renderer is created, unsupported formats fall back to
+    /// `DepthFormat::Depth32Float`.
+    pub depth_format: DepthFormat,
+    /// Per-instance data layout of the model pipelines, read when pipelines are built.
+    pub model_instance_format: InstanceFormat,
+    /// Per-instance data layout of crowds, read when pipelines are built.
+    pub crowd_instance_format: InstanceFormat,
+    /// Read when the renderer is created; `None` runs the simulation on the
+    /// render thread, `Renderer::simulator` can detach it later.
+    pub update_thread: Option<SyncPolicy>,
+    /// Texture decode workers, read when the renderer is created.
+    pub decode: DecodeSettings,
+    /// Draw the model instances of each LOD level nearest to the camera
+    /// first, so early depth testing skips shading what they hide. Read every
+    /// frame, the instances are re-sorted whenever the camera moves. The HUD
+    /// shows the estimated overdraw either way.
+    pub sort_front_to_back: bool,
+}
+
+impl Default for Settings {
+    fn default() -> Self {
+        Self {
+            display: DisplaySettings::default(),
+            frame: FrameSettings::default(),
+            passes: PassSettings::default(),
+            atmosphere: AtmosphereSettings::default(),
+            background: BackgroundSettings::default(),
+            controller: ControllerSettings::default(),
+            ui_scale: 1.0,
+            render_scale: 1.0,
+            overlay_alpha: OverlayAlpha::Straight,
+            input_mode: InputMode::GameLook,
+            capture: CaptureSettings::default(),
+            actions: ActionMap::default(),
+            screenshot_directory: PathBuf::from("screenshots"),
+            msaa_samples: 1,
+            depth_format: DepthFormat::Depth32Float,
+            model_instance_format: InstanceFormat::Matrix,
+            crowd_instance_format: InstanceFormat::Matrix,
+            update_thread: None,
+            decode: DecodeSettings::default(),
+            sort_front_to_back: false,
+        }
+    }
+}
@@ -0,0 +1,297 @@
+use std::{
+    borrow::Cow,
+    collections::{BTreeMap, HashMap, HashSet},
+};
+
+use anyhow::*;
+
+/// Engine shader files, available to `#include` by name.
+const BUILTIN_FILES: &[(&str, &str)] = &[
+    ("atmosphere.wgsl", include_str!("atmosphere.wgsl")),
+    ("atmosphere_lut.wgsl", include_str!("atmosphere_lut.wgsl")),
+    ("background.wgsl", include_str!("background.wgsl")),
+    ("basic.wgsl", include_str!("basic.wgsl")),
+    ("camera.wgsl", include_str!("camera.wgsl")),
+    ("cubemap.wgsl", include_str!("cubemap.wgsl")),
+    ("depth_probe.wgsl", include_str!("depth_probe.wgsl")),
+    ("fallback.wgsl", include_str!("fallback.wgsl")),
+    ("flare.wgsl", include_str!("flare.wgsl")),
+    ("gizmo.wgsl", include_str!("gizmo.wgsl")),
+    ("ibl.wgsl", include_str!("ibl.wgsl")),
+    ("instance.wgsl", include_str!("instance.wgsl")),
+    ("light.wgsl", include_str!("light.wgsl")),
+    ("lightmap.wgsl", include_str!("lightmap.wgsl")),
+    ("lights.wgsl", include_str!("lights.wgsl")),
+    ("luminance.wgsl", include_str!("luminance.wgsl")),
+    ("motion.wgsl", include_str!("motion.wgsl")),
+    ("oit.wgsl", include_str!("oit.wgsl")),
+    ("oit_composite.wgsl", include_str!("oit_composite.wgsl")),
+    ("overlay.wgsl", include_str!("overlay.wgsl")),
+    ("particles.wgsl", include_str!("particles.wgsl")),
+    ("point_cloud.wgsl", include_str!("point_cloud.wgsl")),
+    ("picking.wgsl", include_str!("picking.wgsl")),
+    ("post.wgsl", include_str!("post.wgsl")),
+    ("scene.wgsl", include_str!("scene.wgsl")),
+    ("shadow.wgsl", include_str!("shadow.wgsl")),
+    ("tangents.wgsl", include_str!("tangents.wgsl")),
+    ("trail.wgsl", include_str!("trail.wgsl")),
+    ("volumetric.wgsl", include_str!("volumetric.wgsl")),
+];
+
+/// Composes WGSL from several files and strips disabled variants before the
+/// source reaches the shader compiler.
+///
+/// Supported directives, each on its own line:
+/// - `#include "file.wgsl"`, every file is included at most once
+/// - `#define NAME [value]` and `#undef NAME`
+/// - `#ifdef NAME`, `#ifndef NAME`, `#else`, `#endif`
+///
+/// Defines with a value are substituted wherever `NAME` appears as a whole
+/// identifier.
+///
+/// Sizes and modes shared with Rust are declared as pipeline-overridable
+/// constants, `override NAME: type = default;` on a line of their own, and
+/// specialized with `set_constant`. naga can't override them when the
+/// pipeline is created yet, so they are baked in as module constants here:
+/// every specialization is a module and pipeline of its own.
+#[derive(Debug, Clone)]
+pub struct ShaderPreprocessor {
+    files: HashMap<String, Cow<'static, str>>,
+    defines: BTreeMap<String, String>,
+    constants: BTreeMap<String, String>,
+}
+
+struct Condition {
+    active: bool,
+    // Whether some branch of this block was already taken
+    taken: bool,
+}
+
+impl Default for ShaderPreprocessor {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl ShaderPreprocessor {
+    pub fn new() -> Self {
+        let files = BUILTIN_FILES
+            .iter()
+            .map(|(name, source)| (name.to_string(), Cow::Borrowed(*source)))
+            .collect();
+        return Self {
+            files,
+            defines: BTreeMap::new(),
+            constants: BTreeMap::new(),
+        };
+    }
+
+    /// Register or replace a file that can be processed or included.
+    pub fn add_file<S: Into<Cow<'static, str>>>(&mut self, name: &str, source: S) {
+        self.files.insert(name.to_string(), source.into());
+    }
+
+    pub fn define<V: ToString>(&mut self, name: &str, value: V) {
+        self.defines.insert(name.to_string(), value.to_string());
+    }
+
+    /// Define a flag without a value, only meaningful to `#ifdef`.
+    pub fn enable(&mut self, name: &str) {
+        self.defines.insert(name.to_string(), String::new());
+    }
+
+    pub fn undefine(&mut self, name: &str) {
+        self.defines.remove(name);
+    }
+
+    pub fn is_defined(&self, name: &str) -> bool {
+        return self.defines.contains_key(name);
+    }
+
+    /// Specialize the `override` constant `name`, written as a literal of
+    /// its declared type, e.g. `256u` for a `u32`.
+    pub fn set_constant<V: ToString>(&mut self, name: &str, value: V) {
+        self.constants.insert(name.to_string(), value.to_string());
+    }
+
+    pub fn process(&self, name: &str) -> Result<String> {
+        let mut defines = self.defines.clone();
+        let mut included = HashSet::new();
+        let mut output = String::new();
+        self.process_file(name, &mut defines, &mut included, &mut output)?;
+        return Ok(output);
+    }
+
+    /// Processed source of `name`, ready for `Device::create_shader_module`.
+    pub fn descriptor(&self, label: &'static str, name: &str) -> Result<wgpu::ShaderModuleDescriptor<'static>> {
+        let source = self.process(name)?;
+        return Ok(wgpu::ShaderModuleDescriptor {
+            label: Some(label),
+            source: wgpu::ShaderSource::Wgsl(source.into()),
+        });
+    }
+
+    fn process_file(
+        &self,
+        name: &str,
+        defines: &mut BTreeMap<String, String>,
+        included: &mut HashSet<String>,
+        output: &mut String,
+    ) -> Result<()> {
+        let source = self
+            .files
+            .get(name)
+            .with_context(|| format!("Shader file `{}` not found", name))?;
+        included.insert(name.to_string());
+
+        let mut conditions: Vec<Condition> = Vec::new();
+        for (number, line) in source.lines().enumerate() {
+            let error = |message: &str| anyhow!("{}:{}: {}", name, number + 1, message);
+            let active = conditions.last().is_none_or(|c| c.active);
+            let trimmed = line.trim();
+
+            let directive = match trimmed.strip_prefix('#') {
+                Some(directive) => directive,
+                None => {
+                    if !active {
+                        continue;
+                    }
+                    if is_override(trimmed) {
+                        let constant = self.specialize(trimmed).map_err(|e| error(&e.to_string()))?;
+                        output.push_str(&substitute(&constant, defines));
+                    } else {
+                        output.push_str(&substitute(line, defines));
+                    }
+                    output.push('\n');
+                    continue;
+                }
+            };
+
+            let mut parts = directive.splitn(2, char::is_whitespace);
+            let keyword = parts.next().unwrap_or_default();
+            let argument = parts.next().unwrap_or_default().trim();
+            match keyword {
+                "include" => {
+                    if !active {
+                        continue;
+                    }
+                    let file = argument
+                        .strip_prefix('"')
+                        .and_then(|a| a.strip_suffix('"'))
+                        .ok_or_else(|| error("expected `#include \"file\"`"))?;
+                    if !included.contains(file) {
+                        self.process_file(file, defines, included, output)
+                            .with_context(|| format!("included from {}:{}", name, number + 1))?;
+                    }
+                }
+                "define" => {
+                    if !active {
+                        continue;
+                    }
+                    let mut parts = argument.splitn(2, char::is_whitespace);
+                    let define = parts.next().filter(|d| !d.is_empty());
+                    let define = define.ok_or_else(|| error("expected a name after `#define`"))?;
+                    let value = parts.next().unwrap_or_default().trim();
+                    defines.insert(define.to_string(), value.to_string());
+                }
+                "undef" => {
+                    if active {
+                        defines.remove(argument);
+                    }
+                }
+                "ifdef" | "ifndef" => {
+                    let defined = defines.contains_key(argument);
+                    let condition = defined == (keyword == "ifdef");
+                    conditions.push(Condition {
+                        active: active && condition,
+                        taken: condition,
+                    });
+                }
+                "else" => {
+                    let parent_active = conditions.len() < 2 || conditions[conditions.len() - 2].active;
+                    let condition = conditions
+                        .last_mut()
+                        .ok_or_else(|| error("`#else` without `#ifdef`"))?;
+                    condition.active = parent_active && !condition.taken;
+                    condition.taken = true;
+                }
+                "endif" => {
+                    conditions.pop().ok_or_else(|| error("`#endif` without `#ifdef`"))?;
+                }
+                _ => return Err(error(&format!("unknown directive `#{}`", keyword))),
+            }
+        }
+
+        if !conditions.is_empty() {
+            bail!("{}: unterminated `#ifdef`", name);
+        }
+        return Ok(());
+    }
+
+    // `override NAME: type = default;` as a module constant with the value
+    // set for it or its default
+    fn specialize(&self, declaration: &str) -> Result<String> {
+        let rest = declaration.trim_end_matches(';');
+        // Numeric ids only matter to the pipeline, which sees a constant
+        let rest = match rest.strip_prefix("@id(") {
+            Some(rest) => rest.split_once(')').map_or(rest, |(_, rest)| rest).trim_start(),
+            None => rest,
+        };
+        let rest = rest.trim_start_matches("override").trim();
+        let (declared, default) = match rest.split_once('=') {
+            Some((declared, default)) => (declared.trim(), Some(default.trim())),
+            None => (rest, None),
+        };
+        let (name, ty) = declared
+            .split_once(':')
+            .map(|(name, ty)| (name.trim(), ty.trim()))
+            .ok_or_else(|| anyhow!("expected `override NAME: type = default;`"))?;
+        let value = match self.constants.get(name) {
+            Some(value) => typed_literal(ty, value),
+            None => default
+                .ok_or_else(|| anyhow!("`{}` has no default and no value set with `set_constant`", name))?
+                .to_string(),
+        };
+        return Ok(format!("let {}: {} = {};", name, ty, value));
+    }
+}
+
+fn is_override(line: &str) -> bool {
+    return line.starts_with("override ") || (line.starts_with("@id(") && line.contains(") override "));
+}
+
+// `value` with the suffix its type needs if it's a bare integer, e.g. `256`
+// set for a `u32` becomes `256u`
+fn typed_literal(ty: &str, value: &str) -> String {
+    let integer = value.parse::<i64>().is_ok();
+    return match ty {
+        "u32" if integer => format!("{}u", value),
+        "f32" if integer => format!("{}.0", value),
+        _ => value.to_string(),
+    };
+}
+
+/// Replace identifiers that have a define value.
+fn substitute(line: &str, defines: &BTreeMap<String, String>) -> String {
+    let mut result = String::with_capacity(line.len());
+    let mut rest = line;
+    while let Some(start) = rest.find(|c: char| c.is_ascii_alphabetic() || c == '_') {
+        let (before, from) = rest.split_at(start);
+        result.push_str(before);
+
+        let end = from
+            .find(|c: char| !(c.is_ascii_alphanumeric() || c == '_'))
+            .unwrap_or(from.len());
+        let (identifier, after) = from.split_at(end);
+
+        // Part of a number literal such as `1e5` or `2u`
+        let in_number = before.chars().last().is_some_and(|c| c.is_ascii_digit() || c == '.');
+        match defines.get(identifier) {
+            Some(value) if !value.is_empty() && !in_number => result.push_str(value),
+            _ => result.push_str(identifier),
+        }
+        rest = after;
+    }
+    result.push_str(rest);
+    return result;
+}
@@ -0,0 +1,434 @@
+use cgmath::{prelude::*, Deg, Matrix4, Point3, Rad, Vector3, Vector4};
+
+use crate::{
+    camera::{ortho_rh, perspective_rh},
+    light::LightId,
+    math::Aabb,
+    memory::{MemoryCategory, MemoryTracker, TrackedBuffer},
+    texture::Texture,
+};
+
+pub const MAX_SHADOWS: usize = 16;
+/// Largest `ShadowSettings::pcf_kernel`, bounding the filter loops shaders
+/// are compiled with.
+pub const MAX_PCF_KERNEL: u32 = 7;
+
+/// Shadow map parameters of one light. Raise the biases against acne on lit
+/// surfaces, lower them against shadows detaching from their casters.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct ShadowSettings {
+    /// Side of the light's square tile in the shadow atlas, in texels.
+    pub resolution: u32,
+    /// Subtracted from the depth seen from the light (0 to 1) before comparing.
+    pub depth_bias: f32,
+    /// Offset of the lookup along the surface normal, in shadow map texels.
+    pub normal_bias: f32,
+    /// Taps per side of the PCF filter, 1 for a single filtered tap, up to
+    /// `MAX_PCF_KERNEL`.
+    pub pcf_kernel: u32,
+}
+
+impl Default for ShadowSettings {
+    fn default() -> Self {
+        Self {
+            resolution: 1024,
+            depth_bias: 0.0005,
+            normal_bias: 1.5,
+            pcf_kernel: 3,
+        }
+    }
+}
+
+#[repr(C)]
+#[derive(Debug, Copy, Clone, Default, bytemuck::Pod, bytemuck::Zeroable)]
+pub(crate) struct ShadowUniform {
+    view_proj: [[f32; 4]; 4],
+    // Tile in atlas UVs, offset in xy and size in zw
+    rect: [f32; 4],
+    // Depth bias, normal bias in world units, PCF taps per side, texel size
+    params: [f32; 4],
+}
+
+/// What a shadowed light looks like to the atlas.
+#[derive(Debug, Copy, Clone)]
+pub(crate) enum ShadowCaster {
+    Directional {
+        direction: Vector3<f32>,
+    },
+    Spot {
+        position: Vector3<f32>,
+        direction: Vector3<f32>,
+        cutoff: Rad<f32>,
+        range: f32,
+    },
+}
+
+impl ShadowCaster {
+    /// How much the light's shadows matter from `eye`: its luminance, color
+    /// times strength, times the proximity, 1 inside the light's range and falling off with the square
+    /// of the distance past it. Directional lights are close everywhere.
+    pub(crate) fn importance(&self, luminance: f32, eye: Point3<f32>) -> f32 {
+        let proximity = match *self {
+            ShadowCaster::Directional { .. } => 1.0,
+            ShadowCaster::Spot { position, range, .. } => {
+                let distance = (Point3::from_vec(position) - eye).magnitude();
+                if distance <= range {
+                    1.0
+                } else {
+                    (range / distance).powi(2)
+                }
+            }
+        };
+        return luminance * proximity;
+    }
+}
+
+/// How directional light shadow maps cover the scene.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum DirectionalFit {
+    /// Square of `directional_extent` around the camera.
+    Camera,
+    /// Camera frustum up to `max_distance` from the eye, or the scene bounds
+    /// when those are smaller, with depth reaching every caster in the scene.
+    Frustum { max_distance: f32 },
+}
+
+/// Camera and scene the directional shadow maps are fitted to.
+#[derive(Debug, Copy, Clone)]
+pub(crate) struct ShadowView {
+    pub position: Point3<f32>,
+    pub inv_view_proj: Matrix4<f32>,
+    pub scene_bounds: Option<Aabb>,
+}
+
+/// Part of the atlas a light's shadow map is drawn into.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct ShadowTile {
+    pub light: LightId,
+    pub x: u32,
+    pub y: u32,
+    pub size: u32,
+}
+
+/// Depth texture holding the shadow maps of all shadowed lights side by side,
+/// so lights can pick their own resolution.
+pub struct ShadowAtlas {
+    pub atlas: Texture,
+    pub size: u32,
+    /// Half the side of the area around the camera covered by directional
+    /// light shadows with `DirectionalFit::Camera`.
+    pub directional_extent: f32,
+    pub directional_fit: DirectionalFit,
+    /// Lights shadowed per frame at most, the most important ones are picked.
+    pub max_shadowed_lights: usize,
+    // One light view projection per tile, at dynamic offsets
+    pass_buffer: TrackedBuffer,
+    pass_stride: u64,
+    pub pass_bind_group_layout: wgpu::BindGroupLayout,
+    pub pass_bind_group: wgpu::BindGroup,
+    tiles: Vec<ShadowTile>,
+    view_projs: Vec<Matrix4<f32>>,
+}
+
+impl ShadowAtlas {
+    pub const FORMAT: wgpu::TextureFormat = wgpu::TextureFormat::Depth32Float;
+    const SIZE: u32 = 4096;
+    // Spot shadows stop here when the light's range is unbounded
+    const MAX_SPOT_RANGE: f32 = 1000.0;
+    // Fitted directional extents grow in steps of this, so that small
+    // changes of the camera frustum don't resize the texels
+    const EXTENT_STEP: f32 = 0.5;
+
+    pub fn new(device: &wgpu::Device, memory: &MemoryTracker) -> Self {
+        let size = Self::SIZE.min(device.limits().max_texture_dimension_2d);
+        let texture = memory.create_texture(
+            device,
+            &wgpu::TextureDescriptor {
+                label: Some("Shadow Atlas"),
+                size: wgpu::Extent3d {
+                    width: size,
+                    height: size,
+                    depth_or_array_layers: 1,
+                },
+                mip_level_count: 1,
+                sample_count: 1,
+                dimension: wgpu::TextureDimension::D2,
+                format: Self::FORMAT,
+                usage: wgpu::TextureUsages::RENDER_ATTACHMENT | wgpu::TextureUsages::TEXTURE_BINDING,
+            },
+            MemoryCategory::Texture,
+        );
+        let view = texture.create_view(&wgpu::TextureViewDescriptor::default());
+        // Linear filtering compares the four nearest texels
+        let sampler = device.create_sampler(&wgpu::SamplerDescriptor {
+            label: Some("Shadow Sampler"),
+            address_mode_u: wgpu::AddressMode::ClampToEdge,
+            address_mode_v: wgpu::AddressMode::ClampToEdge,
+            address_mode_w: wgpu::AddressMode::ClampToEdge,
+            mag_filter: wgpu::FilterMode::Linear,
+            min_filter: wgpu::FilterMode::Linear,
+            mipmap_filter: wgpu::FilterMode::Nearest,
+            compare: Some(wgpu::CompareFunction::LessEqual),
+            ..Default::default()
+        });
+
+        let alignment = device.limits().min_uniform_buffer_offset_alignment as u64;
+        let matrix_size = std::mem::size_of::<[[f32; 4]; 4]>() as u64;
+        let pass_stride = matrix_size.div_ceil(alignment) * alignment;
+        let pass_buffer = memory.create_buffer(
+            device,
+            &wgpu::BufferDescriptor {
+                label: Some("Shadow Pass Buffer"),
+                size: pass_stride * MAX_SHADOWS as u64,
+                usage: wgpu::BufferUsages::UNIFORM | wgpu::BufferUsages::COPY_DST,
+                mapped_at_creation: false,
+            },
+            MemoryCategory::Uniform,
+        );
+        let pass_bind_group_layout = device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
+            entries: &[wgpu::BindGroupLayoutEntry {
+                binding: 0,
+                visibility: wgpu::ShaderStages::VERTEX,
+                ty: wgpu::BindingType::Buffer {
+                    ty: wgpu::BufferBindingType::Uniform,
+                    has_dynamic_offset: true,
+                    min_binding_size: wgpu::BufferSize::new(matrix_size),
+                },
+                count: None,
+            }],
+            label: Some("shadow_pass_bind_group_layout"),
+        });
+        let pass_bind_group = device.create_bind_group(&wgpu::BindGroupDescriptor {
+            layout: &pass_bind_group_layout,
+            entries: &[wgpu::BindGroupEntry {
+                binding: 0,
+                resource: wgpu::BindingResource::Buffer(wgpu::BufferBinding {
+                    buffer: &pass_buffer,
+                    offset: 0,
+                    size: wgpu::BufferSize::new(matrix_size),
+                }),
+            }],
+            label: Some("shadow_pass_bind_group"),
+        });
+
+        return Self {
+            atlas: Texture {
+                texture,
+                view,
+                sampler,
+            },
+            size,
+            directional_extent: 30.0,
+            directional_fit: DirectionalFit::Camera,
+            max_shadowed_lights: MAX_SHADOWS,
+            pass_buffer,
+            pass_stride,
+            pass_bind_group_layout,
+            pass_bind_group,
+            tiles: Vec::new(),
+            view_projs: Vec::new(),
+        };
+    }
+
+    /// Layout entries of the atlas and its comparison sampler, for the light
+    /// bind group.
+    pub fn layout_entries(first_binding: u32) -> [wgpu::BindGroupLayoutEntry; 2] {
+        return [
+            wgpu::BindGroupLayoutEntry {
+                binding: first_binding,
+                visibility: wgpu::ShaderStages::FRAGMENT,
+                ty: wgpu::BindingType::Texture {
+                    multisampled: false,
+                    view_dimension: wgpu::TextureViewDimension::D2,
+                    sample_type: wgpu::TextureSampleType::Depth,
+                },
+                count: None,
+            },
+            wgpu::BindGroupLayoutEntry {
+                binding: first_binding + 1,
+                visibility: wgpu::ShaderStages::FRAGMENT,
+                ty: wgpu::BindingType::Sampler(wgpu::SamplerBindingType::Comparison),
+                count: None,
+            },
+        ];
+    }
+
+    /// Bind group entries matching `layout_entries`.
+    pub fn bind_entries(&self, first_binding: u32) -> [wgpu::BindGroupEntry<'_>; 2] {
+        return [
+            wgpu::BindGroupEntry {
+                binding: first_binding,
+                resource: wgpu::BindingResource::TextureView(&self.atlas.view),
+            },
+            wgpu::BindGroupEntry {
+                binding: first_binding + 1,
+                resource: wgpu::BindingResource::Sampler(&self.atlas.sampler),
+            },
+        ];
+    }
+
+    /// Tiles drawn this frame, in the order of their shadow uniforms.
+    pub fn tiles(&self) -> &[ShadowTile] {
+        return &self.tiles;
+    }
+
+    /// Light space view projection of each tile, e.g. for drawing frustums.
+    pub fn view_projs(&self) -> &[Matrix4<f32>] {
+        return &self.view_projs;
+    }
+
+    /// Dynamic offset of the pass bind group for tile `index`.
+    pub fn pass_offset(&self, index: usize) -> u32 {
+        return (self.pass_stride * index as u64) as u32;
+    }
+
+    /// Lay out tiles for `casters`, largest first in rows, and compute their
+    /// uniforms. Lights that don't fit into the atlas are left unshadowed.
+    pub(crate) fn allocate(
+        &mut self,
+        queue: &wgpu::Queue,
+        casters: &[(LightId, ShadowCaster, ShadowSettings)],
+        view: &ShadowView,
+    ) -> Vec<ShadowUniform> {
+        let mut order = (0..casters.len()).collect::<Vec<_>>();
+        order.sort_by_key(|&i| std::cmp::Reverse(casters[i].2.resolution));
+
+        self.tiles.clear();
+        self.view_projs.clear();
+        let mut uniforms = Vec::new();
+        let (mut x, mut y, mut row_height) = (0, 0, 0);
+        for i in order {
+            let (light, caster, settings) = casters[i];
+            let size = settings.resolution.clamp(1, self.size);
+            if x + size > self.size {
+                x = 0;
+                y += row_height;
+                row_height = 0;
+            }
+            if y + size > self.size || self.tiles.len() == MAX_SHADOWS {
+                log::debug!("No room in the shadow atlas for {:?}", light);
+                continue;
+            }
+            let tile = ShadowTile { light, x, y, size };
+            x += size;
+            row_height = row_height.max(size);
+
+            let (view_proj, texel_world_size) = self.view_projection(caster, size, view);
+            let matrix: [[f32; 4]; 4] = view_proj.into();
+            let atlas = self.size as f32;
+            uniforms.push(ShadowUniform {
+                view_proj: matrix,
+                rect: [
+                    tile.x as f32 / atlas,
+                    tile.y as f32 / atlas,
+                    size as f32 / atlas,
+                    size as f32 / atlas,
+                ],
+                params: [
+                    settings.depth_bias,
+                    settings.normal_bias * texel_world_size,
+                    settings.pcf_kernel.clamp(1, MAX_PCF_KERNEL) as f32,
+                    1.0 / atlas,
+                ],
+            });
+            queue.write_buffer(
+                &self.pass_buffer,
+                self.pass_stride * self.tiles.len() as u64,
+                bytemuck::cast_slice(&[matrix]),
+            );
+            self.tiles.push(tile);
+            self.view_projs.push(view_proj);
+        }
+        return uniforms;
+    }
+
+    // Light space view projection, and the world size of a texel: across the
+    // whole map for directional lights, at unit distance for spot lights
+    fn view_projection(&self, caster: ShadowCaster, size: u32, view: &ShadowView) -> (Matrix4<f32>, f32) {
+        let look = |eye: Point3<f32>, direction: Vector3<f32>| {
+            let direction = direction.normalize();
+            let up = if direction.y.abs() > 0.99 {
+                Vector3::unit_z()
+            } else {
+                Vector3::unit_y()
+            };
+            Matrix4::look_to_rh(eye, direction, up)
+        };
+        return match caster {
+            ShadowCaster::Directional { direction } => {
+                let light_view = look(Point3::origin(), direction);
+                let (center, extent, near, far) = self.directional_bounds(&light_view, view);
+                // Moving the map in whole texels keeps the texels over the
+                // same world positions, so edges don't shimmer
+                let texel = 2.0 * extent / size as f32;
+                let (x, y) = ((center.x / texel).round() * texel, (center.y / texel).round() * texel);
+                let projection = ortho_rh(x - extent, x + extent, y - extent, y + extent, near, far);
+                (projection * light_view, texel)
+            }
+            ShadowCaster::Spot {
+                position,
+                direction,
+                cutoff,
+                range,
+            } => {
+                let fovy = Rad((cutoff.0 * 2.0).min(Rad::from(Deg(170.0)).0));
+                let far = range.clamp(0.1, Self::MAX_SPOT_RANGE);
+                let projection = perspective_rh(fovy, 1.0, (far * 0.001).max(0.01), far);
+                let view_proj = projection * look(Point3::from_vec(position), direction);
+                (view_proj, 2.0 * (fovy * 0.5).tan() / size as f32)
+            }
+        };
+    }
+
+    // Center of the directional shadow map in light space, its half extent,
+    // and the near and far depth of the projection
+    fn directional_bounds(&self, light_view: &Matrix4<f32>, view: &ShadowView) -> (Vector3<f32>, f32, f32, f32) {
+        let max_distance = match self.directional_fit {
+            DirectionalFit::Camera => {
+                let extent = self.directional_extent;
+                let center = (light_view * view.position.to_homogeneous()).truncate();
+                // Casters up to two extents towards the light are included
+                return (center, extent, -center.z - extent * 2.0, -center.z + extent * 2.0);
+            }
+            DirectionalFit::Frustum { max_distance } => max_distance.max(0.01),
+        };
+
+        // Frustum corners with the far ones pulled in to `max_distance`
+        let unproject = |x: f32, y: f32, z: f32| {
+            let p = view.inv_view_proj * Vector4::new(x, y, z, 1.0);
+            p.truncate() / p.w
+        };
+        let mut corners = Vec::with_capacity(8);
+        for (x, y) in [(-1.0, -1.0), (1.0, -1.0), (-1.0, 1.0), (1.0, 1.0)] {
+            let near = unproject(x, y, 0.0);
+            let edge = unproject(x, y, 1.0) - near;
+            let length = edge.magnitude();
+            corners.push(near);
+            corners.push(near + edge * (max_distance / length).min(1.0));
+        }
+        // A sphere around the corners keeps its size as the camera turns
+        let sphere_center = corners.iter().sum::<Vector3<f32>>() / corners.len() as f32;
+        let radius = corners
+            .iter()
+            .map(|c| (c - sphere_center).magnitude())
+            .fold(0.0, f32::max);
+        let radius = (radius / Self::EXTENT_STEP).ceil().max(1.0) * Self::EXTENT_STEP;
+        let mut center = (light_view * sphere_center.extend(1.0)).truncate();
+        let mut extent = radius;
+        let (mut near, far) = (-center.z - radius * 2.0, -center.z + radius);
+
+        if let Some(bounds) = view.scene_bounds {
+            let scene = bounds.transform(light_view);
+            let scene_extent = (scene.max.x - scene.min.x).max(scene.max.y - scene.min.y) * 0.5;
+            if scene_extent < extent {
+                let scene_center = scene.center();
+                center.x = scene_center.x;
+                center.y = scene_center.y;
+                extent = scene_extent.max(Self::EXTENT_STEP);
+            }
+            // Every caster between the light and the view casts into it
+            near = -scene.max.z;
+        }
+        return (center, extent, near, far.max(near + 0.01));
+    }
+}
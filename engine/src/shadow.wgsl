@@ -0,0 +1,16 @@
+// Depth of the scene seen from a shadowed light, drawn into its tile of the
+// shadow atlas. See shadow.rs
+#include "instance.wgsl"
+
+@group(0) @binding(0)
+var<uniform> light_view_proj: mat4x4<f32>;
+
+struct VertexInput {
+    @location(0) position: vec3<f32>,
+};
+
+@vertex
+fn vs_main(model: VertexInput, instance: InstanceInput) -> @builtin(position) vec4<f32> {
+    let transform = instance_transform(instance);
+    return light_view_proj * transform.model * vec4<f32>(model.position, 1.0);
+}
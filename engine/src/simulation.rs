@@ -0,0 +1,365 @@
+use std::{
+    sync::{
+        mpsc::{self, Receiver, Sender},
+        Arc, Condvar, Mutex,
+    },
+    thread::JoinHandle,
+    time::{Duration, Instant},
+};
+
+use anyhow::*;
+
+use crate::{
+    camera::{CameraPose, FPSCamera},
+    controller::{Controller, ControllerEvent},
+    light::{LightId, PositionalLight},
+};
+
+/// How the render thread consumes states produced by a detached update thread.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum SyncPolicy {
+    // The update thread steps on its own clock; every frame draws the newest
+    // finished state and never waits for the simulation
+    LatestWins { step: Duration },
+    // One simulation step per frame with the frame's delta time. The step for
+    // the next frame runs while the current one is drawn, so frames show the
+    // state one step behind. Needed for reproducible captures
+    Lockstep,
+}
+
+/// Everything the simulation owns that the renderer reads each frame.
+#[derive(Debug, Clone)]
+pub struct SceneState {
+    pub camera: CameraPose,
+    // True while input is still moving the camera
+    pub camera_moving: bool,
+    // Lights driven by the simulation, written over the light buffer's copies
+    pub lights: Vec<(LightId, PositionalLight)>,
+    pub elapsed: Duration,
+    pub step: u64,
+}
+
+/// Runs after the camera on every step, e.g. light animation or physics.
+pub type System = Box<dyn FnMut(&mut SceneState, Duration) + Send>;
+
+/// Camera control and systems advanced by `Simulator`, either on the render
+/// thread or on an update thread of its own.
+pub struct Simulation {
+    camera: FPSCamera,
+    state: SceneState,
+    systems: Vec<System>,
+}
+
+impl Simulation {
+    pub fn new(camera: FPSCamera) -> Self {
+        let state = SceneState {
+            camera: camera.pose(),
+            camera_moving: false,
+            lights: Vec::new(),
+            elapsed: Duration::ZERO,
+            step: 0,
+        };
+        return Self {
+            camera,
+            state,
+            systems: Vec::new(),
+        };
+    }
+
+    /// Hand `lights` to the simulation, the renderer uploads them after every step.
+    pub fn with_lights(mut self, lights: Vec<(LightId, PositionalLight)>) -> Self {
+        self.state.lights = lights;
+        return self;
+    }
+
+    pub fn add_system<F: FnMut(&mut SceneState, Duration) + Send + 'static>(&mut self, system: F) {
+        self.systems.push(Box::new(system));
+    }
+
+    pub fn state(&self) -> &SceneState {
+        return &self.state;
+    }
+
+    pub fn input(&mut self, event: ControllerEvent) {
+        self.camera.input(event);
+    }
+
+    pub fn update(&mut self, dt: Duration) {
+        self.camera.update(dt);
+        self.state.camera = self.camera.pose();
+        self.state.camera_moving = self.camera.is_moving();
+        for system in &mut self.systems {
+            system(&mut self.state, dt);
+        }
+        self.state.elapsed += dt;
+        self.state.step += 1;
+    }
+
+    // Same camera and state, systems can't be cloned
+    fn without_systems(&self) -> Self {
+        return Self {
+            camera: self.camera.clone(),
+            state: self.state.clone(),
+            systems: Vec::new(),
+        };
+    }
+}
+
+enum Message {
+    Input(ControllerEvent),
+    Step(Duration),
+}
+
+// Front buffer of the double-buffered state, the back buffer is the one
+// owned by the simulation on the update thread
+#[derive(Default)]
+struct Shared {
+    latest: Mutex<Option<SceneState>>,
+    published: Condvar,
+}
+
+impl Shared {
+    fn publish(&self, state: SceneState) {
+        *self.latest.lock().unwrap() = Some(state);
+        self.published.notify_one();
+    }
+
+    fn take(&self) -> Option<SceneState> {
+        return self.latest.lock().unwrap().take();
+    }
+}
+
+struct UpdateThread {
+    policy: SyncPolicy,
+    // Dropped first when stopping, which ends the thread's loop
+    sender: Option<Sender<Message>>,
+    shared: Arc<Shared>,
+    handle: Option<JoinHandle<Simulation>>,
+    // Lockstep: a step was requested and not yet taken
+    in_flight: bool,
+    // Last state handed to the renderer, continued from if the thread panics
+    fallback: Simulation,
+}
+
+impl UpdateThread {
+    fn spawn(simulation: Simulation, policy: SyncPolicy) -> Result<Self> {
+        let (sender, receiver) = mpsc::channel();
+        let shared = Arc::new(Shared::default());
+        let fallback = simulation.without_systems();
+        let thread_shared = shared.clone();
+        let handle = std::thread::Builder::new()
+            .name("simulation".into())
+            .spawn(move || run(simulation, policy, receiver, &thread_shared))
+            .context("Failed to spawn the update thread")?;
+        return Ok(Self {
+            policy,
+            sender: Some(sender),
+            shared,
+            handle: Some(handle),
+            in_flight: false,
+            fallback,
+        });
+    }
+
+    fn send(&self, message: Message) {
+        if let Some(sender) = &self.sender {
+            // Fails only if the thread is gone, which `stop` reports
+            let _ = sender.send(message);
+        }
+    }
+
+    fn is_running(&self) -> bool {
+        return self.handle.as_ref().is_some_and(|h| !h.is_finished());
+    }
+
+    fn step(&mut self, dt: Duration) -> Option<SceneState> {
+        let state = match self.policy {
+            SyncPolicy::LatestWins { .. } => self.shared.take(),
+            SyncPolicy::Lockstep => {
+                let state = if self.in_flight { self.wait() } else { None };
+                self.in_flight = true;
+                self.send(Message::Step(dt));
+                state
+            }
+        };
+        if let Some(state) = &state {
+            self.fallback.camera.set_pose(state.camera);
+            self.fallback.state = state.clone();
+        }
+        return state;
+    }
+
+    // Block until the requested step is published, or the thread has died
+    fn wait(&mut self) -> Option<SceneState> {
+        let mut latest = self.shared.latest.lock().unwrap();
+        while latest.is_none() {
+            if !self.is_running() {
+                return None;
+            }
+            latest = self
+                .shared
+                .published
+                .wait_timeout(latest, Duration::from_millis(100))
+                .unwrap()
+                .0;
+        }
+        return latest.take();
+    }
+
+    // Join the thread and take back its simulation, `None` if it panicked
+    fn stop(&mut self) -> Option<Simulation> {
+        self.sender = None;
+        return self.handle.take().and_then(|h| h.join().ok());
+    }
+}
+
+impl Drop for UpdateThread {
+    fn drop(&mut self) {
+        if self.handle.is_some() {
+            let _ = self.stop();
+        }
+    }
+}
+
+fn run(
+    mut simulation: Simulation,
+    policy: SyncPolicy,
+    receiver: Receiver<Message>,
+    shared: &Shared,
+) -> Simulation {
+    match policy {
+        SyncPolicy::LatestWins { step } => loop {
+            let start = Instant::now();
+            loop {
+                match receiver.try_recv() {
+                    Result::Ok(Message::Input(event)) => simulation.input(event),
+                    Result::Ok(Message::Step(_)) => {}
+                    Err(mpsc::TryRecvError::Empty) => break,
+                    Err(mpsc::TryRecvError::Disconnected) => return simulation,
+                }
+            }
+            simulation.update(step);
+            shared.publish(simulation.state.clone());
+            std::thread::sleep(step.saturating_sub(start.elapsed()));
+        },
+        SyncPolicy::Lockstep => {
+            for message in receiver {
+                match message {
+                    Message::Input(event) => simulation.input(event),
+                    Message::Step(dt) => {
+                        simulation.update(dt);
+                        shared.publish(simulation.state.clone());
+                    }
+                }
+            }
+            return simulation;
+        }
+    }
+}
+
+enum Mode {
+    Inline(Box<Simulation>),
+    Detached(Box<UpdateThread>),
+}
+
+/// Owns the simulation and decides where it runs. Starts on the render
+/// thread and can be detached onto an update thread and attached back.
+pub struct Simulator {
+    mode: Option<Mode>,
+}
+
+impl Simulator {
+    pub fn new(simulation: Simulation) -> Self {
+        return Self {
+            mode: Some(Mode::Inline(Box::new(simulation))),
+        };
+    }
+
+    pub fn is_detached(&self) -> bool {
+        return matches!(self.mode, Some(Mode::Detached(_)));
+    }
+
+    pub fn policy(&self) -> Option<SyncPolicy> {
+        return match &self.mode {
+            Some(Mode::Detached(thread)) => Some(thread.policy),
+            _ => None,
+        };
+    }
+
+    /// The simulation while it runs on the render thread; `None` when detached.
+    pub fn simulation_mut(&mut self) -> Option<&mut Simulation> {
+        return match &mut self.mode {
+            Some(Mode::Inline(simulation)) => Some(simulation),
+            _ => None,
+        };
+    }
+
+    pub fn input(&mut self, event: ControllerEvent) {
+        match &mut self.mode {
+            Some(Mode::Inline(simulation)) => simulation.input(event),
+            Some(Mode::Detached(thread)) => thread.send(Message::Input(event)),
+            None => {}
+        }
+    }
+
+    /// Advance by `dt` and return the state to draw this frame, `None` if no
+    /// new state is available yet.
+    pub fn step(&mut self, dt: Duration) -> Option<SceneState> {
+        return match &mut self.mode {
+            Some(Mode::Inline(simulation)) => {
+                simulation.update(dt);
+                Some(simulation.state.clone())
+            }
+            Some(Mode::Detached(thread)) => thread.step(dt),
+            None => None,
+        };
+    }
+
+    /// Move the simulation onto an update thread, or change the policy of
+    /// one that is already running.
+    pub fn detach(&mut self, policy: SyncPolicy) -> Result<()> {
+        if self.policy() == Some(policy) {
+            return Ok(());
+        }
+        self.attach()?;
+        let simulation = match self.mode.take() {
+            Some(Mode::Inline(simulation)) => *simulation,
+            _ => unreachable!("attached above"),
+        };
+        let fallback = simulation.without_systems();
+        return match UpdateThread::spawn(simulation, policy) {
+            Result::Ok(thread) => {
+                self.mode = Some(Mode::Detached(Box::new(thread)));
+                Ok(())
+            }
+            Err(e) => {
+                self.mode = Some(Mode::Inline(Box::new(fallback)));
+                Err(e)
+            }
+        };
+    }
+
+    /// Stop the update thread and continue on the render thread. If the thread
+    /// panicked, the simulation continues from the last state the renderer
+    /// received, without its systems, and an error is returned.
+    pub fn attach(&mut self) -> Result<()> {
+        let mut thread = match self.mode.take() {
+            Some(Mode::Detached(thread)) => thread,
+            mode => {
+                self.mode = mode;
+                return Ok(());
+            }
+        };
+        return match thread.stop() {
+            Some(simulation) => {
+                self.mode = Some(Mode::Inline(Box::new(simulation)));
+                Ok(())
+            }
+            None => {
+                let fallback = thread.fallback.without_systems();
+                self.mode = Some(Mode::Inline(Box::new(fallback)));
+                Err(anyhow!("Update thread panicked, its systems were lost"))
+            }
+        };
+    }
+}
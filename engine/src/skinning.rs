@@ -0,0 +1,508 @@
+use std::ops::Range;
+
+use anyhow::*;
+use cgmath::{prelude::*, Matrix4, Quaternion, Vector3};
+
+use crate::{
+    frame::FrameBuffers,
+    layers::RenderLayers,
+    memory::{MemoryCategory, MemoryTracker, TrackedBuffer},
+    model::Model,
+    motion::{InstanceMotion, MotionKernel, MotionState},
+    resources::{Instance, InstanceFormat},
+};
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct JointPose {
+    pub translation: Vector3<f32>,
+    pub rotation: Quaternion<f32>,
+    pub scale: Vector3<f32>,
+}
+
+impl JointPose {
+    pub fn identity() -> Self {
+        return Self {
+            translation: Vector3::zero(),
+            rotation: Quaternion::one(),
+            scale: Vector3::new(1.0, 1.0, 1.0),
+        };
+    }
+
+    pub fn matrix(&self) -> Matrix4<f32> {
+        return Matrix4::from_translation(self.translation)
+            * Matrix4::from(self.rotation)
+            * Matrix4::from_nonuniform_scale(self.scale.x, self.scale.y, self.scale.z);
+    }
+
+    pub fn interpolate(&self, other: &JointPose, t: f32) -> JointPose {
+        return JointPose {
+            translation: self.translation.lerp(other.translation, t),
+            rotation: self.rotation.slerp(other.rotation, t),
+            scale: self.scale.lerp(other.scale, t),
+        };
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Joint {
+    pub name: String,
+    pub parent: Option<usize>,
+    // Pose used when no animation track drives the joint
+    pub rest: JointPose,
+    // Model space to joint space in the bind pose
+    pub inverse_bind: Matrix4<f32>,
+}
+
+/// Joint hierarchy where every parent comes before its children.
+#[derive(Debug, Clone)]
+pub struct Skeleton {
+    joints: Vec<Joint>,
+}
+
+impl Skeleton {
+    pub fn new(joints: Vec<Joint>) -> Result<Self> {
+        for (i, joint) in joints.iter().enumerate() {
+            if let Some(parent) = joint.parent {
+                ensure!(
+                    parent < i,
+                    "Joint `{}` must come after its parent {}",
+                    joint.name,
+                    parent
+                );
+            }
+        }
+        return Ok(Self { joints });
+    }
+
+    pub fn joints(&self) -> &[Joint] {
+        return &self.joints;
+    }
+
+    pub fn joint_index(&self, name: &str) -> Option<usize> {
+        return self.joints.iter().position(|j| j.name == name);
+    }
+
+    /// Skinning matrices (model space pose times inverse bind) for local `poses`.
+    pub fn skinning_matrices(&self, poses: &[JointPose]) -> Vec<Matrix4<f32>> {
+        let mut world: Vec<Matrix4<f32>> = Vec::with_capacity(self.joints.len());
+        for (joint, pose) in self.joints.iter().zip(poses) {
+            let local = pose.matrix();
+            let matrix = match joint.parent {
+                Some(parent) => world[parent] * local,
+                None => local,
+            };
+            world.push(matrix);
+        }
+        return world
+            .iter()
+            .zip(&self.joints)
+            .map(|(m, joint)| m * joint.inverse_bind)
+            .collect();
+    }
+}
+
+/// Keyframes of one joint, sorted by time.
+#[derive(Debug, Clone)]
+pub struct JointTrack {
+    pub joint: usize,
+    pub keyframes: Vec<(f32, JointPose)>,
+}
+
+impl JointTrack {
+    pub fn sample(&self, time: f32) -> Option<JointPose> {
+        let next = self.keyframes.partition_point(|(t, _)| *t <= time);
+        if next == 0 {
+            return self.keyframes.first().map(|(_, pose)| *pose);
+        }
+        if next == self.keyframes.len() {
+            return self.keyframes.last().map(|(_, pose)| *pose);
+        }
+        let (t0, p0) = self.keyframes[next - 1];
+        let (t1, p1) = self.keyframes[next];
+        return Some(p0.interpolate(&p1, (time - t0) / (t1 - t0)));
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct AnimationClip {
+    pub name: String,
+    pub duration: f32,
+    pub tracks: Vec<JointTrack>,
+}
+
+impl AnimationClip {
+    /// Local pose of every joint at `time`, joints without a track keep their rest pose.
+    pub fn pose(&self, skeleton: &Skeleton, time: f32) -> Vec<JointPose> {
+        let mut poses: Vec<JointPose> = skeleton.joints.iter().map(|j| j.rest).collect();
+        for track in &self.tracks {
+            if let (Some(pose), Some(slot)) = (track.sample(time), poses.get_mut(track.joint)) {
+                *slot = pose;
+            }
+        }
+        return poses;
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct BakedClip {
+    pub name: String,
+    pub first_frame: u32,
+    pub frame_count: u32,
+    pub duration: f32,
+}
+
+/// Skinning matrices of every clip sampled at a fixed rate, laid out as
+/// `[frame][joint]` so instances only need to know which frames to read.
+#[derive(Debug, Clone)]
+pub struct BakedAnimations {
+    pub joint_count: u32,
+    pub sample_rate: f32,
+    pub clips: Vec<BakedClip>,
+    matrices: Vec<[[f32; 4]; 4]>,
+}
+
+impl BakedAnimations {
+    pub fn bake(skeleton: &Skeleton, clips: &[AnimationClip], sample_rate: f32) -> Self {
+        let mut matrices = Vec::new();
+        let mut baked = Vec::new();
+        let mut first_frame = 0;
+        for clip in clips {
+            let frame_count = (clip.duration * sample_rate).ceil().max(0.0) as u32 + 1;
+            for frame in 0..frame_count {
+                let time = (frame as f32 / sample_rate).min(clip.duration);
+                let poses = clip.pose(skeleton, time);
+                for matrix in skeleton.skinning_matrices(&poses) {
+                    matrices.push(matrix.into());
+                }
+            }
+            baked.push(BakedClip {
+                name: clip.name.clone(),
+                first_frame,
+                frame_count,
+                duration: clip.duration,
+            });
+            first_frame += frame_count;
+        }
+
+        return Self {
+            joint_count: skeleton.joints.len() as u32,
+            sample_rate,
+            clips: baked,
+            matrices,
+        };
+    }
+
+    pub fn matrices(&self) -> &[[[f32; 4]; 4]] {
+        return &self.matrices;
+    }
+
+    pub fn clip_index(&self, name: &str) -> Option<usize> {
+        return self.clips.iter().position(|c| c.name == name);
+    }
+
+    /// Frames to blend between for an instance.
+    pub fn sample(&self, state: &AnimationState) -> AnimationInstanceRaw {
+        return AnimationInstanceRaw::sample(&self.clips, self.sample_rate, self.joint_count, state);
+    }
+}
+
+/// Playback state of one animated instance.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct AnimationState {
+    pub clip: usize,
+    pub time: f32,
+    pub speed: f32,
+    pub looping: bool,
+}
+
+impl AnimationState {
+    pub fn new(clip: usize) -> Self {
+        return Self {
+            clip,
+            time: 0.0,
+            speed: 1.0,
+            looping: true,
+        };
+    }
+
+    pub fn advance(&mut self, dt: f32, duration: f32) {
+        self.time += dt * self.speed;
+        if duration <= 0.0 {
+            self.time = 0.0;
+        } else if self.looping {
+            self.time = self.time.rem_euclid(duration);
+        } else {
+            self.time = self.time.clamp(0.0, duration);
+        }
+    }
+}
+
+#[repr(C)]
+#[derive(Debug, Copy, Clone, Default, bytemuck::Pod, bytemuck::Zeroable)]
+pub struct AnimationInstanceRaw {
+    // Offsets of the two frames in the baked matrix buffer
+    frames: [u32; 2],
+    blend: f32,
+    _padding: u32,
+}
+
+impl AnimationInstanceRaw {
+    /// Frames of `clips` to blend between for `state`, as offsets into frames
+    /// of `frame_size` elements each.
+    pub(crate) fn sample(clips: &[BakedClip], sample_rate: f32, frame_size: u32, state: &AnimationState) -> Self {
+        let clip = match clips.get(state.clip) {
+            Some(clip) => clip,
+            None => return Self::default(),
+        };
+
+        let frame = state.time * sample_rate;
+        let last = clip.frame_count - 1;
+        let frame0 = (frame.floor() as u32).min(last);
+        let frame1 = if state.looping && frame0 == last {
+            0
+        } else {
+            (frame0 + 1).min(last)
+        };
+        let offset = |frame: u32| (clip.first_frame + frame) * frame_size;
+
+        return Self {
+            frames: [offset(frame0), offset(frame1)],
+            blend: frame.fract(),
+            _padding: 0,
+        };
+    }
+
+    pub fn desc<'a>() -> wgpu::VertexBufferLayout<'a> {
+        use std::mem;
+        wgpu::VertexBufferLayout {
+            array_stride: mem::size_of::<AnimationInstanceRaw>() as wgpu::BufferAddress,
+            step_mode: wgpu::VertexStepMode::Instance,
+            attributes: &[
+                wgpu::VertexAttribute {
+                    offset: 0,
+                    shader_location: 14,
+                    format: wgpu::VertexFormat::Uint32x2,
+                },
+                wgpu::VertexAttribute {
+                    offset: mem::size_of::<[u32; 2]>() as wgpu::BufferAddress,
+                    shader_location: 15,
+                    format: wgpu::VertexFormat::Float32,
+                },
+            ],
+        }
+    }
+}
+
+/// Joint influences of a vertex, stored in a buffer next to the regular vertices.
+#[repr(C)]
+#[derive(Debug, Copy, Clone, Default, bytemuck::Pod, bytemuck::Zeroable)]
+pub struct SkinVertex {
+    pub joints: [u32; 4],
+    pub weights: [f32; 4],
+}
+
+impl SkinVertex {
+    pub fn desc<'a>() -> wgpu::VertexBufferLayout<'a> {
+        use std::mem;
+        wgpu::VertexBufferLayout {
+            array_stride: mem::size_of::<SkinVertex>() as wgpu::BufferAddress,
+            step_mode: wgpu::VertexStepMode::Vertex,
+            attributes: &[
+                wgpu::VertexAttribute {
+                    offset: 0,
+                    shader_location: 12,
+                    format: wgpu::VertexFormat::Uint32x4,
+                },
+                wgpu::VertexAttribute {
+                    offset: mem::size_of::<[u32; 4]>() as wgpu::BufferAddress,
+                    shader_location: 13,
+                    format: wgpu::VertexFormat::Float32x4,
+                },
+            ],
+        }
+    }
+}
+
+/// Many instances of one skinned model, each playing its own clip, drawn with
+/// one instanced draw call per mesh.
+pub struct Crowd {
+    pub model: Model,
+    skins: Vec<TrackedBuffer>,
+    animations: BakedAnimations,
+    _joint_buffer: TrackedBuffer,
+    pub bind_group: wgpu::BindGroup,
+    pub instances: Vec<(Instance, AnimationState)>,
+    instance_buffers: FrameBuffers,
+    animation_buffers: FrameBuffers,
+    /// Moves the instances every frame on the GPU, see `InstanceMotion`.
+    pub motion: Option<InstanceMotion>,
+    motion_state: MotionState,
+    instance_format: InstanceFormat,
+    // Instances uploaded for the current frame
+    visible: u32,
+}
+
+impl Crowd {
+    /// `skins` holds the joint influences of each mesh of `model`, per vertex.
+    pub fn new(
+        device: &wgpu::Device,
+        memory: &MemoryTracker,
+        layout: &wgpu::BindGroupLayout,
+        model: Model,
+        skins: &[Vec<SkinVertex>],
+        animations: BakedAnimations,
+        instance_format: InstanceFormat,
+    ) -> Result<Self> {
+        ensure!(
+            skins.len() == model.meshes.len(),
+            "Expected skin data for {} meshes, got {}",
+            model.meshes.len(),
+            skins.len()
+        );
+        ensure!(!animations.matrices.is_empty(), "Crowd needs at least one baked clip");
+
+        let skins = model
+            .meshes
+            .iter()
+            .zip(skins)
+            .map(|(mesh, skin)| {
+                memory.create_buffer_init(
+                    device,
+                    &wgpu::util::BufferInitDescriptor {
+                        label: Some(&format!("{:?} Skin Buffer", mesh.name)),
+                        contents: bytemuck::cast_slice(skin),
+                        usage: wgpu::BufferUsages::VERTEX,
+                    },
+                    MemoryCategory::Mesh,
+                )
+            })
+            .collect();
+
+        let joint_buffer = memory.create_buffer_init(
+            device,
+            &wgpu::util::BufferInitDescriptor {
+                label: Some("Joint Matrix Buffer"),
+                contents: bytemuck::cast_slice(animations.matrices()),
+                usage: wgpu::BufferUsages::STORAGE,
+            },
+            MemoryCategory::Mesh,
+        );
+        let bind_group = device.create_bind_group(&wgpu::BindGroupDescriptor {
+            layout,
+            entries: &[wgpu::BindGroupEntry {
+                binding: 0,
+                resource: joint_buffer.as_entire_binding(),
+            }],
+            label: Some("crowd_bind_group"),
+        });
+
+        let instance_buffers = FrameBuffers::new(
+            device,
+            memory,
+            "Crowd Instance Buffer",
+            MotionKernel::instance_usage(device),
+            MemoryCategory::Mesh,
+            &vec![0; instance_format.stride()],
+        );
+        let animation_buffers = FrameBuffers::new(
+            device,
+            memory,
+            "Crowd Animation Buffer",
+            wgpu::BufferUsages::VERTEX,
+            MemoryCategory::Mesh,
+            bytemuck::cast_slice(&[AnimationInstanceRaw::default()]),
+        );
+
+        return Ok(Self {
+            model,
+            skins,
+            animations,
+            _joint_buffer: joint_buffer,
+            bind_group,
+            instances: Vec::new(),
+            instance_buffers,
+            animation_buffers,
+            motion: None,
+            motion_state: MotionState::default(),
+            instance_format,
+            visible: 0,
+        });
+    }
+
+    pub fn animations(&self) -> &BakedAnimations {
+        return &self.animations;
+    }
+
+    pub fn skin(&self, mesh: usize) -> &TrackedBuffer {
+        return &self.skins[mesh];
+    }
+
+    pub fn instance_buffer(&self) -> &TrackedBuffer {
+        return self.instance_buffers.current();
+    }
+
+    pub fn animation_buffer(&self) -> &TrackedBuffer {
+        return self.animation_buffers.current();
+    }
+
+    pub fn instance_range(&self) -> Range<u32> {
+        return 0..self.visible;
+    }
+
+    /// Advance every instance's animation and upload the frame's instance data
+    /// for instances on `layers`. `motion` records the dispatch moving them by
+    /// `Crowd::motion`, without a kernel they're moved on the CPU.
+    #[allow(clippy::too_many_arguments)]
+    pub fn update(
+        &mut self,
+        device: &wgpu::Device,
+        memory: &MemoryTracker,
+        queue: &wgpu::Queue,
+        dt: f32,
+        layers: RenderLayers,
+        motion: Option<(&MotionKernel, &mut wgpu::CommandEncoder)>,
+        version: u64,
+    ) {
+        self.instance_buffers.advance();
+        self.animation_buffers.advance();
+
+        let mut visible = Vec::with_capacity(self.instances.len());
+        let mut animation_data = Vec::with_capacity(self.instances.len());
+        for (instance, state) in &mut self.instances {
+            let duration = self
+                .animations
+                .clips
+                .get(state.clip)
+                .map_or(0.0, |c| c.duration);
+            state.advance(dt, duration);
+            if instance.layers.intersects(layers) {
+                visible.push(*instance);
+                animation_data.push(self.animations.sample(state));
+            }
+        }
+        self.visible = visible.len() as u32;
+        if visible.is_empty() {
+            return;
+        }
+
+        self.motion_state.upload(
+            device,
+            memory,
+            queue,
+            motion,
+            self.motion,
+            &mut self.instance_buffers,
+            self.instance_format,
+            &visible,
+            dt,
+            version,
+        );
+        self.animation_buffers.write(
+            device,
+            memory,
+            queue,
+            bytemuck::cast_slice(&animation_data),
+            version,
+        );
+    }
+}
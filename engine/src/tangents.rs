@@ -0,0 +1,251 @@
+use wgpu::util::DeviceExt;
+
+use crate::{geometry::Geometry, memory::MemoryTracker, model::Mesh, resources::ModelVertex, shader::ShaderPreprocessor};
+
+#[repr(C)]
+#[derive(Debug, Copy, Clone, bytemuck::Pod, bytemuck::Zeroable)]
+struct CountsUniform {
+    triangles: u32,
+    vertices: u32,
+    triangle_row: u32,
+    vertex_row: u32,
+}
+
+/// Computes tangents and bitangents of uploaded meshes in compute passes, for
+/// meshes big enough that `Geometry::calculate_tangents_bitangents` would hold
+/// up loading.
+pub struct TangentGenerator {
+    bind_group_layout: wgpu::BindGroupLayout,
+    triangle_pipeline: wgpu::ComputePipeline,
+    vertex_pipeline: wgpu::ComputePipeline,
+}
+
+impl TangentGenerator {
+    /// Meshes with fewer indices are faster on the CPU than the extra uploads.
+    pub const MIN_INDICES: usize = 1 << 18;
+    const WORKGROUP_SIZE: u32 = 64;
+    const STORAGE_BUFFERS: u32 = 5;
+
+    /// Preprocessor for `tangents.wgsl`, which reads and writes vertices as
+    /// flat floats laid out like `ModelVertex`.
+    pub fn shaders() -> ShaderPreprocessor {
+        let float = std::mem::size_of::<f32>();
+        let mut shaders = ShaderPreprocessor::new();
+        shaders.set_constant("VERTEX_STRIDE", std::mem::size_of::<ModelVertex>() / float);
+        shaders.set_constant("TANGENT_OFFSET", std::mem::offset_of!(ModelVertex, tangent) / float);
+        shaders.set_constant("BITANGENT_OFFSET", std::mem::offset_of!(ModelVertex, bitangent) / float);
+        return shaders;
+    }
+
+    /// `None` if the device can't run the compute passes, e.g. on WebGL.
+    pub fn new(device: &wgpu::Device) -> Option<Self> {
+        let limits = device.limits();
+        if limits.max_storage_buffers_per_shader_stage < Self::STORAGE_BUFFERS
+            || limits.max_compute_workgroups_per_dimension == 0
+        {
+            return None;
+        }
+
+        let storage = |binding: u32, read_only: bool| wgpu::BindGroupLayoutEntry {
+            binding,
+            visibility: wgpu::ShaderStages::COMPUTE,
+            ty: wgpu::BindingType::Buffer {
+                ty: wgpu::BufferBindingType::Storage { read_only },
+                has_dynamic_offset: false,
+                min_binding_size: None,
+            },
+            count: None,
+        };
+        let bind_group_layout = device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
+            entries: &[
+                wgpu::BindGroupLayoutEntry {
+                    binding: 0,
+                    visibility: wgpu::ShaderStages::COMPUTE,
+                    ty: wgpu::BindingType::Buffer {
+                        ty: wgpu::BufferBindingType::Uniform,
+                        has_dynamic_offset: false,
+                        min_binding_size: None,
+                    },
+                    count: None,
+                },
+                storage(1, true),
+                storage(2, false),
+                storage(3, false),
+                storage(4, true),
+                storage(5, true),
+            ],
+            label: Some("tangent_bind_group_layout"),
+        });
+
+        let shader = Self::shaders()
+            .descriptor("Tangent Shader", "tangents.wgsl")
+            .expect("Failed to preprocess tangents.wgsl");
+        let shader = device.create_shader_module(shader);
+        let layout = device.create_pipeline_layout(&wgpu::PipelineLayoutDescriptor {
+            label: Some("Tangent Pipeline Layout"),
+            bind_group_layouts: &[&bind_group_layout],
+            push_constant_ranges: &[],
+        });
+        let pipeline = |label: &str, entry_point: &str| {
+            device.create_compute_pipeline(&wgpu::ComputePipelineDescriptor {
+                label: Some(label),
+                layout: Some(&layout),
+                module: &shader,
+                entry_point,
+            })
+        };
+
+        return Some(Self {
+            triangle_pipeline: pipeline("Tangent Triangle Pipeline", "triangle_main"),
+            vertex_pipeline: pipeline("Tangent Vertex Pipeline", "vertex_main"),
+            bind_group_layout,
+        });
+    }
+
+    /// Whether `geometry` is worth generating on the GPU and fits the device's
+    /// storage buffer limits.
+    pub fn accepts(&self, device: &wgpu::Device, geometry: &Geometry) -> bool {
+        let max_binding = device.limits().max_storage_buffer_binding_size as usize;
+        let vertex_bytes = std::mem::size_of_val(geometry.vertices.as_slice());
+        // Two vec4s per triangle
+        let triangle_bytes = geometry.indices.len() / 3 * 32;
+        return geometry.indices.len() >= Self::MIN_INDICES
+            && vertex_bytes <= max_binding
+            && triangle_bytes <= max_binding
+            && geometry.indices.len() * 4 <= max_binding;
+    }
+
+    /// Upload `geometry` like `Geometry::to_mesh` and fill in the tangents and
+    /// bitangents of the uploaded vertices. `geometry` itself is left as is.
+    pub fn generate(
+        &self,
+        device: &wgpu::Device,
+        memory: &MemoryTracker,
+        queue: &wgpu::Queue,
+        geometry: &Geometry,
+        name: &str,
+        material: usize,
+    ) -> Mesh {
+        let mesh = geometry.to_mesh_with_usage(device, memory, name, material, wgpu::BufferUsages::STORAGE);
+        let triangles = (geometry.indices.len() / 3) as u32;
+        let vertices = geometry.vertices.len() as u32;
+        if triangles == 0 || vertices == 0 {
+            return mesh;
+        }
+        let (offsets, adjacency) = adjacency(geometry);
+
+        let limit = device.limits().max_compute_workgroups_per_dimension;
+        let triangle_groups = dispatch_size(triangles, limit);
+        let vertex_groups = dispatch_size(vertices, limit);
+        let counts = CountsUniform {
+            triangles,
+            vertices,
+            triangle_row: triangle_groups.0 * Self::WORKGROUP_SIZE,
+            vertex_row: vertex_groups.0 * Self::WORKGROUP_SIZE,
+        };
+
+        // Scratch buffers live until the passes are done, so they're not tracked
+        let init = |label: &str, contents: &[u8], usage: wgpu::BufferUsages| {
+            device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
+                label: Some(label),
+                contents,
+                usage,
+            })
+        };
+        let counts_buffer = init("Tangent Counts", bytemuck::cast_slice(&[counts]), wgpu::BufferUsages::UNIFORM);
+        let offsets_buffer = init("Tangent Offsets", bytemuck::cast_slice(&offsets), wgpu::BufferUsages::STORAGE);
+        let adjacency_buffer = init("Tangent Adjacency", bytemuck::cast_slice(&adjacency), wgpu::BufferUsages::STORAGE);
+        let triangle_buffer = device.create_buffer(&wgpu::BufferDescriptor {
+            label: Some("Tangent Triangles"),
+            size: triangles as u64 * 32,
+            usage: wgpu::BufferUsages::STORAGE,
+            mapped_at_creation: false,
+        });
+        // The index buffer is INDEX only, upload the indices again for reading
+        let index_buffer = init(
+            "Tangent Indices",
+            bytemuck::cast_slice(&geometry.indices[..triangles as usize * 3]),
+            wgpu::BufferUsages::STORAGE,
+        );
+
+        let bind_group = device.create_bind_group(&wgpu::BindGroupDescriptor {
+            layout: &self.bind_group_layout,
+            entries: &[
+                wgpu::BindGroupEntry {
+                    binding: 0,
+                    resource: counts_buffer.as_entire_binding(),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 1,
+                    resource: index_buffer.as_entire_binding(),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 2,
+                    resource: mesh.vertex_buffer.as_entire_binding(),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 3,
+                    resource: triangle_buffer.as_entire_binding(),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 4,
+                    resource: offsets_buffer.as_entire_binding(),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 5,
+                    resource: adjacency_buffer.as_entire_binding(),
+                },
+            ],
+            label: Some("tangent_bind_group"),
+        });
+
+        let mut encoder = device.create_command_encoder(&wgpu::CommandEncoderDescriptor {
+            label: Some("Tangent Encoder"),
+        });
+        {
+            let mut pass = encoder.begin_compute_pass(&wgpu::ComputePassDescriptor {
+                label: Some("Tangent Pass"),
+            });
+            pass.set_bind_group(0, &bind_group, &[]);
+            pass.set_pipeline(&self.triangle_pipeline);
+            pass.dispatch_workgroups(triangle_groups.0, triangle_groups.1, 1);
+            // The vertex dispatch reads what the triangle dispatch wrote, wgpu
+            // inserts the barrier between them
+            pass.set_pipeline(&self.vertex_pipeline);
+            pass.dispatch_workgroups(vertex_groups.0, vertex_groups.1, 1);
+        }
+        queue.submit(std::iter::once(encoder.finish()));
+
+        return mesh;
+    }
+}
+
+// Triangles touching each vertex in compressed rows: the triangles of vertex
+// `v` are `adjacency[offsets[v]..offsets[v + 1]]`. A triangle that uses a
+// vertex twice is listed twice, like on the CPU path
+fn adjacency(geometry: &Geometry) -> (Vec<u32>, Vec<u32>) {
+    let indices = &geometry.indices[..geometry.indices.len() / 3 * 3];
+    let mut offsets = vec![0u32; geometry.vertices.len() + 1];
+    for &i in indices {
+        offsets[i as usize + 1] += 1;
+    }
+    for v in 0..geometry.vertices.len() {
+        offsets[v + 1] += offsets[v];
+    }
+
+    let mut cursor = offsets.clone();
+    let mut adjacency = vec![0u32; indices.len()];
+    for (k, &i) in indices.iter().enumerate() {
+        let slot = &mut cursor[i as usize];
+        adjacency[*slot as usize] = (k / 3) as u32;
+        *slot += 1;
+    }
+    return (offsets, adjacency);
+}
+
+// Workgroups in x and y covering `threads`, wrapping rows at `limit`
+fn dispatch_size(threads: u32, limit: u32) -> (u32, u32) {
+    let groups = threads.div_ceil(TangentGenerator::WORKGROUP_SIZE);
+    let x = groups.min(limit);
+    return (x, groups.div_ceil(x));
+}
@@ -0,0 +1,104 @@
+// Tangent generation in two passes: one thread per triangle solves for its
+// tangent frame, then one thread per vertex averages the frames of the
+// triangles around it. Mirrors `Geometry::calculate_tangents_bitangents`.
+
+struct Counts {
+    triangles: u32,
+    vertices: u32,
+    // Threads per row of workgroups in each pass, dispatches too wide for the
+    // x dimension wrap into y
+    triangle_row: u32,
+    vertex_row: u32,
+};
+@group(0) @binding(0)
+var<uniform> counts: Counts;
+@group(0) @binding(1)
+var<storage, read> indices: array<u32>;
+// `ModelVertex` as flat floats
+@group(0) @binding(2)
+var<storage, read_write> vertices: array<f32>;
+// Two entries per triangle: tangent with w = 1 if its UVs are usable, bitangent
+@group(0) @binding(3)
+var<storage, read_write> triangles: array<vec4<f32>>;
+// Triangles around vertex v are `adjacency[offsets[v]]` up to `adjacency[offsets[v + 1]]`
+@group(0) @binding(4)
+var<storage, read> offsets: array<u32>;
+@group(0) @binding(5)
+var<storage, read> adjacency: array<u32>;
+
+// In floats, set from `ModelVertex`'s layout, which the `secondary-uv` feature changes
+override VERTEX_STRIDE: u32 = 14u;
+override TANGENT_OFFSET: u32 = 8u;
+override BITANGENT_OFFSET: u32 = 11u;
+let MAX_FLOAT: f32 = 3.402823e38;
+
+fn position(v: u32) -> vec3<f32> {
+    let i = v * VERTEX_STRIDE;
+    return vec3<f32>(vertices[i], vertices[i + 1u], vertices[i + 2u]);
+}
+
+fn tex_coords(v: u32) -> vec2<f32> {
+    let i = v * VERTEX_STRIDE + 3u;
+    return vec2<f32>(vertices[i], vertices[i + 1u]);
+}
+
+fn write_vec3(offset: u32, value: vec3<f32>) {
+    vertices[offset] = value.x;
+    vertices[offset + 1u] = value.y;
+    vertices[offset + 2u] = value.z;
+}
+
+@compute @workgroup_size(64)
+fn triangle_main(@builtin(global_invocation_id) id: vec3<u32>) {
+    let t = id.x + id.y * counts.triangle_row;
+    if (t >= counts.triangles) {
+        return;
+    }
+    let i0 = indices[t * 3u];
+    let i1 = indices[t * 3u + 1u];
+    let i2 = indices[t * 3u + 2u];
+
+    let delta_pos1 = position(i1) - position(i0);
+    let delta_pos2 = position(i2) - position(i0);
+    let delta_uv1 = tex_coords(i1) - tex_coords(i0);
+    let delta_uv2 = tex_coords(i2) - tex_coords(i0);
+
+    let r = 1.0 / (delta_uv1.x * delta_uv2.y - delta_uv1.y * delta_uv2.x);
+    let tangent = (delta_pos1 * delta_uv2.y - delta_pos2 * delta_uv1.y) * r;
+    // Flipped for right-handed normal maps, as on the CPU
+    let bitangent = (delta_pos2 * delta_uv1.x - delta_pos1 * delta_uv2.x) * -r;
+
+    // Degenerate UVs give infinities or NaN, which fail both comparisons
+    if (abs(tangent.x) <= MAX_FLOAT && abs(bitangent.x) <= MAX_FLOAT) {
+        triangles[t * 2u] = vec4<f32>(tangent, 1.0);
+        triangles[t * 2u + 1u] = vec4<f32>(bitangent, 0.0);
+    } else {
+        triangles[t * 2u] = vec4<f32>(0.0);
+        triangles[t * 2u + 1u] = vec4<f32>(0.0);
+    }
+}
+
+@compute @workgroup_size(64)
+fn vertex_main(@builtin(global_invocation_id) id: vec3<u32>) {
+    let v = id.x + id.y * counts.vertex_row;
+    if (v >= counts.vertices) {
+        return;
+    }
+    var tangent = vec3<f32>(0.0);
+    var bitangent = vec3<f32>(0.0);
+    var included = 0.0;
+    for (var k = offsets[v]; k < offsets[v + 1u]; k++) {
+        let t = adjacency[k];
+        let frame = triangles[t * 2u];
+        tangent += frame.xyz;
+        bitangent += triangles[t * 2u + 1u].xyz;
+        included += frame.w;
+    }
+    if (included > 0.0) {
+        let denom = 1.0 / included;
+        tangent *= denom;
+        bitangent *= denom;
+    }
+    write_vec3(v * VERTEX_STRIDE + TANGENT_OFFSET, tangent);
+    write_vec3(v * VERTEX_STRIDE + BITANGENT_OFFSET, bitangent);
+}
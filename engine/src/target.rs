@@ -0,0 +1,269 @@
+use std::ops::Range;
+
+use cgmath::{EuclideanSpace, InnerSpace, Matrix4, Point3, Rad, Vector3};
+
+use crate::{
+    camera::{Camera, CameraUniform, Projection},
+    frame::FrameBuffers,
+    layers::RenderLayers,
+    math::{Frustum, Plane},
+    memory::{MemoryCategory, MemoryTracker},
+    post::PostProcess,
+    texture::Texture,
+};
+
+/// Material a render target camera draws into, as the diffuse map of one of
+/// the renderer's models.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum TargetMaterial {
+    /// Index into the materials of the instanced model, LOD levels keep theirs.
+    Instances(usize),
+    /// Index into the materials of the static batch.
+    StaticBatch(usize),
+}
+
+/// Camera rendering the scene into a texture used as a material's diffuse
+/// map, e.g. a security monitor or a rear-view mirror. Targets are drawn
+/// before the main pass in the order they were added, so a target sees the
+/// ones added before it as of this frame and the others as of the last. A
+/// target draws instances and the static batch, but never its own material.
+pub struct RenderTargetCamera {
+    pub eye: Point3<f32>,
+    pub target: Point3<f32>,
+    pub up: Vector3<f32>,
+    pub projection: Projection,
+    /// Instances drawn by this camera, see `RenderLayers`.
+    pub render_layers: RenderLayers,
+    /// Fragments behind the plane are discarded, e.g. the wall behind a mirror.
+    pub clip_plane: Option<Plane>,
+    /// Disabled targets keep their last image.
+    pub enabled: bool,
+    material: TargetMaterial,
+    width: u32,
+    height: u32,
+    // Multisampled color resolved into the material's texture when MSAA is on
+    msaa_texture: Option<Texture>,
+    depth_texture: Texture,
+    camera_buffers: FrameBuffers,
+    bind_groups: Vec<wgpu::BindGroup>,
+    instance_buffers: FrameBuffers,
+    // Uploaded instances of each LOD level
+    lod_ranges: Vec<Range<u32>>,
+}
+
+impl RenderTargetCamera {
+    /// `scene_buffers` are bound next to the camera like in the main pass.
+    /// The color texture for the material is returned alongside the camera.
+    #[allow(clippy::too_many_arguments)]
+    pub(crate) fn new(
+        device: &wgpu::Device,
+        memory: &MemoryTracker,
+        camera_layout: &wgpu::BindGroupLayout,
+        scene_buffers: &FrameBuffers,
+        material: TargetMaterial,
+        width: u32,
+        height: u32,
+        sample_count: u32,
+        depth_format: wgpu::TextureFormat,
+    ) -> (Self, Texture) {
+        let (width, height) = (width.max(1), height.max(1));
+        let color = Texture::create_render_target(
+            device,
+            memory,
+            width,
+            height,
+            PostProcess::SCENE_FORMAT,
+            1,
+            "Render Target Camera Texture",
+        );
+        let msaa_texture = (sample_count > 1).then(|| {
+            Texture::create_render_target(
+                device,
+                memory,
+                width,
+                height,
+                PostProcess::SCENE_FORMAT,
+                sample_count,
+                "Render Target Camera MSAA Texture",
+            )
+        });
+        let depth_texture = Texture::create_render_target(
+            device,
+            memory,
+            width,
+            height,
+            depth_format,
+            sample_count,
+            "Render Target Camera Depth Texture",
+        );
+
+        let eye = Point3::new(0.0, 0.0, 0.0);
+        let target = Point3::new(0.0, 0.0, -1.0);
+        let projection = Projection::new(width, height, Rad(std::f32::consts::FRAC_PI_3), 0.1, 100.0);
+        let uniform = CameraUniform::new(eye, projection.calc_matrix());
+        let mut camera_buffers = FrameBuffers::new(
+            device,
+            memory,
+            "Render Target Camera Buffer",
+            wgpu::BufferUsages::UNIFORM,
+            MemoryCategory::Uniform,
+            bytemuck::cast_slice(&[uniform]),
+        );
+        // Stay in step with the scene buffers the bind groups pair them with
+        while camera_buffers.index() != scene_buffers.index() {
+            camera_buffers.advance();
+        }
+        let bind_groups = camera_buffers
+            .buffers()
+            .iter()
+            .zip(scene_buffers.buffers())
+            .map(|(camera_buffer, scene_buffer)| {
+                device.create_bind_group(&wgpu::BindGroupDescriptor {
+                    layout: camera_layout,
+                    entries: &[
+                        wgpu::BindGroupEntry {
+                            binding: 0,
+                            resource: camera_buffer.as_entire_binding(),
+                        },
+                        wgpu::BindGroupEntry {
+                            binding: 1,
+                            resource: scene_buffer.as_entire_binding(),
+                        },
+                    ],
+                    label: Some("render_target_camera_bind_group"),
+                })
+            })
+            .collect();
+        let instance_buffers = FrameBuffers::new(
+            device,
+            memory,
+            "Render Target Camera Instance Buffer",
+            wgpu::BufferUsages::VERTEX,
+            MemoryCategory::Mesh,
+            &[0; 64],
+        );
+
+        let camera = Self {
+            eye,
+            target,
+            up: Vector3::unit_y(),
+            projection,
+            render_layers: RenderLayers::ALL,
+            clip_plane: None,
+            enabled: true,
+            material,
+            width,
+            height,
+            msaa_texture,
+            depth_texture,
+            camera_buffers,
+            bind_groups,
+            instance_buffers,
+            lod_ranges: Vec::new(),
+        };
+        return (camera, color);
+    }
+
+    pub fn material(&self) -> TargetMaterial {
+        return self.material;
+    }
+
+    pub fn size(&self) -> (u32, u32) {
+        return (self.width, self.height);
+    }
+
+    pub fn look_at(&mut self, eye: Point3<f32>, target: Point3<f32>) {
+        self.eye = eye;
+        self.target = target;
+    }
+
+    /// Look at what a viewer at `eye` looking at `target` sees in a mirror on
+    /// `plane`, which faces the viewer, clipping everything behind it. The
+    /// image is not flipped, mirror it with the material's `uv_transform` of
+    /// `[-1, 1, 1, 0]`.
+    pub fn mirror(&mut self, eye: Point3<f32>, target: Point3<f32>, plane: Plane) {
+        let reflect = |p: Point3<f32>| p - plane.normal * (2.0 * plane.signed_distance(p.to_vec()));
+        self.eye = reflect(eye);
+        self.target = reflect(target);
+        self.up = Vector3::unit_y() - plane.normal * (2.0 * plane.normal.y);
+        self.clip_plane = Some(plane);
+    }
+
+    /// World to view transform looking from `eye` at `target`.
+    pub fn view_matrix(&self) -> Matrix4<f32> {
+        // Looking straight along `up` has no defined roll
+        let up = if (self.target - self.eye).normalize().cross(self.up).magnitude2() > 0.0 {
+            self.up
+        } else {
+            Vector3::unit_z()
+        };
+        return Matrix4::look_at_rh(self.eye, self.target, up);
+    }
+
+    pub fn frustum(&self) -> Frustum {
+        return Frustum::from_view_proj(&self.uniform().view_proj());
+    }
+
+    pub(crate) fn color_attachment<'a>(
+        &'a self,
+        texture: &'a Texture,
+    ) -> (&'a wgpu::TextureView, Option<&'a wgpu::TextureView>) {
+        return match &self.msaa_texture {
+            Some(msaa) => (&msaa.view, Some(&texture.view)),
+            None => (&texture.view, None),
+        };
+    }
+
+    pub(crate) fn depth_view(&self) -> &wgpu::TextureView {
+        return &self.depth_texture.view;
+    }
+
+    pub(crate) fn bind_group(&self) -> &wgpu::BindGroup {
+        return &self.bind_groups[self.camera_buffers.index()];
+    }
+
+    pub(crate) fn instance_buffer(&self) -> &wgpu::Buffer {
+        return self.instance_buffers.current();
+    }
+
+    pub(crate) fn lod_ranges(&self) -> &[Range<u32>] {
+        return &self.lod_ranges;
+    }
+
+    // Upload the camera and the instances it sees, `instance_data` holding
+    // the LOD levels' instances back to back
+    pub(crate) fn update(
+        &mut self,
+        device: &wgpu::Device,
+        memory: &MemoryTracker,
+        queue: &wgpu::Queue,
+        instance_data: &[u8],
+        lod_ranges: Vec<Range<u32>>,
+        version: u64,
+    ) {
+        self.camera_buffers.advance();
+        self.instance_buffers.advance();
+        let uniform = self.uniform().with_clip_plane(self.clip_plane);
+        self.camera_buffers
+            .write(device, memory, queue, bytemuck::cast_slice(&[uniform]), version);
+        if !instance_data.is_empty() {
+            self.instance_buffers
+                .write(device, memory, queue, instance_data, version);
+        }
+        self.lod_ranges = lod_ranges;
+    }
+}
+
+impl Camera for RenderTargetCamera {
+    fn uniform(&self) -> CameraUniform {
+        return CameraUniform::new(self.eye, self.projection.calc_matrix() * self.view_matrix());
+    }
+
+    fn projection(&self) -> &Projection {
+        return &self.projection;
+    }
+
+    fn projection_mut(&mut self) -> &mut Projection {
+        return &mut self.projection;
+    }
+}
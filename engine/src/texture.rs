@@ -1,18 +1,24 @@
 use anyhow::*;
 use image::GenericImageView;
 
+use crate::{
+    decode::DecodedImage,
+    memory::{MemoryCategory, MemoryTracker, TrackedTexture},
+};
+
 pub struct Texture {
-    pub texture: wgpu::Texture,
+    pub texture: TrackedTexture,
     pub view: wgpu::TextureView,
     pub sampler: wgpu::Sampler,
 }
 
 impl Texture {
-    pub const DEPTH_FORMAT: wgpu::TextureFormat = wgpu::TextureFormat::Depth32Float;
-
     pub fn create_depth_texture(
         device: &wgpu::Device,
+        memory: &MemoryTracker,
         config: &wgpu::SurfaceConfiguration,
+        format: wgpu::TextureFormat,
+        sample_count: u32,
         label: &str,
     ) -> Self {
         let size = wgpu::Extent3d {
@@ -24,12 +30,12 @@ impl Texture {
             label: Some(label),
             size,
             mip_level_count: 1,
-            sample_count: 1,
+            sample_count,
             dimension: wgpu::TextureDimension::D2,
-            format: Self::DEPTH_FORMAT,
+            format,
             usage: wgpu::TextureUsages::RENDER_ATTACHMENT | wgpu::TextureUsages::TEXTURE_BINDING,
         };
-        let texture = device.create_texture(&desc);
+        let texture = memory.create_texture(device, &desc, MemoryCategory::Texture);
 
         let view = texture.create_view(&wgpu::TextureViewDescriptor::default());
         let sampler = device.create_sampler(&wgpu::SamplerDescriptor {
@@ -53,19 +59,64 @@ impl Texture {
         }
     }
 
+    pub fn create_render_target(
+        device: &wgpu::Device,
+        memory: &MemoryTracker,
+        width: u32,
+        height: u32,
+        format: wgpu::TextureFormat,
+        sample_count: u32,
+        label: &str,
+    ) -> Self {
+        let size = wgpu::Extent3d {
+            width,
+            height,
+            depth_or_array_layers: 1,
+        };
+        let desc = wgpu::TextureDescriptor {
+            label: Some(label),
+            size,
+            mip_level_count: 1,
+            sample_count,
+            dimension: wgpu::TextureDimension::D2,
+            format,
+            usage: wgpu::TextureUsages::RENDER_ATTACHMENT | wgpu::TextureUsages::TEXTURE_BINDING,
+        };
+        let texture = memory.create_texture(device, &desc, MemoryCategory::Texture);
+
+        let view = texture.create_view(&wgpu::TextureViewDescriptor::default());
+        let sampler = device.create_sampler(&wgpu::SamplerDescriptor {
+            address_mode_u: wgpu::AddressMode::ClampToEdge,
+            address_mode_v: wgpu::AddressMode::ClampToEdge,
+            address_mode_w: wgpu::AddressMode::ClampToEdge,
+            mag_filter: wgpu::FilterMode::Linear,
+            min_filter: wgpu::FilterMode::Linear,
+            mipmap_filter: wgpu::FilterMode::Nearest,
+            ..Default::default()
+        });
+
+        Self {
+            texture,
+            view,
+            sampler,
+        }
+    }
+
     pub fn from_bytes(
         device: &wgpu::Device,
+        memory: &MemoryTracker,
         queue: &wgpu::Queue,
         bytes: &[u8],
         label: &str,
         is_normal_map: bool,
     ) -> Result<Self> {
         let img = image::load_from_memory(bytes)?;
-        Self::from_image(device, queue, &img, Some(label), is_normal_map)
+        Self::from_image(device, memory, queue, &img, Some(label), is_normal_map)
     }
 
     pub fn from_image(
         device: &wgpu::Device,
+        memory: &MemoryTracker,
         queue: &wgpu::Queue,
         img: &image::DynamicImage,
         label: Option<&str>,
@@ -79,19 +130,23 @@ impl Texture {
             height: dimensions.1,
             depth_or_array_layers: 1,
         };
-        let texture = device.create_texture(&wgpu::TextureDescriptor {
-            label,
-            size,
-            mip_level_count: 1,
-            sample_count: 1,
-            dimension: wgpu::TextureDimension::D2,
-            format: if is_normal_map {
-                wgpu::TextureFormat::Rgba8Unorm
-            } else {
-                wgpu::TextureFormat::Rgba8UnormSrgb
+        let texture = memory.create_texture(
+            device,
+            &wgpu::TextureDescriptor {
+                label,
+                size,
+                mip_level_count: 1,
+                sample_count: 1,
+                dimension: wgpu::TextureDimension::D2,
+                format: if is_normal_map {
+                    wgpu::TextureFormat::Rgba8Unorm
+                } else {
+                    wgpu::TextureFormat::Rgba8UnormSrgb
+                },
+                usage: wgpu::TextureUsages::TEXTURE_BINDING | wgpu::TextureUsages::COPY_DST,
             },
-            usage: wgpu::TextureUsages::TEXTURE_BINDING | wgpu::TextureUsages::COPY_DST,
-        });
+            MemoryCategory::Texture,
+        );
 
         queue.write_texture(
             wgpu::ImageCopyTexture {
@@ -126,4 +181,115 @@ impl Texture {
             sampler,
         })
     }
+
+    /// Upload an image decoded off-thread with all the mips it comes with.
+    pub fn from_decoded(
+        device: &wgpu::Device,
+        memory: &MemoryTracker,
+        queue: &wgpu::Queue,
+        image: &DecodedImage,
+        label: &str,
+        is_normal_map: bool,
+    ) -> Self {
+        let size = wgpu::Extent3d {
+            width: image.width,
+            height: image.height,
+            depth_or_array_layers: 1,
+        };
+        let format = image.encoding.texture_format(!is_normal_map);
+        let texture = memory.create_texture(
+            device,
+            &wgpu::TextureDescriptor {
+                label: Some(label),
+                size,
+                mip_level_count: image.mips.len().max(1) as u32,
+                sample_count: 1,
+                dimension: wgpu::TextureDimension::D2,
+                format,
+                usage: wgpu::TextureUsages::TEXTURE_BINDING | wgpu::TextureUsages::COPY_DST,
+            },
+            MemoryCategory::Texture,
+        );
+
+        // Rows are of 4x4 blocks for compressed formats, which are copied
+        // whole even where the level is smaller than a block
+        let info = format.describe();
+        let (block_width, block_height) = (info.block_dimensions.0 as u32, info.block_dimensions.1 as u32);
+        for (level, pixels) in image.mips.iter().enumerate() {
+            let level_size = size.mip_level_size(level as u32, false).physical_size(format);
+            queue.write_texture(
+                wgpu::ImageCopyTexture {
+                    aspect: wgpu::TextureAspect::All,
+                    texture: &texture,
+                    mip_level: level as u32,
+                    origin: wgpu::Origin3d::ZERO,
+                },
+                pixels,
+                wgpu::ImageDataLayout {
+                    offset: 0,
+                    bytes_per_row: std::num::NonZeroU32::new(level_size.width / block_width * info.block_size as u32),
+                    rows_per_image: std::num::NonZeroU32::new(level_size.height / block_height),
+                },
+                level_size,
+            );
+        }
+
+        let view = texture.create_view(&wgpu::TextureViewDescriptor::default());
+        let sampler = device.create_sampler(&wgpu::SamplerDescriptor {
+            address_mode_u: wgpu::AddressMode::ClampToEdge,
+            address_mode_v: wgpu::AddressMode::ClampToEdge,
+            address_mode_w: wgpu::AddressMode::ClampToEdge,
+            mag_filter: wgpu::FilterMode::Linear,
+            min_filter: wgpu::FilterMode::Nearest,
+            mipmap_filter: wgpu::FilterMode::Linear,
+            ..Default::default()
+        });
+
+        Self {
+            texture,
+            view,
+            sampler,
+        }
+    }
+
+    /// Stand-in for a texture that failed to load: a magenta and black
+    /// checkerboard that is hard to miss, or a flat normal for normal maps so
+    /// shading stays as if there was none.
+    pub fn placeholder(
+        device: &wgpu::Device,
+        memory: &MemoryTracker,
+        queue: &wgpu::Queue,
+        is_normal_map: bool,
+    ) -> Result<Self> {
+        const SIZE: u32 = 64;
+        const CELL: u32 = 8;
+        let img = image::RgbaImage::from_fn(SIZE, SIZE, |x, y| {
+            if is_normal_map {
+                image::Rgba([128, 128, 255, 255])
+            } else if (x / CELL + y / CELL).is_multiple_of(2) {
+                image::Rgba([255, 0, 255, 255])
+            } else {
+                image::Rgba([0, 0, 0, 255])
+            }
+        });
+        let img = image::DynamicImage::ImageRgba8(img);
+        Self::from_image(device, memory, queue, &img, Some("placeholder"), is_normal_map)
+    }
+}
+
+/// Linear value of an sRGB encoded channel, clamped to 0 to 1.
+pub fn srgb_to_linear(c: f32) -> f32 {
+    let c = c.clamp(0.0, 1.0);
+    if c <= 0.04045 {
+        return c / 12.92;
+    }
+    return ((c + 0.055) / 1.055).powf(2.4);
+}
+
+/// sRGB encoding of a linear channel.
+pub fn linear_to_srgb(c: f32) -> f32 {
+    if c <= 0.0031308 {
+        return c * 12.92;
+    }
+    return 1.055 * c.powf(1.0 / 2.4) - 0.055;
 }
@@ -0,0 +1,339 @@
+use std::{collections::VecDeque, sync::Arc};
+
+use cgmath::{prelude::*, Point3, Vector3};
+
+use crate::{
+    frame::FrameBuffers,
+    memory::{MemoryCategory, MemoryTracker},
+    particles::Curve,
+    pipelines::{PipelineCache, PipelineDescriptor},
+    post::PostProcess,
+    resources::Instance,
+    shader::ShaderPreprocessor,
+};
+
+/// How a trail's ribbon is drawn over the scene, unlit either way.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum TrailMaterial {
+    /// Blended by its alpha. Trails aren't sorted against each other.
+    Unlit,
+    /// Added onto the scene scaled by its alpha, e.g. for glowing projectiles.
+    Additive,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct TrailSettings {
+    /// Seconds each point of the trail lives after it's left behind.
+    pub lifetime: f32,
+    /// Distance the head moves before a point is left behind.
+    pub min_distance: f32,
+    /// Full width of the ribbon over a point's life.
+    pub width: Curve<f32>,
+    pub color: Curve<[f32; 4]>,
+    pub material: TrailMaterial,
+    /// Oldest points past this many are dropped early.
+    pub max_points: usize,
+}
+
+impl Default for TrailSettings {
+    fn default() -> Self {
+        Self {
+            lifetime: 1.0,
+            min_distance: 0.1,
+            width: Curve::linear(0.2, 0.0),
+            color: Curve::linear([1.0, 1.0, 1.0, 1.0], [1.0, 1.0, 1.0, 0.0]),
+            material: TrailMaterial::Unlit,
+            max_points: 64,
+        }
+    }
+}
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct TrailPoint {
+    pub position: Vector3<f32>,
+    /// Seconds since the point was left behind, 0 for the head.
+    pub age: f32,
+}
+
+/// Positions an instance passed through, extruded into a camera facing
+/// ribbon that narrows and fades with `TrailSettings` as the points age.
+#[derive(Debug, Clone)]
+pub struct Trail {
+    pub settings: TrailSettings,
+    /// Index into `Renderer::instances` the head follows, `None` to follow
+    /// `position`. The trail stops growing when the instance is gone.
+    pub instance: Option<usize>,
+    /// Offset of the head from the followed instance in its space, e.g. the
+    /// tail of a projectile, or the head's position without an instance.
+    pub position: Vector3<f32>,
+    /// Whether the head follows, the points left behind fade either way.
+    pub emitting: bool,
+    // Newest first
+    points: VecDeque<TrailPoint>,
+}
+
+impl Trail {
+    pub fn new(settings: TrailSettings, instance: Option<usize>) -> Self {
+        return Self {
+            settings,
+            instance,
+            position: Vector3::zero(),
+            emitting: true,
+            points: VecDeque::new(),
+        };
+    }
+
+    /// Points from the head to the oldest.
+    pub fn points(&self) -> impl Iterator<Item = &TrailPoint> {
+        return self.points.iter();
+    }
+
+    pub fn clear(&mut self) {
+        self.points.clear();
+    }
+
+    /// World position of the head, `None` if the followed instance is gone.
+    pub fn head_position(&self, instances: &[Instance]) -> Option<Vector3<f32>> {
+        return match self.instance {
+            Some(index) => {
+                let instance = instances.get(index)?;
+                let offset = instance.rotation.rotate_vector(self.position.mul_element_wise(instance.scale));
+                Some(instance.position + offset)
+            }
+            None => Some(self.position),
+        };
+    }
+
+    /// Age the points and move the head to `head`, leaving a point behind
+    /// whenever it's `min_distance` away from the last one.
+    pub fn update(&mut self, dt: f32, head: Option<Vector3<f32>>) {
+        let lifetime = self.settings.lifetime.max(f32::EPSILON);
+        for point in &mut self.points {
+            point.age += dt;
+        }
+        self.points.retain(|p| p.age < lifetime);
+
+        if let Some(position) = head.filter(|_| self.emitting) {
+            let settled = self
+                .points
+                .get(1)
+                .is_some_and(|p| (p.position - position).magnitude() < self.settings.min_distance);
+            if settled {
+                self.points[0] = TrailPoint { position, age: 0.0 };
+            } else if self.points.front().is_none_or(|p| p.position != position) {
+                self.points.push_front(TrailPoint { position, age: 0.0 });
+            }
+        }
+        self.points.truncate(self.settings.max_points.max(2));
+    }
+
+    // Two triangles per segment between points, each point pushed out to
+    // both sides across the direction to `eye`
+    fn extrude(&self, eye: Point3<f32>, vertices: &mut Vec<TrailVertex>) {
+        if self.points.len() < 2 {
+            return;
+        }
+        let lifetime = self.settings.lifetime.max(f32::EPSILON);
+        let mut side = Vector3::unit_y();
+        let mut previous: Option<[TrailVertex; 2]> = None;
+        for (i, point) in self.points.iter().enumerate() {
+            let newer = self.points[i.saturating_sub(1)].position;
+            let older = self.points.get(i + 1).map_or(point.position, |p| p.position);
+            let across = (newer - older).cross(eye.to_vec() - point.position);
+            // Keep the last side where the ribbon points at the eye
+            if across.magnitude2() > f32::EPSILON {
+                side = across.normalize();
+            }
+            let life = (point.age / lifetime).min(1.0);
+            let offset = side * self.settings.width.sample(life) * 0.5;
+            let color = self.settings.color.sample(life);
+            let vertex = |position: Vector3<f32>, edge: f32| TrailVertex {
+                position_edge: position.extend(edge).into(),
+                color,
+            };
+            let current = [vertex(point.position - offset, -1.0), vertex(point.position + offset, 1.0)];
+            if let Some([left, right]) = previous {
+                vertices.extend([left, right, current[1], left, current[1], current[0]]);
+            }
+            previous = Some(current);
+        }
+    }
+}
+
+#[repr(C)]
+#[derive(Debug, Copy, Clone, Default, bytemuck::Pod, bytemuck::Zeroable)]
+struct TrailVertex {
+    position_edge: [f32; 4],
+    color: [f32; 4],
+}
+
+impl TrailVertex {
+    fn desc<'a>() -> wgpu::VertexBufferLayout<'a> {
+        use std::mem;
+        wgpu::VertexBufferLayout {
+            array_stride: mem::size_of::<TrailVertex>() as wgpu::BufferAddress,
+            step_mode: wgpu::VertexStepMode::Vertex,
+            attributes: &[
+                wgpu::VertexAttribute {
+                    offset: 0,
+                    shader_location: 0,
+                    format: wgpu::VertexFormat::Float32x4,
+                },
+                wgpu::VertexAttribute {
+                    offset: mem::size_of::<[f32; 4]>() as wgpu::BufferAddress,
+                    shader_location: 1,
+                    format: wgpu::VertexFormat::Float32x4,
+                },
+            ],
+        }
+    }
+}
+
+/// Trails extruded into ribbons on the CPU every frame, drawn after the
+/// opaque scene with one draw per `TrailMaterial`.
+pub struct TrailRenderer {
+    pub trails: Vec<Trail>,
+    vertex_buffers: FrameBuffers,
+    // Unlit vertices first, then additive ones
+    unlit_count: u32,
+    additive_count: u32,
+    pipeline: Arc<wgpu::RenderPipeline>,
+    additive_pipeline: Arc<wgpu::RenderPipeline>,
+}
+
+impl TrailRenderer {
+    pub fn new(
+        device: &wgpu::Device,
+        memory: &MemoryTracker,
+        pipelines: &mut PipelineCache,
+        shaders: &ShaderPreprocessor,
+        camera_bind_group_layout: &wgpu::BindGroupLayout,
+        depth_format: wgpu::TextureFormat,
+        multisample: wgpu::MultisampleState,
+    ) -> Self {
+        let vertex_buffers = FrameBuffers::new(
+            device,
+            memory,
+            "Trail Buffer",
+            wgpu::BufferUsages::VERTEX,
+            MemoryCategory::Mesh,
+            bytemuck::cast_slice(&[TrailVertex::default()]),
+        );
+
+        let shader = shaders.process("trail.wgsl").expect("Failed to preprocess trail.wgsl");
+        let desc = PipelineDescriptor {
+            label: "Trail Pipeline",
+            layout: "Trail Pipeline Layout",
+            bind_group_layouts: &[camera_bind_group_layout],
+            shader: &shader,
+            vertex_layouts: &[TrailVertex::desc()],
+            color_format: Some(PostProcess::SCENE_FORMAT),
+            depth_format: Some(depth_format),
+            blend: wgpu::BlendState::ALPHA_BLENDING,
+            cull_mode: None,
+            // Soft sides are blended, coverage would dither them
+            multisample: wgpu::MultisampleState {
+                alpha_to_coverage_enabled: false,
+                ..multisample
+            },
+            order_independent: false,
+        };
+        let pipeline = pipelines.pipeline(&desc);
+        // Added onto the scene color, its alpha is kept
+        let additive_pipeline = pipelines.pipeline(&PipelineDescriptor {
+            label: "Additive Trail Pipeline",
+            blend: wgpu::BlendState {
+                color: wgpu::BlendComponent {
+                    src_factor: wgpu::BlendFactor::SrcAlpha,
+                    dst_factor: wgpu::BlendFactor::One,
+                    operation: wgpu::BlendOperation::Add,
+                },
+                alpha: wgpu::BlendComponent {
+                    src_factor: wgpu::BlendFactor::Zero,
+                    dst_factor: wgpu::BlendFactor::One,
+                    operation: wgpu::BlendOperation::Add,
+                },
+            },
+            ..desc
+        });
+
+        return Self {
+            trails: Vec::new(),
+            vertex_buffers,
+            unlit_count: 0,
+            additive_count: 0,
+            pipeline,
+            additive_pipeline,
+        };
+    }
+
+    /// Vertices uploaded last update, six per segment.
+    pub fn vertex_count(&self) -> u32 {
+        return self.unlit_count + self.additive_count;
+    }
+
+    /// Whether any trail has points left, they change every frame until
+    /// they fade out.
+    pub fn is_active(&self) -> bool {
+        return self.trails.iter().any(|t| !t.points.is_empty());
+    }
+
+    /// Move the trails with their instances, age their points and upload the
+    /// ribbons facing `eye`.
+    #[allow(clippy::too_many_arguments)]
+    pub fn update(
+        &mut self,
+        device: &wgpu::Device,
+        memory: &MemoryTracker,
+        queue: &wgpu::Queue,
+        dt: f32,
+        eye: Point3<f32>,
+        instances: &[Instance],
+        version: u64,
+    ) {
+        self.vertex_buffers.advance();
+        for trail in &mut self.trails {
+            let head = trail.head_position(instances);
+            trail.update(dt, head);
+        }
+
+        let mut vertices = Vec::new();
+        for material in [TrailMaterial::Unlit, TrailMaterial::Additive] {
+            let start = vertices.len();
+            for trail in self.trails.iter().filter(|t| t.settings.material == material) {
+                trail.extrude(eye, &mut vertices);
+            }
+            let count = (vertices.len() - start) as u32;
+            if material == TrailMaterial::Unlit {
+                self.unlit_count = count;
+            } else {
+                self.additive_count = count;
+            }
+        }
+        if !vertices.is_empty() {
+            self.vertex_buffers
+                .write(device, memory, queue, bytemuck::cast_slice(&vertices), version);
+        }
+    }
+
+    pub fn render<'a>(&'a self, render_pass: &mut wgpu::RenderPass<'a>, camera_bind_group: &'a wgpu::BindGroup) {
+        if self.vertex_count() == 0 {
+            return;
+        }
+        render_pass.set_bind_group(0, camera_bind_group, &[]);
+        render_pass.set_vertex_buffer(0, self.vertex_buffers.current().slice(..));
+        if self.unlit_count > 0 {
+            render_pass.set_pipeline(&self.pipeline);
+            render_pass.draw(0..self.unlit_count, 0..1);
+        }
+        if self.additive_count > 0 {
+            render_pass.set_pipeline(&self.additive_pipeline);
+            render_pass.draw(self.unlit_count..self.vertex_count(), 0..1);
+        }
+    }
+
+    /// Draws `render` issues.
+    pub fn draw_count(&self) -> usize {
+        return (self.unlit_count > 0) as usize + (self.additive_count > 0) as usize;
+    }
+}
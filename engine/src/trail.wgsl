@@ -0,0 +1,32 @@
+// Ribbons extruded from trail points on the CPU, see trail.rs
+#include "camera.wgsl"
+@group(0) @binding(0)
+var<uniform> camera: Camera;
+
+struct TrailVertexInput {
+    // xyz: world position, w: -1 to 1 across the ribbon
+    @location(0) position_edge: vec4<f32>,
+    @location(1) color: vec4<f32>,
+};
+
+struct VertexOutput {
+    @builtin(position) clip_position: vec4<f32>,
+    @location(0) edge: f32,
+    @location(1) color: vec4<f32>,
+};
+
+@vertex
+fn vs_main(vertex: TrailVertexInput) -> VertexOutput {
+    var out: VertexOutput;
+    out.clip_position = camera.view_proj * vec4<f32>(vertex.position_edge.xyz, 1.0);
+    out.edge = vertex.position_edge.w;
+    out.color = vertex.color;
+    return out;
+}
+
+@fragment
+fn fs_main(input: VertexOutput) -> @location(0) vec4<f32> {
+    // Soft along the ribbon's sides, unlit
+    let falloff = 1.0 - smoothstep(0.6, 1.0, abs(input.edge));
+    return vec4<f32>(input.color.rgb, input.color.a * falloff);
+}
@@ -0,0 +1,387 @@
+use std::ops::Range;
+
+use anyhow::*;
+
+use crate::{
+    frame::FrameBuffers,
+    layers::RenderLayers,
+    memory::{MemoryCategory, MemoryTracker, TrackedTexture},
+    model::Model,
+    motion::{InstanceMotion, MotionKernel, MotionState},
+    resources::{Instance, InstanceFormat, ModelVertex},
+    skinning::{AnimationInstanceRaw, AnimationState, BakedClip},
+};
+
+/// Vertex positions and normals of a mesh baked per frame, e.g. from a cloth
+/// or fluid simulation, laid out as `[frame][vertex]`.
+#[derive(Debug, Clone)]
+pub struct VertexAnimation {
+    pub vertex_count: u32,
+    pub sample_rate: f32,
+    pub clips: Vec<BakedClip>,
+    positions: Vec<[f32; 4]>,
+    normals: Vec<[f32; 4]>,
+}
+
+impl VertexAnimation {
+    const MAGIC: &'static [u8; 4] = b"VAT1";
+
+    pub fn new(vertex_count: u32, sample_rate: f32) -> Self {
+        return Self {
+            vertex_count,
+            sample_rate,
+            clips: Vec::new(),
+            positions: Vec::new(),
+            normals: Vec::new(),
+        };
+    }
+
+    /// Add a clip of `frames`, each holding a position and normal per vertex.
+    pub fn push_clip(&mut self, name: &str, frames: &[Vec<([f32; 3], [f32; 3])>]) -> Result<()> {
+        ensure!(!frames.is_empty(), "Clip {:?} has no frames", name);
+        for (i, frame) in frames.iter().enumerate() {
+            ensure!(
+                frame.len() == self.vertex_count as usize,
+                "Frame {} of clip {:?} has {} vertices, expected {}",
+                i,
+                name,
+                frame.len(),
+                self.vertex_count
+            );
+        }
+
+        let first_frame = (self.positions.len() / self.vertex_count.max(1) as usize) as u32;
+        for (position, normal) in frames.iter().flatten() {
+            self.positions.push([position[0], position[1], position[2], 1.0]);
+            self.normals.push([normal[0], normal[1], normal[2], 0.0]);
+        }
+        self.clips.push(BakedClip {
+            name: name.to_string(),
+            first_frame,
+            frame_count: frames.len() as u32,
+            duration: (frames.len() - 1) as f32 / self.sample_rate,
+        });
+        return Ok(());
+    }
+
+    /// Parse the VAT format: "VAT1", vertex count (u32), clip count (u32) and
+    /// sample rate (f32), then per clip its name length (u32), UTF-8 name,
+    /// frame count (u32) and per frame and vertex a position and a normal as
+    /// three f32 each. All little endian.
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self> {
+        let mut reader = Reader { bytes };
+        ensure!(reader.take(4)? == Self::MAGIC, "Not a vertex animation file");
+        let vertex_count = reader.u32()?;
+        let clip_count = reader.u32()?;
+        let sample_rate = reader.f32()?;
+        ensure!(sample_rate > 0.0, "Invalid sample rate {}", sample_rate);
+
+        let mut animation = Self::new(vertex_count, sample_rate);
+        for _ in 0..clip_count {
+            let name_length = reader.u32()? as usize;
+            let name = std::str::from_utf8(reader.take(name_length)?)?.to_string();
+            let frame_count = reader.u32()?;
+            // Six f32 per frame and vertex, checked before allocating for them
+            let size = (frame_count as usize)
+                .checked_mul(vertex_count as usize)
+                .and_then(|count| count.checked_mul(24));
+            ensure!(
+                size.is_some_and(|size| size <= reader.bytes.len()),
+                "Clip {:?} has {} frames of {} vertices, more than the data holds",
+                name,
+                frame_count,
+                vertex_count
+            );
+            let mut frames = Vec::with_capacity(frame_count as usize);
+            for _ in 0..frame_count {
+                let mut frame = Vec::with_capacity(vertex_count as usize);
+                for _ in 0..vertex_count {
+                    let position = [reader.f32()?, reader.f32()?, reader.f32()?];
+                    let normal = [reader.f32()?, reader.f32()?, reader.f32()?];
+                    frame.push((position, normal));
+                }
+                frames.push(frame);
+            }
+            animation.push_clip(&name, &frames)?;
+        }
+        return Ok(animation);
+    }
+
+    pub fn load<P: AsRef<std::path::Path>>(path: P) -> Result<Self> {
+        let path = path.as_ref();
+        let bytes = std::fs::read(path).with_context(|| format!("Failed to read {:?}", path))?;
+        return Self::from_bytes(&bytes).with_context(|| format!("Failed to parse {:?}", path));
+    }
+
+    pub fn frame_count(&self) -> u32 {
+        return (self.positions.len() / self.vertex_count.max(1) as usize) as u32;
+    }
+
+    pub fn clip_index(&self, name: &str) -> Option<usize> {
+        return self.clips.iter().position(|c| c.name == name);
+    }
+
+    /// Frames to blend between for an instance.
+    pub fn sample(&self, state: &AnimationState) -> AnimationInstanceRaw {
+        return AnimationInstanceRaw::sample(&self.clips, self.sample_rate, self.vertex_count, state);
+    }
+}
+
+// Little endian reader over VAT data
+struct Reader<'a> {
+    bytes: &'a [u8],
+}
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, count: usize) -> Result<&'a [u8]> {
+        ensure!(self.bytes.len() >= count, "Unexpected end of vertex animation data");
+        let (head, rest) = self.bytes.split_at(count);
+        self.bytes = rest;
+        return Ok(head);
+    }
+
+    fn u32(&mut self) -> Result<u32> {
+        return Ok(u32::from_le_bytes(self.take(4)?.try_into()?));
+    }
+
+    fn f32(&mut self) -> Result<f32> {
+        return Ok(f32::from_bits(self.u32()?));
+    }
+}
+
+/// Many instances of a model playing a baked vertex animation, each with its
+/// own clip and time, drawn with one instanced draw call. Positions and
+/// normals are read in the vertex shader from two float textures holding
+/// every frame, in rows of `TEXTURE_WIDTH` vertices.
+pub struct VatCrowd {
+    pub model: Model,
+    animation: VertexAnimation,
+    _positions: TrackedTexture,
+    _normals: TrackedTexture,
+    pub bind_group: wgpu::BindGroup,
+    pub instances: Vec<(Instance, AnimationState)>,
+    instance_buffers: FrameBuffers,
+    animation_buffers: FrameBuffers,
+    /// Moves the instances every frame on the GPU, see `InstanceMotion`.
+    pub motion: Option<InstanceMotion>,
+    motion_state: MotionState,
+    instance_format: InstanceFormat,
+    // Instances uploaded for the current frame
+    visible: u32,
+}
+
+impl VatCrowd {
+    pub const TEXTURE_WIDTH: u32 = 2048;
+
+    /// `model` must have a single mesh with the animation's vertices.
+    pub fn new(
+        device: &wgpu::Device,
+        memory: &MemoryTracker,
+        queue: &wgpu::Queue,
+        layout: &wgpu::BindGroupLayout,
+        model: Model,
+        animation: VertexAnimation,
+        instance_format: InstanceFormat,
+    ) -> Result<Self> {
+        ensure!(model.meshes.len() == 1, "Vertex animated models need a single mesh");
+        let vertex_count = model.meshes[0].vertex_buffer.size() / std::mem::size_of::<ModelVertex>() as u64;
+        ensure!(
+            vertex_count == animation.vertex_count as u64,
+            "Mesh has {} vertices, the animation {}",
+            vertex_count,
+            animation.vertex_count
+        );
+        ensure!(animation.frame_count() > 0, "Vertex animation needs at least one clip");
+
+        let texels = animation.positions.len() as u32;
+        let height = texels.div_ceil(Self::TEXTURE_WIDTH);
+        ensure!(
+            height <= device.limits().max_texture_dimension_2d,
+            "Vertex animation too large, {} frames of {} vertices",
+            animation.frame_count(),
+            animation.vertex_count
+        );
+        let size = wgpu::Extent3d {
+            width: Self::TEXTURE_WIDTH,
+            height,
+            depth_or_array_layers: 1,
+        };
+        let upload = |label: &str, data: &[[f32; 4]]| {
+            let texture = memory.create_texture(
+                device,
+                &wgpu::TextureDescriptor {
+                    label: Some(label),
+                    size,
+                    mip_level_count: 1,
+                    sample_count: 1,
+                    dimension: wgpu::TextureDimension::D2,
+                    format: wgpu::TextureFormat::Rgba32Float,
+                    usage: wgpu::TextureUsages::TEXTURE_BINDING | wgpu::TextureUsages::COPY_DST,
+                },
+                MemoryCategory::Texture,
+            );
+            // The last row is padded to the full width
+            let mut padded = data.to_vec();
+            padded.resize((Self::TEXTURE_WIDTH * height) as usize, [0.0; 4]);
+            queue.write_texture(
+                wgpu::ImageCopyTexture {
+                    aspect: wgpu::TextureAspect::All,
+                    texture: &texture,
+                    mip_level: 0,
+                    origin: wgpu::Origin3d::ZERO,
+                },
+                bytemuck::cast_slice(&padded),
+                wgpu::ImageDataLayout {
+                    offset: 0,
+                    bytes_per_row: std::num::NonZeroU32::new(16 * Self::TEXTURE_WIDTH),
+                    rows_per_image: std::num::NonZeroU32::new(height),
+                },
+                size,
+            );
+            texture
+        };
+        let positions = upload("Vertex Animation Positions", &animation.positions);
+        let normals = upload("Vertex Animation Normals", &animation.normals);
+
+        let positions_view = positions.create_view(&wgpu::TextureViewDescriptor::default());
+        let normals_view = normals.create_view(&wgpu::TextureViewDescriptor::default());
+        let bind_group = device.create_bind_group(&wgpu::BindGroupDescriptor {
+            layout,
+            entries: &[
+                wgpu::BindGroupEntry {
+                    binding: 0,
+                    resource: wgpu::BindingResource::TextureView(&positions_view),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 1,
+                    resource: wgpu::BindingResource::TextureView(&normals_view),
+                },
+            ],
+            label: Some("vat_bind_group"),
+        });
+
+        let instance_buffers = FrameBuffers::new(
+            device,
+            memory,
+            "Vertex Animation Instance Buffer",
+            MotionKernel::instance_usage(device),
+            MemoryCategory::Mesh,
+            &vec![0; instance_format.stride()],
+        );
+        let animation_buffers = FrameBuffers::new(
+            device,
+            memory,
+            "Vertex Animation Frame Buffer",
+            wgpu::BufferUsages::VERTEX,
+            MemoryCategory::Mesh,
+            bytemuck::cast_slice(&[AnimationInstanceRaw::default()]),
+        );
+
+        return Ok(Self {
+            model,
+            animation,
+            _positions: positions,
+            _normals: normals,
+            bind_group,
+            instances: Vec::new(),
+            instance_buffers,
+            animation_buffers,
+            motion: None,
+            motion_state: MotionState::default(),
+            instance_format,
+            visible: 0,
+        });
+    }
+
+    /// Layout of the position and normal textures, at group 3 of the vertex
+    /// animation pipeline.
+    pub fn bind_group_layout(device: &wgpu::Device) -> wgpu::BindGroupLayout {
+        let entry = |binding: u32| wgpu::BindGroupLayoutEntry {
+            binding,
+            visibility: wgpu::ShaderStages::VERTEX,
+            ty: wgpu::BindingType::Texture {
+                multisampled: false,
+                view_dimension: wgpu::TextureViewDimension::D2,
+                sample_type: wgpu::TextureSampleType::Float { filterable: false },
+            },
+            count: None,
+        };
+        return device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
+            entries: &[entry(0), entry(1)],
+            label: Some("vat_bind_group_layout"),
+        });
+    }
+
+    pub fn animation(&self) -> &VertexAnimation {
+        return &self.animation;
+    }
+
+    pub fn instance_buffer(&self) -> &wgpu::Buffer {
+        return self.instance_buffers.current();
+    }
+
+    pub fn animation_buffer(&self) -> &wgpu::Buffer {
+        return self.animation_buffers.current();
+    }
+
+    pub fn instance_range(&self) -> Range<u32> {
+        return 0..self.visible;
+    }
+
+    /// Advance every instance's animation and upload the frame's instance data
+    /// for instances on `layers`. `motion` records the dispatch moving them by
+    /// `VatCrowd::motion`, without a kernel they're moved on the CPU.
+    #[allow(clippy::too_many_arguments)]
+    pub fn update(
+        &mut self,
+        device: &wgpu::Device,
+        memory: &MemoryTracker,
+        queue: &wgpu::Queue,
+        dt: f32,
+        layers: RenderLayers,
+        motion: Option<(&MotionKernel, &mut wgpu::CommandEncoder)>,
+        version: u64,
+    ) {
+        self.instance_buffers.advance();
+        self.animation_buffers.advance();
+
+        let mut visible = Vec::with_capacity(self.instances.len());
+        let mut animation_data = Vec::with_capacity(self.instances.len());
+        for (instance, state) in &mut self.instances {
+            let duration = self
+                .animation
+                .clips
+                .get(state.clip)
+                .map_or(0.0, |c| c.duration);
+            state.advance(dt, duration);
+            if instance.layers.intersects(layers) {
+                visible.push(*instance);
+                animation_data.push(self.animation.sample(state));
+            }
+        }
+        self.visible = visible.len() as u32;
+        if visible.is_empty() {
+            return;
+        }
+
+        self.motion_state.upload(
+            device,
+            memory,
+            queue,
+            motion,
+            self.motion,
+            &mut self.instance_buffers,
+            self.instance_format,
+            &visible,
+            dt,
+            version,
+        );
+        self.animation_buffers.write(
+            device,
+            memory,
+            queue,
+            bytemuck::cast_slice(&animation_data),
+            version,
+        );
+    }
+}
@@ -0,0 +1,135 @@
+use cgmath::{InnerSpace, Matrix4, Point3, Vector2, Vector4};
+
+use crate::math::Ray;
+
+/// Area of the window, in physical pixels from its top left corner.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct ViewportRect {
+    pub x: f32,
+    pub y: f32,
+    pub width: f32,
+    pub height: f32,
+}
+
+impl ViewportRect {
+    pub fn new(x: f32, y: f32, width: f32, height: f32) -> Self {
+        return Self { x, y, width, height };
+    }
+
+    pub fn contains(&self, x: f32, y: f32) -> bool {
+        return x >= self.x && y >= self.y && x < self.x + self.width && y < self.y + self.height;
+    }
+}
+
+/// Where the scene ends up on screen: the window, the area of it the scene
+/// is shown in, the resolution it is rendered at and the DPI scale. Every
+/// conversion between window pixels, render target pixels and the camera's
+/// clip space goes through here, so picking, gizmos, the overlay and the
+/// camera agree when the scene is rendered at another size than it is shown.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct Viewport {
+    /// Size of the window in physical pixels.
+    pub window: [u32; 2],
+    /// Where the scene is shown in the window, all of it unless set.
+    pub rect: ViewportRect,
+    /// Size of the scene's render targets, `rect` scaled by the render scale
+    /// or a capture's resolution.
+    pub render: [u32; 2],
+    /// DPI scale factor of the window's monitor, from winit.
+    pub scale_factor: f64,
+}
+
+impl Viewport {
+    /// The scene rendered at the window's size and shown in all of it.
+    pub fn new(width: u32, height: u32, scale_factor: f64) -> Self {
+        return Self {
+            window: [width, height],
+            rect: ViewportRect::new(0.0, 0.0, width as f32, height as f32),
+            render: [width, height],
+            scale_factor,
+        };
+    }
+
+    /// Render target size for showing `rect` at `render_scale`.
+    pub fn scaled_size(rect: ViewportRect, render_scale: f32) -> [u32; 2] {
+        let scale = render_scale.max(0.01);
+        return [rect.width, rect.height].map(|s| ((s * scale).round() as u32).max(1));
+    }
+
+    /// Width over height of the area the scene is shown in.
+    pub fn aspect(&self) -> f32 {
+        return self.rect.width / self.rect.height.max(1.0);
+    }
+
+    /// Render target pixels per window pixel, horizontally and vertically.
+    pub fn render_scale(&self) -> [f32; 2] {
+        return [
+            self.render[0] as f32 / self.rect.width.max(1.0),
+            self.render[1] as f32 / self.rect.height.max(1.0),
+        ];
+    }
+
+    /// A window position in the scene's normalized device coordinates, x
+    /// right and y up from -1 to 1 across the viewport rect.
+    pub fn window_to_ndc(&self, x: f32, y: f32) -> Vector2<f32> {
+        return Vector2::new(
+            2.0 * (x - self.rect.x) / self.rect.width.max(1.0) - 1.0,
+            1.0 - 2.0 * (y - self.rect.y) / self.rect.height.max(1.0),
+        );
+    }
+
+    pub fn ndc_to_window(&self, ndc: Vector2<f32>) -> [f32; 2] {
+        return [
+            self.rect.x + (ndc.x + 1.0) * 0.5 * self.rect.width,
+            self.rect.y + (1.0 - ndc.y) * 0.5 * self.rect.height,
+        ];
+    }
+
+    /// A window position in the pixels of the scene's render targets.
+    pub fn window_to_render(&self, x: f32, y: f32) -> [f32; 2] {
+        let scale = self.render_scale();
+        return [(x - self.rect.x) * scale[0], (y - self.rect.y) * scale[1]];
+    }
+
+    pub fn render_to_window(&self, x: f32, y: f32) -> [f32; 2] {
+        let scale = self.render_scale();
+        return [self.rect.x + x / scale[0], self.rect.y + y / scale[1]];
+    }
+
+    /// Ray through a window position, unprojected with the camera's inverse
+    /// view-projection matrix.
+    pub fn ray(&self, x: f32, y: f32, inv_view_proj: Matrix4<f32>) -> Ray {
+        let near = self.unproject(x, y, 0.0, inv_view_proj);
+        let far = self.unproject(x, y, 1.0, inv_view_proj);
+        return Ray {
+            origin: near,
+            direction: (far - near).normalize(),
+        };
+    }
+
+    /// World position of a depth buffer value at a window position.
+    pub fn unproject(&self, x: f32, y: f32, depth: f32, inv_view_proj: Matrix4<f32>) -> Point3<f32> {
+        let ndc = self.window_to_ndc(x, y);
+        return Point3::from_homogeneous(inv_view_proj * Vector4::new(ndc.x, ndc.y, depth, 1.0));
+    }
+
+    /// Window position of a world position, `None` behind the camera.
+    pub fn project(&self, point: Point3<f32>, view_proj: Matrix4<f32>) -> Option<[f32; 2]> {
+        let clip = view_proj * point.to_homogeneous();
+        if clip.w <= 0.0 {
+            return None;
+        }
+        return Some(self.ndc_to_window(Vector2::new(clip.x / clip.w, clip.y / clip.w)));
+    }
+
+    /// Physical window pixels to the logical pixels of the OS.
+    pub fn to_logical(&self, physical: [f32; 2]) -> [f32; 2] {
+        let factor = self.scale_factor as f32;
+        return [physical[0] / factor, physical[1] / factor];
+    }
+
+    pub fn to_physical(&self, logical: [f32; 2]) -> [f32; 2] {
+        let factor = self.scale_factor as f32;
+        return [logical[0] * factor, logical[1] * factor];
+    }
+}
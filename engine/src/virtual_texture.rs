@@ -0,0 +1,850 @@
+use std::{
+    cmp::Reverse,
+    collections::{HashMap, HashSet},
+    path::Path,
+    sync::{
+        mpsc::{self, Receiver, Sender},
+        Arc, Mutex,
+    },
+};
+
+use anyhow::*;
+use cgmath::Point3;
+use image::{imageops::FilterType, DynamicImage, RgbaImage};
+
+use crate::{
+    memory::{MemoryCategory, MemoryTracker, TrackedBuffer, TrackedTexture},
+    pipelines::{PipelineCache, PipelineDescriptor},
+    post::PostProcess,
+    shader::ShaderPreprocessor,
+    texture::Texture,
+};
+
+/// Side of a page in texels, without its border.
+pub const PAGE_SIZE: u32 = 128;
+// Texels copied from the neighbouring pages on each side, so bilinear
+// filtering near a page's edge doesn't read the next atlas page
+const PAGE_BORDER: u32 = 4;
+const PHYSICAL_PAGE_SIZE: u32 = PAGE_SIZE + 2 * PAGE_BORDER;
+// Page coordinates are packed into 12 bits each in the feedback
+const MAX_PAGES: u32 = 1 << 12;
+// Pages requested from the worker but not uploaded yet
+const MAX_REQUESTS_IN_FLIGHT: usize = 32;
+const FEEDBACK_FORMAT: wgpu::TextureFormat = wgpu::TextureFormat::R32Uint;
+const FEEDBACK_DEPTH_FORMAT: wgpu::TextureFormat = wgpu::TextureFormat::Depth32Float;
+
+/// A page of a virtual texture, `x` and `y` counted in pages of `mip`.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+pub struct PageKey {
+    pub mip: u32,
+    pub x: u32,
+    pub y: u32,
+}
+
+impl PageKey {
+    /// The page covering this one in the next coarser mip.
+    pub fn parent(&self) -> Self {
+        return Self {
+            mip: self.mip + 1,
+            x: self.x / 2,
+            y: self.y / 2,
+        };
+    }
+
+    // Inverse of the packing in virtual_texture.wgsl, `None` for fragments
+    // without terrain
+    fn unpack(value: u32) -> Option<Self> {
+        if value == 0 {
+            return None;
+        }
+        return Some(Self {
+            mip: (value >> 24) - 1,
+            x: value & 0xfff,
+            y: (value >> 12) & 0xfff,
+        });
+    }
+}
+
+/// Texel data of a virtual texture, read on a worker thread one page at a
+/// time. Implementations may decode tiles from disk or generate texels, only
+/// the pages the camera sees are ever read.
+pub trait PageSource: Send {
+    /// Side of mip 0 in texels, a power of two multiple of `PAGE_SIZE`.
+    fn size(&self) -> u32;
+
+    /// RGBA8 texels of the `size`×`size` block of `mip` starting at `origin`,
+    /// which may reach past the edges; those texels repeat the edge.
+    fn read(&self, mip: u32, origin: [i32; 2], size: u32) -> Vec<u8>;
+}
+
+/// Page source keeping a whole image and its mips in memory. Good for trying
+/// out textures that still fit in memory, but not on the GPU in one piece.
+pub struct ImagePageSource {
+    mips: Vec<RgbaImage>,
+}
+
+impl ImagePageSource {
+    pub fn new(image: DynamicImage) -> Result<Self> {
+        let image = image.to_rgba8();
+        let size = image.width();
+        if image.height() != size || !size.is_power_of_two() || size < PAGE_SIZE {
+            bail!(
+                "Virtual textures have to be square with a power of two side of at least {}, got {}x{}",
+                PAGE_SIZE,
+                image.width(),
+                image.height()
+            );
+        }
+        let mut mips = vec![image];
+        while mips.last().unwrap().width() > PAGE_SIZE {
+            let side = mips.last().unwrap().width() / 2;
+            let mip = image::imageops::resize(mips.last().unwrap(), side, side, FilterType::Triangle);
+            mips.push(mip);
+        }
+        return Ok(Self { mips });
+    }
+
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<Self> {
+        let path = path.as_ref();
+        let image = image::open(path).with_context(|| format!("Failed to open {}", path.display()))?;
+        return Self::new(image);
+    }
+}
+
+impl PageSource for ImagePageSource {
+    fn size(&self) -> u32 {
+        return self.mips[0].width();
+    }
+
+    fn read(&self, mip: u32, origin: [i32; 2], size: u32) -> Vec<u8> {
+        let image = &self.mips[(mip as usize).min(self.mips.len() - 1)];
+        let last = image.width() as i32 - 1;
+        let mut texels = Vec::with_capacity((size * size * 4) as usize);
+        for y in 0..size as i32 {
+            for x in 0..size as i32 {
+                let px = (origin[0] + x).clamp(0, last) as u32;
+                let py = (origin[1] + y).clamp(0, last) as u32;
+                texels.extend_from_slice(&image.get_pixel(px, py).0);
+            }
+        }
+        return texels;
+    }
+}
+
+#[derive(Debug, Copy, Clone)]
+pub struct VirtualTextureSettings {
+    /// Side of the physical page atlas in pages, at most 255.
+    pub atlas_pages: u32,
+    /// The feedback pass renders at the screen resolution divided by this.
+    pub feedback_divisor: u32,
+    /// Pages copied into the atlas per frame, bounding the upload cost.
+    pub uploads_per_frame: usize,
+}
+
+impl Default for VirtualTextureSettings {
+    fn default() -> Self {
+        Self {
+            atlas_pages: 16,
+            feedback_divisor: 8,
+            uploads_per_frame: 4,
+        }
+    }
+}
+
+#[repr(C)]
+#[derive(Debug, Copy, Clone, bytemuck::Pod, bytemuck::Zeroable)]
+struct TerrainUniform {
+    origin_extent: [f32; 4],
+    params: [f32; 4],
+}
+
+#[derive(Debug, Copy, Clone)]
+struct Resident {
+    slot: u32,
+    // Frame of the last feedback that needed the page
+    last_used: u64,
+}
+
+type MapResult = Arc<Mutex<Option<Result<(), wgpu::BufferAsyncError>>>>;
+
+enum Readback {
+    Idle,
+    Pending,
+    Mapping(MapResult),
+}
+
+// Low resolution target the feedback pass renders page ids into
+struct FeedbackTarget {
+    texture: TrackedTexture,
+    view: wgpu::TextureView,
+    depth: Texture,
+    readback: TrackedBuffer,
+    width: u32,
+    height: u32,
+    padded_bytes_per_row: u32,
+}
+
+/// Square terrain plane textured from a virtual texture far larger than
+/// would fit on the GPU. Only the pages the camera needs are resident:
+///
+/// - a feedback pass renders, at a fraction of the screen resolution, which
+///   page and mip each pixel samples, and is read back asynchronously
+/// - missing pages are read from the `PageSource` on a worker thread, coarse
+///   mips first, and copied into a fixed size atlas, evicting the least
+///   recently needed pages when it's full
+/// - a page table with one texel per page and mip points at the atlas page to
+///   sample, the nearest resident ancestor for pages that aren't there yet.
+///   The coarsest mip is a single page that is never evicted, so every
+///   texel has a fallback
+pub struct VirtualTerrain {
+    pub origin: Point3<f32>,
+    /// Side of the plane in world units.
+    pub extent: f32,
+    settings: VirtualTextureSettings,
+    // Pages per side at mip 0
+    pages: u32,
+    mip_count: u32,
+    page_table: TrackedTexture,
+    page_table_dirty: bool,
+    atlas: TrackedTexture,
+    uniform_buffer: TrackedBuffer,
+    bind_group: wgpu::BindGroup,
+    pipeline: Arc<wgpu::RenderPipeline>,
+    feedback_pipeline: Arc<wgpu::RenderPipeline>,
+    feedback: Option<FeedbackTarget>,
+    readback: Readback,
+    frame: u64,
+    // Frame of the last feedback read back
+    feedback_frame: u64,
+    resident: HashMap<PageKey, Resident>,
+    free_slots: Vec<u32>,
+    requested: HashSet<PageKey>,
+    // `None` if the worker couldn't be started, only the root page shows then
+    requests: Option<Sender<PageKey>>,
+    loaded: Receiver<(PageKey, Vec<u8>)>,
+}
+
+impl VirtualTerrain {
+    #[allow(clippy::too_many_arguments)]
+    pub fn new(
+        device: &wgpu::Device,
+        memory: &MemoryTracker,
+        queue: &wgpu::Queue,
+        pipelines: &mut PipelineCache,
+        shaders: &ShaderPreprocessor,
+        camera_bind_group_layout: &wgpu::BindGroupLayout,
+        depth_format: wgpu::TextureFormat,
+        multisample: wgpu::MultisampleState,
+        source: Box<dyn PageSource>,
+        settings: VirtualTextureSettings,
+    ) -> Result<Self> {
+        let size = source.size();
+        if !size.is_power_of_two() || size < PAGE_SIZE {
+            bail!("Virtual texture side {} isn't a power of two multiple of {}", size, PAGE_SIZE);
+        }
+        let pages = size / PAGE_SIZE;
+        if pages > MAX_PAGES {
+            bail!("Virtual textures are limited to {} pages per side, got {}", MAX_PAGES, pages);
+        }
+        if settings.atlas_pages < 2 || settings.atlas_pages > 255 {
+            bail!("The page atlas needs between 2 and 255 pages per side, got {}", settings.atlas_pages);
+        }
+        let mip_count = pages.trailing_zeros() + 1;
+
+        let page_table = memory.create_texture(
+            device,
+            &wgpu::TextureDescriptor {
+                label: Some("Virtual Page Table"),
+                size: wgpu::Extent3d {
+                    width: pages,
+                    height: pages,
+                    depth_or_array_layers: 1,
+                },
+                mip_level_count: mip_count,
+                sample_count: 1,
+                dimension: wgpu::TextureDimension::D2,
+                format: wgpu::TextureFormat::Rgba8Uint,
+                usage: wgpu::TextureUsages::TEXTURE_BINDING | wgpu::TextureUsages::COPY_DST,
+            },
+            MemoryCategory::Texture,
+        );
+        let atlas_side = settings.atlas_pages * PHYSICAL_PAGE_SIZE;
+        let atlas = memory.create_texture(
+            device,
+            &wgpu::TextureDescriptor {
+                label: Some("Virtual Page Atlas"),
+                size: wgpu::Extent3d {
+                    width: atlas_side,
+                    height: atlas_side,
+                    depth_or_array_layers: 1,
+                },
+                mip_level_count: 1,
+                sample_count: 1,
+                dimension: wgpu::TextureDimension::D2,
+                format: wgpu::TextureFormat::Rgba8UnormSrgb,
+                usage: wgpu::TextureUsages::TEXTURE_BINDING | wgpu::TextureUsages::COPY_DST,
+            },
+            MemoryCategory::Texture,
+        );
+        let sampler = device.create_sampler(&wgpu::SamplerDescriptor {
+            label: Some("Virtual Page Atlas Sampler"),
+            address_mode_u: wgpu::AddressMode::ClampToEdge,
+            address_mode_v: wgpu::AddressMode::ClampToEdge,
+            mag_filter: wgpu::FilterMode::Linear,
+            min_filter: wgpu::FilterMode::Linear,
+            ..Default::default()
+        });
+        let uniform = TerrainUniform {
+            origin_extent: [0.0; 4],
+            params: [
+                pages as f32,
+                (mip_count - 1) as f32,
+                (settings.feedback_divisor.max(1) as f32).log2(),
+                atlas_side as f32,
+            ],
+        };
+        let uniform_buffer = memory.create_buffer_init(
+            device,
+            &wgpu::util::BufferInitDescriptor {
+                label: Some("Virtual Terrain Uniform Buffer"),
+                contents: bytemuck::cast_slice(&[uniform]),
+                usage: wgpu::BufferUsages::UNIFORM | wgpu::BufferUsages::COPY_DST,
+            },
+            MemoryCategory::Uniform,
+        );
+
+        let bind_group_layout = device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
+            label: Some("Virtual Terrain Bind Group Layout"),
+            entries: &[
+                wgpu::BindGroupLayoutEntry {
+                    binding: 0,
+                    visibility: wgpu::ShaderStages::VERTEX | wgpu::ShaderStages::FRAGMENT,
+                    ty: wgpu::BindingType::Buffer {
+                        ty: wgpu::BufferBindingType::Uniform,
+                        has_dynamic_offset: false,
+                        min_binding_size: None,
+                    },
+                    count: None,
+                },
+                wgpu::BindGroupLayoutEntry {
+                    binding: 1,
+                    visibility: wgpu::ShaderStages::FRAGMENT,
+                    ty: wgpu::BindingType::Texture {
+                        multisampled: false,
+                        view_dimension: wgpu::TextureViewDimension::D2,
+                        sample_type: wgpu::TextureSampleType::Uint,
+                    },
+                    count: None,
+                },
+                wgpu::BindGroupLayoutEntry {
+                    binding: 2,
+                    visibility: wgpu::ShaderStages::FRAGMENT,
+                    ty: wgpu::BindingType::Texture {
+                        multisampled: false,
+                        view_dimension: wgpu::TextureViewDimension::D2,
+                        sample_type: wgpu::TextureSampleType::Float { filterable: true },
+                    },
+                    count: None,
+                },
+                wgpu::BindGroupLayoutEntry {
+                    binding: 3,
+                    visibility: wgpu::ShaderStages::FRAGMENT,
+                    ty: wgpu::BindingType::Sampler(wgpu::SamplerBindingType::Filtering),
+                    count: None,
+                },
+            ],
+        });
+        let page_table_view = page_table.create_view(&wgpu::TextureViewDescriptor::default());
+        let atlas_view = atlas.create_view(&wgpu::TextureViewDescriptor::default());
+        let bind_group = device.create_bind_group(&wgpu::BindGroupDescriptor {
+            label: Some("Virtual Terrain Bind Group"),
+            layout: &bind_group_layout,
+            entries: &[
+                wgpu::BindGroupEntry {
+                    binding: 0,
+                    resource: uniform_buffer.as_entire_binding(),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 1,
+                    resource: wgpu::BindingResource::TextureView(&page_table_view),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 2,
+                    resource: wgpu::BindingResource::TextureView(&atlas_view),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 3,
+                    resource: wgpu::BindingResource::Sampler(&sampler),
+                },
+            ],
+        });
+
+        let mut shaders = shaders.clone();
+        shaders.add_file("virtual_texture.wgsl", include_str!("virtual_texture.wgsl"));
+        shaders.set_constant("PAGE_SIZE", PAGE_SIZE);
+        shaders.set_constant("PAGE_BORDER", PAGE_BORDER);
+        let shader = shaders.process("virtual_texture.wgsl")?;
+        shaders.enable("FEEDBACK");
+        let feedback_shader = shaders.process("virtual_texture.wgsl")?;
+        let pipeline = pipelines.pipeline(&PipelineDescriptor {
+            label: "Virtual Terrain Pipeline",
+            layout: "Virtual Terrain Pipeline Layout",
+            bind_group_layouts: &[camera_bind_group_layout, &bind_group_layout],
+            shader: &shader,
+            vertex_layouts: &[],
+            color_format: Some(PostProcess::SCENE_FORMAT),
+            depth_format: Some(depth_format),
+            blend: wgpu::BlendState::REPLACE,
+            cull_mode: None,
+            multisample,
+            order_independent: false,
+        });
+        let feedback_pipeline = pipelines.pipeline(&PipelineDescriptor {
+            label: "Virtual Terrain Feedback Pipeline",
+            layout: "Virtual Terrain Pipeline Layout",
+            bind_group_layouts: &[camera_bind_group_layout, &bind_group_layout],
+            shader: &feedback_shader,
+            vertex_layouts: &[],
+            color_format: Some(FEEDBACK_FORMAT),
+            depth_format: Some(FEEDBACK_DEPTH_FORMAT),
+            blend: wgpu::BlendState::REPLACE,
+            cull_mode: None,
+            multisample: wgpu::MultisampleState::default(),
+            order_independent: false,
+        });
+
+        // The root page is read up front, so there's always something to sample
+        let root = PageKey {
+            mip: mip_count - 1,
+            x: 0,
+            y: 0,
+        };
+        let root_texels = read_page(source.as_ref(), root);
+
+        let (requests, worker_requests) = mpsc::channel::<PageKey>();
+        let (worker_loaded, loaded) = mpsc::channel();
+        let worker = std::thread::Builder::new()
+            .name("virtual texture pages".into())
+            .spawn(move || {
+                for key in worker_requests {
+                    if worker_loaded.send((key, read_page(source.as_ref(), key))).is_err() {
+                        return;
+                    }
+                }
+            });
+        let requests = match worker {
+            Result::Ok(_) => Some(requests),
+            Err(e) => {
+                log::error!("Failed to start the virtual texture page worker: {}", e);
+                None
+            }
+        };
+
+        let slot_count = settings.atlas_pages * settings.atlas_pages;
+        let mut terrain = Self {
+            origin: Point3::new(-50.0, 0.0, -50.0),
+            extent: 100.0,
+            settings,
+            pages,
+            mip_count,
+            page_table,
+            page_table_dirty: true,
+            atlas,
+            uniform_buffer,
+            bind_group,
+            pipeline,
+            feedback_pipeline,
+            feedback: None,
+            readback: Readback::Idle,
+            frame: 0,
+            feedback_frame: 0,
+            resident: HashMap::new(),
+            // Popped from the back, so pages fill the atlas from the start
+            free_slots: (0..slot_count).rev().collect(),
+            requested: HashSet::new(),
+            requests,
+            loaded,
+        };
+        terrain.upload(queue, root, &root_texels);
+        return Ok(terrain);
+    }
+
+    pub fn settings(&self) -> VirtualTextureSettings {
+        return self.settings;
+    }
+
+    /// Pages in the atlas and pages waiting for the worker.
+    pub fn page_counts(&self) -> (usize, usize) {
+        return (self.resident.len(), self.requested.len());
+    }
+
+    /// Pick up the last feedback, request the pages it's missing and copy
+    /// finished pages into the atlas. `size` is the scene resolution the
+    /// feedback is a fraction of.
+    pub fn update(&mut self, device: &wgpu::Device, memory: &MemoryTracker, queue: &wgpu::Queue, size: [u32; 2]) {
+        self.frame += 1;
+        if matches!(self.readback, Readback::Mapping(_)) {
+            device.poll(wgpu::Maintain::Poll);
+            self.read_feedback();
+        }
+
+        let divisor = self.settings.feedback_divisor.max(1);
+        let width = (size[0] / divisor).max(1);
+        let height = (size[1] / divisor).max(1);
+        let resize = match &self.feedback {
+            Some(feedback) => feedback.width != width || feedback.height != height,
+            None => true,
+        };
+        // A readback in flight would be read with the new target's size
+        if resize && matches!(self.readback, Readback::Idle) {
+            self.feedback = Some(Self::create_feedback(device, memory, width, height));
+        }
+
+        for _ in 0..self.settings.uploads_per_frame {
+            let (key, texels) = match self.loaded.try_recv() {
+                Result::Ok(page) => page,
+                Err(_) => break,
+            };
+            self.requested.remove(&key);
+            self.upload(queue, key, &texels);
+        }
+        if self.page_table_dirty {
+            self.write_page_table(queue);
+            self.page_table_dirty = false;
+        }
+
+        let uniform = TerrainUniform {
+            origin_extent: [self.origin.x, self.origin.y, self.origin.z, self.extent],
+            params: [
+                self.pages as f32,
+                (self.mip_count - 1) as f32,
+                (self.settings.feedback_divisor.max(1) as f32).log2(),
+                (self.settings.atlas_pages * PHYSICAL_PAGE_SIZE) as f32,
+            ],
+        };
+        queue.write_buffer(&self.uniform_buffer, 0, bytemuck::cast_slice(&[uniform]));
+    }
+
+    /// Render the pages the camera needs into the feedback target and copy
+    /// them for reading back, unless the previous readback is still going.
+    pub fn encode_feedback(&mut self, encoder: &mut wgpu::CommandEncoder, camera_bind_group: &wgpu::BindGroup) {
+        let feedback = match (&self.feedback, &self.readback) {
+            (Some(feedback), Readback::Idle) => feedback,
+            _ => return,
+        };
+        {
+            let mut render_pass = encoder.begin_render_pass(&wgpu::RenderPassDescriptor {
+                label: Some("Virtual Texture Feedback Pass"),
+                color_attachments: &[Some(wgpu::RenderPassColorAttachment {
+                    view: &feedback.view,
+                    resolve_target: None,
+                    ops: wgpu::Operations {
+                        load: wgpu::LoadOp::Clear(wgpu::Color::TRANSPARENT),
+                        store: true,
+                    },
+                })],
+                depth_stencil_attachment: Some(wgpu::RenderPassDepthStencilAttachment {
+                    view: &feedback.depth.view,
+                    depth_ops: Some(wgpu::Operations {
+                        load: wgpu::LoadOp::Clear(1.0),
+                        store: false,
+                    }),
+                    stencil_ops: None,
+                }),
+            });
+            render_pass.set_pipeline(&self.feedback_pipeline);
+            render_pass.set_bind_group(0, camera_bind_group, &[]);
+            render_pass.set_bind_group(1, &self.bind_group, &[]);
+            render_pass.draw(0..6, 0..1);
+        }
+        encoder.copy_texture_to_buffer(
+            wgpu::ImageCopyTexture {
+                texture: &feedback.texture,
+                mip_level: 0,
+                origin: wgpu::Origin3d::ZERO,
+                aspect: wgpu::TextureAspect::All,
+            },
+            wgpu::ImageCopyBuffer {
+                buffer: &feedback.readback,
+                layout: wgpu::ImageDataLayout {
+                    offset: 0,
+                    bytes_per_row: std::num::NonZeroU32::new(feedback.padded_bytes_per_row),
+                    rows_per_image: None,
+                },
+            },
+            wgpu::Extent3d {
+                width: feedback.width,
+                height: feedback.height,
+                depth_or_array_layers: 1,
+            },
+        );
+        self.readback = Readback::Pending;
+    }
+
+    /// Start mapping the feedback, once the encoder from `encode_feedback`
+    /// was submitted.
+    pub fn map_feedback(&mut self) {
+        if let (Readback::Pending, Some(feedback)) = (&self.readback, &self.feedback) {
+            let result = Arc::new(Mutex::new(None));
+            let sender = result.clone();
+            feedback.readback.slice(..).map_async(wgpu::MapMode::Read, move |r| {
+                *sender.lock().unwrap() = Some(r);
+            });
+            self.readback = Readback::Mapping(result);
+        }
+    }
+
+    pub fn render<'a>(&'a self, render_pass: &mut wgpu::RenderPass<'a>, camera_bind_group: &'a wgpu::BindGroup) {
+        render_pass.set_pipeline(&self.pipeline);
+        render_pass.set_bind_group(0, camera_bind_group, &[]);
+        render_pass.set_bind_group(1, &self.bind_group, &[]);
+        render_pass.draw(0..6, 0..1);
+    }
+
+    fn create_feedback(device: &wgpu::Device, memory: &MemoryTracker, width: u32, height: u32) -> FeedbackTarget {
+        let texture = memory.create_texture(
+            device,
+            &wgpu::TextureDescriptor {
+                label: Some("Virtual Texture Feedback"),
+                size: wgpu::Extent3d {
+                    width,
+                    height,
+                    depth_or_array_layers: 1,
+                },
+                mip_level_count: 1,
+                sample_count: 1,
+                dimension: wgpu::TextureDimension::D2,
+                format: FEEDBACK_FORMAT,
+                usage: wgpu::TextureUsages::RENDER_ATTACHMENT | wgpu::TextureUsages::COPY_SRC,
+            },
+            MemoryCategory::Texture,
+        );
+        let view = texture.create_view(&wgpu::TextureViewDescriptor::default());
+        let depth = Texture::create_render_target(
+            device,
+            memory,
+            width,
+            height,
+            FEEDBACK_DEPTH_FORMAT,
+            1,
+            "Virtual Texture Feedback Depth",
+        );
+        // Rows of a texture copy have to be aligned
+        let align = wgpu::COPY_BYTES_PER_ROW_ALIGNMENT;
+        let padded_bytes_per_row = (width * 4).div_ceil(align) * align;
+        let readback = memory.create_buffer(
+            device,
+            &wgpu::BufferDescriptor {
+                label: Some("Virtual Texture Feedback Readback"),
+                size: (padded_bytes_per_row * height) as wgpu::BufferAddress,
+                usage: wgpu::BufferUsages::COPY_DST | wgpu::BufferUsages::MAP_READ,
+                mapped_at_creation: false,
+            },
+            MemoryCategory::Other,
+        );
+        return FeedbackTarget {
+            texture,
+            view,
+            depth,
+            readback,
+            width,
+            height,
+            padded_bytes_per_row,
+        };
+    }
+
+    // Mark the pages a finished readback saw as used and request the missing ones
+    fn read_feedback(&mut self) {
+        let mapped = match &self.readback {
+            Readback::Mapping(result) => match result.lock().unwrap().take() {
+                Some(mapped) => mapped,
+                None => return,
+            },
+            _ => return,
+        };
+        self.readback = Readback::Idle;
+        let feedback = match &self.feedback {
+            Some(feedback) => feedback,
+            None => return,
+        };
+        if let Err(e) = mapped {
+            log::warn!("Failed to read back the virtual texture feedback: {:?}", e);
+            return;
+        }
+
+        let mut needed = HashSet::new();
+        {
+            let data = feedback.readback.slice(..).get_mapped_range();
+            for row in data.chunks_exact(feedback.padded_bytes_per_row as usize) {
+                let row = &row[..(feedback.width * 4) as usize];
+                for texel in row.chunks_exact(4) {
+                    let value = u32::from_ne_bytes([texel[0], texel[1], texel[2], texel[3]]);
+                    needed.extend(PageKey::unpack(value).filter(|key| self.is_valid(*key)));
+                }
+            }
+        }
+        feedback.readback.unmap();
+
+        self.feedback_frame = self.frame;
+        let mut missing = Vec::new();
+        for key in needed {
+            // Fallbacks drawn in place of the page count as used too
+            let mut ancestor = key;
+            loop {
+                if let Some(resident) = self.resident.get_mut(&ancestor) {
+                    resident.last_used = self.frame;
+                }
+                if ancestor.mip + 1 >= self.mip_count {
+                    break;
+                }
+                ancestor = ancestor.parent();
+            }
+            if !self.resident.contains_key(&key) && !self.requested.contains(&key) {
+                missing.push(key);
+            }
+        }
+
+        let requests = match &self.requests {
+            Some(requests) => requests,
+            None => return,
+        };
+        // Coarse pages first, they cover more of the screen. Pages that
+        // wouldn't find a slot without evicting ones in view aren't read.
+        missing.sort_by_key(|key| Reverse(key.mip));
+        let root_mip = self.mip_count - 1;
+        let evictable = self
+            .resident
+            .iter()
+            .filter(|(key, resident)| key.mip != root_mip && resident.last_used < self.frame)
+            .count();
+        let capacity = MAX_REQUESTS_IN_FLIGHT
+            .min(self.free_slots.len() + evictable)
+            .saturating_sub(self.requested.len());
+        for key in missing.into_iter().take(capacity) {
+            if requests.send(key).is_err() {
+                log::error!("The virtual texture page worker stopped");
+                self.requests = None;
+                return;
+            }
+            self.requested.insert(key);
+        }
+    }
+
+    fn is_valid(&self, key: PageKey) -> bool {
+        let side = self.pages >> key.mip.min(31);
+        return key.mip < self.mip_count && key.x < side && key.y < side;
+    }
+
+    // Copy a page into a free atlas slot and mark it resident
+    fn upload(&mut self, queue: &wgpu::Queue, key: PageKey, texels: &[u8]) {
+        let slot = match self.allocate_slot() {
+            Some(slot) => slot,
+            // Everything is in use, the page is requested again by a later feedback
+            None => return,
+        };
+        queue.write_texture(
+            wgpu::ImageCopyTexture {
+                texture: &self.atlas,
+                mip_level: 0,
+                origin: wgpu::Origin3d {
+                    x: slot % self.settings.atlas_pages * PHYSICAL_PAGE_SIZE,
+                    y: slot / self.settings.atlas_pages * PHYSICAL_PAGE_SIZE,
+                    z: 0,
+                },
+                aspect: wgpu::TextureAspect::All,
+            },
+            texels,
+            wgpu::ImageDataLayout {
+                offset: 0,
+                bytes_per_row: std::num::NonZeroU32::new(PHYSICAL_PAGE_SIZE * 4),
+                rows_per_image: None,
+            },
+            wgpu::Extent3d {
+                width: PHYSICAL_PAGE_SIZE,
+                height: PHYSICAL_PAGE_SIZE,
+                depth_or_array_layers: 1,
+            },
+        );
+        self.resident.insert(
+            key,
+            Resident {
+                slot,
+                last_used: self.frame,
+            },
+        );
+        self.page_table_dirty = true;
+    }
+
+    // A free slot, or the slot of the least recently needed page that the
+    // last feedback didn't ask for. The root page is never evicted.
+    fn allocate_slot(&mut self) -> Option<u32> {
+        if let Some(slot) = self.free_slots.pop() {
+            return Some(slot);
+        }
+        let root_mip = self.mip_count - 1;
+        let (key, resident) = self
+            .resident
+            .iter()
+            .filter(|(key, resident)| key.mip != root_mip && resident.last_used < self.feedback_frame)
+            .min_by_key(|(_, resident)| resident.last_used)
+            .map(|(key, resident)| (*key, *resident))?;
+        self.resident.remove(&key);
+        self.page_table_dirty = true;
+        return Some(resident.slot);
+    }
+
+    // Rebuild the page table from the coarsest mip down, pages that aren't
+    // resident pointing wherever their parent points
+    fn write_page_table(&self, queue: &wgpu::Queue) {
+        let atlas_pages = self.settings.atlas_pages;
+        let mut parent_level: Vec<[u8; 4]> = Vec::new();
+        for mip in (0..self.mip_count).rev() {
+            let side = self.pages >> mip;
+            let mut level = Vec::with_capacity((side * side) as usize);
+            for y in 0..side {
+                for x in 0..side {
+                    let entry = match self.resident.get(&PageKey { mip, x, y }) {
+                        Some(resident) => [
+                            (resident.slot % atlas_pages) as u8,
+                            (resident.slot / atlas_pages) as u8,
+                            mip as u8,
+                            255,
+                        ],
+                        None if parent_level.is_empty() => [0, 0, mip as u8, 0],
+                        None => parent_level[((y / 2) * (side / 2) + x / 2) as usize],
+                    };
+                    level.push(entry);
+                }
+            }
+            queue.write_texture(
+                wgpu::ImageCopyTexture {
+                    texture: &self.page_table,
+                    mip_level: mip,
+                    origin: wgpu::Origin3d::ZERO,
+                    aspect: wgpu::TextureAspect::All,
+                },
+                bytemuck::cast_slice(&level),
+                wgpu::ImageDataLayout {
+                    offset: 0,
+                    bytes_per_row: std::num::NonZeroU32::new(side * 4),
+                    rows_per_image: None,
+                },
+                wgpu::Extent3d {
+                    width: side,
+                    height: side,
+                    depth_or_array_layers: 1,
+                },
+            );
+            parent_level = level;
+        }
+    }
+}
+
+// Texels of a page with its border
+fn read_page(source: &dyn PageSource, key: PageKey) -> Vec<u8> {
+    let origin = [
+        (key.x * PAGE_SIZE) as i32 - PAGE_BORDER as i32,
+        (key.y * PAGE_SIZE) as i32 - PAGE_BORDER as i32,
+    ];
+    return source.read(key.mip, origin, PHYSICAL_PAGE_SIZE);
+}
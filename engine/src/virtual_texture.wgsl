@@ -0,0 +1,95 @@
+// Terrain plane textured through a virtual texture, see virtual_texture.rs.
+// With FEEDBACK defined, writes the page each fragment needs instead of a color.
+#include "camera.wgsl"
+@group(0) @binding(0)
+var<uniform> camera: Camera;
+
+// Texels per side of a page and of the border around it in the atlas
+override PAGE_SIZE: u32 = 128u;
+override PAGE_BORDER: u32 = 4u;
+
+struct Terrain {
+    // xyz: corner with the smallest coordinates, w: side length
+    origin_extent: vec4<f32>,
+    // x: pages per side at mip 0, y: coarsest mip, z: log2 of the feedback
+    // resolution divisor, w: atlas side in texels
+    params: vec4<f32>,
+};
+@group(1) @binding(0)
+var<uniform> terrain: Terrain;
+// Per page and mip: atlas page x and y, mip of the resident page (the page
+// itself or its nearest resident ancestor)
+@group(1) @binding(1)
+var page_table: texture_2d<u32>;
+@group(1) @binding(2)
+var atlas: texture_2d<f32>;
+@group(1) @binding(3)
+var atlas_sampler: sampler;
+
+struct VertexOutput {
+    @builtin(position) clip_position: vec4<f32>,
+    @location(0) uv: vec2<f32>,
+};
+
+@vertex
+fn vs_main(@builtin(vertex_index) index: u32) -> VertexOutput {
+    var corners = array<vec2<f32>, 6>(
+        vec2<f32>(0.0, 0.0),
+        vec2<f32>(0.0, 1.0),
+        vec2<f32>(1.0, 1.0),
+        vec2<f32>(0.0, 0.0),
+        vec2<f32>(1.0, 1.0),
+        vec2<f32>(1.0, 0.0),
+    );
+    let uv = corners[index];
+    let world_position = terrain.origin_extent.xyz + vec3<f32>(uv.x, 0.0, uv.y) * terrain.origin_extent.w;
+
+    var out: VertexOutput;
+    out.clip_position = camera.view_proj * vec4<f32>(world_position, 1.0);
+    out.uv = uv;
+    return out;
+}
+
+// Mip for the screen space derivatives of the uv, unclamped. Derivatives are
+// taken by the caller, helpers also end up in the vertex stage on GL.
+fn virtual_lod(uv_dx: vec2<f32>, uv_dy: vec2<f32>) -> f32 {
+    let texels = terrain.params.x * f32(PAGE_SIZE);
+    let dx = uv_dx * texels;
+    let dy = uv_dy * texels;
+    return 0.5 * log2(max(dot(dx, dx), dot(dy, dy)));
+}
+
+fn virtual_page(uv: vec2<f32>, mip: u32) -> vec2<u32> {
+    let side = max(u32(terrain.params.x) >> mip, 1u);
+    return min(vec2<u32>(uv * f32(side)), vec2<u32>(side - 1u));
+}
+
+#ifdef FEEDBACK
+@fragment
+fn fs_main(in: VertexOutput) -> @location(0) vec4<u32> {
+    // Derivatives are larger on the smaller feedback target
+    let lod = virtual_lod(dpdx(in.uv), dpdy(in.uv)) - terrain.params.z;
+    let mip = u32(clamp(lod, 0.0, terrain.params.y));
+    let page = virtual_page(clamp(in.uv, vec2<f32>(0.0), vec2<f32>(1.0)), mip);
+    // Zero is left for fragments without terrain
+    return vec4<u32>(((mip + 1u) << 24u) | (page.y << 12u) | page.x, 0u, 0u, 0u);
+}
+#else
+@fragment
+fn fs_main(in: VertexOutput) -> @location(0) vec4<f32> {
+    let lod = virtual_lod(dpdx(in.uv), dpdy(in.uv));
+    // Just below one, so the last page's fraction doesn't wrap to zero
+    let uv = clamp(in.uv, vec2<f32>(0.0), vec2<f32>(0.999999));
+    let mip = u32(clamp(lod, 0.0, terrain.params.y));
+    let entry = textureLoad(page_table, vec2<i32>(virtual_page(uv, mip)), i32(mip));
+
+    // Position inside the resident page, which may be a coarser fallback
+    let resident_pages = f32(max(u32(terrain.params.x) >> entry.b, 1u));
+    let in_page = fract(uv * resident_pages);
+    let physical_size = f32(PAGE_SIZE + 2u * PAGE_BORDER);
+    let texel = vec2<f32>(entry.rg) * physical_size + f32(PAGE_BORDER) + in_page * f32(PAGE_SIZE);
+    // Borders hold the neighbouring texels, so bilinear filtering stays inside the page
+    let color = textureSampleLevel(atlas, atlas_sampler, texel / terrain.params.w, 0.0);
+    return vec4<f32>(color.rgb, 1.0);
+}
+#endif
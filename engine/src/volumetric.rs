@@ -0,0 +1,260 @@
+use std::sync::Arc;
+
+use crate::{
+    light::{LightBufferManager, LightKind},
+    memory::{MemoryCategory, MemoryTracker, TrackedBuffer},
+    pipelines::{PipelineCache, PipelineDescriptor},
+    post::PostProcess,
+    shader::ShaderPreprocessor,
+    texture::Texture,
+};
+
+/// Lights past this many with `VolumetricSettings` scatter no light.
+pub const MAX_VOLUMETRIC_LIGHTS: usize = 4;
+
+/// Light a directional or spot light scatters in the air it passes through.
+/// Shafts appear where the light's shadow map blocks it, so the light needs
+/// shadow settings for them.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct VolumetricSettings {
+    /// Scattering per world unit, 0 turns the light's scattering off.
+    pub density: f32,
+    /// Henyey-Greenstein asymmetry from -1 to 1; positive values scatter
+    /// forward, brightening the air when looking towards the light.
+    pub anisotropy: f32,
+}
+
+impl Default for VolumetricSettings {
+    fn default() -> Self {
+        Self {
+            density: 0.02,
+            anisotropy: 0.3,
+        }
+    }
+}
+
+#[repr(C)]
+#[derive(Debug, Copy, Clone, Default, bytemuck::Pod, bytemuck::Zeroable)]
+struct VolumetricLightUniform {
+    light: [i32; 4],
+    params: [f32; 4],
+}
+
+#[repr(C)]
+#[derive(Debug, Copy, Clone, Default, bytemuck::Pod, bytemuck::Zeroable)]
+struct VolumetricUniform {
+    lights: [VolumetricLightUniform; MAX_VOLUMETRIC_LIGHTS],
+    counts: [u32; 4],
+    params: [f32; 4],
+}
+
+/// Light shafts: the light of directional and spot lights with
+/// `VolumetricSettings` scattered towards the eye, marched through their
+/// shadow maps from the eye to the scene depth and added onto the scene
+/// color after the main pass.
+pub struct VolumetricLighting {
+    /// Samples along each pixel's view ray.
+    pub steps: u32,
+    /// Distance from the eye past which nothing scatters, e.g. for the sky.
+    pub max_distance: f32,
+    light_count: u32,
+    uniform_buffer: TrackedBuffer,
+    bind_group_layout: wgpu::BindGroupLayout,
+    bind_group: wgpu::BindGroup,
+    pipeline: Arc<wgpu::RenderPipeline>,
+}
+
+impl VolumetricLighting {
+    #[allow(clippy::too_many_arguments)]
+    pub fn new(
+        device: &wgpu::Device,
+        memory: &MemoryTracker,
+        pipelines: &mut PipelineCache,
+        shaders: &ShaderPreprocessor,
+        camera_bind_group_layout: &wgpu::BindGroupLayout,
+        light_bind_group_layout: &wgpu::BindGroupLayout,
+        depth_texture: &Texture,
+        sample_count: u32,
+    ) -> Self {
+        let uniform_buffer = memory.create_buffer_init(
+            device,
+            &wgpu::util::BufferInitDescriptor {
+                label: Some("Volumetric Buffer"),
+                contents: bytemuck::cast_slice(&[VolumetricUniform::default()]),
+                usage: wgpu::BufferUsages::UNIFORM | wgpu::BufferUsages::COPY_DST,
+            },
+            MemoryCategory::Uniform,
+        );
+
+        let bind_group_layout = device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
+            entries: &[
+                wgpu::BindGroupLayoutEntry {
+                    binding: 0,
+                    visibility: wgpu::ShaderStages::FRAGMENT,
+                    ty: wgpu::BindingType::Buffer {
+                        ty: wgpu::BufferBindingType::Uniform,
+                        has_dynamic_offset: false,
+                        min_binding_size: None,
+                    },
+                    count: None,
+                },
+                wgpu::BindGroupLayoutEntry {
+                    binding: 1,
+                    visibility: wgpu::ShaderStages::FRAGMENT,
+                    ty: wgpu::BindingType::Texture {
+                        sample_type: wgpu::TextureSampleType::Float { filterable: false },
+                        view_dimension: wgpu::TextureViewDimension::D2,
+                        multisampled: sample_count > 1,
+                    },
+                    count: None,
+                },
+            ],
+            label: Some("volumetric_bind_group_layout"),
+        });
+        let bind_group = Self::create_bind_group(device, &bind_group_layout, &uniform_buffer, depth_texture);
+
+        let pipeline = {
+            let mut shaders = shaders.clone();
+            shaders.set_constant("MAX_VOLUMETRIC_LIGHTS", MAX_VOLUMETRIC_LIGHTS);
+            if sample_count > 1 {
+                shaders.enable("MULTISAMPLED_DEPTH");
+            }
+            let shader = shaders
+                .process("volumetric.wgsl")
+                .expect("Failed to preprocess volumetric.wgsl");
+            pipelines.pipeline(&PipelineDescriptor {
+                label: "Volumetric Pipeline",
+                layout: "Volumetric Pipeline Layout",
+                bind_group_layouts: &[camera_bind_group_layout, &bind_group_layout, light_bind_group_layout],
+                shader: &shader,
+                vertex_layouts: &[],
+                color_format: Some(PostProcess::SCENE_FORMAT),
+                depth_format: None,
+                // Added onto the scene color, its alpha is kept
+                blend: wgpu::BlendState {
+                    color: wgpu::BlendComponent {
+                        src_factor: wgpu::BlendFactor::One,
+                        dst_factor: wgpu::BlendFactor::One,
+                        operation: wgpu::BlendOperation::Add,
+                    },
+                    alpha: wgpu::BlendComponent {
+                        src_factor: wgpu::BlendFactor::Zero,
+                        dst_factor: wgpu::BlendFactor::One,
+                        operation: wgpu::BlendOperation::Add,
+                    },
+                },
+                cull_mode: Some(wgpu::Face::Back),
+                multisample: wgpu::MultisampleState::default(),
+                order_independent: false,
+            })
+        };
+
+        return Self {
+            steps: 32,
+            max_distance: 100.0,
+            light_count: 0,
+            uniform_buffer,
+            bind_group_layout,
+            bind_group,
+            pipeline,
+        };
+    }
+
+    fn create_bind_group(
+        device: &wgpu::Device,
+        layout: &wgpu::BindGroupLayout,
+        uniform_buffer: &TrackedBuffer,
+        depth_texture: &Texture,
+    ) -> wgpu::BindGroup {
+        // Stencil formats can only be sampled one aspect at a time
+        let depth_view = depth_texture.texture.create_view(&wgpu::TextureViewDescriptor {
+            aspect: wgpu::TextureAspect::DepthOnly,
+            ..Default::default()
+        });
+        return device.create_bind_group(&wgpu::BindGroupDescriptor {
+            layout,
+            entries: &[
+                wgpu::BindGroupEntry {
+                    binding: 0,
+                    resource: uniform_buffer.as_entire_binding(),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 1,
+                    resource: wgpu::BindingResource::TextureView(&depth_view),
+                },
+            ],
+            label: Some("volumetric_bind_group"),
+        });
+    }
+
+    /// Must be called whenever the depth texture is recreated.
+    pub fn set_depth_texture(&mut self, device: &wgpu::Device, depth_texture: &Texture) {
+        self.bind_group = Self::create_bind_group(
+            device,
+            &self.bind_group_layout,
+            &self.uniform_buffer,
+            depth_texture,
+        );
+    }
+
+    /// Whether any light scatters, otherwise the pass is skipped.
+    pub fn is_active(&self) -> bool {
+        return self.light_count > 0;
+    }
+
+    /// Pick up the scattering lights of `lights`, the first
+    /// `MAX_VOLUMETRIC_LIGHTS` of them.
+    pub fn update(&mut self, queue: &wgpu::Queue, lights: &LightBufferManager) {
+        let mut uniform = VolumetricUniform::default();
+        let mut count = 0;
+        for (id, settings) in lights.volumetric_lights() {
+            if count == MAX_VOLUMETRIC_LIGHTS {
+                log::debug!("{:?} is over the volumetric light budget", id);
+                continue;
+            }
+            let kind = match id.kind {
+                LightKind::Directional => 0,
+                _ => 1,
+            };
+            uniform.lights[count] = VolumetricLightUniform {
+                light: [kind, id.index as i32, 0, 0],
+                params: [settings.density, settings.anisotropy.clamp(-0.99, 0.99), 0.0, 0.0],
+            };
+            count += 1;
+        }
+        uniform.counts = [count as u32, self.steps.max(1), 0, 0];
+        uniform.params = [self.max_distance, 0.0, 0.0, 0.0];
+        self.light_count = count as u32;
+        queue.write_buffer(&self.uniform_buffer, 0, bytemuck::cast_slice(&[uniform]));
+    }
+
+    /// Add the scattered light onto `target`, the resolved scene color.
+    pub fn render(
+        &self,
+        encoder: &mut wgpu::CommandEncoder,
+        target: &wgpu::TextureView,
+        camera_bind_group: &wgpu::BindGroup,
+        light_bind_group: &wgpu::BindGroup,
+    ) {
+        if !self.is_active() {
+            return;
+        }
+        let mut render_pass = encoder.begin_render_pass(&wgpu::RenderPassDescriptor {
+            label: Some("Volumetric Pass"),
+            color_attachments: &[Some(wgpu::RenderPassColorAttachment {
+                view: target,
+                resolve_target: None,
+                ops: wgpu::Operations {
+                    load: wgpu::LoadOp::Load,
+                    store: true,
+                },
+            })],
+            depth_stencil_attachment: None,
+        });
+        render_pass.set_pipeline(&self.pipeline);
+        render_pass.set_bind_group(0, camera_bind_group, &[]);
+        render_pass.set_bind_group(1, &self.bind_group, &[]);
+        render_pass.set_bind_group(2, light_bind_group, &[]);
+        render_pass.draw(0..3, 0..1);
+    }
+}
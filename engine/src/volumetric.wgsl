@@ -0,0 +1,151 @@
+// Light scattered in the air towards the eye, marched from the eye to the
+// scene depth and added onto the resolved scene color. See volumetric.rs
+#include "camera.wgsl"
+#include "scene.wgsl"
+@group(0) @binding(0)
+var<uniform> camera: Camera;
+@group(0) @binding(1)
+var<uniform> scene: Scene;
+
+// Specialized with volumetric.rs's constant
+override MAX_VOLUMETRIC_LIGHTS: u32 = 4u;
+
+struct VolumetricLight {
+    // x: 0 directional, 1 spot, y: index into its light array
+    light: vec4<i32>,
+    // x: density per world unit, y: anisotropy
+    params: vec4<f32>,
+};
+struct Volumetric {
+    lights: array<VolumetricLight, MAX_VOLUMETRIC_LIGHTS>,
+    // x: light count, y: steps
+    counts: vec4<u32>,
+    // x: maximum distance from the eye
+    params: vec4<f32>,
+};
+@group(1) @binding(0)
+var<uniform> volumetric: Volumetric;
+// Depth bound as plain floats, the GL backend can't load from depth textures
+#ifdef MULTISAMPLED_DEPTH
+@group(1) @binding(1)
+var t_depth: texture_multisampled_2d<f32>;
+#else
+@group(1) @binding(1)
+var t_depth: texture_2d<f32>;
+#endif
+
+#include "lights.wgsl"
+@group(2) @binding(0)
+var<uniform> lights: LightBuffer;
+@group(2) @binding(6)
+var t_shadow: texture_depth_2d;
+@group(2) @binding(7)
+var s_shadow: sampler_comparison;
+
+struct VertexOutput {
+    @builtin(position) clip_position: vec4<f32>,
+    @location(0) ndc: vec2<f32>,
+};
+
+@vertex
+fn vs_main(@builtin(vertex_index) index: u32) -> VertexOutput {
+    // Fullscreen triangle
+    let uv = vec2<f32>(f32((index << 1u) & 2u), f32(index & 2u));
+    var out: VertexOutput;
+    out.ndc = uv * 2.0 - 1.0;
+    out.clip_position = vec4<f32>(out.ndc, 0.0, 1.0);
+    return out;
+}
+
+// Henyey-Greenstein, `cos_theta` between the view ray and the direction to
+// the light
+fn henyey_greenstein(cos_theta: f32, g: f32) -> f32 {
+    let g2 = g * g;
+    let denominator = pow(max(1.0 + g2 - 2.0 * g * cos_theta, 0.0001), 1.5);
+    return (1.0 - g2) / (4.0 * 3.14159265 * denominator);
+}
+
+// Single filtered tap of shadow map `index`, 1 outside of it
+fn shadow_visibility(index: i32, position: vec3<f32>) -> f32 {
+    if (index < 0) {
+        return 1.0;
+    }
+    let shadow = lights.shadows[index];
+    let clip = shadow.view_proj * vec4<f32>(position, 1.0);
+    if (clip.w <= 0.0) {
+        return 1.0;
+    }
+    let ndc = clip.xyz * (1.0 / clip.w);
+    if (any(abs(ndc.xy) > vec2<f32>(1.0)) || ndc.z > 1.0) {
+        return 1.0;
+    }
+    let uv = shadow.rect.xy + (ndc.xy * vec2<f32>(0.5, -0.5) + vec2<f32>(0.5)) * shadow.rect.zw;
+    return textureSampleCompareLevel(t_shadow, s_shadow, uv, ndc.z - shadow.params.x);
+}
+
+// Light arriving at a point from one of the volumetric lights
+struct Incoming {
+    radiance: vec3<f32>,
+    to_light: vec3<f32>,
+};
+
+fn incoming(i: i32, position: vec3<f32>) -> Incoming {
+    var out: Incoming;
+    let entry = volumetric.lights[i].light;
+    if (entry.x == 0) {
+        let light = lights.dirs[entry.y];
+        out.to_light = -normalize(light.direction);
+        out.radiance = light.color_strength.rgb * light.color_strength.w * shadow_visibility(light.shadow, position);
+        return out;
+    }
+    let light = lights.spots[entry.y];
+    let offset = light.base.position - position;
+    let distance = length(offset);
+    out.to_light = offset / max(distance, 0.0001);
+    out.radiance = vec3<f32>(0.0);
+    let spot_factor = dot(-out.to_light, normalize(light.direction_ccos.xyz));
+    if (distance >= light.base.range || spot_factor <= light.direction_ccos.w) {
+        return out;
+    }
+    // Same falloff as the surface lighting in basic.wgsl
+    let cone = 1.0 - (1.0 - spot_factor) / (1.0 - light.direction_ccos.w);
+    let attenuation = light.base.attenuation.x + light.base.attenuation.y * distance + light.base.attenuation.z * distance * distance;
+    let window = clamp(1.0 - pow(distance / light.base.range, 4.0), 0.0, 1.0);
+    let falloff = cone / max(attenuation, 0.0001) * window * window;
+    out.radiance = light.base.color * falloff * shadow_visibility(light.base.shadow, position);
+    return out;
+}
+
+// Per pixel offset of the first sample, trades banding for noise
+fn interleaved_gradient_noise(position: vec2<f32>) -> f32 {
+    return fract(52.9829189 * fract(dot(position, vec2<f32>(0.06711056, 0.00583715))));
+}
+
+@fragment
+fn fs_main(in: VertexOutput) -> @location(0) vec4<f32> {
+    let depth = textureLoad(t_depth, vec2<i32>(in.clip_position.xy), 0).r;
+    let far = camera.inv_view_proj * vec4<f32>(in.ndc, depth, 1.0);
+    let eye = camera.view_pos.xyz;
+    let to_surface = far.xyz / far.w - eye;
+    let ray_length = min(length(to_surface), volumetric.params.x);
+    let direction = normalize(to_surface);
+
+    let steps = max(volumetric.counts.y, 1u);
+    let dt = ray_length / f32(steps);
+    let jitter = interleaved_gradient_noise(in.clip_position.xy);
+    var result = vec3<f32>(0.0);
+    for (var i = 0; i < i32(volumetric.counts.x); i++) {
+        let density = volumetric.lights[i].params.x;
+        let anisotropy = volumetric.lights[i].params.y;
+        var scattered = vec3<f32>(0.0);
+        for (var s = 0u; s < steps; s++) {
+            let t = (f32(s) + jitter) * dt;
+            let light = incoming(i, eye + direction * t);
+            let phase = henyey_greenstein(dot(direction, light.to_light), anisotropy);
+            // Dimmed by the air between the sample and the eye
+            scattered += light.radiance * phase * exp(-density * t);
+        }
+        result += scattered * density * dt;
+    }
+    return vec4<f32>(result, 0.0);
+}
@@ -0,0 +1,124 @@
+//! Stereo rendering for XR headsets: per-eye projections and views, a
+//! head-driven camera pose and `Renderer::render_xr`. This is not an OpenXR
+//! integration. Creating the instance, session and swapchains, reading
+//! actions and submitting frames is left to the application's binding,
+//! behind `XrSession`.
+use cgmath::{Matrix4, Point3, Quaternion, Rad, Rotation, SquareMatrix, Vector3};
+
+use crate::camera::{frustum_rh, orientation_yaw_pitch, yaw_pitch_orientation, CameraPose, CameraUniform};
+
+/// Field of view of one eye as angles from its view direction, left and down
+/// negative, as reported by OpenXR.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct EyeFov {
+    pub left: Rad<f32>,
+    pub right: Rad<f32>,
+    pub up: Rad<f32>,
+    pub down: Rad<f32>,
+}
+
+impl EyeFov {
+    /// Asymmetric perspective projection into wgpu's depth range.
+    pub fn projection(&self, znear: f32, zfar: f32) -> Matrix4<f32> {
+        let (left, right) = (self.left.0.tan() * znear, self.right.0.tan() * znear);
+        let (bottom, top) = (self.down.0.tan() * znear, self.up.0.tan() * znear);
+        return frustum_rh(left, right, bottom, top, znear, zfar);
+    }
+}
+
+/// Rigid pose in the runtime's tracking space, y up and -z forward.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct XrPose {
+    pub position: Vector3<f32>,
+    pub orientation: Quaternion<f32>,
+}
+
+impl XrPose {
+    pub fn to_matrix(&self) -> Matrix4<f32> {
+        return Matrix4::from_translation(self.position) * Matrix4::from(self.orientation);
+    }
+
+    pub fn forward(&self) -> Vector3<f32> {
+        return self.orientation.rotate_vector(-Vector3::unit_z());
+    }
+}
+
+/// Where one eye is and what it sees, located by the runtime for a frame.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct EyeView {
+    pub pose: XrPose,
+    pub fov: EyeFov,
+}
+
+impl EyeView {
+    /// Camera uniform of the eye, `origin` placing the tracking space in the world.
+    pub fn uniform(&self, origin: &Matrix4<f32>, znear: f32, zfar: f32) -> CameraUniform {
+        let eye = origin * self.pose.to_matrix();
+        let view = eye.invert().unwrap_or_else(Matrix4::identity);
+        let position = Point3::new(eye.w.x, eye.w.y, eye.w.z);
+        return CameraUniform::new(position, self.fov.projection(znear, zfar) * view);
+    }
+}
+
+/// Frame handed out by the runtime, rendered once per eye.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct XrFrame {
+    /// Left eye first.
+    pub views: [EyeView; 2],
+    pub head: XrPose,
+    /// When the frame will be shown, in nanoseconds of the runtime's clock.
+    pub display_time: i64,
+}
+
+impl XrFrame {
+    /// One view from the head seeing everything either eye sees, for culling
+    /// and the effects both eyes share.
+    pub fn head_view(&self) -> EyeView {
+        let [left, right] = self.views.map(|view| view.fov);
+        return EyeView {
+            pose: self.head,
+            fov: EyeFov {
+                left: Rad(left.left.0.min(right.left.0)),
+                right: Rad(left.right.0.max(right.right.0)),
+                up: Rad(left.up.0.max(right.up.0)),
+                down: Rad(left.down.0.min(right.down.0)),
+            },
+        };
+    }
+}
+
+/// Session and eye swapchains of an XR runtime, rendered into with
+/// `Renderer::render_xr`. The engine doesn't link an OpenXR loader itself:
+/// the application implements this on top of its OpenXR binding, creating
+/// the session and swapchains on the renderer's device.
+pub trait XrSession {
+    /// Format of the eye swapchain images.
+    fn swapchain_format(&self) -> wgpu::TextureFormat;
+
+    /// Size in pixels of each eye's swapchain images.
+    fn eye_resolution(&self) -> (u32, u32);
+
+    /// Handle runtime events, false once the session ended.
+    fn poll_events(&mut self) -> bool;
+
+    /// Wait for the runtime's next frame, `None` when it shouldn't be rendered,
+    /// e.g. while the headset is off.
+    fn begin_frame(&mut self) -> Option<XrFrame>;
+
+    /// Swapchain image to render `eye` of the current frame into.
+    fn eye_target(&mut self, eye: usize) -> &wgpu::TextureView;
+
+    /// Release the eye images and hand the frame to the compositor.
+    fn end_frame(&mut self, frame: &XrFrame);
+}
+
+/// Pose of an `FPSCamera` looking where `head` looks, with the tracking
+/// space origin at `origin`. Head roll is dropped, the compositor shows it.
+pub fn head_camera_pose(head: &XrPose, origin: Point3<f32>, fovy: Rad<f32>) -> CameraPose {
+    let (yaw, pitch) = orientation_yaw_pitch(head.orientation);
+    return CameraPose {
+        position: origin + head.position,
+        orientation: yaw_pitch_orientation(yaw, pitch),
+        fovy,
+    };
+}
@@ -0,0 +1,22 @@
+use engine::golden::{GoldenImages, GoldenScene};
+
+// Renders every predefined scene headless and compares it with its image in
+// `tests/golden`, scenes without one fail. Set `UPDATE_GOLDEN` to record new
+// images after intended changes. Skipped on machines without a GPU adapter
+#[test]
+fn predefined_scenes_match_golden_images() {
+    if !pollster::block_on(GoldenScene::can_render()) {
+        eprintln!("No GPU adapter, skipping golden image tests");
+        return;
+    }
+    let golden = GoldenImages::new(concat!(env!("CARGO_MANIFEST_DIR"), "/tests/golden"));
+
+    let mut failures = Vec::new();
+    for scene in GoldenScene::predefined() {
+        let result = pollster::block_on(scene.render()).and_then(|image| golden.check(scene.name, &image));
+        if let Err(e) = result {
+            failures.push(format!("{}: {:#}", scene.name, e));
+        }
+    }
+    assert!(failures.is_empty(), "Golden image mismatches:\n{}", failures.join("\n"));
+}
\ No newline at end of file
@@ -0,0 +1,32 @@
+use engine::{resources::ModelVertex, tangents::TangentGenerator};
+
+// Value of a `u32` constant of the processed tangent shader
+fn constant(module: &naga::Module, name: &str) -> u64 {
+    let (_, constant) = module
+        .constants
+        .iter()
+        .find(|(_, c)| c.name.as_deref() == Some(name))
+        .unwrap_or_else(|| panic!("`{}` not found", name));
+    return match constant.inner {
+        naga::ConstantInner::Scalar {
+            value: naga::ScalarValue::Uint(value),
+            ..
+        } => value,
+        _ => panic!("`{}` is not a u32", name),
+    };
+}
+
+// The compute pass indexes vertex buffers as flat floats, so its stride has to
+// follow `ModelVertex` with and without the `secondary-uv` feature
+#[test]
+fn tangent_shader_matches_vertex_layout() {
+    let source = TangentGenerator::shaders().process("tangents.wgsl").unwrap();
+    let module = naga::front::wgsl::parse_str(&source).unwrap();
+
+    let floats = std::mem::size_of::<ModelVertex>() / std::mem::size_of::<f32>();
+    let expected = if cfg!(feature = "secondary-uv") { 16 } else { 14 };
+    assert_eq!(floats, expected);
+    assert_eq!(constant(&module, "VERTEX_STRIDE"), floats as u64);
+    assert_eq!(constant(&module, "TANGENT_OFFSET"), 8);
+    assert_eq!(constant(&module, "BITANGENT_OFFSET"), 11);
+}